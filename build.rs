@@ -4,6 +4,6 @@ fn main() {
     tower_grpc_build::Config::new()
         .enable_server(true)
         .enable_client(true)
-        .build(&["proto/placement.proto", "proto/cluster.proto"], &["proto/"])
+        .build(&["proto/placement.proto", "proto/cluster.proto", "proto/raft.proto", "proto/toshi.proto"], &["proto/"])
         .unwrap_or_else(|e| panic!("Compilation failed :( {}", e));
 }
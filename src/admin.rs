@@ -0,0 +1,429 @@
+//! Index administration for the command line, run either against a live server over HTTP or
+//! directly against a data path while no server is using it.
+
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crossbeam::channel::bounded;
+use futures::{Future, Stream};
+use hyper::{Body, Client, Method, Request};
+use serde::Serialize;
+use tantivy::schema::Schema;
+use tantivy::{DocAddress, Document, Index};
+use tokio::runtime::current_thread::Runtime;
+
+use crate::index::IndexCatalog;
+use crate::shard::Sharding;
+use crate::storage::{self, DirectoryType, StorageSettings};
+use crate::{Error, Result};
+
+/// Where administrative commands are carried out
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// The base url of a running Toshi, e.g. `http://localhost:8080`
+    Server(String),
+    /// The data paths of a Toshi that isn't running
+    DataPath(Vec<PathBuf>),
+}
+
+impl Target {
+    /// Create an index stored as `storage` describes, split into shards if `sharding` is given, and in `location`
+    /// if one is given rather than on one of the data paths
+    pub fn create_index(
+        &self,
+        name: &str,
+        schema: &str,
+        location: Option<&str>,
+        storage: &StorageSettings,
+        sharding: Option<&Sharding>,
+    ) -> Result<String> {
+        match self {
+            Target::Server(url) => {
+                let mut query = Vec::new();
+                if storage.directory == DirectoryType::Ram {
+                    query.push("directory=ram".to_string());
+                }
+                if storage.preload {
+                    query.push("preload=true".into());
+                }
+                if let Some(ref field) = storage.sort_by {
+                    query.push(format!("sort_by={}", encode_query(field)));
+                }
+                if !storage.default_fields.is_empty() {
+                    query.push(format!("default_fields={}", encode_query(&storage.default_fields.join(","))));
+                }
+                if let Some(location) = location {
+                    query.push(format!("data_path={}", encode_query(location)));
+                }
+                if let Some(sharding) = sharding {
+                    query.push(format!("shards={}", sharding.shards));
+                    if let Some(ref field) = sharding.routing_field {
+                        query.push(format!("routing_field={}", encode_query(field)));
+                    }
+                }
+                request(
+                    Method::PUT,
+                    &format!("{}/{}/_create?{}", url, name, query.join("&")),
+                    schema.to_string(),
+                )
+            }
+            Target::DataPath(paths) => {
+                let schema: Schema = serde_json::from_str(schema)?;
+                if IndexCatalog::find_index_path(paths, name).is_some() {
+                    return Err(Error::IOError(format!("Index {} already exists", name)));
+                }
+                let path = match location {
+                    Some(location) => {
+                        let location = PathBuf::from(location);
+                        fs::create_dir_all(&location)?;
+                        let mut placements = IndexCatalog::custom_placements(&paths[0])?;
+                        placements.insert(name.to_string(), location.clone());
+                        IndexCatalog::save_custom_placements(&paths[0], &placements)?;
+                        location
+                    }
+                    None => IndexCatalog::emptiest_path(paths).clone(),
+                };
+                match sharding {
+                    Some(sharding) => {
+                        sharding.create(&path.join(name), &schema, storage)?;
+                    }
+                    None => {
+                        storage.create(&path.join(name), schema)?;
+                    }
+                }
+                Ok(format!("Created index {} in {}", name, path.display()))
+            }
+        }
+    }
+
+    pub fn list_indexes(&self) -> Result<Vec<String>> {
+        match self {
+            Target::Server(url) => {
+                let body = request(Method::GET, &format!("{}/_list", url), String::new())?;
+                Ok(serde_json::from_str(&body)?)
+            }
+            Target::DataPath(paths) => {
+                let mut names: Vec<String> = IndexCatalog::custom_placements(&paths[0])?.keys().cloned().collect();
+                for path in paths {
+                    for entry in fs::read_dir(path)? {
+                        let entry = entry?.path();
+                        if storage::is_index(&entry) {
+                            if let Some(name) = entry.file_name().and_then(|n| n.to_str()) {
+                                names.push(name.to_string());
+                            }
+                        }
+                    }
+                }
+                names.sort();
+                Ok(names)
+            }
+        }
+    }
+
+    pub fn delete_index(&self, name: &str) -> Result<String> {
+        match self {
+            Target::Server(url) => request(Method::DELETE, &format!("{}/{}/_drop", url, name), String::new()),
+            Target::DataPath(paths) => {
+                let index_path = IndexCatalog::find_index_path(paths, name).ok_or_else(|| Error::UnknownIndex(name.into()))?;
+                fs::remove_dir_all(index_path)?;
+                let mut placements = IndexCatalog::custom_placements(&paths[0])?;
+                if placements.remove(name).is_some() {
+                    IndexCatalog::save_custom_placements(&paths[0], &placements)?;
+                }
+                Ok(format!("Deleted index {}", name))
+            }
+        }
+    }
+
+    /// The index's metadata: its schema, segments and the opstamp of its last commit
+    pub fn inspect_index(&self, name: &str) -> Result<String> {
+        match self {
+            Target::Server(url) => request(Method::GET, &format!("{}/{}/_summary", url, name), String::new()),
+            Target::DataPath(paths) => {
+                let index_path = IndexCatalog::find_index_path(paths, name).ok_or_else(|| Error::UnknownIndex(name.into()))?;
+                let index = open_index(&index_path)?;
+                Ok(serde_json::to_string_pretty(&index.load_metas()?)?)
+            }
+        }
+    }
+}
+
+/// The least memory Tantivy lets each indexing thread work with
+const MIN_HEAP_PER_THREAD: usize = 3_000_000;
+/// How many lines and parsed documents may queue up between the build stages
+const BUILD_BUFFER_SIZE: usize = 10_000;
+
+#[derive(Serialize, Debug, Default)]
+pub struct BuildSummary {
+    pub indexed: u64,
+    /// Lines that weren't a valid document for the schema
+    pub skipped: u64,
+}
+
+/// Build a new index at `output` from newline delimited JSON documents, parsing and indexing on every
+/// core. The result is an ordinary index directory, so placing it in a data path lets Toshi load it on startup.
+pub fn build_index<R>(schema: Schema, input: R, output: &Path, heap_size: usize) -> Result<BuildSummary>
+where
+    R: BufRead + Send + 'static,
+{
+    if output.join("meta.json").exists() {
+        return Err(Error::IOError(format!("An index already exists at {}", output.display())));
+    }
+    fs::create_dir_all(output)?;
+    let index = Index::create_in_dir(output, schema.clone())?;
+    let threads = num_cpus::get();
+    let mut writer = index.writer_with_num_threads(threads, heap_size.max(threads * MIN_HEAP_PER_THREAD))?;
+
+    let (line_sender, line_recv) = bounded::<String>(BUILD_BUFFER_SIZE);
+    let (doc_sender, doc_recv) = bounded::<Option<Document>>(BUILD_BUFFER_SIZE);
+
+    let reader = thread::spawn(move || -> std::io::Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() && line_sender.send(line).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    });
+    for _ in 0..threads {
+        let schema = schema.clone();
+        let lines = line_recv.clone();
+        let docs = doc_sender.clone();
+        thread::spawn(move || {
+            for line in lines {
+                if docs.send(schema.parse_document(&line).ok()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(line_recv);
+    drop(doc_sender);
+
+    let mut summary = BuildSummary::default();
+    for doc in doc_recv {
+        match doc {
+            Some(doc) => {
+                writer.add_document(doc);
+                summary.indexed += 1;
+            }
+            None => summary.skipped += 1,
+        }
+    }
+    reader.join().map_err(|_| Error::IOError("Reading the input panicked".into()))??;
+
+    writer.commit()?;
+    writer.wait_merging_threads()?;
+    Ok(summary)
+}
+
+/// Write every stored document in the index at `path` to `out` as newline delimited JSON, in the same
+/// shape `toshi build` and the bulk endpoint accept, returning how many documents were written.
+pub fn dump_index<W: Write>(path: &Path, out: &mut W) -> Result<u64> {
+    let index = open_index(path)?;
+    let schema = index.schema();
+    index.load_searchers()?;
+    let searcher = index.searcher();
+
+    let mut written = 0;
+    for (ord, segment) in searcher.segment_readers().iter().enumerate() {
+        for doc_id in (0..segment.max_doc()).filter(|d| !segment.is_deleted(*d)) {
+            let doc = searcher.doc(DocAddress(ord as u32, doc_id))?;
+            writeln!(out, "{}", schema.to_json(&doc))?;
+            written += 1;
+        }
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+#[derive(Serialize, Debug)]
+pub struct IndexStats {
+    pub schema: Schema,
+    pub num_docs: u64,
+    pub segments: Vec<SegmentStats>,
+    pub fields: Vec<FieldStats>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SegmentStats {
+    pub id: String,
+    pub max_doc: u32,
+    pub num_docs: u32,
+    pub num_deleted: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldStats {
+    pub name: String,
+    /// Distinct terms in the field's dictionary, summed across segments
+    pub num_terms: usize,
+}
+
+/// Describe the index at `path`: its schema, how it is split into segments and how large each field's term dictionary is
+pub fn index_stats(path: &Path) -> Result<IndexStats> {
+    let index = open_index(path)?;
+    let schema = index.schema();
+    index.load_searchers()?;
+    let searcher = index.searcher();
+
+    let segments = searcher
+        .segment_readers()
+        .iter()
+        .map(|segment| SegmentStats {
+            id: segment.segment_id().uuid_string(),
+            max_doc: segment.max_doc(),
+            num_docs: segment.num_docs(),
+            num_deleted: segment.num_deleted_docs(),
+        })
+        .collect();
+
+    let fields = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.is_indexed())
+        .map(|(id, entry)| {
+            let field = tantivy::schema::Field(id as u32);
+            let num_terms = searcher
+                .segment_readers()
+                .iter()
+                .map(|segment| segment.inverted_index(field).terms().num_terms())
+                .sum();
+            FieldStats {
+                name: entry.name().to_string(),
+                num_terms,
+            }
+        })
+        .collect();
+
+    Ok(IndexStats {
+        num_docs: searcher.num_docs(),
+        schema,
+        segments,
+        fields,
+    })
+}
+
+fn open_index(path: &Path) -> Result<Index> {
+    Index::open_in_dir(path).map_err(|_| Error::UnknownIndex(path.display().to_string()))
+}
+
+/// Percent-encode a query string value
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Make a single blocking request to a running server, returning the response body
+fn request(method: Method, url: &str, body: String) -> Result<String> {
+    let request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| Error::IOError(e.to_string()))?;
+
+    let response = Client::new().request(request).and_then(|response| {
+        let status = response.status();
+        response.into_body().concat2().map(move |body| (status, body))
+    });
+
+    let mut runtime = Runtime::new()?;
+    let (status, body) = runtime.block_on(response).map_err(|e| Error::IOError(e.to_string()))?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(Error::IOError(format!("{}: {}", status, body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_path_admin() {
+        let path = std::env::temp_dir().join("toshi-admin-test");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let target = Target::DataPath(vec![path.clone()]);
+        let schema = r#"[{ "name": "test_text", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }]"#;
+
+        let storage = StorageSettings::default();
+        target.create_index("new_index", schema, None, &storage, None).unwrap();
+        assert!(target.create_index("new_index", schema, None, &storage, None).is_err());
+        assert_eq!(target.list_indexes().unwrap(), vec!["new_index".to_string()]);
+        assert!(target.inspect_index("new_index").unwrap().contains("test_text"));
+
+        let custom = std::env::temp_dir().join("toshi-admin-test-custom");
+        let _ = fs::remove_dir_all(&custom);
+        target
+            .create_index("custom_index", schema, custom.to_str(), &storage, None)
+            .unwrap();
+        assert!(custom.join("custom_index").join("meta.json").exists());
+        assert_eq!(
+            target.list_indexes().unwrap(),
+            vec!["custom_index".to_string(), "new_index".to_string()]
+        );
+        target.delete_index("custom_index").unwrap();
+
+        target.delete_index("new_index").unwrap();
+        assert!(target.list_indexes().unwrap().is_empty());
+        assert!(target.delete_index("new_index").is_err());
+        assert_eq!(encode_query("/mnt/nvme disk"), "/mnt/nvme%20disk");
+        fs::remove_dir_all(custom).unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_index() {
+        let output = std::env::temp_dir().join("toshi-build-test");
+        let _ = fs::remove_dir_all(&output);
+        let schema: Schema = serde_json::from_str(
+            r#"[
+            { "name": "test_text", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } },
+            { "name": "test_u64", "type": "u64", "options": { "indexed": true, "stored": true } }
+         ]"#,
+        )
+        .unwrap();
+        let input = std::io::Cursor::new(
+            r#"{"test_text": "Document 1", "test_u64": 1}
+{"test_text": "Document 2", "test_u64": 2}
+
+not json
+{"test_text": "Document 3", "test_u64": 3}"#,
+        );
+
+        let summary = build_index(schema.clone(), input, &output, 30_000_000).unwrap();
+        assert_eq!(summary.indexed, 3);
+        assert_eq!(summary.skipped, 1);
+
+        let index = Index::open_in_dir(&output).unwrap();
+        index.load_searchers().unwrap();
+        assert_eq!(index.searcher().num_docs(), 3);
+        assert!(build_index(schema, std::io::Cursor::new(""), &output, 30_000_000).is_err());
+
+        let mut dumped = Vec::new();
+        assert_eq!(dump_index(&output, &mut dumped).unwrap(), 3);
+        let dumped = String::from_utf8(dumped).unwrap();
+        assert_eq!(dumped.lines().count(), 3);
+        assert!(dumped.contains(r#""test_text":["Document 2"]"#));
+
+        let stats = index_stats(&output).unwrap();
+        assert_eq!(stats.num_docs, 3);
+        assert_eq!(stats.segments.iter().map(|s| s.num_docs).sum::<u32>(), 3);
+        let text = stats.fields.iter().find(|f| f.name == "test_text").unwrap();
+        assert!(text.num_terms >= 4);
+        fs::remove_dir_all(output).unwrap();
+    }
+}
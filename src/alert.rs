@@ -0,0 +1,233 @@
+//! Alerts run a saved search on an interval and post to a webhook when its results meet a condition, such as more
+//! than 10 errors in the last run or an average latency over 500. An alert fires when its condition starts being met
+//! and not again until it has stopped being met, so a condition that holds for an hour posts once rather than every
+//! interval.
+//!
+//! Hit counts and aggregations are taken over the documents the search returns, so an alert's query should have a
+//! `limit` above the counts it compares against.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+use tokio::timer::Interval;
+
+use crate::cluster::routing::Preference;
+use crate::handlers::SearchHandler;
+use crate::results::SearchResults;
+use crate::settings::Alert;
+use crate::sql::{Accumulator, Function};
+use crate::{Error, Result};
+
+/// Runs every alert on its interval
+pub struct Alerter {
+    search: SearchHandler,
+    alerts: Vec<Alert>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Alerter {
+    pub fn new(search: SearchHandler, alerts: &[Alert]) -> Self {
+        let https = HttpsConnector::new(4).expect("Could not create TLS for Hyper");
+        Alerter {
+            search,
+            alerts: alerts.to_vec(),
+            client: Client::builder().build(https),
+        }
+    }
+
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        for alert in self.alerts.iter().cloned() {
+            tokio::spawn(watch(self.search.clone(), self.client.clone(), alert));
+        }
+        future::ok(())
+    }
+}
+
+/// Run `alert` every interval, the next run waits for the previous one so a slow search never overlaps itself
+fn watch(search: SearchHandler, client: Client<HttpsConnector<HttpConnector>>, alert: Alert) -> impl Future<Item = (), Error = ()> {
+    let firing = Arc::new(AtomicBool::new(false));
+    let name = alert.name.clone();
+    Interval::new(Instant::now(), Duration::from_secs(alert.interval))
+        .map_err(move |e| error!("Alert {} timer failed: {}", name, e))
+        .for_each(move |_| {
+            let (alert, client, firing) = (alert.clone(), client.clone(), Arc::clone(&firing));
+            let request = match serde_json::from_str(&alert.query) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!("Alert {} query is not a valid search request: {}", alert.name, e);
+                    return future::Either::A(future::ok(()));
+                }
+            };
+            let name = alert.name.clone();
+            let check = search
+                .search_refs(request, alert.index.clone(), Preference::parse(None))
+                .and_then(move |results| {
+                    let value = aggregate(&alert, &results);
+                    let met = condition_met(&alert, &results, &value);
+                    if !met {
+                        firing.store(false, Ordering::SeqCst);
+                    }
+                    if !met || firing.load(Ordering::SeqCst) {
+                        return future::Either::A(future::ok(()));
+                    }
+                    info!("Alert {} fired with {} hits", alert.name, results.hits);
+                    let body = payload(&alert, &results, &value);
+                    // Only a delivered alert counts as fired, one the webhook didn't take is posted again next run
+                    let posted = post(&client, &alert.webhook, &body).map(move |_| firing.store(true, Ordering::SeqCst));
+                    future::Either::B(posted)
+                })
+                .then(move |posted| {
+                    if let Err(e) = posted {
+                        warn!("Alert {} failed: {}", name, e);
+                    }
+                    Ok::<(), ()>(())
+                });
+            future::Either::B(check)
+        })
+}
+
+/// The aggregation of `alert` over its results, or null when it has none or no result holds a number in its field
+pub fn aggregate(alert: &Alert, results: &SearchResults) -> Value {
+    let (function, field) = match (alert.aggregation.as_ref().and_then(|name| Function::parse(name)), &alert.field) {
+        (Some(function), Some(field)) => (function, field),
+        _ => return Value::Null,
+    };
+    let mut accumulator = Accumulator::default();
+    for doc in &results.docs {
+        if let Some(Ok(Value::Array(values))) = doc.doc.get(field).map(serde_json::to_value) {
+            for value in values {
+                accumulator.add(value.as_f64());
+            }
+        }
+    }
+    accumulator.finish(function)
+}
+
+/// Whether any of the conditions of `alert` hold for `results` and the value of its aggregation
+pub fn condition_met(alert: &Alert, results: &SearchResults, value: &Value) -> bool {
+    let value = value.as_f64();
+    alert.hits_above.map_or(false, |above| results.hits > above)
+        || alert.hits_below.map_or(false, |below| results.hits < below)
+        || alert.above.map_or(false, |above| value.map_or(false, |value| value > above))
+        || alert.below.map_or(false, |below| value.map_or(false, |value| value < below))
+}
+
+/// What's posted to the webhook of `alert`, in its format
+pub fn payload(alert: &Alert, results: &SearchResults, value: &Value) -> Value {
+    let docs: Vec<_> = results.docs.iter().take(alert.max_docs).map(|doc| &doc.doc).collect();
+    if alert.format == "slack" {
+        let mut text = format!("*{}*: {} hits in {}", alert.name, results.hits, alert.index);
+        if let (Some(aggregation), Some(field), false) = (&alert.aggregation, &alert.field, value.is_null()) {
+            text.push_str(&format!(", {} of {} is {}", aggregation, field, value));
+        }
+        if !docs.is_empty() {
+            let docs = serde_json::to_string_pretty(&docs).unwrap_or_default();
+            text.push_str(&format!("\n```{}```", docs));
+        }
+        json!({ "text": text })
+    } else {
+        json!({
+            "alert": alert.name,
+            "index": alert.index,
+            "hits": results.hits,
+            "value": value,
+            "docs": docs,
+        })
+    }
+}
+
+fn post(client: &Client<HttpsConnector<HttpConnector>>, webhook: &str, body: &Value) -> impl Future<Item = (), Error = Error> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .map_err(|e| Error::IOError(e.to_string()));
+    let (client, webhook) = (client.clone(), webhook.to_string());
+    future::result(request)
+        .and_then(move |request| client.request(request).map_err(|e| Error::IOError(e.to_string())))
+        .and_then(move |response| -> Result<()> {
+            if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(Error::IOError(format!("Webhook {} answered {}", webhook, response.status())))
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use crate::settings::Settings;
+    use std::str::FromStr;
+
+    fn alert(cfg: &str) -> Alert {
+        let settings = Settings::from_str(&format!(
+            r#"
+            [[alerts]]
+            name = "documents"
+            index = "test_index"
+            query = '{{ "query": {{ "term": {{ "test_text": "document" }} }} }}'
+            webhook = "http://localhost:8080/hook"
+            {}"#,
+            cfg
+        ))
+        .unwrap();
+        settings.alerts[0].clone()
+    }
+
+    #[test]
+    fn test_alert_conditions() {
+        let search = SearchHandler::new(create_test_catalog("test_index"));
+        let request = serde_json::from_str(&alert("").query).unwrap();
+        let results = search
+            .search_refs(request, "test_index".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        assert_eq!(results.hits, 3);
+
+        let hits = alert("hits_above = 2");
+        assert!(condition_met(&hits, &results, &aggregate(&hits, &results)));
+        let hits = alert("hits_above = 3\nhits_below = 3");
+        assert!(!condition_met(&hits, &results, &aggregate(&hits, &results)));
+
+        let sum = alert("aggregation = \"sum\"\nfield = \"test_u64\"\nabove = 30.0");
+        let value = aggregate(&sum, &results);
+        assert!(value.as_f64().unwrap() > 30.0);
+        assert!(condition_met(&sum, &results, &value));
+        let max = alert("aggregation = \"max\"\nfield = \"test_u64\"\nabove = 14.0");
+        assert_eq!(aggregate(&max, &results), json!(14.0));
+        assert!(!condition_met(&max, &results, &aggregate(&max, &results)));
+        let missing = alert("aggregation = \"avg\"\nfield = \"missing\"\nbelow = 1.0");
+        assert_eq!(aggregate(&missing, &results), Value::Null);
+        assert!(!condition_met(&missing, &results, &Value::Null));
+    }
+
+    #[test]
+    fn test_alert_payload() {
+        let search = SearchHandler::new(create_test_catalog("test_index"));
+        let request = serde_json::from_str(&alert("").query).unwrap();
+        let results = search
+            .search_refs(request, "test_index".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+
+        let json = alert("hits_above = 0\nmax_docs = 2");
+        let body = payload(&json, &results, &Value::Null);
+        assert_eq!(body["alert"], "documents");
+        assert_eq!(body["hits"], 3);
+        assert_eq!(body["docs"].as_array().unwrap().len(), 2);
+
+        let slack = alert("aggregation = \"max\"\nfield = \"test_u64\"\nabove = 0.0\nformat = \"slack\"\nmax_docs = 0");
+        let body = payload(&slack, &results, &json!(14.0));
+        assert_eq!(body["text"], "*documents*: 3 hits in test_index, max of test_u64 is 14.0");
+    }
+}
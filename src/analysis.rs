@@ -0,0 +1,1492 @@
+//! Analyzers split the text of a field into the terms it's indexed and searched by. Every index has tantivy's own
+//! `default`, `raw` and `en_stem` tokenizers, and Toshi registers these analyzers alongside them:
+//!
+//! - `standard`, the same as `default`: words split on anything that isn't a letter or digit, lowercased, with words
+//!   longer than 40 bytes left out
+//! - `simple`, words split on anything that isn't a letter or digit and lowercased
+//! - `whitespace`, words split on whitespace and kept as they are
+//! - `keyword`, the whole text kept as a single term like `raw`, which `lowercase` and `trim` filters normalize for
+//!   fields given as a `keyword`
+//! - `unicode`, words split at Unicode word boundaries, lowercased, with words longer than 40 bytes left out
+//! - `cjk`, which is `unicode` with Chinese, Japanese and Korean text indexed as overlapping pairs of characters
+//! - a stemming analyzer for each language, named after it such as `english` or `german`, which is `standard` with
+//!   each word reduced to its stem
+//!
+//! More can be given in the settings under `[analyzers.<name>]`, each picking its tokenizer and filters. A text field
+//! picks its analyzer with `analyzer` in the schema given to `PUT /:index/_create`, which is shorthand for the
+//! tokenizer of its indexing options. It can also pick a `stemmer` language, or `none` to keep words unstemmed for
+//! exact matches, `html_strip` to take HTML out, `ascii_folding` to take accents off, `stop_words` to leave out,
+//! groups of `synonyms`, and `ngram` or `edge_ngram` grams to index in place of each word, which change its analyzer
+//! for that field alone. The same analyzer splits the text of queries the query parser reads, so a query for `running`
+//! finds `runs` in a field analyzed as `english`, except that queries aren't split into grams.
+//!
+//! Programs that build Toshi in can add tokenizers of their own with `register_tokenizer`, which every index opened
+//! afterwards has under the name they're given.
+//!
+//! Synonyms are applied at index time unless `synonyms_at` is `query`. At index time each word is indexed as the first
+//! word of its group, so queries for any of them match. At query time words are indexed as they are and raw queries
+//! are rewritten to search for every word of a group, so the groups can change without indexing again.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use log::warn;
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::Deserialize;
+use serde_json::Value;
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::tokenizer::{Token, TokenStream, Tokenizer, TokenizerManager};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::settings::Settings;
+use crate::{Error, Result};
+
+/// The tokenizers tantivy registers with every index itself
+const TANTIVY_TOKENIZERS: [&str; 3] = ["default", "raw", "en_stem"];
+
+/// Added to an analyzer's name for the analyzer expanding the words of queries into their synonyms, registered along
+/// with each analyzer that applies synonyms at query time
+const EXPANSION_SUFFIX: &str = "@synonyms";
+
+/// Added to an analyzer's name for the analyzer splitting queries of the fields it analyzes, registered along with each
+/// analyzer that indexes n-grams
+const SEARCH_SUFFIX: &str = "@search";
+
+/// Registers a tokenizer added with `register_tokenizer` with an index's tokenizers, under the name it's given
+type CustomTokenizer = Box<Fn(&TokenizerManager, &str) + Send + Sync>;
+
+lazy_static! {
+    /// Tokenizers added by the program Toshi is built into, by name
+    static ref CUSTOM_TOKENIZERS: RwLock<BTreeMap<String, CustomTokenizer>> = RwLock::new(BTreeMap::new());
+}
+
+/// The keys of a text field in a schema's JSON that `apply_analyzers` turns into its tokenizer
+const FIELD_FILTERS: [&str; 10] = [
+    "analyzer",
+    "keyword",
+    "html_strip",
+    "ascii_folding",
+    "stemmer",
+    "stop_words",
+    "synonyms",
+    "synonyms_at",
+    "ngram",
+    "edge_ngram",
+];
+
+/// Words longer than this are left out by the standard analyzers, as tantivy's default tokenizer does
+const MAX_TOKEN_LENGTH: usize = 40;
+
+/// How text is first split into words
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    /// On anything that isn't a letter or digit
+    Simple,
+    Whitespace,
+    /// Not at all, the whole text is a single term
+    Raw,
+    /// At the word boundaries of Unicode text segmentation, which keeps words like `can't` and `3.14` whole
+    Unicode,
+    /// As `unicode`, with Chinese, Japanese and Korean text split into overlapping pairs of characters, since it isn't
+    /// written with spaces between words
+    Cjk,
+}
+
+impl Default for Split {
+    fn default() -> Self {
+        Split::Simple
+    }
+}
+
+/// A language words can be stemmed in
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Hungarian,
+    Italian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Turkish,
+}
+
+impl Language {
+    pub const ALL: [Language; 14] = [
+        Language::Danish,
+        Language::Dutch,
+        Language::English,
+        Language::Finnish,
+        Language::French,
+        Language::German,
+        Language::Hungarian,
+        Language::Italian,
+        Language::Portuguese,
+        Language::Romanian,
+        Language::Russian,
+        Language::Spanish,
+        Language::Swedish,
+        Language::Turkish,
+    ];
+
+    pub fn parse(name: &str) -> Option<Language> {
+        Language::ALL.iter().cloned().find(|language| language.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::Danish => "danish",
+            Language::Dutch => "dutch",
+            Language::English => "english",
+            Language::Finnish => "finnish",
+            Language::French => "french",
+            Language::German => "german",
+            Language::Hungarian => "hungarian",
+            Language::Italian => "italian",
+            Language::Portuguese => "portuguese",
+            Language::Romanian => "romanian",
+            Language::Russian => "russian",
+            Language::Spanish => "spanish",
+            Language::Swedish => "swedish",
+            Language::Turkish => "turkish",
+        }
+    }
+
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::Danish => Algorithm::Danish,
+            Language::Dutch => Algorithm::Dutch,
+            Language::English => Algorithm::English,
+            Language::Finnish => Algorithm::Finnish,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Hungarian => Algorithm::Hungarian,
+            Language::Italian => Algorithm::Italian,
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::Romanian => Algorithm::Romanian,
+            Language::Russian => Algorithm::Russian,
+            Language::Spanish => Algorithm::Spanish,
+            Language::Swedish => Algorithm::Swedish,
+            Language::Turkish => Algorithm::Turkish,
+        }
+    }
+}
+
+/// When synonyms are applied
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SynonymMode {
+    /// Each synonym is indexed as the first word of its group, and searched for the same way, so changing the groups
+    /// needs the documents to be indexed again
+    Index,
+    /// Words are indexed as they are, and raw queries search for every synonym of each of their words
+    Query,
+}
+
+impl Default for SynonymMode {
+    fn default() -> Self {
+        SynonymMode::Index
+    }
+}
+
+/// Grams of each word, from `min_gram` to `max_gram` characters long
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Ngram {
+    pub min_gram: usize,
+    pub max_gram: usize,
+    /// Only the grams starting each word, for matching words as they're typed
+    #[serde(default)]
+    pub edge: bool,
+}
+
+impl Ngram {
+    /// The grams of `min` to `max` characters, given as `<min>,<max>`
+    pub fn parse(grams: &str, edge: bool) -> Option<Ngram> {
+        let mut bounds = grams.splitn(2, ',').map(|bound| bound.trim().parse::<usize>().ok());
+        let (min_gram, max_gram) = (bounds.next()??, bounds.next()??);
+        Some(Ngram { min_gram, max_gram, edge })
+    }
+
+    /// Whether the grams are at least one character and no longer than the longest
+    pub fn is_valid(self) -> bool {
+        self.min_gram >= 1 && self.min_gram <= self.max_gram
+    }
+
+    /// The grams of `word`, which has none when it's shorter than `min_gram`
+    pub fn grams(self, word: &str) -> Vec<String> {
+        let bounds: Vec<usize> = word.char_indices().map(|(at, _)| at).chain(Some(word.len())).collect();
+        let chars = bounds.len() - 1;
+        let starts = if self.edge { chars.min(1) } else { chars };
+        let mut grams = Vec::new();
+        for start in 0..starts {
+            for length in self.min_gram..=self.max_gram {
+                if start + length > chars {
+                    break;
+                }
+                grams.push(word[bounds[start]..bounds[start + length]].to_string());
+            }
+        }
+        grams
+    }
+}
+
+/// A tokenizer and the filters applied to each of its words, in the order they're listed here
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Analyzer {
+    #[serde(default)]
+    pub tokenizer: Split,
+    #[serde(default)]
+    pub lowercase: bool,
+    /// Take whitespace off the ends of each word, which matters for keywords split `raw`
+    #[serde(default)]
+    pub trim: bool,
+    /// Fold letters to their ASCII equivalents, taking accents off so `résumé` becomes `resume`
+    #[serde(default)]
+    pub ascii_folding: bool,
+    /// Take HTML tags out of the text before splitting it, along with scripts, styles and comments, and decode its
+    /// entities
+    #[serde(default)]
+    pub html_strip: bool,
+    /// Leave out words longer than this many bytes
+    #[serde(default)]
+    pub max_token_length: Option<usize>,
+    /// Leave out these words, compared after lowercasing
+    #[serde(default)]
+    pub stop_words: HashSet<String>,
+    /// A file in the analysis path with more stop words, one to a line. Lines starting with `#` are comments.
+    #[serde(default)]
+    pub stop_words_file: Option<String>,
+    /// Groups of words that mean the same, compared after lowercasing
+    #[serde(default)]
+    pub synonyms: Vec<Vec<String>>,
+    /// A file in the analysis path with more synonyms, each line a group of comma separated words
+    #[serde(default)]
+    pub synonyms_file: Option<String>,
+    #[serde(default)]
+    pub synonyms_at: SynonymMode,
+    /// Reduce each word to its stem in this language
+    #[serde(default)]
+    pub stemmer: Option<Language>,
+    /// Index the grams of each word in place of the word. Queries are split without them, so a query for `lap` finds
+    /// `laptop` when it was indexed with edge grams.
+    #[serde(default)]
+    pub ngram: Option<Ngram>,
+    /// Whether every synonym of a word is given rather than the word, which is how queries are expanded
+    #[serde(skip)]
+    expanding: bool,
+}
+
+impl Analyzer {
+    pub fn new(tokenizer: Split) -> Self {
+        Analyzer {
+            tokenizer,
+            lowercase: false,
+            trim: false,
+            ascii_folding: false,
+            html_strip: false,
+            max_token_length: None,
+            stop_words: HashSet::new(),
+            stop_words_file: None,
+            synonyms: Vec::new(),
+            synonyms_file: None,
+            synonyms_at: SynonymMode::default(),
+            stemmer: None,
+            ngram: None,
+            expanding: false,
+        }
+    }
+
+    pub fn standard() -> Self {
+        Analyzer::new(Split::Simple)
+            .with_lowercase()
+            .with_max_token_length(MAX_TOKEN_LENGTH)
+    }
+
+    pub fn with_lowercase(mut self) -> Self {
+        self.lowercase = true;
+        self
+    }
+
+    pub fn with_trim(mut self) -> Self {
+        self.trim = true;
+        self
+    }
+
+    pub fn with_ascii_folding(mut self) -> Self {
+        self.ascii_folding = true;
+        self
+    }
+
+    pub fn with_html_strip(mut self) -> Self {
+        self.html_strip = true;
+        self
+    }
+
+    pub fn with_max_token_length(mut self, length: usize) -> Self {
+        self.max_token_length = Some(length);
+        self
+    }
+
+    pub fn with_stop_words<I: IntoIterator<Item = String>>(mut self, words: I) -> Self {
+        self.stop_words.extend(words);
+        self
+    }
+
+    pub fn with_synonyms(mut self, group: Vec<String>, at: SynonymMode) -> Self {
+        self.synonyms.push(group);
+        self.synonyms_at = at;
+        self
+    }
+
+    /// The analyzer giving every synonym of each word, when the analyzer expands queries into synonyms. Words aren't
+    /// stemmed, since the synonyms are searched for with the analyzer again.
+    pub fn expansion(&self) -> Option<Analyzer> {
+        if self.synonyms.is_empty() || self.synonyms_at != SynonymMode::Query {
+            return None;
+        }
+        Some(Analyzer {
+            stemmer: None,
+            ngram: None,
+            expanding: true,
+            ..self.clone()
+        })
+    }
+
+    /// The analyzer splitting queries, when it isn't this one because this one indexes n-grams
+    pub fn search(&self) -> Option<Analyzer> {
+        self.ngram?;
+        Some(Analyzer {
+            ngram: None,
+            ..self.clone()
+        })
+    }
+
+    pub fn with_ngram(mut self, ngram: Ngram) -> Self {
+        self.ngram = Some(ngram);
+        self
+    }
+
+    pub fn with_stemmer(mut self, language: Language) -> Self {
+        self.stemmer = Some(language);
+        self
+    }
+
+    /// The analyzers every index has, by name
+    pub fn builtin() -> Vec<(String, Analyzer)> {
+        let mut analyzers = vec![
+            ("standard".to_string(), Analyzer::standard()),
+            ("simple".to_string(), Analyzer::new(Split::Simple).with_lowercase()),
+            ("whitespace".to_string(), Analyzer::new(Split::Whitespace)),
+            ("keyword".to_string(), Analyzer::new(Split::Raw)),
+            (
+                "unicode".to_string(),
+                Analyzer::new(Split::Unicode)
+                    .with_lowercase()
+                    .with_max_token_length(MAX_TOKEN_LENGTH),
+            ),
+            (
+                "cjk".to_string(),
+                Analyzer::new(Split::Cjk).with_lowercase().with_max_token_length(MAX_TOKEN_LENGTH),
+            ),
+        ];
+        for language in &Language::ALL {
+            analyzers.push((language.name().to_string(), Analyzer::standard().with_stemmer(*language)));
+        }
+        analyzers
+    }
+
+    /// Read the files the analyzer names from `path`, so it no longer needs them
+    pub fn load(mut self, path: &Path) -> Result<Self> {
+        let lowercase = self.lowercase;
+        let normalize = |word: &str| {
+            if lowercase {
+                word.trim().to_lowercase()
+            } else {
+                word.trim().to_string()
+            }
+        };
+        if let Some(file) = self.stop_words_file.take() {
+            let words = fs::read_to_string(path.join(&file))
+                .map_err(|e| Error::IOError(format!("Unable to read stop words from {}: {}", file, e)))?;
+            self.stop_words.extend(
+                words
+                    .lines()
+                    .map(str::trim)
+                    .filter(|word| !word.is_empty() && !word.starts_with('#'))
+                    .map(normalize),
+            );
+        }
+        if let Some(file) = self.synonyms_file.take() {
+            let groups = fs::read_to_string(path.join(&file))
+                .map_err(|e| Error::IOError(format!("Unable to read synonyms from {}: {}", file, e)))?;
+            self.synonyms.extend(
+                groups
+                    .lines()
+                    .map(str::trim)
+                    .filter(|group| !group.is_empty() && !group.starts_with('#'))
+                    .map(|group| group.split(',').map(normalize).collect()),
+            );
+        }
+        if lowercase {
+            for group in &mut self.synonyms {
+                group.iter_mut().for_each(|word| *word = word.to_lowercase());
+            }
+        }
+        if self.ascii_folding {
+            self.stop_words = self.stop_words.iter().map(|word| fold(word)).collect();
+            for group in &mut self.synonyms {
+                group.iter_mut().for_each(|word| *word = fold(word));
+            }
+        }
+        Ok(self)
+    }
+
+    /// What `word` is indexed as after applying synonyms
+    fn synonyms_of(&self, word: String) -> Vec<String> {
+        match self.synonyms.iter().find(|group| group.contains(&word)) {
+            Some(group) if self.expanding => group.clone(),
+            Some(group) if self.synonyms_at == SynonymMode::Index => vec![group[0].clone()],
+            _ => vec![word],
+        }
+    }
+
+    /// The words of `text`, as each is indexed
+    pub fn analyze(&self, text: &str) -> Vec<String> {
+        let mut stream = self.token_stream(text);
+        let mut words = Vec::new();
+        while stream.advance() {
+            words.push(stream.token().text.clone());
+        }
+        words
+    }
+
+    /// The spans of the words of `text`, as byte offsets
+    fn split(&self, text: &str) -> Vec<(usize, usize)> {
+        let is_separator: fn(char) -> bool = match self.tokenizer {
+            Split::Simple => |c: char| !c.is_alphanumeric(),
+            Split::Whitespace => char::is_whitespace,
+            Split::Raw => |_| false,
+            Split::Unicode => return text.unicode_word_indices().map(|(at, word)| (at, at + word.len())).collect(),
+            Split::Cjk => return cjk_bigrams(text),
+        };
+        let mut spans = Vec::new();
+        let mut start = None;
+        for (at, c) in text.char_indices() {
+            match (is_separator(c), start) {
+                (true, Some(from)) => {
+                    spans.push((from, at));
+                    start = None;
+                }
+                (false, None) => start = Some(at),
+                _ => {}
+            }
+        }
+        if let Some(from) = start {
+            spans.push((from, text.len()));
+        }
+        spans
+    }
+}
+
+/// `word` with its letters folded to ASCII: accents and other combining marks are taken off, compatibility forms such
+/// as ligatures and full width letters are decomposed, and letters with no decomposition like `ß` and `ø` are spelled
+/// the way they usually are in ASCII. Anything else is kept.
+pub fn fold(word: &str) -> String {
+    let mut folded = String::with_capacity(word.len());
+    for c in word.nfkd().filter(|c| !is_combining_mark(*c)) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'æ' => folded.push_str("ae"),
+            'Æ' => folded.push_str("AE"),
+            'œ' => folded.push_str("oe"),
+            'Œ' => folded.push_str("OE"),
+            'þ' => folded.push_str("th"),
+            'Þ' => folded.push_str("TH"),
+            'ø' => folded.push('o'),
+            'Ø' => folded.push('O'),
+            'đ' | 'ð' => folded.push('d'),
+            'Đ' | 'Ð' => folded.push('D'),
+            'ł' => folded.push('l'),
+            'Ł' => folded.push('L'),
+            'ı' => folded.push('i'),
+            c => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// Tags that mark up text within a line, which are taken out without separating the words around them
+const INLINE_TAGS: [&str; 22] = [
+    "a", "abbr", "b", "bdi", "bdo", "cite", "code", "dfn", "em", "font", "i", "kbd", "mark", "q", "s", "samp", "small", "span", "strong",
+    "sub", "sup", "u",
+];
+
+/// The text of `html` with its tags, comments, scripts and styles taken out and its entities decoded, along with the
+/// offset in `html` of each byte of the text and of its end. Tags other than inline ones like `<b>` are replaced by a
+/// space, so the words on either side of a `<br>` or `</p>` stay apart.
+pub fn strip_html(html: &str) -> (String, Vec<usize>) {
+    fn push(text: &mut String, offsets: &mut Vec<usize>, c: char, at: usize) {
+        text.push(c);
+        offsets.extend(std::iter::repeat(at).take(c.len_utf8()));
+    }
+
+    let mut text = String::with_capacity(html.len());
+    let mut offsets = Vec::with_capacity(html.len() + 1);
+    let mut at = 0;
+    while let Some(c) = html[at..].chars().next() {
+        let rest = &html[at..];
+        if rest.starts_with("<!--") {
+            push(&mut text, &mut offsets, ' ', at);
+            at += rest.find("-->").map_or(rest.len(), |end| end + 3);
+            continue;
+        }
+        let is_tag = c == '<' && rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!' || c == '?');
+        if is_tag {
+            let mut end = rest.find('>').map_or(rest.len(), |end| end + 1);
+            let name: String = rest[1..end]
+                .trim_start_matches('/')
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if (name == "script" || name == "style") && !rest.starts_with("</") {
+                let close = format!("</{}", name);
+                end = rest.to_ascii_lowercase().find(&close).map_or(rest.len(), |close| {
+                    rest[close..].find('>').map_or(rest.len(), |end| close + end + 1)
+                });
+            }
+            if !INLINE_TAGS.contains(&name.as_str()) {
+                push(&mut text, &mut offsets, ' ', at);
+            }
+            at += end;
+            continue;
+        }
+        if let Some((decoded, length)) = if c == '&' { entity(rest) } else { None } {
+            push(&mut text, &mut offsets, decoded, at);
+            at += length;
+            continue;
+        }
+        push(&mut text, &mut offsets, c, at);
+        at += c.len_utf8();
+    }
+    offsets.push(html.len());
+    (text, offsets)
+}
+
+/// The character the entity `text` starts with stands for and the entity's length, if it's a numeric entity or one
+/// of the common named ones
+fn entity(text: &str) -> Option<(char, usize)> {
+    let end = text.char_indices().take(12).find(|&(_, c)| c == ';')?.0;
+    let name = &text[1..end];
+    let decoded = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ if name.starts_with("#x") || name.starts_with("#X") => std::char::from_u32(u32::from_str_radix(&name[2..], 16).ok()?)?,
+        _ if name.starts_with('#') => std::char::from_u32(name[1..].parse().ok()?)?,
+        _ => return None,
+    };
+    Some((decoded, end + 1))
+}
+
+/// Whether `c` is a Chinese, Japanese or Korean character
+fn is_cjk(c: char) -> bool {
+    match c as u32 {
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => true, // Hangul
+        0x3040..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => true, // Hiragana and Katakana
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FFFF => true, // Han ideographs
+        _ => false,
+    }
+}
+
+/// The spans of the Unicode words of `text`, with each run of CJK characters split into the overlapping pairs of its
+/// characters, or the character alone when it has no neighbour
+fn cjk_bigrams(text: &str) -> Vec<(usize, usize)> {
+    let pairs = |run: &[(usize, usize)]| -> Vec<(usize, usize)> {
+        if run.len() == 1 {
+            run.to_vec()
+        } else {
+            run.windows(2).map(|pair| (pair[0].0, pair[1].1)).collect()
+        }
+    };
+    let mut spans = Vec::new();
+    let mut run: Vec<(usize, usize)> = Vec::new();
+    for (at, word) in text.unicode_word_indices() {
+        if !word.chars().all(is_cjk) {
+            spans.extend(pairs(&run));
+            run.clear();
+            spans.push((at, at + word.len()));
+            continue;
+        }
+        if run.last().map_or(false, |&(_, to)| to != at) {
+            spans.extend(pairs(&run));
+            run.clear();
+        }
+        run.extend(word.char_indices().map(|(from, c)| (at + from, at + from + c.len_utf8())));
+    }
+    spans.extend(pairs(&run));
+    spans
+}
+
+impl<'a> Tokenizer<'a> for Analyzer {
+    type TokenStreamImpl = AnalyzedStream;
+
+    fn token_stream(&self, text: &'a str) -> AnalyzedStream {
+        let stemmer = self.stemmer.map(|language| Stemmer::create(language.algorithm()));
+        let stripped = if self.html_strip { Some(strip_html(text)) } else { None };
+        let (text, offsets) = match stripped {
+            Some((ref text, ref offsets)) => (text.as_str(), Some(offsets)),
+            None => (text, None),
+        };
+        let original = |at: usize| offsets.map_or(at, |offsets| offsets[at]);
+        let mut tokens = Vec::new();
+        for (position, (from, to)) in self.split(text).into_iter().enumerate() {
+            if self.max_token_length.map_or(false, |max| to - from > max) {
+                continue;
+            }
+            let word = if self.trim { text[from..to].trim() } else { &text[from..to] };
+            if word.is_empty() {
+                continue;
+            }
+            let mut word = if self.lowercase { word.to_lowercase() } else { word.to_string() };
+            if self.ascii_folding {
+                word = fold(&word);
+            }
+            if self.stop_words.contains(&word) {
+                continue;
+            }
+            for mut word in self.synonyms_of(word) {
+                if let Some(ref stemmer) = stemmer {
+                    word = stemmer.stem(&word).into_owned();
+                }
+                let words = match self.ngram {
+                    Some(ngram) => ngram.grams(&word),
+                    None => vec![word],
+                };
+                for word in words {
+                    let mut token = Token::default();
+                    token.offset_from = original(from);
+                    token.offset_to = original(to);
+                    token.position = position;
+                    token.text = word;
+                    tokens.push(token);
+                }
+            }
+        }
+        AnalyzedStream { tokens, at: None }
+    }
+}
+
+/// The words of a text, analyzed up front
+pub struct AnalyzedStream {
+    tokens: Vec<Token>,
+    at: Option<usize>,
+}
+
+impl TokenStream for AnalyzedStream {
+    fn advance(&mut self) -> bool {
+        let next = self.at.map_or(0, |at| at + 1);
+        self.at = Some(next);
+        next < self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.at.unwrap_or(0)]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.at.unwrap_or(0)]
+    }
+}
+
+/// Make `tokenizer` available to every index opened from now on as `name`, for programs that build Toshi in and have
+/// tokenizers of their own, such as a dictionary based one for a language. Fields pick it with `analyzer` like any
+/// other, but it's used as it is: it takes none of the filters a field can add, such as a `stemmer` or `stop_words`.
+/// It can't take the name of a built in analyzer or of tantivy's tokenizers.
+pub fn register_tokenizer<T>(name: &str, tokenizer: T) -> Result<()>
+where
+    T: for<'a> Tokenizer<'a> + Clone + Send + Sync + 'static,
+{
+    let builtin = Analyzer::builtin().into_iter().any(|(builtin, _)| builtin == name);
+    if builtin || TANTIVY_TOKENIZERS.contains(&name) {
+        return Err(Error::QueryError(format!("Tokenizer {} would replace a built in analyzer", name)));
+    }
+    if name.is_empty() || name.contains(|c| c == '/' || c == '@') {
+        return Err(Error::QueryError(format!(
+            "Tokenizer name {} can't be empty or hold '/' or '@'",
+            name
+        )));
+    }
+    let register: CustomTokenizer = Box::new(move |tokenizers, name| tokenizers.register(name, tokenizer.clone()));
+    CUSTOM_TOKENIZERS.write()?.insert(name.to_string(), register);
+    Ok(())
+}
+
+/// Whether `name` is a tokenizer added with `register_tokenizer`
+fn is_custom(name: &str) -> bool {
+    CUSTOM_TOKENIZERS.read().map(|custom| custom.contains_key(name)).unwrap_or(false)
+}
+
+/// The name of every analyzer a field can pick: tantivy's, the built in ones, the ones in `settings` and the tokenizers
+/// added with `register_tokenizer`, sorted
+pub fn available(settings: &Settings) -> Vec<String> {
+    let mut names: Vec<String> = TANTIVY_TOKENIZERS.iter().map(|name| name.to_string()).collect();
+    names.extend(Analyzer::builtin().into_iter().map(|(name, _)| name));
+    names.extend(settings.analyzers.keys().cloned());
+    if let Ok(custom) = CUSTOM_TOKENIZERS.read() {
+        names.extend(custom.keys().cloned());
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Register the built in analyzers, the tokenizers added with `register_tokenizer` and the analyzers in `settings` with
+/// `tokenizers`, the ones in `settings` replacing others of the same name, along with the analyzers the fields of
+/// `schema` change with their own filters. Analyzers whose files can't be read are left out, so searches of their
+/// fields fail rather than the whole index.
+pub fn register(tokenizers: &TokenizerManager, schema: &Schema, settings: &Settings) {
+    for (name, analyzer) in Analyzer::builtin() {
+        tokenizers.register(&name, analyzer);
+    }
+    match CUSTOM_TOKENIZERS.read() {
+        Ok(custom) => custom.iter().for_each(|(name, register)| register(tokenizers, name)),
+        Err(e) => warn!("Unable to register custom tokenizers: {}", e),
+    }
+    let mut names: Vec<&str> = settings.analyzers.keys().map(String::as_str).collect();
+    names.extend(tokenizer_names(schema).into_iter().filter(|name| name.contains('/')));
+    for name in names {
+        match resolve(name, settings) {
+            Ok(analyzer) => {
+                if let Some(expansion) = analyzer.expansion() {
+                    tokenizers.register(&format!("{}{}", name, EXPANSION_SUFFIX), expansion);
+                }
+                if let Some(search) = analyzer.search() {
+                    tokenizers.register(&format!("{}{}", name, SEARCH_SUFFIX), search);
+                }
+                tokenizers.register(name, analyzer)
+            }
+            Err(e) => warn!("Unable to register analyzer {}: {}", name, e),
+        }
+    }
+}
+
+/// The tokenizers the text fields of `schema` are indexed with
+fn tokenizer_names(schema: &Schema) -> Vec<&str> {
+    schema
+        .fields()
+        .iter()
+        .filter_map(|field| match field.field_type() {
+            FieldType::Str(options) => options.get_indexing_options().map(|indexing| indexing.tokenizer()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The analyzer a tokenizer name stands for, with its files read. A name is an analyzer, either one in `settings` or a
+/// built in one, followed by the filters a field added to it, each after a `/`:
+///
+/// - a language to stem words in, or `none` to not stem them
+/// - `ascii` to fold letters to ASCII
+/// - `lowercase` to lowercase words and `trim` to take whitespace off their ends, the normalizers of keywords
+/// - `html` to take HTML out of the text
+/// - `stop=<words>` to leave out the comma separated words
+/// - `stopfile=<file>` to leave out the words of a file in the analysis path
+/// - `syn=<groups>` for groups of synonyms, separated by `;` with their words separated by commas
+/// - `synfile=<file>` for the groups of synonyms in a file in the analysis path
+/// - `synat=index` or `synat=query` for when synonyms are applied
+/// - `ngram=<min>,<max>` or `edgengram=<min>,<max>` to index the grams of each word
+pub fn resolve(name: &str, settings: &Settings) -> Result<Analyzer> {
+    let mut parts = name.split('/');
+    let base = parts.next().unwrap_or_default();
+    let mut analyzer = match (settings.analyzers.get(base), base) {
+        (Some(analyzer), _) => analyzer.clone(),
+        (None, "default") => Analyzer::standard(),
+        (None, "en_stem") => Analyzer::standard().with_stemmer(Language::English),
+        (None, "raw") => Analyzer::new(Split::Raw),
+        (None, _) => match Analyzer::builtin().into_iter().find(|(builtin, _)| builtin == base) {
+            Some((_, analyzer)) => analyzer,
+            None if is_custom(base) => {
+                return Err(Error::QueryError(format!("Tokenizer {} takes no filters, as in {}", base, name)));
+            }
+            None => {
+                let available = available(settings).join(", ");
+                return Err(Error::QueryError(format!(
+                    "Unknown analyzer {}, the available analyzers are {}",
+                    base, available
+                )));
+            }
+        },
+    };
+    for filter in parts {
+        let mut split = filter.splitn(2, '=');
+        analyzer = match (split.next().unwrap_or_default(), split.next()) {
+            ("none", None) => Analyzer { stemmer: None, ..analyzer },
+            ("ascii", None) => analyzer.with_ascii_folding(),
+            ("lowercase", None) => analyzer.with_lowercase(),
+            ("trim", None) => analyzer.with_trim(),
+            ("html", None) => analyzer.with_html_strip(),
+            ("stop", Some(words)) => {
+                let lowercase = analyzer.lowercase;
+                analyzer.with_stop_words(
+                    words
+                        .split(',')
+                        .map(|word| if lowercase { word.to_lowercase() } else { word.to_string() }),
+                )
+            }
+            ("stopfile", Some(file)) if analyzer.stop_words_file.is_none() => Analyzer {
+                stop_words_file: Some(file.to_string()),
+                ..analyzer
+            },
+            ("syn", Some(groups)) => {
+                let at = analyzer.synonyms_at;
+                groups.split(';').fold(analyzer, |analyzer, group| {
+                    analyzer.with_synonyms(group.split(',').map(str::to_string).collect(), at)
+                })
+            }
+            ("synfile", Some(file)) if analyzer.synonyms_file.is_none() => Analyzer {
+                synonyms_file: Some(file.to_string()),
+                ..analyzer
+            },
+            ("synat", Some("index")) => Analyzer {
+                synonyms_at: SynonymMode::Index,
+                ..analyzer
+            },
+            ("synat", Some("query")) => Analyzer {
+                synonyms_at: SynonymMode::Query,
+                ..analyzer
+            },
+            ("ngram", Some(grams)) | ("edgengram", Some(grams)) => match Ngram::parse(grams, filter.starts_with("edge")) {
+                Some(ngram) => analyzer.with_ngram(ngram),
+                None => {
+                    return Err(Error::QueryError(format!(
+                        "Grams {} of analyzer {} aren't <min>,<max>",
+                        grams, name
+                    )))
+                }
+            },
+            (language, None) if Language::parse(language).is_some() => {
+                analyzer.with_stemmer(Language::parse(language).unwrap_or(Language::English))
+            }
+            _ => return Err(Error::QueryError(format!("Unknown filter {} of analyzer {}", filter, name))),
+        };
+    }
+    if analyzer.ngram.map_or(false, |ngram| !ngram.is_valid()) {
+        return Err(Error::QueryError(format!(
+            "Analyzer {} needs grams of at least one character, up to max_gram",
+            name
+        )));
+    }
+    analyzer.load(Path::new(&settings.analysis_path))
+}
+
+/// Turn the filters of each text field in a schema's JSON, the keys in `FIELD_FILTERS`, into the tokenizer of its
+/// indexing options, indexing the field with positions if it had no indexing options, or without them for keywords.
+/// `stop_words` is either a list of words or the name of a file of them in the analysis path, `synonyms` either a list
+/// of groups of words or the name of a file of them, and the grams objects with a `min_gram` and `max_gram`.
+pub fn apply_analyzers(schema: &mut Value) -> Result<()> {
+    let fields = match schema.as_array_mut() {
+        Some(fields) => fields,
+        None => return Ok(()),
+    };
+    for field in fields {
+        let mut filters: HashMap<&str, Value> = match field.as_object_mut() {
+            Some(field) => FIELD_FILTERS.iter().filter_map(|key| Some((*key, field.remove(*key)?))).collect(),
+            None => continue,
+        };
+        if filters.is_empty() {
+            continue;
+        }
+        if field["type"] != "text" {
+            return Err(Error::QueryError(format!("Field {} is not a text field to analyze", field["name"])));
+        }
+        let keyword = match filters.remove("keyword") {
+            Some(keyword) => keyword_tokenizer(&keyword)?,
+            None => None,
+        };
+        let options = &mut field["options"];
+        if options.get("indexing").map_or(true, Value::is_null) {
+            let record = if keyword.is_some() { "basic" } else { "position" };
+            options["indexing"] = serde_json::json!({ "record": record, "tokenizer": "default" });
+        }
+        let mut tokenizer = match (filters.remove("analyzer"), keyword) {
+            (Some(_), Some(_)) => return Err(Error::QueryError("A keyword field has no analyzer".into())),
+            (None, Some(keyword)) => keyword,
+            (Some(Value::String(analyzer)), None) => analyzer,
+            (Some(other), None) => return Err(Error::QueryError(format!("Analyzer {} is not a name", other))),
+            (None, None) => options["indexing"]["tokenizer"].as_str().unwrap_or("default").to_string(),
+        };
+        let (stemmer, stop_words) = (filters.remove("stemmer"), filters.remove("stop_words"));
+        let (synonyms, synonyms_at) = (filters.remove("synonyms"), filters.remove("synonyms_at"));
+        let (ngram, edge_ngram) = (filters.remove("ngram"), filters.remove("edge_ngram"));
+        let (html, folding) = (filters.remove("html_strip"), filters.remove("ascii_folding"));
+        for (key, enabled, filter) in [("html_strip", html, "/html"), ("ascii_folding", folding, "/ascii")].iter() {
+            match enabled {
+                Some(Value::Bool(true)) => tokenizer.push_str(filter),
+                Some(Value::Bool(false)) | None => {}
+                Some(other) => return Err(Error::QueryError(format!("{} is {} rather than true or false", key, other))),
+            }
+        }
+        match stop_words {
+            Some(Value::String(file)) if !file.contains('/') => tokenizer.push_str(&format!("/stopfile={}", file)),
+            Some(Value::Array(words)) => {
+                let words: Vec<&str> = words.iter().filter_map(Value::as_str).collect();
+                if words.iter().any(|word| word.contains(',') || word.contains('/')) {
+                    return Err(Error::QueryError("Stop words can't hold ',' or '/'".into()));
+                }
+                tokenizer.push_str(&format!("/stop={}", words.join(",")));
+            }
+            Some(other) => return Err(Error::QueryError(format!("Stop words {} are neither a list nor a file", other))),
+            None => {}
+        }
+        match synonyms {
+            Some(Value::String(file)) if !file.contains('/') => tokenizer.push_str(&format!("/synfile={}", file)),
+            Some(Value::Array(groups)) => {
+                let groups: Option<Vec<String>> = groups
+                    .iter()
+                    .map(|group| {
+                        let words: Option<Vec<&str>> = group.as_array()?.iter().map(Value::as_str).collect();
+                        let words = words?;
+                        if words.iter().any(|word| word.contains(|c| ",;/".contains(c))) {
+                            return None;
+                        }
+                        Some(words.join(","))
+                    })
+                    .collect();
+                match groups {
+                    Some(groups) => tokenizer.push_str(&format!("/syn={}", groups.join(";"))),
+                    None => return Err(Error::QueryError("Synonyms are groups of words without ',', ';' or '/'".into())),
+                }
+            }
+            Some(other) => return Err(Error::QueryError(format!("Synonyms {} are neither a list nor a file", other))),
+            None => {}
+        }
+        match synonyms_at {
+            Some(Value::String(ref at)) if at == "index" || at == "query" => tokenizer.push_str(&format!("/synat={}", at)),
+            Some(other) => return Err(Error::QueryError(format!("Synonyms can't be applied at {}", other))),
+            None => {}
+        }
+        match stemmer {
+            Some(Value::String(ref stemmer)) if stemmer == "none" || Language::parse(stemmer).is_some() => {
+                tokenizer = format!("{}/{}", tokenizer, stemmer);
+            }
+            Some(other) => return Err(Error::QueryError(format!("Unknown stemmer {}", other))),
+            None => {}
+        }
+        for (filter, grams) in [("ngram", ngram), ("edgengram", edge_ngram)].iter() {
+            let grams = match grams {
+                Some(grams) => grams,
+                None => continue,
+            };
+            match (grams["min_gram"].as_u64(), grams["max_gram"].as_u64()) {
+                (Some(min), Some(max)) if min >= 1 && min <= max => tokenizer.push_str(&format!("/{}={},{}", filter, min, max)),
+                _ => {
+                    return Err(Error::QueryError(format!(
+                        "Grams {} need a min_gram of at least 1 up to max_gram",
+                        grams
+                    )))
+                }
+            }
+        }
+        options["indexing"]["tokenizer"] = tokenizer.into();
+    }
+    Ok(())
+}
+
+/// The tokenizer name of a keyword field given `keyword`, which is either `true` or an object with a `normalizer` of
+/// `lowercase`, `trim` or a list of both. A `false` keyword leaves the field as it is.
+fn keyword_tokenizer(keyword: &Value) -> Result<Option<String>> {
+    let normalizers = match keyword {
+        Value::Bool(false) => return Ok(None),
+        Value::Bool(true) => Vec::new(),
+        Value::Object(options) => match options.get("normalizer") {
+            Some(Value::String(normalizer)) => vec![normalizer.as_str()],
+            Some(Value::Array(normalizers)) => normalizers.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        },
+        other => return Err(Error::QueryError(format!("Keyword {} is neither true nor options", other))),
+    };
+    let mut tokenizer = "keyword".to_string();
+    for normalizer in normalizers {
+        match normalizer {
+            "lowercase" | "trim" => tokenizer = format!("{}/{}", tokenizer, normalizer),
+            other => {
+                return Err(Error::QueryError(format!(
+                    "Unknown normalizer {}, keywords take lowercase and trim",
+                    other
+                )))
+            }
+        }
+    }
+    Ok(Some(tokenizer))
+}
+
+/// `text` as the field is indexed, when it's a keyword field with normalizers, so exact terms of it match whatever
+/// case and whitespace they're given with. Text of other fields is returned as it is, since splitting it into words
+/// would no longer give one term.
+pub fn normalize(schema: &Schema, field: Field, text: &str) -> String {
+    let tokenizer = match schema.get_field_entry(field).field_type() {
+        FieldType::Str(options) => options.get_indexing_options().map(|indexing| indexing.tokenizer()),
+        _ => None,
+    };
+    let mut parts = tokenizer.unwrap_or_default().split('/');
+    if parts.next() != Some("keyword") {
+        return text.to_string();
+    }
+    let normalizer = parts.try_fold(Analyzer::new(Split::Raw), |analyzer, filter| match filter {
+        "lowercase" => Some(analyzer.with_lowercase()),
+        "trim" => Some(analyzer.with_trim()),
+        _ => None,
+    });
+    match normalizer {
+        Some(normalizer) => normalizer.analyze(text).pop().unwrap_or_default(),
+        None => text.to_string(),
+    }
+}
+
+/// Rewrite a raw query so each of its words also searches for its synonyms, in the fields whose analyzers apply
+/// synonyms at query time. `word` becomes `(word OR synonym)` and `field:word` becomes `(field:word OR field:synonym)`,
+/// while phrases, ranges and anything already grouped are left as they are.
+pub fn expand_synonyms(raw: &str, schema: &Schema, tokenizers: &TokenizerManager, default_fields: &[Field]) -> String {
+    let synonyms = |field: Field, word: &str| -> Vec<String> {
+        let expansion = match schema.get_field_entry(field).field_type() {
+            FieldType::Str(options) => options
+                .get_indexing_options()
+                .and_then(|indexing| tokenizers.get(&format!("{}{}", indexing.tokenizer(), EXPANSION_SUFFIX))),
+            _ => None,
+        };
+        let mut words = Vec::new();
+        if let Some(expansion) = expansion {
+            let mut stream = expansion.token_stream(word);
+            while stream.advance() {
+                words.push(stream.token().text.clone());
+            }
+        }
+        words
+    };
+
+    let (mut in_phrase, mut in_range) = (false, false);
+    let mut expanded = Vec::new();
+    for piece in raw.split_whitespace() {
+        let untouched = in_phrase || in_range || piece.contains(|c| "\"[]{}()*^~\\".contains(c));
+        if piece.matches('"').count() % 2 == 1 {
+            in_phrase = !in_phrase;
+        }
+        if piece.contains(|c| c == '[' || c == '{') {
+            in_range = true;
+        }
+        if piece.contains(|c| c == ']' || c == '}') {
+            in_range = false;
+        }
+        if untouched || piece == "AND" || piece == "OR" || piece == "NOT" {
+            expanded.push(piece.to_string());
+            continue;
+        }
+
+        let (occur, clause) = if piece.starts_with('+') || piece.starts_with('-') {
+            piece.split_at(1)
+        } else {
+            ("", piece)
+        };
+        let (field, word) = match clause.find(':') {
+            Some(at) => (Some(&clause[..at]), &clause[at + 1..]),
+            None => (None, clause),
+        };
+        let fields = match field {
+            Some(name) => schema.get_field(name).into_iter().collect(),
+            None => default_fields.to_vec(),
+        };
+        let mut alternatives: Vec<String> = Vec::new();
+        for synonym in fields.into_iter().flat_map(|field| synonyms(field, word)) {
+            if !alternatives.contains(&synonym) {
+                alternatives.push(synonym);
+            }
+        }
+        if alternatives.len() < 2 {
+            expanded.push(piece.to_string());
+            continue;
+        }
+        let alternatives: Vec<String> = alternatives
+            .into_iter()
+            .map(|synonym| match field {
+                Some(field) => format!("{}:{}", field, synonym),
+                None => synonym,
+            })
+            .collect();
+        expanded.push(format!("{}({})", occur, alternatives.join(" OR ")));
+    }
+    expanded.join(" ")
+}
+
+/// `schema` with each text field analyzed by the analyzer its queries are split by, which leaves out the n-grams of the
+/// analyzers that index them so a query for `lap` searches for the gram `lap` rather than each of `l`, `la` and `lap`
+pub fn search_schema(schema: &Schema, tokenizers: &TokenizerManager) -> Schema {
+    let mut fields = match serde_json::to_value(schema) {
+        Ok(fields) => fields,
+        Err(_) => return schema.clone(),
+    };
+    for field in fields.as_array_mut().into_iter().flatten() {
+        if let Some(Value::String(tokenizer)) = field.pointer_mut("/options/indexing/tokenizer") {
+            let search = format!("{}{}", tokenizer, SEARCH_SUFFIX);
+            if tokenizers.get(&search).is_some() {
+                *tokenizer = search;
+            }
+        }
+    }
+    serde_json::from_value(fields).unwrap_or_else(|_| schema.clone())
+}
+
+/// Check that every text field of `schema` is analyzed by an analyzer there is, with the files it needs
+pub fn check(schema: &Schema, settings: &Settings) -> Result<()> {
+    for name in tokenizer_names(schema) {
+        if !TANTIVY_TOKENIZERS.contains(&name) && !is_custom(name) {
+            resolve(name, settings)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_analyzers() {
+        let text = "The Runners were RUNNING, état-civil";
+        assert_eq!(
+            Analyzer::standard().analyze(text),
+            vec!["the", "runners", "were", "running", "état", "civil"]
+        );
+        assert_eq!(
+            Analyzer::new(Split::Whitespace).analyze(text),
+            vec!["The", "Runners", "were", "RUNNING,", "état-civil"]
+        );
+        assert_eq!(Analyzer::new(Split::Raw).analyze(text), vec![text]);
+        let english = Analyzer::standard().with_stemmer(Language::English);
+        assert_eq!(english.analyze("running runs"), vec!["run", "run"]);
+        assert_eq!(
+            Analyzer::new(Split::Simple).with_max_token_length(3).analyze("a long way"),
+            vec!["a", "way"]
+        );
+
+        let mut settings = Settings::default();
+        settings.analyzers.insert("shouting".to_string(), Analyzer::new(Split::Whitespace));
+        let tokenizers = TokenizerManager::default();
+        register(&tokenizers, &Schema::builder().build(), &settings);
+        assert!(tokenizers.get("german").is_some());
+        assert!(tokenizers.get("shouting").is_some());
+        assert!(tokenizers.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_segmentation() {
+        let unicode = Analyzer::builtin().into_iter().find(|(name, _)| name == "unicode").unwrap().1;
+        assert_eq!(unicode.analyze("Can't pay 3.14€, O'Neil"), vec!["can't", "pay", "3.14", "o'neil"]);
+        let cjk = Analyzer::builtin().into_iter().find(|(name, _)| name == "cjk").unwrap().1;
+        assert_eq!(cjk.analyze("東京都に住む"), vec!["東京", "京都", "都に", "に住", "住む"]);
+        assert_eq!(cjk.analyze("Tokyo 東 and 한국어"), vec!["tokyo", "東", "and", "한국", "국어"]);
+        assert_eq!(cjk.analyze("東京 京都"), vec!["東京", "京都"]);
+        let positions: Vec<usize> = {
+            let mut stream = cjk.token_stream("北京大学");
+            let mut positions = Vec::new();
+            while stream.advance() {
+                positions.push(stream.token().position);
+            }
+            positions
+        };
+        assert_eq!(positions, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_custom_tokenizers() {
+        assert!(register_tokenizer("standard", Analyzer::new(Split::Raw)).is_err());
+        assert!(register_tokenizer("raw", Analyzer::new(Split::Raw)).is_err());
+        assert!(register_tokenizer("codes/none", Analyzer::new(Split::Raw)).is_err());
+        register_tokenizer("test_codes", Analyzer::new(Split::Whitespace)).unwrap();
+
+        let settings = Settings::default();
+        assert!(available(&settings).contains(&"test_codes".to_string()));
+        let tokenizers = TokenizerManager::default();
+        register(&tokenizers, &Schema::builder().build(), &settings);
+        assert!(tokenizers.get("test_codes").is_some());
+
+        let mut schema = json!([{ "name": "sku", "type": "text", "analyzer": "test_codes", "options": {} }]);
+        apply_analyzers(&mut schema).unwrap();
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+        assert!(check(&schema, &settings).is_ok());
+        assert!(resolve("test_codes/english", &settings).is_err());
+
+        let unknown = resolve("missing", &settings).unwrap_err().to_string();
+        assert!(unknown.contains("missing"));
+        assert!(unknown.contains("cjk, danish, default"));
+        assert!(unknown.contains("test_codes"));
+    }
+
+    #[test]
+    fn test_apply_analyzers() {
+        let mut schema = json!([
+            { "name": "title", "type": "text", "analyzer": "english", "options": { "stored": true } },
+            { "name": "body", "type": "text", "analyzer": "missing", "options": { "indexing": { "record": "freq", "tokenizer": "default" } } },
+            { "name": "year", "type": "u64", "options": { "indexed": true } }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(
+            schema[0]["options"]["indexing"],
+            json!({ "record": "position", "tokenizer": "english" })
+        );
+        assert_eq!(
+            schema[1]["options"]["indexing"],
+            json!({ "record": "freq", "tokenizer": "missing" })
+        );
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+        assert!(check(&schema, &Settings::default()).unwrap_err().to_string().contains("missing"));
+
+        let mut numeric = json!([{ "name": "year", "type": "u64", "analyzer": "english", "options": { "indexed": true } }]);
+        assert!(apply_analyzers(&mut numeric).is_err());
+    }
+
+    #[test]
+    fn test_stemmers() {
+        let mut schema = json!([
+            { "name": "title", "type": "text", "stemmer": "german", "options": { "stored": true } },
+            { "name": "sku", "type": "text", "analyzer": "english", "stemmer": "none", "options": { "stored": true } },
+            { "name": "tags", "type": "text", "analyzer": "tags", "stemmer": "french", "options": { "stored": true } }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "default/german");
+        assert_eq!(schema[1]["options"]["indexing"]["tokenizer"], "english/none");
+        assert_eq!(schema[2]["options"]["indexing"]["tokenizer"], "tags/french");
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let mut settings = Settings::default();
+        assert_eq!(
+            resolve("default/german", &settings).unwrap(),
+            Analyzer::standard().with_stemmer(Language::German)
+        );
+        assert_eq!(resolve("english/none", &settings).unwrap(), Analyzer::standard());
+        assert!(resolve("english/klingon", &settings).is_err());
+        assert!(check(&schema, &settings).unwrap_err().to_string().contains("tags"));
+        settings.analyzers.insert("tags".to_string(), Analyzer::new(Split::Whitespace));
+        assert!(check(&schema, &settings).is_ok());
+
+        let mut unknown = json!([{ "name": "title", "type": "text", "stemmer": "klingon", "options": {} }]);
+        assert!(apply_analyzers(&mut unknown).is_err());
+    }
+
+    #[test]
+    fn test_stop_words() {
+        let path = std::env::temp_dir().join("toshi-stop-words-test");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("english.txt"), "# articles\nThe\na\n\nan\n").unwrap();
+        let settings = Settings::from_str(&format!(
+            r#"
+            analysis_path = "{}"
+            [analyzers.articles]
+            lowercase = true
+            stop_words_file = "english.txt""#,
+            path.display()
+        ))
+        .unwrap();
+
+        let mut schema = json!([
+            { "name": "title", "type": "text", "stop_words": ["The", "of"], "stemmer": "english", "options": {} },
+            { "name": "body", "type": "text", "analyzer": "whitespace", "stop_words": "english.txt", "options": {} }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "default/stop=The,of/english");
+        assert_eq!(schema[1]["options"]["indexing"]["tokenizer"], "whitespace/stopfile=english.txt");
+
+        let title = resolve("default/stop=The,of/english", &settings).unwrap();
+        assert_eq!(title.analyze("The Lord of the Rings"), vec!["lord", "ring"]);
+        let body = resolve("whitespace/stopfile=english.txt", &settings).unwrap();
+        assert_eq!(body.analyze("the cat and The hat"), vec!["the", "cat", "and", "hat"]);
+        let articles = resolve("articles", &settings).unwrap();
+        assert_eq!(articles.analyze("The cat and a hat"), vec!["cat", "and", "hat"]);
+        assert!(resolve("default/stopfile=missing.txt", &settings).is_err());
+
+        let mut nested = json!([{ "name": "title", "type": "text", "stop_words": "../secrets", "options": {} }]);
+        assert!(apply_analyzers(&mut nested).is_err());
+    }
+
+    #[test]
+    fn test_synonyms() {
+        let mut schema = json!([
+            { "name": "title", "type": "text", "synonyms": [["TV", "television"], ["couch", "sofa"]], "options": {} },
+            { "name": "body", "type": "text", "synonyms": [["tv", "television"]], "synonyms_at": "query", "options": {} }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(
+            schema[0]["options"]["indexing"]["tokenizer"],
+            "default/syn=TV,television;couch,sofa"
+        );
+        assert_eq!(
+            schema[1]["options"]["indexing"]["tokenizer"],
+            "default/syn=tv,television/synat=query"
+        );
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let settings = Settings::default();
+        let title = resolve("default/syn=TV,television;couch,sofa", &settings).unwrap();
+        assert_eq!(title.analyze("Television on the Sofa"), vec!["tv", "on", "the", "couch"]);
+        let body = resolve("default/syn=tv,television/synat=query", &settings).unwrap();
+        assert_eq!(body.analyze("Television"), vec!["television"]);
+        assert_eq!(body.expansion().unwrap().analyze("Television"), vec!["tv", "television"]);
+        assert!(title.expansion().is_none());
+
+        let tokenizers = TokenizerManager::default();
+        register(&tokenizers, &schema, &settings);
+        let fields: Vec<Field> = schema.fields().iter().filter_map(|e| schema.get_field(e.name())).collect();
+        assert_eq!(
+            expand_synonyms("+body:TV sofa \"tv set\" AND title:tv", &schema, &tokenizers, &fields),
+            "+(body:tv OR body:television) sofa \"tv set\" AND title:tv"
+        );
+        assert_eq!(expand_synonyms("television", &schema, &tokenizers, &fields), "(tv OR television)");
+
+        let mut invalid = json!([{ "name": "title", "type": "text", "synonyms": [["a,b"]], "options": {} }]);
+        assert!(apply_analyzers(&mut invalid).is_err());
+        let mut invalid = json!([{ "name": "title", "type": "text", "synonyms_at": "never", "options": {} }]);
+        assert!(apply_analyzers(&mut invalid).is_err());
+    }
+
+    #[test]
+    fn test_ascii_folding() {
+        assert_eq!(fold("Résumé"), "Resume");
+        assert_eq!(fold("ﬁancée Straße Ærø Łódź"), "fiancee Strasse AEro Lodz");
+        assert_eq!(fold("東京"), "東京");
+
+        let mut schema = json!([
+            { "name": "title", "type": "text", "ascii_folding": true, "stop_words": ["à"], "options": {} },
+            { "name": "body", "type": "text", "ascii_folding": false, "options": {} }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "default/ascii/stop=à");
+        assert_eq!(schema[1]["options"]["indexing"]["tokenizer"], "default");
+
+        let title = resolve("default/ascii/stop=à", &Settings::default()).unwrap();
+        assert_eq!(title.analyze("Crème BRÛLÉE à la carte"), vec!["creme", "brulee", "la", "carte"]);
+        assert_eq!(title.analyze("creme brulee"), title.analyze("crème brûlée"));
+
+        let mut invalid = json!([{ "name": "title", "type": "text", "ascii_folding": "yes", "options": {} }]);
+        assert!(apply_analyzers(&mut invalid).is_err());
+    }
+
+    #[test]
+    fn test_html_strip() {
+        let html = "<p>Fish&amp;Chips</p><script>var x = '<b>';</script><!-- hidden -->caf&#233;<br/>b<b>ol</b>d &lt;3";
+        let (text, offsets) = strip_html(html);
+        assert_eq!(text, " Fish&Chips   café bold <3");
+        assert_eq!(offsets.len(), text.len() + 1);
+        assert_eq!(&html[offsets[1]..offsets[5]], "Fish");
+
+        let mut schema = json!([{ "name": "page", "type": "text", "html_strip": true, "ascii_folding": true, "options": {} }]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "default/html/ascii");
+
+        let page = resolve("default/html/ascii", &Settings::default()).unwrap();
+        assert_eq!(page.analyze(html), vec!["fish", "chips", "cafe", "bold", "3"]);
+        let mut stream = page.token_stream("<i>Caf&eacute;</i> <em>Noir</em>");
+        let mut spans = Vec::new();
+        while stream.advance() {
+            spans.push((stream.token().text.clone(), stream.token().offset_from, stream.token().offset_to));
+        }
+        assert_eq!(
+            spans,
+            vec![
+                ("caf".to_string(), 3, 6),
+                ("eacute".to_string(), 7, 13),
+                ("noir".to_string(), 23, 27)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let mut schema = json!([
+            { "name": "status", "type": "text", "keyword": { "normalizer": ["lowercase", "trim"] }, "options": { "stored": true } },
+            { "name": "code", "type": "text", "keyword": true, "options": {} },
+            { "name": "title", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" } } }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(
+            schema[0]["options"]["indexing"],
+            json!({ "record": "basic", "tokenizer": "keyword/lowercase/trim" })
+        );
+        assert_eq!(schema[1]["options"]["indexing"]["tokenizer"], "keyword");
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+        assert!(check(&schema, &Settings::default()).is_ok());
+
+        let field = |name| schema.get_field(name).unwrap();
+        assert_eq!(normalize(&schema, field("status"), "  In Progress "), "in progress");
+        assert_eq!(normalize(&schema, field("code"), " AB-12"), " AB-12");
+        assert_eq!(normalize(&schema, field("title"), "In Progress"), "In Progress");
+        let status = resolve("keyword/lowercase/trim", &Settings::default()).unwrap();
+        assert_eq!(status.analyze(" Done\n"), vec!["done"]);
+
+        let mut both = json!([{ "name": "status", "type": "text", "keyword": true, "analyzer": "english", "options": {} }]);
+        assert!(apply_analyzers(&mut both).is_err());
+        let mut unknown = json!([{ "name": "status", "type": "text", "keyword": { "normalizer": "uppercase" }, "options": {} }]);
+        assert!(apply_analyzers(&mut unknown).is_err());
+    }
+
+    #[test]
+    fn test_ngrams() {
+        let edge = Ngram::parse("1,3", true).unwrap();
+        assert_eq!(edge.grams("laptop"), vec!["l", "la", "lap"]);
+        let ngram = Ngram::parse("2, 3", false).unwrap();
+        assert_eq!(ngram.grams("été"), vec!["ét", "été", "té"]);
+        assert!(ngram.grams("a").is_empty());
+        assert!(Ngram::parse("3", false).is_none());
+
+        let mut schema = json!([
+            { "name": "title", "type": "text", "edge_ngram": { "min_gram": 2, "max_gram": 10 }, "options": {} },
+            { "name": "sku", "type": "text", "analyzer": "whitespace", "ngram": { "min_gram": 3, "max_gram": 3 }, "options": {} }
+        ]);
+        apply_analyzers(&mut schema).unwrap();
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "default/edgengram=2,10");
+        assert_eq!(schema[1]["options"]["indexing"]["tokenizer"], "whitespace/ngram=3,3");
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let settings = Settings::default();
+        let title = resolve("default/edgengram=2,10", &settings).unwrap();
+        assert_eq!(
+            title.analyze("Red Laptop"),
+            vec!["re", "red", "la", "lap", "lapt", "lapto", "laptop"]
+        );
+        assert_eq!(title.search().unwrap().analyze("Red Lap"), vec!["red", "lap"]);
+        assert!(Analyzer::standard().search().is_none());
+        assert!(resolve("default/ngram=3,2", &settings).is_err());
+        assert!(resolve("default/ngram=a,2", &settings).is_err());
+
+        let tokenizers = TokenizerManager::default();
+        register(&tokenizers, &schema, &settings);
+        let search = search_schema(&schema, &tokenizers);
+        let tokenizer = |schema: &Schema, name: &str| match schema.get_field_entry(schema.get_field(name).unwrap()).field_type() {
+            FieldType::Str(options) => options.get_indexing_options().unwrap().tokenizer().to_string(),
+            _ => String::new(),
+        };
+        assert_eq!(tokenizer(&search, "title"), "default/edgengram=2,10@search");
+        assert_eq!(tokenizer(&search, "sku"), "whitespace/ngram=3,3@search");
+
+        let mut invalid = json!([{ "name": "title", "type": "text", "ngram": { "min_gram": 0, "max_gram": 2 }, "options": {} }]);
+        assert!(apply_analyzers(&mut invalid).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Async searches run in the background, for searches that would take longer than a connection can be held open
+//! for, such as heavy aggregations over large indexes. Submitting one through `POST /:index/_async_search` answers
+//! straight away with an id, and `GET /_async_search/:id` then reports how far it has got until it's done. While a
+//! search over several indexes runs, the results of the indexes searched so far are reported, merged the way the
+//! finished search's are. A search's results are kept for its keep alive after it was submitted or last polled,
+//! and `DELETE /_async_search/:id` forgets them sooner, cancelling the search if it's still running.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future};
+use serde::Serialize;
+use serde_json::Value;
+use tower_web::Response;
+use uuid::Uuid;
+
+use crate::handlers::search::Searches;
+use crate::query::{run_pipelines, Pipeline, Sort};
+use crate::results::SearchResults;
+use crate::shard;
+use crate::tasks::Cancellation;
+use crate::{Error, Result};
+
+/// How long the results of a search are kept, in seconds, when it isn't given a keep alive
+pub const DEFAULT_KEEP_ALIVE: u64 = 300;
+
+#[derive(Response, Serialize, Debug)]
+pub struct AsyncSearchStatus {
+    /// A random id, so other clients' searches can't be guessed at
+    pub id: String,
+    pub index: String,
+    /// When the search was submitted, in seconds since the Unix epoch
+    pub started: u64,
+    pub is_running: bool,
+    /// Whether `response` is missing the results of some of the indexes searched
+    pub is_partial: bool,
+    /// Indexes searched so far, out of all the search covers
+    pub completed: usize,
+    pub total: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct AsyncSearch {
+    index: String,
+    started: u64,
+    keep_alive: Duration,
+    expires: Instant,
+    running: bool,
+    completed: usize,
+    total: usize,
+    sort: Option<Sort>,
+    limit: usize,
+    results: Option<SearchResults>,
+    error: Option<String>,
+    cancellation: Cancellation,
+}
+
+impl AsyncSearch {
+    /// Take in the results of one of the indexes searched
+    fn add(&mut self, searched: Result<SearchResults>) {
+        self.completed += 1;
+        match searched {
+            Ok(results) => {
+                self.results = Some(match self.results.take() {
+                    Some(merged) => shard::merge_results(vec![merged, results], self.sort.as_ref(), self.limit),
+                    None if self.total > 1 => shard::merge_results(vec![results], self.sort.as_ref(), self.limit),
+                    None => results,
+                })
+            }
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, pipelines: &BTreeMap<String, Pipeline>) {
+        self.running = false;
+        if self.error.is_some() {
+            // A search fails as a whole, as it would have if it weren't async
+            self.results = None;
+            return;
+        }
+        if let Some(ref mut buckets) = self.results.as_mut().and_then(|results| results.buckets.as_mut()) {
+            if let Err(e) = run_pipelines(pipelines, buckets) {
+                self.error = Some(e.to_string());
+                self.results = None;
+            }
+        }
+    }
+
+    fn status(&self, id: &str) -> Result<AsyncSearchStatus> {
+        Ok(AsyncSearchStatus {
+            id: id.to_string(),
+            index: self.index.clone(),
+            started: self.started,
+            is_running: self.running,
+            is_partial: self.completed < self.total || self.error.is_some(),
+            completed: self.completed,
+            total: self.total,
+            response: match self.results {
+                Some(ref results) => Some(serde_json::to_value(results)?),
+                None => None,
+            },
+            error: self.error.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AsyncSearches {
+    searches: Arc<RwLock<BTreeMap<String, Arc<Mutex<AsyncSearch>>>>>,
+}
+
+impl AsyncSearches {
+    /// Start keeping track of `searches` of `index`, returning the search's status and the work of running it, which
+    /// the caller spawns. `cancellation` is what the searches stop on, and is set when the search is removed.
+    pub fn start(
+        &self,
+        index: String,
+        searches: Searches,
+        sort: Option<Sort>,
+        limit: usize,
+        pipelines: BTreeMap<String, Pipeline>,
+        keep_alive: Duration,
+        cancellation: Cancellation,
+    ) -> Result<(AsyncSearchStatus, Box<Future<Item = (), Error = ()> + Send>)> {
+        let searches = match searches {
+            Searches::Single(search) => vec![search],
+            Searches::Many(searches) => searches,
+        };
+        let search = Arc::new(Mutex::new(AsyncSearch {
+            index,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default(),
+            keep_alive,
+            expires: Instant::now() + keep_alive,
+            running: true,
+            completed: 0,
+            total: searches.len(),
+            sort,
+            limit,
+            results: None,
+            error: None,
+            cancellation,
+        }));
+        let id = Uuid::new_v4().to_string();
+        let status = search.lock()?.status(&id)?;
+        {
+            let mut all = self.searches.write()?;
+            let now = Instant::now();
+            all.retain(|_, search| search.lock().map(|search| search.running || search.expires > now).unwrap_or(false));
+            all.insert(id, Arc::clone(&search));
+        }
+
+        let running = searches.into_iter().map(|part| {
+            let search = Arc::clone(&search);
+            part.then(move |searched| {
+                search.lock().unwrap_or_else(|e| e.into_inner()).add(searched);
+                Ok::<(), ()>(())
+            })
+        });
+        let work = future::join_all(running.collect::<Vec<_>>()).map(move |_| {
+            search.lock().unwrap_or_else(|e| e.into_inner()).finish(&pipelines);
+        });
+        Ok((status, Box::new(work)))
+    }
+
+    /// The status of the search `id`, keeping its results for another keep alive
+    pub fn get(&self, id: &str) -> Result<AsyncSearchStatus> {
+        let search = self
+            .searches
+            .read()?
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::IOError(format!("No async search {} is running or kept", id)))?;
+        let mut search = search.lock()?;
+        if !search.running && search.expires <= Instant::now() {
+            return Err(Error::IOError(format!("No async search {} is running or kept", id)));
+        }
+        search.expires = Instant::now() + search.keep_alive;
+        search.status(id)
+    }
+
+    /// Forget the search `id`, returning whether there was one. A search that's still running is cancelled, though
+    /// searches of other clusters carry on until they answer.
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let search = match self.searches.write()?.remove(id) {
+            Some(search) => search,
+            None => return Ok(false),
+        };
+        search.lock()?.cancellation.cancel();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::results::ScoredDoc;
+    use futures::sync::oneshot;
+    use tantivy::schema::NamedFieldDocument;
+
+    fn results(scores: &[f32]) -> SearchResults {
+        SearchResults::new(
+            scores
+                .iter()
+                .map(|score| ScoredDoc::new(Some(*score), NamedFieldDocument(BTreeMap::new())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_async_search() {
+        let searches = AsyncSearches::default();
+        let (first, first_done) = oneshot::channel::<Result<SearchResults>>();
+        let (second, second_done) = oneshot::channel::<Result<SearchResults>>();
+        let parts: Vec<Box<Future<Item = SearchResults, Error = Error> + Send>> = vec![
+            Box::new(first_done.map_err(|_| Error::SpawnError).and_then(|searched| searched)),
+            Box::new(second_done.map_err(|_| Error::SpawnError).and_then(|searched| searched)),
+        ];
+        let (status, work) = searches
+            .start(
+                "logs-*".into(),
+                Searches::Many(parts),
+                None,
+                2,
+                BTreeMap::new(),
+                Duration::from_secs(60),
+                Cancellation::default(),
+            )
+            .unwrap();
+        assert_eq!((status.is_running, status.total), (true, 2));
+        assert_eq!(Uuid::parse_str(&status.id).unwrap().get_version_num(), 4);
+        assert!(status.response.is_none());
+
+        first.send(Ok(results(&[1.0, 3.0]))).unwrap();
+        let work = future::lazy(move || {
+            let mut work = work;
+            let _ = work.poll();
+            Ok::<_, ()>(work)
+        })
+        .wait()
+        .unwrap();
+        let partial = searches.get(&status.id).unwrap();
+        assert!(partial.is_running && partial.is_partial);
+        assert_eq!(partial.response.unwrap()["hits"], 2);
+
+        second.send(Ok(results(&[2.0]))).unwrap();
+        work.wait().unwrap();
+        let done = searches.get(&status.id).unwrap();
+        assert!(!done.is_running && !done.is_partial);
+        let response = done.response.unwrap();
+        assert_eq!(response["docs"][0]["score"], 3.0);
+        assert_eq!(response["docs"][1]["score"], 2.0);
+        assert_eq!(response["hits"], 2);
+
+        assert!(searches.remove(&status.id).unwrap());
+        assert!(searches.get(&status.id).is_err());
+        assert!(!searches.remove(&status.id).unwrap());
+    }
+
+    #[test]
+    fn test_remove_cancels() {
+        let searches = AsyncSearches::default();
+        let cancellation = Cancellation::default();
+        let (_running, done) = oneshot::channel::<Result<SearchResults>>();
+        let (status, _work) = searches
+            .start(
+                "a".into(),
+                Searches::Single(Box::new(done.map_err(|_| Error::SpawnError).and_then(|searched| searched))),
+                None,
+                10,
+                BTreeMap::new(),
+                Duration::from_secs(60),
+                cancellation.clone(),
+            )
+            .unwrap();
+        assert!(!cancellation.is_cancelled());
+        assert!(searches.remove(&status.id).unwrap());
+        assert!(cancellation.is_cancelled());
+    }
+
+    #[test]
+    fn test_failed_search() {
+        let searches = AsyncSearches::default();
+        let parts: Vec<Box<Future<Item = SearchResults, Error = Error> + Send>> = vec![
+            Box::new(future::ok(results(&[1.0]))),
+            Box::new(future::err(Error::UnknownIndex("missing".into()))),
+        ];
+        let (status, work) = searches
+            .start(
+                "a,missing".into(),
+                Searches::Many(parts),
+                None,
+                10,
+                BTreeMap::new(),
+                Duration::from_secs(60),
+                Cancellation::default(),
+            )
+            .unwrap();
+        work.wait().unwrap();
+        let failed = searches.get(&status.id).unwrap();
+        assert!(!failed.is_running && failed.is_partial);
+        assert!(failed.response.is_none());
+        assert!(failed.error.unwrap().contains("missing"));
+
+        // Finished searches are forgotten once they've been kept for their keep alive
+        let (status, work) = searches
+            .start(
+                "a".into(),
+                Searches::Single(Box::new(future::ok(results(&[1.0])))),
+                None,
+                10,
+                BTreeMap::new(),
+                Duration::from_secs(0),
+                Cancellation::default(),
+            )
+            .unwrap();
+        work.wait().unwrap();
+        assert!(searches.get(&status.id).is_err());
+    }
+}
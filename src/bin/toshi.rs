@@ -1,95 +1,241 @@
+use std::io::{self, BufReader};
 use std::net::SocketAddr;
 use std::{
-    fs::create_dir,
+    fs::{self, create_dir},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use clap::{crate_authors, crate_description, crate_version, App, Arg, ArgMatches};
+use clap::{crate_authors, crate_description, crate_version, App, AppSettings, Arg, ArgMatches, SubCommand};
 use futures::{future, sync::oneshot, Future, Stream};
-use log::{error, info};
+use log::{error, info, LevelFilter};
+use tantivy::schema::Schema;
 use tokio::runtime::Runtime;
 
 use toshi::{
-    cluster::{self, rpc_server::RpcServer, Consul},
+    admin::{self, Target},
+    cluster::{
+        self, rebalance::Rebalancer, rpc_server::RpcServer, seeds::Membership, shipping::SegmentShipper, Consul, Etcd, Gossip, Kubernetes,
+        MetadataStore, Seeds,
+    },
     commit::IndexWatcher,
+    daemon::{self, PidFile},
+    executor::Executors,
     index::IndexCatalog,
+    lifecycle::Lifecycle,
+    mapping::Mappings,
+    reload::{self, Reloader},
     router::router_with_catalog,
-    settings::{Settings, HEADER, RPC_HEADER},
+    settings::{ConfigSource, Settings, HEADER, RPC_HEADER},
+    shard::Sharding,
+    snapshot::{self, SnapshotScheduler},
+    storage::{DirectoryType, StorageSettings},
 };
 
 pub fn main() -> Result<(), ()> {
-    let settings = settings();
+    let options = cli().get_matches();
+    if options.is_present("check-config") {
+        return check_config(&options);
+    }
+    match options.subcommand() {
+        ("index", Some(args)) => index(args),
+        ("build", Some(args)) => build(args),
+        ("dump", Some(args)) => dump(args),
+        ("inspect", Some(args)) => inspect(args),
+        ("service", Some(args)) => service(args),
+        ("serve", Some(args)) => serve_until(args, shutdown_signals()),
+        _ => serve_until(&options, shutdown_signals()),
+    }
+}
+
+/// Run the server until `stop` yields, which is a signal when run from a terminal or a stop request from the service manager
+fn serve_until<S>(options: &ArgMatches, stop: S) -> Result<(), ()>
+where
+    S: Stream<Item = String, Error = std::io::Error> + Send + 'static,
+{
+    let (config_source, settings) = settings(options);
+    if let Err(errors) = settings.validate() {
+        errors.iter().for_each(|e| eprintln!("error: {}", e));
+        std::process::exit(1);
+    }
+
+    #[cfg(unix)]
+    {
+        if options.is_present("daemonize") {
+            if let Err(e) = toshi::daemon::daemonize(options.value_of("log-file")) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let _pid_file = match options.value_of("pid-file").map(PidFile::create) {
+        Some(Err(e)) => {
+            eprintln!("Unable to write pid file: {}", e);
+            std::process::exit(1);
+        }
+        pid_file => pid_file,
+    };
 
-    std::env::set_var("RUST_LOG", &settings.log_level);
+    // Plain levels are enforced through the global max level so they can be changed on reload
+    if settings.log_level.parse::<LevelFilter>().is_ok() {
+        std::env::set_var("RUST_LOG", "trace");
+    } else {
+        std::env::set_var("RUST_LOG", &settings.log_level);
+    }
     pretty_env_logger::init();
+    reload::set_log_level(&settings.log_level);
     info!("{:?}", &settings);
 
     let mut rt = Runtime::new().expect("failed to start new Runtime");
 
     let (tx, shutdown_signal) = oneshot::channel();
 
-    if !Path::new(&settings.path).exists() {
-        info!("Base data path {} does not exist, creating it...", settings.path);
-        create_dir(settings.path.clone()).expect("Unable to create data directory");
+    for path in settings.data_paths().iter().filter(|p| !p.exists()) {
+        info!("Data path {} does not exist, creating it...", path.display());
+        create_dir(path).expect("Unable to create data directory");
     }
 
+    let lifecycle = Arc::new(if settings.enable_clustering {
+        Lifecycle::clustered()
+    } else {
+        Lifecycle::new()
+    });
+
     let index_catalog = {
-        let path = PathBuf::from(settings.path.clone());
-        let index_catalog = match IndexCatalog::new(path, settings.clone()) {
+        let index_catalog = match IndexCatalog::new(settings.data_paths(), settings.clone()) {
             Ok(v) => v,
             Err(e) => {
-                eprintln!("Error creating IndexCatalog from path {} - {}", settings.path, e);
+                eprintln!("Error creating IndexCatalog from paths {} - {}", settings.path.join(", "), e);
                 std::process::exit(1);
             }
         };
 
         Arc::new(RwLock::new(index_catalog))
     };
+    lifecycle.set_catalog_loaded();
+    let reloader = Arc::new(Reloader::new(config_source, Arc::clone(&index_catalog), settings.clone()));
 
     let toshi = {
         let server = if settings.master {
-            future::Either::A(run(index_catalog.clone(), &settings))
+            future::Either::A(run(index_catalog.clone(), Arc::clone(&lifecycle), Arc::clone(&reloader), &settings))
         } else {
             let addr = format!("{}:{}", &settings.host, settings.port);
             println!("{}", RPC_HEADER);
             info!("I am a data node...Binding to: {}", addr);
             let bind: SocketAddr = addr.parse().unwrap();
-            future::Either::B(RpcServer::get_service(bind, Arc::clone(&index_catalog)))
+            let membership = Membership::new(Some(addr.clone()));
+            let gossip = if settings.gossip.enabled {
+                Some(Gossip::new(addr.clone(), &settings.gossip))
+            } else {
+                None
+            };
+            let seeds = Seeds::new(settings.cluster_name.clone(), addr, &settings.seed_nodes, membership.clone());
+            let has_seeds = !settings.seed_nodes.is_empty();
+            let catalog = Arc::clone(&index_catalog);
+            let service = future::lazy(move || {
+                if let Some(ref gossip) = gossip {
+                    tokio::spawn(gossip.clone().run(bind));
+                }
+                if has_seeds {
+                    let join = seeds
+                        .join()
+                        .map(move |nodes| {
+                            info!("Joined a cluster of {} nodes", nodes.len());
+                            if let Some(gossip) = gossip {
+                                gossip.add_members(nodes);
+                            }
+                        })
+                        .map_err(|e| error!("Unable to join the cluster: {}", e));
+                    tokio::spawn(join);
+                }
+                // The listener is bound by the time the service is returned, so systemd is only told once it is
+                let service = RpcServer::get_service(bind, catalog, membership);
+                daemon::notify_ready();
+                service
+            });
+            future::Either::B(service)
         };
-        let shutdown = shutdown(tx);
+        let shutdown = handle_shutdown(tx, stop);
         server.select(shutdown)
     };
 
     rt.spawn(toshi.map(|_| ()).map_err(|_| ()));
+    rt.spawn(reload_on_hangup(reloader));
+    if let Some(interval) = daemon::watchdog_interval() {
+        info!("Pinging the systemd watchdog every {:?}", interval / 2);
+        rt.spawn(daemon::watchdog(interval));
+    }
 
     shutdown_signal
-        .map_err(|_| unreachable!("Shutdown signal channel should not error, This is a bug."))
-        .and_then(move |_| {
-            index_catalog
-                .write()
-                .expect("Unable to acquire write lock on index catalog")
-                .clear();
-            Ok(())
-        })
-        .and_then(move |_| rt.shutdown_now())
         .wait()
+        .expect("Shutdown signal channel should not error, This is a bug.");
+    daemon::notify_stopping();
+
+    let drain_timeout = Duration::from_secs(settings.drain_timeout);
+    if let Ok(summary) = rt.block_on(Lifecycle::drain(&lifecycle, Arc::clone(&index_catalog), drain_timeout)) {
+        if summary.in_flight > 0 {
+            error!("Shutting down with {} requests still in flight", summary.in_flight);
+        }
+    }
+
+    index_catalog
+        .write()
+        .expect("Unable to acquire write lock on index catalog")
+        .clear();
+    rt.shutdown_now().wait()
 }
 
-fn settings() -> Settings {
-    let options: ArgMatches = App::new("Toshi Search")
+fn cli() -> App<'static, 'static> {
+    App::new("Toshi Search")
         .version(crate_version!())
         .about(crate_description!())
         .author(crate_authors!())
         .arg(
             Arg::with_name("config")
+                .global(true)
                 .short("c")
                 .long("config")
                 .takes_value(true)
                 .default_value("config/config.toml"),
         )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("Validate the configuration and exit, with a non-zero status if it has errors"),
+        )
+        .arg(
+            Arg::with_name("pid-file")
+                .global(true)
+                .long("pid-file")
+                .help("Write the process id to this file while Toshi is running")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("daemonize")
+                .global(true)
+                .long("daemonize")
+                .help("Detach from the terminal and run in the background (Unix only)"),
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .global(true)
+                .long("log-file")
+                .help("Where a daemonized Toshi writes its output")
+                .takes_value(true)
+                .requires("daemonize"),
+        )
+        .arg(
+            Arg::with_name("config-format")
+                .global(true)
+                .long("config-format")
+                .help("Format of the configuration file, detected from its extension when omitted")
+                .takes_value(true)
+                .possible_values(&["toml", "yaml", "json"]),
+        )
         .arg(
             Arg::with_name("level")
+                .global(true)
                 .short("l")
                 .long("level")
                 .takes_value(true)
@@ -97,6 +243,7 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("path")
+                .global(true)
                 .short("d")
                 .long("data-path")
                 .takes_value(true)
@@ -104,6 +251,7 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("host")
+                .global(true)
                 .short("h")
                 .long("host")
                 .takes_value(true)
@@ -111,6 +259,7 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("port")
+                .global(true)
                 .short("p")
                 .long("port")
                 .takes_value(true)
@@ -118,6 +267,7 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("consul-addr")
+                .global(true)
                 .short("C")
                 .long("consul-addr")
                 .takes_value(true)
@@ -125,6 +275,7 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("cluster-name")
+                .global(true)
                 .short("N")
                 .long("cluster-name")
                 .takes_value(true)
@@ -132,29 +283,449 @@ fn settings() -> Settings {
         )
         .arg(
             Arg::with_name("enable-clustering")
+                .global(true)
                 .short("e")
                 .long("enable-clustering")
                 .takes_value(true),
         )
-        .get_matches();
+        .subcommand(SubCommand::with_name("serve").about("Run the Toshi server, this is the default when no command is given"))
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Build an index offline from newline delimited JSON documents")
+                .arg(
+                    Arg::with_name("schema")
+                        .long("schema")
+                        .help("JSON file with the index's schema")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .help("Newline delimited JSON documents, read from stdin when omitted")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .help("Directory to create the index in, e.g. data/my_index")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Write every stored document in an index to stdout as newline delimited JSON")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Show an index's schema, segments and term dictionary statistics")
+                .arg(Arg::with_name("name").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("service")
+                .about("Manage Toshi as a Windows service")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("install").about("Register Toshi with the service manager, using the current options"))
+                .subcommand(SubCommand::with_name("uninstall").about("Remove the Toshi service"))
+                .subcommand(SubCommand::with_name("run").about("Run as a service, this is what the service manager starts")),
+        )
+        .subcommand(
+            SubCommand::with_name("index")
+                .about("Administer indexes on a running server, or directly on the data path when no server is given")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .arg(
+                    Arg::with_name("server")
+                        .short("s")
+                        .long("server")
+                        .help("Base url of a running Toshi, e.g. http://localhost:8080")
+                        .takes_value(true),
+                )
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create an index from a JSON schema file")
+                        .arg(Arg::with_name("name").required(true))
+                        .arg(Arg::with_name("schema").long("schema").takes_value(true).required(true))
+                        .arg(
+                            Arg::with_name("data-path")
+                                .long("data-path")
+                                .takes_value(true)
+                                .help("Create the index in this directory instead of one of the data paths"),
+                        )
+                        .arg(
+                            Arg::with_name("directory")
+                                .long("directory")
+                                .takes_value(true)
+                                .possible_values(&["mmap", "ram"])
+                                .default_value("mmap")
+                                .help("Memory map the index from disk, or keep it entirely in memory"),
+                        )
+                        .arg(
+                            Arg::with_name("preload")
+                                .long("preload")
+                                .help("Read the index's files into memory whenever it's opened"),
+                        )
+                        .arg(
+                            Arg::with_name("sort-by")
+                                .long("sort-by")
+                                .takes_value(true)
+                                .help("A u64 or i64 fast field documents will be added in order of, like a timestamp"),
+                        )
+                        .arg(
+                            Arg::with_name("default-field")
+                                .long("default-field")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("A field raw queries search when they name none, as name or name^boost"),
+                        )
+                        .arg(
+                            Arg::with_name("shards")
+                                .long("shards")
+                                .takes_value(true)
+                                .help("Split the index into this many shards"),
+                        )
+                        .arg(
+                            Arg::with_name("routing-field")
+                                .long("routing-field")
+                                .takes_value(true)
+                                .requires("shards")
+                                .help("The field whose value picks each document's shard, the whole document by default"),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List every index"))
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete an index and all of its documents")
+                        .arg(Arg::with_name("name").required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("inspect")
+                        .about("Show an index's schema and segments")
+                        .arg(Arg::with_name("name").required(true)),
+                ),
+        )
+}
 
+fn settings(options: &ArgMatches) -> (Option<ConfigSource>, Settings) {
     match options.value_of("config") {
-        Some(v) => Settings::new(v).expect("Invalid configuration file"),
-        None => Settings::from_args(&options),
+        Some(v) => {
+            let source = ConfigSource::new(v, options.value_of("config-format").and_then(Settings::parse_format));
+            let settings = source.load().expect("Invalid configuration file");
+            (Some(source), settings)
+        }
+        None => (None, Settings::from_args(&options)),
     }
 }
 
-fn run(catalog: Arc<RwLock<IndexCatalog>>, settings: &Settings) -> impl Future<Item = (), Error = ()> {
-    let commit_watcher = if settings.auto_commit_duration > 0 {
-        let commit_watcher = IndexWatcher::new(catalog.clone(), settings.auto_commit_duration);
-        future::Either::A(future::lazy(move || {
-            commit_watcher.start();
-            future::ok::<(), ()>(())
-        }))
-    } else {
-        future::Either::B(future::ok::<(), ()>(()))
+fn index(args: &ArgMatches) -> Result<(), ()> {
+    let target = match args.value_of("server") {
+        Some(url) => Target::Server(url.trim_end_matches('/').to_string()),
+        None => Target::DataPath(settings(args).1.data_paths()),
+    };
+
+    let result = match args.subcommand() {
+        ("create", Some(create)) => fs::read_to_string(create.value_of("schema").unwrap())
+            .map_err(Into::into)
+            .and_then(|schema| {
+                let storage = StorageSettings {
+                    directory: match create.value_of("directory") {
+                        Some("ram") => DirectoryType::Ram,
+                        _ => DirectoryType::Mmap,
+                    },
+                    preload: create.is_present("preload"),
+                    sort_by: create.value_of("sort-by").map(String::from),
+                    mappings: Mappings::new(),
+                    default_fields: create
+                        .values_of("default-field")
+                        .map(|fields| fields.map(String::from).collect())
+                        .unwrap_or_default(),
+                };
+                let sharding = match create.value_of("shards").map(str::parse::<usize>) {
+                    Some(Ok(shards)) if shards != 1 => Some(Sharding::new(shards, create.value_of("routing-field").map(String::from))?),
+                    Some(Err(_)) => return Err(toshi::Error::IOError("--shards must be a number".into())),
+                    _ => None,
+                };
+                let name = create.value_of("name").unwrap();
+                target.create_index(name, &schema, create.value_of("data-path"), &storage, sharding.as_ref())
+            }),
+        ("list", _) => target.list_indexes().map(|names| names.join("\n")),
+        ("delete", Some(delete)) => target.delete_index(delete.value_of("name").unwrap()),
+        ("inspect", Some(inspect)) => target.inspect_index(inspect.value_of("name").unwrap()),
+        _ => unreachable!("clap requires an index subcommand"),
+    };
+
+    match result {
+        Ok(output) => {
+            println!("{}", output);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn service(args: &ArgMatches) -> Result<(), ()> {
+    let result = match args.subcommand() {
+        ("install", _) => windows::install(),
+        ("uninstall", _) => windows::uninstall(),
+        _ => windows::run(),
+    };
+    result.map_err(|e| {
+        eprintln!("Windows service error: {}", e);
+        std::process::exit(1);
+    })
+}
+
+#[cfg(not(windows))]
+fn service(_: &ArgMatches) -> Result<(), ()> {
+    eprintln!("Running as a service is only supported on Windows, use --daemonize or systemd instead");
+    std::process::exit(1);
+}
+
+/// Integration with the Windows service control manager. The service manager starts `toshi service run`
+/// with the options given at install time, and a stop request shuts Toshi down just like a signal would.
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo, ServiceStartType,
+        ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    const SERVICE_NAME: &str = "toshi";
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Hand the process over to the service control manager, which calls back into `service_main`
+    pub fn run() -> windows_service::Result<()> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+    }
+
+    fn service_main(_: Vec<OsString>) {
+        let (stop_tx, stop_rx) = mpsc::unbounded();
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop_tx.unbounded_send(String::from("SERVICE_STOP"));
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let status = match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(status) => status,
+            Err(e) => {
+                log::error!("Unable to register the service control handler: {}", e);
+                return;
+            }
+        };
+
+        set_state(
+            &status,
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        );
+        let options = super::cli().get_matches();
+        let stop = stop_rx.map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Service control channel closed"));
+        let exit_code = match super::serve_until(&options, stop) {
+            Ok(_) => 0,
+            Err(_) => 1,
+        };
+        set_state(&status, ServiceState::Stopped, ServiceControlAccept::empty(), exit_code);
+    }
+
+    fn set_state(status: &ServiceStatusHandle, state: ServiceState, accept: ServiceControlAccept, exit_code: u32) {
+        let result = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OwnProcess,
+            current_state: state,
+            controls_accepted: accept,
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+        });
+        if let Err(e) = result {
+            log::error!("Unable to report service state {:?}: {}", state, e);
+        }
+    }
+
+    /// Register the service to start `toshi service run` with the options this command was given, except
+    /// with the configuration path made absolute since services start in the system directory.
+    pub fn install() -> windows_service::Result<()> {
+        let mut arguments: Vec<OsString> = vec!["service".into(), "run".into()];
+        let mut args = std::env::args_os().skip(1).peekable();
+        while let Some(arg) = args.next() {
+            if arg == "service" || arg == "install" {
+                continue;
+            }
+            let is_config = arg == "-c" || arg == "--config";
+            arguments.push(arg);
+            if is_config {
+                if let Some(path) = args.next() {
+                    arguments.push(std::fs::canonicalize(&path).map(|p| p.into_os_string()).unwrap_or(path));
+                }
+            }
+        }
+
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CREATE_SERVICE)?;
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: "Toshi Search".into(),
+            service_type: ServiceType::OwnProcess,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe().expect("Unable to find the toshi executable"),
+            launch_arguments: arguments,
+            account_name: None,
+            account_password: None,
+        };
+        manager.create_service(info, ServiceAccess::empty())?;
+        println!("Installed the {} service", SERVICE_NAME);
+        Ok(())
+    }
+
+    pub fn uninstall() -> windows_service::Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::DELETE)?;
+        service.delete()?;
+        println!("Removed the {} service", SERVICE_NAME);
+        Ok(())
+    }
+}
+
+fn check_config(options: &ArgMatches) -> Result<(), ()> {
+    let settings = match options.value_of("config") {
+        Some(v) => ConfigSource::new(v, options.value_of("config-format").and_then(Settings::parse_format)).load(),
+        None => Ok(Settings::from_args(options)),
     };
 
+    let errors = match settings {
+        Ok(settings) => settings.validate().err().unwrap_or_default(),
+        Err(e) => vec![format!("Unable to parse configuration: {}", e)],
+    };
+    if errors.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    for error in errors {
+        eprintln!("error: {}", error);
+    }
+    std::process::exit(1);
+}
+
+fn build(args: &ArgMatches) -> Result<(), ()> {
+    let (_, settings) = settings(args);
+    let schema = fs::read_to_string(args.value_of("schema").unwrap())
+        .map_err(toshi::Error::from)
+        .and_then(|schema| serde_json::from_str::<Schema>(&schema).map_err(toshi::Error::from));
+    let output = Path::new(args.value_of("output").unwrap());
+
+    let summary = schema.and_then(|schema| match args.value_of("input") {
+        Some(input) => {
+            let input = BufReader::new(fs::File::open(input)?);
+            admin::build_index(schema, input, output, settings.writer_memory)
+        }
+        None => admin::build_index(schema, BufReader::new(io::stdin()), output, settings.writer_memory),
+    });
+
+    match summary {
+        Ok(summary) => {
+            println!(
+                "Indexed {} documents into {}, skipped {} invalid lines",
+                summary.indexed,
+                output.display(),
+                summary.skipped
+            );
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Find which data path holds the index named on the command line
+fn index_path(args: &ArgMatches) -> PathBuf {
+    let name = args.value_of("name").unwrap();
+    match IndexCatalog::find_index_path(&settings(args).1.data_paths(), name) {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", toshi::Error::UnknownIndex(name.into()));
+            std::process::exit(1);
+        }
+    }
+}
+
+fn dump(args: &ArgMatches) -> Result<(), ()> {
+    let path = index_path(args);
+    let stdout = io::stdout();
+    match admin::dump_index(&path, &mut stdout.lock()) {
+        Ok(count) => {
+            eprintln!("Dumped {} documents", count);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn inspect(args: &ArgMatches) -> Result<(), ()> {
+    let path = index_path(args);
+    match admin::index_stats(&path).and_then(|stats| serde_json::to_string_pretty(&stats).map_err(toshi::Error::from)) {
+        Ok(stats) => {
+            println!("{}", stats);
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run(
+    catalog: Arc<RwLock<IndexCatalog>>,
+    lifecycle: Arc<Lifecycle>,
+    reloader: Arc<Reloader>,
+    settings: &Settings,
+) -> impl Future<Item = (), Error = ()> {
+    // Shared by the router's handlers and the watcher's warm-ups
+    let executors = Executors::new(settings);
+    // The watcher always runs, an auto_commit_duration of 0 only pauses it so it can be turned on by a reload
+    let commit_watcher = IndexWatcher::new(catalog.clone(), settings.auto_commit_duration)
+        .with_settings(reloader.watch())
+        .with_executor(executors.search.clone());
+    // Snapshot policies run on every node, over the indexes it holds
+    let scheduler = match snapshot::repository(settings) {
+        Some(ref repository) if !settings.snapshot_policies.is_empty() => Some(SnapshotScheduler::new(
+            Arc::clone(&catalog),
+            Arc::clone(repository),
+            &settings.snapshot_policies,
+        )),
+        _ => None,
+    };
+    let commit_watcher = future::lazy(move || {
+        commit_watcher.start();
+        if let Some(scheduler) = scheduler {
+            tokio::spawn(scheduler.run());
+        }
+        future::ok::<(), ()>(())
+    });
+
     let addr = format!("{}:{}", &settings.host, settings.port);
     let bind: SocketAddr = addr.parse().expect("Failed to parse socket address");
 
@@ -162,74 +733,191 @@ fn run(catalog: Arc<RwLock<IndexCatalog>>, settings: &Settings) -> impl Future<I
 
     if settings.enable_clustering {
         let settings = settings.clone();
-        let place_addr = settings.place_addr.clone();
-        let consul_addr = settings.consul_addr.clone();
-        let cluster_name = settings.cluster_name.clone();
 
-        let run = future::lazy(move || connect_to_consul(&settings)).and_then(move |_| {
+        let nodes = settings.nodes.clone();
+        let rebalance = settings.rebalance.clone();
+        let (replicas, replication) = (settings.replicas.clone(), settings.replication.clone());
+        let registered = Arc::clone(&lifecycle);
+        let run = future::lazy(move || join_cluster(&settings)).and_then(move |_| {
+            registered.set_registered();
             tokio::spawn(commit_watcher);
+            if replication.ships_segments() && !replicas.is_empty() {
+                // Replicas fetch the segments they're shipped from the master's own RPC service
+                let ship_addr: SocketAddr = replication.ship_addr.parse().expect("Failed to parse ship address");
+                tokio::spawn(RpcServer::get_service(ship_addr, Arc::clone(&catalog), Membership::default()));
+                let shipper = SegmentShipper::new(Arc::clone(&catalog), replication.ship_addr, &replicas);
+                tokio::spawn(shipper.run(Duration::from_secs(replication.ship_interval_secs)));
+            }
+            if rebalance.enabled {
+                tokio::spawn(Rebalancer::new(Arc::clone(&catalog), &nodes, rebalance).run());
+            }
+            if !nodes.is_empty() {
+                // Searches on the master span every data node holding the index
+                tokio::spawn(IndexCatalog::follow_remote_catalogs(
+                    Arc::clone(&catalog),
+                    nodes,
+                    Duration::from_secs(5),
+                ));
+            }
 
-            let mut consul = Consul::builder()
-                .with_cluster_name(cluster_name)
-                .with_address(consul_addr)
-                .build()
-                .expect("Could not build Consul client.");
-
-            let place_addr = place_addr.parse().expect("Placement address must be a valid SocketAddr");
-            tokio::spawn(cluster::run(place_addr, consul).map_err(|e| error!("Error with running cluster: {}", e)));
-
-            router_with_catalog(&bind, &catalog)
+            start_router(&bind, &catalog, &lifecycle, &reloader, &executors)
         });
 
         future::Either::A(run)
     } else {
-        let run = commit_watcher.and_then(move |_| router_with_catalog(&bind, &catalog));
+        let run = commit_watcher.and_then(move |_| start_router(&bind, &catalog, &lifecycle, &reloader, &executors));
         future::Either::B(run)
     }
 }
 
-fn connect_to_consul(settings: &Settings) -> impl Future<Item = (), Error = ()> {
-    let consul_address = settings.consul_addr.clone();
-    let cluster_name = settings.cluster_name.clone();
-    let settings_path = settings.path.clone();
+/// Start serving HTTP, letting systemd know Toshi is ready once the listeners are bound
+fn start_router(
+    bind: &SocketAddr,
+    catalog: &Arc<RwLock<IndexCatalog>>,
+    lifecycle: &Arc<Lifecycle>,
+    reloader: &Arc<Reloader>,
+    executors: &Executors,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    match router_with_catalog(bind, catalog, lifecycle, reloader, executors) {
+        Ok(router) => {
+            daemon::notify_ready();
+            router
+        }
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Join the cluster through the embedded Raft group when it's enabled, and otherwise through the configured
+/// metadata store, which then backs the placement service
+fn join_cluster(settings: &Settings) -> Box<Future<Item = (), Error = ()> + Send> {
+    #[cfg(feature = "raft")]
+    {
+        if settings.raft.enabled {
+            return Box::new(start_raft(settings));
+        }
+    }
+    match settings.metadata_store.as_ref() {
+        "seeds" => {
+            let node_addr = format!("{}:{}", settings.host, settings.port);
+            let seeds = Seeds::new(
+                settings.cluster_name.clone(),
+                node_addr,
+                &settings.seed_nodes,
+                Membership::default(),
+            );
+            Box::new(register_node(seeds, settings))
+        }
+        "kubernetes" => {
+            let kubernetes = Kubernetes::in_cluster(settings.cluster_name.clone(), settings.kubernetes.clone())
+                .expect("Could not set up Kubernetes discovery.");
+            Box::new(register_node(kubernetes, settings))
+        }
+        "etcd" => {
+            let etcd = Etcd::builder()
+                .with_cluster_name(settings.cluster_name.clone())
+                .with_address(settings.etcd_addr.clone())
+                .with_node_addr(format!("{}:{}", settings.host, settings.port))
+                .build()
+                .expect("Could not build etcd client.");
+            Box::new(register_node(etcd, settings))
+        }
+        _ => {
+            let consul = Consul::builder()
+                .with_cluster_name(settings.cluster_name.clone())
+                .with_address(settings.consul_addr.clone())
+                .build()
+                .expect("Could not build Consul client.");
+            Box::new(register_node(consul, settings))
+        }
+    }
+}
+
+#[cfg(feature = "raft")]
+fn start_raft(settings: &Settings) -> impl Future<Item = (), Error = ()> {
+    use toshi::cluster::raft::RaftCluster;
 
+    let raft = settings.raft.clone();
+    let data_path = PathBuf::from(&settings.path[0]);
     future::lazy(move || {
-        let mut consul_client = Consul::builder()
-            .with_cluster_name(cluster_name)
-            .with_address(consul_address)
-            .build()
-            .expect("Could not build Consul client.");
-
-        // Build future that will connect to Consul and register the node_id
-        consul_client
+        let cluster = RaftCluster::new(&raft, &data_path).map_err(|e| error!("Error starting Raft: {}", e))?;
+        let addr = raft.addr.parse().expect("Raft address must be a valid SocketAddr");
+        info!("Joining Raft group as node {}", raft.node_id);
+        tokio::spawn(RaftCluster::run(cluster, addr, Duration::from_millis(raft.tick_ms)));
+        Ok(())
+    })
+}
+
+/// Register this node with `store`, then start the placement service on top of it
+fn register_node<S: MetadataStore>(mut store: S, settings: &Settings) -> impl Future<Item = (), Error = ()> {
+    let settings_path = settings.path[0].clone();
+    let place_addr: SocketAddr = settings.place_addr.parse().expect("Placement address must be a valid SocketAddr");
+    let node_addr = format!("{}:{}", settings.host, settings.port);
+    let gossip = if settings.gossip.enabled {
+        Some(Gossip::new(node_addr.clone(), &settings.gossip))
+    } else {
+        None
+    };
+
+    future::lazy(move || {
+        if let Some(ref gossip) = gossip {
+            let bind = node_addr.parse().expect("Node address must be a valid SocketAddr");
+            tokio::spawn(gossip.clone().run(bind));
+        }
+        // Build future that will connect to the store and register the node_id
+        store
             .register_cluster()
             .and_then(|_| cluster::init_node_id(settings_path))
             .and_then(move |id| {
-                consul_client.set_node_id(id);
-                consul_client.register_node()
+                store.set_node_id(id);
+                store.register_node().map(move |_| store)
+            })
+            .map(move |store| {
+                tokio::spawn(cluster::run(place_addr, store, gossip).map_err(|e| error!("Error with running cluster: {}", e)));
             })
             .map_err(|e| error!("Error: {}", e))
     })
 }
 
 #[cfg(unix)]
-fn shutdown(signal: oneshot::Sender<()>) -> impl Future<Item = (), Error = ()> {
+fn reload_on_hangup(reloader: Arc<Reloader>) -> impl Future<Item = (), Error = ()> {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .for_each(move |_| {
+            info!("Received signal: SIGHUP, reloading settings");
+            if let Err(e) = reloader.reload() {
+                error!("Failed to reload settings: {}", e);
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("Error listening for SIGHUP: {}", e))
+}
+#[cfg(not(unix))]
+fn reload_on_hangup(_: Arc<Reloader>) -> impl Future<Item = (), Error = ()> {
+    future::ok(())
+}
+
+#[cfg(unix)]
+fn shutdown_signals() -> impl Stream<Item = String, Error = std::io::Error> {
     use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
 
     let sigint = Signal::new(SIGINT).flatten_stream().map(|_| String::from("SIGINT"));
     let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| String::from("SIGTERM"));
 
-    handle_shutdown(signal, sigint.select(sigterm))
+    sigint.select(sigterm)
 }
 #[cfg(not(unix))]
-fn shutdown(signal: oneshot::Sender<()>) -> impl Future<Item = (), Error = ()> {
-    let stream = tokio_signal::ctrl_c().flatten_stream().map(|_| String::from("ctrl-r"));
-    handle_shutdown(signal, stream)
+fn shutdown_signals() -> impl Stream<Item = String, Error = std::io::Error> {
+    tokio_signal::ctrl_c().flatten_stream().map(|_| String::from("ctrl-r"))
 }
 
 fn handle_shutdown<S>(signal: oneshot::Sender<()>, stream: S) -> impl Future<Item = (), Error = ()>
 where
-    S: Stream<Item = String, Error = std::io::Error>,
+    S: Stream<Item = String, Error = std::io::Error> + Send + 'static,
 {
     stream
         .take(1)
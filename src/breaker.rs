@@ -0,0 +1,173 @@
+//! A circuit breaker on the memory searches take, so a search that would take more than the node can spare fails
+//! with an error rather than taking the whole process down with it. Searches account for what grows with the data
+//! they read, the hits they collect and load and the buckets of their aggregations, against a budget of their own of
+//! `request_bytes` and against `total_bytes` shared by every search running at once. What a search has taken is given
+//! back once it's done. The amounts are estimates, close enough to tell a search that's about to run away from one that
+//! isn't, rather than exact measures of the heap.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tantivy::schema::{Document, Value};
+
+use crate::settings::CircuitBreakerSettings;
+use crate::{Error, Result};
+
+/// Roughly what a bucket of an aggregation takes while it's collected, with its place in the map of buckets
+pub const BUCKET_BYTES: usize = 64;
+/// Roughly what a hit takes while the best of them are collected, before its document is loaded
+pub const HIT_BYTES: usize = 32;
+
+/// The memory every search running at once has taken between them. One from `CircuitBreaker::default()` has no
+/// limits, and budgets from it account for nothing.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    request_limit: usize,
+    total_limit: usize,
+    used: AtomicUsize,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        CircuitBreaker {
+            request_limit: settings.request_bytes,
+            total_limit: settings.total_bytes,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// The bytes searches running now have taken
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// A budget for one search, which gives back what it took once it's dropped
+    pub fn budget(breaker: &Arc<CircuitBreaker>) -> MemoryBudget {
+        if breaker.request_limit == 0 && breaker.total_limit == 0 {
+            return MemoryBudget::default();
+        }
+        MemoryBudget(Some(Arc::new(Reservation {
+            breaker: Arc::clone(breaker),
+            reserved: AtomicUsize::new(0),
+            tripped: Mutex::new(None),
+        })))
+    }
+}
+
+/// What a search has taken, shared by the collectors of each of its segments. One from `MemoryBudget::default()` is
+/// unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget(Option<Arc<Reservation>>);
+
+#[derive(Debug)]
+struct Reservation {
+    breaker: Arc<CircuitBreaker>,
+    reserved: AtomicUsize,
+    /// Why the search went over its budget, once it has
+    tripped: Mutex<Option<String>>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.breaker.used.fetch_sub(*self.reserved.get_mut(), Ordering::SeqCst);
+    }
+}
+
+impl MemoryBudget {
+    /// Take `bytes` more, failing if that would go over the search's limit or the node's. Collectors that can't fail
+    /// there and then stop taking more, and the search fails once they're done with `check`.
+    pub fn reserve(&self, bytes: usize) -> Result<()> {
+        let reservation = match self.0 {
+            Some(ref reservation) => reservation,
+            None => return Ok(()),
+        };
+        self.check()?;
+        let breaker = &reservation.breaker;
+        let reserved = reservation.reserved.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        let used = breaker.used.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        let exceeded = if breaker.request_limit > 0 && reserved > breaker.request_limit {
+            format!("it would take more than request_bytes, {} bytes", breaker.request_limit)
+        } else if breaker.total_limit > 0 && used > breaker.total_limit {
+            format!(
+                "searches would take more than total_bytes, {} bytes, between them",
+                breaker.total_limit
+            )
+        } else {
+            return Ok(());
+        };
+        reservation.reserved.fetch_sub(bytes, Ordering::SeqCst);
+        breaker.used.fetch_sub(bytes, Ordering::SeqCst);
+        let error = format!("Search aborted by the circuit breaker: {}", exceeded);
+        reservation
+            .tripped
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert(error.clone());
+        Err(Error::QueryError(error))
+    }
+
+    /// An error if the search has gone over its budget
+    pub fn check(&self) -> Result<()> {
+        if let Some(ref reservation) = self.0 {
+            if let Some(ref error) = *reservation.tripped.lock().unwrap_or_else(|e| e.into_inner()) {
+                return Err(Error::QueryError(error.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The bytes the search has taken
+    pub fn reserved(&self) -> usize {
+        self.0.as_ref().map_or(0, |reservation| reservation.reserved.load(Ordering::SeqCst))
+    }
+}
+
+/// Roughly what `doc` takes once it's loaded
+pub fn doc_bytes(doc: &Document) -> usize {
+    doc.field_values()
+        .iter()
+        .map(|field_value| match field_value.value() {
+            Value::Str(text) => HIT_BYTES + text.len(),
+            Value::Bytes(bytes) => HIT_BYTES + bytes.len(),
+            _ => HIT_BYTES,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budgets() {
+        let settings = CircuitBreakerSettings {
+            request_bytes: 100,
+            total_bytes: 150,
+        };
+        let breaker = Arc::new(CircuitBreaker::new(&settings));
+        let first = CircuitBreaker::budget(&breaker);
+        let second = CircuitBreaker::budget(&breaker);
+        first.reserve(60).unwrap();
+        first.clone().reserve(30).unwrap();
+        assert_eq!((first.reserved(), breaker.used()), (90, 90));
+
+        // Over its own limit
+        assert!(first.reserve(20).unwrap_err().to_string().contains("request_bytes"));
+        assert!(first.check().is_err());
+        assert!(first.reserve(1).is_err());
+        assert_eq!(breaker.used(), 90);
+
+        // Over what every search can take between them
+        second.reserve(50).unwrap();
+        assert!(second.reserve(20).unwrap_err().to_string().contains("total_bytes"));
+
+        drop(first);
+        drop(second);
+        assert_eq!(breaker.used(), 0);
+
+        let unlimited = CircuitBreaker::budget(&Arc::new(CircuitBreaker::default()));
+        unlimited.reserve(usize::max_value() / 2).unwrap();
+        assert!(unlimited.check().is_ok());
+        assert_eq!(unlimited.reserved(), 0);
+    }
+}
@@ -14,6 +14,7 @@ use tower_service::Service;
 use crate::cluster::shard::PrimaryShard;
 use crate::cluster::shard::ReplicaShard;
 use crate::cluster::shard::Shard;
+use crate::cluster::store::{MetadataStore, StoreFuture};
 use crate::cluster::ClusterError;
 use crate::{Error, Result};
 
@@ -51,39 +52,8 @@ impl Consul {
             .map_err(|err| Error::IOError(err.to_string()))
     }
 
-    /// Registers this node with Consul via HTTP API
-    pub fn register_node(&mut self) -> impl Future<Item = (), Error = ClusterError> {
-        let key = "toshi/".to_owned() + &self.cluster_name() + "/" + &self.node_id();
-        self.client
-            .set(&key, Vec::new())
-            .map(|_| ())
-            .map_err(|err| ClusterError::FailedRegisteringNode(format!("{:?}", err)))
-    }
-
-    /// Registers a cluster with Consul via the HTTP API
-    pub fn register_cluster(&mut self) -> impl Future<Item = (), Error = ClusterError> {
-        let key = "toshi/".to_owned() + &self.cluster_name();
-        self.client
-            .set(&key, Vec::new())
-            .map(|_| ())
-            .map_err(|err| ClusterError::FailedRegisteringNode(format!("{:?}", err)))
-    }
-
-    /// Registers a shard with the Consul cluster
-    pub fn register_shard<T>(&mut self, shard: &T) -> impl Future<Item = (), Error = ClusterError>
-    where
-        T: Shard + Serialize,
-    {
-        let key = format!("toshi/{}/{}", self.cluster_name(), shard.shard_id().to_hyphenated_ref());
-        let shard = serde_json::to_vec(&shard).unwrap();
-
-        self.client
-            .set(&key, shard)
-            .map(|_| ())
-            .map_err(|err| ClusterError::FailedCreatingPrimaryShard(format!("{:?}", err)))
-    }
-
-    pub fn nodes(&mut self) -> impl Future<Item = Vec<ConsulService>, Error = ClusterError> {
+    /// The services registered as Toshi nodes
+    pub fn services(&mut self) -> impl Future<Item = Vec<ConsulService>, Error = ClusterError> {
         self.client
             .service_nodes("toshi")
             .map_err(|err| ClusterError::FailedFetchingNodes(format!("{:?}", err)))
@@ -91,26 +61,67 @@ impl Consul {
 
     /// Gets the specified index
     pub fn get_index(&mut self, index: String, recurse: bool) -> impl Future<Item = Vec<KVValue>, Error = ClusterError> {
-        let key = format!("toshi/{}/{}?recurse={}", &self.cluster_name(), &index, recurse);
+        let key = format!("toshi/{}/{}?recurse={}", &self.cluster_name, &index, recurse);
         self.client
             .get(&key)
             .map_err(|err| ClusterError::FailedGettingIndex(format!("{:?}", err)))
     }
+}
 
-    /// Get a reference to the cluster name
-    pub fn cluster_name(&self) -> &String {
+impl MetadataStore for Consul {
+    fn cluster_name(&self) -> &str {
         &self.cluster_name
     }
 
-    /// Get a reference to the current node id
-    pub fn node_id(&self) -> &String {
+    fn node_id(&self) -> &str {
         &self.node_id
     }
 
-    /// Set the node id for the current node
-    pub fn set_node_id(&mut self, new_id: String) {
+    fn set_node_id(&mut self, new_id: String) {
         self.node_id = new_id;
     }
+
+    /// Registers a cluster with Consul via the HTTP API
+    fn register_cluster(&mut self) -> StoreFuture<()> {
+        let key = "toshi/".to_owned() + &self.cluster_name;
+        let set = self
+            .client
+            .set(&key, Vec::new())
+            .map(|_| ())
+            .map_err(|err| ClusterError::FailedRegisteringNode(format!("{:?}", err)));
+        Box::new(set)
+    }
+
+    /// Registers this node with Consul via HTTP API
+    fn register_node(&mut self) -> StoreFuture<()> {
+        let key = "toshi/".to_owned() + &self.cluster_name + "/" + &self.node_id;
+        let set = self
+            .client
+            .set(&key, Vec::new())
+            .map(|_| ())
+            .map_err(|err| ClusterError::FailedRegisteringNode(format!("{:?}", err)));
+        Box::new(set)
+    }
+
+    /// Registers a shard with the Consul cluster
+    fn register_shard<T>(&mut self, shard: &T) -> StoreFuture<()>
+    where
+        T: Shard + Serialize,
+    {
+        let key = format!("toshi/{}/{}", self.cluster_name, shard.shard_id().to_hyphenated_ref());
+        let shard = serde_json::to_vec(&shard).unwrap();
+
+        let set = self
+            .client
+            .set(&key, shard)
+            .map(|_| ())
+            .map_err(|err| ClusterError::FailedCreatingPrimaryShard(format!("{:?}", err)));
+        Box::new(set)
+    }
+
+    fn nodes(&mut self) -> StoreFuture<Vec<String>> {
+        Box::new(self.services().map(|services| services.into_iter().map(|s| s.address).collect()))
+    }
 }
 
 #[derive(Default, Clone)]
@@ -0,0 +1,238 @@
+//! Provides an interface to an etcd cluster, through the JSON gateway of its v3 API
+
+use futures::{future, stream::Stream, Future};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::shard::Shard;
+use crate::cluster::store::{MetadataStore, StoreFuture};
+use crate::cluster::ClusterError;
+use crate::{Error, Result};
+
+/// etcd connection client, clones share the underlying connection pool
+#[derive(Clone)]
+pub struct Etcd {
+    address: String,
+    cluster_name: String,
+    node_id: String,
+    node_addr: String,
+    client: Client<HttpConnector>,
+}
+
+#[derive(Serialize)]
+struct PutRequest {
+    key: String,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct RangeRequest {
+    key: String,
+    range_end: String,
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    #[serde(default)]
+    value: String,
+}
+
+impl Etcd {
+    /// Create a builder instance
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    fn nodes_prefix(&self) -> String {
+        format!("toshi/{}/nodes/", self.cluster_name)
+    }
+
+    fn call<T: Serialize>(&self, method: &str, body: &T) -> impl Future<Item = Vec<u8>, Error = ClusterError> {
+        let request = serde_json::to_vec(body)
+            .map_err(|e| ClusterError::ErrorInEtcdResponse(e.to_string()))
+            .and_then(|body| {
+                let uri: Uri = format!("http://{}/v3/kv/{}", self.address, method)
+                    .parse()
+                    .map_err(|e: hyper::http::uri::InvalidUri| ClusterError::ErrorInEtcdResponse(e.to_string()))?;
+                Request::post(uri)
+                    .header("content-type", "application/json")
+                    .body(Body::from(body))
+                    .map_err(|e| ClusterError::ErrorInEtcdResponse(e.to_string()))
+            });
+        let client = self.client.clone();
+        future::result(request).and_then(move |request| {
+            client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response.into_body().concat2().map(move |body| (status, body))
+                })
+                .map_err(|e| ClusterError::ErrorInEtcdResponse(e.to_string()))
+                .and_then(|(status, body)| {
+                    if status.is_success() {
+                        Ok(body.to_vec())
+                    } else {
+                        Err(ClusterError::ErrorInEtcdResponse(String::from_utf8_lossy(&body).into_owned()))
+                    }
+                })
+        })
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> impl Future<Item = (), Error = ClusterError> {
+        let request = PutRequest {
+            key: base64::encode(key),
+            value: base64::encode(value),
+        };
+        self.call("put", &request).map(|_| ())
+    }
+
+    /// The values of every key starting with `prefix`
+    fn get_prefix(&self, prefix: &str) -> impl Future<Item = Vec<Vec<u8>>, Error = ClusterError> {
+        let request = RangeRequest {
+            key: base64::encode(prefix),
+            range_end: base64::encode(&prefix_end(prefix.as_bytes())),
+        };
+        self.call("range", &request).and_then(|body| {
+            let response: RangeResponse = serde_json::from_slice(&body).map_err(|e| ClusterError::ErrorInEtcdResponse(e.to_string()))?;
+            response
+                .kvs
+                .into_iter()
+                .map(|kv| base64::decode(&kv.value).map_err(|e| ClusterError::ErrorInEtcdResponse(e.to_string())))
+                .collect()
+        })
+    }
+}
+
+/// The key just past every key starting with `prefix`, which etcd takes as the end of a range
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every byte was 0xff, so the range runs to the end of the keyspace
+    vec![0]
+}
+
+impl MetadataStore for Etcd {
+    fn cluster_name(&self) -> &str {
+        &self.cluster_name
+    }
+
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    fn register_cluster(&mut self) -> StoreFuture<()> {
+        let key = format!("toshi/{}", self.cluster_name);
+        Box::new(
+            self.put(&key, &[])
+                .map_err(|err| ClusterError::FailedRegisteringCluster(err.to_string())),
+        )
+    }
+
+    fn register_node(&mut self) -> StoreFuture<()> {
+        let key = format!("{}{}", self.nodes_prefix(), self.node_id);
+        Box::new(
+            self.put(&key, self.node_addr.as_bytes())
+                .map_err(|err| ClusterError::FailedRegisteringNode(err.to_string())),
+        )
+    }
+
+    fn register_shard<T>(&mut self, shard: &T) -> StoreFuture<()>
+    where
+        T: Shard + Serialize,
+    {
+        let key = format!("toshi/{}/{}", self.cluster_name, shard.shard_id().to_hyphenated_ref());
+        let shard = serde_json::to_vec(&shard).unwrap();
+        Box::new(
+            self.put(&key, &shard)
+                .map_err(|err| ClusterError::FailedCreatingPrimaryShard(err.to_string())),
+        )
+    }
+
+    fn nodes(&mut self) -> StoreFuture<Vec<String>> {
+        let nodes = self
+            .get_prefix(&self.nodes_prefix())
+            .map(|values| values.into_iter().filter_map(|v| String::from_utf8(v).ok()).collect())
+            .map_err(|err| ClusterError::FailedFetchingNodes(err.to_string()));
+        Box::new(nodes)
+    }
+}
+
+#[derive(Default, Clone)]
+/// Builder struct for Etcd
+pub struct Builder {
+    address: Option<String>,
+    cluster_name: Option<String>,
+    node_id: Option<String>,
+    node_addr: Option<String>,
+}
+
+impl Builder {
+    /// Sets the address of an etcd member
+    pub fn with_address(mut self, address: String) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    /// Sets the *Toshi* cluster name
+    pub fn with_cluster_name(mut self, cluster_name: String) -> Self {
+        self.cluster_name = Some(cluster_name);
+        self
+    }
+
+    /// Sets the ID of this specific node in the Toshi cluster
+    pub fn with_node_id(mut self, node_id: String) -> Self {
+        self.node_id = Some(node_id);
+        self
+    }
+
+    /// Sets the address other nodes reach this one on, which is what it's registered under
+    pub fn with_node_addr(mut self, node_addr: String) -> Self {
+        self.node_addr = Some(node_addr);
+        self
+    }
+
+    pub fn build(self) -> Result<Etcd> {
+        let address = self.address.unwrap_or_else(|| "127.0.0.1:2379".into());
+        let node_addr = self
+            .node_addr
+            .ok_or_else(|| Error::IOError("etcd needs the node's address to register it".into()))?;
+
+        Ok(Etcd {
+            address,
+            cluster_name: self.cluster_name.unwrap_or_else(|| "kitsune".into()),
+            node_id: self.node_id.unwrap_or_else(|| "alpha".into()),
+            node_addr,
+            client: Client::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"toshi/kitsune/nodes/"), b"toshi/kitsune/nodes0".to_vec());
+        assert_eq!(prefix_end(&[b'a', 0xff]), b"b".to_vec());
+        assert_eq!(prefix_end(&[0xff, 0xff]), vec![0]);
+        assert!(Etcd::builder().build().is_err());
+        assert!(Etcd::builder().with_node_addr("10.0.0.1:8080".into()).build().is_ok());
+    }
+}
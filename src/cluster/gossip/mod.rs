@@ -0,0 +1,118 @@
+//! Gossip-based failure detection. Nodes probe each other over UDP on the same port they serve on, so a node that
+//! goes down is noticed within a few probe intervals rather than whenever the metadata store next catches up.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{future, sync::mpsc, Future, Sink, Stream};
+use log::{debug, error, info};
+use tokio::codec::BytesCodec;
+use tokio::net::{UdpFramed, UdpSocket};
+use tokio::timer::Interval;
+
+use crate::settings::GossipSettings;
+
+pub mod swim;
+
+pub use self::swim::{MemberState, Message, Swim, Timing};
+
+/// How often, in milliseconds, the protocol is advanced. Every other interval is counted in these.
+pub const TICK_MS: u64 = 100;
+
+/// A node's view of cluster membership, clones share it
+#[derive(Clone)]
+pub struct Gossip {
+    swim: Arc<Mutex<Swim>>,
+}
+
+impl Gossip {
+    /// Membership as seen by the node other members reach at `addr`
+    pub fn new(addr: String, settings: &GossipSettings) -> Self {
+        let ticks = |ms: u64| (ms / TICK_MS).max(1);
+        let timing = Timing {
+            probe_interval: ticks(settings.probe_interval_ms),
+            probe_timeout: ticks(settings.probe_timeout_ms),
+            suspect_timeout: ticks(settings.suspect_timeout_ms),
+            indirect_checks: settings.indirect_checks,
+        };
+        Gossip {
+            swim: Arc::new(Mutex::new(Swim::new(addr, timing))),
+        }
+    }
+
+    pub fn add_members<I: IntoIterator<Item = String>>(&self, addrs: I) {
+        self.swim.lock().unwrap().add_members(addrs);
+    }
+
+    /// Whether `addr` has failed. Members gossip hasn't heard of yet are given the benefit of the doubt.
+    pub fn is_dead(&self, addr: &str) -> bool {
+        self.swim.lock().unwrap().state(addr) == Some(MemberState::Dead)
+    }
+
+    pub fn live_members(&self) -> Vec<String> {
+        self.swim.lock().unwrap().live_members()
+    }
+
+    /// Gossip over UDP on `bind` until an error stops it
+    pub fn run(self, bind: SocketAddr) -> impl Future<Item = (), Error = ()> {
+        future::lazy(move || {
+            let socket = UdpSocket::bind(&bind).map_err(|e| error!("Unable to bind gossip to {}: {}", bind, e))?;
+            info!("Gossiping on {}", bind);
+            Ok(socket)
+        })
+        .and_then(move |socket| {
+            let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+            let (tx, rx) = mpsc::unbounded();
+
+            let send = rx
+                .forward(sink.sink_map_err(|e| error!("Unable to send gossip: {}", e)))
+                .map(|_| ());
+
+            let gossip = self.clone();
+            let outbox = tx.clone();
+            let receive = stream
+                .for_each(move |(bytes, from)| {
+                    match serde_json::from_slice::<Message>(&bytes) {
+                        Ok(message) => {
+                            let mut swim = gossip.swim.lock().unwrap();
+                            swim.step(message);
+                            flush(&mut swim, &outbox);
+                        }
+                        Err(e) => debug!("Ignoring malformed gossip from {}: {}", from, e),
+                    }
+                    Ok(())
+                })
+                .map_err(|e| error!("Unable to receive gossip: {}", e));
+
+            let ticks = Interval::new_interval(Duration::from_millis(TICK_MS))
+                .for_each(move |_| {
+                    let mut swim = self.swim.lock().unwrap();
+                    swim.tick();
+                    flush(&mut swim, &tx);
+                    Ok(())
+                })
+                .map_err(|e| error!("Gossip timer failed: {}", e));
+
+            send.select(receive)
+                .map(|_| ())
+                .map_err(|_| ())
+                .select(ticks)
+                .map(|_| ())
+                .map_err(|_| ())
+        })
+    }
+}
+
+fn flush(swim: &mut Swim, outbox: &mpsc::UnboundedSender<(Bytes, SocketAddr)>) {
+    for (to, message) in swim.take_messages() {
+        let addr = match to.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => continue,
+        };
+        if let Ok(bytes) = serde_json::to_vec(&message) {
+            let _ = outbox.unbounded_send((Bytes::from(bytes), addr));
+        }
+    }
+}
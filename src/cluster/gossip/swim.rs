@@ -0,0 +1,422 @@
+//! The SWIM membership protocol, kept free of any I/O like the Raft node. Every probe interval a node pings the
+//! next member in turn. A member that doesn't answer is pinged indirectly through a few others, and if that fails
+//! too it's suspected, then declared dead unless it refutes the suspicion in time. Changes to membership ride
+//! along on the pings and acks themselves, so they spread through the cluster without any extra messages.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+/// What one node believes about another, and what's gossiped between them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Update {
+    pub addr: String,
+    pub state: MemberState,
+    /// Only the member itself raises this, to refute suspicion about it
+    pub incarnation: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Message {
+    Ping {
+        from: String,
+        updates: Vec<Update>,
+    },
+    /// `target` is the member that answered, which differs from the sender when an ack is relayed
+    Ack {
+        target: String,
+        updates: Vec<Update>,
+    },
+    PingReq {
+        from: String,
+        target: String,
+        updates: Vec<Update>,
+    },
+}
+
+/// How many ticks each stage of failure detection takes
+#[derive(Clone, Copy, Debug)]
+pub struct Timing {
+    pub probe_interval: u64,
+    pub probe_timeout: u64,
+    pub suspect_timeout: u64,
+    pub indirect_checks: usize,
+}
+
+struct Probe {
+    target: String,
+    sent: u64,
+    indirect: bool,
+}
+
+pub struct Swim {
+    me: String,
+    incarnation: u64,
+    members: BTreeMap<String, Update>,
+    timing: Timing,
+    now: u64,
+    next_probe: u64,
+    probe: Option<Probe>,
+    order: usize,
+    suspected_at: HashMap<String, u64>,
+    /// Members this node is pinging on someone else's behalf, and who asked
+    relays: HashMap<String, String>,
+    /// Updates still to be gossiped, with how many more times each will be sent
+    gossip: Vec<(Update, usize)>,
+    outbox: Vec<(String, Message)>,
+}
+
+impl Swim {
+    pub fn new(me: String, timing: Timing) -> Self {
+        Swim {
+            me,
+            incarnation: 0,
+            members: BTreeMap::new(),
+            timing,
+            now: 0,
+            next_probe: 0,
+            probe: None,
+            order: 0,
+            suspected_at: HashMap::new(),
+            relays: HashMap::new(),
+            gossip: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Start tracking members found some other way, such as from seed nodes or a discovery service
+    pub fn add_members<I: IntoIterator<Item = String>>(&mut self, addrs: I) {
+        for addr in addrs {
+            if addr != self.me && !self.members.contains_key(&addr) {
+                let update = Update {
+                    addr,
+                    state: MemberState::Alive,
+                    incarnation: 0,
+                };
+                self.members.insert(update.addr.clone(), update);
+            }
+        }
+    }
+
+    pub fn state(&self, addr: &str) -> Option<MemberState> {
+        if addr == self.me {
+            return Some(MemberState::Alive);
+        }
+        self.members.get(addr).map(|m| m.state)
+    }
+
+    /// Members that aren't known to be dead, this node included
+    pub fn live_members(&self) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|m| m.state != MemberState::Dead)
+            .map(|m| m.addr.clone())
+            .chain(Some(self.me.clone()))
+            .collect()
+    }
+
+    pub fn tick(&mut self) {
+        self.now += 1;
+
+        let expired: Vec<String> = self
+            .suspected_at
+            .iter()
+            .filter(|(_, since)| self.now - **since >= self.timing.suspect_timeout)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in expired {
+            let incarnation = self.members.get(&addr).map(|m| m.incarnation).unwrap_or(0);
+            self.apply(Update {
+                addr,
+                state: MemberState::Dead,
+                incarnation,
+            });
+        }
+
+        if let Some(probe) = self.probe.take() {
+            if self.now - probe.sent < self.timing.probe_timeout {
+                self.probe = Some(probe);
+            } else if !probe.indirect {
+                self.ping_indirectly(probe.target);
+            } else {
+                let incarnation = self.members.get(&probe.target).map(|m| m.incarnation).unwrap_or(0);
+                self.apply(Update {
+                    addr: probe.target,
+                    state: MemberState::Suspect,
+                    incarnation,
+                });
+            }
+        }
+
+        if self.probe.is_none() && self.now >= self.next_probe {
+            self.next_probe = self.now + self.timing.probe_interval;
+            if let Some(target) = self.next_target() {
+                let ping = self.ping();
+                self.outbox.push((target.clone(), ping));
+                self.probe = Some(Probe {
+                    target,
+                    sent: self.now,
+                    indirect: false,
+                });
+            }
+        }
+    }
+
+    pub fn step(&mut self, message: Message) {
+        match message {
+            Message::Ping { from, updates } => {
+                self.receive(&from, updates);
+                let ack = Message::Ack {
+                    target: self.me.clone(),
+                    updates: self.piggyback(),
+                };
+                self.outbox.push((from, ack));
+            }
+            Message::PingReq { from, target, updates } => {
+                self.receive(&from, updates);
+                self.relays.insert(target.clone(), from);
+                let ping = self.ping();
+                self.outbox.push((target, ping));
+            }
+            Message::Ack { target, updates } => {
+                self.receive(&target, updates);
+                if self.probe.as_ref().map(|p| p.target == target).unwrap_or(false) {
+                    self.probe = None;
+                }
+                if let Some(requester) = self.relays.remove(&target) {
+                    let ack = Message::Ack {
+                        target,
+                        updates: self.piggyback(),
+                    };
+                    self.outbox.push((requester, ack));
+                }
+            }
+        }
+    }
+
+    /// Messages waiting to be sent, paired with the member each is for
+    pub fn take_messages(&mut self) -> Vec<(String, Message)> {
+        mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    /// Hearing from a member directly shows it's alive, whatever was gossiped about it
+    fn receive(&mut self, from: &str, updates: Vec<Update>) {
+        self.add_members(Some(from.to_string()));
+        match self.members.get(from).map(|m| m.state) {
+            Some(MemberState::Suspect) => {
+                self.suspected_at.remove(from);
+                if let Some(member) = self.members.get_mut(from) {
+                    member.state = MemberState::Alive;
+                }
+            }
+            Some(MemberState::Dead) => {
+                // A member that was declared dead has come back, so tell it. It refutes that with a new
+                // incarnation, which outranks its death everywhere else too.
+                if let Some(dead) = self.members.get(from).cloned() {
+                    self.spread(dead);
+                }
+            }
+            _ => {}
+        }
+        for update in updates {
+            self.apply(update);
+        }
+    }
+
+    fn apply(&mut self, update: Update) {
+        if update.addr == self.me {
+            // Refute anything but aliveness by outliving the incarnation it was claimed at
+            if update.state != MemberState::Alive && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                let alive = Update {
+                    addr: self.me.clone(),
+                    state: MemberState::Alive,
+                    incarnation: self.incarnation,
+                };
+                self.spread(alive);
+            }
+            return;
+        }
+        let newer = match self.members.get(&update.addr) {
+            None => true,
+            Some(known) => match update.state {
+                MemberState::Alive => update.incarnation > known.incarnation,
+                MemberState::Suspect => {
+                    known.state == MemberState::Alive && update.incarnation >= known.incarnation || update.incarnation > known.incarnation
+                }
+                MemberState::Dead => known.state != MemberState::Dead && update.incarnation >= known.incarnation,
+            },
+        };
+        if !newer {
+            return;
+        }
+        match update.state {
+            MemberState::Suspect => {
+                self.suspected_at.entry(update.addr.clone()).or_insert(self.now);
+            }
+            _ => {
+                self.suspected_at.remove(&update.addr);
+            }
+        }
+        self.members.insert(update.addr.clone(), update.clone());
+        self.spread(update);
+    }
+
+    /// Queue an update to be piggybacked on the next few messages, enough for it to reach everyone
+    fn spread(&mut self, update: Update) {
+        let sends = 3 * (64 - (self.members.len() as u64 + 1).leading_zeros() as usize).max(1);
+        self.gossip.retain(|(queued, _)| queued.addr != update.addr);
+        self.gossip.push((update, sends));
+    }
+
+    fn piggyback(&mut self) -> Vec<Update> {
+        let updates = self.gossip.iter().map(|(u, _)| u.clone()).collect();
+        for (_, sends) in self.gossip.iter_mut() {
+            *sends -= 1;
+        }
+        self.gossip.retain(|(_, sends)| *sends > 0);
+        updates
+    }
+
+    fn ping(&mut self) -> Message {
+        Message::Ping {
+            from: self.me.clone(),
+            updates: self.piggyback(),
+        }
+    }
+
+    /// The next member to probe, going round every member that isn't dead
+    fn next_target(&mut self) -> Option<String> {
+        let candidates: Vec<&String> = self
+            .members
+            .values()
+            .filter(|m| m.state != MemberState::Dead)
+            .map(|m| &m.addr)
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        self.order = (self.order + 1) % candidates.len();
+        Some(candidates[self.order].clone())
+    }
+
+    fn ping_indirectly(&mut self, target: String) {
+        let helpers: Vec<String> = self
+            .members
+            .values()
+            .filter(|m| m.state == MemberState::Alive && m.addr != target)
+            .map(|m| m.addr.clone())
+            .take(self.timing.indirect_checks)
+            .collect();
+        for helper in helpers {
+            let request = Message::PingReq {
+                from: self.me.clone(),
+                target: target.clone(),
+                updates: self.piggyback(),
+            };
+            self.outbox.push((helper, request));
+        }
+        self.probe = Some(Probe {
+            target,
+            sent: self.now,
+            indirect: true,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMING: Timing = Timing {
+        probe_interval: 2,
+        probe_timeout: 2,
+        suspect_timeout: 6,
+        indirect_checks: 2,
+    };
+
+    fn cluster(size: usize) -> BTreeMap<String, Swim> {
+        let addrs: Vec<String> = (0..size).map(|i| format!("10.0.0.{}:8080", i)).collect();
+        addrs
+            .iter()
+            .map(|addr| {
+                let mut swim = Swim::new(addr.clone(), TIMING);
+                swim.add_members(addrs.clone());
+                (addr.clone(), swim)
+            })
+            .collect()
+    }
+
+    fn run(nodes: &mut BTreeMap<String, Swim>, down: &[&str], ticks: usize) {
+        for _ in 0..ticks {
+            let mut in_flight = Vec::new();
+            for (_, node) in nodes.iter_mut().filter(|(addr, _)| !down.contains(&addr.as_str())) {
+                node.tick();
+                in_flight.extend(node.take_messages());
+            }
+            while let Some((to, message)) = in_flight.pop() {
+                if down.contains(&to.as_str()) {
+                    continue;
+                }
+                let node = nodes.get_mut(&to).unwrap();
+                node.step(message);
+                in_flight.extend(node.take_messages());
+            }
+        }
+    }
+
+    #[test]
+    fn test_failure_detection() {
+        let mut nodes = cluster(4);
+        run(&mut nodes, &[], 20);
+        assert!(nodes.values().all(|n| n.live_members().len() == 4));
+
+        let failed = "10.0.0.2:8080";
+        run(&mut nodes, &[failed], 40);
+        for (addr, node) in nodes.iter().filter(|(addr, _)| addr.as_str() != failed) {
+            assert_eq!(
+                node.state(failed),
+                Some(MemberState::Dead),
+                "{} should have found the failure",
+                addr
+            );
+            assert_eq!(node.live_members().len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_refuting_suspicion() {
+        let mut nodes = cluster(3);
+        run(&mut nodes, &[], 10);
+        let suspect = Update {
+            addr: "10.0.0.1:8080".into(),
+            state: MemberState::Suspect,
+            incarnation: 0,
+        };
+        nodes.get_mut("10.0.0.0:8080").unwrap().apply(suspect);
+        run(&mut nodes, &[], 30);
+        assert!(nodes.values().all(|n| n.state("10.0.0.1:8080") == Some(MemberState::Alive)));
+        assert!(nodes["10.0.0.1:8080"].incarnation > 0);
+    }
+
+    #[test]
+    fn test_rejoining() {
+        let mut nodes = cluster(3);
+        let failed = "10.0.0.1:8080";
+        run(&mut nodes, &[failed], 40);
+        assert_eq!(nodes["10.0.0.0:8080"].state(failed), Some(MemberState::Dead));
+
+        run(&mut nodes, &[], 40);
+        assert!(nodes.values().all(|n| n.state(failed) == Some(MemberState::Alive)));
+        assert!(nodes.values().all(|n| n.live_members().len() == 3));
+    }
+}
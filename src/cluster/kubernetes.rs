@@ -0,0 +1,217 @@
+//! Finds the other nodes of a cluster running on Kubernetes, either by asking the API server for the pods matching a
+//! label selector or by resolving a headless service. Kubernetes already keeps track of which pods exist, so there's
+//! nothing for a node to register.
+
+use std::fs;
+use std::net::ToSocketAddrs;
+
+use futures::{future, stream::Stream, Future};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::shard::Shard;
+use crate::cluster::store::{MetadataStore, StoreFuture};
+use crate::cluster::ClusterError;
+use crate::settings::KubernetesSettings;
+use crate::{Error, Result};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const API_SERVER: &str = "https://kubernetes.default.svc";
+
+#[derive(Clone)]
+pub struct Kubernetes {
+    cluster_name: String,
+    node_id: String,
+    settings: KubernetesSettings,
+    api: Option<ApiClient>,
+}
+
+#[derive(Clone)]
+struct ApiClient {
+    client: Client<HttpsConnector<HttpConnector>>,
+    token: String,
+    namespace: String,
+}
+
+#[derive(Deserialize)]
+struct PodList {
+    #[serde(default)]
+    items: Vec<Pod>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    #[serde(default)]
+    status: PodStatus,
+}
+
+#[derive(Deserialize, Default)]
+struct PodStatus {
+    #[serde(default)]
+    phase: String,
+    #[serde(rename = "podIP")]
+    pod_ip: Option<String>,
+}
+
+impl Kubernetes {
+    /// Discovery for a node running in a pod. Without a headless service to resolve, this uses the pod's
+    /// service account to talk to the API server.
+    pub fn in_cluster(cluster_name: String, settings: KubernetesSettings) -> Result<Self> {
+        let api = match settings.service {
+            Some(_) => None,
+            None => Some(ApiClient::in_cluster(settings.namespace.clone())?),
+        };
+        Ok(Kubernetes {
+            cluster_name,
+            node_id: String::new(),
+            settings,
+            api,
+        })
+    }
+
+    fn pods(&self, api: &ApiClient) -> impl Future<Item = Vec<String>, Error = ClusterError> {
+        let uri = format!(
+            "{}/api/v1/namespaces/{}/pods?labelSelector={}",
+            API_SERVER,
+            api.namespace,
+            encode_query(&self.settings.label_selector)
+        );
+        let request = Request::get(uri)
+            .header("authorization", format!("Bearer {}", api.token))
+            .body(Body::empty())
+            .map_err(|e| ClusterError::ErrorInKubernetesResponse(e.to_string()));
+        let client = api.client.clone();
+        let port = self.settings.port;
+        future::result(request).and_then(move |request| {
+            client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response.into_body().concat2().map(move |body| (status, body))
+                })
+                .map_err(|e| ClusterError::ErrorInKubernetesResponse(e.to_string()))
+                .and_then(move |(status, body)| {
+                    if !status.is_success() {
+                        return Err(ClusterError::ErrorInKubernetesResponse(String::from_utf8_lossy(&body).into_owned()));
+                    }
+                    let pods: PodList =
+                        serde_json::from_slice(&body).map_err(|e| ClusterError::ErrorInKubernetesResponse(e.to_string()))?;
+                    Ok(pod_addrs(pods, port))
+                })
+        })
+    }
+
+    fn resolve(&self, service: String) -> impl Future<Item = Vec<String>, Error = ClusterError> {
+        let port = self.settings.port;
+        future::poll_fn(move || {
+            tokio_threadpool::blocking(|| {
+                (service.as_str(), port)
+                    .to_socket_addrs()
+                    .map(|addrs| addrs.map(|addr| addr.to_string()).collect())
+            })
+            .map_err(|e| ClusterError::ErrorInKubernetesResponse(e.to_string()))
+        })
+        .and_then(|resolved: std::io::Result<Vec<String>>| resolved.map_err(|e| ClusterError::ErrorInKubernetesResponse(e.to_string())))
+    }
+}
+
+impl ApiClient {
+    fn in_cluster(namespace: Option<String>) -> Result<Self> {
+        let token = fs::read_to_string(format!("{}/token", SERVICE_ACCOUNT))?;
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => fs::read_to_string(format!("{}/namespace", SERVICE_ACCOUNT))?,
+        };
+        let ca = fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT))?;
+        let tls = native_tls::Certificate::from_pem(&ca)
+            .and_then(|ca| native_tls::TlsConnector::builder().add_root_certificate(ca).build())
+            .map_err(|e| Error::IOError(format!("Unable to trust the Kubernetes CA: {}", e)))?;
+        let mut http = HttpConnector::new(1);
+        http.enforce_http(false);
+        Ok(ApiClient {
+            client: Client::builder().build(HttpsConnector::from((http, tls))),
+            token: token.trim().to_string(),
+            namespace: namespace.trim().to_string(),
+        })
+    }
+}
+
+/// The address of every running pod that has been given an IP
+fn pod_addrs(pods: PodList, port: u16) -> Vec<String> {
+    pods.items
+        .into_iter()
+        .filter(|pod| pod.status.phase == "Running")
+        .filter_map(|pod| pod.status.pod_ip)
+        .map(|ip| format!("{}:{}", ip, port))
+        .collect()
+}
+
+/// Percent-encode a label selector such as `app=toshi,tier in (search)` for use in a query string
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl MetadataStore for Kubernetes {
+    fn cluster_name(&self) -> &str {
+        &self.cluster_name
+    }
+
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    fn register_cluster(&mut self) -> StoreFuture<()> {
+        Box::new(future::ok(()))
+    }
+
+    fn register_node(&mut self) -> StoreFuture<()> {
+        Box::new(future::ok(()))
+    }
+
+    fn register_shard<T>(&mut self, _: &T) -> StoreFuture<()>
+    where
+        T: Shard + Serialize,
+    {
+        Box::new(future::err(ClusterError::FailedCreatingPrimaryShard(
+            "Kubernetes discovery doesn't store shards".into(),
+        )))
+    }
+
+    fn nodes(&mut self) -> StoreFuture<Vec<String>> {
+        match (&self.settings.service, &self.api) {
+            (Some(service), _) => Box::new(self.resolve(service.clone())),
+            (None, Some(api)) => Box::new(self.pods(api)),
+            (None, None) => Box::new(future::ok(Vec::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pod_addrs() {
+        let pods = r#"{ "items": [
+            { "status": { "phase": "Running", "podIP": "10.1.0.4" } },
+            { "status": { "phase": "Pending" } },
+            { "status": { "phase": "Running", "podIP": "10.1.0.5" } },
+            { "status": { "phase": "Succeeded", "podIP": "10.1.0.6" } }
+        ] }"#;
+        let addrs = pod_addrs(serde_json::from_str(pods).unwrap(), 8080);
+        assert_eq!(addrs, vec!["10.1.0.4:8080", "10.1.0.5:8080"]);
+        assert_eq!(encode_query("app=toshi,tier in (search)"), "app%3Dtoshi%2Ctier%20in%20%28search%29");
+    }
+}
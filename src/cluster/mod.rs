@@ -9,7 +9,12 @@ use tokio::net::tcp::ConnectFuture;
 use tokio::net::TcpStream;
 
 pub use self::consul::Consul;
+pub use self::etcd::Etcd;
+pub use self::gossip::Gossip;
+pub use self::kubernetes::Kubernetes;
 pub use self::node::*;
+pub use self::seeds::Seeds;
+pub use self::store::MetadataStore;
 use tower_h2::client::ConnectError;
 
 pub mod placement_proto {
@@ -30,26 +35,48 @@ pub mod cluster_rpc {
     include!(concat!(env!("OUT_DIR"), "\\cluster_rpc.rs"));
 }
 
+#[cfg(feature = "raft")]
+pub mod raft_rpc {
+    use prost_derive::Message;
+
+    #[cfg(target_family = "unix")]
+    include!(concat!(env!("OUT_DIR"), "/raft_rpc.rs"));
+    #[cfg(target_family = "windows")]
+    include!(concat!(env!("OUT_DIR"), "\\raft_rpc.rs"));
+}
+
 pub mod consul;
+pub mod etcd;
+pub mod gossip;
+pub mod kubernetes;
 pub mod node;
+#[cfg(feature = "raft")]
+pub mod raft;
 
 mod placement;
+pub mod remote_cluster;
 pub mod remote_handle;
+pub mod replication;
+pub mod rebalance;
+pub mod routing;
 pub mod rpc_server;
+pub mod seeds;
 pub mod shard;
+pub mod shipping;
+pub mod store;
 
 use self::placement::{Background, Place};
 
-/// Run the services associated with the cluster
-pub fn run(place_addr: SocketAddr, consul: Consul) -> impl Future<Item = (), Error = std::io::Error> {
+/// Run the services associated with the cluster, leaving out of placement any node `gossip` finds has failed
+pub fn run<S: MetadataStore>(place_addr: SocketAddr, store: S, gossip: Option<Gossip>) -> impl Future<Item = (), Error = std::io::Error> {
     future::lazy(move || {
-        let (nodes, bg) = Background::new(consul.clone(), Duration::from_secs(2));
+        let (nodes, bg) = Background::new(store.clone(), Duration::from_secs(2), gossip);
 
         tokio::spawn(bg.map_err(|e| error!("Error in background placement sync: {:?}", e)));
 
         // TODO: add cluster service et al
 
-        Place::serve(consul, nodes, place_addr)
+        Place::serve(store, nodes, place_addr)
     })
 }
 
@@ -95,6 +122,10 @@ pub enum ClusterError {
     ErrorParsingConsulJSON(String),
     #[fail(display = "Request from Consul returned an error: {}", _0)]
     ErrorInConsulResponse(String),
+    #[fail(display = "Request to etcd returned an error: {}", _0)]
+    ErrorInEtcdResponse(String),
+    #[fail(display = "Request to Kubernetes returned an error: {}", _0)]
+    ErrorInKubernetesResponse(String),
     #[fail(display = "Unable to get index handle")]
     UnableToGetIndexHandle,
     #[fail(display = "Unable to store services")]
@@ -1,4 +1,6 @@
-use crate::cluster::{consul::Consul, ClusterError};
+use crate::cluster::gossip::Gossip;
+use crate::cluster::store::StoreFuture;
+use crate::cluster::{ClusterError, MetadataStore};
 use futures::{sync::mpsc, try_ready, Future, Poll};
 use futures_watch::{Store, Watch};
 use log::debug;
@@ -7,12 +9,12 @@ use std::hash::Hash;
 use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use tokio::timer::Delay;
-use tower_consul::ConsulService;
 use tower_discover::Change;
 use tower_service::Service;
 
-pub struct Background {
-    consul: Consul,
+pub struct Background<S> {
+    metadata: S,
+    gossip: Option<Gossip>,
     // TODO: better D/S for this?
     store: Store<HashSet<SocketAddr>>,
     nodes: HashSet<SocketAddr>,
@@ -20,16 +22,18 @@ pub struct Background {
     interval: Duration,
 }
 
-impl Background {
-    pub fn new(mut consul: Consul, interval: Duration) -> (Watch<HashSet<SocketAddr>>, Self) {
+impl<S: MetadataStore> Background<S> {
+    /// With `gossip`, nodes it has found to be dead are left out of placement even while the store still lists them
+    pub fn new(mut metadata: S, interval: Duration, gossip: Option<Gossip>) -> (Watch<HashSet<SocketAddr>>, Self) {
         let (watch, mut store) = Watch::new(HashSet::new());
 
         store.store(HashSet::new()).expect("Unable to store inital placement bg watch");
 
-        let state = State::Fetching(Box::new(consul.nodes()));
+        let state = State::Fetching(metadata.nodes());
 
         let bg = Background {
-            consul,
+            metadata,
+            gossip,
             store,
             nodes: HashSet::new(),
             state,
@@ -40,7 +44,7 @@ impl Background {
     }
 }
 
-impl Future for Background {
+impl<S: MetadataStore> Future for Background<S> {
     type Item = ();
     type Error = Error;
 
@@ -50,15 +54,22 @@ impl Future for Background {
                 State::Fetching(ref mut fut) => {
                     let services = try_ready!(fut.poll());
 
-                    debug!("Got {} services from consul", services.len());
+                    debug!("Got {} nodes from the metadata store", services.len());
 
-                    let services = services.into_iter().map(|e| e.address.parse().unwrap()).collect::<HashSet<_>>();
+                    let services = match self.gossip {
+                        Some(ref gossip) => {
+                            gossip.add_members(services.iter().cloned());
+                            services.into_iter().filter(|addr| !gossip.is_dead(addr)).collect()
+                        }
+                        None => services,
+                    };
+                    let services = services.into_iter().filter_map(|addr| addr.parse().ok()).collect::<HashSet<_>>();
 
                     self.store.store(services).map_err(|_| ClusterError::UnableToStoreServices)?;
 
                     let deadline = Instant::now() + self.interval;
 
-                    debug!("Waiting {:?} duration till next consul refresh", deadline);
+                    debug!("Waiting {:?} duration till next node refresh", deadline);
 
                     let delay = Delay::new(deadline);
 
@@ -68,7 +79,7 @@ impl Future for Background {
                 State::Waiting(ref mut fut) => {
                     try_ready!(fut.poll());
 
-                    self.state = State::Fetching(Box::new(self.consul.nodes()));
+                    self.state = State::Fetching(self.metadata.nodes());
                 }
             }
         }
@@ -76,7 +87,7 @@ impl Future for Background {
 }
 
 enum State {
-    Fetching(Box<Future<Item = Vec<ConsulService>, Error = ClusterError> + Send>),
+    Fetching(StoreFuture<Vec<String>>),
     Waiting(Delay),
 }
 
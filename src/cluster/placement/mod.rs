@@ -1,5 +1,5 @@
-use crate::cluster::consul::Consul;
 use crate::cluster::placement_proto::{server, PlacementReply, PlacementRequest};
+use crate::cluster::MetadataStore;
 use futures::{future, try_ready, Async, Future, Poll, Stream};
 use futures_watch::Watch;
 use log::error;
@@ -23,17 +23,17 @@ type GrpcFuture<T> = Box<Future<Item = Response<T>, Error = Error> + Send + 'sta
 /// The placement service for toshi. Its role is to
 /// tell the cluster where to place writes and reads.
 #[derive(Clone)]
-pub struct Place {
-    consul: Consul,
+pub struct Place<S> {
+    store: S,
     nodes: Watch<HashSet<SocketAddr>>,
 }
 
-impl Place {
+impl<S: MetadataStore> Place<S> {
     /// Bind a tcp listener on the provided address and
     /// spawn a new service on each incoming connection.
-    pub fn serve(consul: Consul, nodes: Watch<HashSet<SocketAddr>>, addr: SocketAddr) -> impl Future<Item = (), Error = std::io::Error> {
+    pub fn serve(store: S, nodes: Watch<HashSet<SocketAddr>>, addr: SocketAddr) -> impl Future<Item = (), Error = std::io::Error> {
         future::lazy(move || TcpListener::bind(&addr)).and_then(|bind| {
-            let placer = Place { consul, nodes };
+            let placer = Place { store, nodes };
             let placement = server::PlacementServer::new(placer);
             let mut server = Server::new(placement, Default::default(), DefaultExecutor::current());
 
@@ -51,7 +51,7 @@ impl Place {
     }
 }
 
-impl server::Placement for Place {
+impl<S: MetadataStore> server::Placement for Place<S> {
     type GetPlacementFuture = GrpcFuture<PlacementReply>;
 
     fn get_placement(&mut self, request: Request<PlacementRequest>) -> Self::GetPlacementFuture {
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cluster::raft::NodeId;
+
+/// A change to the cluster's metadata, as it's stored in the Raft log
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    AddNode { id: NodeId, addr: String },
+    RemoveNode { id: NodeId },
+    PlaceIndex { index: String, node: NodeId },
+    RemoveIndex { index: String },
+    SetAlias { alias: String, index: String },
+    RemoveAlias { alias: String },
+}
+
+/// What the cluster agrees on: which nodes are members, where each index lives and what aliases point to
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ClusterMetadata {
+    pub nodes: BTreeMap<NodeId, String>,
+    pub placements: BTreeMap<String, Vec<NodeId>>,
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl ClusterMetadata {
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::AddNode { id, addr } => {
+                self.nodes.insert(id, addr);
+            }
+            Command::RemoveNode { id } => {
+                self.nodes.remove(&id);
+                for nodes in self.placements.values_mut() {
+                    nodes.retain(|n| *n != id);
+                }
+            }
+            Command::PlaceIndex { index, node } => {
+                let nodes = self.placements.entry(index).or_default();
+                if !nodes.contains(&node) {
+                    nodes.push(node);
+                }
+            }
+            Command::RemoveIndex { index } => {
+                self.placements.remove(&index);
+                let stale: Vec<String> = self
+                    .aliases
+                    .iter()
+                    .filter(|(_, target)| **target == index)
+                    .map(|(alias, _)| alias.clone())
+                    .collect();
+                for alias in stale {
+                    self.aliases.remove(&alias);
+                }
+            }
+            Command::SetAlias { alias, index } => {
+                self.aliases.insert(alias, index);
+            }
+            Command::RemoveAlias { alias } => {
+                self.aliases.remove(&alias);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut metadata = ClusterMetadata::default();
+        let commands = r#"[
+            { "op": "add_node", "id": 1, "addr": "10.0.0.1:8090" },
+            { "op": "add_node", "id": 2, "addr": "10.0.0.2:8090" },
+            { "op": "place_index", "index": "logs", "node": 1 },
+            { "op": "place_index", "index": "logs", "node": 2 },
+            { "op": "place_index", "index": "logs", "node": 2 },
+            { "op": "set_alias", "alias": "current", "index": "logs" },
+            { "op": "remove_node", "id": 1 }
+        ]"#;
+        for command in serde_json::from_str::<Vec<Command>>(commands).unwrap() {
+            metadata.apply(command);
+        }
+        assert_eq!(metadata.nodes.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(metadata.placements["logs"], vec![2]);
+        assert_eq!(metadata.aliases["current"], "logs");
+
+        metadata.apply(Command::RemoveIndex { index: "logs".into() });
+        assert!(metadata.placements.is_empty());
+        assert!(metadata.aliases.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+//! Cluster metadata kept in a Raft group among the Toshi nodes themselves, so small clusters can run without Consul.
+//! Membership, index placement and aliases are commands in a replicated log, and every node applies them in the
+//! same order to its own copy of the metadata. Only the leader takes new commands.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use http::Uri;
+use log::{debug, error, info};
+use tokio::net::TcpListener;
+use tokio::timer::Interval;
+use tokio_executor::DefaultExecutor;
+use tower_buffer::Buffer;
+use tower_grpc::{Request, Response};
+use tower_h2::client::Connect;
+use tower_h2::Server;
+use tower_http::add_origin::Builder;
+use tower_util::MakeService;
+
+use crate::cluster::raft_rpc::{client, server, RaftMessage, RaftReply};
+use crate::cluster::rpc_server::Buf;
+use crate::cluster::{GrpcConn, RPCError};
+use crate::settings::RaftSettings;
+use crate::{Error, Result};
+
+pub use self::metadata::{ClusterMetadata, Command};
+pub use self::node::{Entry, HardState, Message, NodeId, RaftNode};
+
+pub mod metadata;
+pub mod node;
+
+pub const STATE_FILENAME: &str = "raft.json";
+
+pub type RaftClient = client::Raft<Buf>;
+
+pub struct RaftCluster {
+    node: Mutex<RaftNode>,
+    metadata: RwLock<ClusterMetadata>,
+    addr: String,
+    peers: HashMap<NodeId, SocketAddr>,
+    state_path: PathBuf,
+    clients: Arc<Mutex<HashMap<NodeId, RaftClient>>>,
+    announced_term: Mutex<u64>,
+}
+
+impl RaftCluster {
+    /// This node's member of the group, picking up the log it had stored under `data_path` if it ran before
+    pub fn new(settings: &RaftSettings, data_path: &Path) -> Result<Arc<Self>> {
+        let peers = settings.peer_addrs().map_err(Error::IOError)?;
+        let state_path = data_path.join(STATE_FILENAME);
+        let state = match fs::read_to_string(&state_path) {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        let node = RaftNode::new(settings.node_id, peers.keys().cloned().collect(), state, settings.election_ticks);
+        Ok(Arc::new(RaftCluster {
+            node: Mutex::new(node),
+            metadata: RwLock::new(ClusterMetadata::default()),
+            addr: settings.addr.clone(),
+            peers,
+            state_path,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            announced_term: Mutex::new(0),
+        }))
+    }
+
+    pub fn metadata(&self) -> ClusterMetadata {
+        self.metadata.read().unwrap().clone()
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.node.lock().unwrap().leader()
+    }
+
+    /// Add `command` to the log. It's applied once a majority of nodes have it, which only the leader can arrange.
+    pub fn propose(&self, command: &Command) -> Result<()> {
+        let mut node = self.node.lock()?;
+        if node.propose(serde_json::to_vec(command)?).is_none() {
+            return Err(Error::IOError(format!(
+                "Node {} is not the Raft leader, the leader is {:?}",
+                node.id(),
+                node.leader()
+            )));
+        }
+        self.flush(&mut node)
+    }
+
+    /// Serve the Raft service on `addr` and advance the node's clock every `tick`
+    pub fn run(cluster: Arc<Self>, addr: SocketAddr, tick: Duration) -> impl Future<Item = (), Error = ()> {
+        let service = RaftService {
+            cluster: Arc::clone(&cluster),
+        };
+        let serve = future::lazy(move || TcpListener::bind(&addr))
+            .and_then(move |bind| {
+                info!("Raft bound to: {:?}", addr);
+                let mut h2 = Server::new(server::RaftServer::new(service), Default::default(), DefaultExecutor::current());
+                bind.incoming().for_each(move |sock| {
+                    tokio::spawn(h2.serve(sock).map_err(|e| error!("Raft h2 error: {:?}", e)));
+                    Ok(())
+                })
+            })
+            .map_err(|e| error!("Raft server error: {:?}", e));
+
+        let ticks = Interval::new_interval(tick)
+            .map_err(|e| error!("Raft timer error: {:?}", e))
+            .for_each(move |_| {
+                let mut node = cluster.node.lock().unwrap();
+                node.tick();
+                cluster.announce_members(&mut node);
+                cluster.flush(&mut node).map_err(|e| error!("Raft error: {}", e))
+            });
+
+        serve.join(ticks).map(|_| ())
+    }
+
+    /// Once per term, have a new leader record any configured member the metadata doesn't know of yet
+    fn announce_members(&self, node: &mut RaftNode) {
+        let mut announced = self.announced_term.lock().unwrap();
+        if !node.is_leader() || *announced == node.term() {
+            return;
+        }
+        *announced = node.term();
+        let known = self.metadata.read().unwrap().nodes.clone();
+        let members = self
+            .peers
+            .iter()
+            .map(|(id, addr)| (*id, addr.to_string()))
+            .chain(Some((node.id(), self.addr.clone())));
+        for (id, addr) in members.filter(|(id, addr)| known.get(id) != Some(addr)) {
+            if let Ok(data) = serde_json::to_vec(&Command::AddNode { id, addr }) {
+                node.propose(data);
+            }
+        }
+    }
+
+    /// Store the node's state, apply what it has committed and send what it has to say, in that order
+    fn flush(&self, node: &mut RaftNode) -> Result<()> {
+        if let Some(state) = node.take_hard_state() {
+            fs::write(&self.state_path, serde_json::to_vec(&state)?)?;
+        }
+        let committed = node.take_committed();
+        if !committed.is_empty() {
+            let mut metadata = self.metadata.write()?;
+            for entry in committed.into_iter().filter(|e| !e.data.is_empty()) {
+                match serde_json::from_slice(&entry.data) {
+                    Ok(command) => metadata.apply(command),
+                    Err(e) => error!("Skipping unreadable Raft entry: {}", e),
+                }
+            }
+        }
+        for (to, message) in node.take_messages() {
+            self.send(node.id(), to, &message);
+        }
+        Ok(())
+    }
+
+    /// Send a message in the background. Raft copes with lost messages, so failures are only logged.
+    fn send(&self, from: NodeId, to: NodeId, message: &Message) {
+        let addr = match self.peers.get(&to) {
+            Some(addr) => *addr,
+            None => return,
+        };
+        let request = RaftMessage {
+            from,
+            message: serde_json::to_vec(message).unwrap_or_default(),
+        };
+        let clients = Arc::clone(&self.clients);
+        let cached = clients.lock().unwrap().get(&to).cloned();
+        let client = match cached {
+            Some(client) => future::Either::A(future::ok(client)),
+            None => future::Either::B(RaftCluster::connect(addr)),
+        };
+        let send = client
+            .and_then(move |mut client| {
+                clients.lock().unwrap().insert(to, client.clone());
+                client.step(Request::new(request)).map_err(RPCError::from).then(move |result| {
+                    if result.is_err() {
+                        clients.lock().unwrap().remove(&to);
+                    }
+                    result
+                })
+            })
+            .map(|_| ())
+            .map_err(move |e| debug!("Failed to reach Raft node {}: {}", to, e));
+        tokio::spawn(send);
+    }
+
+    fn connect(addr: SocketAddr) -> impl Future<Item = RaftClient, Error = RPCError> + Send + 'static {
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(addr.to_string().as_str())
+            .path_and_query("")
+            .build()
+            .unwrap();
+        let mut connect = Connect::new(GrpcConn(addr), Default::default(), DefaultExecutor::current());
+        connect
+            .make_service(())
+            .map(move |conn| {
+                let connection = Builder::new().uri(uri).build(conn).unwrap();
+                client::Raft::new(Buffer::new(connection, 0).expect("Failed to buffer Raft connection"))
+            })
+            .map_err(|e| e.into())
+    }
+}
+
+#[derive(Clone)]
+struct RaftService {
+    cluster: Arc<RaftCluster>,
+}
+
+impl server::Raft for RaftService {
+    type StepFuture = future::FutureResult<Response<RaftReply>, tower_grpc::Error>;
+
+    fn step(&mut self, request: Request<RaftMessage>) -> Self::StepFuture {
+        let inner = request.into_inner();
+        match serde_json::from_slice(&inner.message) {
+            Ok(message) => {
+                let mut node = self.cluster.node.lock().unwrap();
+                node.step(inner.from, message);
+                if let Err(e) = self.cluster.flush(&mut node) {
+                    error!("Raft error: {}", e);
+                }
+            }
+            Err(e) => error!("Unreadable Raft message from node {}: {}", inner.from, e),
+        }
+        future::ok(Response::new(RaftReply {}))
+    }
+}
@@ -0,0 +1,501 @@
+//! The Raft consensus algorithm, kept free of any I/O so it can be driven by a timer and the network, or by a test.
+//! Callers feed it ticks and messages from its peers, then take the messages it wants sent, the state it needs
+//! persisted, and the entries that have been committed.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
+
+use serde::{Deserialize, Serialize};
+
+pub type NodeId = u64;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    /// An empty entry is the no-op a new leader appends to commit entries left over from earlier terms
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Message {
+    Vote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+    AppendReply {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+impl Message {
+    fn term(&self) -> u64 {
+        match self {
+            Message::Vote { term, .. } | Message::VoteReply { term, .. } => *term,
+            Message::Append { term, .. } | Message::AppendReply { term, .. } => *term,
+        }
+    }
+}
+
+/// The state that has to survive a restart for Raft to stay safe
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<NodeId>,
+    pub log: Vec<Entry>,
+}
+
+enum Role {
+    Follower,
+    Candidate(HashSet<NodeId>),
+    Leader {
+        next_index: HashMap<NodeId, u64>,
+        match_index: HashMap<NodeId, u64>,
+    },
+}
+
+pub struct RaftNode {
+    id: NodeId,
+    peers: Vec<NodeId>,
+    state: HardState,
+    role: Role,
+    leader: Option<NodeId>,
+    commit_index: u64,
+    last_applied: u64,
+    election_ticks: u32,
+    election_timeout: u32,
+    elapsed: u32,
+    outbox: Vec<(NodeId, Message)>,
+    dirty: bool,
+}
+
+impl RaftNode {
+    /// A node that starts out as a follower. Elections time out after between `election_ticks` and twice that
+    /// many ticks, picked at random so nodes don't keep splitting the vote.
+    pub fn new(id: NodeId, peers: Vec<NodeId>, state: HardState, election_ticks: u32) -> Self {
+        let mut node = RaftNode {
+            id,
+            peers,
+            state,
+            role: Role::Follower,
+            leader: None,
+            commit_index: 0,
+            last_applied: 0,
+            election_ticks: election_ticks.max(2),
+            election_timeout: 0,
+            elapsed: 0,
+            outbox: Vec::new(),
+            dirty: false,
+        };
+        node.reset_timer();
+        node
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        match self.role {
+            Role::Leader { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed += 1;
+        if self.is_leader() {
+            if self.elapsed >= self.election_ticks / 2 {
+                self.elapsed = 0;
+                self.broadcast_append();
+            }
+        } else if self.elapsed >= self.election_timeout {
+            self.start_election();
+        }
+    }
+
+    /// Append `data` to the log if this node is the leader, returning the index it was given
+    pub fn propose(&mut self, data: Vec<u8>) -> Option<u64> {
+        if !self.is_leader() {
+            return None;
+        }
+        self.append(data);
+        self.broadcast_append();
+        self.maybe_commit();
+        Some(self.last_index())
+    }
+
+    pub fn step(&mut self, from: NodeId, message: Message) {
+        if message.term() > self.state.term {
+            self.become_follower(message.term(), None);
+        }
+        match message {
+            Message::Vote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.state.term && up_to_date && self.state.voted_for.map(|v| v == from).unwrap_or(true);
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.dirty = true;
+                    self.reset_timer();
+                }
+                let reply = Message::VoteReply {
+                    term: self.state.term,
+                    granted,
+                };
+                self.outbox.push((from, reply));
+            }
+            Message::VoteReply { term, granted } => {
+                if term != self.state.term || !granted {
+                    return;
+                }
+                let quorum = self.quorum();
+                let elected = match self.role {
+                    Role::Candidate(ref mut votes) => {
+                        votes.insert(from);
+                        votes.len() >= quorum
+                    }
+                    _ => false,
+                };
+                if elected {
+                    self.become_leader();
+                }
+            }
+            Message::Append {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.state.term {
+                    self.reply_append(from, false, 0);
+                    return;
+                }
+                self.become_follower(term, Some(from));
+                if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+                    self.reply_append(from, false, 0);
+                    return;
+                }
+                let mut index = prev_log_index;
+                for entry in entries {
+                    index += 1;
+                    if index <= self.last_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        self.state.log.truncate(index as usize - 1);
+                    }
+                    self.state.log.push(entry);
+                    self.dirty = true;
+                }
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(index);
+                }
+                self.reply_append(from, true, index);
+            }
+            Message::AppendReply {
+                term,
+                success,
+                match_index: matched,
+            } => {
+                if term != self.state.term {
+                    return;
+                }
+                let retry = match self.role {
+                    Role::Leader {
+                        ref mut next_index,
+                        ref mut match_index,
+                    } => {
+                        if success {
+                            let known = match_index.entry(from).or_insert(0);
+                            *known = (*known).max(matched);
+                            next_index.insert(from, *known + 1);
+                            false
+                        } else {
+                            // Walk back until the follower's log agrees with ours
+                            let next = next_index.entry(from).or_insert(1);
+                            *next = next.saturating_sub(1).max(1);
+                            true
+                        }
+                    }
+                    _ => return,
+                };
+                if retry {
+                    self.send_append(from);
+                } else {
+                    self.maybe_commit();
+                }
+            }
+        }
+    }
+
+    /// Messages waiting to be sent, paired with the node each is for
+    pub fn take_messages(&mut self) -> Vec<(NodeId, Message)> {
+        mem::replace(&mut self.outbox, Vec::new())
+    }
+
+    /// The state to persist, if it changed since it was last taken. It has to be stored before any of the
+    /// messages taken alongside it are sent.
+    pub fn take_hard_state(&mut self) -> Option<HardState> {
+        if self.dirty {
+            self.dirty = false;
+            Some(self.state.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Entries committed since they were last taken, in log order
+    pub fn take_committed(&mut self) -> Vec<Entry> {
+        let committed = self.state.log[self.last_applied as usize..self.commit_index as usize].to_vec();
+        self.last_applied = self.commit_index;
+        committed
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.state.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.state.log.get(i as usize - 1).map(|e| e.term).unwrap_or(0),
+        }
+    }
+
+    fn reset_timer(&mut self) {
+        let jitter = RandomState::new().build_hasher().finish() % u64::from(self.election_ticks);
+        self.elapsed = 0;
+        self.election_timeout = self.election_ticks + jitter as u32;
+    }
+
+    fn append(&mut self, data: Vec<u8>) {
+        self.state.log.push(Entry {
+            term: self.state.term,
+            data,
+        });
+        self.dirty = true;
+    }
+
+    fn start_election(&mut self) {
+        self.state.term += 1;
+        self.state.voted_for = Some(self.id);
+        self.dirty = true;
+        self.leader = None;
+        self.reset_timer();
+        let mut votes = HashSet::new();
+        votes.insert(self.id);
+        self.role = Role::Candidate(votes);
+        if self.quorum() == 1 {
+            self.become_leader();
+            return;
+        }
+        let vote = Message::Vote {
+            term: self.state.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in &self.peers {
+            self.outbox.push((*peer, vote.clone()));
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.state.term {
+            self.state.term = term;
+            self.state.voted_for = None;
+            self.dirty = true;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_timer();
+    }
+
+    fn become_leader(&mut self) {
+        let next = self.last_index() + 1;
+        self.role = Role::Leader {
+            next_index: self.peers.iter().map(|p| (*p, next)).collect(),
+            match_index: self.peers.iter().map(|p| (*p, 0)).collect(),
+        };
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        // Entries from earlier terms can only be committed once one from this term is
+        self.append(Vec::new());
+        self.broadcast_append();
+        self.maybe_commit();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, to: NodeId) {
+        let next = match self.role {
+            Role::Leader { ref next_index, .. } => next_index.get(&to).cloned().unwrap_or(1),
+            _ => return,
+        };
+        let prev_log_index = next - 1;
+        let append = Message::Append {
+            term: self.state.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.state.log[prev_log_index as usize..].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.outbox.push((to, append));
+    }
+
+    fn reply_append(&mut self, to: NodeId, success: bool, match_index: u64) {
+        let reply = Message::AppendReply {
+            term: self.state.term,
+            success,
+            match_index,
+        };
+        self.outbox.push((to, reply));
+    }
+
+    /// Commit the newest entry from this term that a majority of nodes have
+    fn maybe_commit(&mut self) {
+        let matched: Vec<u64> = match self.role {
+            Role::Leader { ref match_index, .. } => match_index.values().cloned().collect(),
+            _ => return,
+        };
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            let replicated = 1 + matched.iter().filter(|m| **m >= index).count();
+            if self.term_at(index) == self.state.term && replicated >= self.quorum() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(size: u64) -> HashMap<NodeId, RaftNode> {
+        (1..=size)
+            .map(|id| {
+                let peers = (1..=size).filter(|p| *p != id).collect();
+                (id, RaftNode::new(id, peers, HardState::default(), 10))
+            })
+            .collect()
+    }
+
+    /// Tick every node not in `down` and deliver messages between them until nothing is left in flight
+    fn run(nodes: &mut HashMap<NodeId, RaftNode>, down: &[NodeId], ticks: usize) {
+        for _ in 0..ticks {
+            let mut in_flight = Vec::new();
+            for (id, node) in nodes.iter_mut().filter(|(id, _)| !down.contains(id)) {
+                node.tick();
+                in_flight.extend(node.take_messages().into_iter().map(|(to, m)| (*id, to, m)));
+            }
+            while let Some((from, to, message)) = in_flight.pop() {
+                if down.contains(&to) {
+                    continue;
+                }
+                let node = nodes.get_mut(&to).unwrap();
+                node.step(from, message);
+                in_flight.extend(node.take_messages().into_iter().map(|(next, m)| (to, next, m)));
+            }
+        }
+    }
+
+    fn leader(nodes: &HashMap<NodeId, RaftNode>, down: &[NodeId]) -> Option<NodeId> {
+        let leaders: Vec<NodeId> = nodes
+            .values()
+            .filter(|n| n.is_leader() && !down.contains(&n.id()))
+            .map(|n| n.id())
+            .collect();
+        assert!(leaders.len() <= 1);
+        leaders.first().cloned()
+    }
+
+    fn committed(node: &mut RaftNode) -> Vec<Vec<u8>> {
+        node.take_committed()
+            .into_iter()
+            .map(|e| e.data)
+            .filter(|d| !d.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_election_and_replication() {
+        let mut nodes = cluster(3);
+        run(&mut nodes, &[], 50);
+        let first = leader(&nodes, &[]).expect("a leader should be elected");
+        assert!(nodes.values().all(|n| n.leader() == Some(first)));
+
+        assert!(nodes
+            .values_mut()
+            .filter(|n| !n.is_leader())
+            .all(|n| n.propose(b"nope".to_vec()).is_none()));
+        nodes.get_mut(&first).unwrap().propose(b"one".to_vec()).unwrap();
+        run(&mut nodes, &[], 10);
+        for node in nodes.values_mut() {
+            assert_eq!(committed(node), vec![b"one".to_vec()]);
+            assert!(node.take_hard_state().is_some());
+        }
+
+        // The rest carry on without the leader, and it catches up once it's back
+        run(&mut nodes, &[first], 50);
+        let second = leader(&nodes, &[first]).expect("a new leader should be elected");
+        assert_ne!(first, second);
+        nodes.get_mut(&second).unwrap().propose(b"two".to_vec()).unwrap();
+        run(&mut nodes, &[first], 10);
+        run(&mut nodes, &[], 50);
+        for node in nodes.values_mut() {
+            assert_eq!(committed(node), vec![b"two".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_single_node() {
+        let mut node = RaftNode::new(1, Vec::new(), HardState::default(), 2);
+        for _ in 0..4 {
+            node.tick();
+        }
+        assert!(node.is_leader());
+        node.propose(b"solo".to_vec()).unwrap();
+        assert_eq!(committed(&mut node), vec![b"solo".to_vec()]);
+    }
+}
@@ -0,0 +1,453 @@
+//! Evening out the load on data nodes. The master periodically asks each data node how much it holds, and when the
+//! heaviest holds more than the lightest by too much, it has the lightest pull one of the heaviest's indexes. The
+//! files are copied over RPC into a staging directory, then copied again to pick up anything committed in the
+//! meantime, and only then renamed into place. Once the copy is open, searches are routed to it and the original
+//! is removed.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{future, future::Loop, Future, Stream};
+use http::Uri;
+use log::{error, info, warn};
+use tokio::timer::Interval;
+use tower_grpc::Request;
+
+use crate::cluster::cluster_rpc::*;
+use crate::cluster::rpc_server::{RpcClient, RpcServer};
+use crate::cluster::GrpcConn;
+use crate::index::{IndexCatalog, MOVING_PREFIX};
+use crate::settings::RebalanceSettings;
+use crate::{Error, Result};
+
+/// The most an index file is read at a time when it's fetched
+pub const CHUNK_SIZE: u64 = 1 << 20;
+
+/// Added to the name of a file while it's being fetched
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// What a data node holds, with the load each of its indexes puts on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeLoad {
+    pub node: String,
+    pub indexes: Vec<(String, u64)>,
+}
+
+impl NodeLoad {
+    pub fn total(&self) -> u64 {
+        self.indexes.iter().map(|(_, load)| load).sum()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Move {
+    pub index: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// The move from the heaviest node to the lightest that leaves them closest to even, if the gap between them is more
+/// than `threshold` of the heaviest's load. Indexes are only moved to nodes that don't already hold one by that name.
+pub fn plan(loads: &[NodeLoad], threshold: f64) -> Option<Move> {
+    let heaviest = loads.iter().max_by_key(|l| l.total())?;
+    let lightest = loads.iter().min_by_key(|l| l.total())?;
+    let (heavy, light) = (heaviest.total(), lightest.total());
+    if heavy == 0 || (heavy - light) as f64 <= heavy as f64 * threshold {
+        return None;
+    }
+    heaviest
+        .indexes
+        .iter()
+        // Anything as large as the gap would only move the imbalance to the other node
+        .filter(|(name, load)| *load > 0 && *load < heavy - light && !lightest.indexes.iter().any(|(n, _)| n == name))
+        .min_by_key(|(_, load)| ((heavy - load) as i64 - (light + load) as i64).abs())
+        .map(|(name, _)| Move {
+            index: name.clone(),
+            from: heaviest.node.clone(),
+            to: lightest.node.clone(),
+        })
+}
+
+/// Every file making up the index stored in `path` and its size, named relative to `path`. Lock files are left
+/// out, since they only mean something to the process holding them, as are files still being fetched.
+pub fn index_files(path: &Path) -> Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?.path();
+            if entry.is_dir() {
+                dirs.push(entry);
+                continue;
+            }
+            let name = match entry.strip_prefix(path).ok().and_then(|name| name.to_str()) {
+                Some(name) if !name.ends_with(".lock") && !name.ends_with(PARTIAL_SUFFIX) => name.to_string(),
+                _ => continue,
+            };
+            files.push((name, entry.metadata()?.len()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Read up to `length` bytes of the file `name` in the index stored in `path`, starting at `offset`
+pub fn read_chunk(path: &Path, name: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+    let file = safe_join(path, name)?;
+    let mut file = File::open(file)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(length.min(CHUNK_SIZE)).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// `name` within `path`, refusing names that would lead outside of it
+fn safe_join(path: &Path, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if relative.components().any(|c| match c {
+        Component::Normal(_) => false,
+        _ => true,
+    }) {
+        return Err(Error::IOError(format!("{} is not a file in the index", name)));
+    }
+    Ok(path.join(relative))
+}
+
+fn check(result: Option<ResultReply>) -> Result<()> {
+    match result {
+        Some(ref result) if result.code != ResultCode::Success as i32 => Err(Error::IOError(result.message.clone())),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn connect(addr: SocketAddr) -> impl Future<Item = RpcClient, Error = Error> {
+    let uri: Uri = format!("http://{}", addr).parse().unwrap();
+    RpcServer::create_client(GrpcConn(addr), uri).map_err(|e| Error::IOError(e.to_string()))
+}
+
+/// Ask the node `client` is connected to to make `index` read only, committing what has been written to it, or to
+/// take writes again
+fn freeze(mut client: RpcClient, index: String, frozen: bool) -> impl Future<Item = RpcClient, Error = Error> {
+    client
+        .freeze_index(Request::new(FreezeRequest { index, frozen }))
+        .map_err(|e| Error::IOError(e.to_string()))
+        .and_then(move |reply| check(Some(reply.into_inner())).map(|_| client))
+}
+
+/// Copy `index` from the node at `from` and start serving it here.
+///
+/// When the index is moving rather than being copied, `fence` makes it read only on `from` once most of it has been
+/// copied, so nothing written to it there afterwards is lost. What was written is committed as it's made read only,
+/// and the final pass copies that commit's metadata. If the copy fails from then on, `from` takes writes again.
+pub fn pull_index(
+    catalog: Arc<RwLock<IndexCatalog>>,
+    index: String,
+    from: SocketAddr,
+    fence: bool,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let staging = match catalog.read() {
+        Ok(ref cat) if cat.exists(&index) => return Box::new(future::err(Error::IOError(format!("Index {} already exists", index)))),
+        Ok(cat) => cat.base_path().join(format!("{}{}", MOVING_PREFIX, index)),
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    // Whatever is left of an earlier move that failed partway is started over
+    if staging.exists() {
+        if let Err(e) = fs::remove_dir_all(&staging) {
+            return Box::new(future::err(e.into()));
+        }
+    }
+
+    let (first, second, frozen, thawed) = (index.clone(), index.clone(), index.clone(), index.clone());
+    let (first_staging, second_staging) = (staging.clone(), staging.clone());
+    let pull = connect(from)
+        .and_then(move |client| copy_files(client, first, first_staging, HashSet::new()))
+        .and_then(move |(client, copied)| {
+            let fenced: Box<Future<Item = RpcClient, Error = Error> + Send> = if fence {
+                Box::new(freeze(client, frozen, true))
+            } else {
+                Box::new(future::ok(client))
+            };
+            fenced.map(move |client| (client, copied))
+        })
+        // Segment files never change once written, so the second pass only fetches new ones and the metadata
+        .and_then(move |(client, copied)| copy_files(client, second, second_staging, copied))
+        .and_then(move |_| {
+            let adopted = catalog.write()?.adopt_index(&index, &staging);
+            if adopted.is_err() {
+                let _ = fs::remove_dir_all(&staging);
+            }
+            adopted
+        });
+    if !fence {
+        return Box::new(pull);
+    }
+    let pull = pull.or_else(move |e| {
+        // Making the source writable again if it was never made read only does no harm
+        connect(from)
+            .and_then(move |client| freeze(client, thawed, false))
+            .then(move |result| {
+                if let Err(t) = result {
+                    warn!(
+                        "Unable to make the index on {} writable again after failing to copy it: {}",
+                        from, t
+                    );
+                }
+                Err(e)
+            })
+    });
+    Box::new(pull)
+}
+
+/// Fetch every file of `index` not in `skip` into `staging`, then remove any in `skip` that no longer exist on the
+/// source. Metadata is always fetched, and fetched last, so an index being copied into is never left pointing at
+/// segments that haven't arrived yet.
+pub(crate) fn copy_files(
+    mut client: RpcClient,
+    index: String,
+    staging: PathBuf,
+    skip: HashSet<(String, u64)>,
+) -> impl Future<Item = (RpcClient, HashSet<(String, u64)>), Error = Error> {
+    let request = FilesRequest { index: index.clone() };
+    client
+        .list_files(Request::new(request))
+        .map_err(|e| Error::IOError(e.to_string()))
+        .and_then(move |reply| {
+            let reply = reply.into_inner();
+            check(reply.result)?;
+            let files: Vec<(String, u64)> = reply.files.into_iter().map(|f| (f.name, f.size)).collect();
+            let mut wanted = files
+                .iter()
+                .filter(|file| !skip.contains(*file) || file.0.ends_with(".json"))
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            // Files are fetched from the back
+            wanted.sort_by_key(|name| !name.ends_with("meta.json"));
+            Ok((client, index, staging, skip, files, wanted))
+        })
+        .and_then(|(client, index, staging, skip, files, wanted)| {
+            let fetch_into = staging.clone();
+            future::loop_fn((client, wanted), move |(client, mut wanted)| {
+                let next = wanted.pop();
+                let fetch: Box<Future<Item = RpcClient, Error = Error> + Send> = match next {
+                    Some(name) => Box::new(fetch_file(client, index.clone(), name, &fetch_into)),
+                    None => return future::Either::A(future::ok(Loop::Break(client))),
+                };
+                future::Either::B(fetch.map(move |client| Loop::Continue((client, wanted))))
+            })
+            .and_then(move |client| {
+                for (stale, _) in skip.iter().filter(|copied| !files.iter().any(|(name, _)| name == &copied.0)) {
+                    let _ = fs::remove_file(safe_join(&staging, stale)?);
+                }
+                Ok((client, files.into_iter().collect()))
+            })
+        })
+}
+
+/// Fetch `name` into `staging`, by way of a partial file that's only renamed once all of it has arrived
+fn fetch_file(client: RpcClient, index: String, name: String, staging: &Path) -> impl Future<Item = RpcClient, Error = Error> {
+    let file = safe_join(staging, &name).and_then(|dest| {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = PathBuf::from(format!("{}{}", dest.display(), PARTIAL_SUFFIX));
+        let file = File::create(&partial)?;
+        Ok((dest, partial, file))
+    });
+    future::result(file).and_then(move |(dest, partial, file)| {
+        let fetched = future::loop_fn((client, file, 0), move |(mut client, mut file, offset)| {
+            let request = FetchRequest {
+                index: index.clone(),
+                name: name.clone(),
+                offset,
+                length: CHUNK_SIZE,
+            };
+            client
+                .fetch_file(Request::new(request))
+                .map_err(|e| Error::IOError(e.to_string()))
+                .and_then(move |reply| {
+                    let reply = reply.into_inner();
+                    check(reply.result)?;
+                    file.write_all(&reply.data)?;
+                    // A short read means the end of the file, which for metadata may not be the size it was listed at
+                    if (reply.data.len() as u64) < CHUNK_SIZE {
+                        file.sync_all()?;
+                        Ok(Loop::Break(client))
+                    } else {
+                        Ok(Loop::Continue((client, file, offset + CHUNK_SIZE)))
+                    }
+                })
+        });
+        fetched.and_then(move |client| {
+            fs::rename(&partial, &dest)?;
+            Ok(client)
+        })
+    })
+}
+
+/// Watches the load on the master's data nodes and moves indexes between them as it grows uneven
+pub struct Rebalancer {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    nodes: Vec<SocketAddr>,
+    settings: RebalanceSettings,
+}
+
+impl Rebalancer {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, nodes: &[String], settings: RebalanceSettings) -> Self {
+        Rebalancer {
+            catalog,
+            nodes: nodes.iter().filter_map(|n| n.parse().ok()).collect(),
+            settings,
+        }
+    }
+
+    /// Check the balance every `interval_secs`, making at most one move at a time
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let interval = Duration::from_secs(self.settings.interval_secs);
+        Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| error!("Rebalancing timer failed: {}", e))
+            .for_each(move |_| self.step().or_else(|e| Ok(warn!("Unable to rebalance data nodes: {}", e))))
+    }
+
+    fn step(&self) -> impl Future<Item = (), Error = Error> {
+        let by_docs = self.settings.measure == "docs";
+        let loads = self.nodes.iter().map(move |node| {
+            let node = *node;
+            Rebalancer::load(node, by_docs).then(move |load| match load {
+                Ok(load) => Ok(Some(load)),
+                // A node that can't be reached can't take part in a move either way
+                Err(e) => {
+                    warn!("Unable to get the load on {}: {}", node, e);
+                    Ok::<_, Error>(None)
+                }
+            })
+        });
+        let threshold = self.settings.threshold;
+        let catalog = Arc::clone(&self.catalog);
+        future::join_all(loads).and_then(move |loads| {
+            let loads: Vec<NodeLoad> = loads.into_iter().filter_map(|l| l).collect();
+            match plan(&loads, threshold) {
+                Some(planned) => future::Either::A(Rebalancer::execute(catalog, planned)),
+                None => future::Either::B(future::ok(())),
+            }
+        })
+    }
+
+    fn load(node: SocketAddr, by_docs: bool) -> impl Future<Item = NodeLoad, Error = Error> {
+        connect(node).and_then(move |mut client| {
+            client
+                .node_stats(Request::new(StatsRequest {}))
+                .map_err(|e| Error::IOError(e.to_string()))
+                .map(move |reply| NodeLoad {
+                    node: node.to_string(),
+                    indexes: reply
+                        .into_inner()
+                        .indexes
+                        .into_iter()
+                        .map(|i| (i.index, if by_docs { i.docs } else { i.bytes }))
+                        .collect(),
+                })
+        })
+    }
+
+    fn execute(catalog: Arc<RwLock<IndexCatalog>>, planned: Move) -> impl Future<Item = (), Error = Error> {
+        info!("Moving index {} from {} to {}", planned.index, planned.from, planned.to);
+        let addrs = planned
+            .from
+            .parse::<SocketAddr>()
+            .and_then(|from| planned.to.parse::<SocketAddr>().map(|to| (from, to)))
+            .map_err(|e| Error::IOError(e.to_string()));
+        future::result(addrs).and_then(move |(from, to)| {
+            let request = ReplicaRequest {
+                index: planned.index.clone(),
+                from: planned.from.clone(),
+                to: planned.to.clone(),
+            };
+            let thawed = planned.index.clone();
+            // The new node only answers once the index has been made read only here and its last commit copied over,
+            // so the original is never removed with writes the copy doesn't have
+            connect(to)
+                .and_then(|mut client| {
+                    client
+                        .place_replica(Request::new(request))
+                        .map_err(|e| Error::IOError(e.to_string()))
+                        .and_then(move |reply| check(Some(reply.into_inner())).map(|_| client))
+                })
+                .or_else(move |e| {
+                    // The new node normally makes the original writable again itself, unless it's the one that failed
+                    connect(from).and_then(move |client| freeze(client, thawed, false)).then(|_| Err(e))
+                })
+                .and_then(move |client| {
+                    // The new copy is serving, so searches switch to it before the original goes away
+                    catalog
+                        .write()?
+                        .move_remote_index(&planned.index, &planned.from, planned.to.clone(), client)?;
+                    Ok(planned)
+                })
+                .and_then(move |planned| {
+                    connect(from).and_then(move |mut client| {
+                        let request = RemoveRequest {
+                            index: planned.index.clone(),
+                        };
+                        client
+                            .remove_index(Request::new(request))
+                            .map_err(|e| Error::IOError(e.to_string()))
+                            .and_then(move |reply| {
+                                check(Some(reply.into_inner()))?;
+                                info!("Moved index {} from {} to {}", planned.index, planned.from, planned.to);
+                                Ok(())
+                            })
+                    })
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(name: &str, indexes: &[(&str, u64)]) -> NodeLoad {
+        NodeLoad {
+            node: name.into(),
+            indexes: indexes.iter().map(|(n, l)| (n.to_string(), *l)).collect(),
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let loads = vec![node("a", &[("logs", 60), ("users", 30)]), node("b", &[("events", 10)])];
+        let planned = plan(&loads, 0.2).unwrap();
+        assert_eq!(planned.index, "users");
+        assert_eq!((planned.from.as_str(), planned.to.as_str()), ("a", "b"));
+
+        let even = vec![node("a", &[("logs", 50)]), node("b", &[("events", 45)])];
+        assert_eq!(plan(&even, 0.2), None);
+
+        let held = vec![node("a", &[("logs", 60), ("users", 30)]), node("b", &[("users", 10)])];
+        assert_eq!(plan(&held, 0.2).map(|m| m.index), Some("logs".to_string()));
+
+        // Moving the only index would leave the other node just as far ahead
+        assert_eq!(plan(&[node("a", &[("logs", 100)]), node("b", &[])], 0.2), None);
+    }
+
+    #[test]
+    fn test_index_files() {
+        let path = std::env::temp_dir().join("toshi_rebalance_files");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("0")).unwrap();
+        fs::write(path.join("meta.json"), b"{}").unwrap();
+        fs::write(path.join("0").join("abc.idx"), b"segment").unwrap();
+        fs::write(path.join(".tantivy-writer.lock"), b"").unwrap();
+
+        let files = index_files(&path).unwrap();
+        assert_eq!(files, vec![("0/abc.idx".to_string(), 7), ("meta.json".to_string(), 2)]);
+        assert_eq!(read_chunk(&path, "0/abc.idx", 3, 10).unwrap(), b"ment".to_vec());
+        assert!(read_chunk(&path, "../toshi_rebalance_files/meta.json", 0, 10).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Searching other Toshi clusters. An index reference of the form `cluster:index` names an index on a remote
+//! cluster, which is searched through the HTTP API of one of that cluster's coordinators, trying each in turn
+//! until one answers. Its results are merged with the local ones by the search handler.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::{future, future::Loop, stream::Stream, Future};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use log::debug;
+
+use crate::results::SearchResults;
+use crate::Error;
+
+/// A reference to one index or pattern of indexes, on this cluster or a remote one
+#[derive(Debug, PartialEq)]
+pub struct IndexRef {
+    pub cluster: Option<String>,
+    pub index: String,
+}
+
+impl IndexRef {
+    /// Split a comma separated list of references, such as `logs,remote:logs-*`
+    pub fn parse_list(refs: &str) -> Vec<IndexRef> {
+        refs.split(',')
+            .map(str::trim)
+            .filter(|r| !r.is_empty())
+            .map(|r| match r.find(':') {
+                Some(at) => IndexRef {
+                    cluster: Some(r[..at].to_string()),
+                    index: r[at + 1..].to_string(),
+                },
+                None => IndexRef {
+                    cluster: None,
+                    index: r.to_string(),
+                },
+            })
+            .collect()
+    }
+
+    pub fn is_pattern(&self) -> bool {
+        self.index.contains('*')
+    }
+}
+
+/// Whether `name` matches `pattern`, where each `*` in the pattern stands for any run of characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == name;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !name.starts_with(first) || !name[first.len()..].ends_with(last) {
+        return false;
+    }
+    let mut rest = &name[first.len()..name.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The coordinators of each remote cluster, by the name index references use for it
+#[derive(Clone, Default)]
+pub struct RemoteClusters {
+    clusters: Arc<HashMap<String, Vec<String>>>,
+    /// Only made when there are clusters to search, since a client starts threads of its own for DNS
+    client: Option<Client<HttpConnector>>,
+}
+
+impl RemoteClusters {
+    pub fn new(clusters: &HashMap<String, Vec<String>>) -> Self {
+        RemoteClusters {
+            clusters: Arc::new(clusters.clone()),
+            client: if clusters.is_empty() { None } else { Some(Client::new()) },
+        }
+    }
+
+    /// Search `index`, a name or pattern, on the remote `cluster` with `query`, a search request as JSON
+    pub fn search(&self, cluster: &str, index: &str, query: Vec<u8>) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let (coordinators, client) = match (self.clusters.get(cluster), &self.client) {
+            (Some(coordinators), Some(client)) if !coordinators.is_empty() => (coordinators.clone(), client.clone()),
+            _ => return Box::new(future::err(Error::UnknownIndex(format!("{}:{}", cluster, index)))),
+        };
+        let index = index.to_string();
+        let cluster = cluster.to_string();
+        let search = future::loop_fn(0, move |attempt| {
+            let coordinator = coordinators[attempt].clone();
+            let last = attempt + 1 == coordinators.len();
+            let cluster = cluster.clone();
+            RemoteClusters::search_coordinator(&client, &coordinator, &index, query.clone()).then(move |result| match result {
+                Ok(results) => Ok(Loop::Break(results)),
+                // Only an unreachable coordinator is worth retrying, another would answer a bad search the same way
+                Err(Error::IOError(ref e)) if !last => {
+                    debug!("Unable to reach {} of cluster {}, trying the next: {}", coordinator, cluster, e);
+                    Ok(Loop::Continue(attempt + 1))
+                }
+                Err(e) => Err(e),
+            })
+        });
+        Box::new(search)
+    }
+
+    fn search_coordinator(
+        client: &Client<HttpConnector>,
+        coordinator: &str,
+        index: &str,
+        query: Vec<u8>,
+    ) -> impl Future<Item = SearchResults, Error = Error> {
+        let request = Request::post(format!("http://{}/{}", coordinator, index))
+            .header("content-type", "application/json")
+            .body(Body::from(query))
+            .map_err(|e| Error::IOError(e.to_string()));
+        let client = client.clone();
+        future::result(request).and_then(move |request| {
+            client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response.into_body().concat2().map(move |body| (status, body))
+                })
+                .map_err(|e| Error::IOError(e.to_string()))
+                .and_then(|(status, body)| {
+                    if status.is_success() {
+                        Ok(serde_json::from_slice(&body)?)
+                    } else {
+                        Err(Error::QueryError(String::from_utf8_lossy(&body).into_owned()))
+                    }
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_refs() {
+        let refs = IndexRef::parse_list("logs, remote:logs-*,");
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0].cluster, None);
+        assert_eq!(refs[1].cluster, Some("remote".to_string()));
+        assert_eq!(refs[1].index, "logs-*");
+        assert!(refs[1].is_pattern() && !refs[0].is_pattern());
+
+        assert!(matches_pattern("logs-*", "logs-2019"));
+        assert!(matches_pattern("*-2019-*", "logs-2019-01"));
+        assert!(matches_pattern("*", "anything"));
+        assert!(!matches_pattern("logs-*", "metrics-2019"));
+        assert!(!matches_pattern("a*a", "a"));
+        assert!(!matches_pattern("logs", "logs-2019"));
+    }
+}
@@ -1,4 +1,4 @@
-use log::info;
+use log::debug;
 use tokio::prelude::*;
 use tower_grpc::Request as TowerRequest;
 
@@ -13,14 +13,21 @@ use crate::query::Request;
 /// the remote host and full filling the request via rpc, we need to figure out a better way
 /// (tower-buffer) on how to keep these clients.
 
+#[derive(Clone)]
 pub struct RemoteIndex {
     name: String,
+    node: String,
     remote: RpcClient,
 }
 
 impl RemoteIndex {
-    pub fn new(name: String, remote: RpcClient) -> Self {
-        Self { name, remote }
+    pub fn new(name: String, node: String, remote: RpcClient) -> Self {
+        Self { name, node, remote }
+    }
+
+    /// The address of the node holding the index
+    pub fn node(&self) -> &str {
+        &self.node
     }
 }
 
@@ -42,16 +49,10 @@ impl IndexHandle for RemoteIndex {
         let mut client = self.remote.clone();
         let bytes = serde_json::to_vec(&search).unwrap();
         let req = TowerRequest::new(SearchRequest { index: name, query: bytes });
-        let fut = client
-            .search_index(req)
-            .map(|res| {
-                info!("RESPONSE = {:?}", res);
-                res.into_inner()
-            })
-            .map_err(|e| {
-                info!("{:?}", e);
-                e.into()
-            });
+        let fut = client.search_index(req).map(|res| res.into_inner()).map_err(|e| {
+            debug!("{:?}", e);
+            e.into()
+        });
 
         Box::new(fut)
     }
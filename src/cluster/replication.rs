@@ -0,0 +1,284 @@
+//! Forwarding writes from a primary node to its replicas. The primary applies every write locally first, then
+//! sends it on to each replica's RPC service in the background, so a slow or unreachable replica never holds
+//! up a client. Each replica is sent its writes over a single connection, one at a time in the order they were
+//! handed over, so an add and a later delete of the same document can't reach it the other way around. Replicas
+//! that miss a write stay behind until they're rebuilt from the primary.
+//!
+//! A write can instead ask for a quorum or all of the copies to accept it before it's acknowledged, in which case
+//! the client waits on the replicas' replies, though every replica is still sent the write either way.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::sync::mpsc;
+use futures::{future, Future, Stream};
+use http::Uri;
+use log::{debug, error, warn};
+use serde::Deserialize;
+use tantivy::schema::Schema;
+use tokio::timer::Timeout;
+use tower_grpc::Request;
+
+use crate::cluster::cluster_rpc::*;
+use crate::cluster::rpc_server::{RpcClient, RpcServer};
+use crate::cluster::{GrpcConn, RPCError};
+use crate::handlers::index::{AddDocument, DeleteDoc};
+use crate::Error;
+
+/// How long a write waits for the replicas it needs to accept it
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves once as many replicas as a write's consistency needs have accepted it
+pub type Acknowledged = Box<Future<Item = (), Error = Error> + Send>;
+
+/// How many copies of an index must accept a write before it's acknowledged
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Only the primary, replicas are written to in the background
+    One,
+    /// A majority of the primary and its replicas
+    Quorum,
+    /// The primary and every replica
+    All,
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Consistency::One
+    }
+}
+
+impl Consistency {
+    /// How many of `replicas` must accept a write, on top of the primary
+    pub fn required(self, replicas: usize) -> usize {
+        match self {
+            Consistency::One => 0,
+            Consistency::Quorum => (replicas + 1) / 2,
+            Consistency::All => replicas,
+        }
+    }
+}
+
+/// A replica's answer to a write
+type Reply = Box<Future<Item = tower_grpc::Response<ResultReply>, Error = RPCError> + Send>;
+
+/// A write on its way to a replica
+struct Write {
+    method: &'static str,
+    send: Box<Fn(&mut RpcClient) -> Reply + Send>,
+    /// Told whether the replica accepted the write
+    accepted: mpsc::UnboundedSender<bool>,
+}
+
+/// A replica and the queue of writes waiting to be sent to it
+#[derive(Clone)]
+struct Replica {
+    addr: SocketAddr,
+    uri: Uri,
+    /// Started by the first write, since sending needs a runtime to run on
+    queue: Arc<Mutex<Option<mpsc::UnboundedSender<Write>>>>,
+}
+
+impl Replica {
+    fn new(addr: SocketAddr) -> Option<Self> {
+        let uri = Uri::builder()
+            .scheme("http")
+            .authority(addr.to_string().as_str())
+            .path_and_query("")
+            .build();
+        match uri {
+            Ok(uri) => Some(Replica {
+                addr,
+                uri,
+                queue: Arc::new(Mutex::new(None)),
+            }),
+            Err(e) => {
+                error!("Invalid replica address {}: {}", addr, e);
+                None
+            }
+        }
+    }
+
+    /// Queue `write` behind every write queued before it
+    fn send(&self, write: Write) {
+        let mut queue = self.queue.lock().unwrap_or_else(|e| e.into_inner());
+        let write = match *queue {
+            Some(ref sender) => match sender.unbounded_send(write) {
+                Ok(()) => return,
+                Err(e) => e.into_inner(),
+            },
+            None => write,
+        };
+        let (sender, writes) = mpsc::unbounded();
+        tokio::spawn(Replica::deliver(self.addr, self.uri.clone(), writes));
+        let _ = sender.unbounded_send(write);
+        *queue = Some(sender);
+    }
+
+    /// Send `writes` to the replica at `addr` one at a time, each once the replica has answered the one before,
+    /// over a connection that's kept open until it fails
+    fn deliver(addr: SocketAddr, uri: Uri, writes: mpsc::UnboundedReceiver<Write>) -> impl Future<Item = (), Error = ()> {
+        writes
+            .fold(None, move |client: Option<RpcClient>, write| {
+                let Write { method, send, accepted } = write;
+                let connected: Box<Future<Item = RpcClient, Error = RPCError> + Send> = match client {
+                    Some(client) => Box::new(future::ok(client)),
+                    None => Box::new(RpcServer::create_client(GrpcConn(addr), uri.clone())),
+                };
+                connected
+                    .and_then(move |mut client| send(&mut client).map(move |reply| (client, reply.into_inner())))
+                    .then(move |result| {
+                        let (client, success) = match result {
+                            Ok((client, ref reply)) if reply.code == ResultCode::Success as i32 => {
+                                debug!("Replicated {} to {}", method, addr);
+                                (Some(client), true)
+                            }
+                            Ok((client, reply)) => {
+                                error!("Replica {} rejected {}: {}", addr, method, reply.message);
+                                (Some(client), false)
+                            }
+                            // The connection is made again for the next write
+                            Err(e) => {
+                                error!("Failed to replicate {} to {}: {}", method, addr, e);
+                                (None, false)
+                            }
+                        };
+                        // Nobody is listening once the write has been acknowledged
+                        let _ = accepted.unbounded_send(success);
+                        Ok(client)
+                    })
+            })
+            .map(move |_| warn!("Stopped replicating to {}", addr))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Replicator {
+    replicas: Vec<Replica>,
+}
+
+impl Replicator {
+    /// A replicator for the RPC addresses in `replicas`, skipping any that don't parse
+    pub fn new(replicas: &[String]) -> Self {
+        let replicas = replicas.iter().filter_map(|r| r.parse().ok()).filter_map(Replica::new).collect();
+        Replicator { replicas }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    pub fn place_index(&self, index: &str, schema: &Schema) {
+        let request = PlaceRequest {
+            index: index.into(),
+            schema: serde_json::to_vec(schema).unwrap_or_default(),
+        };
+        self.replicate("place_index", Consistency::One, move |client| {
+            client.place_index(Request::new(request.clone()))
+        });
+    }
+
+    pub fn place_document(&self, index: &str, doc: &AddDocument, consistency: Consistency) -> Acknowledged {
+        let request = DocumentRequest {
+            index: index.into(),
+            document: serde_json::to_vec(doc).unwrap_or_default(),
+        };
+        self.replicate("place_document", consistency, move |client| {
+            client.place_document(Request::new(request.clone()))
+        })
+    }
+
+    pub fn bulk_insert(&self, index: &str, documents: Vec<u8>, consistency: Consistency) -> Acknowledged {
+        let request = BulkRequest {
+            index: index.into(),
+            documents,
+        };
+        self.replicate("bulk_insert", consistency, move |client| {
+            client.bulk_insert(Request::new(request.clone()))
+        })
+    }
+
+    pub fn delete_documents(&self, index: &str, delete: &DeleteDoc, consistency: Consistency) -> Acknowledged {
+        let request = DeleteRequest {
+            index: index.into(),
+            terms: serde_json::to_vec(delete).unwrap_or_default(),
+        };
+        self.replicate("delete_documents", consistency, move |client| {
+            client.delete_documents(Request::new(request.clone()))
+        })
+    }
+
+    /// Queue a request built by `call` for every replica, logging the ones that fail. The returned future resolves
+    /// as soon as enough replicas for `consistency` accept it, and fails once too many have failed for that to happen.
+    fn replicate<F, R>(&self, method: &'static str, consistency: Consistency, call: F) -> Acknowledged
+    where
+        F: Fn(&mut RpcClient) -> R + Clone + Send + 'static,
+        R: Future<Item = tower_grpc::Response<ResultReply>> + Send + 'static,
+        RPCError: From<R::Error>,
+    {
+        let (accepted, replies) = mpsc::unbounded();
+        for replica in &self.replicas {
+            let call = call.clone();
+            replica.send(Write {
+                method,
+                send: Box::new(move |client: &mut RpcClient| -> Reply { Box::new(call(client).map_err(RPCError::from)) }),
+                accepted: accepted.clone(),
+            });
+        }
+        drop(accepted);
+
+        let required = consistency.required(self.replicas.len());
+        if required == 0 {
+            return Box::new(future::ok(()));
+        }
+        // The stream of replies ends once every replica has answered, so it's cut short by enough of them succeeding
+        let acks = replies
+            .filter(|success| *success)
+            .take(required as u64)
+            .collect()
+            .map_err(|_| Error::IOError("Replication was interrupted".into()))
+            .and_then(move |acks| {
+                if acks.len() < required {
+                    Err(Error::IOError(format!(
+                        "Only {} of the {} replicas required accepted {}",
+                        acks.len(),
+                        required,
+                        method
+                    )))
+                } else {
+                    Ok(())
+                }
+            });
+        let acknowledged = Timeout::new(acks, ACK_TIMEOUT).map_err(move |e| match e.into_inner() {
+            Some(e) => e,
+            None => Error::IOError(format!("Timed out waiting for {} replicas to accept {}", required, method)),
+        });
+        Box::new(acknowledged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replicas() {
+        assert!(!Replicator::default().is_enabled());
+        let replicator = Replicator::new(&["127.0.0.1:8081".into(), "nowhere".into()]);
+        assert!(replicator.is_enabled());
+        let addrs: Vec<SocketAddr> = replicator.replicas.iter().map(|replica| replica.addr).collect();
+        assert_eq!(addrs, vec!["127.0.0.1:8081".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn test_consistency() {
+        assert_eq!(Consistency::default().required(2), 0);
+        assert_eq!(Consistency::Quorum.required(1), 1);
+        assert_eq!(Consistency::Quorum.required(2), 1);
+        assert_eq!(Consistency::Quorum.required(3), 2);
+        assert_eq!(Consistency::All.required(3), 3);
+        assert_eq!(Consistency::All.required(0), 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Choosing which copy of an index serves a search. The master holds the primary copy and each of its replicas holds
+//! another, any one of which can answer. By default the copy that has recently answered fastest is picked, which
+//! steers searches away from a node that's overloaded or slow, while a search's `preference` can pin it to the
+//! primary, to a replica, or to the same copy as other searches given the same string.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The name the coordinating node's own copy of an index goes by among the copies
+pub const LOCAL_COPY: &str = "_local";
+
+/// How much each new latency counts towards a copy's estimate, the rest being its earlier estimate
+const SMOOTHING: f64 = 0.3;
+
+/// What a failed search counts as towards a copy's latency, so copies that fail are avoided as if they were slow
+pub const FAILURE_PENALTY: Duration = Duration::from_secs(5);
+
+/// How long a copy's estimate takes to halve while it isn't searched, so a copy that was avoided for being slow or
+/// failing is tried again once it has been left alone long enough
+const HALF_LIFE: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Preference {
+    /// The copy that has recently answered fastest
+    Adaptive,
+    /// The master's own copy. Searches sent to a node that holds no copy, so isn't the master, go to the fastest one.
+    Primary,
+    /// The fastest replica, or the primary when there are none
+    Replica,
+    /// The copy on the node the search was sent to, or the fastest one when it holds none
+    Local,
+    /// The same copy for every search given the same string, such as a user's session id
+    Custom(String),
+}
+
+impl Preference {
+    pub fn parse(preference: Option<&str>) -> Self {
+        match preference {
+            None | Some("") => Preference::Adaptive,
+            Some("primary") => Preference::Primary,
+            Some("replica") => Preference::Replica,
+            Some("local") => Preference::Local,
+            Some(custom) => Preference::Custom(custom.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Routing {
+    replicas: Arc<HashSet<String>>,
+    /// A moving average of each copy's search latency in milliseconds and when it was last searched, by node
+    latencies: Arc<Mutex<HashMap<String, (f64, Instant)>>>,
+}
+
+impl Routing {
+    pub fn new(replicas: &[String]) -> Self {
+        Routing {
+            replicas: Arc::new(replicas.iter().cloned().collect()),
+            latencies: Arc::default(),
+        }
+    }
+
+    /// Whether the indexes on `node` are copies of this node's rather than indexes of their own
+    pub fn is_replica(&self, node: &str) -> bool {
+        self.replicas.contains(node)
+    }
+
+    /// Record how long a search on `node` took
+    pub fn record(&self, node: &str, elapsed: Duration) {
+        self.record_at(node, elapsed, Instant::now());
+    }
+
+    fn record_at(&self, node: &str, elapsed: Duration, now: Instant) {
+        let millis = elapsed.as_secs() as f64 * 1000.0 + f64::from(elapsed.subsec_micros()) / 1000.0;
+        if let Ok(mut latencies) = self.latencies.lock() {
+            let decayed = latencies.get(node).map_or(millis, |latency| Routing::decayed(*latency, now));
+            latencies.insert(node.to_string(), (SMOOTHING * millis + (1.0 - SMOOTHING) * decayed, now));
+        }
+    }
+
+    /// What an estimate made at some point has come down to by `now`
+    fn decayed((estimate, at): (f64, Instant), now: Instant) -> f64 {
+        let idle = now.duration_since(at);
+        let half_lives = (idle.as_secs() as f64 + f64::from(idle.subsec_millis()) / 1000.0) / HALF_LIFE.as_secs() as f64;
+        estimate * 0.5f64.powf(half_lives)
+    }
+
+    /// Which of `copies`, named by node with the local copy as `LOCAL_COPY`, should serve a search. There must be
+    /// at least one copy.
+    pub fn choose(&self, preference: &Preference, copies: &[String]) -> usize {
+        self.choose_at(preference, copies, Instant::now())
+    }
+
+    fn choose_at(&self, preference: &Preference, copies: &[String], now: Instant) -> usize {
+        let local = copies.iter().position(|c| c == LOCAL_COPY);
+        match (preference, local) {
+            (Preference::Primary, Some(local)) | (Preference::Local, Some(local)) => local,
+            (Preference::Replica, Some(local)) if copies.len() > 1 => {
+                let replicas: Vec<usize> = (0..copies.len()).filter(|i| *i != local).collect();
+                replicas[self.fastest(copies, &replicas, now)]
+            }
+            (Preference::Custom(ref key), _) => {
+                let mut sorted: Vec<usize> = (0..copies.len()).collect();
+                sorted.sort_by_key(|i| &copies[*i]);
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                sorted[(hasher.finish() % copies.len() as u64) as usize]
+            }
+            _ => self.fastest(copies, &(0..copies.len()).collect::<Vec<_>>(), now),
+        }
+    }
+
+    /// The position within `candidates` of the copy with the lowest latency. Copies that haven't been searched yet
+    /// count as the fastest, so each gets tried.
+    fn fastest(&self, copies: &[String], candidates: &[usize], now: Instant) -> usize {
+        let latencies = match self.latencies.lock() {
+            Ok(latencies) => latencies,
+            Err(_) => return 0,
+        };
+        let estimate = |i: usize| {
+            latencies
+                .get(&copies[candidates[i]])
+                .map_or(0.0, |latency| Routing::decayed(*latency, now))
+        };
+        let mut best = 0;
+        for at in 1..candidates.len() {
+            if estimate(at) < estimate(best) {
+                best = at;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copies() -> Vec<String> {
+        vec![LOCAL_COPY.to_string(), "10.0.0.2:8080".to_string(), "10.0.0.3:8080".to_string()]
+    }
+
+    #[test]
+    fn test_preferences() {
+        let routing = Routing::new(&["10.0.0.2:8080".into(), "10.0.0.3:8080".into()]);
+        let copies = copies();
+        routing.record(LOCAL_COPY, Duration::from_millis(5));
+        routing.record("10.0.0.2:8080", Duration::from_millis(80));
+        routing.record("10.0.0.3:8080", Duration::from_millis(20));
+
+        assert_eq!(routing.choose(&Preference::Adaptive, &copies), 0);
+        assert_eq!(routing.choose(&Preference::Primary, &copies), 0);
+        assert_eq!(routing.choose(&Preference::Replica, &copies), 2);
+        assert_eq!(routing.choose(&Preference::Replica, &copies[..1]), 0);
+
+        let session = Preference::parse(Some("user-42"));
+        let chosen = routing.choose(&session, &copies);
+        let mut reordered = copies.clone();
+        reordered.reverse();
+        assert_eq!(reordered[routing.choose(&session, &reordered)], copies[chosen]);
+    }
+
+    #[test]
+    fn test_adaptive_selection() {
+        let routing = Routing::default();
+        let copies = copies();
+        routing.record(LOCAL_COPY, Duration::from_millis(10));
+        routing.record("10.0.0.2:8080", Duration::from_millis(15));
+        routing.record("10.0.0.3:8080", Duration::from_millis(12));
+        assert_eq!(routing.choose(&Preference::Adaptive, &copies), 0);
+
+        // A run of slow searches moves the local copy behind the others
+        for _ in 0..5 {
+            routing.record(LOCAL_COPY, Duration::from_millis(100));
+        }
+        assert_eq!(routing.choose(&Preference::Adaptive, &copies), 2);
+        routing.record("10.0.0.3:8080", FAILURE_PENALTY);
+        assert_eq!(routing.choose(&Preference::Adaptive, &copies), 1);
+    }
+
+    #[test]
+    fn test_penalty_decays() {
+        let routing = Routing::default();
+        let copies = copies();
+        let start = Instant::now();
+        routing.record_at(LOCAL_COPY, FAILURE_PENALTY, start);
+        routing.record_at("10.0.0.2:8080", Duration::from_millis(20), start);
+        routing.record_at("10.0.0.3:8080", Duration::from_millis(30), start);
+        assert_eq!(routing.choose_at(&Preference::Adaptive, &copies, start), 1);
+
+        // The copies that are searched keep their estimates, while the failed one's comes down until it's tried again
+        let later = start + HALF_LIFE * 10;
+        routing.record_at("10.0.0.2:8080", Duration::from_millis(20), later);
+        routing.record_at("10.0.0.3:8080", Duration::from_millis(30), later);
+        assert_eq!(routing.choose_at(&Preference::Adaptive, &copies, later), 0);
+    }
+}
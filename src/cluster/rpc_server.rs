@@ -3,6 +3,7 @@ use std::sync::{Arc, RwLock};
 
 use futures::{future, future::Future, stream::Stream};
 use log::{error, info};
+use tantivy::schema::Schema;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_executor::DefaultExecutor;
 use tower_buffer::Buffer;
@@ -15,11 +16,18 @@ use tower_util::MakeService;
 
 use crate::cluster::cluster_rpc::server;
 use crate::cluster::cluster_rpc::*;
+use crate::cluster::rebalance;
+use crate::cluster::seeds::Membership;
+use crate::cluster::shipping;
 use crate::cluster::GrpcConn;
 use crate::cluster::RPCError;
+use crate::executor::blocking;
 use crate::handle::IndexHandle;
+use crate::handlers::index::{AddDocument, DeleteDoc};
+use crate::handlers::BulkHandler;
 use crate::index::IndexCatalog;
 use crate::query;
+use crate::storage::{DirectoryType, StorageSettings};
 
 pub type Buf = Buffer<AddOrigin<Connection<TcpStream, DefaultExecutor, BoxBody>>, http::Request<BoxBody>>;
 pub type RpcClient = client::IndexService<Buf>;
@@ -29,19 +37,22 @@ pub type RpcClient = client::IndexService<Buf>;
 /// indexes are stored and make the RPC query to the node to get the data.
 pub struct RpcServer {
     catalog: Arc<RwLock<IndexCatalog>>,
+    membership: Membership,
 }
 
 impl Clone for RpcServer {
     fn clone(&self) -> Self {
         Self {
             catalog: Arc::clone(&self.catalog),
+            membership: self.membership.clone(),
         }
     }
 }
 
 impl RpcServer {
-    pub fn get_service(addr: SocketAddr, catalog: Arc<RwLock<IndexCatalog>>) -> impl Future<Item = (), Error = ()> {
-        let service = server::IndexServiceServer::new(RpcServer { catalog });
+    /// Serve the index service on `addr`, answering nodes that join through it with `membership`
+    pub fn get_service(addr: SocketAddr, catalog: Arc<RwLock<IndexCatalog>>, membership: Membership) -> impl Future<Item = (), Error = ()> {
+        let service = server::IndexServiceServer::new(RpcServer { catalog, membership });
         let executor = DefaultExecutor::current();
 
         info!("Binding on port: {:?}", addr);
@@ -84,6 +95,97 @@ impl RpcServer {
     pub fn create_search_reply(result: Option<ResultReply>, doc: Vec<u8>) -> SearchReply {
         SearchReply { result, doc }
     }
+
+    /// Reply to a write, reporting whether it was applied
+    fn write_reply(result: crate::Result<()>) -> Response<ResultReply> {
+        match result {
+            Ok(()) => Response::new(RpcServer::create_result(ResultCode::Success as i32, "".into())),
+            Err(e) => {
+                error!("Failed to apply replicated write: {}", e);
+                Response::new(RpcServer::create_result(ResultCode::Failure as i32, e.to_string()))
+            }
+        }
+    }
+
+    fn place_schema(&self, index: &str, schema: &[u8]) -> crate::Result<()> {
+        let schema: Schema = serde_json::from_slice(schema)?;
+        self.catalog.write()?.create_index(index, schema, None, StorageSettings::default())
+    }
+
+    /// Apply a replicated write with `apply` on a blocking thread, since applying it commits, and reply once it's done.
+    /// The primary counts the reply as the write being accepted, so it's only sent once the write is durable.
+    fn apply_write<F>(&self, apply: F) -> Box<Future<Item = Response<ResultReply>, Error = Error> + Send>
+    where
+        F: FnOnce(&RpcServer) -> crate::Result<()> + Send + 'static,
+    {
+        let server = self.clone();
+        Box::new(blocking(move || apply(&server)).then(|result| Ok(RpcServer::write_reply(result))))
+    }
+
+    fn add_document(&self, index: &str, document: &[u8]) -> crate::Result<()> {
+        let doc: AddDocument = serde_json::from_slice(document)?;
+        let cat = self.catalog.read()?;
+        let shard = cat.route(index, &doc.document)?;
+        shard.add_document(doc)?;
+        shard.commit()?;
+        Ok(())
+    }
+
+    fn index_bulk(&self, index: &str, documents: Vec<u8>) -> crate::Result<()> {
+        BulkHandler::new(Arc::clone(&self.catalog)).index_bulk(documents, index)?;
+        for shard in self.catalog.read()?.shards(index)? {
+            shard.commit()?;
+        }
+        Ok(())
+    }
+
+    fn delete_terms(&self, index: &str, terms: &[u8]) -> crate::Result<()> {
+        let delete: DeleteDoc = serde_json::from_slice(terms)?;
+        let cat = self.catalog.read()?;
+        cat.delete_documents(index, &delete)?;
+        for shard in cat.shards(index)? {
+            shard.commit()?;
+        }
+        Ok(())
+    }
+
+    fn index_stats(&self) -> crate::Result<Vec<IndexStats>> {
+        let cat = self.catalog.read()?;
+        let mut stats = Vec::new();
+        for index in cat.index_names() {
+            let mut docs = 0;
+            for shard in cat.shards(&index)? {
+                docs += shard
+                    .get_index()
+                    .load_metas()?
+                    .segments
+                    .iter()
+                    .map(|s| u64::from(s.num_docs()))
+                    .sum::<u64>();
+            }
+            let bytes = rebalance::index_files(&cat.index_path(&index)?)?.iter().map(|(_, size)| size).sum();
+            stats.push(IndexStats { index, docs, bytes });
+        }
+        Ok(stats)
+    }
+
+    fn index_files(&self, index: &str) -> crate::Result<Vec<IndexFile>> {
+        let path = self.catalog.read()?.index_path(index)?;
+        // An index kept in memory has nothing on disk but its schema to copy
+        if StorageSettings::load(&path)?.0.directory == DirectoryType::Ram {
+            return Err(crate::Error::IOError(format!(
+                "Index {} is kept in memory and can't be copied",
+                index
+            )));
+        }
+        let files = rebalance::index_files(&path)?;
+        Ok(files.into_iter().map(|(name, size)| IndexFile { name, size }).collect())
+    }
+
+    fn read_file(&self, request: &FetchRequest) -> crate::Result<Vec<u8>> {
+        let path = self.catalog.read()?.index_path(&request.index)?;
+        rebalance::read_chunk(&path, &request.name, request.offset, request.length)
+    }
 }
 
 impl server::IndexService for RpcServer {
@@ -92,16 +194,26 @@ impl server::IndexService for RpcServer {
     type PlaceDocumentFuture = Box<Future<Item = Response<ResultReply>, Error = Error> + Send>;
     type PlaceReplicaFuture = Box<Future<Item = Response<ResultReply>, Error = Error> + Send>;
     type SearchIndexFuture = future::FutureResult<Response<SearchReply>, Error>;
+    type BulkInsertFuture = Box<Future<Item = Response<ResultReply>, Error = Error> + Send>;
+    type DeleteDocumentsFuture = Box<Future<Item = Response<ResultReply>, Error = Error> + Send>;
+    type JoinFuture = future::FutureResult<Response<JoinReply>, Error>;
+    type NodeStatsFuture = future::FutureResult<Response<StatsReply>, Error>;
+    type ListFilesFuture = future::FutureResult<Response<FilesReply>, Error>;
+    type FetchFileFuture = future::FutureResult<Response<FetchReply>, Error>;
+    type RemoveIndexFuture = future::FutureResult<Response<ResultReply>, Error>;
+    type SyncIndexFuture = Box<Future<Item = Response<ResultReply>, Error = Error> + Send>;
+    type FreezeIndexFuture = future::FutureResult<Response<ResultReply>, Error>;
 
-    fn place_index(&mut self, _request: Request<PlaceRequest>) -> Self::PlaceIndexFuture {
-        unimplemented!()
+    fn place_index(&mut self, request: Request<PlaceRequest>) -> Self::PlaceIndexFuture {
+        let inner = request.into_inner();
+        future::finished(RpcServer::write_reply(self.place_schema(&inner.index, &inner.schema)))
     }
 
     fn list_indexes(&mut self, _: Request<ListRequest>) -> Self::ListIndexesFuture {
         if let Ok(ref mut cat) = self.catalog.read() {
-            let indexes = cat.get_collection();
-            let lists: Vec<String> = indexes.into_iter().map(|t| t.0.to_string()).collect();
-            let resp = Response::new(ListReply { indexes: lists });
+            let resp = Response::new(ListReply {
+                indexes: cat.index_names(),
+            });
             future::finished(resp)
         } else {
             let status = Status::with_code_and_message(Code::NotFound, "Could not get lock on index catalog".into());
@@ -110,20 +222,120 @@ impl server::IndexService for RpcServer {
         }
     }
 
-    fn place_document(&mut self, _request: Request<DocumentRequest>) -> Self::PlaceDocumentFuture {
-        unimplemented!()
+    fn place_document(&mut self, request: Request<DocumentRequest>) -> Self::PlaceDocumentFuture {
+        let inner = request.into_inner();
+        self.apply_write(move |server| server.add_document(&inner.index, &inner.document))
+    }
+
+    fn bulk_insert(&mut self, request: Request<BulkRequest>) -> Self::BulkInsertFuture {
+        let inner = request.into_inner();
+        self.apply_write(move |server| server.index_bulk(&inner.index, inner.documents))
+    }
+
+    fn delete_documents(&mut self, request: Request<DeleteRequest>) -> Self::DeleteDocumentsFuture {
+        let inner = request.into_inner();
+        self.apply_write(move |server| server.delete_terms(&inner.index, &inner.terms))
+    }
+
+    fn join(&mut self, request: Request<JoinRequest>) -> Self::JoinFuture {
+        let inner = request.into_inner();
+        info!("Node joined: {}", inner.addr);
+        self.membership.extend(Some(inner.addr));
+        future::finished(Response::new(JoinReply {
+            nodes: self.membership.members(),
+        }))
+    }
+
+    fn place_replica(&mut self, request: Request<ReplicaRequest>) -> Self::PlaceReplicaFuture {
+        let inner = request.into_inner();
+        let from = match inner.from.parse() {
+            Ok(from) => from,
+            Err(e) => return Box::new(future::finished(RpcServer::write_reply(Err(crate::Error::IOError(e.to_string()))))),
+        };
+        info!("Copying index {} from {}", inner.index, inner.from);
+        let pull = rebalance::pull_index(Arc::clone(&self.catalog), inner.index, from, true)
+            .then(|result| Ok::<_, Error>(RpcServer::write_reply(result)));
+        Box::new(pull)
+    }
+
+    fn node_stats(&mut self, _: Request<StatsRequest>) -> Self::NodeStatsFuture {
+        match self.index_stats() {
+            Ok(indexes) => future::finished(Response::new(StatsReply { indexes })),
+            Err(e) => future::failed(Error::Grpc(Status::with_code_and_message(Code::Internal, e.to_string()))),
+        }
+    }
+
+    fn list_files(&mut self, request: Request<FilesRequest>) -> Self::ListFilesFuture {
+        let inner = request.into_inner();
+        let reply = match self.index_files(&inner.index) {
+            Ok(files) => FilesReply {
+                result: Some(RpcServer::create_result(ResultCode::Success as i32, "".into())),
+                files,
+            },
+            Err(e) => FilesReply {
+                result: Some(RpcServer::create_result(ResultCode::Failure as i32, e.to_string())),
+                files: vec![],
+            },
+        };
+        future::finished(Response::new(reply))
     }
 
-    fn place_replica(&mut self, _request: Request<ReplicaRequest>) -> Self::PlaceReplicaFuture {
-        unimplemented!()
+    fn fetch_file(&mut self, request: Request<FetchRequest>) -> Self::FetchFileFuture {
+        let inner = request.into_inner();
+        let reply = match self.read_file(&inner) {
+            Ok(data) => FetchReply {
+                result: Some(RpcServer::create_result(ResultCode::Success as i32, "".into())),
+                data,
+            },
+            Err(e) => FetchReply {
+                result: Some(RpcServer::create_result(ResultCode::Failure as i32, e.to_string())),
+                data: vec![],
+            },
+        };
+        future::finished(Response::new(reply))
+    }
+
+    fn remove_index(&mut self, request: Request<RemoveRequest>) -> Self::RemoveIndexFuture {
+        let inner = request.into_inner();
+        info!("Removing index {}, which moved to another node", inner.index);
+        let result = self
+            .catalog
+            .write()
+            .map_err(crate::Error::from)
+            .and_then(|mut cat| cat.remove_index(&inner.index));
+        future::finished(RpcServer::write_reply(result))
+    }
+
+    fn sync_index(&mut self, request: Request<SyncRequest>) -> Self::SyncIndexFuture {
+        let inner = request.into_inner();
+        let from = match inner.from.parse() {
+            Ok(from) => from,
+            Err(e) => return Box::new(future::finished(RpcServer::write_reply(Err(crate::Error::IOError(e.to_string()))))),
+        };
+        let sync =
+            shipping::receive(Arc::clone(&self.catalog), inner.index, from).then(|result| Ok::<_, Error>(RpcServer::write_reply(result)));
+        Box::new(sync)
+    }
+
+    fn freeze_index(&mut self, request: Request<FreezeRequest>) -> Self::FreezeIndexFuture {
+        let inner = request.into_inner();
+        info!("Setting index {} read only: {}", inner.index, inner.frozen);
+        let result = self.catalog.read().map_err(crate::Error::from).and_then(|cat| {
+            for shard in cat.shards(&inner.index)? {
+                shard.set_read_only(inner.frozen)?;
+            }
+            Ok(())
+        });
+        future::finished(RpcServer::write_reply(result))
     }
 
     fn search_index(&mut self, request: Request<SearchRequest>) -> Self::SearchIndexFuture {
         let inner = request.into_inner();
         if let Ok(ref mut cat) = self.catalog.read() {
-            let index = cat.get_index(&inner.index).unwrap();
-            let query: query::Request = serde_json::from_slice(&inner.query).unwrap();
-            match index.search_index(query) {
+            let results = serde_json::from_slice::<query::Request>(&inner.query)
+                .map_err(crate::Error::from)
+                .and_then(|query| cat.search_index(&inner.index, query));
+            match results {
                 Ok(query_results) => {
                     let query_bytes: Vec<u8> = serde_json::to_vec(&query_results).unwrap();
                     let result = Some(RpcServer::create_result(0, "".into()));
@@ -151,13 +363,21 @@ mod tests {
     use futures::future::Future;
     use http::Uri;
 
+    use super::server::IndexService;
     use super::*;
 
     #[test]
     #[ignore]
     fn client_test() {
         let body = r#"test_text:"Duckiment""#;
-        let req = query::Request::new(Some(Query::Raw { raw: body.into() }), None, 10);
+        let req = query::Request::new(
+            Some(Query::Raw {
+                raw: body.into(),
+                fields: Vec::new(),
+            }),
+            None,
+            10,
+        );
         let list = ListRequest {};
         let socket_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
 
@@ -170,7 +390,7 @@ mod tests {
 
         let tcp_stream = GrpcConn(socket_addr);
         let cat = create_test_catalog("test_index");
-        let service = RpcServer::get_service(socket_addr, cat);
+        let service = RpcServer::get_service(socket_addr, cat, Membership::default());
 
         let client_fut = RpcServer::create_client(tcp_stream.clone(), host_uri)
             .and_then(|mut client| {
@@ -190,4 +410,40 @@ mod tests {
 
         tokio::run(s);
     }
+
+    #[test]
+    fn test_replicated_writes_are_committed() {
+        let cat = create_test_catalog("test_index");
+        let mut server = RpcServer {
+            catalog: Arc::clone(&cat),
+            membership: Membership::default(),
+        };
+        let committed_docs = || -> u32 {
+            let index = cat.read().unwrap().get_index("test_index").unwrap().get_index().clone();
+            index.load_metas().unwrap().segments.iter().map(|segment| segment.num_docs()).sum()
+        };
+        let before = committed_docs();
+
+        let doc = AddDocument {
+            options: None,
+            document: serde_json::json!({ "test_text": "Replicated", "test_i64": 1, "test_u64": 1, "test_unindex": "r" }),
+        };
+        let request = DocumentRequest {
+            index: "test_index".into(),
+            document: serde_json::to_vec(&doc).unwrap(),
+        };
+        let reply = server.place_document(Request::new(request)).wait().unwrap().into_inner();
+        assert_eq!(reply.code, ResultCode::Success as i32);
+        assert_eq!(committed_docs(), before + 1);
+
+        let mut terms = std::collections::HashMap::new();
+        terms.insert("test_text".to_string(), "replicated".to_string());
+        let request = DeleteRequest {
+            index: "test_index".into(),
+            terms: serde_json::to_vec(&DeleteDoc { options: None, terms }).unwrap(),
+        };
+        let reply = server.delete_documents(Request::new(request)).wait().unwrap().into_inner();
+        assert_eq!(reply.code, ResultCode::Success as i32);
+        assert_eq!(committed_docs(), before);
+    }
 }
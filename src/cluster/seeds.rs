@@ -0,0 +1,150 @@
+//! Clustering from a static list of seed nodes, for clusters too small to be worth running a discovery service.
+//! A node joins by telling every seed its address over the RPC layer, and each seed answers with every member it
+//! has heard of, so the whole cluster is learned from any one seed that's up.
+
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use http::Uri;
+use log::debug;
+use serde::Serialize;
+use tower_grpc::Request;
+
+use crate::cluster::cluster_rpc::JoinRequest;
+use crate::cluster::rpc_server::RpcServer;
+use crate::cluster::shard::Shard;
+use crate::cluster::store::{MetadataStore, StoreFuture};
+use crate::cluster::{ClusterError, GrpcConn, RPCError};
+
+/// The addresses of the members a node knows of, shared between its RPC service and its store
+#[derive(Clone, Debug, Default)]
+pub struct Membership {
+    members: Arc<RwLock<BTreeSet<String>>>,
+}
+
+impl Membership {
+    pub fn new<I: IntoIterator<Item = String>>(members: I) -> Self {
+        Membership {
+            members: Arc::new(RwLock::new(members.into_iter().collect())),
+        }
+    }
+
+    pub fn extend<I: IntoIterator<Item = String>>(&self, members: I) {
+        self.members.write().unwrap().extend(members);
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        self.members.read().unwrap().iter().cloned().collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Seeds {
+    cluster_name: String,
+    node_id: String,
+    node_addr: String,
+    seeds: Vec<SocketAddr>,
+    membership: Membership,
+}
+
+impl Seeds {
+    /// A store that joins the cluster through `seeds`, advertising this node as `node_addr`
+    pub fn new(cluster_name: String, node_addr: String, seeds: &[String], membership: Membership) -> Self {
+        Seeds {
+            cluster_name,
+            node_id: String::new(),
+            node_addr,
+            seeds: seeds.iter().filter_map(|s| s.parse().ok()).collect(),
+            membership,
+        }
+    }
+
+    /// Join through every seed, adding the members they know of. Seeds that can't be reached are skipped, and this
+    /// only fails when none of them can be.
+    pub fn join(&self) -> impl Future<Item = Vec<String>, Error = ClusterError> {
+        let addr = self.node_addr.clone();
+        let joins = self.seeds.iter().map(move |seed| {
+            let seed = *seed;
+            let request = JoinRequest { addr: addr.clone() };
+            let uri: Uri = format!("http://{}", seed).parse().unwrap();
+            RpcServer::create_client(GrpcConn(seed), uri)
+                .and_then(move |mut client| client.join(Request::new(request)).map_err(RPCError::from))
+                .map(|reply| Some(reply.into_inner().nodes))
+                .or_else(move |e| {
+                    debug!("Unable to join seed {}: {}", seed, e);
+                    Ok::<_, ClusterError>(None)
+                })
+        });
+        let membership = self.membership.clone();
+        let seeds = self.seeds.len();
+        future::join_all(joins).and_then(move |replies| {
+            let replies: Vec<Vec<String>> = replies.into_iter().filter_map(|r| r).collect();
+            if replies.is_empty() && seeds > 0 {
+                return Err(ClusterError::FailedFetchingNodes("No seed node could be reached".into()));
+            }
+            for nodes in replies {
+                membership.extend(nodes);
+            }
+            Ok(membership.members())
+        })
+    }
+}
+
+impl MetadataStore for Seeds {
+    fn cluster_name(&self) -> &str {
+        &self.cluster_name
+    }
+
+    fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    fn register_cluster(&mut self) -> StoreFuture<()> {
+        Box::new(future::ok(()))
+    }
+
+    fn register_node(&mut self) -> StoreFuture<()> {
+        Box::new(self.join().map(|_| ()))
+    }
+
+    fn register_shard<T>(&mut self, _: &T) -> StoreFuture<()>
+    where
+        T: Shard + Serialize,
+    {
+        Box::new(future::err(ClusterError::FailedCreatingPrimaryShard(
+            "Seed node clustering doesn't store shards".into(),
+        )))
+    }
+
+    /// Rejoining keeps this node known to seeds that restarted, and picks up members that joined since
+    fn nodes(&mut self) -> StoreFuture<Vec<String>> {
+        Box::new(self.join())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_membership() {
+        let membership = Membership::new(vec!["10.0.0.1:8081".to_string()]);
+        let shared = membership.clone();
+        shared.extend(vec!["10.0.0.2:8081".to_string(), "10.0.0.1:8081".to_string()]);
+        assert_eq!(membership.members(), vec!["10.0.0.1:8081", "10.0.0.2:8081"]);
+
+        let seeds = Seeds::new(
+            "kitsune".into(),
+            "10.0.0.3:8080".into(),
+            &["10.0.0.1:8081".into(), "bad".into()],
+            membership,
+        );
+        assert_eq!(seeds.seeds.len(), 1);
+    }
+}
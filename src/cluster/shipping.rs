@@ -0,0 +1,233 @@
+//! Replicating indexes by shipping their committed segments rather than their writes. Replicas in this mode never
+//! index a document themselves: every so often the primary tells each replica which of its indexes have had commits
+//! since they were last shipped, and the replica copies whatever files it's missing over RPC, the metadata last, so
+//! its searches move straight from one commit to the next. Replicas lag behind by up to a shipping interval, but
+//! spend next to nothing on keeping up.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use log::{debug, error, warn};
+use tokio::timer::Interval;
+use tower_grpc::Request;
+
+use crate::cluster::cluster_rpc::*;
+use crate::cluster::rebalance;
+use crate::index::IndexCatalog;
+use crate::{Error, Result};
+
+/// Bring `index` here up to date with its copy on the node at `from`, copying all of it if it isn't here yet
+pub fn receive(catalog: Arc<RwLock<IndexCatalog>>, index: String, from: SocketAddr) -> Box<Future<Item = (), Error = Error> + Send> {
+    let path = match catalog.read() {
+        Ok(cat) => cat.index_path(&index),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(Error::UnknownIndex(_)) = path {
+        return rebalance::pull_index(catalog, index, from, false);
+    }
+    // Only what's changed since the last shipment is fetched
+    let present = path.and_then(|path| Ok((rebalance::index_files(&path)?, path)));
+    let sync = future::result(present).and_then(move |(present, path)| {
+        let present: HashSet<(String, u64)> = present.into_iter().collect();
+        rebalance::connect(from).and_then(move |client| rebalance::copy_files(client, index, path, present))
+    });
+    Box::new(sync.map(|_| ()))
+}
+
+/// The opstamp of every shard of `index`'s last commit, which changes whenever there's something new to ship
+fn commit_version(catalog: &IndexCatalog, index: &str) -> Result<Vec<u64>> {
+    catalog
+        .shards(index)?
+        .iter()
+        .map(|shard| Ok(shard.get_index().load_metas()?.opstamp))
+        .collect()
+}
+
+/// Ships the primary's committed segments to its replicas
+pub struct SegmentShipper {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    /// The address replicas fetch files from, this node's RPC service
+    addr: String,
+    replicas: Vec<SocketAddr>,
+    /// The commit last shipped to each replica, by replica and index
+    shipped: Arc<RwLock<HashMap<(SocketAddr, String), Vec<u64>>>>,
+}
+
+impl SegmentShipper {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, addr: String, replicas: &[String]) -> Self {
+        SegmentShipper {
+            catalog,
+            addr,
+            replicas: replicas.iter().filter_map(|r| r.parse().ok()).collect(),
+            shipped: Arc::default(),
+        }
+    }
+
+    /// Ship new commits every `interval`. A replica that fails to take a shipment is sent it again next time.
+    pub fn run(self, interval: Duration) -> impl Future<Item = (), Error = ()> {
+        Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| error!("Segment shipping timer failed: {}", e))
+            .for_each(move |_| {
+                self.ship();
+                Ok(())
+            })
+    }
+
+    fn ship(&self) {
+        let versions: Vec<(String, Vec<u64>)> = match self.catalog.read() {
+            Ok(cat) => cat
+                .index_names()
+                .into_iter()
+                .filter_map(|index| commit_version(&cat, &index).ok().map(|version| (index, version)))
+                .collect(),
+            Err(e) => return error!("Unable to read the index catalog: {}", e),
+        };
+        for replica in &self.replicas {
+            for (index, version) in self.stale(*replica, &versions) {
+                tokio::spawn(self.ship_to(*replica, index, version));
+            }
+        }
+    }
+
+    /// The indexes among `versions` whose commit `replica` hasn't been shipped yet
+    fn stale(&self, replica: SocketAddr, versions: &[(String, Vec<u64>)]) -> Vec<(String, Vec<u64>)> {
+        let shipped = self.shipped.read().unwrap_or_else(|e| e.into_inner());
+        versions
+            .iter()
+            .filter(|(index, version)| shipped.get(&(replica, index.clone())) != Some(version))
+            .cloned()
+            .collect()
+    }
+
+    fn ship_to(&self, replica: SocketAddr, index: String, version: Vec<u64>) -> impl Future<Item = (), Error = ()> {
+        let request = SyncRequest {
+            index: index.clone(),
+            from: self.addr.clone(),
+        };
+        let shipped = Arc::clone(&self.shipped);
+        // Until the shipment is done it counts as shipped, so a slow one isn't started over on the next tick
+        shipped
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((replica, index.clone()), version.clone());
+        rebalance::connect(replica)
+            .and_then(|mut client| client.sync_index(Request::new(request)).map_err(|e| Error::IOError(e.to_string())))
+            .and_then(|reply| {
+                let reply = reply.into_inner();
+                if reply.code == ResultCode::Success as i32 {
+                    Ok(())
+                } else {
+                    Err(Error::IOError(reply.message))
+                }
+            })
+            .then(move |result| {
+                match result {
+                    Ok(()) => debug!("Shipped segments of {} to {}", index, replica),
+                    Err(e) => {
+                        warn!("Failed to ship segments of {} to {}: {}", index, replica, e);
+                        let mut shipped = shipped.write().unwrap_or_else(|e| e.into_inner());
+                        if shipped.get(&(replica, index.clone())) == Some(&version) {
+                            shipped.remove(&(replica, index));
+                        }
+                    }
+                }
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::rpc_server::RpcServer;
+    use crate::cluster::seeds::Membership;
+    use crate::handle::IndexHandle;
+    use crate::handlers::index::{AddDocument, IndexOptions};
+    use crate::index::tests::create_test_catalog;
+    use crate::storage::StorageSettings;
+    use std::fs;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::Index;
+    use tokio::runtime::Runtime;
+
+    fn free_addr() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn catalog_at(path: &std::path::Path) -> IndexCatalog {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+        let mut catalog = IndexCatalog::with_path(path.to_path_buf()).unwrap();
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("text", STORED | TEXT);
+        catalog
+            .create_index("logs", builder.build(), None, StorageSettings::default())
+            .unwrap();
+        catalog
+    }
+
+    #[test]
+    fn test_receive() {
+        let root = std::env::temp_dir().join("toshi-shipping-test");
+        let primary = catalog_at(&root.join("primary"));
+        for text in &["first", "second"] {
+            let doc = AddDocument {
+                options: Some(IndexOptions { commit: true }),
+                document: serde_json::json!({ "text": text }),
+            };
+            primary.get_index("logs").unwrap().add_document(doc).unwrap();
+        }
+        let replica = Arc::new(RwLock::new(catalog_at(&root.join("replica"))));
+
+        let addr = free_addr();
+        let primary = Arc::new(RwLock::new(primary));
+        let mut runtime = Runtime::new().unwrap();
+        let service = runtime
+            .block_on(future::lazy(move || {
+                Ok::<_, ()>(RpcServer::get_service(addr, primary, Membership::default()))
+            }))
+            .unwrap();
+        runtime.spawn(service);
+
+        // The replica already has the index, so only the files it's missing are fetched into it
+        runtime.block_on(receive(Arc::clone(&replica), "logs".into(), addr)).unwrap();
+        let path = replica.read().unwrap().index_path("logs").unwrap();
+        let received = Index::open_in_dir(&path).unwrap();
+        received.load_searchers().unwrap();
+        assert_eq!(received.searcher().num_docs(), 2);
+        drop(replica);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_stale() {
+        let catalog = create_test_catalog("test_index");
+        let version = commit_version(&catalog.read().unwrap(), "test_index").unwrap();
+        let (first, second) = (free_addr(), free_addr());
+        let shipper = SegmentShipper::new(catalog, "127.0.0.1:8081".into(), &[first.to_string(), second.to_string()]);
+        let versions = vec![("test_index".to_string(), version.clone())];
+        assert_eq!(shipper.stale(first, &versions), versions);
+
+        // Each replica is kept track of on its own
+        shipper
+            .shipped
+            .write()
+            .unwrap()
+            .insert((first, "test_index".into()), version.clone());
+        assert!(shipper.stale(first, &versions).is_empty());
+        assert_eq!(shipper.stale(second, &versions), versions);
+
+        // A new commit makes the index stale again
+        let committed = vec![("test_index".to_string(), version.iter().map(|opstamp| opstamp + 1).collect())];
+        assert_eq!(shipper.stale(first, &committed), committed);
+
+        // A shipment that fails is forgotten, so it's sent again next time
+        let mut runtime = Runtime::new().unwrap();
+        let failed = shipper.ship_to(second, "test_index".into(), version);
+        runtime.block_on(failed).unwrap();
+        assert_eq!(shipper.stale(second, &versions), versions);
+    }
+}
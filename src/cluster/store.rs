@@ -0,0 +1,32 @@
+//! The interface to wherever a cluster keeps its shared metadata, so Toshi isn't tied to one service for it
+
+use futures::Future;
+use serde::Serialize;
+
+use crate::cluster::shard::Shard;
+use crate::cluster::ClusterError;
+
+pub type StoreFuture<T> = Box<Future<Item = T, Error = ClusterError> + Send>;
+
+/// A store of cluster metadata. Clones should be cheap, and share a connection to the store.
+pub trait MetadataStore: Clone + Send + 'static {
+    fn cluster_name(&self) -> &str;
+
+    fn node_id(&self) -> &str;
+
+    fn set_node_id(&mut self, node_id: String);
+
+    /// Record that the cluster exists
+    fn register_cluster(&mut self) -> StoreFuture<()>;
+
+    /// Record this node as a member of the cluster
+    fn register_node(&mut self) -> StoreFuture<()>;
+
+    /// Record a shard and the index it belongs to
+    fn register_shard<T>(&mut self, shard: &T) -> StoreFuture<()>
+    where
+        T: Shard + Serialize;
+
+    /// The addresses of the cluster's nodes
+    fn nodes(&mut self) -> StoreFuture<Vec<String>>;
+}
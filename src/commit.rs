@@ -1,46 +1,139 @@
+use crate::executor::Executor;
 use crate::index::IndexCatalog;
+use crate::settings::Settings;
 
 use futures::{Future, Stream};
-use log::debug;
+use futures_watch::Watch;
+use log::{debug, error};
 use tokio::timer::Interval;
 
 use std::{
     sync::{Arc, RwLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 pub struct IndexWatcher {
     commit_duration: u64,
     catalog: Arc<RwLock<IndexCatalog>>,
+    settings: Option<Watch<Settings>>,
+    executor: Executor,
 }
 
 impl IndexWatcher {
     pub fn new(catalog: Arc<RwLock<IndexCatalog>>, commit_duration: u64) -> Self {
-        IndexWatcher { catalog, commit_duration }
+        IndexWatcher {
+            catalog,
+            commit_duration,
+            settings: None,
+            executor: Executor::default(),
+        }
+    }
+
+    /// Run warm-up queries on `executor`, the pool searches run on, rather than on a thread of their own
+    pub fn with_executor(mut self, executor: Executor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Take the commit duration from reloadable settings instead of the one given at construction,
+    /// a duration of 0 pauses auto committing until it is changed again.
+    pub fn with_settings(mut self, settings: Watch<Settings>) -> Self {
+        self.settings = Some(settings);
+        self
     }
 
     pub fn start(self) {
         let catalog = Arc::clone(&self.catalog);
-        let task = Interval::new_interval(Duration::from_secs(self.commit_duration))
+        let settings = self.settings;
+        let fixed_duration = self.commit_duration;
+        let executor = self.executor;
+        let mut last_commit = Instant::now();
+
+        if let Ok(cat) = catalog.read() {
+            let current = settings.as_ref().map(|s| s.borrow().clone());
+            let queries = IndexWatcher::warmup_queries(current.as_ref().unwrap_or(&cat.settings), &cat.index_names());
+            IndexWatcher::warm_up(&executor, &catalog, queries);
+        }
+
+        // Tick every second rather than every commit duration, so a reloaded duration takes effect promptly
+        let task = Interval::new_interval(Duration::from_secs(1))
             .for_each(move |_| {
-                if let Ok(mut cat) = catalog.write() {
-                    cat.get_mut_collection().into_iter().for_each(|(key, index)| {
-                        let writer = index.get_writer();
-                        let current_ops = index.get_opstamp();
-                        if current_ops == 0 {
-                            debug!("No update to index={}, opstamp={}", key, current_ops);
-                        } else if let Ok(mut w) = writer.lock() {
-                            w.commit().unwrap();
-                            index.set_opstamp(0);
-                        }
-                    });
+                let commit_duration = settings.as_ref().map(|s| s.borrow().auto_commit_duration).unwrap_or(fixed_duration);
+                if commit_duration == 0 || last_commit.elapsed() < Duration::from_secs(commit_duration) {
+                    return Ok(());
                 }
+                last_commit = Instant::now();
+
+                let queries = match catalog.write() {
+                    Ok(mut cat) => {
+                        let mut committed = Vec::new();
+                        cat.get_mut_collection().into_iter().for_each(|(key, index)| {
+                            let current_ops = index.get_opstamp();
+                            if current_ops == 0 {
+                                debug!("No update to index={}, opstamp={}", key, current_ops);
+                            } else if let Err(e) = index.commit() {
+                                error!("Failed to commit index={}: {}", key, e);
+                            } else {
+                                // Shards are warmed up along with the rest of their index
+                                committed.push(key.split('/').next().unwrap_or(key).to_string());
+                            }
+                        });
+                        committed.sort();
+                        committed.dedup();
+                        cat.close_idle_writers();
+
+                        let current = settings.as_ref().map(|s| s.borrow().clone());
+                        IndexWatcher::warmup_queries(current.as_ref().unwrap_or(&cat.settings), &committed)
+                    }
+                    Err(_) => return Ok(()),
+                };
+                // The write lock is let go by now, warm-ups only read the catalog so searches and writes carry on
+                IndexWatcher::warm_up(&executor, &catalog, queries);
                 Ok(())
             })
             .map_err(|e| panic!("Error in commit-watcher={:?}", e));
 
         tokio::spawn(task);
     }
+
+    /// The warm-up queries configured for each of `names` that has any
+    fn warmup_queries(settings: &Settings, names: &[String]) -> Vec<(String, Vec<String>)> {
+        names
+            .iter()
+            .filter_map(|name| {
+                let queries: Vec<String> = settings.warmup_queries_for(name).into_iter().map(String::from).collect();
+                if queries.is_empty() {
+                    None
+                } else {
+                    Some((name.clone(), queries))
+                }
+            })
+            .collect()
+    }
+
+    /// Run the warm-up `queries` of each index on `executor`, so the first search after startup or a commit doesn't
+    /// have to load the new segments from disk itself
+    fn warm_up(executor: &Executor, catalog: &Arc<RwLock<IndexCatalog>>, queries: Vec<(String, Vec<String>)>) {
+        if queries.is_empty() {
+            return;
+        }
+        let catalog = Arc::clone(catalog);
+        executor.spawn(move || {
+            let catalog = match catalog.read() {
+                Ok(catalog) => catalog,
+                Err(e) => {
+                    error!("Unable to warm up indexes: {}", e);
+                    return;
+                }
+            };
+            for (name, queries) in &queries {
+                let queries: Vec<&str> = queries.iter().map(String::as_str).collect();
+                for shard in catalog.shards(name).unwrap_or_default() {
+                    shard.warm_up(&queries);
+                }
+            }
+        });
+    }
 }
 
 #[cfg(test)]
@@ -71,7 +164,7 @@ pub mod tests {
 
         let body = r#"{ "document": { "test_text": "Babbaboo!", "test_u64": 10 , "test_i64": -10, "test_unindex": "asdf1234" } }"#;
         let add: AddDocument = serde_json::from_str(body).unwrap();
-        handler.add(add, "test_index".into()).unwrap();
+        handler.add(add, "test_index".into(), None).wait().unwrap();
 
         std::thread::sleep(std::time::Duration::from_secs(2));
 
@@ -80,4 +173,27 @@ pub mod tests {
         assert_eq!(6, docs.hits);
         rt.shutdown_now();
     }
+
+    #[test]
+    pub fn test_warm_up() {
+        let catalog = IndexCatalog::with_index("test_index".to_string(), create_test_index()).unwrap();
+        let index = catalog.get_index("test_index").unwrap();
+        let queries = [
+            r#"{ "query": { "term": { "test_text": "document" } } }"#,
+            r#"{ "query": { "term": { "asdf": "x" } } }"#,
+        ];
+        assert_eq!(index.warm_up(&queries), 1);
+        assert_eq!(index.warm_up(&[]), 0);
+    }
+
+    #[test]
+    pub fn test_warmup_queries() {
+        let mut settings = crate::settings::Settings::default();
+        settings.warmup_queries = serde_json::from_str(r#"[{ "index": "test_index", "query": "{}" }]"#).unwrap();
+        let names = vec!["test_index".to_string(), "other_index".to_string()];
+        assert_eq!(
+            IndexWatcher::warmup_queries(&settings, &names),
+            vec![("test_index".to_string(), vec!["{}".to_string()])]
+        );
+    }
 }
@@ -0,0 +1,210 @@
+//! Integration with the process managers Toshi is run under
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use log::{debug, error, warn};
+use tokio::timer::Interval;
+
+use crate::{Error, Result};
+
+/// Holds the process id in a file for as long as it is alive, removing the file on drop
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the pid file, refusing to when it names a process that is still running so a second Toshi isn't started
+    /// over the first
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Ok(existing) = fs::read_to_string(&path) {
+            let existing = existing.trim();
+            match existing.parse::<u32>() {
+                Ok(pid) if pid != std::process::id() && is_running(pid) => {
+                    return Err(Error::IOError(format!(
+                        "Toshi is already running as process {}, according to pid file {}",
+                        pid,
+                        path.display()
+                    )));
+                }
+                _ => warn!("Replacing pid file {} left behind by process {}", path.display(), existing),
+            }
+        }
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Whether a process with this id exists
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// There's no cheap way to tell here, so a pid file left behind is always taken to be stale
+#[cfg(not(unix))]
+fn is_running(_: u32) -> bool {
+    false
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Unable to remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Fork into the background and detach from the terminal. This has to happen before any threads
+/// are started, so before the runtime is built. The working directory is kept so relative data
+/// paths keep working, and output goes to `log_file` when one is given.
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&str>) -> Result<()> {
+    use daemonize::Daemonize;
+
+    let mut daemon = Daemonize::new().working_directory(std::env::current_dir()?);
+    if let Some(log_file) = log_file {
+        let stdout = fs::OpenOptions::new().create(true).append(true).open(log_file)?;
+        let stderr = stdout.try_clone()?;
+        daemon = daemon.stdout(stdout).stderr(stderr);
+    }
+    daemon.start().map_err(|e| Error::IOError(format!("Unable to daemonize: {}", e)))
+}
+
+/// Send a state change to systemd when running as a `Type=notify` service, returning whether anyone was listening
+pub fn notify(state: &str) -> Result<bool> {
+    notify_socket(env::var("NOTIFY_SOCKET").ok().as_ref().map(String::as_str), state)
+}
+
+/// Send a state change to the socket systemd gave in `NOTIFY_SOCKET`, if it gave one
+#[cfg(unix)]
+pub fn notify_socket(socket: Option<&str>, state: &str) -> Result<bool> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = match socket {
+        Some(socket) => socket,
+        None => return Ok(false),
+    };
+    if socket.starts_with('@') {
+        warn!("Abstract NOTIFY_SOCKET {} is not supported", socket);
+        return Ok(false);
+    }
+    let sender = UnixDatagram::unbound()?;
+    sender.send_to(state.as_bytes(), socket)?;
+    debug!("Notified systemd: {}", state);
+    Ok(true)
+}
+
+#[cfg(not(unix))]
+pub fn notify_socket(_: Option<&str>, _: &str) -> Result<bool> {
+    Ok(false)
+}
+
+/// Tell systemd the catalog is loaded and the router is accepting requests
+pub fn notify_ready() {
+    if let Err(e) = notify("READY=1") {
+        error!("Unable to notify systemd that Toshi is ready: {}", e);
+    }
+}
+
+pub fn notify_stopping() {
+    if let Err(e) = notify("STOPPING=1") {
+        error!("Unable to notify systemd that Toshi is stopping: {}", e);
+    }
+}
+
+/// How often systemd expects to hear from the watchdog, if it has one enabled for this process
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// Ping the systemd watchdog at twice the rate it requires. It runs on the runtime it is spawned on,
+/// so if that event loop hangs the pings stop and systemd restarts the process.
+pub fn watchdog(interval: Duration) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(interval / 2)
+        .map_err(|e| error!("Watchdog timer failed: {}", e))
+        .for_each(|_| {
+            if let Err(e) = notify("WATCHDOG=1") {
+                error!("Unable to ping the systemd watchdog: {}", e);
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join("toshi-test.pid");
+        {
+            let pid_file = PidFile::create(&path).unwrap();
+            let contents = fs::read_to_string(pid_file.path()).unwrap();
+            assert_eq!(contents.trim(), std::process::id().to_string());
+        }
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pid_file_in_use() {
+        let path = std::env::temp_dir().join(format!("toshi-test-{}.pid", uuid::Uuid::new_v4()));
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        fs::write(&path, format!("{}\n", child.id())).unwrap();
+        assert!(PidFile::create(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), child.id().to_string());
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(pid_file.path()).unwrap().trim(), std::process::id().to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_notify() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = env::temp_dir().join(format!("toshi-notify-{}.sock", uuid::Uuid::new_v4()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        assert!(notify_socket(path.to_str(), "READY=1").unwrap());
+        assert!(!notify_socket(None, "READY=1").unwrap());
+        assert!(!notify_socket(Some("@toshi"), "READY=1").unwrap());
+
+        let mut buf = [0; 16];
+        let read = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"READY=1");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Running Toshi inside another process, without the HTTP server. `Toshi` takes the same JSON queries and documents
+//! as the HTTP API and goes through the same handlers, so an application or a test gets the results a server would
+//! give, synchronously.
+//!
+//! ```no_run
+//! use toshi::embedded::Toshi;
+//!
+//! let toshi = Toshi::open("data/").unwrap();
+//! toshi.add_json("books", r#"{ "title": "The Old Man and the Sea" }"#, true).unwrap();
+//! let results = toshi.search_json("books", r#"{ "query": { "term": { "title": "sea" } } }"#).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use futures::Future;
+use serde_json::Value;
+use tantivy::schema::Schema;
+
+use crate::cluster::routing::Preference;
+use crate::handlers::index::{AddDocument, CreateOptions, DeleteDoc, IndexOptions};
+use crate::handlers::{BulkHandler, IndexHandler, SearchHandler};
+use crate::index::IndexCatalog;
+use crate::query::Request;
+use crate::results::SearchResults;
+use crate::settings::Settings;
+use crate::Result;
+
+#[derive(Clone)]
+pub struct Toshi {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+    index: IndexHandler,
+    bulk: BulkHandler,
+}
+
+impl Toshi {
+    /// Open the indexes kept under `path`, with the default settings
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        Ok(Toshi::from_catalog(Arc::new(RwLock::new(IndexCatalog::with_path(path.into())?))))
+    }
+
+    /// Open the indexes in the data paths of `settings`
+    pub fn with_settings(settings: Settings) -> Result<Self> {
+        let catalog = IndexCatalog::new(settings.data_paths(), settings)?;
+        Ok(Toshi::from_catalog(Arc::new(RwLock::new(catalog))))
+    }
+
+    pub fn from_catalog(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        Toshi {
+            search: SearchHandler::new(Arc::clone(&catalog)),
+            index: IndexHandler::new(Arc::clone(&catalog)),
+            bulk: BulkHandler::new(Arc::clone(&catalog)),
+            catalog,
+        }
+    }
+
+    pub fn catalog(&self) -> &Arc<RwLock<IndexCatalog>> {
+        &self.catalog
+    }
+
+    pub fn create_index(&self, index: &str, schema: Schema, options: Option<CreateOptions>) -> Result<()> {
+        self.index.create_index(index, schema, options)
+    }
+
+    pub fn drop_index(&self, index: &str) -> Result<()> {
+        self.catalog.write()?.remove_index(index)
+    }
+
+    pub fn list_indexes(&self) -> Result<Vec<String>> {
+        Ok(self.catalog.read()?.index_names())
+    }
+
+    /// Index a document, committing the index afterwards when `commit` is set
+    pub fn add(&self, index: &str, document: Value, commit: bool) -> Result<()> {
+        let body = AddDocument {
+            options: Some(IndexOptions { commit }),
+            document,
+        };
+        self.index.add(body, index.to_string(), None).wait().map(|_| ())
+    }
+
+    pub fn add_json(&self, index: &str, document: &str, commit: bool) -> Result<()> {
+        self.add(index, serde_json::from_str(document)?, commit)
+    }
+
+    /// Index newline separated documents, as `POST /:index/_bulk` takes them. They're committed in the background
+    /// once all of them are indexed, so searches may not find them as soon as this returns.
+    pub fn bulk(&self, index: &str, documents: Vec<u8>) -> Result<()> {
+        self.bulk.index_bulk(documents, index)
+    }
+
+    /// Delete the documents holding any of `terms`, returning how many were deleted
+    pub fn delete(&self, index: &str, terms: HashMap<String, String>, commit: bool) -> Result<u32> {
+        let body = DeleteDoc {
+            options: Some(IndexOptions { commit }),
+            terms,
+        };
+        let affected = self.index.delete(body, index.to_string(), None).wait()?;
+        Ok(affected.docs_affected)
+    }
+
+    /// Search an index, or several separated by commas or given as patterns like `logs-*`
+    pub fn search(&self, index: &str, request: Request) -> Result<SearchResults> {
+        self.search.search_refs(request, index.to_string(), Preference::parse(None)).wait()
+    }
+
+    /// Search with a request in the JSON form `POST /:index` takes
+    pub fn search_json(&self, index: &str, request: &str) -> Result<SearchResults> {
+        self.search(index, serde_json::from_str(request)?)
+    }
+
+    /// Commit every index, returning the names of those that failed to
+    pub fn commit(&self) -> Result<Vec<String>> {
+        Ok(self.catalog.read()?.commit_all().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+
+    #[test]
+    fn test_embedded() {
+        let toshi = Toshi::from_catalog(create_test_catalog("test_index"));
+        assert_eq!(toshi.list_indexes().unwrap(), vec!["test_index".to_string()]);
+
+        toshi
+            .add_json(
+                "test_index",
+                r#"{ "test_text": "Babbaboo", "test_i64": 2020, "test_u64": 99, "test_unindex": "x" }"#,
+                true,
+            )
+            .unwrap();
+        let results = toshi
+            .search_json("test_index", r#"{ "query": { "term": { "test_text": "babbaboo" } } }"#)
+            .unwrap();
+        assert_eq!(results.hits, 1);
+        assert_eq!(results.docs[0].doc["test_u64"][0].u64_value(), 99);
+
+        let mut terms = HashMap::new();
+        terms.insert("test_text".to_string(), "babbaboo".to_string());
+        assert_eq!(toshi.delete("test_index", terms, true).unwrap(), 1);
+        assert_eq!(toshi.search("test_index", Request::all_docs()).unwrap().hits, 5);
+        assert!(toshi
+            .search_json("missing", r#"{ "query": { "raw": "test_text:babbaboo" } }"#)
+            .is_err());
+        assert!(toshi.commit().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Dedicated thread pools for searching and indexing, so a burst of one can't starve the other or tie up
+//! the runtime threads that accept and answer requests.
+
+use std::sync::Arc;
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::{future, Async, Future};
+use tokio_threadpool::{Builder, ThreadPool};
+
+use crate::settings::Settings;
+use crate::{Error, Result};
+
+/// Runs blocking work on a pool of threads. Without a pool, work runs on the calling thread and spawned
+/// tasks get a thread of their own, which is how handlers behaved before the pools were configurable.
+#[derive(Clone, Default)]
+pub struct Executor {
+    pool: Option<Arc<ThreadPool>>,
+}
+
+impl Executor {
+    pub fn new(name: &str, threads: usize) -> Self {
+        let pool = Builder::new()
+            .pool_size(threads.max(1))
+            .name_prefix(format!("toshi-{}-", name))
+            .build();
+        Executor {
+            pool: Some(Arc::new(pool)),
+        }
+    }
+
+    /// Run `work` on the pool, resolving to its result once it's done
+    pub fn run<F, T>(&self, work: F) -> Box<Future<Item = T, Error = Error> + Send>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.pool {
+            Some(ref pool) => {
+                let (tx, rx) = oneshot::channel();
+                pool.spawn(future::lazy(move || {
+                    let _ = tx.send(work());
+                    Ok(())
+                }));
+                Box::new(rx.map_err(|_| Error::SpawnError).and_then(|result| result))
+            }
+            None => Box::new(future::result(work())),
+        }
+    }
+
+    /// Run `work` on the pool without waiting for it to finish
+    pub fn spawn<F>(&self, work: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.pool {
+            Some(ref pool) => pool.spawn(future::lazy(move || {
+                work();
+                Ok(())
+            })),
+            None => {
+                thread::spawn(work);
+            }
+        }
+    }
+}
+
+/// Run `f`, which blocks on the file system, on the runtime's blocking threads, or right away outside of a runtime
+pub fn blocking<T, F>(f: F) -> impl Future<Item = T, Error = Error>
+where
+    F: FnOnce() -> Result<T>,
+{
+    let mut f = Some(f);
+    future::poll_fn(
+        move || match tokio_threadpool::blocking(|| f.take().expect("Blocking work polled after it ran")()) {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => f.take().expect("Blocking work polled after it ran")().map(Async::Ready),
+        },
+    )
+}
+
+/// Searches an index's segments in parallel on `threads` threads, or one after another on the searching thread
+/// when there is only one. Shared by every index, since a search already runs on the search pool and only
+/// needs these threads while its segments are being collected.
+pub fn segment_executor(threads: usize) -> Result<tantivy::Executor> {
+    if threads <= 1 {
+        Ok(tantivy::Executor::single_thread())
+    } else {
+        Ok(tantivy::Executor::multi_thread(threads, "toshi-segment-")?)
+    }
+}
+
+/// The pools handlers run their work on
+#[derive(Clone, Default)]
+pub struct Executors {
+    pub search: Executor,
+    pub indexing: Executor,
+}
+
+impl Executors {
+    pub fn new(settings: &Settings) -> Self {
+        Executors {
+            search: Executor::new("search", settings.search_threads),
+            indexing: Executor::new("indexing", settings.indexing_threads),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::channel::unbounded;
+
+    #[test]
+    fn test_run_on_pool() {
+        let executor = Executor::new("test", 2);
+        let name = executor.run(|| Ok(thread::current().name().map(String::from))).wait().unwrap();
+        assert!(name.unwrap().starts_with("toshi-test-"));
+        assert!(executor.run::<_, ()>(|| Err(Error::IOError("failed".into()))).wait().is_err());
+
+        let (tx, rx) = unbounded();
+        executor.spawn(move || tx.send(1).unwrap());
+        assert_eq!(rx.recv().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_segment_executor() {
+        use crate::index::tests::create_test_index;
+        use tantivy::collector::Count;
+        use tantivy::query::AllQuery;
+
+        let index = create_test_index();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        for threads in &[1, 4] {
+            let executor = segment_executor(*threads).unwrap();
+            assert_eq!(searcher.search_with_executor(&AllQuery, &Count, &executor).unwrap(), 5);
+        }
+    }
+
+    #[test]
+    fn test_run_without_pool() {
+        let executor = Executor::default();
+        assert_eq!(executor.run(|| Ok(1)).wait().unwrap(), 1);
+    }
+}
@@ -0,0 +1,1142 @@
+//! GraphQL over the indexes. The schema is generated from the indexes' own: every index is a field of `Query`
+//! searching it, whose results hold the matching documents and aggregations over them, which can be nested by
+//! bucketing documents on the terms of a field.
+//!
+//! ```graphql
+//! query Slow($text: String) {
+//!   logs(query: $text, limit: 5) {
+//!     hits
+//!     docs { message duration }
+//!     aggregations { terms(field: level) { key count aggregations { avg(field: duration) } } }
+//!   }
+//! }
+//! ```
+//!
+//! Queries, variables, aliases, fragments and the `@skip` and `@include` directives are understood. Introspection
+//! isn't, the schema is served in SDL by `GET /graphql/schema` for tooling to load instead.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tantivy::schema::{FieldType, Schema};
+use tower_web::Response;
+
+use crate::query::{Query, Request, Sort, SortOrder};
+use crate::results::{ScoredDoc, SearchResults};
+use crate::settings::Settings;
+use crate::sql::{Accumulator, Function, MAX_AGGREGATED_DOCS};
+use crate::{Error, Result};
+
+/// How deeply selections, values and types can be nested, which also stops fragments that spread themselves
+const MAX_DEPTH: usize = 32;
+
+const DEFAULT_BUCKETS: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Punctuator(&'static str),
+    Name(String),
+    Int(String),
+    Float(String),
+    Str(String),
+}
+
+const PUNCTUATORS: &[&str] = &["...", "!", "$", "&", "(", ")", ":", "=", "@", "[", "]", "{", "|", "}"];
+
+fn tokenize(document: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = document.chars().collect();
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while at < chars.len() {
+        let c = chars[at];
+        if c.is_whitespace() || c == ',' || c == '\u{feff}' {
+            at += 1;
+        } else if c == '#' {
+            while at < chars.len() && chars[at] != '\n' && chars[at] != '\r' {
+                at += 1;
+            }
+        } else if c == '"' {
+            let (text, end) = string(&chars, at)?;
+            tokens.push(Token::Str(text));
+            at = end;
+        } else if c == '-' || c.is_ascii_digit() {
+            let start = at;
+            let mut float = false;
+            at += 1;
+            while at < chars.len() {
+                match chars[at] {
+                    d if d.is_ascii_digit() => {}
+                    '.' | 'e' | 'E' => float = true,
+                    '+' | '-' if float && "eE".contains(chars[at - 1]) => {}
+                    _ => break,
+                }
+                at += 1;
+            }
+            let number = chars[start..at].iter().collect();
+            tokens.push(if float { Token::Float(number) } else { Token::Int(number) });
+        } else if c == '_' || c.is_ascii_alphabetic() {
+            let start = at;
+            while at < chars.len() && (chars[at] == '_' || chars[at].is_ascii_alphanumeric()) {
+                at += 1;
+            }
+            tokens.push(Token::Name(chars[start..at].iter().collect()));
+        } else {
+            let rest: String = chars[at..chars.len().min(at + 3)].iter().collect();
+            match PUNCTUATORS.iter().find(|punctuator| rest.starts_with(*punctuator)) {
+                Some(punctuator) => {
+                    at += punctuator.len();
+                    tokens.push(Token::Punctuator(*punctuator));
+                }
+                None => return Err(Error::QueryError(format!("Unexpected '{}' in GraphQL", c))),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// The string starting with the quote at `at`, and where it ends
+fn string(chars: &[char], at: usize) -> Result<(String, usize)> {
+    let unterminated = || Error::QueryError("Unterminated string in GraphQL".into());
+    let mut text = String::new();
+    if chars[at..].starts_with(&['"'; 3]) {
+        // Block strings are trimmed rather than having their common indentation removed
+        let mut at = at + 3;
+        loop {
+            if chars[at..].starts_with(&['"'; 3]) {
+                return Ok((text.trim().to_string(), at + 3));
+            } else if chars[at..].starts_with(&['\\', '"', '"', '"']) {
+                text.push_str("\"\"\"");
+                at += 4;
+            } else {
+                text.push(*chars.get(at).ok_or_else(unterminated)?);
+                at += 1;
+            }
+        }
+    }
+    let mut at = at + 1;
+    loop {
+        match chars.get(at) {
+            None | Some('\n') | Some('\r') => return Err(unterminated()),
+            Some('"') => return Ok((text, at + 1)),
+            Some('\\') => {
+                let escaped = match chars.get(at + 1) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex: String = chars.get(at + 2..at + 6).ok_or_else(unterminated)?.iter().collect();
+                        let escaped = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| Error::QueryError(format!("\\u{} isn't a valid escape in GraphQL", hex)))?;
+                        text.push(escaped);
+                        at += 6;
+                        continue;
+                    }
+                    _ => return Err(Error::QueryError("Invalid escape in a GraphQL string".into())),
+                };
+                text.push(escaped);
+                at += 2;
+            }
+            Some(&c) => {
+                text.push(c);
+                at += 1;
+            }
+        }
+    }
+}
+
+/// An argument's value as written, which can refer to variables
+#[derive(Clone, Debug)]
+enum Input {
+    Variable(String),
+    Const(Value),
+    List(Vec<Input>),
+    Object(Vec<(String, Input)>),
+}
+
+type Arguments = Vec<(String, Input)>;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Field {
+        alias: Option<String>,
+        name: String,
+        arguments: Arguments,
+        directives: Vec<(String, Arguments)>,
+        selections: Vec<Node>,
+    },
+    Spread {
+        fragment: String,
+        directives: Vec<(String, Arguments)>,
+    },
+    Inline {
+        directives: Vec<(String, Arguments)>,
+        selections: Vec<Node>,
+    },
+}
+
+struct Operation {
+    kind: String,
+    name: Option<String>,
+    /// The default values of the operation's variables
+    defaults: Vec<(String, Input)>,
+    selections: Vec<Node>,
+}
+
+struct Document {
+    operations: Vec<Operation>,
+    fragments: HashMap<String, Vec<Node>>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    /// How many selection sets, values or types the parser is inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn unexpected<T>(&self) -> Result<T> {
+        match self.peek() {
+            Some(token) => Err(Error::QueryError(format!("Unexpected {:?} in GraphQL", token))),
+            None => Err(Error::QueryError("Unexpected end of GraphQL".into())),
+        }
+    }
+
+    fn punctuator(&mut self, punctuator: &str) -> bool {
+        match self.peek() {
+            Some(Token::Punctuator(p)) if *p == punctuator => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, punctuator: &str) -> Result<()> {
+        if self.punctuator(punctuator) {
+            Ok(())
+        } else {
+            self.unexpected()
+        }
+    }
+
+    /// Parses with `parse` one level deeper, failing rather than recursing past `MAX_DEPTH`
+    fn nested<T, F>(&mut self, parse: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        if self.depth > MAX_DEPTH {
+            return Err(Error::QueryError(format!("GraphQL can't be nested more than {} deep", MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Name(name)) if name == keyword => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn name(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(name),
+            _ => {
+                self.at -= 1;
+                self.unexpected()
+            }
+        }
+    }
+
+    fn document(&mut self) -> Result<Document> {
+        let mut operations = Vec::new();
+        let mut fragments = HashMap::new();
+        while self.peek().is_some() {
+            if self.peek() == Some(&Token::Punctuator("{")) {
+                operations.push(Operation {
+                    kind: "query".into(),
+                    name: None,
+                    defaults: Vec::new(),
+                    selections: self.selection_set()?,
+                });
+            } else if self.keyword("fragment") {
+                let name = self.name()?;
+                if !self.keyword("on") {
+                    return self.unexpected();
+                }
+                self.name()?;
+                self.directives()?;
+                fragments.insert(name, self.selection_set()?);
+            } else {
+                operations.push(self.operation()?);
+            }
+        }
+        Ok(Document { operations, fragments })
+    }
+
+    fn operation(&mut self) -> Result<Operation> {
+        let kind = self.name()?;
+        if !["query", "mutation", "subscription"].contains(&kind.as_str()) {
+            self.at -= 1;
+            return self.unexpected();
+        }
+        let name = match self.peek() {
+            Some(Token::Name(_)) => Some(self.name()?),
+            _ => None,
+        };
+        let mut defaults = Vec::new();
+        if self.punctuator("(") {
+            while !self.punctuator(")") {
+                self.expect("$")?;
+                let variable = self.name()?;
+                self.expect(":")?;
+                self.type_reference()?;
+                if self.punctuator("=") {
+                    defaults.push((variable, self.value(true)?));
+                }
+                self.directives()?;
+            }
+        }
+        self.directives()?;
+        Ok(Operation {
+            kind,
+            name,
+            defaults,
+            selections: self.selection_set()?,
+        })
+    }
+
+    /// Variables' types are read past, they aren't checked
+    fn type_reference(&mut self) -> Result<()> {
+        if self.punctuator("[") {
+            self.nested(Self::type_reference)?;
+            self.expect("]")?;
+        } else {
+            self.name()?;
+        }
+        self.punctuator("!");
+        Ok(())
+    }
+
+    fn selection_set(&mut self) -> Result<Vec<Node>> {
+        self.expect("{")?;
+        self.nested(|parser| {
+            let mut selections = vec![parser.selection()?];
+            while !parser.punctuator("}") {
+                selections.push(parser.selection()?);
+            }
+            Ok(selections)
+        })
+    }
+
+    fn selection(&mut self) -> Result<Node> {
+        if self.punctuator("...") {
+            return match self.peek() {
+                Some(Token::Name(name)) if name != "on" => {
+                    let fragment = self.name()?;
+                    let directives = self.directives()?;
+                    Ok(Node::Spread { fragment, directives })
+                }
+                _ => {
+                    // Type conditions are read past, every index's results are of a single type
+                    if self.keyword("on") {
+                        self.name()?;
+                    }
+                    let directives = self.directives()?;
+                    let selections = self.selection_set()?;
+                    Ok(Node::Inline { directives, selections })
+                }
+            };
+        }
+        let name = self.name()?;
+        let (alias, name) = if self.punctuator(":") {
+            (Some(name), self.name()?)
+        } else {
+            (None, name)
+        };
+        let arguments = self.arguments(false)?;
+        let directives = self.directives()?;
+        let selections = if self.peek() == Some(&Token::Punctuator("{")) {
+            self.selection_set()?
+        } else {
+            Vec::new()
+        };
+        Ok(Node::Field {
+            alias,
+            name,
+            arguments,
+            directives,
+            selections,
+        })
+    }
+
+    fn arguments(&mut self, constant: bool) -> Result<Arguments> {
+        let mut arguments = Vec::new();
+        if self.punctuator("(") {
+            while !self.punctuator(")") {
+                let name = self.name()?;
+                self.expect(":")?;
+                arguments.push((name, self.value(constant)?));
+            }
+        }
+        Ok(arguments)
+    }
+
+    fn directives(&mut self) -> Result<Vec<(String, Arguments)>> {
+        let mut directives = Vec::new();
+        while self.punctuator("@") {
+            let name = self.name()?;
+            directives.push((name, self.arguments(false)?));
+        }
+        Ok(directives)
+    }
+
+    /// A value, which can't refer to variables when it's `constant`
+    fn value(&mut self, constant: bool) -> Result<Input> {
+        let invalid = |number: &str| Error::QueryError(format!("{} isn't a valid number", number));
+        let value = match self.next() {
+            Some(Token::Punctuator("$")) if !constant => Input::Variable(self.name()?),
+            Some(Token::Int(number)) => Input::Const(number.parse::<i64>().map_err(|_| invalid(&number))?.into()),
+            Some(Token::Float(number)) => Input::Const(number.parse::<f64>().map_err(|_| invalid(&number))?.into()),
+            Some(Token::Str(text)) => Input::Const(Value::String(text)),
+            Some(Token::Name(name)) => Input::Const(match name.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                // Enum values are given as strings
+                _ => Value::String(name),
+            }),
+            Some(Token::Punctuator("[")) => self.nested(|parser| {
+                let mut items = Vec::new();
+                while !parser.punctuator("]") {
+                    items.push(parser.value(constant)?);
+                }
+                Ok(Input::List(items))
+            })?,
+            Some(Token::Punctuator("{")) => self.nested(|parser| {
+                let mut fields = Vec::new();
+                while !parser.punctuator("}") {
+                    let name = parser.name()?;
+                    parser.expect(":")?;
+                    fields.push((name, parser.value(constant)?));
+                }
+                Ok(Input::Object(fields))
+            })?,
+            _ => {
+                self.at -= 1;
+                return self.unexpected();
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// A field selected by a query, with the variables in its arguments filled in and its directives and fragments
+/// applied
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub alias: Option<String>,
+    pub name: String,
+    pub arguments: Map<String, Value>,
+    pub selections: Vec<Selection>,
+}
+
+impl Selection {
+    /// The key the field's value is given under in the response
+    pub fn key(&self) -> &str {
+        match self.alias {
+            Some(ref alias) => alias,
+            None => &self.name,
+        }
+    }
+}
+
+/// Adds `selection` to `selections`, merging it into any field already selected under the same key
+fn merge(selections: &mut Vec<Selection>, selection: Selection) {
+    match selections.iter_mut().find(|existing| existing.key() == selection.key()) {
+        Some(existing) => {
+            for nested in selection.selections {
+                merge(&mut existing.selections, nested);
+            }
+        }
+        None => selections.push(selection),
+    }
+}
+
+struct Resolver<'a> {
+    fragments: &'a HashMap<String, Vec<Node>>,
+    variables: Map<String, Value>,
+}
+
+impl<'a> Resolver<'a> {
+    fn input(&self, input: &Input) -> Value {
+        match input {
+            // Variables that aren't given are null
+            Input::Variable(name) => self.variables.get(name).cloned().unwrap_or_default(),
+            Input::Const(value) => value.clone(),
+            Input::List(items) => Value::Array(items.iter().map(|item| self.input(item)).collect()),
+            Input::Object(fields) => Value::Object(self.arguments(fields)),
+        }
+    }
+
+    fn arguments(&self, arguments: &[(String, Input)]) -> Map<String, Value> {
+        arguments.iter().map(|(name, input)| (name.clone(), self.input(input))).collect()
+    }
+
+    fn included(&self, directives: &[(String, Arguments)]) -> Result<bool> {
+        for (name, arguments) in directives {
+            let condition = self.arguments(arguments).get("if").and_then(Value::as_bool);
+            match (name.as_str(), condition) {
+                ("skip", Some(true)) | ("include", Some(false)) => return Ok(false),
+                ("skip", Some(_)) | ("include", Some(_)) => {}
+                ("skip", None) | ("include", None) => {
+                    return Err(Error::QueryError(format!("@{} needs an if argument that's true or false", name)));
+                }
+                _ => return Err(Error::QueryError(format!("Unknown directive @{}", name))),
+            }
+        }
+        Ok(true)
+    }
+
+    fn selections(&self, nodes: &[Node], depth: usize) -> Result<Vec<Selection>> {
+        if depth > MAX_DEPTH {
+            return Err(Error::QueryError(format!(
+                "Selections can't be nested more than {} deep",
+                MAX_DEPTH
+            )));
+        }
+        let mut selections = Vec::new();
+        for node in nodes {
+            match node {
+                Node::Field {
+                    alias,
+                    name,
+                    arguments,
+                    directives,
+                    selections: nested,
+                } => {
+                    if self.included(directives)? {
+                        let selection = Selection {
+                            alias: alias.clone(),
+                            name: name.clone(),
+                            arguments: self.arguments(arguments),
+                            selections: self.selections(nested, depth + 1)?,
+                        };
+                        merge(&mut selections, selection);
+                    }
+                }
+                Node::Spread { fragment, directives } => {
+                    if self.included(directives)? {
+                        let nodes = self
+                            .fragments
+                            .get(fragment)
+                            .ok_or_else(|| Error::QueryError(format!("Unknown fragment {}", fragment)))?;
+                        for selection in self.selections(nodes, depth + 1)? {
+                            merge(&mut selections, selection);
+                        }
+                    }
+                }
+                Node::Inline {
+                    directives,
+                    selections: nested,
+                } => {
+                    if self.included(directives)? {
+                        for selection in self.selections(nested, depth + 1)? {
+                            merge(&mut selections, selection);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(selections)
+    }
+}
+
+/// The fields selected at the root of the operation named `operation_name` in `document`, or of its only operation
+/// when no name is given
+pub fn parse(document: &str, variables: &Map<String, Value>, operation_name: Option<&str>) -> Result<Vec<Selection>> {
+    let document = Parser {
+        tokens: tokenize(document)?,
+        at: 0,
+        depth: 0,
+    }
+    .document()?;
+    let operation = match operation_name {
+        Some(name) => document
+            .operations
+            .iter()
+            .find(|operation| operation.name.as_ref().map(String::as_str) == Some(name))
+            .ok_or_else(|| Error::QueryError(format!("There's no operation named {}", name)))?,
+        None if document.operations.len() == 1 => &document.operations[0],
+        None => {
+            return Err(Error::QueryError(
+                "operationName is needed to pick one of several operations".into(),
+            ))
+        }
+    };
+    if operation.kind != "query" {
+        return Err(Error::QueryError(format!("Only queries are supported, not {}s", operation.kind)));
+    }
+
+    let mut resolver = Resolver {
+        fragments: &document.fragments,
+        variables: Map::new(),
+    };
+    let defaults: Map<String, Value> = operation
+        .defaults
+        .iter()
+        .map(|(name, default)| (name.clone(), resolver.input(default)))
+        .collect();
+    resolver.variables = defaults;
+    resolver.variables.extend(variables.clone());
+    resolver.selections(&operation.selections, 0)
+}
+
+/// The response to a GraphQL request, with an error for each field that couldn't be resolved
+#[derive(Response, Serialize, Debug)]
+pub struct GraphqlResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<GraphqlError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GraphqlError {
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<String>,
+}
+
+impl GraphqlResponse {
+    /// The response made of what each root field resolved to, by its key
+    pub fn new(fields: Vec<(String, Result<Value>)>) -> Self {
+        let mut data = Map::new();
+        let mut errors = Vec::new();
+        for (key, value) in fields {
+            match value {
+                Ok(value) => {
+                    data.insert(key, value);
+                }
+                Err(e) => {
+                    data.insert(key.clone(), Value::Null);
+                    errors.push(GraphqlError {
+                        message: e.to_string(),
+                        path: vec![key],
+                    });
+                }
+            }
+        }
+        GraphqlResponse {
+            data: Some(Value::Object(data)),
+            errors,
+        }
+    }
+
+    /// The response to a request that couldn't be executed at all
+    pub fn failed(error: &Error) -> Self {
+        GraphqlResponse {
+            data: None,
+            errors: vec![GraphqlError {
+                message: error.to_string(),
+                path: Vec::new(),
+            }],
+        }
+    }
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first = chars.next().map_or(false, |c| c == '_' || c.is_ascii_alphabetic());
+    first && !name.starts_with("__") && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// The types of an index's results, its documents and aggregations
+#[derive(Clone, Debug)]
+struct IndexType {
+    /// The field of `Query` searching the index, which is the index's name made a valid GraphQL name
+    field: String,
+    index: String,
+    /// The name the index's types are prefixed with
+    name: String,
+    /// The index's fields with names that are valid in GraphQL
+    fields: Vec<(String, FieldType)>,
+}
+
+/// The value of an argument given as a whole number, or `default` when it isn't given
+fn count_argument(selection: &Selection, argument: &str, default: usize) -> Result<usize> {
+    match selection.arguments.get(argument) {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => value
+            .as_u64()
+            .map(|count| count as usize)
+            .ok_or_else(|| Error::QueryError(format!("{} of {} must be a whole number", argument, selection.name))),
+    }
+}
+
+/// Checks that fields of `field`, of type `type_name`, are selected. Lists of objects have to be checked before
+/// they're resolved, as an empty one resolves no objects.
+fn needs_fields(type_name: &str, field: &Selection) -> Result<()> {
+    if field.selections.is_empty() {
+        Err(Error::QueryError(format!(
+            "{} is a {} and needs fields selected",
+            field.name, type_name
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Resolves the fields selected of an object of type `type_name` with `resolve`, which gives `None` for the fields
+/// the type doesn't have
+fn object<F>(type_name: &str, field: &Selection, mut resolve: F) -> Result<Value>
+where
+    F: FnMut(&Selection) -> Result<Option<Value>>,
+{
+    needs_fields(type_name, field)?;
+    let mut object = Map::new();
+    for selection in &field.selections {
+        let value = if selection.name == "__typename" {
+            Some(Value::String(type_name.to_string()))
+        } else {
+            resolve(selection)?
+        };
+        match value {
+            Some(value) => {
+                object.insert(selection.key().to_string(), value);
+            }
+            None => return Err(Error::QueryError(format!("{} has no field {}", type_name, selection.name))),
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+fn scalar(field: &Selection, value: Value) -> Result<Option<Value>> {
+    if field.selections.is_empty() {
+        Ok(Some(value))
+    } else {
+        Err(Error::QueryError(format!(
+            "{} is a scalar and can't have fields selected",
+            field.name
+        )))
+    }
+}
+
+/// Every value a result has for `field`
+fn values(doc: &ScoredDoc, field: &str) -> Vec<Value> {
+    doc.doc
+        .get(field)
+        .map(|values| values.iter().filter_map(|value| serde_json::to_value(value).ok()).collect())
+        .unwrap_or_default()
+}
+
+fn aggregated(field: &Selection) -> bool {
+    field.selections.iter().any(|selection| selection.name == "aggregations")
+}
+
+impl IndexType {
+    fn new(index: &str, schema: &Schema) -> Self {
+        let mut field: String = index.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        if !is_name(&field) {
+            field.insert(0, 'i');
+        }
+        let mut name: String = field
+            .split('_')
+            .map(|part| {
+                let mut chars = part.chars();
+                chars
+                    .next()
+                    .map(|first| first.to_ascii_uppercase().to_string() + chars.as_str())
+                    .unwrap_or_default()
+            })
+            .collect();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            name.insert_str(0, "Index");
+        }
+        let fields = schema
+            .fields()
+            .iter()
+            .filter(|entry| is_name(entry.name()))
+            .map(|entry| (entry.name().to_string(), entry.field_type().clone()))
+            .collect();
+        IndexType {
+            field,
+            index: index.to_string(),
+            name,
+            fields,
+        }
+    }
+
+    fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|(field, _)| field == name)
+    }
+
+    fn sdl(&self) -> String {
+        let name = &self.name;
+        let type_of = |field_type: &FieldType| match field_type {
+            FieldType::I64(_) | FieldType::U64(_) => "Long",
+            _ => "String",
+        };
+        let fields: Vec<String> = self.fields.iter().map(|(field, _)| format!("  {}\n", field)).collect();
+        let document: Vec<String> = self
+            .fields
+            .iter()
+            .map(|(field, field_type)| format!("  {}: {}\n", field, type_of(field_type)))
+            .collect();
+        format!(
+            "type {name}Results {{\n  hits: Int!\n  docs: [{name}Document!]!\n  aggregations: {name}Aggregations!\n}}\n\n\
+             type {name}Document {{\n  _score: Float\n{document}}}\n\n\
+             enum {name}Field {{\n{fields}}}\n\n\
+             type {name}Aggregations {{\n  count: Long!\n  sum(field: {name}Field!): Float\n  \
+             min(field: {name}Field!): Float\n  max(field: {name}Field!): Float\n  avg(field: {name}Field!): Float\n  \
+             terms(field: {name}Field!, size: Int = {buckets}): [{name}Bucket!]!\n}}\n\n\
+             type {name}Bucket {{\n  key: JSON\n  count: Long!\n  aggregations: {name}Aggregations!\n}}\n",
+            name = name,
+            document = document.concat(),
+            fields = fields.concat(),
+            buckets = DEFAULT_BUCKETS,
+        )
+    }
+
+    /// The field named in the `field` argument of `selection`
+    fn field_argument<'s>(&self, selection: &'s Selection) -> Result<&'s str> {
+        match selection.arguments.get("field") {
+            Some(Value::String(field)) if self.has_field(field) => Ok(field),
+            Some(Value::String(field)) => Err(Error::UnknownIndexField(field.clone())),
+            _ => Err(Error::QueryError(format!("{} needs the field to aggregate", selection.name))),
+        }
+    }
+
+    fn document(&self, field: &Selection, doc: &ScoredDoc) -> Result<Value> {
+        object(&format!("{}Document", self.name), field, |selection| {
+            match selection.name.as_str() {
+                "_score" => scalar(selection, doc.score.map_or(Value::Null, |score| f64::from(score).into())),
+                // Fields holding several values give the first
+                name if self.has_field(name) => scalar(selection, values(doc, name).into_iter().next().unwrap_or_default()),
+                _ => Ok(None),
+            }
+        })
+    }
+
+    fn aggregations(&self, field: &Selection, docs: &[&ScoredDoc]) -> Result<Value> {
+        object(&format!("{}Aggregations", self.name), field, |selection| {
+            let function = match selection.name.as_str() {
+                "count" => return scalar(selection, docs.len().into()),
+                "terms" => return self.terms(selection, docs).map(Some),
+                "sum" => Function::Sum,
+                "min" => Function::Min,
+                "max" => Function::Max,
+                "avg" => Function::Avg,
+                _ => return Ok(None),
+            };
+            let field = self.field_argument(selection)?;
+            let mut accumulator = Accumulator::default();
+            for doc in docs {
+                for value in values(doc, field) {
+                    accumulator.add(value.as_f64());
+                }
+            }
+            scalar(selection, accumulator.finish(function))
+        })
+    }
+
+    /// Buckets of the documents holding each term of a field, the ones with the most documents first
+    fn terms(&self, field: &Selection, docs: &[&ScoredDoc]) -> Result<Value> {
+        let name = self.field_argument(field)?;
+        let size = count_argument(field, "size", DEFAULT_BUCKETS)?;
+        let mut buckets: BTreeMap<String, (Value, Vec<&ScoredDoc>)> = BTreeMap::new();
+        for doc in docs {
+            for key in values(doc, name) {
+                buckets.entry(key.to_string()).or_insert_with(|| (key, Vec::new())).1.push(*doc);
+            }
+        }
+        // Buckets with as many documents stay in the order of their keys
+        let mut buckets: Vec<(Value, Vec<&ScoredDoc>)> = buckets.into_iter().map(|(_, bucket)| bucket).collect();
+        buckets.sort_by(|a, b| b.1.len().cmp(&a.1.len()));
+        buckets.truncate(size);
+
+        let bucket_type = format!("{}Bucket", self.name);
+        needs_fields(&bucket_type, field)?;
+        let buckets = buckets
+            .iter()
+            .map(|(key, docs)| {
+                object(&bucket_type, field, |selection| match selection.name.as_str() {
+                    "key" => scalar(selection, key.clone()),
+                    "count" => scalar(selection, docs.len().into()),
+                    "aggregations" => self.aggregations(selection, docs).map(Some),
+                    _ => Ok(None),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Value::Array(buckets))
+    }
+}
+
+/// The GraphQL schema of a set of indexes
+#[derive(Clone, Debug)]
+pub struct GraphqlSchema {
+    indexes: Vec<IndexType>,
+}
+
+impl GraphqlSchema {
+    /// The schema of `indexes`, given by name along with their schemas
+    pub fn new(indexes: &[(String, Schema)]) -> Self {
+        GraphqlSchema {
+            indexes: indexes.iter().map(|(index, schema)| IndexType::new(index, schema)).collect(),
+        }
+    }
+
+    /// The schema in the GraphQL schema definition language
+    pub fn sdl(&self) -> String {
+        let mut sdl = String::from("scalar JSON\n\nscalar Long\n\nenum SortOrder {\n  asc\n  desc\n}\n\ntype Query {\n");
+        for index in &self.indexes {
+            sdl.push_str(&format!(
+                "  {}(query: JSON, limit: Int = {}, sort: {}Field, order: SortOrder = asc): {}Results!\n",
+                index.field,
+                Settings::default_result_limit(),
+                index.name,
+                index.name
+            ));
+        }
+        sdl.push_str("}\n");
+        for index in &self.indexes {
+            sdl.push('\n');
+            sdl.push_str(&index.sdl());
+        }
+        sdl
+    }
+
+    fn index(&self, field: &str) -> Result<&IndexType> {
+        self.indexes.iter().find(|index| index.field == field).ok_or_else(|| {
+            if field.starts_with("__") {
+                Error::QueryError("Introspection isn't supported, the schema can be fetched from GET /graphql/schema".into())
+            } else {
+                Error::UnknownIndex(field.to_string())
+            }
+        })
+    }
+
+    /// The index a root field of `Query` searches, and the search for it. A `query` argument given as a string is
+    /// taken for a query in the query parser's syntax, otherwise it's a Toshi query.
+    pub fn request(&self, field: &Selection) -> Result<(String, Request)> {
+        let index = self.index(&field.name)?;
+        let query = match field.arguments.get("query") {
+            None | Some(Value::Null) => Query::All,
+            Some(Value::String(raw)) => Query::Raw {
+                raw: raw.clone(),
+                fields: Vec::new(),
+            },
+            Some(query) => serde_json::from_value(query.clone())?,
+        };
+        // Aggregations are over every matching document, one more is fetched to tell when there are too many
+        let limit = if aggregated(field) {
+            MAX_AGGREGATED_DOCS + 1
+        } else {
+            count_argument(field, "limit", Settings::default_result_limit())?
+        };
+        let mut request = Request::new(Some(query), None, limit);
+        request.sort = match field.arguments.get("sort") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(sort)) => Some(Sort {
+                field: sort.clone(),
+                order: match field.arguments.get("order") {
+                    None | Some(Value::Null) => SortOrder::Asc,
+                    Some(order) => serde_json::from_value(order.clone())?,
+                },
+                missing: None,
+                script: None,
+            }),
+            Some(_) => return Err(Error::QueryError("sort takes the name of a field".into())),
+        };
+        Ok((index.index.clone(), request))
+    }
+
+    /// Resolves a root field of `Query` from the results of its `request`
+    pub fn resolve(&self, field: &Selection, results: SearchResults) -> Result<Value> {
+        let index = self.index(&field.name)?;
+        if aggregated(field) && results.docs.len() > MAX_AGGREGATED_DOCS {
+            return Err(Error::QueryError(format!(
+                "More than {} documents match, which is more than can be aggregated",
+                MAX_AGGREGATED_DOCS
+            )));
+        }
+        let limit = count_argument(field, "limit", Settings::default_result_limit())?;
+        let shown = &results.docs[..results.docs.len().min(limit)];
+        let docs: Vec<&ScoredDoc> = results.docs.iter().collect();
+        object(&format!("{}Results", index.name), field, |selection| {
+            match selection.name.as_str() {
+                "hits" => scalar(selection, shown.len().into()),
+                "docs" => {
+                    needs_fields(&format!("{}Document", index.name), selection)?;
+                    let docs = shown.iter().map(|doc| index.document(selection, doc)).collect::<Result<Vec<_>>>()?;
+                    Ok(Some(Value::Array(docs)))
+                }
+                "aggregations" => index.aggregations(selection, &docs).map(Some),
+                _ => Ok(None),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tantivy::schema::{Document, SchemaBuilder, INT_INDEXED, INT_STORED, STORED, STRING};
+
+    fn schema() -> Schema {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("level", STRING | STORED);
+        builder.add_u64_field("duration", INT_INDEXED | INT_STORED);
+        builder.add_text_field("not-graphql", STORED);
+        builder.build()
+    }
+
+    #[test]
+    fn test_parse() {
+        let document = r#"
+            # Slow requests
+            query Slow($text: String = "timeout", $show: Boolean!) {
+                recent: logs(query: $text, limit: 5) @include(if: $show) { ...counts docs { level } }
+                logs { ... on LogsResults { docs { duration } } }
+                skipped: logs @skip(if: true) { hits }
+            }
+            fragment counts on LogsResults { hits }
+            mutation Remove { logs { hits } }
+        "#;
+        let mut variables = Map::new();
+        variables.insert("show".into(), json!(true));
+        let fields = parse(document, &variables, Some("Slow")).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].key(), "recent");
+        assert_eq!(fields[0].arguments.get("query"), Some(&json!("timeout")));
+        assert_eq!(fields[0].arguments.get("limit"), Some(&json!(5)));
+        let nested: Vec<&str> = fields[0].selections.iter().map(Selection::key).collect();
+        assert_eq!(nested, vec!["hits", "docs"]);
+        assert_eq!(fields[1].selections[0].selections[0].name, "duration");
+
+        variables.insert("show".into(), json!(false));
+        assert_eq!(parse(document, &variables, Some("Slow")).unwrap().len(), 1);
+
+        assert!(parse(document, &variables, None).is_err());
+        assert!(parse(document, &variables, Some("Remove")).is_err());
+        assert!(parse("{ logs { ...missing } }", &variables, None).is_err());
+        assert!(parse("{ logs(query: \"unterminated) { hits } }", &variables, None).is_err());
+        assert!(parse("{ logs { } }", &variables, None).is_err());
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let variables = Map::new();
+        let nested =
+            |open: &str, close: &str, depth: usize| format!("{{ logs(query: {}1{}) {{ hits }} }}", open.repeat(depth), close.repeat(depth));
+        assert!(parse(&nested("[", "]", MAX_DEPTH), &variables, None).is_ok());
+        assert!(parse(&nested("[", "]", 100_000), &variables, None).is_err());
+        assert!(parse(&nested("{a: ", "}", 100_000), &variables, None).is_err());
+        assert!(parse(
+            &format!("query($a: {}Int{}) {{ logs {{ hits }} }}", "[".repeat(100_000), "]".repeat(100_000)),
+            &variables,
+            None
+        )
+        .is_err());
+        assert!(parse(&"{a".repeat(100_000), &variables, None).is_err());
+        assert!(parse(&"{...{a".repeat(100_000), &variables, None).is_err());
+    }
+
+    #[test]
+    fn test_sdl() {
+        let schema = GraphqlSchema::new(&[("app-logs".into(), schema())]);
+        let sdl = schema.sdl();
+        assert!(sdl.contains("  app_logs(query: JSON, limit: Int = 100, sort: AppLogsField, order: SortOrder = asc): AppLogsResults!"));
+        assert!(sdl.contains("type AppLogsDocument {\n  _score: Float\n  level: String\n  duration: Long\n}"));
+        assert!(!sdl.contains("not-graphql"));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let schema = schema();
+        let graphql = GraphqlSchema::new(&[("logs".into(), schema.clone())]);
+        let doc = |level: &str, duration: u64| {
+            let mut doc = Document::default();
+            doc.add_text(schema.get_field("level").unwrap(), level);
+            doc.add_u64(schema.get_field("duration").unwrap(), duration);
+            ScoredDoc::new(Some(1.0), schema.to_named_doc(&doc))
+        };
+        let results = SearchResults::new(vec![doc("error", 10), doc("info", 1), doc("error", 30), doc("warn", 5)]);
+
+        let query = r#"{
+            logs(query: {term: {level: "error"}}, limit: 1) {
+                __typename
+                hits
+                docs { level duration }
+                aggregations {
+                    total: count
+                    terms(field: level, size: 2) { key count aggregations { max(field: duration) } }
+                }
+            }
+        }"#;
+        let fields = parse(query, &Map::new(), None).unwrap();
+        let (index, request) = graphql.request(&fields[0]).unwrap();
+        assert_eq!(index, "logs");
+        assert_eq!(request.limit, MAX_AGGREGATED_DOCS + 1);
+        assert!(match request.query {
+            Some(Query::Exact(ref term)) => term.term.get("level") == Some(&"error".to_string()),
+            _ => false,
+        });
+
+        let value = graphql.resolve(&fields[0], results).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "__typename": "LogsResults",
+                "hits": 1,
+                "docs": [{ "level": "error", "duration": 10 }],
+                "aggregations": {
+                    "total": 4,
+                    "terms": [
+                        { "key": "error", "count": 2, "aggregations": { "max": 30.0 } },
+                        { "key": "info", "count": 1, "aggregations": { "max": 1.0 } }
+                    ]
+                }
+            })
+        );
+
+        let invalid = |query: &str| {
+            let fields = parse(query, &Map::new(), None).unwrap();
+            graphql
+                .request(&fields[0])
+                .and_then(|_| graphql.resolve(&fields[0], SearchResults::new(Vec::new())))
+                .is_err()
+        };
+        assert!(invalid("{ logs { missing } }"));
+        assert!(invalid("{ logs { docs } }"));
+        assert!(invalid("{ logs { hits { count } } }"));
+        assert!(invalid("{ logs { aggregations { sum(field: missing) } } }"));
+        assert!(invalid("{ __schema { types { name } } }"));
+        assert!(invalid("{ other { hits } }"));
+    }
+}
@@ -0,0 +1,506 @@
+//! Toshi's public gRPC API, defined in `proto/toshi.proto`. Each method goes through the same handler as its
+//! counterpart in the HTTP API, so writes are routed to shards and replicated just the same, but documents are sent
+//! as typed fields instead of JSON.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future, Stream};
+use log::{error, info};
+use serde_json::{Map, Value};
+use tantivy::schema::{FieldType, Schema, Value as TantivyValue};
+use tokio::net::TcpListener;
+use tokio_executor::DefaultExecutor;
+use tower_grpc::{Code, Request, Response, Status};
+use tower_h2::Server;
+
+use self::proto::server;
+use self::proto::*;
+use crate::cluster::replication::Consistency;
+use crate::cluster::routing::Preference;
+use crate::handlers::index::{AddDocument, CreateOptions, DeleteDoc, IndexOptions, WriteOptions};
+use crate::handlers::{BulkHandler, IndexHandler, SearchHandler};
+use crate::index::IndexCatalog;
+use crate::middleware::{Admission, Endpoint, Rejection};
+use crate::query;
+use crate::results::{ScoredDoc, SearchResults};
+use crate::{Error, Result};
+
+pub mod proto {
+    use prost_derive::{Enumeration, Message};
+
+    #[cfg(target_family = "unix")]
+    include!(concat!(env!("OUT_DIR"), "/toshi.v1.rs"));
+    #[cfg(target_family = "windows")]
+    include!(concat!(env!("OUT_DIR"), "\\toshi.v1.rs"));
+}
+
+type Reply<T> = Box<Future<Item = Response<T>, Error = tower_grpc::Error> + Send>;
+
+/// The gRPC status an error is reported with
+fn status(error: Error) -> tower_grpc::Error {
+    let code = match error {
+        Error::UnknownIndex(_) => Code::NotFound,
+        Error::UnknownIndexField(_) | Error::QueryError(_) => Code::InvalidArgument,
+        Error::IOError(_) | Error::SpawnError => Code::Internal,
+    };
+    grpc_error(code, error.to_string())
+}
+
+fn grpc_error(code: Code, message: String) -> tower_grpc::Error {
+    tower_grpc::Error::Grpc(Status::with_code_and_message(code, message))
+}
+
+fn reply<F, T>(result: F) -> Reply<T>
+where
+    F: Future<Item = T, Error = Error> + Send + 'static,
+    T: Send + 'static,
+{
+    Box::new(result.map(Response::new).map_err(status))
+}
+
+fn write_options(consistency: i32) -> Option<WriteOptions> {
+    let consistency = match proto::Consistency::from_i32(consistency) {
+        Some(proto::Consistency::Quorum) => Consistency::Quorum,
+        Some(proto::Consistency::All) => Consistency::All,
+        _ => Consistency::One,
+    };
+    Some(WriteOptions {
+        consistency: Some(consistency),
+    })
+}
+
+/// A document as the JSON the HTTP API takes, using `schema` to tell which member of each value to use
+pub fn json_document(schema: &Schema, document: Document) -> Result<Value> {
+    let mut json = Map::new();
+    for field in document.fields {
+        let field_type = schema
+            .get_field(&field.name)
+            .map(|f| schema.get_field_entry(f).field_type())
+            .ok_or_else(|| Error::UnknownIndexField(field.name.clone()))?;
+        let mut values: Vec<Value> = field
+            .values
+            .into_iter()
+            .map(|value| match field_type {
+                FieldType::U64(_) => value.u64.into(),
+                FieldType::I64(_) => value.i64.into(),
+                FieldType::Bytes => base64::encode(&value.bytes).into(),
+                _ => value.text.into(),
+            })
+            .collect();
+        // A single value is given on its own, which is what routing to shards expects
+        let value = if values.len() == 1 {
+            values.remove(0)
+        } else {
+            Value::Array(values)
+        };
+        json.insert(field.name, value);
+    }
+    Ok(Value::Object(json))
+}
+
+fn field_value(value: &TantivyValue) -> FieldValue {
+    let mut field_value = FieldValue::default();
+    match value {
+        TantivyValue::Str(text) => field_value.text = text.clone(),
+        TantivyValue::U64(number) => field_value.u64 = *number,
+        TantivyValue::I64(number) => field_value.i64 = *number,
+        TantivyValue::Facet(facet) => field_value.text = facet.to_string(),
+        TantivyValue::Bytes(bytes) => field_value.bytes = bytes.clone(),
+    }
+    field_value
+}
+
+fn document(doc: ScoredDoc) -> Document {
+    let fields = doc
+        .doc
+        .into_iter()
+        .map(|(name, values)| Field {
+            name,
+            values: values.iter().map(field_value).collect(),
+        })
+        .collect();
+    Document {
+        fields,
+        score: doc.score.unwrap_or_default(),
+    }
+}
+
+pub fn search_reply(results: SearchResults) -> Result<SearchReply> {
+    let aggregations = match results.aggregate {
+        Some(ref aggregate) => serde_json::to_vec(aggregate)?,
+        None => Vec::new(),
+    };
+    Ok(SearchReply {
+        hits: results.hits as u64,
+        docs: results.docs.into_iter().map(document).collect(),
+        aggregations,
+    })
+}
+
+/// Serves the public API
+#[derive(Clone)]
+pub struct GrpcServer {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+    index: IndexHandler,
+    bulk: BulkHandler,
+    /// What calls are let in by, once the server is serving a connection
+    admission: Option<Admission>,
+    /// Where the connection comes from, gRPC clients are told apart by their address alone
+    peer: Option<SocketAddr>,
+}
+
+impl GrpcServer {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler, index: IndexHandler, bulk: BulkHandler) -> Self {
+        GrpcServer {
+            catalog,
+            search,
+            index,
+            bulk,
+            admission: None,
+            peer: None,
+        }
+    }
+
+    /// Serve the API on `listener`, letting calls in the way the HTTP API's middleware lets requests in
+    pub fn serve(self, listener: TcpListener, admission: Admission) -> impl Future<Item = (), Error = ()> {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Serving the gRPC API on {}", addr);
+        }
+
+        listener
+            .incoming()
+            .for_each(move |sock| {
+                let service = server::ToshiServer::new(GrpcServer {
+                    admission: Some(admission.clone()),
+                    peer: sock.peer_addr().ok(),
+                    ..self.clone()
+                });
+                let mut h2 = Server::new(service, Default::default(), DefaultExecutor::current());
+                let serving = h2.serve(sock).map_err(|err| error!("h2 error: {:?}", err));
+                tokio::spawn(serving);
+                Ok(())
+            })
+            .map_err(|err| error!("gRPC API error: {:?}", err))
+    }
+
+    /// Answer a call to `endpoint` with `serve` if it's let in, counting it as in flight until it's answered
+    fn admitted<T, F>(&self, endpoint: Endpoint, serve: F) -> Reply<T>
+    where
+        F: FnOnce(&GrpcServer) -> Reply<T>,
+        T: Send + 'static,
+    {
+        let admission = match self.admission {
+            Some(ref admission) => admission,
+            None => return serve(self),
+        };
+        let mut request = http::Request::new(());
+        if let Some(peer) = self.peer {
+            request.extensions_mut().insert(peer);
+        }
+        match admission.admit(endpoint, &request) {
+            Ok(in_flight) => Box::new(serve(self).then(move |reply| {
+                drop(in_flight);
+                reply
+            })),
+            Err(Rejection::Draining) => Box::new(future::err(grpc_error(Code::Unavailable, "Node is draining".into()))),
+            Err(Rejection::RateLimited(wait)) => Box::new(future::err(grpc_error(
+                Code::ResourceExhausted,
+                format!("Rate limit exceeded, retry in {:?}", wait),
+            ))),
+        }
+    }
+
+    fn schema(&self, index: &str) -> Result<Schema> {
+        let catalog = self.catalog.read()?;
+        let shards = catalog.shards(index)?;
+        let shard = shards.first().ok_or_else(|| Error::UnknownIndex(index.to_string()))?;
+        Ok(shard.get_index().schema())
+    }
+
+    fn bulk_body(&self, index: &str, documents: Vec<Document>) -> Result<Vec<u8>> {
+        let schema = self.schema(index)?;
+        let mut body = Vec::new();
+        for document in documents {
+            serde_json::to_writer(&mut body, &json_document(&schema, document)?)?;
+            body.push(b'\n');
+        }
+        Ok(body)
+    }
+
+    fn summary_reply(&self, index: &str) -> Result<SummaryReply> {
+        let catalog = self.catalog.read()?;
+        let mut reply = SummaryReply::default();
+        for shard in catalog.shards(index)? {
+            let metas = shard.get_index().load_metas()?;
+            reply.docs += metas.segments.iter().map(|s| u64::from(s.num_docs())).sum::<u64>();
+            reply.segments += metas.segments.len() as u32;
+            reply.schema = serde_json::to_vec(&shard.get_index().schema())?;
+        }
+        Ok(reply)
+    }
+}
+
+impl server::Toshi for GrpcServer {
+    type SearchFuture = Reply<SearchReply>;
+    type IndexFuture = Reply<IndexReply>;
+    type BulkFuture = Reply<IndexReply>;
+    type DeleteFuture = Reply<DeleteReply>;
+    type ListIndexesFuture = Reply<ListIndexesReply>;
+    type CreateIndexFuture = Reply<CreateIndexReply>;
+    type DropIndexFuture = Reply<DropIndexReply>;
+    type SummaryFuture = Reply<SummaryReply>;
+
+    fn search(&mut self, request: Request<SearchRequest>) -> Self::SearchFuture {
+        self.admitted(Endpoint::Search, move |server| {
+            let inner = request.into_inner();
+            match serde_json::from_slice::<query::Request>(&inner.search) {
+                Ok(search) => reply(
+                    server
+                        .search
+                        .search_refs(search, inner.index, Preference::parse(None))
+                        .and_then(search_reply),
+                ),
+                Err(e) => reply(future::err(e.into())),
+            }
+        })
+    }
+
+    fn index(&mut self, request: Request<IndexRequest>) -> Self::IndexFuture {
+        self.admitted(Endpoint::Index, move |server| {
+            let inner = request.into_inner();
+            let document = server
+                .schema(&inner.index)
+                .and_then(|schema| json_document(&schema, inner.document.unwrap_or_default()));
+            match document {
+                Ok(document) => {
+                    let body = AddDocument {
+                        options: Some(IndexOptions { commit: inner.commit }),
+                        document,
+                    };
+                    reply(
+                        server
+                            .index
+                            .add(body, inner.index, write_options(inner.consistency))
+                            .map(|_| IndexReply {}),
+                    )
+                }
+                Err(e) => reply(future::err(e)),
+            }
+        })
+    }
+
+    fn bulk(&mut self, request: Request<BulkRequest>) -> Self::BulkFuture {
+        self.admitted(Endpoint::Bulk, move |server| {
+            let inner = request.into_inner();
+            match server.bulk_body(&inner.index, inner.documents) {
+                Ok(body) => reply(
+                    server
+                        .bulk
+                        .handle(body, inner.index, write_options(inner.consistency))
+                        .map(|_| IndexReply {}),
+                ),
+                Err(e) => reply(future::err(e)),
+            }
+        })
+    }
+
+    fn delete(&mut self, request: Request<DeleteRequest>) -> Self::DeleteFuture {
+        self.admitted(Endpoint::Index, move |server| {
+            let inner = request.into_inner();
+            let body = DeleteDoc {
+                options: Some(IndexOptions { commit: inner.commit }),
+                terms: inner
+                    .terms
+                    .into_iter()
+                    .map(|term| (term.field, term.value))
+                    .collect::<HashMap<_, _>>(),
+            };
+            let deleted = server.index.delete(body, inner.index, write_options(inner.consistency));
+            reply(deleted.map(|affected| DeleteReply {
+                docs_affected: affected.docs_affected,
+            }))
+        })
+    }
+
+    fn list_indexes(&mut self, _: Request<ListIndexesRequest>) -> Self::ListIndexesFuture {
+        self.admitted(Endpoint::Admin, move |server| {
+            let indexes = server.catalog.read().map(|catalog| catalog.index_names()).map_err(Error::from);
+            reply(future::result(indexes).map(|indexes| ListIndexesReply { indexes }))
+        })
+    }
+
+    fn create_index(&mut self, request: Request<CreateIndexRequest>) -> Self::CreateIndexFuture {
+        self.admitted(Endpoint::Index, move |server| {
+            let inner = request.into_inner();
+            let options = CreateOptions {
+                data_path: None,
+                directory: None,
+                preload: None,
+                sort_by: None,
+                shards: if inner.shards > 1 { Some(inner.shards as usize) } else { None },
+                routing_field: if inner.routing_field.is_empty() {
+                    None
+                } else {
+                    Some(inner.routing_field)
+                },
+                ignore_malformed: None,
+                default_fields: None,
+            };
+            let created = serde_json::from_slice::<Schema>(&inner.schema)
+                .map_err(Error::from)
+                .and_then(|schema| server.index.create_index(&inner.index, schema, Some(options)));
+            reply(future::result(created).map(|_| CreateIndexReply {}))
+        })
+    }
+
+    fn drop_index(&mut self, request: Request<DropIndexRequest>) -> Self::DropIndexFuture {
+        self.admitted(Endpoint::Index, move |server| {
+            let inner = request.into_inner();
+            reply(future::result(server.index.drop_index(inner.index)).map(|_| DropIndexReply {}))
+        })
+    }
+
+    fn summary(&mut self, request: Request<SummaryRequest>) -> Self::SummaryFuture {
+        self.admitted(Endpoint::Admin, move |server| {
+            let inner = request.into_inner();
+            reply(future::result(server.summary_reply(&inner.index)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use server::Toshi;
+
+    fn server() -> GrpcServer {
+        let catalog = create_test_catalog("test_index");
+        GrpcServer::new(
+            Arc::clone(&catalog),
+            SearchHandler::new(Arc::clone(&catalog)),
+            IndexHandler::new(Arc::clone(&catalog)),
+            BulkHandler::new(catalog),
+        )
+    }
+
+    fn field(name: &str, value: FieldValue) -> Field {
+        Field {
+            name: name.into(),
+            values: vec![value],
+        }
+    }
+
+    #[test]
+    fn test_json_document() {
+        let schema = server().schema("test_index").unwrap();
+        let document = Document {
+            fields: vec![
+                field(
+                    "test_text",
+                    FieldValue {
+                        text: "Babbaboo".into(),
+                        ..FieldValue::default()
+                    },
+                ),
+                field(
+                    "test_i64",
+                    FieldValue {
+                        i64: -10,
+                        ..FieldValue::default()
+                    },
+                ),
+                Field {
+                    name: "test_u64".into(),
+                    values: vec![
+                        FieldValue {
+                            u64: 1,
+                            ..FieldValue::default()
+                        },
+                        FieldValue {
+                            u64: 2,
+                            ..FieldValue::default()
+                        },
+                    ],
+                },
+            ],
+            score: 0.0,
+        };
+        assert_eq!(
+            json_document(&schema, document).unwrap(),
+            serde_json::json!({ "test_text": "Babbaboo", "test_i64": -10, "test_u64": [1, 2] })
+        );
+
+        let unknown = Document {
+            fields: vec![field("missing", FieldValue::default())],
+            score: 0.0,
+        };
+        assert!(json_document(&schema, unknown).is_err());
+    }
+
+    #[test]
+    fn test_api() {
+        let mut server = server();
+        let document = Document {
+            fields: vec![
+                field(
+                    "test_text",
+                    FieldValue {
+                        text: "Babbaboo".into(),
+                        ..FieldValue::default()
+                    },
+                ),
+                field(
+                    "test_u64",
+                    FieldValue {
+                        u64: 99,
+                        ..FieldValue::default()
+                    },
+                ),
+            ],
+            score: 0.0,
+        };
+        let index = IndexRequest {
+            index: "test_index".into(),
+            document: Some(document),
+            commit: true,
+            consistency: proto::Consistency::One as i32,
+        };
+        assert!(server.index(Request::new(index)).wait().is_ok());
+
+        let search = SearchRequest {
+            index: "test_index".into(),
+            search: br#"{ "query": { "term": { "test_text": "babbaboo" } } }"#.to_vec(),
+        };
+        let reply = server.search(Request::new(search)).wait().unwrap().into_inner();
+        assert_eq!(reply.hits, 1);
+        let u64s: Vec<u64> = reply.docs[0]
+            .fields
+            .iter()
+            .filter(|field| field.name == "test_u64")
+            .flat_map(|field| field.values.iter().map(|value| value.u64))
+            .collect();
+        assert_eq!(u64s, vec![99]);
+
+        let summary = server
+            .summary(Request::new(SummaryRequest {
+                index: "test_index".into(),
+            }))
+            .wait()
+            .unwrap()
+            .into_inner();
+        assert_eq!(summary.docs, 6);
+
+        let missing = SearchRequest {
+            index: "missing".into(),
+            search: br#"{ "query": { "raw": "test_text:babbaboo" } }"#.to_vec(),
+        };
+        match server.search(Request::new(missing)).wait() {
+            Err(tower_grpc::Error::Grpc(status)) => assert_eq!(status.code(), Code::NotFound),
+            _ => panic!("Searching a missing index should fail with NOT_FOUND"),
+        }
+    }
+}
@@ -1,18 +1,29 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use log::debug;
-use tantivy::collector::TopDocs;
-use tantivy::query::{AllQuery, QueryParser};
+use log::{debug, info, warn};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{BooleanQuery, Occur, Query as TantivyQuery};
 use tantivy::schema::*;
-use tantivy::{Document, Index, IndexWriter, Term};
+use tantivy::{DocAddress, Index, IndexWriter, Score, Searcher, SegmentId, Term};
 
+use crate::analysis;
+use crate::breaker::{self, CircuitBreaker, MemoryBudget, HIT_BYTES};
 use crate::handlers::index::{AddDocument, DeleteDoc, DocsAffected};
-use crate::query::{CreateQuery, Query, Request};
+use crate::mapping::{self, MappedType, Mappings};
+use crate::query::{
+    apply_runtime_fields, cancellable, doc_value_fields, doc_value_scripts, doc_values, script_doc_values, sorted_search,
+    with_default_fields, without_nested, FilterBucket, FilterCache, Metrics, Query, RankerRescore, Request, Rescore, RuntimeFields,
+    Similarities, SortedSegments, SubAggregation, TopHits, TopHitsResult, VectorGraphs,
+};
+use crate::ranker::{self, Rankers};
 use crate::results::{ScoredDoc, SearchResults};
 use crate::settings::Settings;
+use crate::wal::{self, Operation, Wal};
 use crate::{Error, Result};
-use futures::IntoFuture;
+use futures::{future, Future, IntoFuture};
 
 pub enum IndexLocation {
     LOCAL,
@@ -36,12 +47,97 @@ pub trait IndexHandle {
 /// local handle will always get called through rpc
 pub struct LocalIndex {
     index: Index,
-    writer: Arc<Mutex<IndexWriter>>,
+    writer: Mutex<Option<OpenWriter>>,
+    last_write: Mutex<Instant>,
+    budget: Arc<WriterBudget>,
+    filter_cache: Arc<FilterCache>,
+    /// Runs the per segment part of each search, shared with the other indexes
+    segment_executor: Arc<tantivy::Executor>,
+    /// Which segments are in order of the index's sort field, if it was created with one
+    sorted_segments: Option<SortedSegments>,
+    /// How the index's fields of types Tantivy has none of are stored
+    mappings: Mappings,
+    /// The graphs of the vectors of each segment, for dense vector fields built with them
+    vector_graphs: Arc<VectorGraphs>,
+    /// How the index's text fields score the terms they match, when not BM25 as Tantivy scores them
+    similarities: Similarities,
+    /// The fields every search of the index can use as if they were indexed, worked out by scripts
+    runtime_fields: RuntimeFields,
+    /// The rankers rescores can hand the top of a search to
+    rankers: Arc<Rankers>,
+    /// What searches take the memory for their hits and buckets from, shared with the other indexes
+    breaker: Arc<CircuitBreaker>,
+    /// The fields raw queries search when they don't name any
+    default_fields: Vec<String>,
+    /// Whether writes are turned away, once a lifecycle policy has moved the index on from taking them
+    read_only: AtomicBool,
+    /// Where the index's writes are logged before they're written, when they are
+    wal: Option<Arc<Wal>>,
     current_opstamp: AtomicUsize,
     settings: Settings,
     name: String,
 }
 
+struct OpenWriter {
+    writer: Arc<Mutex<IndexWriter>>,
+    heap_size: usize,
+}
+
+/// The least memory Tantivy lets an indexing thread work with
+const MIN_HEAP_PER_THREAD: usize = 3_000_000;
+/// The most indexing threads Tantivy gives a writer by default
+const MAX_WRITER_THREADS: usize = 8;
+
+/// Shares `writer_memory_budget` between the writers of every local index. Writers are opened when an
+/// index is first written to, taking `writer_memory` or whatever is left of the budget if that is less,
+/// and their memory is returned when they are closed after sitting idle.
+#[derive(Debug, Default)]
+pub struct WriterBudget {
+    total: usize,
+    allocated: Mutex<usize>,
+}
+
+impl WriterBudget {
+    /// A budget of 0 is unlimited, every writer gets the heap it asks for and is opened with its index
+    pub fn new(total: usize) -> Self {
+        WriterBudget {
+            total,
+            allocated: Mutex::new(0),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.total > 0
+    }
+
+    pub fn allocated(&self) -> usize {
+        *self.allocated.lock().unwrap()
+    }
+
+    fn reserve(&self, wanted: usize) -> usize {
+        if !self.is_limited() {
+            return wanted;
+        }
+        let mut allocated = self.allocated.lock().unwrap();
+        let heap_size = wanted.min(self.total.saturating_sub(*allocated)).max(MIN_HEAP_PER_THREAD);
+        if *allocated + heap_size > self.total {
+            warn!(
+                "Writer memory budget of {} bytes is exhausted, opening a writer with {} bytes anyway",
+                self.total, heap_size
+            );
+        }
+        *allocated += heap_size;
+        heap_size
+    }
+
+    fn release(&self, heap_size: usize) {
+        if self.is_limited() {
+            let mut allocated = self.allocated.lock().unwrap();
+            *allocated = allocated.saturating_sub(heap_size);
+        }
+    }
+}
+
 impl IndexHandle for LocalIndex {
     type SearchResponse = Result<SearchResults>;
     type DeleteResponse = Result<DocsAffected>;
@@ -55,55 +151,77 @@ impl IndexHandle for LocalIndex {
         IndexLocation::LOCAL
     }
 
-    fn search_index(&self, search: Request) -> Self::SearchResponse {
+    fn search_index(&self, mut search: Request) -> Self::SearchResponse {
         self.index.load_searchers()?;
         let searcher = self.index.searcher();
+        let segments: Vec<SegmentId> = searcher.segment_readers().iter().map(|r| r.segment_id()).collect();
+        self.filter_cache.retain_segments(&segments);
+        self.vector_graphs.retain_segments(&segments);
+        if let Some(ref sorted) = self.sorted_segments {
+            sorted.retain_segments(&segments);
+        }
         let schema = self.index.schema();
-        let collector = TopDocs::with_limit(search.limit);
+        // A search's own runtime fields take the place of the index's of the same name
+        let mut runtime_fields = self.runtime_fields.clone();
+        runtime_fields.extend(std::mem::replace(&mut search.runtime_fields, RuntimeFields::new()));
+        apply_runtime_fields(&runtime_fields, &mut search)?;
+        let scripts = doc_value_scripts(&runtime_fields, &schema, &mut search.docvalue_fields)?;
+        let fields = doc_value_fields(&schema, &search.docvalue_fields)?;
+        let values_of = |doc: DocAddress| -> Result<BTreeMap<String, Vec<Value>>> {
+            let mut values = doc_values(&searcher, doc, &fields)?;
+            values.extend(script_doc_values(&searcher, doc, &scripts)?);
+            Ok(values)
+        };
         if let Some(query) = search.query {
-            let scored_docs = match query {
-                Query::Regex(regex) => {
-                    let regex_query = regex.create_query(&schema)?;
-                    searcher.search(&*regex_query, &collector)?
-                }
-                Query::Phrase(phrase) => {
-                    let phrase_query = phrase.create_query(&schema)?;
-                    searcher.search(&*phrase_query, &collector)?
-                }
-                Query::Fuzzy(fuzzy) => {
-                    let fuzzy_query = fuzzy.create_query(&schema)?;
-                    searcher.search(&*fuzzy_query, &collector)?
-                }
-                Query::Exact(term) => {
-                    let exact_query = term.create_query(&schema)?;
-                    searcher.search(&*exact_query, &collector)?
-                }
-                Query::Boolean { bool } => {
-                    let bool_query = bool.create_query(&schema)?;
-                    searcher.search(&*bool_query, &collector)?
-                }
-                Query::Range(range) => {
-                    debug!("{:#?}", range);
-                    let range_query = range.create_query(&schema)?;
-                    debug!("{:?}", range_query);
-                    searcher.search(&*range_query, &collector)?
-                }
-                Query::Raw { raw } => {
-                    let fields: Vec<Field> = schema.fields().iter().filter_map(|e| schema.get_field(e.name())).collect();
-                    let query_parser = QueryParser::for_index(&self.index, fields);
-                    let query = query_parser.parse_query(&raw)?;
-                    debug!("{:#?}", query);
-                    searcher.search(&*query, &collector)?
+            let query = with_default_fields(mapping::convert_query(&self.mappings, query)?, &self.default_fields)?;
+            let query = query.create(&self.index, Some(&self.filter_cache), Some(&self.vector_graphs))?;
+            let query = without_nested(&schema, &self.nested_paths(), self.similarities.apply(query));
+            debug!("{:?}", query);
+            // A cancelled search stops collecting, so what it did collect is incomplete and isn't answered with
+            let cancellation = search.cancellation;
+            let query = cancellable(query, &cancellation);
+            let budget = CircuitBreaker::budget(&self.breaker);
+            let aggregated = self.aggregate(&searcher, &*query, search.aggs, &budget)?;
+            cancellation.check()?;
+            if let Some(sort) = search.sort {
+                if search.rescore.is_some() {
+                    return Err(Error::QueryError("Results sorted by a field can't be rescored".into()));
                 }
-                Query::All => searcher.search(&AllQuery, &collector)?,
+                // The best hits are kept while they're collected, as many as are asked for however few match
+                budget.reserve(search.limit.saturating_mul(HIT_BYTES))?;
+                let sorted_docs = sorted_search(&searcher, &*query, &sort, search.limit, self.sorted_segments.as_ref())?;
+                cancellation.check()?;
+                let sorted_docs = sorted_docs
+                    .into_iter()
+                    .map(|(value, doc)| {
+                        let d = searcher.doc(doc).expect("Doc not found in segment");
+                        budget.reserve(breaker::doc_bytes(&d))?;
+                        let named = mapping::display_document(&self.mappings, schema.to_named_doc(&d));
+                        Ok(ScoredDoc::sorted(value, named).with_fields(values_of(doc)?))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(aggregated.with_docs(sorted_docs));
             }
-            .into_iter()
-            .map(|(score, doc)| {
-                let d = searcher.doc(doc).expect("Doc not found in segment");
-                ScoredDoc::new(Some(score), schema.to_named_doc(&d))
-            })
-            .collect();
-            Ok(SearchResults::new(scored_docs))
+            // A rescore may move documents below the limit into it, so the search finds the whole window
+            let window = search.rescore.as_ref().map_or(0, |rescore| rescore.window_size);
+            budget.reserve(search.limit.max(window).saturating_mul(HIT_BYTES))?;
+            let collector = TopDocs::with_limit(search.limit.max(window));
+            let mut docs = searcher.search_with_executor(&*query, &collector, &self.segment_executor)?;
+            cancellation.check()?;
+            if let Some(rescore) = search.rescore {
+                self.rescore(&searcher, rescore, &mut docs)?;
+                docs.truncate(search.limit);
+            }
+            let scored_docs = docs
+                .into_iter()
+                .map(|(score, doc)| {
+                    let d = searcher.doc(doc).expect("Doc not found in segment");
+                    budget.reserve(breaker::doc_bytes(&d))?;
+                    let named = mapping::display_document(&self.mappings, schema.to_named_doc(&d));
+                    Ok(ScoredDoc::new(Some(score), named).with_fields(values_of(doc)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(aggregated.with_docs(scored_docs))
         } else {
             Err(Error::QueryError("Empty Query Provided".into()))
         }
@@ -111,13 +229,20 @@ impl IndexHandle for LocalIndex {
 
     fn add_document(&self, add_doc: AddDocument) -> Self::AddResponse {
         let index_schema = self.index.schema();
-        let writer_lock = self.get_writer();
+        let writer_lock = self.get_writer()?;
         let mut index_writer = writer_lock.lock()?;
-        let doc: Document = LocalIndex::parse_doc(&index_schema, &add_doc.document.to_string())?;
-        index_writer.add_document(doc);
+        let docs = mapping::parse_documents(&index_schema, &self.mappings, &add_doc.document.to_string())?;
+        if let Some(ref wal) = self.wal {
+            wal.append(Operation::Add {
+                document: add_doc.document,
+            })?;
+        }
+        for doc in docs {
+            index_writer.add_document(doc);
+        }
         if let Some(opts) = add_doc.options {
             if opts.commit {
-                index_writer.commit().unwrap();
+                self.commit_writer(&mut index_writer)?;
                 self.set_opstamp(0);
             }
         } else {
@@ -126,19 +251,123 @@ impl IndexHandle for LocalIndex {
         Ok(())
     }
 
+    /// Deletes made here aren't logged, `IndexCatalog::delete_documents` logs them once for every shard of the index
     fn delete_term(&self, term: DeleteDoc) -> Self::DeleteResponse {
-        let index_schema = self.index.schema();
-        let writer_lock = self.get_writer();
+        let writer_lock = self.get_writer()?;
         let mut index_writer = writer_lock.lock()?;
+        self.delete_with(&mut index_writer, &term)
+    }
+}
 
-        for (field, value) in term.terms {
-            let f = index_schema.get_field(&field).unwrap();
-            let term = Term::from_field_text(f, &value);
-            index_writer.delete_term(term);
+impl LocalIndex {
+    pub fn new(index: Index, settings: Settings, name: &str) -> Result<Self> {
+        LocalIndex::with_budget(index, settings, name, Arc::new(WriterBudget::default()))
+    }
+
+    /// Open an index whose writer shares `budget` with other indexes. With an unlimited budget the
+    /// writer is opened right away, otherwise not until the index is written to.
+    pub fn with_budget(index: Index, settings: Settings, name: &str, budget: Arc<WriterBudget>) -> Result<Self> {
+        let handle = Self {
+            index,
+            writer: Mutex::new(None),
+            last_write: Mutex::new(Instant::now()),
+            budget,
+            filter_cache: Arc::new(FilterCache::new(settings.filter_cache_size)),
+            segment_executor: Arc::new(tantivy::Executor::single_thread()),
+            sorted_segments: None,
+            mappings: Mappings::new(),
+            vector_graphs: Arc::new(VectorGraphs::default()),
+            similarities: settings.similarities_for(name),
+            runtime_fields: settings.runtime_fields_for(name),
+            rankers: Arc::new(Rankers::default()),
+            breaker: Arc::new(CircuitBreaker::default()),
+            default_fields: Vec::new(),
+            read_only: AtomicBool::new(false),
+            wal: None,
+            current_opstamp: AtomicUsize::new(0),
+            settings,
+            name: name.into(),
+        };
+        analysis::register(handle.index.tokenizers(), &handle.index.schema(), &handle.settings);
+        if !handle.budget.is_limited() {
+            handle.get_writer()?;
         }
-        if let Some(opts) = term.options {
+        Ok(handle)
+    }
+
+    /// Search segments on `executor` instead of one after another on the searching thread
+    pub fn with_segment_executor(mut self, executor: Arc<tantivy::Executor>) -> Self {
+        self.segment_executor = executor;
+        self
+    }
+
+    /// Let rescores rank with `rankers`, shared with the other indexes
+    pub fn with_rankers(mut self, rankers: Arc<Rankers>) -> Self {
+        self.rankers = rankers;
+        self
+    }
+
+    /// Take the memory of searches from `breaker`, shared with the other indexes
+    pub fn with_breaker(mut self, breaker: Arc<CircuitBreaker>) -> Self {
+        self.breaker = breaker;
+        self
+    }
+
+    /// Let searches sorted by `field` stop early in segments whose documents were added in its order
+    pub fn with_sort_by(mut self, field: Option<String>) -> Self {
+        self.sorted_segments = field.map(SortedSegments::new);
+        self
+    }
+
+    /// Convert the values documents and queries give the fields of `mappings` to what those fields are stored as
+    pub fn with_mappings(mut self, mappings: Mappings) -> Self {
+        self.vector_graphs = Arc::new(VectorGraphs::new(&self.index.schema(), &mappings));
+        self.mappings = mappings;
+        self
+    }
+
+    /// Search `fields`, each `name` or `name^boost`, for the terms of raw queries that don't name fields of their own
+    pub fn with_default_fields(mut self, fields: Vec<String>) -> Self {
+        self.default_fields = fields;
+        self
+    }
+
+    /// Log every write to the index in `wal` before it's written, and record in each commit where the log stands
+    pub fn with_wal(mut self, wal: Option<Arc<Wal>>) -> Self {
+        self.wal = wal;
+        self
+    }
+
+    pub fn mappings(&self) -> &Mappings {
+        &self.mappings
+    }
+
+    /// Where the index's writes are logged, if they are
+    pub fn wal(&self) -> Option<&Arc<Wal>> {
+        self.wal.as_ref()
+    }
+
+    /// The terms whose documents `delete` deletes
+    pub fn terms_of(&self, delete: &DeleteDoc) -> Result<Vec<Term>> {
+        let schema = self.index.schema();
+        delete
+            .terms
+            .iter()
+            .map(|(field, value)| {
+                let field = schema.get_field(field).ok_or_else(|| Error::UnknownIndexField(field.clone()))?;
+                Ok(Term::from_field_text(field, value))
+            })
+            .collect()
+    }
+
+    /// Delete the documents holding any of the terms of `delete` through `writer`, the index's writer the caller holds
+    pub fn delete_with(&self, writer: &mut IndexWriter, delete: &DeleteDoc) -> Result<DocsAffected> {
+        for term in self.terms_of(delete)? {
+            writer.delete_term(term);
+        }
+        if let Some(ref opts) = delete.options {
             if opts.commit {
-                index_writer.commit().unwrap();
+                self.commit_writer(writer)?;
                 self.set_opstamp(0);
             }
         }
@@ -150,25 +379,180 @@ impl IndexHandle for LocalIndex {
 
         Ok(DocsAffected { docs_affected })
     }
-}
 
-impl LocalIndex {
-    pub fn new(index: Index, settings: Settings, name: &str) -> Result<Self> {
-        let i = index.writer(settings.writer_memory)?;
-        i.set_merge_policy(settings.get_merge_policy());
-        let current_opstamp = AtomicUsize::new(0);
-        let writer = Arc::new(Mutex::new(i));
-        Ok(Self {
-            index,
-            writer,
-            current_opstamp,
-            settings,
-            name: name.into(),
-        })
+    /// Commit `writer`, the index's writer the caller holds, recording where the index's log stands if it has one
+    fn commit_writer(&self, writer: &mut IndexWriter) -> Result<u64> {
+        wal::commit(writer, self.wal.as_ref().map(|wal| &**wal))
     }
 
-    fn parse_doc(schema: &Schema, bytes: &str) -> Result<Document> {
-        schema.parse_document(bytes).map_err(|e| e.into())
+    /// `query` built for this index as a search's own query would be
+    fn build_query(&self, query: Query) -> Result<Box<TantivyQuery>> {
+        let query = with_default_fields(mapping::convert_query(&self.mappings, query)?, &self.default_fields)?;
+        let query = query.create(&self.index, Some(&self.filter_cache), Some(&self.vector_graphs))?;
+        Ok(self.similarities.apply(query))
+    }
+
+    /// Score the best of `docs`, the documents a search found, again as `rescore` asks
+    fn rescore(&self, searcher: &Searcher, mut rescore: Rescore, docs: &mut Vec<(Score, DocAddress)>) -> Result<()> {
+        let query = match rescore.take_query()? {
+            Some(query) => Some(self.build_query(query)?),
+            None => None,
+        };
+        if let Some(RankerRescore { name, features, fields }) = rescore.ranker.take() {
+            let ranker = self.rankers.get(&name)?;
+            let features = features
+                .into_iter()
+                .map(|(feature, query)| Ok((feature, self.build_query(query)?)))
+                .collect::<Result<Vec<_>>>()?;
+            let fields = doc_value_fields(&self.index.schema(), &fields)?;
+            // A ranker that fails or is too slow leaves the search with the scores it already has
+            match ranker::rank(&*ranker, &self.name, searcher, &features, &fields, rescore.window(docs)) {
+                Ok(scores) => rescore.combine(&scores, docs),
+                Err(e) => warn!("Ranker {} could not rank a search of {}: {}", name, self.name, e),
+            }
+            return Ok(());
+        }
+        if let Some(ref mut vector) = rescore.vector {
+            if let Some(MappedType::DenseVector { similarity, .. }) = self.mappings.get(&vector.field).and_then(|m| m.kind.as_ref()) {
+                vector.similarity = vector.similarity.or(Some(*similarity));
+            }
+        }
+        rescore.apply(searcher, query.as_ref().map(|query| &**query), docs)
+    }
+
+    /// What `aggs` finds among the documents `query` matches, as results without any hits yet
+    fn aggregate(&self, searcher: &Searcher, query: &TantivyQuery, aggs: Option<Metrics>, budget: &MemoryBudget) -> Result<SearchResults> {
+        let schema = self.index.schema();
+        let results = SearchResults::new(Vec::new());
+        match aggs {
+            Some(Metrics::DateHistogram { date_histogram }) => {
+                let mapping = match self.mappings.get(&date_histogram.field) {
+                    Some(mapping) if mapping.is_date() => Some(mapping),
+                    _ if date_histogram.script.is_some() => None,
+                    _ => return Err(Error::QueryError(format!("Field {} is not a date field", date_histogram.field))),
+                };
+                // A script gives dates in milliseconds, which its missing date is given in too
+                let missing = match (&date_histogram.missing, mapping) {
+                    (Some(date), Some(mapping)) => mapping.convert(date)?.as_i64(),
+                    (Some(date), None) => date.as_i64(),
+                    (None, _) => None,
+                };
+                let mut buckets = date_histogram.collect(searcher, query, &self.segment_executor, missing, budget)?;
+                if let Some(SubAggregation::TopHits { ref top_hits }) = date_histogram.aggs {
+                    for bucket in &mut buckets {
+                        let bucket_query = date_histogram.bucket_query(&schema, bucket.key)?;
+                        bucket.top_hits = Some(self.top_hits(searcher, query, bucket_query, top_hits)?);
+                    }
+                }
+                Ok(results.with_buckets(Some(buckets)))
+            }
+            Some(Metrics::SignificantTerms { significant_terms }) => {
+                let mut significant = significant_terms.collect(searcher, query, budget)?;
+                if let Some(SubAggregation::TopHits { ref top_hits }) = significant_terms.aggs {
+                    for bucket in &mut significant.buckets {
+                        let bucket_query = significant_terms.bucket_query(&schema, &bucket.key)?;
+                        bucket.top_hits = Some(self.top_hits(searcher, query, bucket_query, top_hits)?);
+                    }
+                }
+                Ok(results.with_significant_terms(Some(significant)))
+            }
+            Some(Metrics::Range { range }) => {
+                let mapping = self.mappings.get(&range.field);
+                let mut buckets = range.collect(searcher, query, &self.segment_executor, mapping)?;
+                if let Some(SubAggregation::TopHits { ref top_hits }) = range.aggs {
+                    for (nth, bucket) in buckets.iter_mut().enumerate() {
+                        let bucket_query = range.bucket_query(&schema, nth, mapping)?;
+                        bucket.top_hits = Some(self.top_hits(searcher, query, bucket_query, top_hits)?);
+                    }
+                }
+                Ok(results.with_ranges(Some(buckets)))
+            }
+            Some(Metrics::Filters { filters }) => {
+                let mut buckets = BTreeMap::new();
+                for (name, filter) in filters.filters {
+                    let filter = with_default_fields(mapping::convert_query(&self.mappings, filter)?, &self.default_fields)?;
+                    let filter = filter.create(&self.index, Some(&self.filter_cache), Some(&self.vector_graphs))?;
+                    let both = BooleanQuery::from(vec![(Occur::Must, query.box_clone()), (Occur::Must, filter.box_clone())]);
+                    let doc_count = searcher.search(&both, &Count)? as u64;
+                    let top_hits = match filters.aggs {
+                        Some(SubAggregation::TopHits { ref top_hits }) => Some(self.top_hits(searcher, query, filter, top_hits)?),
+                        None => None,
+                    };
+                    buckets.insert(name, FilterBucket { doc_count, top_hits });
+                }
+                Ok(results.with_filters(Some(buckets)))
+            }
+            Some(Metrics::GeohashGrid { geohash_grid }) => {
+                match self.mappings.get(&geohash_grid.field) {
+                    Some(mapping) if mapping.is_geo_point() => {}
+                    _ => return Err(Error::QueryError(format!("Field {} is not a geo point field", geohash_grid.field))),
+                }
+                let mut buckets = geohash_grid.collect(searcher, query, &self.segment_executor, budget)?;
+                if let Some(SubAggregation::TopHits { ref top_hits }) = geohash_grid.aggs {
+                    for bucket in &mut buckets {
+                        let bucket_query = geohash_grid.bucket_query(&schema, &bucket.key)?;
+                        bucket.top_hits = Some(self.top_hits(searcher, query, bucket_query, top_hits)?);
+                    }
+                }
+                Ok(results.with_geohash_grid(Some(buckets)))
+            }
+            Some(Metrics::Composite { composite }) => {
+                Ok(results.with_composite(Some(composite.collect(searcher, query, &self.mappings, budget)?)))
+            }
+            _ => Ok(results),
+        }
+    }
+
+    /// The hits `top_hits` asks for among the documents that both `query` and `bucket` match
+    fn top_hits(&self, searcher: &Searcher, query: &TantivyQuery, bucket: Box<TantivyQuery>, top_hits: &TopHits) -> Result<TopHitsResult> {
+        if top_hits.size == 0 {
+            return Ok(top_hits.result(Vec::new()));
+        }
+        let query = BooleanQuery::from(vec![(Occur::Must, query.box_clone()), (Occur::Must, bucket)]);
+        let schema = self.index.schema();
+        let named = |doc: DocAddress| -> Result<NamedFieldDocument> {
+            let d = searcher.doc(doc)?;
+            Ok(mapping::display_document(&self.mappings, schema.to_named_doc(&d)))
+        };
+        let hits = match top_hits.sort {
+            Some(ref sort) => sorted_search(searcher, &query, sort, top_hits.size, self.sorted_segments.as_ref())?
+                .into_iter()
+                .map(|(value, doc)| Ok(ScoredDoc::sorted(value, named(doc)?)))
+                .collect::<Result<Vec<_>>>()?,
+            None => searcher
+                .search(&query, &TopDocs::with_limit(top_hits.size))?
+                .into_iter()
+                .map(|(score, doc)| Ok(ScoredDoc::new(Some(score), named(doc)?)))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        Ok(top_hits.result(hits))
+    }
+
+    /// The nested fields of the index, whose objects searches leave out
+    fn nested_paths(&self) -> Vec<String> {
+        self.mappings
+            .iter()
+            .filter(|(_, mapping)| mapping.is_nested())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    fn open_writer(&self) -> Result<OpenWriter> {
+        let heap_size = self.budget.reserve(self.settings.writer_memory);
+        let threads = num_cpus::get().min(MAX_WRITER_THREADS).min(heap_size / MIN_HEAP_PER_THREAD).max(1);
+        match self.index.writer_with_num_threads(threads, heap_size) {
+            Ok(writer) => {
+                writer.set_merge_policy(self.settings.get_merge_policy());
+                Ok(OpenWriter {
+                    writer: Arc::new(Mutex::new(writer)),
+                    heap_size,
+                })
+            }
+            Err(e) => {
+                self.budget.release(heap_size);
+                Err(e.into())
+            }
+        }
     }
 
     pub fn get_index(&self) -> &Index {
@@ -176,11 +560,144 @@ impl LocalIndex {
     }
 
     pub fn recreate_writer(self) -> Result<Self> {
-        LocalIndex::new(self.index, self.settings.clone(), &self.name)
+        self.close_writer()?;
+        Ok(self)
+    }
+
+    /// The index's writer, opening it if it was closed for being idle. Read only indexes have none to give.
+    pub fn get_writer(&self) -> Result<Arc<Mutex<IndexWriter>>> {
+        if self.is_read_only() {
+            return Err(Error::IOError(format!("Index {} is read only", self.name)));
+        }
+        self.current_writer()
+    }
+
+    /// The index's writer, whether or not it's read only
+    fn current_writer(&self) -> Result<Arc<Mutex<IndexWriter>>> {
+        let mut open = self.writer.lock()?;
+        if open.is_none() {
+            *open = Some(self.open_writer()?);
+        }
+        *self.last_write.lock()? = Instant::now();
+        Ok(Arc::clone(&open.as_ref().unwrap().writer))
+    }
+
+    /// Turn writes away from now on, or take them again. Documents written before the index is made read only are
+    /// committed, and its writer closed.
+    pub fn set_read_only(&self, read_only: bool) -> Result<()> {
+        if read_only && !self.read_only.swap(true, Ordering::SeqCst) {
+            self.close_writer()?;
+        }
+        self.read_only.store(read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Merge every segment of the index into one, in the background, returning the work of waiting for it. Searches
+    /// see the merged segment once it's done.
+    pub fn force_merge(&self) -> Result<Box<Future<Item = (), Error = Error> + Send>> {
+        self.commit()?;
+        let segments = self.index.searchable_segment_ids()?;
+        if segments.len() < 2 {
+            return Ok(Box::new(future::ok(())));
+        }
+        let merging = self.current_writer()?.lock()?.merge(&segments)?;
+        let index = self.index.clone();
+        let merged = merging
+            .map_err(|_| Error::IOError("The merge was cancelled".into()))
+            .and_then(move |_| index.load_searchers().map_err(Error::from));
+        Ok(Box::new(merged))
     }
 
-    pub fn get_writer(&self) -> Arc<Mutex<IndexWriter>> {
-        Arc::clone(&self.writer)
+    /// Whether the index currently holds a writer and the memory that comes with it
+    pub fn has_writer(&self) -> bool {
+        self.writer.lock().map(|w| w.is_some()).unwrap_or(false)
+    }
+
+    /// Commit and close the writer if it hasn't been used for `timeout` and nothing else holds it,
+    /// returning its memory to the budget. Returns whether the writer was closed.
+    pub fn close_idle_writer(&self, timeout: Duration) -> Result<bool> {
+        if self.last_write.lock()?.elapsed() < timeout {
+            return Ok(false);
+        }
+        // Writers are only handed out under this lock, so holding it until the writer is gone keeps one from being
+        // handed out between checking it's unused and closing it
+        let mut writer = self.writer.lock()?;
+        match *writer {
+            Some(ref open) if Arc::strong_count(&open.writer) == 1 => {}
+            _ => return Ok(false),
+        }
+        if let Some(open) = writer.take() {
+            self.close(open)?;
+        }
+        info!("Closed idle writer for index={}", self.name);
+        Ok(true)
+    }
+
+    fn close_writer(&self) -> Result<()> {
+        let mut writer = self.writer.lock()?;
+        match writer.take() {
+            Some(open) => self.close(open),
+            None => Ok(()),
+        }
+    }
+
+    /// Commit what `open` holds and give its memory back, while the caller holds the lock it was taken out from
+    fn close(&self, open: OpenWriter) -> Result<()> {
+        self.budget.release(open.heap_size);
+        self.commit_writer(&mut open.writer.lock()?)?;
+        self.set_opstamp(0);
+        Ok(())
+    }
+
+    /// Commit and close the writer, waiting for the merges it started to finish, so nothing writes to the index once
+    /// it's done with
+    pub fn close_and_wait(self) -> Result<()> {
+        let open = self.writer.lock()?.take();
+        if let Some(open) = open {
+            self.budget.release(open.heap_size);
+            let writer =
+                Arc::try_unwrap(open.writer).map_err(|_| Error::IOError(format!("The writer of {} is still in use", self.name)))?;
+            let mut writer = writer.into_inner()?;
+            self.commit_writer(&mut writer)?;
+            writer.wait_merging_threads()?;
+        }
+        Ok(())
+    }
+
+    /// Commit any pending documents, waiting on writes that currently hold the writer, and build the graphs of the
+    /// vectors of the new segments so searches don't have to
+    pub fn commit(&self) -> Result<u64> {
+        let writer = match *self.writer.lock()? {
+            Some(ref open) => Arc::clone(&open.writer),
+            None => return Ok(self.index.load_metas()?.opstamp),
+        };
+        let opstamp = self.commit_writer(&mut writer.lock()?)?;
+        self.set_opstamp(0);
+        if self.vector_graphs.has_fields() {
+            self.index.load_searchers()?;
+            self.vector_graphs.build(&self.index.searcher())?;
+        }
+        Ok(opstamp)
+    }
+
+    /// Run each of `queries` against the index, reloading its searchers and pulling the segments they touch into the
+    /// page cache ahead of real searches. Returns how many ran successfully, failures are only logged.
+    pub fn warm_up(&self, queries: &[&str]) -> usize {
+        let start = Instant::now();
+        let mut warmed = 0;
+        for query in queries {
+            let request: Result<Request> = serde_json::from_str(query).map_err(Error::from);
+            match request.and_then(|r| self.search_index(r)) {
+                Ok(_) => warmed += 1,
+                Err(e) => warn!("Warm-up query for index={} failed: {}", self.name, e),
+            }
+        }
+        debug!("Ran {} warm-up queries for index={} in {:?}", warmed, self.name, start.elapsed());
+        warmed
     }
 
     pub fn get_opstamp(&self) -> usize {
@@ -191,3 +708,13 @@ impl LocalIndex {
         self.current_opstamp.store(opstamp, Ordering::Relaxed)
     }
 }
+
+impl Drop for LocalIndex {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.writer.lock() {
+            if let Some(open) = open.take() {
+                self.budget.release(open.heap_size);
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use tantivy::schema::FieldType;
+use tantivy::tokenizer::{TokenStream, Tokenizer};
+use tower_web::*;
+
+use crate::analysis::{self, Analyzer};
+use crate::index::IndexCatalog;
+use crate::Error;
+
+/// An analyzer by name, or one given in full the way the settings give them
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum AnalyzerSpec {
+    Name(String),
+    Custom(Analyzer),
+}
+
+/// Text to analyze with the analyzer of `field`, or with `analyzer`, or with `default` when neither is given
+#[derive(Extract, Deserialize)]
+pub struct AnalyzeRequest {
+    text: String,
+    #[serde(default)]
+    field: Option<String>,
+    #[serde(default)]
+    analyzer: Option<AnalyzerSpec>,
+}
+
+/// A term an analyzer produced, with the byte offsets of the text it came from
+#[derive(Serialize, Debug, PartialEq)]
+pub struct AnalyzedToken {
+    pub token: String,
+    pub position: usize,
+    pub start_offset: usize,
+    pub end_offset: usize,
+}
+
+#[derive(Response, Serialize, Debug)]
+pub struct AnalyzeResponse {
+    pub tokens: Vec<AnalyzedToken>,
+}
+
+impl AnalyzeResponse {
+    fn of(stream: &mut TokenStream) -> Self {
+        let mut tokens = Vec::new();
+        while stream.advance() {
+            let token = stream.token();
+            tokens.push(AnalyzedToken {
+                token: token.text.clone(),
+                position: token.position,
+                start_offset: token.offset_from,
+                end_offset: token.offset_to,
+            });
+        }
+        AnalyzeResponse { tokens }
+    }
+}
+
+#[derive(Clone)]
+pub struct AnalyzeHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+}
+
+impl AnalyzeHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        AnalyzeHandler { catalog }
+    }
+
+    /// The terms `request` gives with the analyzers of `index`, as `POST /:index/_analyze` returns them
+    pub fn analyze(&self, index: &str, request: AnalyzeRequest) -> Result<AnalyzeResponse, Error> {
+        let catalog = self.catalog.read()?;
+        let shards = catalog.shards(index)?;
+        let shard = shards.first().ok_or_else(|| Error::UnknownIndex(index.to_string()))?;
+        let index = shard.get_index();
+        let name = match (request.field, request.analyzer) {
+            (Some(_), Some(_)) => return Err(Error::QueryError("Analyze with either a field or an analyzer".into())),
+            (Some(field), None) => {
+                let schema = index.schema();
+                let entry = schema
+                    .get_field(&field)
+                    .map(|field| schema.get_field_entry(field))
+                    .ok_or_else(|| Error::QueryError(format!("Field: {} does not exist", field)))?;
+                match entry.field_type() {
+                    FieldType::Str(options) => match options.get_indexing_options() {
+                        Some(indexing) => indexing.tokenizer().to_string(),
+                        None => return Err(Error::QueryError(format!("Field {} is not indexed", field))),
+                    },
+                    _ => return Err(Error::QueryError(format!("Field {} is not a text field", field))),
+                }
+            }
+            (None, Some(AnalyzerSpec::Name(name))) => name,
+            (None, Some(AnalyzerSpec::Custom(analyzer))) => {
+                let analyzer = analyzer.load(Path::new(&catalog.settings.analysis_path))?;
+                return Ok(AnalyzeResponse::of(&mut analyzer.token_stream(&request.text)));
+            }
+            (None, None) => "default".to_string(),
+        };
+        // Analyzers only fields add with their own filters aren't registered until a field uses them
+        match index.tokenizers().get(&name) {
+            Some(tokenizer) => Ok(AnalyzeResponse::of(&mut *tokenizer.token_stream(&request.text))),
+            None => {
+                let analyzer = analysis::resolve(&name, &catalog.settings)?;
+                Ok(AnalyzeResponse::of(&mut analyzer.token_stream(&request.text)))
+            }
+        }
+    }
+}
+
+impl_web! {
+    impl AnalyzeHandler {
+        #[post("/:index/_analyze")]
+        #[content_type("application/json")]
+        pub fn handle(&self, body: AnalyzeRequest, index: String) -> Result<AnalyzeResponse, Error> {
+            self.analyze(&index, body)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::IndexHandler;
+    use crate::index::tests::create_test_catalog;
+
+    fn token(token: &str, position: usize, start_offset: usize, end_offset: usize) -> AnalyzedToken {
+        AnalyzedToken {
+            token: token.into(),
+            position,
+            start_offset,
+            end_offset,
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let catalog = create_test_catalog("test_index");
+        let schema = r#"[
+            { "name": "title", "type": "text", "analyzer": "english", "stop_words": ["the"], "options": { "stored": true } },
+            { "name": "count", "type": "u64", "options": { "indexed": true } }
+         ]"#;
+        IndexHandler::new(Arc::clone(&catalog))
+            .create(serde_json::from_str(schema).unwrap(), "analyzed".into(), None)
+            .unwrap();
+        let handler = AnalyzeHandler::new(catalog);
+        let analyze = |body: &str| handler.analyze("analyzed", serde_json::from_str(body).unwrap());
+
+        let field = analyze(r#"{ "text": "The Runners", "field": "title" }"#).unwrap();
+        assert_eq!(field.tokens, vec![token("runner", 1, 4, 11)]);
+        let named = analyze(r#"{ "text": "The Runners", "analyzer": "whitespace" }"#).unwrap();
+        assert_eq!(named.tokens, vec![token("The", 0, 0, 3), token("Runners", 1, 4, 11)]);
+        let derived = analyze(r#"{ "text": "running", "analyzer": "english/none" }"#).unwrap();
+        assert_eq!(derived.tokens, vec![token("running", 0, 0, 7)]);
+        let custom = analyze(r#"{ "text": "A b", "analyzer": { "tokenizer": "whitespace", "lowercase": true } }"#).unwrap();
+        assert_eq!(custom.tokens, vec![token("a", 0, 0, 1), token("b", 1, 2, 3)]);
+        let default = analyze(r#"{ "text": "Hello" }"#).unwrap();
+        assert_eq!(default.tokens, vec![token("hello", 0, 0, 5)]);
+
+        assert!(analyze(r#"{ "text": "a", "field": "count" }"#).is_err());
+        assert!(analyze(r#"{ "text": "a", "field": "missing" }"#).is_err());
+        assert!(analyze(r#"{ "text": "a", "analyzer": "missing" }"#).is_err());
+        assert!(analyze(r#"{ "text": "a", "field": "title", "analyzer": "english" }"#).is_err());
+        assert!(handler
+            .analyze("missing", serde_json::from_str(r#"{ "text": "a" }"#).unwrap())
+            .is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use futures::Future;
+use serde::Deserialize;
+use tower_web::*;
+
+use crate::async_search::{AsyncSearchStatus, AsyncSearches, DEFAULT_KEEP_ALIVE};
+use crate::cluster::routing::Preference;
+use crate::handlers::SearchHandler;
+use crate::query::Request;
+use crate::tasks::Cancellation;
+use crate::Error;
+
+/// Options for async searches, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct AsyncSearchOptions {
+    /// See `SearchOptions`
+    pub preference: Option<String>,
+    /// How long to keep the search's results after it's submitted or polled, in seconds
+    pub keep_alive: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct AsyncSearchHandler {
+    searches: AsyncSearches,
+    search: SearchHandler,
+}
+
+impl AsyncSearchHandler {
+    pub fn new(search: SearchHandler) -> Self {
+        AsyncSearchHandler {
+            searches: AsyncSearches::default(),
+            search,
+        }
+    }
+
+    /// Start searching `index` with `body` in the background, returning the search's status and the work to spawn
+    fn start(
+        &self,
+        mut body: Request,
+        index: String,
+        options: Option<AsyncSearchOptions>,
+    ) -> Result<(AsyncSearchStatus, Box<Future<Item = (), Error = ()> + Send>), Error> {
+        let (preference, keep_alive) = match options {
+            Some(options) => (options.preference, options.keep_alive),
+            None => (None, None),
+        };
+        let pipelines = body.take_pipelines();
+        let (sort, limit) = (body.sort.clone(), body.limit);
+        let cancellation = Cancellation::default();
+        body.cancellation = cancellation.clone();
+        let searches = self
+            .search
+            .searches(body, &index, &Preference::parse(preference.as_ref().map(String::as_str)))?;
+        let keep_alive = Duration::from_secs(keep_alive.unwrap_or(DEFAULT_KEEP_ALIVE));
+        self.searches
+            .start(index, searches, sort, limit, pipelines, keep_alive, cancellation)
+    }
+}
+
+impl_web! {
+    impl AsyncSearchHandler {
+        #[post("/:index/_async_search")]
+        #[content_type("application/json")]
+        fn submit(&self, body: Request, index: String, query_string: Option<AsyncSearchOptions>) -> Result<AsyncSearchStatus, Error> {
+            let (status, work) = self.start(body, index, query_string)?;
+            tokio::spawn(work);
+            Ok(status)
+        }
+
+        #[get("/_async_search/:id")]
+        #[content_type("application/json")]
+        fn get(&self, id: String) -> Result<AsyncSearchStatus, Error> {
+            self.searches.get(&id)
+        }
+
+        #[delete("/_async_search/:id")]
+        #[content_type("application/json")]
+        fn remove(&self, id: String) -> Result<String, Error> {
+            if !self.searches.remove(&id)? {
+                return Err(Error::IOError(format!("No async search {} is running or kept", id)));
+            }
+            Ok(serde_json::json!({ "removed": id }).to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+
+    #[test]
+    fn test_async_search() {
+        let handler = AsyncSearchHandler::new(SearchHandler::new(create_test_catalog("test_index")));
+        let body: Request = serde_json::from_str(r#"{ "query": { "term": { "test_text": "document" } } }"#).unwrap();
+        let (status, work) = handler.start(body, "test_index".into(), None).unwrap();
+        assert_eq!(status.index, "test_index");
+        work.wait().unwrap();
+
+        let done = handler.get(status.id.clone()).unwrap();
+        assert!(!done.is_running && !done.is_partial);
+        assert_eq!(done.response.unwrap()["hits"], 3);
+
+        handler.remove(status.id.clone()).unwrap();
+        assert!(handler.get(status.id.clone()).is_err());
+        assert!(handler.remove(status.id).is_err());
+        assert!(handler.start(Request::all_docs(), String::new(), None).is_err());
+    }
+}
@@ -1,5 +1,11 @@
+use crate::cluster::replication::{Acknowledged, Replicator};
+use crate::executor::Executor;
+use crate::handlers::index::WriteOptions;
 use crate::handlers::CreatedResponse;
 use crate::index::IndexCatalog;
+use crate::mapping;
+use crate::shard::Sharding;
+use crate::wal::{self, Operation, Wal};
 use crate::Error;
 
 use std::iter::Iterator;
@@ -8,6 +14,9 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
 use crossbeam::channel::{unbounded, Receiver};
+use futures::{future, Future};
+use log::error;
+use serde_json::Value;
 use tantivy::Document;
 use tantivy::IndexWriter;
 use tower_web::*;
@@ -15,80 +24,144 @@ use tower_web::*;
 #[derive(Clone)]
 pub struct BulkHandler {
     catalog: Arc<RwLock<IndexCatalog>>,
+    executor: Executor,
+    replicator: Replicator,
 }
 
 impl BulkHandler {
     pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
-        BulkHandler { catalog }
+        BulkHandler::with_executor(catalog, Executor::default())
     }
 
-    fn index_documents(index_writer: &Mutex<IndexWriter>, doc_receiver: Receiver<Document>) -> Result<u64, Error> {
+    /// Parse documents on `executor` rather than on threads of their own
+    pub fn with_executor(catalog: Arc<RwLock<IndexCatalog>>, executor: Executor) -> Self {
+        BulkHandler {
+            catalog,
+            executor,
+            replicator: Replicator::default(),
+        }
+    }
+
+    /// Forward every bulk request that's accepted here on to `replicator`'s replicas
+    pub fn with_replicator(mut self, replicator: Replicator) -> Self {
+        self.replicator = replicator;
+        self
+    }
+
+    /// Which shard a document goes to, or `None` if it can't be routed
+    fn shard_of(sharding: Option<&Sharding>, text: &str) -> Option<usize> {
+        match sharding {
+            Some(sharding) => serde_json::from_str(text).ok().and_then(|doc| sharding.route(&doc).ok()),
+            None => Some(0),
+        }
+    }
+
+    /// Write the documents parsed from each line to `index_writer`, logging the line's document to `wal` first
+    fn index_documents(
+        index_writer: &Mutex<IndexWriter>,
+        wal: Option<Arc<Wal>>,
+        doc_receiver: Receiver<(Option<Value>, Vec<Document>)>,
+    ) -> Result<u64, Error> {
         match index_writer.lock() {
             Ok(ref mut w) => {
-                for doc in doc_receiver {
-                    w.add_document(doc);
-                }
-                match w.commit() {
-                    Ok(c) => Ok(c),
-                    Err(e) => Err(e.into()),
+                for (document, docs) in doc_receiver {
+                    if let (Some(wal), Some(document)) = (wal.as_ref(), document) {
+                        if let Err(e) = wal.append(Operation::Add { document }) {
+                            error!("Failed to log a bulk document, so it wasn't indexed: {}", e);
+                            continue;
+                        }
+                    }
+                    for doc in docs {
+                        w.add_document(doc);
+                    }
                 }
+                wal::commit(w, wal.as_ref().map(|wal| &**wal))
             }
             Err(e) => Err(e.into()),
         }
     }
-}
 
-impl_web! {
-    impl BulkHandler {
-        #[post("/:index/_bulk")]
-        #[content_type("application/json")]
-        pub fn handle(&self, body: Vec<u8>, index: String) -> Result<CreatedResponse, Error> {
-
-            let index_lock = self.catalog.read()?;
-            let index_handle = index_lock.get_index(&index)?;
-            let index = index_handle.get_index();
-            let schema = index.schema();
-            let (line_sender, line_recv) = index_lock.settings.get_channel::<Vec<u8>>();
-            let (doc_sender, doc_recv) = unbounded::<Document>();
-
-            for _ in 0..index_lock.settings.json_parsing_threads {
-                let schema_clone = schema.clone();
-                let doc_sender = doc_sender.clone();
-                let line_recv_clone = line_recv.clone();
-                thread::spawn(move || {
-                    for line in line_recv_clone {
-                        if !line.is_empty() {
-                            if let Ok(text) = from_utf8(&line) {
-                                if let Ok(doc) = schema_clone.parse_document(text) {
-                                    doc_sender.send(doc).unwrap()
+    /// Index the newline separated documents in `body`
+    pub fn index_bulk(&self, body: Vec<u8>, index: &str) -> Result<(), Error> {
+        let index_lock = self.catalog.read()?;
+        let shards = index_lock.shards(index)?;
+        let sharding = index_lock.sharding(index).cloned();
+        let schema = shards[0].get_index().schema();
+        let mappings = shards[0].mappings().clone();
+        let logged = shards[0].wal().is_some();
+        let (line_sender, line_recv) = index_lock.settings.get_channel::<Vec<u8>>();
+
+        // Each shard gets its own writer, fed by every parser
+        let mut doc_senders = Vec::with_capacity(shards.len());
+        for shard in &shards {
+            let (doc_sender, doc_recv) = unbounded::<(Option<Value>, Vec<Document>)>();
+            let writer = shard.get_writer()?;
+            let wal = shard.wal().cloned();
+            // The writer waits on the parsers, so it gets a thread of its own rather than possibly taking the only pool thread
+            thread::spawn(move || BulkHandler::index_documents(&writer, wal, doc_recv));
+            doc_senders.push(doc_sender);
+        }
+
+        for _ in 0..index_lock.settings.json_parsing_threads {
+            let schema_clone = schema.clone();
+            let mappings = mappings.clone();
+            let doc_senders = doc_senders.clone();
+            let sharding = sharding.clone();
+            let line_recv_clone = line_recv.clone();
+            self.executor.spawn(move || {
+                for line in line_recv_clone {
+                    if !line.is_empty() {
+                        if let Ok(text) = from_utf8(&line) {
+                            if let Ok(docs) = mapping::parse_documents(&schema_clone, &mappings, text) {
+                                if let Some(shard) = BulkHandler::shard_of(sharding.as_ref(), text) {
+                                    let document = if logged { serde_json::from_str(text).ok() } else { None };
+                                    doc_senders[shard].send((document, docs)).unwrap()
                                 }
                             }
                         }
                     }
-                });
-            }
+                }
+            });
+        }
 
-            let writer = index_handle.get_writer();
-            thread::spawn(move || BulkHandler::index_documents(&writer, doc_recv));
-
-            let line_sender_clone = line_sender.clone();
-            let response = body
-                .into_iter()
-                .fold(Vec::new(), move |mut buf, line| {
-                    buf.push(line);
-                    let mut split = buf.split(|b| *b == b'\n').peekable();
-                    while let Some(l) = split.next() {
-                        if split.peek().is_none() {
-                            return l.to_vec();
-                        }
-                        line_sender_clone.send(l.to_vec()).unwrap()
-                    }
-                    buf.clone()
-                });
-            if !response.is_empty() {
-                line_sender.send(response.to_vec()).unwrap();
+        let line_sender_clone = line_sender.clone();
+        let response = body.into_iter().fold(Vec::new(), move |mut buf, line| {
+            buf.push(line);
+            let mut split = buf.split(|b| *b == b'\n').peekable();
+            while let Some(l) = split.next() {
+                if split.peek().is_none() {
+                    return l.to_vec();
+                }
+                line_sender_clone.send(l.to_vec()).unwrap()
             }
-            Ok(CreatedResponse)
+            buf.clone()
+        });
+        if !response.is_empty() {
+            line_sender.send(response.to_vec()).unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl_web! {
+    impl BulkHandler {
+        #[post("/:index/_bulk")]
+        #[content_type("application/json")]
+        pub fn handle(
+            &self,
+            body: Vec<u8>,
+            index: String,
+            query_string: Option<WriteOptions>,
+        ) -> impl Future<Item = CreatedResponse, Error = Error> + Send {
+            // Bulk inserts are committed in the background, so a replica counts towards the consistency once it has
+            // accepted the documents rather than once they're committed
+            let consistency = WriteOptions::consistency(query_string);
+            let replicated = if self.replicator.is_enabled() { Some(body.clone()) } else { None };
+            let acknowledged = self.index_bulk(body, &index).map(|_| match replicated {
+                Some(documents) => self.replicator.bulk_insert(&index, documents, consistency),
+                None => Box::new(future::ok(())) as Acknowledged,
+            });
+            future::result(acknowledged).and_then(|acknowledged| acknowledged).map(|_| CreatedResponse)
         }
     }
 }
@@ -111,7 +184,7 @@ mod tests {
         {"test_text": "asdf5678", "test_i64": 456, "test_u64": 678, "test_unindex": "asdf"}
         {"test_text": "asdf9012", "test_i64": -12, "test_u64": 901, "test_unindex": "asdf"}"#;
 
-        let index_docs = handler.handle(body.as_bytes().to_vec(), "test_index".into());
+        let index_docs = handler.handle(body.as_bytes().to_vec(), "test_index".into(), None).wait();
         assert_eq!(index_docs.is_ok(), true);
         sleep(Duration::from_secs(1));
 
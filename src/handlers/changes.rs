@@ -0,0 +1,140 @@
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use serde::{Deserialize, Serialize};
+use tower_web::*;
+
+use crate::executor::blocking;
+use crate::index::IndexCatalog;
+use crate::wal::Entry;
+use crate::Error;
+
+/// How many changes are answered with when the request doesn't say
+const DEFAULT_LIMIT: usize = 1000;
+/// The most changes a single request is answered with
+const MAX_LIMIT: usize = 10_000;
+
+/// Options for `GET /:index/_changes`, given in the query string
+#[derive(Extract, Deserialize, Default)]
+pub struct ChangesOptions {
+    /// The sequence number of the last change already seen, from the start of the log if not given
+    pub since: Option<u64>,
+    /// The most changes to answer with
+    pub limit: Option<usize>,
+}
+
+/// The writes made to an index after a sequence number, oldest first
+#[derive(Response, Serialize, Debug)]
+pub struct Changes {
+    pub changes: Vec<Entry>,
+    /// The sequence number to ask for the changes after next, which is where the request started if there were none
+    pub last_seq: u64,
+}
+
+/// Serves the writes each index's write-ahead log holds, so other systems can follow what's added to and deleted from
+/// an index without searching it again and again
+#[derive(Clone)]
+pub struct ChangesHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+}
+
+impl ChangesHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        ChangesHandler { catalog }
+    }
+}
+
+impl_web! {
+    impl ChangesHandler {
+        #[get("/:index/_changes")]
+        #[content_type("application/json")]
+        fn changes(&self, index: String, query_string: Option<ChangesOptions>) -> impl Future<Item = Changes, Error = Error> + Send {
+            let options = query_string.unwrap_or_default();
+            let limit = options.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+            let wal = self.catalog.read().map_err(Error::from).and_then(|cat| Ok(Arc::clone(cat.wal(&index)?)));
+            future::result(wal).and_then(move |wal| {
+                blocking(move || {
+                    let since = match options.since {
+                        Some(since) => since,
+                        None => wal.oldest()?,
+                    };
+                    let changes = wal.since(since, limit)?;
+                    let last_seq = changes.last().map_or(since, |entry| entry.seq);
+                    Ok(Changes { changes, last_seq })
+                })
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::IndexHandle;
+    use crate::handlers::index::{AddDocument, DeleteDoc};
+    use crate::settings::Settings;
+    use crate::storage::StorageSettings;
+    use crate::wal::Operation;
+    use std::collections::HashMap;
+    use std::fs;
+    use tantivy::schema::{SchemaBuilder, STORED, STRING};
+
+    #[test]
+    fn test_changes() {
+        let path = std::env::temp_dir().join(format!("toshi-changes-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        let mut settings = Settings::default();
+        settings.wal_retention_hours = 1;
+        let mut catalog = IndexCatalog::new(vec![path.clone()], settings).unwrap();
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("id", STORED | STRING);
+        catalog
+            .create_index("docs", builder.build(), None, StorageSettings::default())
+            .unwrap();
+        for id in &["a", "b"] {
+            let add = AddDocument {
+                options: None,
+                document: serde_json::json!({ "id": id }),
+            };
+            catalog.get_index("docs").unwrap().add_document(add).unwrap();
+        }
+        let mut terms = HashMap::new();
+        terms.insert("id".to_string(), "a".to_string());
+        catalog
+            .delete_documents(
+                "docs",
+                &DeleteDoc {
+                    options: None,
+                    terms: terms.clone(),
+                },
+            )
+            .unwrap();
+
+        let handler = ChangesHandler::new(Arc::new(RwLock::new(catalog)));
+        let changes = |since: Option<u64>, limit: Option<usize>| {
+            handler
+                .changes("docs".into(), Some(ChangesOptions { since, limit }))
+                .wait()
+                .unwrap()
+        };
+        let all = changes(None, None);
+        assert_eq!(all.changes.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(all.last_seq, 3);
+        assert_eq!(
+            all.changes[0].op,
+            Operation::Add {
+                document: serde_json::json!({ "id": "a" })
+            }
+        );
+        assert_eq!(all.changes[2].op, Operation::Delete { terms });
+
+        let page = changes(Some(1), Some(1));
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(page.last_seq, 2);
+        let caught_up = changes(Some(3), None);
+        assert!(caught_up.changes.is_empty());
+        assert_eq!(caught_up.last_seq, 3);
+        assert!(handler.changes("missing".into(), None).wait().is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,59 @@
+use crate::index::IndexCatalog;
+use crate::lifecycle::Lifecycle;
+use crate::Error;
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::Future;
+use tower_web::*;
+
+#[derive(Clone)]
+pub struct DrainHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    lifecycle: Arc<Lifecycle>,
+    timeout: Duration,
+}
+
+impl DrainHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, lifecycle: Arc<Lifecycle>, timeout: Duration) -> Self {
+        DrainHandler {
+            catalog,
+            lifecycle,
+            timeout,
+        }
+    }
+}
+
+impl_web! {
+    impl DrainHandler {
+        #[post("/_drain")]
+        #[content_type("application/json")]
+        fn drain(&self) -> impl Future<Item = String, Error = Error> + Send {
+            Lifecycle::drain(&self.lifecycle, Arc::clone(&self.catalog), self.timeout)
+                .map_err(|_| Error::IOError("Failed to drain node".into()))
+                .and_then(|summary| serde_json::to_string(&summary).map_err(Error::from))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::index::tests::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_drain() {
+        let mut rt = Runtime::new().unwrap();
+        let cat = create_test_catalog("test_index");
+        let lifecycle = Arc::new(Lifecycle::new());
+        let handler = DrainHandler::new(Arc::clone(&cat), Arc::clone(&lifecycle), Duration::from_secs(1));
+
+        let body = rt.block_on(handler.drain()).unwrap();
+        assert_eq!(body, r#"{"in_flight":0,"committed":["test_index"],"failed":[]}"#);
+        assert!(lifecycle.is_drained());
+        rt.shutdown_now();
+    }
+}
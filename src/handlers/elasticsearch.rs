@@ -0,0 +1,754 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use futures::{future, Future};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use tower_web::*;
+use uuid::Uuid;
+
+use crate::cluster::replication::{Acknowledged, Consistency};
+use crate::cluster::routing::Preference;
+use crate::handlers::index::{AddDocument, DeleteDoc, IndexOptions};
+use crate::handlers::{IndexHandler, SearchHandler};
+use crate::index::IndexCatalog;
+use crate::query::{ExactTerm, Query, Request, Sort, SortOrder};
+use crate::reindex::map_document;
+use crate::results::{ScoredDoc, SearchResults};
+use crate::Error;
+
+/// The Elasticsearch version reported to clients, several of which check it before sending anything else
+pub const COMPATIBLE_VERSION: &str = "7.10.2";
+
+/// How many results a search returns when it doesn't say
+const DEFAULT_SIZE: usize = 10;
+
+/// Options for `GET /:index/_search`, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct UriSearch {
+    /// A query in the query parser's syntax, every document if not given
+    pub q: Option<String>,
+    pub size: Option<usize>,
+    pub from: Option<usize>,
+}
+
+/// Options for writes, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct Refresh {
+    pub refresh: Option<String>,
+}
+
+impl Refresh {
+    /// Writes asking for a refresh are committed, so they can be searched as soon as they're answered
+    fn options(refresh: Option<Refresh>) -> Option<IndexOptions> {
+        match refresh.and_then(|refresh| refresh.refresh) {
+            Some(ref refresh) if refresh != "false" => Some(IndexOptions { commit: true }),
+            _ => None,
+        }
+    }
+}
+
+/// Answers the most common Elasticsearch endpoints from Toshi's indexes, so clients and shippers that only speak
+/// Elasticsearch can be pointed at Toshi. Documents' `_id`s are kept in `id_field`, and only indexes with that field
+/// can have documents looked up, replaced or deleted by id.
+#[derive(Clone)]
+pub struct ElasticsearchHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+    index: IndexHandler,
+    id_field: String,
+}
+
+impl ElasticsearchHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler, index: IndexHandler, id_field: String) -> Self {
+        ElasticsearchHandler {
+            catalog,
+            search,
+            index,
+            id_field,
+        }
+    }
+
+    /// The names of the fields of `index`
+    fn fields(&self, index: &str) -> Result<HashSet<String>, Error> {
+        let catalog = self.catalog.read()?;
+        let shards = catalog.shards(index)?;
+        let shard = shards.first().ok_or_else(|| Error::UnknownIndex(index.to_string()))?;
+        let schema = shard.get_index().schema();
+        Ok(schema.fields().iter().map(|field| field.name().to_string()).collect())
+    }
+
+    fn require_ids(&self, index: &str) -> Result<(), Error> {
+        if self.fields(index)?.contains(&self.id_field) {
+            Ok(())
+        } else {
+            Err(Error::QueryError(format!(
+                "Index {} has no {} field to keep document ids in",
+                index, self.id_field
+            )))
+        }
+    }
+
+    fn id_term(&self, id: &str) -> HashMap<String, String> {
+        let mut term = HashMap::new();
+        term.insert(self.id_field.clone(), id.to_string());
+        term
+    }
+
+    /// Index `source` as the document `id`, replacing any document that already has it, or under a new id when it's
+    /// not given. Fields `index` doesn't have are left out.
+    fn put(&self, index: &str, id: Option<String>, source: &Value, options: Option<IndexOptions>) -> Result<(String, Acknowledged), Error> {
+        let fields = self.fields(index)?;
+        let source = source
+            .as_object()
+            .ok_or_else(|| Error::QueryError("A document must be a JSON object".into()))?;
+        let keeps_ids = fields.contains(&self.id_field);
+        let (id, replaced) = match id {
+            Some(id) => {
+                self.require_ids(index)?;
+                let delete = DeleteDoc {
+                    options: None,
+                    terms: self.id_term(&id),
+                };
+                let (_, replaced) = self.index.delete_documents(delete, index, Consistency::default())?;
+                (id, Some(replaced))
+            }
+            None => (Uuid::new_v4().to_simple().to_string(), None),
+        };
+        let mut document = map_document(source, &HashMap::new(), &fields);
+        if keeps_ids {
+            document[&self.id_field] = Value::String(id.clone());
+        }
+        let added = self
+            .index
+            .add_document(AddDocument { options, document }, index, Consistency::default())?;
+        let acknowledged: Acknowledged = match replaced {
+            Some(replaced) => Box::new(replaced.join(added).map(|_| ())),
+            None => added,
+        };
+        Ok((id, acknowledged))
+    }
+
+    fn remove(&self, index: &str, id: &str, options: Option<IndexOptions>) -> Result<Acknowledged, Error> {
+        self.require_ids(index)?;
+        let delete = DeleteDoc {
+            options,
+            terms: self.id_term(id),
+        };
+        let (_, acknowledged) = self.index.delete_documents(delete, index, Consistency::default())?;
+        Ok(acknowledged)
+    }
+
+    fn write(
+        &self,
+        index: String,
+        id: Option<String>,
+        body: &[u8],
+        refresh: Option<Refresh>,
+    ) -> Box<Future<Item = String, Error = Error> + Send> {
+        let written = serde_json::from_slice(body)
+            .map_err(Error::from)
+            .and_then(|source| self.put(&index, id, &source, Refresh::options(refresh)));
+        match written {
+            Ok((id, acknowledged)) => Box::new(acknowledged.map(move |_| written_response(&index, &id, "created"))),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Carry out every action of a bulk request, answering with the outcome of each
+    fn bulk(&self, body: &[u8], default_index: Option<&str>, refresh: Option<Refresh>) -> Box<Future<Item = String, Error = Error> + Send> {
+        let started = Instant::now();
+        let options = Refresh::options(refresh);
+        let mut lines = body.split(|b| *b == b'\n').filter(|line| !line.iter().all(u8::is_ascii_whitespace));
+        let (mut items, mut acknowledgements) = (Vec::new(), Vec::new());
+        while let Some(line) = lines.next() {
+            let action: Value = match serde_json::from_slice(line) {
+                Ok(action) => action,
+                Err(e) => return Box::new(future::err(e.into())),
+            };
+            let (kind, meta) = match single_entry(&action) {
+                Ok(entry) => entry,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let index = meta.get("_index").and_then(Value::as_str).or(default_index);
+            let id = meta.get("_id").and_then(Value::as_str).map(String::from);
+            let source = match kind {
+                "index" | "create" | "update" => lines.next(),
+                _ => None,
+            };
+
+            let outcome = match (kind, index) {
+                (_, None) => Err(Error::QueryError("No _index was given for the action".into())),
+                ("index", Some(index)) | ("create", Some(index)) => source
+                    .ok_or_else(|| Error::QueryError("The action has no document".into()))
+                    .and_then(|source| Ok(serde_json::from_slice::<Value>(source)?))
+                    .and_then(|source| self.put(index, id.clone(), &source, options.clone()))
+                    .map(|(id, acknowledged)| (id, acknowledged, "created", 201)),
+                ("delete", Some(index)) => match id {
+                    Some(ref id) => self
+                        .remove(index, id, options.clone())
+                        .map(|acknowledged| (id.clone(), acknowledged, "deleted", 200)),
+                    None => Err(Error::QueryError("A delete needs an _id".into())),
+                },
+                (other, _) => Err(Error::QueryError(format!("The {} action isn't supported", other))),
+            };
+            let index = index.unwrap_or_default();
+            let item = match outcome {
+                Ok((id, acknowledged, result, status)) => {
+                    acknowledgements.push(acknowledged);
+                    json!({ "_index": index, "_type": "_doc", "_id": id, "_version": 1, "result": result, "status": status })
+                }
+                Err(e) => {
+                    let status = if let Error::UnknownIndex(_) = e { 404 } else { 400 };
+                    json!({ "_index": index, "_type": "_doc", "_id": id, "status": status, "error": error_body(&e) })
+                }
+            };
+            items.push(json!({ kind: item }));
+        }
+
+        let errors = items.iter().any(|item| {
+            item.as_object()
+                .map_or(false, |item| item.values().any(|i| i.get("error").is_some()))
+        });
+        Box::new(
+            future::join_all(acknowledgements)
+                .map(move |_| json!({ "took": millis(started), "errors": errors, "items": items }).to_string()),
+        )
+    }
+
+    fn delete(&self, index: String, id: String, refresh: Option<Refresh>) -> Box<Future<Item = String, Error = Error> + Send> {
+        match self.remove(&index, &id, Refresh::options(refresh)) {
+            Ok(acknowledged) => Box::new(acknowledged.map(move |_| written_response(&index, &id, "deleted"))),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn search_body(&self, body: &[u8], index: String) -> Box<Future<Item = String, Error = Error> + Send> {
+        let search = if body.iter().all(u8::is_ascii_whitespace) {
+            Ok(Value::Object(Map::new()))
+        } else {
+            serde_json::from_slice(body).map_err(Error::from)
+        };
+        match search.and_then(|search| translate_search(&search)) {
+            Ok((request, from)) => self.run_search(index, request, from),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    fn run_search(&self, index: String, request: Request, from: usize) -> Box<Future<Item = String, Error = Error> + Send> {
+        let (started, id_field, limit) = (Instant::now(), self.id_field.clone(), request.limit);
+        let search = self
+            .search
+            .search_refs(request, index.clone(), Preference::parse(None))
+            .map(move |results| search_response(&index, &id_field, results, from, limit, started));
+        Box::new(search)
+    }
+
+    fn get_document(&self, index: String, id: String) -> Box<Future<Item = String, Error = Error> + Send> {
+        if let Err(e) = self.require_ids(&index) {
+            return Box::new(future::err(e));
+        }
+        let query = Query::Exact(ExactTerm { term: self.id_term(&id) });
+        let id_field = self.id_field.clone();
+        let found = self
+            .search
+            .search_refs(Request::new(Some(query), None, 1), index.clone(), Preference::parse(None))
+            .map(move |results| match results.docs.into_iter().next() {
+                Some(doc) => {
+                    let hit = hit(&index, &id_field, doc);
+                    json!({ "_index": index, "_type": "_doc", "_id": id, "_version": 1, "found": true, "_source": hit["_source"] })
+                }
+                None => json!({ "_index": index, "_type": "_doc", "_id": id, "found": false }),
+            })
+            .map(|found| found.to_string());
+        Box::new(found)
+    }
+}
+
+impl_web! {
+    impl ElasticsearchHandler {
+        #[get("/")]
+        #[content_type("application/json")]
+        fn info(&self) -> Result<String, ()> {
+            let info = json!({
+                "name": "toshi",
+                "cluster_name": self.catalog.read().map(|cat| cat.settings.cluster_name.clone()).unwrap_or_default(),
+                "version": { "number": COMPATIBLE_VERSION, "build_flavor": "oss", "lucene_version": "8.7.0" },
+                "tagline": "You Know, for Search"
+            });
+            Ok(info.to_string())
+        }
+
+        #[post("/_bulk")]
+        #[content_type("application/json")]
+        fn bulk_any(&self, body: Vec<u8>, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.bulk(&body, None, query_string)
+        }
+
+        #[post("/:index/_bulk")]
+        #[content_type("application/json")]
+        fn bulk_index(&self, body: Vec<u8>, index: String, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.bulk(&body, Some(&index), query_string)
+        }
+
+        #[post("/:index/_search")]
+        #[content_type("application/json")]
+        fn search(&self, body: Vec<u8>, index: String) -> impl Future<Item = String, Error = Error> + Send {
+            self.search_body(&body, index)
+        }
+
+        #[get("/:index/_search")]
+        #[content_type("application/json")]
+        fn uri_search(&self, index: String, query_string: Option<UriSearch>) -> impl Future<Item = String, Error = Error> + Send {
+            let (q, size, from) = match query_string {
+                Some(search) => (search.q, search.size, search.from),
+                None => (None, None, None),
+            };
+            let query = match q {
+                Some(raw) => Query::Raw { raw, fields: Vec::new() },
+                None => Query::All,
+            };
+            let from = from.unwrap_or(0);
+            let request = Request::new(Some(query), None, from + size.unwrap_or(DEFAULT_SIZE));
+            self.run_search(index, request, from)
+        }
+
+        #[get("/:index/_doc/:id")]
+        #[content_type("application/json")]
+        fn get(&self, index: String, id: String) -> impl Future<Item = String, Error = Error> + Send {
+            self.get_document(index, id)
+        }
+
+        #[put("/:index/_doc/:id")]
+        #[content_type("application/json")]
+        fn put_doc(&self, body: Vec<u8>, index: String, id: String, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.write(index, Some(id), &body, query_string)
+        }
+
+        #[post("/:index/_doc/:id")]
+        #[content_type("application/json")]
+        fn post_doc(&self, body: Vec<u8>, index: String, id: String, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.write(index, Some(id), &body, query_string)
+        }
+
+        #[post("/:index/_doc")]
+        #[content_type("application/json")]
+        fn create_doc(&self, body: Vec<u8>, index: String, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.write(index, None, &body, query_string)
+        }
+
+        #[delete("/:index/_doc/:id")]
+        #[content_type("application/json")]
+        fn delete_doc(&self, index: String, id: String, query_string: Option<Refresh>) -> impl Future<Item = String, Error = Error> + Send {
+            self.delete(index, id, query_string)
+        }
+    }
+}
+
+/// The body Elasticsearch answers failed requests with
+pub fn error_body(error: &Error) -> Value {
+    let kind = match error {
+        Error::UnknownIndex(_) => "index_not_found_exception",
+        Error::UnknownIndexField(_) | Error::QueryError(_) => "parsing_exception",
+        _ => "toshi_exception",
+    };
+    json!({ "type": kind, "reason": error.to_string() })
+}
+
+fn millis(started: Instant) -> u64 {
+    let elapsed = started.elapsed();
+    elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis())
+}
+
+fn written_response(index: &str, id: &str, result: &str) -> String {
+    json!({
+        "_index": index,
+        "_type": "_doc",
+        "_id": id,
+        "_version": 1,
+        "result": result,
+        "_shards": { "total": 1, "successful": 1, "failed": 0 }
+    })
+    .to_string()
+}
+
+fn search_response(index: &str, id_field: &str, results: SearchResults, from: usize, limit: usize, started: Instant) -> String {
+    let total = results.docs.len();
+    let hits: Vec<Value> = results.docs.into_iter().skip(from).map(|doc| hit(index, id_field, doc)).collect();
+    let max_score = hits
+        .iter()
+        .filter_map(|hit| hit["_score"].as_f64())
+        .fold(None, |max: Option<f64>, score| Some(max.map_or(score, |max| max.max(score))));
+    json!({
+        "took": millis(started),
+        "timed_out": false,
+        "_shards": { "total": 1, "successful": 1, "skipped": 0, "failed": 0 },
+        "hits": {
+            // Toshi doesn't count every match, only those returned
+            "total": { "value": total, "relation": if total < limit { "eq" } else { "gte" } },
+            "max_score": max_score,
+            "hits": hits
+        }
+    })
+    .to_string()
+}
+
+/// A search result as Elasticsearch gives it, with fields holding a single value unwrapped from their arrays
+fn hit(index: &str, id_field: &str, doc: ScoredDoc) -> Value {
+    let mut source = Map::new();
+    let mut id = Value::Null;
+    for (field, values) in doc.doc {
+        let value = match serde_json::to_value(&values) {
+            Ok(Value::Array(mut values)) if values.len() == 1 => values.pop().unwrap_or_default(),
+            Ok(values) => values,
+            Err(_) => Value::Null,
+        };
+        if field == id_field {
+            id = value;
+        } else {
+            source.insert(field, value);
+        }
+    }
+    json!({ "_index": index, "_type": "_doc", "_id": id, "_score": doc.score, "_source": source })
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::QueryError(format!("{} isn't supported", what))
+}
+
+/// The only key of `value` and what it holds
+fn single_entry(value: &Value) -> Result<(&str, &Value), Error> {
+    match value.as_object() {
+        Some(object) if object.len() == 1 => object
+            .iter()
+            .next()
+            .map(|(key, value)| (key.as_str(), value))
+            .ok_or_else(|| Error::QueryError("Expected an object with one key".into())),
+        _ => Err(Error::QueryError(format!("Expected an object with one key, not {}", value))),
+    }
+}
+
+/// The field a leaf query is on and its value, given either directly or as `key` of an object of options
+fn field_value<'a>(body: &'a Value, key: &str) -> Result<(&'a str, &'a Value), Error> {
+    let (field, value) = single_entry(body)?;
+    match value {
+        Value::Object(options) => options
+            .get(key)
+            .map(|value| (field, value))
+            .ok_or_else(|| Error::QueryError(format!("Expected {} for {}", key, field))),
+        value => Ok((field, value)),
+    }
+}
+
+/// The words of a `match` query's text, lowercased as the default tokenizer does
+fn words(value: &Value) -> Vec<String> {
+    let text = match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Translate an Elasticsearch search body into a Toshi search, along with how many of its first results to skip
+pub fn translate_search(body: &Value) -> Result<(Request, usize), Error> {
+    let count = |name: &str| match body.get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(|count| Some(count as usize))
+            .ok_or_else(|| Error::QueryError(format!("{} must be a number", name))),
+    };
+    let from = count("from")?.unwrap_or(0);
+    let size = count("size")?.unwrap_or(DEFAULT_SIZE);
+    let query = match body.get("query") {
+        Some(query) => translate_query(query)?,
+        None => Query::All,
+    };
+    let mut request = Request::new(Some(query), None, from + size);
+    request.sort = match body.get("sort") {
+        Some(sort) => translate_sort(sort)?,
+        None => None,
+    };
+    Ok((request, from))
+}
+
+/// The order of an Elasticsearch `sort`, of which only the first field is used
+fn translate_sort(sort: &Value) -> Result<Option<Sort>, Error> {
+    let first = match sort {
+        Value::Array(sorts) => match sorts.first() {
+            Some(first) => first,
+            None => return Ok(None),
+        },
+        other => other,
+    };
+    let (field, order, missing) = match first {
+        Value::String(field) => (field.as_str(), None, None),
+        Value::Object(_) => {
+            let (field, order) = single_entry(first)?;
+            (field, order.get("order").unwrap_or(order).as_str(), order.get("missing"))
+        }
+        _ => return Err(unsupported("A sort that isn't a field name or object")),
+    };
+    if field == "_score" {
+        return Ok(None);
+    }
+    let order = match order {
+        None | Some("asc") => SortOrder::Asc,
+        Some("desc") => SortOrder::Desc,
+        Some(other) => return Err(Error::QueryError(format!("Unknown sort order {}", other))),
+    };
+    Ok(Some(Sort {
+        field: field.to_string(),
+        order,
+        missing: match missing {
+            Some(missing) => Some(serde_json::from_value(missing.clone())?),
+            None => None,
+        },
+        script: None,
+    }))
+}
+
+/// Translate a query in Elasticsearch's query DSL into a Toshi query. `match_all`, `match`, `query_string` and
+/// `bool` are understood, along with `term`, `match_phrase`, `range`, `fuzzy`, `prefix`, `wildcard` and `regexp`,
+/// which are also the queries a `bool` can be made of.
+pub fn translate_query(query: &Value) -> Result<Query, Error> {
+    let (kind, body) = single_entry(query)?;
+    match kind {
+        "match_all" => Ok(Query::All),
+        "match" => {
+            let (field, text) = field_value(body, "query")?;
+            let terms: Vec<String> = words(text).into_iter().map(|word| format!("{}:{}", field, word)).collect();
+            if terms.is_empty() {
+                return Err(Error::QueryError(format!("The match on {} has no words to match", field)));
+            }
+            Ok(Query::Raw {
+                raw: terms.join(" "),
+                fields: Vec::new(),
+            })
+        }
+        "query_string" => {
+            let raw = match body.get("query").and_then(Value::as_str) {
+                Some(raw) => raw.to_string(),
+                None => return Err(Error::QueryError("query_string needs a query".into())),
+            };
+            let fields = match (body.get("fields"), body.get("default_field")) {
+                (Some(fields), _) => serde_json::from_value(fields.clone())?,
+                (None, Some(Value::String(field))) => vec![field.clone()],
+                _ => Vec::new(),
+            };
+            Ok(Query::Raw { raw, fields })
+        }
+        "bool" => translate_bool(body),
+        _ => Ok(serde_json::from_value(translate_clause(kind, body)?)?),
+    }
+}
+
+fn translate_bool(body: &Value) -> Result<Query, Error> {
+    let mut toshi = Map::new();
+    for occur in &["must", "filter", "should", "must_not"] {
+        let clauses: Vec<&Value> = match body.get(*occur) {
+            None => continue,
+            Some(Value::Array(clauses)) => clauses.iter().collect(),
+            Some(clause) => vec![clause],
+        };
+        let mut translated = Vec::new();
+        for clause in clauses {
+            let (kind, body) = single_entry(clause)?;
+            // Matching everything adds nothing to a clause that has to match
+            if kind == "match_all" && (*occur == "must" || *occur == "filter") {
+                continue;
+            }
+            translated.push(translate_clause(kind, body)?);
+        }
+        if !translated.is_empty() {
+            toshi.insert(occur.to_string(), Value::Array(translated));
+        }
+    }
+    if toshi.is_empty() {
+        return Ok(Query::All);
+    }
+    if let Some(minimum) = body.get("minimum_should_match").and_then(Value::as_u64) {
+        toshi.insert("minimum_should_match".into(), minimum.into());
+    }
+    Ok(serde_json::from_value(json!({ "bool": toshi }))?)
+}
+
+/// A single query that can be part of a `bool`, as the JSON of Toshi's equivalent
+fn translate_clause(kind: &str, body: &Value) -> Result<Value, Error> {
+    let clause = match kind {
+        "term" => match field_value(body, "value")? {
+            (field, Value::String(term)) => json!({ "term": { field: term } }),
+            // Numeric fields aren't matched by text, but by a range holding only the value
+            (field, value) if value.is_number() => json!({ "range": { field: { "gte": value, "lte": value } } }),
+            (field, _) => return Err(Error::QueryError(format!("The term on {} must be a string or number", field))),
+        },
+        "match" => {
+            let (field, text) = field_value(body, "query")?;
+            match words(text).as_slice() {
+                [word] => json!({ "term": { field: word } }),
+                _ => return Err(unsupported("Inside a bool, a match of other than a single word")),
+            }
+        }
+        "match_phrase" => {
+            let (field, text) = field_value(body, "query")?;
+            json!({ "phrase": { field: { "terms": words(text) } } })
+        }
+        "range" => json!({ "range": body }),
+        "fuzzy" => {
+            let (field, value) = field_value(body, "value")?;
+            let value = value
+                .as_str()
+                .ok_or_else(|| Error::QueryError(format!("The fuzzy query on {} must be a string", field)))?;
+            let fuzziness = body[field].get("fuzziness");
+            let distance = match fuzziness.and_then(|f| f.as_u64().or_else(|| f.as_str().and_then(|f| f.parse().ok()))) {
+                Some(distance) => distance,
+                // AUTO, which is also the default, allows more edits the longer the term is
+                None => match value.chars().count() {
+                    0..=2 => 0,
+                    3..=5 => 1,
+                    _ => 2,
+                },
+            };
+            json!({ "fuzzy": { field: { "value": value, "distance": distance } } })
+        }
+        "prefix" | "wildcard" | "regexp" => {
+            let (field, value) = field_value(body, "value")?;
+            let value = value
+                .as_str()
+                .ok_or_else(|| Error::QueryError(format!("The {} query on {} must be a string", kind, field)))?;
+            let regex = match kind {
+                "prefix" => format!("{}.*", escape_regex(value)),
+                "wildcard" => value
+                    .split('*')
+                    .map(|part| part.split('?').map(escape_regex).collect::<Vec<_>>().join("."))
+                    .collect::<Vec<_>>()
+                    .join(".*"),
+                _ => value.to_string(),
+            };
+            json!({ "regexp": { field: regex } })
+        }
+        other => return Err(unsupported(&format!("The {} query", other))),
+    };
+    Ok(clause)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::*;
+    use crate::query::{Missing, MissingPosition};
+
+    fn translate(query: Value) -> Result<Query, Error> {
+        translate_query(&query)
+    }
+
+    #[test]
+    fn test_translate_query() {
+        assert_eq!(translate(json!({ "match_all": {} })).unwrap(), Query::All);
+        assert_eq!(
+            translate(json!({ "match": { "test_text": { "query": "Document, 5" } } })).unwrap(),
+            Query::Raw {
+                raw: "test_text:document test_text:5".into(),
+                fields: Vec::new(),
+            }
+        );
+        assert_eq!(
+            translate(json!({ "query_string": { "query": "rust", "fields": ["title^3", "body"] } })).unwrap(),
+            Query::Raw {
+                raw: "rust".into(),
+                fields: vec!["title^3".into(), "body".into()],
+            }
+        );
+        let term: Query = serde_json::from_value(json!({ "term": { "user": "kimchy" } })).unwrap();
+        assert_eq!(translate(json!({ "term": { "user": { "value": "kimchy" } } })).unwrap(), term);
+        let range: Query = serde_json::from_value(json!({ "range": { "age": { "gte": 10, "lte": 10 } } })).unwrap();
+        assert_eq!(translate(json!({ "term": { "age": 10 } })).unwrap(), range);
+        let prefix: Query = serde_json::from_value(json!({ "regexp": { "host": "db\\-1.*" } })).unwrap();
+        assert_eq!(translate(json!({ "prefix": { "host": "db-1" } })).unwrap(), prefix);
+
+        let bool = translate(json!({ "bool": {
+            "must": { "match": { "user": "Kimchy" } },
+            "filter": [{ "range": { "age": { "gt": 1, "lte": 20 } } }, { "match_all": {} }],
+            "must_not": [{ "wildcard": { "user": "ki?c*" } }]
+        }}))
+        .unwrap();
+        let expected: Query = serde_json::from_value(json!({ "bool": {
+            "must": [{ "term": { "user": "kimchy" } }],
+            "filter": [{ "range": { "age": { "gt": 1, "lte": 20 } } }],
+            "must_not": [{ "regexp": { "user": "ki.c.*" } }]
+        }}))
+        .unwrap();
+        assert_eq!(bool, expected);
+
+        assert!(translate(json!({ "bool": { "must": { "match": { "user": "two words" } } } })).is_err());
+        assert!(translate(json!({ "geo_distance": {} })).is_err());
+    }
+
+    #[test]
+    fn test_translate_search() {
+        let (request, from) = translate_search(&json!({
+            "from": 5,
+            "size": 20,
+            "sort": [{ "timestamp": { "order": "desc", "missing": "_first" } }]
+        }))
+        .unwrap();
+        assert_eq!(from, 5);
+        assert_eq!(request.limit, 25);
+        assert_eq!(request.query, Some(Query::All));
+        assert_eq!(
+            request.sort,
+            Some(Sort {
+                field: "timestamp".into(),
+                order: SortOrder::Desc,
+                missing: Some(Missing::Position(MissingPosition::First)),
+                script: None,
+            })
+        );
+        assert!(translate_search(&json!({ "sort": ["_score"] })).unwrap().0.sort.is_none());
+    }
+
+    #[test]
+    fn test_search_and_bulk() {
+        let catalog = create_test_catalog("test_index");
+        let handler = ElasticsearchHandler::new(
+            Arc::clone(&catalog),
+            SearchHandler::new(Arc::clone(&catalog)),
+            IndexHandler::new(Arc::clone(&catalog)),
+            "doc_id".into(),
+        );
+        let body = br#"{ "query": { "match": { "test_text": "document" } }, "size": 2 }"#.to_vec();
+        let found: Value = serde_json::from_str(&handler.search(body, "test_index".into()).wait().unwrap()).unwrap();
+        assert_eq!(found["hits"]["hits"].as_array().unwrap().len(), 2);
+        assert_eq!(found["hits"]["total"]["relation"], "gte");
+        assert!(found["hits"]["hits"][0]["_source"]["test_text"].is_string());
+
+        let bulk = br#"
+        { "index": { "_index": "test_index" } }
+        { "test_text": "Bulk Document", "test_u64": 7, "unknown": true }
+        { "index": { "_index": "missing" } }
+        { "test_text": "Nowhere" }
+        { "delete": { "_index": "test_index", "_id": "1" } }"#
+            .to_vec();
+        let answer: Value = serde_json::from_str(&handler.bulk_any(bulk, None).wait().unwrap()).unwrap();
+        assert_eq!(answer["errors"], true);
+        assert_eq!(answer["items"][0]["index"]["status"], 201);
+        assert_eq!(answer["items"][1]["index"]["status"], 404);
+        // The test index has no doc_id field to delete documents by
+        assert_eq!(answer["items"][2]["delete"]["status"], 400);
+    }
+}
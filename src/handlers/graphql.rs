@@ -0,0 +1,147 @@
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tower_web::*;
+
+use crate::cluster::routing::Preference;
+use crate::graphql::{self, GraphqlResponse, GraphqlSchema, Selection};
+use crate::handlers::SearchHandler;
+use crate::index::IndexCatalog;
+use crate::Error;
+
+#[derive(Extract, Deserialize)]
+pub struct GraphqlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Value>>,
+    #[serde(default, rename = "operationName")]
+    operation_name: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct GraphqlHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+    enabled: bool,
+}
+
+impl GraphqlHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler, enabled: bool) -> Self {
+        GraphqlHandler { catalog, search, enabled }
+    }
+
+    /// The schema of every local index, generated each time so it follows indexes being created and removed
+    fn schema(&self) -> Result<GraphqlSchema, Error> {
+        if !self.enabled {
+            return Err(Error::IOError(
+                "The GraphQL endpoint isn't enabled in the [graphql] settings".into(),
+            ));
+        }
+        let catalog = self.catalog.read()?;
+        let mut indexes = Vec::new();
+        for name in catalog.index_names() {
+            if let Some(shard) = catalog.shards(&name)?.first() {
+                let schema = shard.get_index().schema();
+                indexes.push((name, schema));
+            }
+        }
+        Ok(GraphqlSchema::new(&indexes))
+    }
+
+    fn execute(&self, request: GraphqlRequest) -> Box<Future<Item = GraphqlResponse, Error = Error> + Send> {
+        let schema = match self.schema() {
+            Ok(schema) => Arc::new(schema),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let variables = request.variables.unwrap_or_default();
+        let fields = match graphql::parse(&request.query, &variables, request.operation_name.as_ref().map(String::as_str)) {
+            Ok(fields) => fields,
+            Err(e) => return Box::new(future::ok(GraphqlResponse::failed(&e))),
+        };
+        // Root fields are resolved concurrently, and one failing leaves the others' data in the response
+        let resolving: Vec<_> = fields
+            .into_iter()
+            .map(|field| {
+                let key = field.key().to_string();
+                self.resolve(Arc::clone(&schema), field)
+                    .then(move |value| Ok::<_, Error>((key, value)))
+            })
+            .collect();
+        Box::new(future::join_all(resolving).map(GraphqlResponse::new))
+    }
+
+    fn resolve(&self, schema: Arc<GraphqlSchema>, field: Selection) -> Box<Future<Item = Value, Error = Error> + Send> {
+        if field.name == "__typename" {
+            return Box::new(future::ok(Value::String("Query".into())));
+        }
+        let (index, request) = match schema.request(&field) {
+            Ok(search) => search,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let resolved = self
+            .search
+            .search_refs(request, index, Preference::parse(None))
+            .and_then(move |results| schema.resolve(&field, results));
+        Box::new(resolved)
+    }
+}
+
+impl_web! {
+    impl GraphqlHandler {
+        #[post("/graphql")]
+        #[content_type("application/json")]
+        fn graphql(&self, body: GraphqlRequest) -> impl Future<Item = GraphqlResponse, Error = Error> + Send {
+            self.execute(body)
+        }
+
+        #[get("/graphql/schema")]
+        #[content_type("text/plain")]
+        fn graphql_schema(&self) -> Result<String, Error> {
+            self.schema().map(|schema| schema.sdl())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    fn request(query: &str) -> GraphqlRequest {
+        GraphqlRequest {
+            query: query.into(),
+            variables: None,
+            operation_name: None,
+        }
+    }
+
+    #[test]
+    fn test_graphql() {
+        let catalog = create_test_catalog("test_index");
+        let handler = GraphqlHandler::new(Arc::clone(&catalog), SearchHandler::new(Arc::clone(&catalog)), true);
+
+        let response = handler
+            .execute(request(
+                r#"{ __typename test_index(query: "test_text:document") { hits aggregations { sum(field: test_u64) } } missing { hits } }"#,
+            ))
+            .wait()
+            .unwrap();
+        let data = response.data.unwrap();
+        assert_eq!(data["__typename"], json!("Query"));
+        assert_eq!(data["test_index"], json!({ "hits": 3, "aggregations": { "sum": 37.0 } }));
+        assert_eq!(data["missing"], Value::Null);
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].path, vec!["missing"]);
+
+        let response = handler.execute(request("{ test_index { hits }")).wait().unwrap();
+        assert!(response.data.is_none());
+        assert_eq!(response.errors.len(), 1);
+
+        assert!(handler.graphql_schema().unwrap().contains("test_index(query: JSON"));
+        let disabled = GraphqlHandler::new(Arc::clone(&catalog), SearchHandler::new(catalog), false);
+        assert!(disabled.graphql_schema().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use crate::lifecycle::Lifecycle;
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tower_web::*;
+
+#[derive(Clone)]
+pub struct HealthHandler {
+    lifecycle: Arc<Lifecycle>,
+}
+
+#[derive(Response, Debug)]
+pub struct HealthResponse {
+    #[web(status)]
+    code: u16,
+    status: &'static str,
+}
+
+impl HealthResponse {
+    fn new(healthy: bool) -> Self {
+        if healthy {
+            HealthResponse { code: 200, status: "ok" }
+        } else {
+            HealthResponse {
+                code: 503,
+                status: "unavailable",
+            }
+        }
+    }
+}
+
+impl HealthHandler {
+    pub fn new(lifecycle: Arc<Lifecycle>) -> Self {
+        HealthHandler { lifecycle }
+    }
+}
+
+impl_web! {
+    impl HealthHandler {
+        #[get("/_health/live")]
+        #[content_type("application/json")]
+        fn live(&self) -> Result<HealthResponse, ()> {
+            Ok(HealthResponse::new(true))
+        }
+
+        #[get("/_health/ready")]
+        #[content_type("application/json")]
+        fn ready(&self) -> Result<HealthResponse, ()> {
+            Ok(HealthResponse::new(self.lifecycle.is_ready()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_live() {
+        let handler = HealthHandler::new(Arc::new(Lifecycle::new()));
+        assert_eq!(handler.live().unwrap().code, 200);
+    }
+
+    #[test]
+    fn test_ready() {
+        let lifecycle = Arc::new(Lifecycle::clustered());
+        let handler = HealthHandler::new(Arc::clone(&lifecycle));
+        assert_eq!(handler.ready().unwrap().code, 503);
+
+        lifecycle.set_catalog_loaded();
+        assert_eq!(handler.ready().unwrap().code, 503);
+
+        lifecycle.set_registered();
+        assert_eq!(handler.ready().unwrap().code, 200);
+    }
+}
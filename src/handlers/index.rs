@@ -1,20 +1,80 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
-use serde::{Deserialize, Serialize};
+use futures::{future, Future};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use tantivy::schema::*;
-use tantivy::Index;
 use tower_web::*;
 
+use crate::analysis;
+use crate::cluster::replication::{Acknowledged, Consistency, Replicator};
 use crate::handle::IndexHandle;
 use crate::handlers::CreatedResponse;
 use crate::index::IndexCatalog;
+use crate::mapping::{self, Mappings};
+use crate::shard::Sharding;
+use crate::storage::{DirectoryType, StorageSettings};
 use crate::Error;
 
-#[derive(Extract, Deserialize)]
-pub struct SchemaBody(Schema);
+/// A schema in tantivy's JSON form, whose text fields can also name their `analyzer` and which can have fields of the
+/// types in `mapping`, such as `date`
+#[derive(Extract)]
+pub struct SchemaBody(Schema, Mappings);
+
+impl<'de> Deserialize<'de> for SchemaBody {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut schema = serde_json::Value::deserialize(deserializer)?;
+        analysis::apply_analyzers(&mut schema).map_err(de::Error::custom)?;
+        let mappings = mapping::apply_mappings(&mut schema).map_err(de::Error::custom)?;
+        let schema = serde_json::from_value(schema).map_err(de::Error::custom)?;
+        Ok(SchemaBody(schema, mappings))
+    }
+}
 
+/// Options for `PUT /:index/_create`, given in the query string
 #[derive(Extract, Deserialize)]
+pub struct CreateOptions {
+    /// A directory to create the index in instead of one of the data paths
+    pub data_path: Option<String>,
+    /// Keep the index in memory, or memory map it from disk which is the default
+    pub directory: Option<DirectoryType>,
+    /// Read the index's files into memory whenever it's opened
+    pub preload: Option<bool>,
+    /// A fast field documents will be added in order of, letting searches sorted by it stop early
+    pub sort_by: Option<String>,
+    /// How many shards to split the index into
+    pub shards: Option<usize>,
+    /// The field whose value picks each document's shard
+    pub routing_field: Option<String>,
+    /// Drop the values documents give fields that aren't of the field's type, unless the field says otherwise
+    pub ignore_malformed: Option<bool>,
+    /// The fields raw queries search when they don't name any, separated by commas, each `name` or `name^boost`
+    pub default_fields: Option<String>,
+}
+
+/// Options for writes, given in the query string
+#[derive(Extract, Deserialize, Default)]
+pub struct WriteOptions {
+    /// How many copies of the index must accept the write before it's acknowledged, `one` unless given
+    pub consistency: Option<Consistency>,
+}
+
+impl WriteOptions {
+    pub fn consistency(options: Option<WriteOptions>) -> Consistency {
+        options.and_then(|options| options.consistency).unwrap_or_default()
+    }
+
+    /// Writes that must reach more than one copy are committed on each, so they're durable once acknowledged
+    pub fn durable(options: Option<IndexOptions>, consistency: Consistency) -> Option<IndexOptions> {
+        match consistency {
+            Consistency::One => options,
+            _ => Some(IndexOptions { commit: true }),
+        }
+    }
+}
+
+#[derive(Extract, Serialize, Deserialize, Clone)]
 pub struct DeleteDoc {
     pub options: Option<IndexOptions>,
     pub terms: HashMap<String, String>,
@@ -23,6 +83,7 @@ pub struct DeleteDoc {
 #[derive(Clone)]
 pub struct IndexHandler {
     catalog: Arc<RwLock<IndexCatalog>>,
+    replicator: Replicator,
 }
 
 #[derive(Response, Deserialize)]
@@ -30,13 +91,13 @@ pub struct DocsAffected {
     pub docs_affected: u32,
 }
 
-#[derive(Extract, Deserialize)]
+#[derive(Extract, Serialize, Deserialize, Clone)]
 pub struct IndexOptions {
     #[serde(default)]
     pub commit: bool,
 }
 
-#[derive(Extract, Deserialize)]
+#[derive(Extract, Serialize, Deserialize, Clone)]
 pub struct AddDocument {
     pub options: Option<IndexOptions>,
     pub document: serde_json::Value,
@@ -44,14 +105,99 @@ pub struct AddDocument {
 
 impl IndexHandler {
     pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
-        IndexHandler { catalog }
+        IndexHandler {
+            catalog,
+            replicator: Replicator::default(),
+        }
+    }
+
+    /// Forward every write that succeeds here on to `replicator`'s replicas
+    pub fn with_replicator(mut self, replicator: Replicator) -> Self {
+        self.replicator = replicator;
+        self
+    }
+
+    pub fn delete_documents(&self, body: DeleteDoc, index: &str, consistency: Consistency) -> Result<(DocsAffected, Acknowledged), Error> {
+        let index_lock = self.catalog.read()?;
+        if !index_lock.exists(index) {
+            return Err(Error::IOError("Failed to obtain index lock".into()));
+        }
+        let docs_affected = index_lock.delete_documents(index, &body)?;
+        let acknowledged = self.replicator.delete_documents(index, &body, consistency);
+        Ok((DocsAffected { docs_affected }, acknowledged))
     }
 
-    fn add_index(catalog: &Arc<RwLock<IndexCatalog>>, name: String, index: Index) -> Result<(), Error> {
-        match catalog.write() {
-            Ok(ref mut cat) => cat.add_index(name, index),
-            Err(e) => Err(Error::IOError(e.to_string())),
+    pub fn add_document(&self, body: AddDocument, index: &str, consistency: Consistency) -> Result<Acknowledged, Error> {
+        if let Ok(ref index_lock) = self.catalog.write() {
+            if index_lock.exists(index) {
+                let replicated = if self.replicator.is_enabled() { Some(body.clone()) } else { None };
+                index_lock.route(index, &body.document)?.add_document(body)?;
+                if let Some(doc) = replicated {
+                    return Ok(self.replicator.place_document(index, &doc, consistency));
+                }
+            }
         }
+        Ok(Box::new(future::ok(())))
+    }
+
+    /// Create `index` with `schema` and place it on the replicas
+    pub fn create_index(&self, index: &str, schema: Schema, options: Option<CreateOptions>) -> Result<(), Error> {
+        self.create_mapped_index(index, schema, Mappings::new(), options)
+    }
+
+    /// Create `index` with `schema`, whose fields of types Tantivy has none of are described by `mappings`
+    pub fn create_mapped_index(
+        &self,
+        index: &str,
+        schema: Schema,
+        mut mappings: Mappings,
+        options: Option<CreateOptions>,
+    ) -> Result<(), Error> {
+        let (location, storage, sharding) = match options {
+            Some(options) => {
+                if options.ignore_malformed == Some(true) {
+                    mapping::ignore_malformed(&schema, &mut mappings);
+                }
+                let storage = StorageSettings {
+                    directory: options.directory.unwrap_or_default(),
+                    preload: options.preload.unwrap_or(false),
+                    sort_by: options.sort_by,
+                    mappings,
+                    default_fields: options
+                        .default_fields
+                        .map(|fields| {
+                            fields
+                                .split(',')
+                                .map(str::trim)
+                                .filter(|f| !f.is_empty())
+                                .map(String::from)
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                };
+                let sharding = match options.shards {
+                    Some(shards) if shards != 1 => Some(Sharding::new(shards, options.routing_field)?),
+                    _ => None,
+                };
+                (options.data_path.map(PathBuf::from), storage, sharding)
+            }
+            None => (
+                None,
+                StorageSettings {
+                    mappings,
+                    ..StorageSettings::default()
+                },
+                None,
+            ),
+        };
+        let mut catalog = self.catalog.write()?;
+        analysis::check(&schema, &catalog.settings)?;
+        match sharding {
+            Some(sharding) => catalog.create_sharded_index(index, schema.clone(), sharding, location, storage),
+            None => catalog.create_index(index, schema.clone(), location, storage),
+        }?;
+        self.replicator.place_index(index, &schema);
+        Ok(())
     }
 }
 
@@ -59,40 +205,60 @@ impl_web! {
     impl IndexHandler {
         #[delete("/:index")]
         #[content_type("application/json")]
-        pub fn delete(&self, body: DeleteDoc, index: String) -> Result<DocsAffected, Error> {
-            if self.catalog.read().unwrap().exists(&index) {
-                let index_lock = self.catalog.read().unwrap();
-                let index_handle = index_lock.get_index(&index)?;
-                index_handle.delete_term(body)
-            } else {
-                Err(Error::IOError("Failed to obtain index lock".into()))
-            }
+        pub fn delete(
+            &self,
+            mut body: DeleteDoc,
+            index: String,
+            query_string: Option<WriteOptions>,
+        ) -> impl Future<Item = DocsAffected, Error = Error> + Send {
+            let consistency = WriteOptions::consistency(query_string);
+            body.options = WriteOptions::durable(body.options, consistency);
+            future::result(self.delete_documents(body, &index, consistency))
+                .and_then(|(affected, acknowledged)| acknowledged.map(move |_| affected))
         }
 
         #[put("/:index")]
         #[content_type("application/json")]
-        pub fn add(&self, body: AddDocument, index: String) -> Result<CreatedResponse, Error> {
-            if let Ok(ref index_lock) = self.catalog.write() {
-                if let Ok(ref index_handle) = index_lock.get_index(&index) {
-                    index_handle.add_document(body)?;
-                }
-            }
-            Ok(CreatedResponse)
+        pub fn add(
+            &self,
+            mut body: AddDocument,
+            index: String,
+            query_string: Option<WriteOptions>,
+        ) -> impl Future<Item = CreatedResponse, Error = Error> + Send {
+            let consistency = WriteOptions::consistency(query_string);
+            body.options = WriteOptions::durable(body.options, consistency);
+            future::result(self.add_document(body, &index, consistency))
+                .and_then(|acknowledged| acknowledged)
+                .map(|_| CreatedResponse)
+        }
+
+        #[get("/_list")]
+        #[content_type("application/json")]
+        pub fn list(&self) -> Result<String, Error> {
+            let names = self.catalog.read()?.index_names();
+            Ok(serde_json::to_string(&names)?)
+        }
+
+        #[delete("/:index/_drop")]
+        #[content_type("application/json")]
+        pub fn drop_index(&self, index: String) -> Result<String, Error> {
+            self.catalog.write()?.remove_index(&index)?;
+            Ok(serde_json::json!({ "dropped": index }).to_string())
         }
 
         #[put("/:index/_create")]
         #[content_type("application/json")]
-        pub fn create(&self, body: SchemaBody, index: String) -> Result<CreatedResponse, Error> {
-            let ip = self.catalog.read()?.base_path().clone();
-            let new_index = IndexCatalog::create_from_managed(ip, &index, body.0)?;
-            IndexHandler::add_index(&self.catalog, index.clone(), new_index).map(|_| CreatedResponse)
+        pub fn create(&self, body: SchemaBody, index: String, query_string: Option<CreateOptions>) -> Result<CreatedResponse, Error> {
+            self.create_mapped_index(&index, body.0, body.1, query_string)?;
+            Ok(CreatedResponse)
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::handlers::SearchHandler;
+    use crate::cluster::routing::Preference;
+    use crate::handlers::{SearchHandler, SqlHandler};
     use crate::index::tests::*;
 
     use super::*;
@@ -108,13 +274,393 @@ mod tests {
          ]"#;
         let handler = IndexHandler::new(Arc::clone(&shared_cat));
         let body: SchemaBody = serde_json::from_str(schema).unwrap();
-        let req = handler.create(body, "new_index".into());
+        let req = handler.create(body, "new_index".into(), None);
         assert_eq!(req.is_ok(), true);
         let search = SearchHandler::new(Arc::clone(&shared_cat));
         let docs = search.get_all_docs("new_index".into()).unwrap();
         assert_eq!(docs.hits, 0);
     }
 
+    #[test]
+    fn test_analyzed_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[{ "name": "title", "type": "text", "analyzer": "missing", "options": { "stored": true } }]"#;
+        let missing = handler.create(serde_json::from_str(schema).unwrap(), "missing".into(), None);
+        assert!(missing.is_err());
+        let schema = r#"[
+            { "name": "title", "type": "text", "analyzer": "english", "options": { "stored": true } },
+            { "name": "sku", "type": "text", "analyzer": "english", "stemmer": "none", "options": { "stored": true } }
+         ]"#;
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "stemmed".into(), None)
+            .unwrap();
+
+        let body = r#"{ "options": { "commit": true }, "document": { "title": "Runners Running", "sku": "Running" } }"#;
+        handler
+            .add(serde_json::from_str(body).unwrap(), "stemmed".into(), None)
+            .wait()
+            .unwrap();
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        for (query, hits) in &[
+            (r#"{ "query": { "raw": "title:runs" } }"#, 1),
+            (r#"{ "query": { "bool": { "must": [{ "raw": "title:runs" }] } } }"#, 1),
+            (r#"{ "query": { "raw": "sku:runs" } }"#, 0),
+            (r#"{ "query": { "raw": "sku:running" } }"#, 1),
+        ] {
+            let results = search.search_refs(serde_json::from_str(query).unwrap(), "stemmed".into(), Preference::parse(None));
+            assert_eq!(results.wait().unwrap().hits, *hits);
+        }
+    }
+
+    #[test]
+    fn test_keyword_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema =
+            r#"[{ "name": "status", "type": "text", "keyword": { "normalizer": ["lowercase", "trim"] }, "options": { "stored": true } }]"#;
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "keywords".into(), None)
+            .unwrap();
+        for status in &["Open", " open", "Closed"] {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {{ "status": "{}" }} }}"#, status);
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "keywords".into(), None)
+                .wait()
+                .unwrap();
+        }
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let query = r#"{ "query": { "term": { "status": "OPEN " } } }"#;
+        let results = search.search_refs(serde_json::from_str(query).unwrap(), "keywords".into(), Preference::parse(None));
+        assert_eq!(results.wait().unwrap().hits, 2);
+
+        let sql = SqlHandler::new(Arc::clone(&shared_cat), search);
+        let table = sql
+            .run("SELECT status, COUNT(*) AS docs FROM keywords GROUP BY status ORDER BY status")
+            .wait()
+            .unwrap();
+        assert_eq!(
+            table.rows,
+            vec![
+                vec![serde_json::json!("closed"), serde_json::json!(1)],
+                vec![serde_json::json!("open"), serde_json::json!(2)]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_date_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[{ "name": "created", "type": "date", "formats": ["rfc3339", "%Y-%m-%d"], "options": { "stored": true } }]"#;
+        handler.create(serde_json::from_str(schema).unwrap(), "dates".into(), None).unwrap();
+        for created in &["2019-01-01T10:00:00Z", "2019-01-01T23:59:59+00:00", "2019-01-03"] {
+            let body = format!(
+                r#"{{ "options": {{ "commit": true }}, "document": {{ "created": "{}" }} }}"#,
+                created
+            );
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "dates".into(), None)
+                .wait()
+                .unwrap();
+        }
+        let bad = r#"{ "document": { "created": "01/01/2019" } }"#;
+        assert!(handler
+            .add(serde_json::from_str(bad).unwrap(), "dates".into(), None)
+            .wait()
+            .is_err());
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let query = r#"{ "query": { "range": { "created": { "gte": "2019-01-01", "lt": "2019-01-02T00:00:00Z" } } },
+                         "aggs": { "date_histogram": { "field": "created", "interval": "1d" } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(query).unwrap(), "dates".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        assert_eq!(results.hits, 2);
+        let buckets = results.buckets.unwrap();
+        assert_eq!(buckets.len(), 1);
+        assert_eq!(
+            (buckets[0].key_as_string.as_str(), buckets[0].doc_count),
+            ("2019-01-01T00:00:00.000Z", 2)
+        );
+
+        let all = r#"{ "query": { "range": { "created": { "gte": "2019-01-01", "lte": "2019-12-31" } } },
+                       "aggs": { "date_histogram": { "field": "created", "interval": "1d" } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(all).unwrap(), "dates".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        let counts: Vec<u64> = results.buckets.unwrap().iter().map(|b| b.doc_count).collect();
+        assert_eq!(counts, vec![2, 1]);
+
+        let latest = r#"{ "query": { "range": { "created": { "gte": "2019-01-01", "lte": "2019-12-31" } } },
+                          "aggs": { "date_histogram": { "field": "created", "interval": "1d",
+                              "aggs": { "top_hits": { "size": 1, "sort": { "field": "created", "order": "desc" } } } } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(latest).unwrap(), "dates".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        let latest: Vec<Vec<Value>> = results
+            .buckets
+            .unwrap()
+            .into_iter()
+            .map(|b| b.top_hits.unwrap().hits.remove(0).doc.remove("created").unwrap())
+            .collect();
+        assert_eq!(
+            latest,
+            vec![vec![Value::I64(1_546_387_199_000)], vec![Value::I64(1_546_473_600_000)]]
+        );
+
+        let bands = r#"{ "query": { "range": { "created": { "gte": "2019-01-01", "lte": "2019-12-31" } } },
+                         "aggs": { "range": { "field": "created", "ranges": [{ "to": "2019-01-02" }, { "key": "later", "from": "2019-01-02" }] } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(bands).unwrap(), "dates".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        let ranges: Vec<(String, u64)> = results.ranges.unwrap().into_iter().map(|b| (b.key, b.doc_count)).collect();
+        assert_eq!(ranges, vec![("*-2019-01-02".to_string(), 2), ("later".to_string(), 1)]);
+
+        let named = r#"{ "query": { "range": { "created": { "gte": "2019-01-01", "lte": "2019-12-31" } } },
+                         "aggs": { "filters": { "filters": {
+                             "evening": { "range": { "created": { "gte": "2019-01-01T12:00:00Z", "lt": "2019-01-02" } } },
+                             "none": { "range": { "created": { "gte": "2020-01-01" } } } },
+                             "aggs": { "top_hits": { "size": 1 } } } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(named).unwrap(), "dates".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        let filters = results.filters.unwrap();
+        assert_eq!(filters["evening"].doc_count, 1);
+        assert_eq!(filters["evening"].top_hits.as_ref().unwrap().hits.len(), 1);
+        assert_eq!(filters["none"].doc_count, 0);
+    }
+
+    #[test]
+    fn test_ip_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[{ "name": "client", "type": "ip", "options": { "stored": true } }]"#;
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "clients".into(), None)
+            .unwrap();
+        for client in &["10.1.2.3", "10.200.0.1", "192.168.0.1", "2001:db8::1"] {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {{ "client": "{}" }} }}"#, client);
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "clients".into(), None)
+                .wait()
+                .unwrap();
+        }
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let hits = |query: &str| {
+            search
+                .search_refs(serde_json::from_str(query).unwrap(), "clients".into(), Preference::parse(None))
+                .wait()
+                .unwrap()
+        };
+        let exact = hits(r#"{ "query": { "term": { "client": "192.168.0.1" } } }"#);
+        assert_eq!(exact.hits, 1);
+        assert_eq!(exact.docs[0].doc["client"], vec![Value::Str("192.168.0.1".into())]);
+        assert_eq!(hits(r#"{ "query": { "term": { "client": "10.0.0.0/8" } } }"#).hits, 2);
+        assert_eq!(hits(r#"{ "query": { "term": { "client": "2001:db8::/32" } } }"#).hits, 1);
+        assert_eq!(
+            hits(r#"{ "query": { "range": { "client": { "gte": "10.1.0.0", "lte": "192.168.0.1" } } } }"#).hits,
+            3
+        );
+    }
+
+    #[test]
+    fn test_geo_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[{ "name": "location", "type": "geo_point", "options": { "stored": true } },
+                         { "name": "city", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } }]"#;
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "places".into(), None)
+            .unwrap();
+        let places = [
+            r#"{ "city": "Amsterdam", "location": { "lat": 52.3702, "lon": 4.8952 } }"#,
+            r#"{ "city": "Amsterdam", "location": "52.3676,4.9041" }"#,
+            r#"{ "city": "New York", "location": "40.7128,-74.0060" }"#,
+            r#"{ "city": "Nowhere" }"#,
+        ];
+        for place in &places {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {} }}"#, place);
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "places".into(), None)
+                .wait()
+                .unwrap();
+        }
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let query = r#"{ "query": { "bool": { "should": [{ "term": { "city": "Amsterdam" } }, { "term": { "city": "New York" } },
+                                                          { "term": { "city": "Nowhere" } }] } },
+                         "aggs": { "geohash_grid": { "field": "location", "precision": 3,
+                         "aggs": { "top_hits": { "size": 1, "_source": ["city"] } } } } }"#;
+        let results = search
+            .search_refs(serde_json::from_str(query).unwrap(), "places".into(), Preference::parse(None))
+            .wait()
+            .unwrap();
+        assert_eq!(results.hits, 4);
+        let cells = results.geohash_grid.unwrap();
+        let counts: Vec<(&str, u64)> = cells.iter().map(|c| (c.key.as_str(), c.doc_count)).collect();
+        assert_eq!(counts, vec![("u17", 2), ("dr5", 1)]);
+        let hit = &cells[1].top_hits.as_ref().unwrap().hits[0];
+        assert_eq!(hit.doc["city"], vec![Value::Str("New York".into())]);
+    }
+
+    #[test]
+    fn test_nested_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[
+            { "name": "order", "type": "u64", "options": { "indexed": true, "stored": true } },
+            { "name": "items", "type": "nested", "options": { "stored": true }, "fields": [
+                { "name": "color", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": false } },
+                { "name": "size", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": false } }
+            ] }
+        ]"#;
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "orders".into(), None)
+            .unwrap();
+        let orders = [
+            r#"{ "order": 1, "items": [{ "color": "red", "size": "S" }, { "color": "blue", "size": "L" }] }"#,
+            r#"{ "order": 2, "items": [{ "color": "red", "size": "L" }] }"#,
+        ];
+        for order in &orders {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {} }}"#, order);
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "orders".into(), None)
+                .wait()
+                .unwrap();
+        }
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let hits = |query: &str| {
+            search
+                .search_refs(serde_json::from_str(query).unwrap(), "orders".into(), Preference::parse(None))
+                .wait()
+                .unwrap()
+        };
+        let red_and_large = r#"{ "query": { "nested": { "path": "items", "query": { "bool": { "must": [
+            { "term": { "items.color": "red" } }, { "term": { "items.size": "L" } }
+        ] } } } } }"#;
+        let results = hits(red_and_large);
+        assert_eq!(results.hits, 1);
+        assert_eq!(results.docs[0].doc["order"], vec![Value::U64(2)]);
+        let red = r#"{ "query": { "nested": { "path": "items", "query": { "term": { "items.color": "red" } } } } }"#;
+        assert_eq!(hits(red).hits, 2);
+        // The documents of the objects themselves are never found
+        assert_eq!(hits(r#"{ "query": { "term": { "items.color": "red" } } }"#).hits, 0);
+        assert_eq!(hits(r#"{ "query": { "range": { "order": { "gte": 0, "lte": 10 } } } }"#).hits, 2);
+    }
+
+    #[test]
+    fn test_join_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let schema = r#"[
+            { "name": "text", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } },
+            { "name": "qa", "type": "join", "options": { "stored": true }, "relations": { "question": "answer" } }
+        ]"#;
+        handler.create(serde_json::from_str(schema).unwrap(), "forum".into(), None).unwrap();
+        let posts = [
+            r#"{ "text": "rust", "qa": { "name": "question", "id": "q1" } }"#,
+            r#"{ "text": "python", "qa": { "name": "question", "id": "q2" } }"#,
+            r#"{ "text": "cargo", "qa": { "name": "answer", "parent": "q1" } }"#,
+            r#"{ "text": "pip", "qa": { "name": "answer", "parent": "q2" } }"#,
+            r#"{ "text": "rustup", "qa": { "name": "answer", "parent": "q1" } }"#,
+        ];
+        for post in &posts {
+            let body = format!(r#"{{ "options": {{ "commit": true }}, "document": {} }}"#, post);
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "forum".into(), None)
+                .wait()
+                .unwrap();
+        }
+
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        let hits = |query: &str| {
+            search
+                .search_refs(serde_json::from_str(query).unwrap(), "forum".into(), Preference::parse(None))
+                .wait()
+                .unwrap()
+        };
+        let answered_with_cargo = hits(r#"{ "query": { "has_child": { "type": "answer", "query": { "term": { "text": "cargo" } } } } }"#);
+        assert_eq!(answered_with_cargo.hits, 1);
+        assert_eq!(answered_with_cargo.docs[0].doc["text"], vec![Value::Str("rust".into())]);
+        let about_rust = hits(r#"{ "query": { "has_parent": { "parent_type": "question", "query": { "term": { "text": "rust" } } } } }"#);
+        assert_eq!(about_rust.hits, 2);
+        assert_eq!(hits(r#"{ "query": { "term": { "qa": "answer" } } }"#).hits, 3);
+    }
+
+    #[test]
+    fn test_sharded_index() {
+        let path = std::env::temp_dir().join("toshi-sharded-test");
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let shared_cat = Arc::new(RwLock::new(IndexCatalog::with_path(path.clone()).unwrap()));
+        let schema = r#"[
+            { "name": "user", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": true } },
+            { "name": "message", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } }
+         ]"#;
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        let options = CreateOptions {
+            data_path: None,
+            directory: None,
+            preload: None,
+            sort_by: None,
+            shards: Some(3),
+            routing_field: Some("user".into()),
+            ignore_malformed: None,
+            default_fields: None,
+        };
+        handler
+            .create(serde_json::from_str(schema).unwrap(), "sharded".into(), Some(options))
+            .unwrap();
+        assert_eq!(handler.list().unwrap(), r#"["sharded"]"#);
+
+        for user in &["alice", "bob", "carol", "dave", "erin", "frank"] {
+            let body = format!(
+                r#"{{ "options": {{ "commit": true }}, "document": {{ "user": "{}", "message": "hello" }} }}"#,
+                user
+            );
+            handler
+                .add(serde_json::from_str(&body).unwrap(), "sharded".into(), None)
+                .wait()
+                .unwrap();
+        }
+        let search = SearchHandler::new(Arc::clone(&shared_cat));
+        assert_eq!(search.get_all_docs("sharded".into()).unwrap().hits, 6);
+        let shard_docs: Vec<usize> = shared_cat
+            .read()
+            .unwrap()
+            .shards("sharded")
+            .unwrap()
+            .into_iter()
+            .map(|shard| shard.search_index(crate::query::Request::all_docs()).unwrap().hits)
+            .collect();
+        assert_eq!(shard_docs.iter().sum::<usize>(), 6);
+        assert!(shard_docs.iter().filter(|hits| **hits > 0).count() > 1);
+
+        shared_cat.write().unwrap().refresh_catalog().unwrap();
+        assert_eq!(search.get_all_docs("sharded".into()).unwrap().hits, 6);
+        assert_eq!(handler.drop_index("sharded".into()).unwrap(), r#"{"dropped":"sharded"}"#);
+        assert!(!path.join("sharded").exists());
+    }
+
+    #[test]
+    fn test_list_and_drop_index() {
+        let shared_cat = create_test_catalog("test_index".into());
+        let handler = IndexHandler::new(Arc::clone(&shared_cat));
+        assert_eq!(handler.list().unwrap(), r#"["test_index"]"#);
+        assert_eq!(handler.drop_index("test_index".into()).unwrap(), r#"{"dropped":"test_index"}"#);
+        assert_eq!(handler.list().unwrap(), "[]");
+        assert_eq!(handler.drop_index("test_index".into()).is_err(), true);
+    }
+
     #[test]
     fn test_doc_create() {
         let shared_cat = create_test_catalog("test_index".into());
@@ -124,7 +670,7 @@ mod tests {
         .unwrap();
 
         let handler = IndexHandler::new(Arc::clone(&shared_cat));
-        let req = handler.add(body, "test_index".into());
+        let req = handler.add(body, "test_index".into(), None).wait();
 
         assert_eq!(req.is_ok(), true);
     }
@@ -139,7 +685,7 @@ mod tests {
             options: Some(IndexOptions { commit: true }),
             terms,
         };
-        let req = handler.delete(delete, "test_index".into());
+        let req = handler.delete(delete, "test_index".into(), None).wait();
         assert_eq!(req.is_ok(), true);
         assert_eq!(req.unwrap().docs_affected, 3);
     }
@@ -153,7 +699,7 @@ mod tests {
             document: bad_json,
             options: None,
         };
-        let req = handler.add(add_doc, "test_index".into());
+        let req = handler.add(add_doc, "test_index".into(), None).wait();
         assert_eq!(req.is_err(), true);
     }
 }
@@ -1,10 +1,27 @@
+pub mod analyze;
+pub mod async_search;
 pub mod bulk;
+pub mod changes;
+pub mod drain;
+pub mod elasticsearch;
+pub mod graphql;
+pub mod health;
 pub mod index;
+pub mod percolator;
+pub mod reindex;
+pub mod reload;
 pub mod root;
 pub mod search;
+pub mod snapshot;
+pub mod sql;
 pub mod summary;
+pub mod tasks;
+pub mod template;
 
-pub use self::{bulk::BulkHandler, index::IndexHandler, root::RootHandler, search::SearchHandler, summary::SummaryHandler};
+pub use self::{
+    analyze::AnalyzeHandler, async_search::AsyncSearchHandler, bulk::BulkHandler, changes::ChangesHandler, drain::DrainHandler, elasticsearch::ElasticsearchHandler, graphql::GraphqlHandler, health::HealthHandler, index::IndexHandler, percolator::PercolatorHandler, reindex::ReindexHandler, reload::ReloadHandler, root::RootHandler,
+    search::SearchHandler, snapshot::SnapshotHandler, sql::SqlHandler, summary::SummaryHandler, tasks::TaskHandler, template::TemplateHandler,
+};
 
 use serde::{Deserialize, Serialize};
 use tower_web::{Extract, Response};
@@ -0,0 +1,99 @@
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower_web::*;
+
+use crate::index::IndexCatalog;
+use crate::percolator::Percolator;
+use crate::Error;
+
+/// A query to register, in the same form as the `query` of a search
+#[derive(Extract, Deserialize)]
+pub struct RegisterQuery {
+    query: Value,
+}
+
+#[derive(Extract, Deserialize)]
+pub struct PercolateRequest {
+    document: Value,
+}
+
+#[derive(Response, Serialize)]
+pub struct PercolateResponse {
+    matches: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct PercolatorHandler {
+    percolator: Percolator,
+}
+
+impl PercolatorHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        PercolatorHandler {
+            percolator: Percolator::new(catalog),
+        }
+    }
+}
+
+impl_web! {
+    impl PercolatorHandler {
+        #[put("/:index/_percolator/:id")]
+        #[content_type("application/json")]
+        pub fn register(&self, body: RegisterQuery, index: String, id: String) -> Result<String, Error> {
+            self.percolator.register(&index, &id, body.query)?;
+            Ok(serde_json::json!({ "registered": id }).to_string())
+        }
+
+        #[delete("/:index/_percolator/:id")]
+        #[content_type("application/json")]
+        pub fn remove(&self, index: String, id: String) -> Result<String, Error> {
+            if !self.percolator.remove(&index, &id)? {
+                return Err(Error::QueryError(format!("No query is registered as {}", id)));
+            }
+            Ok(serde_json::json!({ "removed": id }).to_string())
+        }
+
+        #[get("/:index/_percolator")]
+        #[content_type("application/json")]
+        pub fn queries(&self, index: String) -> Result<String, Error> {
+            Ok(serde_json::to_string(&self.percolator.queries(&index)?)?)
+        }
+
+        #[post("/:index/_percolate")]
+        #[content_type("application/json")]
+        pub fn percolate(&self, body: PercolateRequest, index: String) -> Result<PercolateResponse, Error> {
+            let matches = self.percolator.percolate(&index, &body.document)?;
+            Ok(PercolateResponse { matches })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    #[test]
+    fn test_percolator() {
+        let handler = PercolatorHandler::new(create_test_catalog("test_index"));
+        let query = RegisterQuery {
+            query: json!({ "term": { "test_text": "babbaboo" } }),
+        };
+        handler.register(query, "test_index".into(), "babbaboo".into()).unwrap();
+        assert_eq!(
+            handler.queries("test_index".into()).unwrap(),
+            r#"{"babbaboo":{"term":{"test_text":"babbaboo"}}}"#
+        );
+
+        let request = PercolateRequest {
+            document: json!({ "test_text": "Babbaboo" }),
+        };
+        assert_eq!(handler.percolate(request, "test_index".into()).unwrap().matches, vec!["babbaboo"]);
+
+        handler.remove("test_index".into(), "babbaboo".into()).unwrap();
+        assert!(handler.remove("test_index".into(), "babbaboo".into()).is_err());
+    }
+}
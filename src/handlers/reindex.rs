@@ -0,0 +1,46 @@
+use std::sync::{Arc, RwLock};
+
+use futures::Future;
+use tower_web::*;
+
+use crate::index::IndexCatalog;
+use crate::reindex::{self, ReindexRequest};
+use crate::tasks::{TaskStatus, Tasks};
+use crate::Error;
+
+#[derive(Clone)]
+pub struct ReindexHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    tasks: Tasks,
+}
+
+impl ReindexHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, tasks: Tasks) -> Self {
+        ReindexHandler { catalog, tasks }
+    }
+
+    /// Reindex `request`'s source into `index` in the background, returning the task doing it
+    fn start(&self, request: ReindexRequest, index: String) -> Result<TaskStatus, Error> {
+        reindex::check(&self.catalog, &index, &request)?;
+        let reindex::ReindexSource::Elasticsearch(ref source) = request.source;
+        let description = format!("{} from {}/{}", index, source.url.trim_end_matches('/'), source.index);
+        let progress = self.tasks.start("reindex", description);
+        let task = progress.clone();
+        let reindexing = reindex::reindex(Arc::clone(&self.catalog), index, request, progress.clone()).then(move |reindexed| {
+            task.finish(&reindexed);
+            Ok(())
+        });
+        tokio::spawn(reindexing);
+        Ok(progress.status())
+    }
+}
+
+impl_web! {
+    impl ReindexHandler {
+        #[post("/:index/_reindex")]
+        #[content_type("application/json")]
+        fn reindex(&self, body: ReindexRequest, index: String) -> Result<TaskStatus, Error> {
+            self.start(body, index)
+        }
+    }
+}
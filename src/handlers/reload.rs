@@ -0,0 +1,55 @@
+use crate::reload::Reloader;
+use crate::Error;
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use tower_web::*;
+
+#[derive(Clone)]
+pub struct ReloadHandler {
+    reloader: Arc<Reloader>,
+}
+
+#[derive(Response, Debug)]
+pub struct Reloaded {
+    log_level: String,
+    auto_commit_duration: u64,
+    rate_limit_enabled: bool,
+}
+
+impl ReloadHandler {
+    pub fn new(reloader: Arc<Reloader>) -> Self {
+        ReloadHandler { reloader }
+    }
+}
+
+impl_web! {
+    impl ReloadHandler {
+        #[post("/_settings/reload")]
+        #[content_type("application/json")]
+        fn reload(&self) -> Result<Reloaded, Error> {
+            let settings = self.reloader.reload()?;
+            Ok(Reloaded {
+                log_level: settings.log_level,
+                auto_commit_duration: settings.auto_commit_duration,
+                rate_limit_enabled: settings.rate_limit.enabled,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::index::tests::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_reload_without_config_file() {
+        let cat = create_test_catalog("test_index");
+        let handler = ReloadHandler::new(Arc::new(Reloader::new(None, cat, Settings::default())));
+        assert!(handler.reload().is_err());
+    }
+}
@@ -1,21 +1,247 @@
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
+use futures::{future, Future};
 use log::info;
+use serde::Deserialize;
 use tower_web::*;
 
+use crate::cluster::cluster_rpc::{ResultCode, SearchReply};
+use crate::cluster::remote_cluster::{IndexRef, RemoteClusters};
+use crate::cluster::remote_handle::RemoteIndex;
+use crate::cluster::routing::{Preference, Routing, FAILURE_PENALTY, LOCAL_COPY};
+use crate::executor::Executor;
+use crate::handle::IndexHandle;
 use crate::index::IndexCatalog;
-use crate::query::Request;
+use crate::query::{run_pipelines, Request};
 use crate::results::SearchResults;
+use crate::shard;
+use crate::tasks::{Cancellation, Tasks};
 use crate::Error;
 
+/// Options for searches, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct SearchOptions {
+    /// Which copy of the index serves the search: `primary`, `replica`, `local`, or any other string to keep searches
+    /// given the same one on the same copy
+    pub preference: Option<String>,
+}
+
+/// The searches a search of several indexes is made of
+pub enum Searches {
+    /// A single index, whose results are the search's
+    Single(Box<Future<Item = SearchResults, Error = Error> + Send>),
+    /// Several indexes, or patterns that may match several, whose results are merged
+    Many(Vec<Box<Future<Item = SearchResults, Error = Error> + Send>>),
+}
+
 #[derive(Clone)]
 pub struct SearchHandler {
     catalog: Arc<RwLock<IndexCatalog>>,
+    executor: Executor,
+    remote_clusters: RemoteClusters,
+    routing: Routing,
+    tasks: Tasks,
 }
 
 impl SearchHandler {
     pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
-        SearchHandler { catalog }
+        SearchHandler::with_executor(catalog, Executor::default())
+    }
+
+    /// Run searches on `executor` rather than on the thread handling the request
+    pub fn with_executor(catalog: Arc<RwLock<IndexCatalog>>, executor: Executor) -> Self {
+        SearchHandler {
+            catalog,
+            executor,
+            remote_clusters: RemoteClusters::default(),
+            routing: Routing::default(),
+            tasks: Tasks::default(),
+        }
+    }
+
+    /// Search indexes on `remote_clusters` when they're referred to as `cluster:index`
+    pub fn with_remote_clusters(mut self, remote_clusters: RemoteClusters) -> Self {
+        self.remote_clusters = remote_clusters;
+        self
+    }
+
+    /// Search a single copy of indexes that data nodes in `routing` replicate, chosen by each search's preference
+    pub fn with_routing(mut self, routing: Routing) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    /// Track searches in `tasks` while they run, so they can be cancelled
+    pub fn with_tasks(mut self, tasks: Tasks) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    pub fn doc_search(&self, mut body: Request, index: String) -> Result<SearchResults, Error> {
+        info!("Query: {:?}", body);
+        let description = format!("{} {}", index, serde_json::to_string(&body.query)?);
+        let task = self.tasks.start_with("search", description, body.cancellation.clone());
+        body.cancellation = task.cancellation();
+        let searched = self.catalog.read().unwrap().search_index(&index, body);
+        // Searches are only worth looking up while they run, and would crowd other finished tasks out
+        self.tasks.remove(task.status().id);
+        searched
+    }
+
+    pub fn get_all_docs(&self, index: String) -> Result<SearchResults, Error> {
+        self.catalog.read().unwrap().search_index(&index, Request::all_docs())
+    }
+
+    /// Search every index `refs` names at once and merge their results. Each reference is an index name or a pattern
+    /// such as `logs-*`, prefixed with `cluster:` for indexes on a remote cluster.
+    pub fn search_refs(
+        &self,
+        body: Request,
+        refs: String,
+        preference: Preference,
+    ) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let mut body = body;
+        let pipelines = body.take_pipelines();
+        let search = self.search_all(body, refs, preference);
+        if pipelines.is_empty() {
+            return search;
+        }
+        Box::new(search.and_then(move |mut results| {
+            if let Some(ref mut buckets) = results.buckets {
+                run_pipelines(&pipelines, buckets)?;
+            }
+            Ok(results)
+        }))
+    }
+
+    fn search_all(&self, body: Request, refs: String, preference: Preference) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let (sort, limit) = (body.sort.clone(), body.limit);
+        match self.searches(body, &refs, &preference) {
+            Ok(Searches::Single(search)) => search,
+            Ok(Searches::Many(searches)) => {
+                Box::new(future::join_all(searches).map(move |results| shard::merge_results(results, sort.as_ref(), limit)))
+            }
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// The searches of the indexes `refs` names, without merging their results
+    pub fn searches(&self, body: Request, refs: &str, preference: &Preference) -> Result<Searches, Error> {
+        let refs = IndexRef::parse_list(refs);
+        match refs.first() {
+            None => return Err(Error::UnknownIndex(String::new())),
+            Some(r) if refs.len() == 1 && r.cluster.is_none() && !r.is_pattern() => {
+                return Ok(Searches::Single(self.scatter(body, r.index.clone(), preference)));
+            }
+            _ => {}
+        }
+
+        let query = serde_json::to_vec(&body)?;
+        let mut searches: Vec<Box<Future<Item = SearchResults, Error = Error> + Send>> = Vec::new();
+        for r in refs {
+            if let Some(ref cluster) = r.cluster {
+                searches.push(self.remote_clusters.search(cluster, &r.index, query.clone()));
+                continue;
+            }
+            let indexes = if r.is_pattern() {
+                self.catalog.read().unwrap().matching_indexes(&r.index)
+            } else {
+                vec![r.index]
+            };
+            for index in indexes {
+                searches.push(self.scatter(SearchHandler::copy_of(&query, &body.cancellation)?, index, preference));
+            }
+        }
+        Ok(Searches::Many(searches))
+    }
+
+    /// Search `index` here and on every data node holding it at once, merging the top results of each. Indexes no
+    /// data node holds are only searched here. Data nodes replicating this one hold copies of its indexes rather than
+    /// parts of their own, so only one copy among this node's and theirs is searched, picked by `preference`.
+    fn scatter(&self, body: Request, index: String, preference: &Preference) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let (exists, remotes) = {
+            let cat = self.catalog.read().unwrap();
+            (cat.exists(&index), cat.remote_handles(&index))
+        };
+        if remotes.is_empty() {
+            let handler = self.clone();
+            return Box::new(self.executor.run(move || handler.doc_search(body, index)));
+        }
+
+        // Queries can't be cloned, so each node gets its own copy of the request by way of JSON
+        let query = match serde_json::to_vec(&body) {
+            Ok(query) => query,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let (replicas, partitions): (Vec<RemoteIndex>, Vec<RemoteIndex>) = remotes
+            .into_iter()
+            .partition(|remote| exists && self.routing.is_replica(remote.node()));
+        let mut searches: Vec<Box<Future<Item = SearchResults, Error = Error> + Send>> = partitions
+            .iter()
+            .map(|remote| SearchHandler::search_remote(remote, &query))
+            .collect();
+        if exists {
+            let copies: Vec<String> = Some(LOCAL_COPY.to_string())
+                .into_iter()
+                .chain(replicas.iter().map(|remote| remote.node().to_string()))
+                .collect();
+            let chosen = self.routing.choose(preference, &copies);
+            let search = match chosen {
+                0 => {
+                    let handler = self.clone();
+                    let local = SearchHandler::copy_of(&query, &body.cancellation);
+                    Box::new(self.executor.run(move || handler.doc_search(local?, index)))
+                }
+                chosen => SearchHandler::search_remote(&replicas[chosen - 1], &query),
+            };
+            // Latencies are only worth keeping for copies that were chosen between
+            if copies.len() > 1 {
+                searches.push(self.timed(copies[chosen].clone(), search));
+            } else {
+                searches.push(search);
+            }
+        }
+
+        let (sort, limit) = (body.sort, body.limit);
+        Box::new(future::join_all(searches).map(move |results| shard::merge_results(results, sort.as_ref(), limit)))
+    }
+
+    /// The request `query` was serialized from, stopping along with the original when it's cancelled
+    fn copy_of(query: &[u8], cancellation: &Cancellation) -> Result<Request, Error> {
+        let mut request: Request = serde_json::from_slice(query)?;
+        request.cancellation = cancellation.clone();
+        Ok(request)
+    }
+
+    /// Record how long `search` of the copy on `node` takes, so later searches can avoid slow copies
+    fn timed(
+        &self,
+        node: String,
+        search: Box<Future<Item = SearchResults, Error = Error> + Send>,
+    ) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let (routing, started) = (self.routing.clone(), Instant::now());
+        Box::new(search.then(move |result| {
+            let elapsed = started.elapsed();
+            routing.record(&node, if result.is_ok() { elapsed } else { elapsed.max(FAILURE_PENALTY) });
+            result
+        }))
+    }
+
+    fn search_remote(remote: &RemoteIndex, query: &[u8]) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let request = match serde_json::from_slice(query) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        let node = remote.node().to_string();
+        let search = remote
+            .search_index(request)
+            .map_err(move |e| Error::IOError(format!("Unable to search node {}: {}", node, e)))
+            .and_then(|reply: SearchReply| match reply.result {
+                Some(ref result) if result.code != ResultCode::Success as i32 => Err(Error::QueryError(result.message.clone())),
+                _ => Ok(serde_json::from_slice(&reply.doc)?),
+            });
+        Box::new(search)
     }
 }
 
@@ -24,17 +250,16 @@ impl_web! {
 
         #[post("/:index")]
         #[content_type("application/json")]
-        pub fn doc_search(&self, body: Request, index: String) -> Result<SearchResults, Error> {
-            info!("Query: {:?}", body);
-            self.catalog.read().unwrap()
-                .search_index(&index, body)
+        fn search(&self, body: Request, index: String, query_string: Option<SearchOptions>) -> impl Future<Item = SearchResults, Error = Error> + Send {
+            let preference = query_string.and_then(|options| options.preference);
+            self.search_refs(body, index, Preference::parse(preference.as_ref().map(String::as_str)))
         }
 
         #[get("/:index")]
         #[content_type("application/json")]
-        pub fn get_all_docs(&self, index: String) -> Result<SearchResults, Error> {
-            self.catalog.read().unwrap()
-                .search_index(&index, Request::all_docs())
+        fn all_docs(&self, index: String, query_string: Option<SearchOptions>) -> impl Future<Item = SearchResults, Error = Error> + Send {
+            let preference = query_string.and_then(|options| options.preference);
+            self.search_refs(Request::all_docs(), index, Preference::parse(preference.as_ref().map(String::as_str)))
         }
     }
 }
@@ -70,6 +295,18 @@ pub mod tests {
         assert_eq!(results.hits, 3);
     }
 
+    #[test]
+    fn test_cancelled_search() {
+        let tasks = Tasks::default();
+        let handler = SearchHandler::new(create_test_catalog("test_index")).with_tasks(tasks.clone());
+        let mut req = Request::all_docs();
+        req.cancellation.cancel();
+        assert!(handler.doc_search(req, "test_index".into()).is_err());
+        assert!(handler.doc_search(Request::all_docs(), "test_index".into()).is_ok());
+        // Finished searches aren't kept as tasks
+        assert!(tasks.list().is_empty());
+    }
+
     #[test]
     fn test_wrong_index_error() {
         let cat = create_test_catalog("test_index");
@@ -126,7 +363,14 @@ pub mod tests {
     #[test]
     fn test_raw_query() {
         let body = r#"test_text:"Duckiment""#;
-        let req = Request::new(Some(Query::Raw { raw: body.into() }), None, 10);
+        let req = Request::new(
+            Some(Query::Raw {
+                raw: body.into(),
+                fields: Vec::new(),
+            }),
+            None,
+            10,
+        );
         let docs = run_query(req, "test_index");
         assert_eq!(docs.is_ok(), true);
         let result = docs.unwrap();
@@ -171,6 +415,20 @@ pub mod tests {
         assert_eq!(results.docs[0].score.unwrap(), 1.0);
     }
 
+    #[test]
+    fn test_bool_filter_query() {
+        let cat = create_test_catalog("test_index");
+        let handler = SearchHandler::new(Arc::clone(&cat));
+        let body = r#"{ "query": { "bool": {
+            "must": [ { "term": { "test_text": "document" } } ],
+            "filter": [ { "range": { "test_i64": { "gte": 2012, "lte": 2017 } } } ] } } }"#;
+        for _ in 0..2 {
+            let req: Request = serde_json::from_str(body).unwrap();
+            let results = handler.doc_search(req, "test_index".into()).unwrap();
+            assert_eq!(results.hits, 1);
+        }
+    }
+
     // This is ignored right now while we wait for https://github.com/tantivy-search/tantivy/pull/437
     // to be released.
     //#[test]
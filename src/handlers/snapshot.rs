@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use futures::{future, stream, Future, Stream};
+use serde::Deserialize;
+use tower_web::*;
+
+use crate::index::IndexCatalog;
+use crate::snapshot::{Manifest, SnapshotFuture, SnapshotRepository, Snapshots};
+use crate::tasks::{Progress, TaskStatus, Tasks};
+use crate::wal::Replay;
+use crate::Error;
+
+/// Options for `POST /:index/_snapshot`, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct SnapshotOptions {
+    /// What to call the snapshot, the time it's taken if not given
+    pub name: Option<String>,
+}
+
+/// Options for `POST /:index/_snapshot/:name/_restore`, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct RestoreOptions {
+    /// What to call the restored index, the name of the index the snapshot was taken of if not given
+    pub into: Option<String>,
+}
+
+/// Options for `POST /:index/_recover`, given in the query string
+#[derive(Extract, Deserialize, Default)]
+pub struct RecoverOptions {
+    /// What to call the recovered index, which has to be given since the index itself still exists
+    pub into: Option<String>,
+    /// The time to recover the index as it was at, in seconds since the Unix epoch, now if not given
+    pub until: Option<u64>,
+    /// The sequence number of the last write to recover, such as the one before a bad delete
+    pub until_seq: Option<u64>,
+}
+
+/// The body of `POST /_snapshot/:repo/:snapshot/_restore`
+#[derive(Extract, Deserialize)]
+pub struct RestoreRequest {
+    /// The indexes to restore from their snapshots of the given name
+    pub indexes: Vec<String>,
+    /// New names for any of the indexes, which are otherwise restored under their own
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+pub struct SnapshotHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    repository: Option<Arc<SnapshotRepository>>,
+    tasks: Tasks,
+}
+
+impl SnapshotHandler {
+    /// Snapshots go in `repository`, and can't be taken without one
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, repository: Option<Arc<SnapshotRepository>>) -> Self {
+        SnapshotHandler {
+            catalog,
+            repository,
+            tasks: Tasks::default(),
+        }
+    }
+
+    /// Keep track of snapshots and restores among `tasks`, so their progress can be looked up
+    pub fn with_tasks(mut self, tasks: Tasks) -> Self {
+        self.tasks = tasks;
+        self
+    }
+
+    /// Restore `snapshot` of every index in `request` in the background, returning the task doing it. Indexes are
+    /// restored one after another, and ones restored before another fails are kept.
+    fn start_restore(&self, request: RestoreRequest, repo: &str, snapshot: String) -> Result<TaskStatus, Error> {
+        let repository = match self.repository {
+            Some(ref repository) if repository.name() == repo => Arc::clone(repository),
+            Some(ref repository) => {
+                return Err(Error::IOError(format!(
+                    "No snapshot repository named {}, the configured one is {}",
+                    repo,
+                    repository.name()
+                )));
+            }
+            None => return Err(Error::IOError("No snapshot repository is configured".into())),
+        };
+        if request.indexes.is_empty() {
+            return Err(Error::IOError("No indexes were given to restore".into()));
+        }
+        if let Some(unknown) = request.rename.keys().find(|index| !request.indexes.contains(index)) {
+            return Err(Error::IOError(format!("{} is renamed but not restored", unknown)));
+        }
+
+        let description = format!("{} from snapshot {} in {}", request.indexes.join(", "), snapshot, repo);
+        let progress = self.tasks.start("restore", description);
+        let (catalog, task, rename) = (Arc::clone(&self.catalog), progress.clone(), request.rename);
+        let restores = stream::iter_ok(request.indexes)
+            .for_each(move |index| {
+                let into = rename.get(&index).cloned();
+                repository
+                    .restore(Arc::clone(&catalog), index, snapshot.clone(), into, None, task.clone())
+                    .map(|_| ())
+            })
+            .then(move |restored| {
+                task.finish(&restored);
+                Ok(())
+            });
+        tokio::spawn(restores);
+        Ok(progress.status())
+    }
+
+    /// Restore `index` as it was at the point `options` gives into a new index, from the latest snapshot of it taken
+    /// before then with the writes its log holds since replayed on top
+    fn recover(&self, index: String, options: RecoverOptions) -> SnapshotFuture<Manifest> {
+        let into = match options.into {
+            Some(into) => into,
+            None => {
+                return Box::new(future::err(Error::IOError(
+                    "The index to recover into must be given with ?into=".into(),
+                )))
+            }
+        };
+        let wal = self
+            .catalog
+            .read()
+            .map_err(Error::from)
+            .and_then(|cat| Ok(Arc::clone(cat.wal(&index)?)));
+        let replay = match wal {
+            Ok(wal) => Replay::new(wal, options.until, options.until_seq),
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let catalog = Arc::clone(&self.catalog);
+        let description = format!("{} into {} from its write-ahead log", index, into);
+        self.track("restore", description, move |repository, progress| -> SnapshotFuture<Manifest> {
+            let repository = Arc::clone(repository);
+            Box::new(repository.list(index.clone()).and_then(move |manifests| {
+                let name = match replay.nearest(&manifests) {
+                    Some(manifest) => manifest.name.clone(),
+                    None => {
+                        return Box::new(future::err(Error::IOError(format!(
+                            "No snapshot of {} was taken before that point while its writes were logged",
+                            index
+                        )))) as SnapshotFuture<Manifest>;
+                    }
+                };
+                repository.restore(catalog, index, name, Some(into), Some(replay), progress)
+            }))
+        })
+    }
+
+    /// `f`'s work, kept track of as a task doing `action` until it's done
+    fn track<T, F>(&self, action: &str, description: String, f: F) -> SnapshotFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Arc<SnapshotRepository>, Progress) -> SnapshotFuture<T>,
+    {
+        self.with_repository(|repository| {
+            let progress = self.tasks.start(action, description);
+            let task = progress.clone();
+            Box::new(f(repository, progress).then(move |done| {
+                task.finish(&done);
+                done
+            }))
+        })
+    }
+
+    fn with_repository<T, F>(&self, f: F) -> SnapshotFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Arc<SnapshotRepository>) -> SnapshotFuture<T>,
+    {
+        match self.repository {
+            Some(ref repository) => f(repository),
+            None => Box::new(future::err(Error::IOError("No snapshot repository is configured".into()))),
+        }
+    }
+}
+
+impl_web! {
+    impl SnapshotHandler {
+        #[post("/:index/_snapshot")]
+        #[content_type("application/json")]
+        fn snapshot(&self, index: String, query_string: Option<SnapshotOptions>) -> impl Future<Item = Manifest, Error = Error> + Send {
+            let name = query_string.and_then(|options| options.name);
+            let catalog = Arc::clone(&self.catalog);
+            let description = match name {
+                Some(ref name) => format!("{} as {}", index, name),
+                None => index.clone(),
+            };
+            self.track("snapshot", description, |repository, _| repository.snapshot(catalog, index, name))
+        }
+
+        #[get("/:index/_snapshot")]
+        #[content_type("application/json")]
+        fn list(&self, index: String) -> impl Future<Item = Snapshots, Error = Error> + Send {
+            self.with_repository(|repository| repository.list(index))
+                .map(|snapshots| Snapshots { snapshots })
+        }
+
+        #[delete("/:index/_snapshot/:name")]
+        #[content_type("application/json")]
+        fn delete(&self, index: String, name: String) -> impl Future<Item = String, Error = Error> + Send {
+            let deleted = serde_json::json!({ "deleted": name, "index": index }).to_string();
+            self.with_repository(|repository| repository.delete(index, name))
+                .map(move |_| deleted)
+        }
+
+        #[post("/:index/_snapshot/:name/_restore")]
+        #[content_type("application/json")]
+        fn restore(&self, index: String, name: String, query_string: Option<RestoreOptions>) -> impl Future<Item = Manifest, Error = Error> + Send {
+            let into = query_string.and_then(|options| options.into);
+            let catalog = Arc::clone(&self.catalog);
+            let description = format!("{} from snapshot {}", index, name);
+            self.track("restore", description, |repository, progress| {
+                repository.restore(catalog, index, name, into, None, progress)
+            })
+        }
+
+        #[post("/:index/_recover")]
+        #[content_type("application/json")]
+        fn recover_index(&self, index: String, query_string: Option<RecoverOptions>) -> impl Future<Item = Manifest, Error = Error> + Send {
+            self.recover(index, query_string.unwrap_or_default())
+        }
+
+        #[post("/_snapshot/:repo/:snapshot/_restore")]
+        #[content_type("application/json")]
+        fn restore_indexes(&self, body: RestoreRequest, repo: String, snapshot: String) -> Result<TaskStatus, Error> {
+            self.start_restore(body, &repo, snapshot)
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use serde::Deserialize;
+use tantivy::schema::Schema;
+use tower_web::*;
+
+use crate::cluster::routing::Preference;
+use crate::handlers::SearchHandler;
+use crate::index::IndexCatalog;
+use crate::sql::{Statement, Table};
+use crate::Error;
+
+#[derive(Extract, Deserialize)]
+pub struct SqlRequest {
+    query: String,
+}
+
+#[derive(Clone)]
+pub struct SqlHandler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+}
+
+impl SqlHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler) -> Self {
+        SqlHandler { catalog, search }
+    }
+
+    fn schema(&self, index: &str) -> Result<Schema, Error> {
+        let catalog = self.catalog.read()?;
+        let shards = catalog.shards(index)?;
+        let shard = shards.first().ok_or_else(|| Error::UnknownIndex(index.to_string()))?;
+        Ok(shard.get_index().schema())
+    }
+
+    /// Run a statement, as `POST /_sql` does
+    pub fn run(&self, sql: &str) -> Box<Future<Item = Table, Error = Error> + Send> {
+        let planned = Statement::parse(sql).and_then(|statement| {
+            let schema = self.schema(&statement.index)?;
+            let request = statement.request(&schema)?;
+            Ok((statement, schema, request))
+        });
+        match planned {
+            Ok((statement, schema, request)) => {
+                let index = statement.index.clone();
+                let table = self
+                    .search
+                    .search_refs(request, index, Preference::parse(None))
+                    .and_then(move |results| statement.table(&schema, results));
+                Box::new(table)
+            }
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+impl_web! {
+    impl SqlHandler {
+        #[post("/_sql")]
+        #[content_type("application/json")]
+        fn sql(&self, body: SqlRequest) -> impl Future<Item = Table, Error = Error> + Send {
+            self.run(&body.query)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    #[test]
+    fn test_sql() {
+        let catalog = create_test_catalog("test_index");
+        let search = SearchHandler::new(Arc::clone(&catalog));
+        let handler = SqlHandler::new(catalog, search);
+
+        let table = handler
+            .run("SELECT test_text FROM test_index WHERE test_i64 >= 2015")
+            .wait()
+            .unwrap();
+        assert_eq!(table.columns, vec!["test_text"]);
+        assert_eq!(table.rows.len(), 2);
+
+        let table = handler
+            .run("SELECT COUNT(*) AS docs FROM test_index WHERE MATCH(test_text, 'document')")
+            .wait()
+            .unwrap();
+        assert_eq!(table.columns, vec!["docs"]);
+        assert_eq!(table.rows, vec![vec![json!(3)]]);
+
+        assert!(handler.run("SELECT * FROM missing").wait().is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use serde::Deserialize;
+use tower_web::*;
+
+use crate::tasks::{TaskList, TaskStatus, Tasks};
+use crate::Error;
+
+/// Options for `GET /_tasks`, given in the query string
+#[derive(Extract, Deserialize)]
+pub struct TaskListOptions {
+    /// Only list tasks doing one of these actions, separated by commas, such as `reindex,restore`
+    pub actions: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct TaskHandler {
+    tasks: Tasks,
+}
+
+impl TaskHandler {
+    pub fn new(tasks: Tasks) -> Self {
+        TaskHandler { tasks }
+    }
+}
+
+impl_web! {
+    impl TaskHandler {
+        #[get("/_tasks")]
+        #[content_type("application/json")]
+        fn list(&self, query_string: Option<TaskListOptions>) -> Result<TaskList, ()> {
+            let tasks = match query_string.and_then(|options| options.actions) {
+                Some(actions) => self.tasks.list_actions(&actions.split(',').map(str::trim).collect::<Vec<_>>()),
+                None => self.tasks.list(),
+            };
+            Ok(TaskList { tasks })
+        }
+
+        #[get("/_tasks/:id")]
+        #[content_type("application/json")]
+        fn get(&self, id: u64) -> Result<TaskStatus, Error> {
+            self.tasks.get(id).ok_or_else(|| Error::IOError(format!("No task {} is running or finished recently", id)))
+        }
+
+        #[post("/_tasks/:id/_cancel")]
+        #[content_type("application/json")]
+        fn cancel(&self, id: u64) -> Result<TaskStatus, Error> {
+            self.tasks.cancel(id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let tasks = Tasks::default();
+        let handler = TaskHandler::new(tasks.clone());
+        assert!(handler.get(1).is_err());
+        tasks.start("restore", "logs from nightly".into());
+        assert_eq!(handler.get(1).unwrap().action, "restore");
+        assert_eq!(handler.list(None).unwrap().tasks.len(), 1);
+
+        assert!(handler.cancel(1).is_err());
+        let search = tasks.start("search", "test_index".into());
+        let cancellation = search.cancellation();
+        handler.cancel(2).unwrap();
+        assert!(cancellation.is_cancelled());
+
+        let searches = TaskListOptions {
+            actions: Some("search, reindex".into()),
+        };
+        let listed = handler.list(Some(searches)).unwrap().tasks;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].action, "search");
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::{Arc, RwLock};
+
+use futures::{future, Future};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tower_web::*;
+
+use crate::cluster::routing::Preference;
+use crate::handlers::search::SearchOptions;
+use crate::handlers::SearchHandler;
+use crate::index::IndexCatalog;
+use crate::query::Request;
+use crate::results::SearchResults;
+use crate::template::{self, TemplateStore};
+use crate::Error;
+
+/// A template to store, in the same form as the body of a search with placeholders in it
+#[derive(Extract, Deserialize)]
+pub struct StoreTemplate {
+    source: Value,
+}
+
+/// A search by the template stored as `id`, or by the template `source` given with it, rendered with `params`
+#[derive(Extract, Deserialize)]
+pub struct TemplateSearch {
+    id: Option<String>,
+    source: Option<Value>,
+    #[serde(default)]
+    params: Map<String, Value>,
+}
+
+#[derive(Clone)]
+pub struct TemplateHandler {
+    templates: TemplateStore,
+    search: SearchHandler,
+}
+
+impl TemplateHandler {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler) -> Self {
+        TemplateHandler {
+            templates: TemplateStore::new(catalog),
+            search,
+        }
+    }
+
+    /// The search `body` asks for, with the placeholders of its template replaced by its parameters
+    pub fn render(&self, body: TemplateSearch) -> Result<Request, Error> {
+        let source = match (body.id, body.source) {
+            (Some(id), None) => self.templates.get(&id)?,
+            (None, Some(source)) => source,
+            _ => return Err(Error::QueryError("A template search needs either an id or a source".into())),
+        };
+        template::render(&source, &body.params)
+    }
+}
+
+impl_web! {
+    impl TemplateHandler {
+        #[put("/_search/template/:id")]
+        #[content_type("application/json")]
+        pub fn store(&self, body: StoreTemplate, id: String) -> Result<String, Error> {
+            self.templates.store(&id, body.source)?;
+            Ok(serde_json::json!({ "stored": id }).to_string())
+        }
+
+        #[delete("/_search/template/:id")]
+        #[content_type("application/json")]
+        pub fn remove(&self, id: String) -> Result<String, Error> {
+            if !self.templates.remove(&id)? {
+                return Err(Error::QueryError(format!("No search template is stored as {}", id)));
+            }
+            Ok(serde_json::json!({ "removed": id }).to_string())
+        }
+
+        #[get("/_search/template")]
+        #[content_type("application/json")]
+        pub fn templates(&self) -> Result<String, Error> {
+            Ok(serde_json::to_string(&self.templates.templates()?)?)
+        }
+
+        #[post("/_render/template")]
+        #[content_type("application/json")]
+        pub fn render_template(&self, body: TemplateSearch) -> Result<String, Error> {
+            Ok(serde_json::to_string(&self.render(body)?)?)
+        }
+
+        #[post("/:index/_search/template")]
+        #[content_type("application/json")]
+        fn search(&self, body: TemplateSearch, index: String, query_string: Option<SearchOptions>) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+            let preference = query_string.and_then(|options| options.preference);
+            match self.render(body) {
+                Ok(request) => self.search.search_refs(request, index, Preference::parse(preference.as_ref().map(String::as_str))),
+                Err(e) => Box::new(future::err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    #[test]
+    fn test_template_search() {
+        let catalog = create_test_catalog("test_index");
+        let handler = TemplateHandler::new(Arc::clone(&catalog), SearchHandler::new(catalog));
+        let source = json!({ "query": { "term": { "test_text": "{{text}}" } }, "limit": "{{size}}" });
+        handler.store(StoreTemplate { source }, "by_text".into()).unwrap();
+        assert!(handler.templates().unwrap().contains("by_text"));
+
+        let search = |id: Option<&str>, source: Option<Value>, params: Value| TemplateSearch {
+            id: id.map(String::from),
+            source,
+            params: params.as_object().unwrap().clone(),
+        };
+        let results = handler
+            .search(
+                search(Some("by_text"), None, json!({ "text": "document", "size": 2 })),
+                "test_index".into(),
+                None,
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(results.hits, 2);
+
+        let inline = search(
+            None,
+            Some(json!({ "query": { "raw": "test_text:{{word}}" } })),
+            json!({ "word": "Duckiment" }),
+        );
+        assert_eq!(handler.search(inline, "test_index".into(), None).wait().unwrap().hits, 1);
+
+        let rendered = handler
+            .render_template(search(Some("by_text"), None, json!({ "text": "document", "size": 2 })))
+            .unwrap();
+        assert!(rendered.contains(r#""limit":2"#));
+        assert!(handler
+            .search(search(Some("by_text"), None, json!({})), "test_index".into(), None)
+            .wait()
+            .is_err());
+        assert!(handler
+            .search(search(None, None, json!({})), "test_index".into(), None)
+            .wait()
+            .is_err());
+
+        handler.remove("by_text".into()).unwrap();
+        assert!(handler.remove("by_text".into()).is_err());
+    }
+}
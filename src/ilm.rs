@@ -0,0 +1,402 @@
+//! Index lifecycle management, for indexes such as daily logs that are written to for a while, then only searched,
+//! and in the end deleted. Each policy in `lifecycle_policies` manages the indexes whose names match its patterns,
+//! moving each one on from taking writes, to being read only once it's old or big enough, to having its segments
+//! merged into one, to being deleted, as it reaches each of the policy's thresholds. The policies are evaluated every
+//! `lifecycle_interval` seconds on every node, over the indexes it holds. The phase each index has reached is kept in
+//! the first data path, so indexes made read only stay that way across restarts.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future, Stream};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::timer::Interval;
+
+use crate::cluster::remote_cluster;
+use crate::executor::blocking;
+use crate::index::IndexCatalog;
+use crate::settings::{LifecyclePolicy, Settings};
+use crate::tasks::Tasks;
+use crate::Result;
+
+/// Where the phase of each managed index is kept, in the first data path
+pub const LIFECYCLE_FILENAME: &str = ".lifecycle.json";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A merge started by a policy, to be spawned
+pub type Merge = Box<Future<Item = (), Error = ()> + Send>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Taking writes
+    Hot,
+    ReadOnly,
+    /// Read only, with its segments merged into one
+    Merged,
+}
+
+/// What a policy does next to an index
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    ReadOnly,
+    ForceMerge,
+    Delete,
+}
+
+/// Where a managed index is in its life
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IndexState {
+    /// When the index was created, in seconds since the Unix epoch, which its age is counted from
+    created: u64,
+    phase: Phase,
+}
+
+/// The first of `policies` that manages `index`
+pub fn policy_for<'a>(policies: &'a [LifecyclePolicy], index: &str) -> Option<&'a LifecyclePolicy> {
+    policies
+        .iter()
+        .find(|policy| policy.indexes.iter().any(|pattern| remote_cluster::matches_pattern(pattern, index)))
+}
+
+/// What `policy` does next to an index in `phase` that's `age` seconds old and takes `bytes` on disk, if anything
+pub fn next_action(policy: &LifecyclePolicy, phase: Phase, age: u64, bytes: u64) -> Option<Action> {
+    let past = |days: Option<u64>| days.map_or(false, |days| age >= days * SECONDS_PER_DAY);
+    if past(policy.delete_after_days) {
+        return Some(Action::Delete);
+    }
+    let too_big = policy.read_only_after_bytes.map_or(false, |max| bytes >= max);
+    match phase {
+        // Nothing is written to an index while it's merged
+        Phase::Hot if too_big || past(policy.read_only_after_days) || past(policy.force_merge_after_days) => Some(Action::ReadOnly),
+        Phase::ReadOnly if past(policy.force_merge_after_days) => Some(Action::ForceMerge),
+        _ => None,
+    }
+}
+
+/// Evaluates the lifecycle policies on an interval
+pub struct LifecycleScheduler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    tasks: Tasks,
+    policies: Vec<LifecyclePolicy>,
+    interval: Duration,
+    path: PathBuf,
+    states: Mutex<BTreeMap<String, IndexState>>,
+    /// Indexes whose segments are being merged, which are left alone until they're done
+    merging: Mutex<HashSet<String>>,
+}
+
+impl LifecycleScheduler {
+    /// Merges are kept track of among `tasks`
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, tasks: Tasks, settings: &Settings) -> Result<Self> {
+        let path = catalog.read()?.base_path().join(LIFECYCLE_FILENAME);
+        let states = match load_states(&path) {
+            Ok(states) => states,
+            Err(e) => {
+                warn!("Unable to read the lifecycle phases of indexes, starting over: {}", e);
+                BTreeMap::new()
+            }
+        };
+        Ok(LifecycleScheduler {
+            catalog,
+            tasks,
+            policies: settings.lifecycle_policies.clone(),
+            interval: Duration::from_secs(settings.lifecycle_interval),
+            path,
+            states: Mutex::new(states),
+            merging: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Evaluate the policies straight away, so indexes made read only before a restart are read only again as soon
+    /// as the node is up, and then every `lifecycle_interval`. Evaluating sizes up and deletes indexes, so it runs on
+    /// the runtime's blocking threads.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let scheduler = Arc::new(self);
+        Interval::new(Instant::now(), scheduler.interval)
+            .map_err(|e| error!("Lifecycle timer failed: {}", e))
+            .for_each(move |_| {
+                let scheduler = Arc::clone(&scheduler);
+                blocking(move || LifecycleScheduler::evaluate(&scheduler, now())).then(|evaluated| {
+                    match evaluated {
+                        Ok(merges) => merges.into_iter().for_each(|merge| {
+                            tokio::spawn(merge);
+                        }),
+                        Err(e) => error!("Unable to evaluate lifecycle policies: {}", e),
+                    }
+                    Ok(())
+                })
+            })
+    }
+
+    /// Move every managed index on as far as its policy has it go by `now`, in seconds since the Unix epoch,
+    /// returning the merges that were started
+    pub fn evaluate(scheduler: &Arc<Self>, now: u64) -> Result<Vec<Merge>> {
+        let names = scheduler.catalog.read()?.index_names();
+        let mut states = scheduler.states.lock()?;
+        // Indexes deleted since, by hand or by a policy, are forgotten
+        states.retain(|name, _| names.contains(name));
+        let mut merges = Vec::new();
+        for name in names {
+            let policy = match policy_for(&scheduler.policies, &name) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            if scheduler.merging.lock()?.contains(&name) {
+                continue;
+            }
+            let path = scheduler.catalog.read()?.index_path(&name)?;
+            let state = states.entry(name.clone()).or_insert_with(|| IndexState {
+                created: created(&path).unwrap_or(now),
+                phase: Phase::Hot,
+            });
+            match scheduler.apply(policy, &name, &path, state, now) {
+                Ok(Some(Action::Delete)) => {
+                    states.remove(&name);
+                }
+                Ok(Some(Action::ForceMerge)) => match LifecycleScheduler::force_merge(scheduler, policy, &name) {
+                    Ok(merge) => merges.push(merge),
+                    Err(e) => error!("Lifecycle policy {} failed to merge {}: {}", policy.name, name, e),
+                },
+                Ok(_) => {}
+                Err(e) => error!("Lifecycle policy {} failed to move {} on: {}", policy.name, name, e),
+            }
+        }
+        save_states(&scheduler.path, &states)?;
+        Ok(merges)
+    }
+
+    /// Take `index` through every phase `policy` has it reach by `now`, stopping at a merge to start or once it's
+    /// deleted, which are returned
+    fn apply(&self, policy: &LifecyclePolicy, index: &str, path: &Path, state: &mut IndexState, now: u64) -> Result<Option<Action>> {
+        if state.phase != Phase::Hot {
+            self.set_read_only(index)?;
+        }
+        let age = now.saturating_sub(state.created);
+        let bytes = dir_size(path).unwrap_or(0);
+        while let Some(action) = next_action(policy, state.phase, age, bytes) {
+            match action {
+                Action::ReadOnly => {
+                    self.set_read_only(index)?;
+                    state.phase = Phase::ReadOnly;
+                    info!("Lifecycle policy {} made {} read only", policy.name, index);
+                }
+                Action::ForceMerge => return Ok(Some(action)),
+                Action::Delete => {
+                    self.catalog.write()?.remove_index(index)?;
+                    info!("Lifecycle policy {} deleted {}", policy.name, index);
+                    return Ok(Some(action));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_read_only(&self, index: &str) -> Result<()> {
+        for shard in self.catalog.read()?.shards(index)? {
+            shard.set_read_only(true)?;
+        }
+        Ok(())
+    }
+
+    /// Start merging the segments of every shard of `index`, returning the work of waiting for them
+    fn force_merge(scheduler: &Arc<Self>, policy: &LifecyclePolicy, index: &str) -> Result<Merge> {
+        let merges = scheduler
+            .catalog
+            .read()?
+            .shards(index)?
+            .iter()
+            .map(|shard| shard.force_merge())
+            .collect::<Result<Vec<_>>>()?;
+        let description = format!("{} for lifecycle policy {}", index, policy.name);
+        let progress = scheduler.tasks.start("force_merge", description);
+        scheduler.merging.lock()?.insert(index.to_string());
+        let (scheduler, index, name) = (Arc::clone(scheduler), index.to_string(), policy.name.clone());
+        let merged = future::join_all(merges).then(move |merged| {
+            progress.finish(&merged);
+            scheduler.merging.lock().unwrap_or_else(|e| e.into_inner()).remove(&index);
+            match merged {
+                Ok(_) => {
+                    info!("Lifecycle policy {} merged {}", name, index);
+                    if let Err(e) = scheduler.set_phase(&index, Phase::Merged) {
+                        error!("Unable to record that {} was merged: {}", index, e);
+                    }
+                }
+                Err(e) => error!("Lifecycle policy {} failed to merge {}: {}", name, index, e),
+            }
+            Ok(())
+        });
+        Ok(Box::new(merged))
+    }
+
+    fn set_phase(&self, index: &str, phase: Phase) -> Result<()> {
+        let mut states = self.states.lock()?;
+        if let Some(state) = states.get_mut(index) {
+            state.phase = phase;
+        }
+        save_states(&self.path, &states)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// When the directory at `path` was created, where the file system keeps track of it
+fn created(path: &Path) -> Option<u64> {
+    let created = fs::metadata(path).and_then(|metadata| metadata.created()).ok()?;
+    created.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+/// The bytes the files under `path` take
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
+
+fn load_states(path: &Path) -> Result<BTreeMap<String, IndexState>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_states(path: &Path, states: &BTreeMap<String, IndexState>) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_string_pretty(states)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::IndexHandle;
+    use crate::handlers::index::AddDocument;
+    use crate::storage::StorageSettings;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+
+    fn policy(indexes: &[&str]) -> LifecyclePolicy {
+        LifecyclePolicy {
+            name: "logs".into(),
+            indexes: indexes.iter().map(|index| index.to_string()).collect(),
+            read_only_after_days: Some(1),
+            read_only_after_bytes: Some(1000),
+            force_merge_after_days: Some(2),
+            delete_after_days: Some(30),
+        }
+    }
+
+    #[test]
+    fn test_next_action() {
+        let policy = policy(&["logs-*"]);
+        let day = SECONDS_PER_DAY;
+        assert_eq!(next_action(&policy, Phase::Hot, 0, 10), None);
+        assert_eq!(next_action(&policy, Phase::Hot, 0, 1000), Some(Action::ReadOnly));
+        assert_eq!(next_action(&policy, Phase::Hot, day, 10), Some(Action::ReadOnly));
+        assert_eq!(next_action(&policy, Phase::ReadOnly, day, 10), None);
+        assert_eq!(next_action(&policy, Phase::Hot, 2 * day, 10), Some(Action::ReadOnly));
+        assert_eq!(next_action(&policy, Phase::ReadOnly, 2 * day, 10), Some(Action::ForceMerge));
+        assert_eq!(next_action(&policy, Phase::Merged, 2 * day, 10), None);
+        assert_eq!(next_action(&policy, Phase::Hot, 30 * day, 10), Some(Action::Delete));
+
+        let other = policy(&["metrics"]);
+        let policies = vec![policy, other];
+        assert_eq!(policy_for(&policies, "logs-2019.01.01").unwrap().indexes[0], "logs-*");
+        assert_eq!(policy_for(&policies, "metrics").unwrap().indexes[0], "metrics");
+        assert!(policy_for(&policies, "metrics-old").is_none());
+    }
+
+    #[test]
+    fn test_poisoned_catalog() {
+        let catalog = crate::index::tests::create_test_catalog("test_index");
+        let poisoned = Arc::clone(&catalog);
+        let _ = std::thread::spawn(move || {
+            let _catalog = poisoned.write().unwrap();
+            panic!("Poisoning the catalog");
+        })
+        .join();
+        assert!(LifecycleScheduler::new(catalog, Tasks::default(), &Settings::default()).is_err());
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let path = std::env::temp_dir().join("toshi-lifecycle-test");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let mut catalog = IndexCatalog::with_path(path.clone()).unwrap();
+        for name in &["logs-1", "metrics"] {
+            let mut builder = SchemaBuilder::new();
+            builder.add_text_field("text", STORED | TEXT);
+            catalog
+                .create_index(name, builder.build(), None, StorageSettings::default())
+                .unwrap();
+        }
+        let add = |catalog: &IndexCatalog, index: &str, text: &str| {
+            let doc: AddDocument = serde_json::from_value(serde_json::json!({
+                "options": { "commit": true },
+                "document": { "text": text }
+            }))
+            .unwrap();
+            catalog.get_index(index).unwrap().add_document(doc)
+        };
+        for text in &["first", "second", "third"] {
+            add(&catalog, "logs-1", text).unwrap();
+        }
+        let catalog = Arc::new(RwLock::new(catalog));
+
+        let mut settings = Settings::default();
+        let mut logs = policy(&["logs-*"]);
+        logs.read_only_after_bytes = Some(1);
+        logs.force_merge_after_days = Some(0);
+        settings.lifecycle_policies = vec![logs];
+        let tasks = Tasks::default();
+        let scheduler = Arc::new(LifecycleScheduler::new(Arc::clone(&catalog), tasks.clone(), &settings).unwrap());
+
+        // Made read only and merged straight away, the index being big enough and old enough
+        let merges = LifecycleScheduler::evaluate(&scheduler, now()).unwrap();
+        assert_eq!(merges.len(), 1);
+        assert!(add(&catalog.read().unwrap(), "logs-1", "fourth").is_err());
+        add(&catalog.read().unwrap(), "metrics", "first").unwrap();
+        for merge in merges {
+            merge.wait().unwrap();
+        }
+        let merged = catalog.read().unwrap().get_index("logs-1").unwrap().get_index().clone();
+        assert_eq!(merged.searchable_segment_ids().unwrap().len(), 1);
+        assert_eq!(merged.searcher().num_docs(), 3);
+        assert_eq!(tasks.list_actions(&["force_merge"]).len(), 1);
+        let states = load_states(&path.join(LIFECYCLE_FILENAME)).unwrap();
+        assert_eq!(states["logs-1"].phase, Phase::Merged);
+        assert!(!states.contains_key("metrics"));
+
+        // The phase is picked up again after a restart, leaving nothing more to do until it's deleted
+        let scheduler = Arc::new(LifecycleScheduler::new(Arc::clone(&catalog), tasks.clone(), &settings).unwrap());
+        assert!(LifecycleScheduler::evaluate(&scheduler, now()).unwrap().is_empty());
+        assert!(LifecycleScheduler::evaluate(&scheduler, now() + 31 * SECONDS_PER_DAY)
+            .unwrap()
+            .is_empty());
+        assert!(!catalog.read().unwrap().exists("logs-1"));
+        assert!(catalog.read().unwrap().exists("metrics"));
+        assert!(load_states(&path.join(LIFECYCLE_FILENAME)).unwrap().is_empty());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -2,51 +2,229 @@ use std::collections::HashMap;
 use std::fs;
 use std::iter::Iterator;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{Future, Stream};
 use http::Uri;
+use log::{debug, error, info, warn};
+use systemstat::{Platform, System};
 use tantivy::directory::MmapDirectory;
 use tantivy::schema::Schema;
 use tantivy::Index;
+use tokio::timer::Interval;
 
+use crate::breaker::CircuitBreaker;
 use crate::cluster::cluster_rpc::ListRequest;
+use crate::cluster::remote_cluster;
 use crate::cluster::remote_handle::RemoteIndex;
 use crate::cluster::rpc_server::RpcClient;
 use crate::cluster::rpc_server::RpcServer;
 use crate::cluster::GrpcConn;
 use crate::cluster::RPCError;
-use crate::handle::{IndexHandle, LocalIndex};
+use crate::executor;
+use crate::handle::{IndexHandle, LocalIndex, WriterBudget};
+use crate::handlers::index::DeleteDoc;
 use crate::query::Request;
+use crate::ranker::Rankers;
 use crate::results::*;
 use crate::settings::Settings;
+use crate::shard::{self, Sharding};
+use crate::storage::{self, StorageSettings};
+use crate::wal::{self, Operation, Wal, WAL_DIRNAME};
 use crate::{Error, Result};
 
+/// Where indexes created outside of the data paths are recorded, kept in the first data path
+pub const PLACEMENTS_FILENAME: &str = ".placements.json";
+
+/// Prefix of the directories indexes being moved here from another node are copied into, in the first data path
+pub const MOVING_PREFIX: &str = ".moving-";
+
 pub struct IndexCatalog {
     pub settings: Settings,
-    base_path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    /// The data path each local index lives in
+    placements: HashMap<String, PathBuf>,
+    budget: Arc<WriterBudget>,
+    segment_executor: Arc<tantivy::Executor>,
+    /// The rankers searches of every index can rescore with
+    rankers: Arc<Rankers>,
+    /// What searches of every index take the memory for their hits and buckets from
+    breaker: Arc<CircuitBreaker>,
+    /// How each sharded index is split. Its shards are kept in `local_indexes` under `Sharding::shard_name`.
+    sharded: HashMap<String, Sharding>,
     local_indexes: HashMap<String, LocalIndex>,
-    remote_indexes: HashMap<String, RemoteIndex>,
+    /// The write-ahead log of each local index, shared by its shards, when `wal_retention_hours` keeps one
+    wals: HashMap<String, Arc<Wal>>,
+    /// Indexes held by data nodes, each with a handle for every node that has a copy or part of it
+    remote_indexes: HashMap<String, Vec<RemoteIndex>>,
 }
 
 impl IndexCatalog {
     pub fn with_path(base_path: PathBuf) -> Result<Self> {
-        IndexCatalog::new(base_path, Settings::default())
+        IndexCatalog::new(vec![base_path], Settings::default())
     }
 
-    pub fn new(base_path: PathBuf, settings: Settings) -> Result<Self> {
+    pub fn new(data_paths: Vec<PathBuf>, settings: Settings) -> Result<Self> {
+        if data_paths.is_empty() {
+            return Err(Error::IOError("At least one data path is required".into()));
+        }
         let mut index_cat = IndexCatalog {
+            budget: Arc::new(WriterBudget::new(settings.writer_memory_budget)),
+            segment_executor: Arc::new(executor::segment_executor(settings.segment_search_threads)?),
+            rankers: Arc::new(Rankers::new(&settings.rankers)?),
+            breaker: Arc::new(CircuitBreaker::new(&settings.circuit_breaker)),
             settings,
-            base_path,
+            data_paths,
+            placements: HashMap::new(),
+            sharded: HashMap::new(),
             local_indexes: HashMap::new(),
+            wals: HashMap::new(),
             remote_indexes: HashMap::new(),
         };
         index_cat.refresh_catalog()?;
         Ok(index_cat)
     }
 
+    /// The rankers searches can rescore with, where programs embedding Toshi can register their own
+    pub fn rankers(&self) -> &Arc<Rankers> {
+        &self.rankers
+    }
+
+    /// The first data path, which also holds node metadata
     pub fn base_path(&self) -> &PathBuf {
-        &self.base_path
+        &self.data_paths[0]
+    }
+
+    pub fn data_paths(&self) -> &[PathBuf] {
+        &self.data_paths
+    }
+
+    /// The data path `name` lives in, or the one it should be created in if it doesn't exist yet
+    pub fn placement(&self, name: &str) -> PathBuf {
+        match self.placements.get(name) {
+            Some(path) => path.clone(),
+            None => IndexCatalog::emptiest_path(&self.data_paths).clone(),
+        }
+    }
+
+    /// The data path with the most free space, preferring earlier paths when they are tied or their space is unknown
+    pub fn emptiest_path(data_paths: &[PathBuf]) -> &PathBuf {
+        let sys = System::new();
+        let mut best = &data_paths[0];
+        let mut best_free = free_space(&sys, best);
+        for path in &data_paths[1..] {
+            let free = free_space(&sys, path);
+            if free > best_free {
+                best = path;
+                best_free = free;
+            }
+        }
+        best
+    }
+
+    /// Find where the index `name` lives, either in one of `data_paths` or in a location it was explicitly created in
+    pub fn find_index_path(data_paths: &[PathBuf], name: &str) -> Option<PathBuf> {
+        let custom = IndexCatalog::custom_placements(&data_paths[0])
+            .ok()
+            .and_then(|mut p| p.remove(name));
+        data_paths
+            .iter()
+            .chain(custom.iter())
+            .map(|p| p.join(name))
+            .find(|p| storage::is_index(p))
+    }
+
+    /// Indexes that were created outside of the data paths, and the directory each was created in
+    pub fn custom_placements(base_path: &Path) -> Result<HashMap<String, PathBuf>> {
+        match fs::read_to_string(base_path.join(PLACEMENTS_FILENAME)) {
+            Ok(contents) => Ok(serde_json::from_str(&contents)?),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record the indexes that live outside of the data paths, so they are found again on startup
+    pub fn save_custom_placements(base_path: &Path, placements: &HashMap<String, PathBuf>) -> Result<()> {
+        let path = base_path.join(PLACEMENTS_FILENAME);
+        if placements.is_empty() {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+            return Ok(());
+        }
+        let tmp = base_path.join(format!("{}.tmp", PLACEMENTS_FILENAME));
+        fs::write(&tmp, serde_json::to_string_pretty(placements)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    fn is_custom(&self, data_path: &Path) -> bool {
+        !self.data_paths.iter().any(|p| p == data_path)
+    }
+
+    fn save_placements(&self) -> Result<()> {
+        let custom = self
+            .placements
+            .iter()
+            .filter(|(_, path)| self.is_custom(path))
+            .map(|(name, path)| (name.clone(), path.clone()))
+            .collect();
+        IndexCatalog::save_custom_placements(self.base_path(), &custom)
+    }
+
+    /// Create a new index and add it to the catalog, stored as `storage` describes. It is created in `location` when
+    /// one is given, such as a faster disk for a busy index, otherwise on the data path with the most free space.
+    pub fn create_index(&mut self, name: &str, schema: Schema, location: Option<PathBuf>, storage: StorageSettings) -> Result<()> {
+        let data_path = self.creation_path(name, location)?;
+        let index = storage.create(&data_path.join(name), schema)?;
+        let wal = self.open_wal(name, &data_path.join(name), &[&index])?;
+        self.add_stored_index(name.to_string(), index, &storage, wal.as_ref())?;
+        self.record_placement(name, data_path)
+    }
+
+    /// Create an index split into shards as `sharding` describes, each stored as `storage` describes, and
+    /// placed the same way `create_index` places an ordinary index
+    pub fn create_sharded_index(
+        &mut self,
+        name: &str,
+        schema: Schema,
+        sharding: Sharding,
+        location: Option<PathBuf>,
+        storage: StorageSettings,
+    ) -> Result<()> {
+        if self.exists(name) {
+            return Err(Error::IOError(format!("Index {} already exists", name)));
+        }
+        let data_path = self.creation_path(name, location)?;
+        let shards = sharding.create(&data_path.join(name), &schema, &storage)?;
+        let wal = self.open_wal(name, &data_path.join(name), &shards.iter().collect::<Vec<_>>())?;
+        for (shard_name, index) in sharding.shard_names(name).into_iter().zip(shards) {
+            self.add_stored_index(shard_name, index, &storage, wal.as_ref())?;
+        }
+        self.sharded.insert(name.to_string(), sharding);
+        self.record_placement(name, data_path)
+    }
+
+    fn creation_path(&self, name: &str, location: Option<PathBuf>) -> Result<PathBuf> {
+        match location {
+            Some(location) if !self.placements.contains_key(name) => {
+                fs::create_dir_all(&location)?;
+                Ok(location)
+            }
+            _ => Ok(self.placement(name)),
+        }
+    }
+
+    fn record_placement(&mut self, name: &str, data_path: PathBuf) -> Result<()> {
+        info!("Placed index {} in {}", name, data_path.display());
+        let custom = self.is_custom(&data_path);
+        self.placements.insert(name.to_string(), data_path);
+        if custom {
+            self.save_placements()?;
+        }
+        Ok(())
     }
 
     #[doc(hidden)]
@@ -58,8 +236,15 @@ impl IndexCatalog {
         map.insert(name, new_index);
         Ok(IndexCatalog {
             settings: Settings::default(),
-            base_path: PathBuf::new(),
+            data_paths: vec![PathBuf::new()],
+            placements: HashMap::new(),
+            budget: Arc::new(WriterBudget::default()),
+            segment_executor: Arc::new(tantivy::Executor::single_thread()),
+            rankers: Arc::new(Rankers::default()),
+            breaker: Arc::new(CircuitBreaker::default()),
+            sharded: HashMap::new(),
             local_indexes: map,
+            wals: HashMap::new(),
             remote_indexes: HashMap::new(),
         })
     }
@@ -85,17 +270,154 @@ impl IndexCatalog {
     }
 
     pub fn add_index(&mut self, name: String, index: Index) -> Result<()> {
-        let handle = LocalIndex::new(index, self.settings.clone(), &name)?;
+        self.add_stored_index(name, index, &StorageSettings::default(), None)
+    }
+
+    fn add_stored_index(&mut self, name: String, index: Index, storage: &StorageSettings, wal: Option<&Arc<Wal>>) -> Result<()> {
+        let handle = LocalIndex::with_budget(index, self.settings.clone(), &name, Arc::clone(&self.budget))?
+            .with_segment_executor(Arc::clone(&self.segment_executor))
+            .with_rankers(Arc::clone(&self.rankers))
+            .with_breaker(Arc::clone(&self.breaker))
+            .with_sort_by(storage.sort_by.clone())
+            .with_mappings(storage.mappings.clone())
+            .with_default_fields(storage.default_fields.clone())
+            .with_wal(wal.cloned());
         self.local_indexes.insert(name.clone(), handle);
         Ok(())
     }
 
-    pub fn add_remote_index(&mut self, name: String, remote: RpcClient) -> Result<()> {
-        let ri = RemoteIndex::new(name.clone(), remote);
-        self.remote_indexes.entry(name).or_insert(ri);
+    /// Open the write-ahead log of the index `name` stored in `path`, numbering writes on from the last one the commits
+    /// of its `shards` hold, when the settings keep one
+    fn open_wal(&mut self, name: &str, path: &Path, shards: &[&Index]) -> Result<Option<Arc<Wal>>> {
+        if self.settings.wal_retention_hours == 0 {
+            return Ok(None);
+        }
+        let retention = Duration::from_secs(self.settings.wal_retention_hours * 60 * 60);
+        let wal = Arc::new(Wal::open(&path.join(WAL_DIRNAME), retention, wal::last_committed(shards)?)?);
+        self.wals.insert(name.to_string(), Arc::clone(&wal));
+        Ok(Some(wal))
+    }
+
+    /// The write-ahead log of `name`
+    pub fn wal(&self, name: &str) -> Result<&Arc<Wal>> {
+        if !self.exists(name) {
+            return Err(Error::UnknownIndex(name.into()));
+        }
+        self.wals
+            .get(name)
+            .ok_or_else(|| Error::IOError(format!("Writes to {} aren't logged, wal_retention_hours is 0", name)))
+    }
+
+    /// Delete the documents of `name` holding any of the terms of `delete` from each of its shards, logging the delete
+    /// once for all of them. Returns how many documents the shards have deleted.
+    pub fn delete_documents(&self, name: &str, delete: &DeleteDoc) -> Result<u32> {
+        let shards = self.shards(name)?;
+        if let Some(shard) = shards.first() {
+            shard.terms_of(delete)?;
+        }
+        let writers = shards.iter().map(|shard| shard.get_writer()).collect::<Result<Vec<_>>>()?;
+        // Every shard's writer is held while the delete is logged, so none of them commits between the two
+        let mut locked = Vec::with_capacity(writers.len());
+        for writer in &writers {
+            locked.push(writer.lock()?);
+        }
+        if let Some(wal) = self.wals.get(name) {
+            wal.append(Operation::Delete {
+                terms: delete.terms.clone(),
+            })?;
+        }
+        let mut docs_affected = 0;
+        for (shard, writer) in shards.iter().zip(locked.iter_mut()) {
+            docs_affected += shard.delete_with(writer, delete)?.docs_affected;
+        }
+        Ok(docs_affected)
+    }
+
+    /// Close an index and delete it from disk
+    pub fn remove_index(&mut self, name: &str) -> Result<()> {
+        // Dropping the handle releases the index writer's lock before its files are removed
+        self.wals.remove(name);
+        match self.sharded.remove(name) {
+            Some(sharding) => sharding.shard_names(name).iter().for_each(|shard| {
+                self.local_indexes.remove(shard);
+            }),
+            None => {
+                self.local_indexes.remove(name).ok_or_else(|| Error::UnknownIndex(name.into()))?;
+            }
+        }
+        let path = match self.placements.remove(name) {
+            Some(data_path) => {
+                if self.is_custom(&data_path) {
+                    self.save_placements()?;
+                }
+                data_path.join(name)
+            }
+            None => self.base_path().join(name),
+        };
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
         Ok(())
     }
 
+    /// The names of every local index, in sorted order. Sharded indexes are listed once, rather than by shard.
+    pub fn index_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .local_indexes
+            .keys()
+            .filter(|name| !name.contains('/'))
+            .chain(self.sharded.keys())
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn add_remote_index(&mut self, name: String, node: String, remote: RpcClient) -> Result<()> {
+        let handles = self.remote_indexes.entry(name.clone()).or_insert_with(Vec::new);
+        handles.retain(|handle| handle.node() != node);
+        handles.push(RemoteIndex::new(name, node, remote));
+        Ok(())
+    }
+
+    /// Replace everything known about the indexes on `node` with `names`, reached through `remote`
+    pub fn set_remote_indexes(&mut self, node: &str, names: Vec<String>, remote: RpcClient) -> Result<()> {
+        for handles in self.remote_indexes.values_mut() {
+            handles.retain(|handle| handle.node() != node);
+        }
+        self.remote_indexes.retain(|_, handles| !handles.is_empty());
+        for name in names {
+            self.add_remote_index(name, node.to_string(), remote.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Every index here or on a data node whose name matches `pattern`, in sorted order
+    pub fn matching_indexes(&self, pattern: &str) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .index_names()
+            .into_iter()
+            .chain(self.remote_indexes.keys().cloned())
+            .filter(|name| remote_cluster::matches_pattern(pattern, name))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Searches of `name` go to `to` instead of `from` from now on, without waiting for the next refresh
+    pub fn move_remote_index(&mut self, name: &str, from: &str, to: String, remote: RpcClient) -> Result<()> {
+        if let Some(handles) = self.remote_indexes.get_mut(name) {
+            handles.retain(|handle| handle.node() != from);
+        }
+        self.add_remote_index(name.to_string(), to, remote)
+    }
+
+    /// A handle on every data node that holds `name`, which is empty when none do
+    pub fn remote_handles(&self, name: &str) -> Vec<RemoteIndex> {
+        self.remote_indexes.get(name).cloned().unwrap_or_default()
+    }
+
     #[allow(dead_code)]
     pub fn get_collection(&self) -> &HashMap<String, LocalIndex> {
         &self.local_indexes
@@ -106,7 +428,28 @@ impl IndexCatalog {
     }
 
     pub fn exists(&self, index: &str) -> bool {
-        self.get_collection().contains_key(&index.to_string())
+        self.get_collection().contains_key(&index.to_string()) || self.sharded.contains_key(index)
+    }
+
+    /// How `name` is split into shards, if it is
+    pub fn sharding(&self, name: &str) -> Option<&Sharding> {
+        self.sharded.get(name)
+    }
+
+    /// Every shard of `name`, or just the index itself if it isn't sharded
+    pub fn shards(&self, name: &str) -> Result<Vec<&LocalIndex>> {
+        match self.sharded.get(name) {
+            Some(sharding) => sharding.shard_names(name).iter().map(|shard| self.get_index(shard)).collect(),
+            None => Ok(vec![self.get_index(name)?]),
+        }
+    }
+
+    /// The index, or shard of it, that `doc` should be written to
+    pub fn route(&self, name: &str, doc: &serde_json::Value) -> Result<&LocalIndex> {
+        match self.sharded.get(name) {
+            Some(sharding) => self.get_index(&Sharding::shard_name(name, sharding.route(doc)?)),
+            None => self.get_index(name),
+        }
     }
 
     pub fn get_mut_index(&mut self, name: &str) -> Result<&mut LocalIndex> {
@@ -119,19 +462,79 @@ impl IndexCatalog {
 
     pub fn refresh_catalog(&mut self) -> Result<()> {
         self.local_indexes.clear();
-
-        for dir in fs::read_dir(self.base_path.clone())? {
-            let entry = dir?.path();
-            if let Some(entry_str) = entry.to_str() {
-                if !entry_str.ends_with(".node_id") {
+        self.wals.clear();
+        self.placements.clear();
+        self.sharded.clear();
+
+        for data_path in self.data_paths.clone() {
+            for dir in fs::read_dir(&data_path)? {
+                let entry = dir?.path();
+                if let Some(entry_str) = entry.to_str() {
                     let pth: String = entry_str.rsplit('/').take(1).collect();
-                    let idx = IndexCatalog::load_index(entry_str)?;
-                    self.add_index(pth.clone(), idx)?;
+                    // Hidden entries hold node metadata and indexes still being copied or snapshotted
+                    if !pth.starts_with('.') {
+                        self.open_placed(pth, data_path.clone())?;
+                    }
+                } else {
+                    return Err(Error::IOError(format!("Path {} is not a valid unicode path", entry.display())));
                 }
-            } else {
-                return Err(Error::IOError(format!("Path {} is not a valid unicode path", entry.display())));
             }
         }
+        for (name, location) in IndexCatalog::custom_placements(self.base_path())? {
+            self.open_placed(name, location)?;
+        }
+        Ok(())
+    }
+
+    /// The directory `name` is stored in
+    pub fn index_path(&self, name: &str) -> Result<PathBuf> {
+        if !self.exists(name) {
+            return Err(Error::UnknownIndex(name.into()));
+        }
+        Ok(self.placement(name).join(name))
+    }
+
+    /// Take in an index that was copied from another node into `copied`, moving it into the first data path
+    pub fn adopt_index(&mut self, name: &str, copied: &Path) -> Result<()> {
+        if self.exists(name) {
+            return Err(Error::IOError(format!("Index {} already exists", name)));
+        }
+        // The copy is made in the first data path, so renaming it is atomic and never crosses file systems
+        let data_path = self.base_path().clone();
+        fs::rename(copied, data_path.join(name))?;
+        self.open_placed(name.to_string(), data_path)
+    }
+
+    fn open_placed(&mut self, name: String, data_path: PathBuf) -> Result<()> {
+        if let Some(other) = self.placements.get(&name) {
+            return Err(Error::IOError(format!(
+                "Index {} exists in both {} and {}",
+                name,
+                other.display(),
+                data_path.display()
+            )));
+        }
+        let index_path = data_path.join(&name);
+        match Sharding::load(&index_path)? {
+            Some(sharding) => {
+                let mut shards = Vec::new();
+                for shard in 0..sharding.shards {
+                    let shard_path = index_path.join(shard.to_string());
+                    shards.push(storage::open(&shard_path).map_err(|_| Error::UnknownIndex(shard_path.display().to_string()))?);
+                }
+                let wal = self.open_wal(&name, &index_path, &shards.iter().map(|(idx, _)| idx).collect::<Vec<_>>())?;
+                for (shard_name, (idx, storage)) in sharding.shard_names(&name).into_iter().zip(shards) {
+                    self.add_stored_index(shard_name, idx, &storage, wal.as_ref())?;
+                }
+                self.sharded.insert(name.clone(), sharding);
+            }
+            None => {
+                let (idx, storage) = storage::open(&index_path).map_err(|_| Error::UnknownIndex(index_path.display().to_string()))?;
+                let wal = self.open_wal(&name, &index_path, &[&idx])?;
+                self.add_stored_index(name.clone(), idx, &storage, wal.as_ref())?;
+            }
+        }
+        self.placements.insert(name, data_path);
         Ok(())
     }
 
@@ -144,38 +547,124 @@ impl IndexCatalog {
             .map_err(|e| Error::IOError(e.to_string()))
     }
 
-    pub fn refresh_remote_catalog(node: String) -> impl Future<Item = (), Error = RPCError> + Send + 'static {
+    /// Ask `node` which indexes it holds, and record them in `catalog` so searches of them are sent there too
+    pub fn refresh_remote_catalog(
+        catalog: Arc<RwLock<IndexCatalog>>,
+        node: String,
+    ) -> impl Future<Item = (), Error = RPCError> + Send + 'static {
         let socket: SocketAddr = node.parse().unwrap();
         let host_uri = IndexCatalog::create_host_uri(socket).unwrap();
         let grpc_conn = GrpcConn(socket);
-        let client_fut = RpcServer::create_client(grpc_conn.clone(), host_uri)
-            .and_then(|mut client| {
-                client
-                    .list_indexes(tower_grpc::Request::new(ListRequest {}))
-                    .map(|resp| resp.into_inner())
-                    .inspect(|x| println!("{:#?}", x))
-                    .map_err(|e| e.into())
-            })
-            .map(|x| {
-                // Uhh... do stuff here that actually matters...
-                println!("{:#?}", x)
-            });
+        RpcServer::create_client(grpc_conn.clone(), host_uri).and_then(move |mut client| {
+            client
+                .list_indexes(tower_grpc::Request::new(ListRequest {}))
+                .map_err(|e| e.into())
+                .map(move |resp| {
+                    let indexes = resp.into_inner().indexes;
+                    debug!("Node {} holds indexes {:?}", node, indexes);
+                    if let Ok(mut cat) = catalog.write() {
+                        let _ = cat.set_remote_indexes(&node, indexes, client);
+                    }
+                })
+        })
+    }
 
-        client_fut
+    /// Keep the indexes held by each of `nodes` up to date, refreshing every `interval`. Nodes that can't be
+    /// reached keep whatever they were last known to hold.
+    pub fn follow_remote_catalogs(
+        catalog: Arc<RwLock<IndexCatalog>>,
+        nodes: Vec<String>,
+        interval: Duration,
+    ) -> impl Future<Item = (), Error = ()> + Send + 'static {
+        Interval::new(Instant::now(), interval)
+            .map_err(|e| error!("Remote catalog timer failed: {}", e))
+            .for_each(move |_| {
+                for node in &nodes {
+                    let refresh = IndexCatalog::refresh_remote_catalog(Arc::clone(&catalog), node.clone());
+                    let node = node.clone();
+                    tokio::spawn(refresh.map_err(move |e| warn!("Unable to refresh the indexes on {}: {}", node, e)));
+                }
+                Ok(())
+            })
     }
 
     pub fn search_index(&self, index: &str, search: Request) -> Result<SearchResults> {
+        if self.sharded.contains_key(index) {
+            // Queries can't be cloned, so each shard gets its own copy of the request by way of JSON
+            let body = serde_json::to_string(&search)?;
+            let results = self
+                .shards(index)?
+                .into_iter()
+                .map(|shard| shard.search_index(serde_json::from_str(&body)?))
+                .collect::<Result<Vec<SearchResults>>>()?;
+            return Ok(shard::merge_results(results, search.sort.as_ref(), search.limit));
+        }
         match self.get_index(index) {
             Ok(hand) => hand.search_index(search),
             Err(e) => Err(e),
         }
     }
 
+    /// Commit every local index, returning the names of the indexes that committed and those that failed to
+    pub fn commit_all(&self) -> (Vec<String>, Vec<String>) {
+        let mut committed = Vec::new();
+        let mut failed = Vec::new();
+        for (name, index) in &self.local_indexes {
+            match index.commit() {
+                Ok(_) => committed.push(name.clone()),
+                Err(e) => {
+                    error!("Failed to commit index={}: {}", name, e);
+                    failed.push(name.clone());
+                }
+            }
+        }
+        (committed, failed)
+    }
+
+    /// Close the writers of indexes that haven't been written to for `writer_idle_timeout` seconds, returning their
+    /// memory to the writer budget. Writers are only closed when there is a budget to return the memory to.
+    pub fn close_idle_writers(&self) -> usize {
+        if !self.budget.is_limited() {
+            return 0;
+        }
+        let timeout = Duration::from_secs(self.settings.writer_idle_timeout);
+        self.local_indexes
+            .iter()
+            .filter(|(name, index)| match index.close_idle_writer(timeout) {
+                Ok(closed) => closed,
+                Err(e) => {
+                    error!("Failed to close idle writer for index={}: {}", name, e);
+                    false
+                }
+            })
+            .count()
+    }
+
+    pub fn writer_budget(&self) -> &WriterBudget {
+        &self.budget
+    }
+
     pub fn clear(&mut self) {
         self.local_indexes.clear();
+        self.wals.clear();
     }
 }
 
+/// Bytes available on the filesystem `path` is on, taken from the mount point with the longest matching prefix
+fn free_space(sys: &System, path: &Path) -> u64 {
+    let path = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(_) => return 0,
+    };
+    sys.mounts()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|mount| path.starts_with(&mount.fs_mounted_on))
+        .max_by_key(|mount| mount.fs_mounted_on.len())
+        .map(|mount| mount.avail.as_usize() as u64)
+        .unwrap_or(0)
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::sync::{Arc, RwLock};
@@ -211,14 +700,102 @@ pub mod tests {
         idx
     }
 
+    #[test]
+    fn test_remove_index() {
+        let cat = create_test_catalog("test_index");
+        assert_eq!(cat.read().unwrap().index_names(), vec!["test_index".to_string()]);
+        cat.write().unwrap().remove_index("test_index").unwrap();
+        assert!(!cat.read().unwrap().exists("test_index"));
+        assert!(cat.write().unwrap().remove_index("test_index").is_err());
+    }
+
+    #[test]
+    fn test_data_path_placement() {
+        let paths: Vec<PathBuf> = (0..2)
+            .map(|i| std::env::temp_dir().join(format!("toshi-placement-test-{}", i)))
+            .collect();
+        for path in &paths {
+            let _ = fs::remove_dir_all(path);
+            fs::create_dir_all(path).unwrap();
+        }
+        let custom = std::env::temp_dir().join("toshi-placement-test-custom");
+        let _ = fs::remove_dir_all(&custom);
+        let schema = create_test_index().schema();
+
+        let placed = {
+            let mut cat = IndexCatalog::new(paths.clone(), Settings::default()).unwrap();
+            cat.create_index("placed_index", schema.clone(), None, StorageSettings::default())
+                .unwrap();
+            let placed = cat.placement("placed_index");
+            assert!(paths.contains(&placed));
+            assert_eq!(
+                IndexCatalog::find_index_path(&paths, "placed_index"),
+                Some(placed.join("placed_index"))
+            );
+            cat.create_index("custom_index", schema, Some(custom.clone()), StorageSettings::default())
+                .unwrap();
+            placed
+        };
+
+        let mut cat = IndexCatalog::new(paths.clone(), Settings::default()).unwrap();
+        assert!(cat.exists("placed_index"));
+        assert_eq!(cat.placement("placed_index"), placed);
+        assert!(cat.exists("custom_index"));
+        assert_eq!(cat.placement("custom_index"), custom);
+        assert_eq!(
+            IndexCatalog::find_index_path(&paths, "custom_index"),
+            Some(custom.join("custom_index"))
+        );
+
+        cat.remove_index("placed_index").unwrap();
+        cat.remove_index("custom_index").unwrap();
+        assert_eq!(IndexCatalog::find_index_path(&paths, "placed_index"), None);
+        assert!(IndexCatalog::custom_placements(&paths[0]).unwrap().is_empty());
+        fs::remove_dir_all(custom).unwrap();
+        for path in paths {
+            fs::remove_dir_all(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_writer_budget() {
+        let path = std::env::temp_dir().join("toshi-budget-test");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        let mut settings = Settings::default();
+        settings.writer_memory_budget = 20_000_000;
+        settings.writer_idle_timeout = 0;
+
+        let mut cat = IndexCatalog::new(vec![path.clone()], settings).unwrap();
+        cat.create_index("first", create_test_index().schema(), None, StorageSettings::default())
+            .unwrap();
+        cat.create_index("second", create_test_index().schema(), None, StorageSettings::default())
+            .unwrap();
+        assert!(!cat.get_index("first").unwrap().has_writer());
+        assert_eq!(cat.writer_budget().allocated(), 0);
+
+        drop(cat.get_index("first").unwrap().get_writer().unwrap());
+        assert_eq!(cat.writer_budget().allocated(), 20_000_000);
+        drop(cat.get_index("second").unwrap().get_writer().unwrap());
+        assert_eq!(cat.writer_budget().allocated(), 23_000_000);
+
+        assert_eq!(cat.close_idle_writers(), 2);
+        assert!(!cat.get_index("first").unwrap().has_writer());
+        assert_eq!(cat.writer_budget().allocated(), 0);
+        drop(cat);
+        fs::remove_dir_all(path).unwrap();
+    }
+
     #[test]
     #[ignore]
     pub fn test_remote_index_refresh() {
+        use crate::cluster::seeds::Membership;
+
         let socket_addr: SocketAddr = "127.0.0.1:8081".parse().unwrap();
         let cat = create_test_catalog("test_index");
-        let service = RpcServer::get_service(socket_addr, cat);
+        let service = RpcServer::get_service(socket_addr, Arc::clone(&cat), Membership::default());
         let nodes = "127.0.0.1:8081".into();
-        let refresh = IndexCatalog::refresh_remote_catalog(nodes).map_err(|_| ());
+        let refresh = IndexCatalog::refresh_remote_catalog(cat, nodes).map_err(|_| ());
 
         let s = service.select(refresh).map(|_| ()).map_err(|_| ());
 
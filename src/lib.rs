@@ -88,13 +88,40 @@ impl From<serde_json::Error> for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-mod handle;
-mod handlers;
-mod query;
-mod results;
+mod middleware;
 
+pub mod admin;
+pub mod alert;
+pub mod analysis;
+pub mod async_search;
+pub mod breaker;
 pub mod cluster;
 pub mod commit;
+pub mod daemon;
+pub mod embedded;
+pub mod executor;
+pub mod graphql;
+pub mod grpc;
+pub mod handle;
+pub mod handlers;
+pub mod ilm;
 pub mod index;
+pub mod lifecycle;
+pub mod mapping;
+pub mod percolator;
+pub mod query;
+pub mod ranker;
+pub mod reindex;
+pub mod reload;
+pub mod results;
+pub mod rollup;
 pub mod router;
 pub mod settings;
+pub mod shard;
+pub mod sql;
+pub mod snapshot;
+pub mod storage;
+pub mod tasks;
+pub mod template;
+pub mod wal;
+pub mod websocket;
@@ -0,0 +1,177 @@
+//! Tracks where the node is in its lifecycle, so it can be taken out of service
+//! without dropping requests or losing uncommitted documents.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use log::{error, info};
+use serde::Serialize;
+use tokio::timer::Interval;
+
+use crate::index::IndexCatalog;
+
+#[derive(Default)]
+pub struct Lifecycle {
+    clustered: bool,
+    catalog_loaded: AtomicBool,
+    registered: AtomicBool,
+    draining: AtomicBool,
+    drained: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+/// The outcome of draining the node
+#[derive(Serialize, Debug)]
+pub struct DrainSummary {
+    /// Requests still running when the drain timeout expired
+    pub in_flight: usize,
+    pub committed: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Marks a request as in flight for as long as it is alive
+pub struct InFlight(Arc<Lifecycle>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Lifecycle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A lifecycle for a node that also has to register itself with Consul before it is ready
+    pub fn clustered() -> Self {
+        Self {
+            clustered: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_catalog_loaded(&self) {
+        self.catalog_loaded.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_registered(&self) {
+        self.registered.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the node should be sent traffic: every index is loaded, the node is known to the
+    /// cluster if clustering is enabled, and it isn't being drained.
+    pub fn is_ready(&self) -> bool {
+        self.catalog_loaded.load(Ordering::SeqCst) && (!self.clustered || self.registered.load(Ordering::SeqCst)) && !self.is_draining()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Whether a drain has completed and the process can be stopped
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    pub fn start_request(lifecycle: &Arc<Lifecycle>) -> InFlight {
+        lifecycle.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(Arc::clone(lifecycle))
+    }
+
+    /// Stop taking new requests, wait up to `timeout` for in flight requests to finish,
+    /// then commit every index so nothing that was acknowledged is lost on shutdown.
+    pub fn drain(
+        lifecycle: &Arc<Lifecycle>,
+        catalog: Arc<RwLock<IndexCatalog>>,
+        timeout: Duration,
+    ) -> impl Future<Item = DrainSummary, Error = ()> {
+        if !lifecycle.draining.swap(true, Ordering::SeqCst) {
+            info!("Draining, waiting up to {:?} for {} requests", timeout, lifecycle.in_flight());
+        }
+
+        let waiting = Arc::clone(lifecycle);
+        let finished = Arc::clone(lifecycle);
+        let deadline = Instant::now() + timeout;
+
+        Interval::new_interval(Duration::from_millis(50))
+            .take_while(move |_| Ok(waiting.in_flight() > 0 && Instant::now() < deadline))
+            .for_each(|_| Ok(()))
+            .map_err(|e| error!("Error waiting for requests to drain: {:?}", e))
+            .and_then(move |_| {
+                let (committed, failed) = match catalog.read() {
+                    Ok(cat) => cat.commit_all(),
+                    Err(e) => {
+                        error!("Unable to acquire catalog to commit indexes: {}", e);
+                        (Vec::new(), Vec::new())
+                    }
+                };
+                let summary = DrainSummary {
+                    in_flight: finished.in_flight(),
+                    committed,
+                    failed,
+                };
+                info!("Drain complete: {:?}", summary);
+                finished.drained.store(true, Ordering::SeqCst);
+                Ok(summary)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_in_flight_tracking() {
+        let lifecycle = Arc::new(Lifecycle::new());
+        let first = Lifecycle::start_request(&lifecycle);
+        let second = Lifecycle::start_request(&lifecycle);
+        assert_eq!(lifecycle.in_flight(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(lifecycle.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_readiness() {
+        let standalone = Lifecycle::new();
+        assert!(!standalone.is_ready());
+        standalone.set_catalog_loaded();
+        assert!(standalone.is_ready());
+
+        let clustered = Lifecycle::clustered();
+        clustered.set_catalog_loaded();
+        assert!(!clustered.is_ready());
+        clustered.set_registered();
+        assert!(clustered.is_ready());
+        clustered.draining.store(true, Ordering::SeqCst);
+        assert!(!clustered.is_ready());
+    }
+
+    #[test]
+    fn test_drain_times_out() {
+        let mut rt = Runtime::new().unwrap();
+        let lifecycle = Arc::new(Lifecycle::new());
+        let catalog = create_test_catalog("test_index");
+        let _stuck = Lifecycle::start_request(&lifecycle);
+
+        let summary = rt
+            .block_on(Lifecycle::drain(&lifecycle, catalog, Duration::from_millis(100)))
+            .unwrap();
+
+        assert!(lifecycle.is_draining());
+        assert!(lifecycle.is_drained());
+        assert_eq!(summary.in_flight, 1);
+        assert_eq!(summary.committed, vec!["test_index".to_string()]);
+        rt.shutdown_now();
+    }
+}
@@ -0,0 +1,1571 @@
+//! Field types Tantivy has no type of its own for. They're declared in a schema like any other field, stored as a
+//! field of a type Tantivy does have, and recorded with the index's storage settings so documents and queries can be
+//! converted to what is stored whenever the index is opened.
+//!
+//! A `date` field is stored as milliseconds since the epoch in an i64 fast field, and its values can be given in any of
+//! the field's `formats`: `rfc3339`, `epoch_millis`, `epoch_second` or a strftime pattern such as `%Y-%m-%d %H:%M`.
+//!
+//! An `ip` field takes IPv4 and IPv6 addresses. Tantivy has no 128 bit integers, so addresses are indexed as raw terms of
+//! 32 hex digits, IPv4 ones mapped into IPv6, which sort in the order of the addresses and let a CIDR block such as
+//! `10.0.0.0/8` be searched as a range of terms.
+//!
+//! A `bytes` field takes base64 and is kept as it's given when stored. Tantivy's fast fields of bytes can't be stored,
+//! so a field that is both stored and fast has its bytes in a fast field of its own as well, named `<field>.fast`.
+//!
+//! A `geo_point` field takes a point as `{ "lat": 52.37, "lon": 4.89 }` or `"52.37,4.89"`. Its latitude and longitude
+//! are each scaled to 32 bits and interleaved, longitude first, into a u64 fast field, so the leading bits of a point
+//! are those of its geohash and the cells of a geohash grid are ranges of the stored values.
+//!
+//! A `dense_vector` field takes an array of `dims` numbers, such as the embedding of a text, kept as their 32 bit floats
+//! in a fast field of bytes for `knn` queries to compare with their own vector by the field's `similarity`. Like a
+//! `bytes` field, a stored one is kept as base64 text with its fast field named `<field>.fast`. Fields given `hnsw`
+//! parameters have a graph of their vectors built for each segment, which searches walk instead of comparing them all.
+//!
+//! A `rank_features` field takes an object of feature names and their positive weights, such as the signals of a
+//! document like its pagerank or the terms of a learned sparse representation of its text. They're kept in a fast field
+//! of bytes for `rank_feature` queries to score documents by, and like a `bytes` field a stored one is kept as base64
+//! text with its fast field named `<field>.fast`.
+//!
+//! A `nested` field holds objects whose `fields` have to match together. Each object is indexed as a hidden document
+//! of its own, with its fields named `<field>.<name>` and referring to the document it came from by an id, while the
+//! document keeps the objects as JSON text when the field is stored. Searches leave the hidden documents out, and a
+//! `nested` query finds the documents with an object its query matches.
+//!
+//! A `join` field relates documents of the same shard as parents and children. Each document gives its relation, the
+//! id its children refer to it by and the id of its parent, which are kept in hidden fields for `has_child` and
+//! `has_parent` queries to look documents up by.
+//!
+//! A field of any other type can have its values `copy_to` text fields as well, such as a catch-all field searched in
+//! place of many others, and any field can have a `null_value` it's given where a document gives it null. Fields that
+//! `ignore_malformed`, or all of an index's fields when it's created that way, drop the values that aren't of their
+//! type instead of failing the whole document.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::schema::{FieldType, NamedFieldDocument, Schema, Value as FieldValue};
+use tantivy::Document;
+use uuid::Uuid;
+
+use crate::analysis;
+use crate::query::{HnswParams, Query, Similarity};
+use crate::{Error, Result};
+
+/// The most numbers a dense vector can have
+pub const MAX_DIMS: usize = 4096;
+
+/// The formats of a date field that doesn't list its own
+pub const DEFAULT_DATE_FORMATS: [&str; 2] = ["rfc3339", "epoch_millis"];
+
+/// The id a document with nested fields is given, for the documents of its objects to refer to it by
+pub const NESTED_ID: &str = "_nested_id";
+/// The id of the document a nested object came from
+pub const NESTED_PARENT: &str = "_nested_parent";
+/// The nested field an object was given in, which marks the documents of objects
+pub const NESTED_PATH: &str = "_nested_path";
+
+/// The relation a document has in the join field of its index
+pub const JOIN_RELATION: &str = "_join_relation";
+/// The id a document with children is referred to by
+pub const JOIN_ID: &str = "_join_id";
+/// The id of a child document's parent
+pub const JOIN_PARENT: &str = "_join_parent";
+
+/// How the values documents give a field are turned into what's stored
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct FieldMapping {
+    /// The type of the field, when it's one Tantivy has no type of its own for
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MappedType>,
+    /// The text fields its values are indexed in too
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copy_to: Vec<String>,
+    /// What the field is given in place of null
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub null_value: Option<Value>,
+    /// Whether values that aren't of the field's type are dropped instead of rejecting the document, when the field
+    /// says so rather than following its index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_malformed: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MappedType {
+    /// Milliseconds since the epoch, given in the first of `formats` a value matches
+    Date { formats: Vec<String> },
+    /// An IPv4 or IPv6 address
+    Ip,
+    /// A latitude and longitude
+    #[serde(rename = "geo_point")]
+    GeoPoint,
+    /// Opaque bytes in base64, kept as text when stored and as bytes when fast
+    Bytes { stored: bool, fast: bool },
+    /// A vector of `dims` numbers, compared with others by `similarity` and searched by a graph built with `hnsw`
+    /// when it's given
+    #[serde(rename = "dense_vector")]
+    DenseVector {
+        dims: usize,
+        similarity: Similarity,
+        stored: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hnsw: Option<HnswParams>,
+    },
+    /// Feature names and their positive weights
+    #[serde(rename = "rank_features")]
+    RankFeatures { stored: bool },
+    /// Objects indexed as documents of their own, with the names of their `fields`
+    Nested { fields: Vec<String> },
+    /// The relation a document has to others, from the parent and child `relations` it can have
+    Join { relations: Relations },
+}
+
+/// The mapped fields of an index by name
+pub type Mappings = BTreeMap<String, FieldMapping>;
+
+/// The child relations of each parent relation of a join field
+pub type Relations = BTreeMap<String, Vec<String>>;
+
+impl From<MappedType> for FieldMapping {
+    fn from(kind: MappedType) -> Self {
+        FieldMapping {
+            kind: Some(kind),
+            ..FieldMapping::default()
+        }
+    }
+}
+
+impl FieldMapping {
+    /// What `value` of a field with this mapping is stored as
+    pub fn convert(&self, value: &Value) -> Result<Value> {
+        match self.kind {
+            Some(ref kind) => kind.convert(value),
+            None => Ok(value.clone()),
+        }
+    }
+
+    pub fn is_nested(&self) -> bool {
+        match self.kind {
+            Some(MappedType::Nested { .. }) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_join(&self) -> bool {
+        match self.kind {
+            Some(MappedType::Join { .. }) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_date(&self) -> bool {
+        match self.kind {
+            Some(MappedType::Date { .. }) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_geo_point(&self) -> bool {
+        match self.kind {
+            Some(MappedType::GeoPoint) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether each value of an array given to the field is converted on its own, rather than the array being one value
+    pub fn converts_arrays(&self) -> bool {
+        match self.kind {
+            Some(MappedType::DenseVector { .. }) | Some(MappedType::RankFeatures { .. }) => false,
+            _ => true,
+        }
+    }
+
+    /// The fast field a field named `name` with this mapping keeps its values in too, if any
+    pub fn fast_field(&self, name: &str) -> Option<String> {
+        match self.kind {
+            Some(MappedType::Bytes { stored: true, fast: true })
+            | Some(MappedType::DenseVector { stored: true, .. })
+            | Some(MappedType::RankFeatures { stored: true }) => Some(format!("{}.fast", name)),
+            _ => None,
+        }
+    }
+
+    /// How a stored `value` of a field with this mapping is shown in results, if not as it's stored
+    pub fn display(&self, value: &FieldValue) -> Option<FieldValue> {
+        match (&self.kind, value) {
+            (Some(MappedType::Ip), FieldValue::Str(term)) => ip_address(term).map(FieldValue::Str),
+            (Some(MappedType::GeoPoint), FieldValue::U64(bits)) => {
+                let (lat, lon) = geo_decode(*bits);
+                Some(FieldValue::Str(format!("{:.6},{:.6}", lat, lon)))
+            }
+            (Some(MappedType::DenseVector { .. }), FieldValue::Str(bytes)) => {
+                let vector = vector_from_bytes(&base64::decode(bytes).ok()?);
+                Some(FieldValue::Str(serde_json::to_string(&vector).ok()?))
+            }
+            (Some(MappedType::RankFeatures { .. }), FieldValue::Str(bytes)) => {
+                let features: BTreeMap<String, f32> = features_from_bytes(&base64::decode(bytes).ok()?).into_iter().collect();
+                Some(FieldValue::Str(serde_json::to_string(&features).ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl MappedType {
+    fn convert(&self, value: &Value) -> Result<Value> {
+        match self {
+            MappedType::Date { formats } => parse_date(value, formats).map(Value::from),
+            MappedType::Ip => match value.as_str() {
+                Some(address) => Ok(Value::String(ip_term(ip_bits(address)?))),
+                None => Err(Error::QueryError(format!("{} is not an IP address", value))),
+            },
+            MappedType::GeoPoint => geo_point(value).map(|(lat, lon)| Value::from(geo_encode(lat, lon))),
+            MappedType::Bytes { .. } => match value.as_str() {
+                Some(bytes) if base64::decode(bytes).is_ok() => Ok(value.clone()),
+                _ => Err(Error::QueryError(format!("{} is not base64", value))),
+            },
+            MappedType::DenseVector { dims, similarity, .. } => {
+                let vector = dense_vector(value, *dims, *similarity)?;
+                Ok(Value::String(base64::encode(&vector_bytes(&vector))))
+            }
+            MappedType::RankFeatures { .. } => Ok(Value::String(base64::encode(&features_bytes(&rank_features(value)?)))),
+            MappedType::Nested { .. } if value.is_object() => Ok(Value::String(value.to_string())),
+            MappedType::Nested { .. } => Err(Error::QueryError(format!("{} is not an object", value))),
+            MappedType::Join { .. } => match value.get("name").unwrap_or(value) {
+                Value::String(relation) => Ok(Value::String(relation.clone())),
+                _ => Err(Error::QueryError(format!("{} has no relation", value))),
+            },
+        }
+    }
+}
+
+/// Turn the mapped fields of a schema in JSON into the fields they're stored as, returning their mappings
+pub fn apply_mappings(schema: &mut Value) -> Result<Mappings> {
+    let mut mappings = Mappings::new();
+    let fields = match schema.as_array_mut() {
+        Some(fields) => fields,
+        None => return Ok(mappings),
+    };
+    // Nested fields become their objects' fields first, so those can be of mapped types too
+    if apply_nested(fields, &mut mappings)? {
+        fields.push(hidden_field(NESTED_ID, false));
+        fields.push(hidden_field(NESTED_PARENT, true));
+        fields.push(hidden_field(NESTED_PATH, false));
+    }
+    let mut added_fields = Vec::new();
+    for field in fields.iter_mut().filter_map(Value::as_object_mut) {
+        let name = field.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+        let mut mapping = FieldMapping::default();
+        match field.get("type").and_then(Value::as_str) {
+            Some(kind @ "date")
+            | Some(kind @ "ip")
+            | Some(kind @ "geo_point")
+            | Some(kind @ "bytes")
+            | Some(kind @ "dense_vector")
+            | Some(kind @ "rank_features")
+            | Some(kind @ "join") => {
+                if name.is_empty() {
+                    return Err(Error::QueryError(format!("A {} field has no name", kind)));
+                }
+                let kind = kind.to_string();
+                mapping.kind = Some(apply_type(field, &kind, &name, &mappings, &mut added_fields)?);
+            }
+            _ => {}
+        }
+        if let Some(copy_to) = field.remove("copy_to") {
+            if mapping.kind.is_some() {
+                return Err(Error::QueryError(format!("Field {} can't be copied to other fields", name)));
+            }
+            mapping.copy_to = copy_targets(&name, copy_to)?;
+        }
+        if let Some(null_value) = field.remove("null_value").filter(|v| !v.is_null()) {
+            mapping.convert(&null_value)?;
+            mapping.null_value = Some(null_value);
+        }
+        if let Some(ignore) = field.remove("ignore_malformed") {
+            match ignore.as_bool() {
+                Some(_) if mapping.is_join() => {
+                    return Err(Error::QueryError(format!("Join field {} can't ignore malformed values", name)))
+                }
+                Some(ignore) => mapping.ignore_malformed = Some(ignore),
+                None => return Err(Error::QueryError(format!("ignore_malformed {} is not true or false", ignore))),
+            }
+        }
+        if mapping != FieldMapping::default() {
+            mappings.insert(name, mapping);
+        }
+    }
+    fields.extend(added_fields);
+    for (name, mapping) in &mappings {
+        for target in &mapping.copy_to {
+            let is_text = fields
+                .iter()
+                .any(|field| field["name"] == target.as_str() && field["type"] == "text");
+            if !is_text || target == name {
+                return Err(Error::QueryError(format!(
+                    "Field {} can't be copied to {}, which isn't another text field",
+                    name, target
+                )));
+            }
+        }
+    }
+    Ok(mappings)
+}
+
+/// Turn `field` of a type Tantivy doesn't have into the field it's stored as, adding any fields it needs besides
+fn apply_type(
+    field: &mut serde_json::Map<String, Value>,
+    kind: &str,
+    name: &str,
+    mappings: &Mappings,
+    added_fields: &mut Vec<Value>,
+) -> Result<MappedType> {
+    let options = field.remove("options").unwrap_or_default();
+    let stored = options.get("stored").and_then(Value::as_bool).unwrap_or(false);
+    let indexed = options.get("indexed").and_then(Value::as_bool).unwrap_or(true);
+    let (stored_type, stored_options, kind) = match kind {
+        "date" => {
+            let formats = date_formats(name, field.remove("formats"))?;
+            // Fields whose documents have several dates keep all of them with `"fast": "multi"`
+            let fast = match options.get("fast").and_then(Value::as_str) {
+                Some("multi") => "multi",
+                _ => "single",
+            };
+            let options = serde_json::json!({ "indexed": indexed, "fast": fast, "stored": stored });
+            ("i64", Some(options), MappedType::Date { formats })
+        }
+        "geo_point" => {
+            // Points are kept as many to a document, so documents without any have none rather than a point of 0
+            let options = serde_json::json!({ "indexed": indexed, "fast": "multi", "stored": stored });
+            ("u64", Some(options), MappedType::GeoPoint)
+        }
+        "ip" => {
+            let indexing = if indexed {
+                serde_json::json!({ "record": "basic", "tokenizer": "raw" })
+            } else {
+                Value::Null
+            };
+            (
+                "text",
+                Some(serde_json::json!({ "indexing": indexing, "stored": stored })),
+                MappedType::Ip,
+            )
+        }
+        "join" => {
+            if mappings.values().any(FieldMapping::is_join) {
+                return Err(Error::QueryError(format!("Join field {} is not the only one", name)));
+            }
+            let relations = join_relations(name, field.remove("relations"))?;
+            added_fields.push(hidden_field(JOIN_RELATION, false));
+            added_fields.push(hidden_field(JOIN_ID, true));
+            added_fields.push(hidden_field(JOIN_PARENT, true));
+            let options = serde_json::json!({ "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": stored });
+            ("text", Some(options), MappedType::Join { relations })
+        }
+        "dense_vector" => {
+            let dims = match field.remove("dims").and_then(|d| d.as_u64()) {
+                Some(dims) if dims > 0 && dims <= MAX_DIMS as u64 => dims as usize,
+                _ => {
+                    return Err(Error::QueryError(format!(
+                        "Dense vector field {} needs dims from 1 to {}",
+                        name, MAX_DIMS
+                    )))
+                }
+            };
+            let similarity = match field.remove("similarity") {
+                Some(similarity) => serde_json::from_value(similarity.clone())
+                    .map_err(|_| Error::QueryError(format!("{} is not cosine, dot_product or l2_norm", similarity)))?,
+                None => Similarity::default(),
+            };
+            let hnsw = match field.remove("hnsw") {
+                Some(hnsw) => match serde_json::from_value::<HnswParams>(hnsw.clone()) {
+                    Ok(params) if params.m >= 2 && params.ef_construction >= params.m => Some(params),
+                    _ => {
+                        return Err(Error::QueryError(format!(
+                            "{} needs an m of at least 2 and an ef_construction of at least m",
+                            hnsw
+                        )))
+                    }
+                },
+                None => None,
+            };
+            let kind = MappedType::DenseVector {
+                dims,
+                similarity,
+                stored,
+                hnsw,
+            };
+            if let Some(fast_field) = FieldMapping::from(kind.clone()).fast_field(name) {
+                added_fields.push(serde_json::json!({ "name": fast_field, "type": "bytes" }));
+            }
+            if stored {
+                ("text", Some(serde_json::json!({ "indexing": null, "stored": true })), kind)
+            } else {
+                ("bytes", None, kind)
+            }
+        }
+        "rank_features" => {
+            let kind = MappedType::RankFeatures { stored };
+            if let Some(fast_field) = FieldMapping::from(kind.clone()).fast_field(name) {
+                added_fields.push(serde_json::json!({ "name": fast_field, "type": "bytes" }));
+            }
+            if stored {
+                ("text", Some(serde_json::json!({ "indexing": null, "stored": true })), kind)
+            } else {
+                ("bytes", None, kind)
+            }
+        }
+        _ => {
+            let fast = options.get("fast").and_then(Value::as_bool).unwrap_or(false);
+            let kind = MappedType::Bytes { stored, fast };
+            if let Some(fast_field) = FieldMapping::from(kind.clone()).fast_field(name) {
+                added_fields.push(serde_json::json!({ "name": fast_field, "type": "bytes" }));
+            }
+            match (stored, fast) {
+                (true, _) => ("text", Some(serde_json::json!({ "indexing": null, "stored": true })), kind),
+                (false, true) => ("bytes", None, kind),
+                (false, false) => return Err(Error::QueryError(format!("Bytes field {} is neither stored nor fast", name))),
+            }
+        }
+    };
+    field.insert("type".into(), stored_type.into());
+    if let Some(options) = stored_options {
+        field.insert("options".into(), options);
+    }
+    Ok(kind)
+}
+
+/// The fields a field's `copy_to` names, either one of them or a list
+fn copy_targets(name: &str, copy_to: Value) -> Result<Vec<String>> {
+    match copy_to {
+        Value::String(target) => Ok(vec![target]),
+        Value::Array(targets) => targets
+            .iter()
+            .map(|t| t.as_str().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| Error::QueryError(format!("Fields {} is copied to are not names", name))),
+        other => Err(Error::QueryError(format!("copy_to {} is neither a field nor a list", other))),
+    }
+}
+
+/// A raw text field relating documents to each other, which results show only when it's stored
+fn hidden_field(name: &str, stored: bool) -> Value {
+    let indexing = serde_json::json!({ "record": "basic", "tokenizer": "raw" });
+    serde_json::json!({ "name": name, "type": "text", "options": { "indexing": indexing, "stored": stored } })
+}
+
+/// The child relations of each parent relation of a join field, given a relation or a list of them each
+fn join_relations(name: &str, relations: Option<Value>) -> Result<Relations> {
+    let relations = match relations {
+        Some(Value::Object(relations)) if !relations.is_empty() => relations,
+        _ => return Err(Error::QueryError(format!("Join field {} has no relations", name))),
+    };
+    relations
+        .into_iter()
+        .map(|(parent, children)| {
+            let children = match children {
+                Value::String(child) => vec![child],
+                Value::Array(children) => children
+                    .iter()
+                    .map(|c| c.as_str().map(str::to_string))
+                    .collect::<Option<Vec<String>>>()
+                    .ok_or_else(|| Error::QueryError(format!("Relations of {} are not names", parent)))?,
+                other => return Err(Error::QueryError(format!("Relations {} are neither a name nor a list", other))),
+            };
+            Ok((parent, children))
+        })
+        .collect()
+}
+
+/// Replace each nested field of `fields` with the stored field of its objects and the fields the objects are indexed
+/// with, returning whether there were any
+fn apply_nested(fields: &mut Vec<Value>, mappings: &mut Mappings) -> Result<bool> {
+    let mut nested = false;
+    let mut applied = Vec::with_capacity(fields.len());
+    for field in fields.drain(..) {
+        if field.get("type").and_then(Value::as_str) != Some("nested") {
+            applied.push(field);
+            continue;
+        }
+        let name = match field.get("name").and_then(Value::as_str) {
+            Some(name) => name.to_string(),
+            None => return Err(Error::QueryError("A nested field has no name".into())),
+        };
+        let mut object_fields = match field.get("fields") {
+            Some(Value::Array(object_fields)) if !object_fields.is_empty() => Value::Array(object_fields.clone()),
+            _ => return Err(Error::QueryError(format!("Nested field {} has no fields", name))),
+        };
+        analysis::apply_analyzers(&mut object_fields)?;
+        let stored = field.pointer("/options/stored").and_then(Value::as_bool).unwrap_or(false);
+        applied.push(serde_json::json!({ "name": name, "type": "text", "options": { "indexing": null, "stored": stored } }));
+
+        let mut names = Vec::new();
+        let object_fields = match object_fields {
+            Value::Array(object_fields) => object_fields,
+            _ => Vec::new(),
+        };
+        for mut object_field in object_fields {
+            let object_name = match (
+                object_field.get("name").and_then(Value::as_str),
+                object_field.get("type").and_then(Value::as_str),
+            ) {
+                (_, Some("nested")) => return Err(Error::QueryError(format!("Nested field {} has a nested field", name))),
+                (Some(object_name), _) => object_name.to_string(),
+                (None, _) => return Err(Error::QueryError(format!("A field of nested field {} has no name", name))),
+            };
+            object_field["name"] = Value::String(format!("{}.{}", name, object_name));
+            applied.push(object_field);
+            names.push(object_name);
+        }
+        mappings.insert(name, MappedType::Nested { fields: names }.into());
+        nested = true;
+    }
+    *fields = applied;
+    Ok(nested)
+}
+
+fn date_formats(name: &str, formats: Option<Value>) -> Result<Vec<String>> {
+    let formats = match formats {
+        None => DEFAULT_DATE_FORMATS.iter().map(|f| f.to_string()).collect(),
+        Some(Value::String(format)) => vec![format],
+        Some(Value::Array(formats)) => formats
+            .iter()
+            .map(|f| f.as_str().map(str::to_string))
+            .collect::<Option<Vec<String>>>()
+            .ok_or_else(|| Error::QueryError(format!("Date formats of {} are not strings", name)))?,
+        Some(other) => return Err(Error::QueryError(format!("Date formats {} are neither a format nor a list", other))),
+    };
+    if formats.is_empty() {
+        return Err(Error::QueryError(format!("Date field {} has no formats", name)));
+    }
+    match formats.iter().find(|f| !is_date_format(f)) {
+        Some(format) => Err(Error::QueryError(format!("{} is not a date format", format))),
+        None => Ok(formats),
+    }
+}
+
+fn is_date_format(format: &str) -> bool {
+    match format {
+        "rfc3339" | "epoch_millis" | "epoch_second" => true,
+        pattern => pattern.contains('%'),
+    }
+}
+
+/// Have every field of `schema` that doesn't say otherwise drop malformed values, for an index that ignores them
+pub fn ignore_malformed(schema: &Schema, mappings: &mut Mappings) {
+    let hidden = [NESTED_ID, NESTED_PARENT, NESTED_PATH, JOIN_RELATION, JOIN_ID, JOIN_PARENT];
+    let fast_fields: Vec<String> = mappings.iter().filter_map(|(name, m)| m.fast_field(name)).collect();
+    for entry in schema.fields() {
+        let name = entry.name();
+        if hidden.contains(&name) || fast_fields.iter().any(|f| f == name) {
+            continue;
+        }
+        let mapping = mappings.entry(name.to_string()).or_insert_with(FieldMapping::default);
+        if mapping.ignore_malformed.is_none() && !mapping.is_nested() && !mapping.is_join() {
+            mapping.ignore_malformed = Some(true);
+        }
+    }
+}
+
+/// Parse the JSON document `text` with the values of its mapped fields converted to what they're stored as
+pub fn parse_document(schema: &Schema, mappings: &Mappings, text: &str) -> Result<Document> {
+    if mappings.is_empty() {
+        return schema.parse_document(text).map_err(Error::from);
+    }
+    let mut document: Value = serde_json::from_str(text)?;
+    convert_document(schema, mappings, &mut document)?;
+    schema.parse_document(&document.to_string()).map_err(Error::from)
+}
+
+/// Parse the JSON document `text` like `parse_document`, preceded by the hidden documents its nested objects are
+/// indexed as
+pub fn parse_documents(schema: &Schema, mappings: &Mappings, text: &str) -> Result<Vec<Document>> {
+    if !mappings.values().any(FieldMapping::is_nested) {
+        return Ok(vec![parse_document(schema, mappings, text)?]);
+    }
+    let mut document: Value = serde_json::from_str(text)?;
+    let mut documents = nested_documents(mappings, &mut document);
+    documents.push(document);
+    documents
+        .into_iter()
+        .map(|mut document| {
+            convert_document(schema, mappings, &mut document)?;
+            schema.parse_document(&document.to_string()).map_err(Error::from)
+        })
+        .collect()
+}
+
+fn convert_document(schema: &Schema, mappings: &Mappings, document: &mut Value) -> Result<()> {
+    if let Some(fields) = document.as_object_mut() {
+        for (name, mapping) in mappings {
+            if let (Some(null_value), Some(value)) = (&mapping.null_value, fields.get_mut(name)) {
+                replace_nulls(value, null_value);
+            }
+            if mapping.ignore_malformed == Some(true) {
+                drop_malformed(schema, name, mapping, fields);
+            }
+            if let (Some(MappedType::Join { relations }), Some(value)) = (&mapping.kind, fields.get(name)) {
+                let related = join_fields(relations, value)?;
+                fields.extend(related);
+            }
+            if let Some(value) = fields.get(name).filter(|_| !mapping.copy_to.is_empty()).cloned() {
+                copy_values(fields, &mapping.copy_to, value);
+            }
+            if let Some(value) = fields.get_mut(name) {
+                *value = match value {
+                    Value::Array(values) if mapping.converts_arrays() => {
+                        Value::Array(values.iter().map(|v| mapping.convert(v)).collect::<Result<_>>()?)
+                    }
+                    value => mapping.convert(value)?,
+                };
+            }
+            if let (Some(fast_field), Some(value)) = (mapping.fast_field(name), fields.get(name).cloned()) {
+                fields.insert(fast_field, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Leave out the values of the field `name` that it can't be given, and the field itself if none are left
+fn drop_malformed(schema: &Schema, name: &str, mapping: &FieldMapping, fields: &mut serde_json::Map<String, Value>) {
+    let is_malformed = |value: &Value| -> bool {
+        if mapping.kind.is_some() {
+            return mapping.convert(value).is_err();
+        }
+        let field = match schema.get_field(name) {
+            Some(field) => field,
+            None => return false,
+        };
+        match schema.get_field_entry(field).field_type() {
+            FieldType::U64(_) => value.as_u64().is_none(),
+            FieldType::I64(_) => value.as_i64().is_none(),
+            FieldType::Bytes => value.as_str().map_or(true, |bytes| base64::decode(bytes).is_err()),
+            _ => !value.is_string(),
+        }
+    };
+    let keep = match fields.get_mut(name) {
+        Some(Value::Array(values)) if mapping.converts_arrays() => {
+            values.retain(|v| !is_malformed(v));
+            !values.is_empty()
+        }
+        Some(value) => !is_malformed(value),
+        None => true,
+    };
+    if !keep {
+        fields.remove(name);
+    }
+}
+
+/// Give a field `null_value` where its value is null, or any of its values are
+fn replace_nulls(value: &mut Value, null_value: &Value) {
+    match value {
+        Value::Null => *value = null_value.clone(),
+        Value::Array(values) => {
+            for value in values.iter_mut().filter(|v| v.is_null()) {
+                *value = null_value.clone();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Add the values of a field to the text fields it's copied to, after any they were given themselves
+fn copy_values(fields: &mut serde_json::Map<String, Value>, targets: &[String], value: Value) {
+    let values = match value {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+    for target in targets {
+        let copied = fields.entry(target.clone()).or_insert_with(|| Value::Array(Vec::new()));
+        if !copied.is_array() {
+            *copied = Value::Array(vec![copied.clone()]);
+        }
+        if let Value::Array(copied) = copied {
+            copied.extend(values.iter().filter(|v| !v.is_null()).map(|v| match v {
+                Value::String(text) => Value::String(text.clone()),
+                other => Value::String(other.to_string()),
+            }));
+        }
+    }
+}
+
+/// The documents the objects of the nested fields of `document` are indexed as, after giving `document` the id they
+/// refer to it by
+fn nested_documents(mappings: &Mappings, document: &mut Value) -> Vec<Value> {
+    let fields = match document.as_object_mut() {
+        Some(fields) => fields,
+        None => return Vec::new(),
+    };
+    let id = Uuid::new_v4().to_simple().to_string();
+    let mut documents = Vec::new();
+    for (path, mapping) in mappings {
+        let (names, objects) = match (&mapping.kind, fields.get(path)) {
+            (Some(MappedType::Nested { fields: names }), Some(Value::Array(objects))) => (names, objects.iter().collect()),
+            (Some(MappedType::Nested { fields: names }), Some(object)) => (names, vec![object]),
+            _ => continue,
+        };
+        // Anything but an object is refused once the document itself is converted
+        for object in objects.into_iter().filter_map(Value::as_object) {
+            let mut nested = serde_json::Map::new();
+            for name in names {
+                if let Some(value) = object.get(name) {
+                    nested.insert(format!("{}.{}", path, name), value.clone());
+                }
+            }
+            nested.insert(NESTED_PARENT.into(), Value::String(id.clone()));
+            nested.insert(NESTED_PATH.into(), Value::String(path.clone()));
+            documents.push(Value::Object(nested));
+        }
+    }
+    fields.insert(NESTED_ID.into(), Value::String(id));
+    documents
+}
+
+/// The hidden fields that relate a document to others by the `value` of its join field, either the name of its
+/// relation or `{ "name": ..., "id": ..., "parent": ... }` with the id children refer to it by and the id of its parent
+fn join_fields(relations: &Relations, value: &Value) -> Result<serde_json::Map<String, Value>> {
+    let relation = match value.get("name").unwrap_or(value) {
+        Value::String(relation) => relation,
+        _ => return Err(Error::QueryError(format!("{} has no relation", value))),
+    };
+    let key = |key: &Value| match key {
+        Value::String(key) => Value::String(key.clone()),
+        other => Value::String(other.to_string()),
+    };
+    let has_children = relations.contains_key(relation);
+    let has_parent = relations.values().any(|children| children.contains(relation));
+    if !has_children && !has_parent {
+        return Err(Error::QueryError(format!("{} is not a relation of the join field", relation)));
+    }
+
+    let mut fields = serde_json::Map::new();
+    fields.insert(JOIN_RELATION.into(), Value::String(relation.clone()));
+    match value.get("id") {
+        Some(id) => {
+            fields.insert(JOIN_ID.into(), key(id));
+        }
+        None if has_children => return Err(Error::QueryError(format!("A {} needs an id for its children", relation))),
+        None => {}
+    }
+    match value.get("parent") {
+        Some(parent) if has_parent => {
+            fields.insert(JOIN_PARENT.into(), key(parent));
+        }
+        Some(_) => return Err(Error::QueryError(format!("A {} has no parent", relation))),
+        None if has_parent => return Err(Error::QueryError(format!("A {} needs the id of its parent", relation))),
+        None => {}
+    }
+    Ok(fields)
+}
+
+/// `query` with the values its `range` and `term` clauses give mapped fields converted to what they're stored as, and
+/// its `knn` and `rank_feature` clauses checked against their fields
+pub fn convert_query(mappings: &Mappings, query: Query) -> Result<Query> {
+    if mappings.is_empty() {
+        return Ok(query);
+    }
+    // Clauses are found the same way however deeply they're nested in bool queries, by way of JSON
+    let mut value = serde_json::to_value(&query)?;
+    convert_clauses(mappings, &mut value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn convert_clauses(mappings: &Mappings, value: &mut Value) -> Result<()> {
+    match value {
+        Value::Object(object) => {
+            if let Some(range) = cidr_clause(mappings, object)? {
+                *object = range;
+                return Ok(());
+            }
+            for (key, value) in object.iter_mut() {
+                match (key.as_str(), value) {
+                    ("range", Value::Object(ranges)) => {
+                        for (field, bounds) in ranges.iter_mut() {
+                            if let Some(mapping) = mappings.get(field) {
+                                for bound in ["gt", "gte", "lt", "lte"].iter() {
+                                    if let Some(bound) = bounds.get_mut(*bound).filter(|b| !b.is_null()) {
+                                        *bound = mapping.convert(bound)?;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    ("term", Value::Object(terms)) => {
+                        for (field, term) in terms.iter_mut() {
+                            if let Some(mapping) = mappings.get(field) {
+                                *term = match mapping.convert(term)? {
+                                    Value::String(term) => Value::String(term),
+                                    other => Value::String(other.to_string()),
+                                };
+                            }
+                        }
+                    }
+                    ("gauss", Value::Object(decays)) | ("exp", Value::Object(decays)) | ("linear", Value::Object(decays)) => {
+                        for (field, decay) in decays.iter_mut() {
+                            if let Some(mapping) = mappings.get(field) {
+                                // `now` is left for the query to take the time of when it's built
+                                if let Some(origin) = decay.get_mut("origin").filter(|o| o.as_str() != Some("now")) {
+                                    *origin = mapping.convert(origin)?;
+                                }
+                            }
+                        }
+                    }
+                    ("rank_feature", Value::Object(feature)) => {
+                        let field = feature.get("field").and_then(Value::as_str).unwrap_or_default();
+                        match mappings.get(field).and_then(|m| m.kind.as_ref()) {
+                            Some(MappedType::RankFeatures { .. }) | None => {}
+                            Some(_) => return Err(Error::QueryError(format!("Field {} is not a rank features field", field))),
+                        }
+                    }
+                    ("knn", Value::Object(knn)) => {
+                        knn_clause(mappings, knn)?;
+                        if let Some(filter) = knn.get_mut("filter") {
+                            convert_clauses(mappings, filter)?;
+                        }
+                    }
+                    (_, value) => convert_clauses(mappings, value)?,
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                convert_clauses(mappings, value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Check that a `knn` clause gives a vector as long as its field's, comparing them by the field's similarity unless it
+/// says otherwise
+fn knn_clause(mappings: &Mappings, knn: &mut serde_json::Map<String, Value>) -> Result<()> {
+    let field = knn.get("field").and_then(Value::as_str).unwrap_or_default().to_string();
+    let (dims, similarity) = match mappings.get(&field).and_then(|m| m.kind.as_ref()) {
+        Some(MappedType::DenseVector { dims, similarity, .. }) => (*dims, *similarity),
+        None => return Ok(()),
+        Some(_) => return Err(Error::QueryError(format!("Field {} is not a dense vector field", field))),
+    };
+    let given = knn.get("vector").and_then(Value::as_array).map_or(0, Vec::len);
+    if given != dims {
+        return Err(Error::QueryError(format!(
+            "The knn query of {} gives {} numbers rather than {}",
+            field, given, dims
+        )));
+    }
+    if knn.get("similarity").map_or(true, Value::is_null) {
+        knn.insert("similarity".into(), serde_json::to_value(similarity)?);
+    }
+    Ok(())
+}
+
+/// A `term` clause of an ip field given a CIDR block, as the `range` clause of the addresses in the block
+fn cidr_clause(mappings: &Mappings, clause: &serde_json::Map<String, Value>) -> Result<Option<serde_json::Map<String, Value>>> {
+    let terms = match clause.get("term").and_then(Value::as_object) {
+        Some(terms) => terms,
+        None => return Ok(None),
+    };
+    for (field, term) in terms {
+        if let (Some(MappedType::Ip), Some(cidr)) = (mappings.get(field).and_then(|m| m.kind.as_ref()), term.as_str()) {
+            if cidr.contains('/') {
+                let (first, last) = cidr_range(cidr)?;
+                let mut range = serde_json::Map::new();
+                range.insert(field.clone(), serde_json::json!({ "gte": ip_term(first), "lte": ip_term(last) }));
+                let mut clause = serde_json::Map::new();
+                clause.insert("range".into(), Value::Object(range));
+                return Ok(Some(clause));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Show the values of the mapped fields of `doc` as they were given rather than as they're stored
+pub fn display_document(mappings: &Mappings, mut doc: NamedFieldDocument) -> NamedFieldDocument {
+    for (name, mapping) in mappings {
+        if let Some(values) = doc.0.get_mut(name) {
+            for value in values.iter_mut() {
+                if let Some(shown) = mapping.display(value) {
+                    *value = shown;
+                }
+            }
+        }
+    }
+    doc
+}
+
+/// `address` as a 128 bit number, with IPv4 addresses mapped into IPv6 so both kinds sort together
+fn ip_bits(address: &str) -> Result<u128> {
+    match address.trim().parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => Ok(u128::from(address.to_ipv6_mapped())),
+        Ok(IpAddr::V6(address)) => Ok(u128::from(address)),
+        Err(_) => Err(Error::QueryError(format!("{} is not an IP address", address))),
+    }
+}
+
+fn ip_term(bits: u128) -> String {
+    format!("{:032x}", bits)
+}
+
+/// The address an indexed term stands for, shown as IPv4 when it was mapped from it
+fn ip_address(term: &str) -> Option<String> {
+    let address = Ipv6Addr::from(u128::from_str_radix(term, 16).ok()?);
+    let segments = address.segments();
+    if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+        let octets = address.octets();
+        return Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]).to_string());
+    }
+    Some(address.to_string())
+}
+
+/// The first and last address of the CIDR block `cidr`, such as `10.0.0.0/8` or `2001:db8::/32`
+pub fn cidr_range(cidr: &str) -> Result<(u128, u128)> {
+    let slash = cidr.find('/').unwrap_or_else(|| cidr.len());
+    let (address, prefix) = (&cidr[..slash], cidr[slash..].trim_start_matches('/'));
+    let bits = ip_bits(address)?;
+    let width = if address.contains(':') { 128 } else { 32 };
+    let prefix = match prefix.parse::<u32>() {
+        Ok(prefix) if prefix <= width => prefix + 128 - width,
+        _ => return Err(Error::QueryError(format!("{} is not a CIDR block", cidr))),
+    };
+    let host = u128::max_value().checked_shr(prefix).unwrap_or(0);
+    Ok((bits & !host, bits | host))
+}
+
+/// The latitude and longitude of `value`, given as `{ "lat": ..., "lon": ... }` or `"lat,lon"`
+pub fn geo_point(value: &Value) -> Result<(f64, f64)> {
+    let point = match value {
+        Value::Object(point) => match (point.get("lat").and_then(Value::as_f64), point.get("lon").and_then(Value::as_f64)) {
+            (Some(lat), Some(lon)) => Some((lat, lon)),
+            _ => None,
+        },
+        Value::String(point) => {
+            let mut parts = point.splitn(2, ',').map(|p| p.trim().parse::<f64>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(lat)), Some(Ok(lon))) => Some((lat, lon)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    match point {
+        Some((lat, lon)) if lat >= -90.0 && lat <= 90.0 && lon >= -180.0 && lon <= 180.0 => Ok((lat, lon)),
+        _ => Err(Error::QueryError(format!("{} is not a geo point", value))),
+    }
+}
+
+/// A point as the u64 it's stored as, the bits of its longitude and latitude interleaved with longitude first
+pub fn geo_encode(lat: f64, lon: f64) -> u64 {
+    let scale = |value: f64, range: f64| ((value / range + 0.5) * 4_294_967_296.0).min(4_294_967_295.0) as u64;
+    let (lat, lon) = (scale(lat, 180.0), scale(lon, 360.0));
+    (0..32).fold(0, |bits, i| bits | ((lon >> i) & 1) << (2 * i + 1) | ((lat >> i) & 1) << (2 * i))
+}
+
+/// The middle of the smallest cell a stored point can be in, as its latitude and longitude
+pub fn geo_decode(bits: u64) -> (f64, f64) {
+    let (lat, lon) = (0..32).fold((0u64, 0u64), |(lat, lon), i| {
+        (lat | ((bits >> (2 * i)) & 1) << i, lon | ((bits >> (2 * i + 1)) & 1) << i)
+    });
+    let unscale = |value: u64, range: f64| ((value as f64 + 0.5) / 4_294_967_296.0 - 0.5) * range;
+    (unscale(lat, 180.0), unscale(lon, 360.0))
+}
+
+/// The geohash of `precision` characters of the cell a stored point is in, from 1 to 12
+pub fn geohash(bits: u64, precision: usize) -> String {
+    const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+    (0..precision)
+        .map(|i| BASE32[(bits >> (59 - 5 * i) & 31) as usize] as char)
+        .collect()
+}
+
+/// The numbers of a dense vector of `dims` numbers, which can't be all 0 when they're compared by their cosine
+fn dense_vector(value: &Value, dims: usize, similarity: Similarity) -> Result<Vec<f32>> {
+    let vector = value
+        .as_array()
+        .filter(|v| v.len() == dims)
+        .and_then(|v| v.iter().map(|n| n.as_f64().map(|n| n as f32)).collect::<Option<Vec<f32>>>())
+        .ok_or_else(|| Error::QueryError(format!("{} is not a vector of {} numbers", value, dims)))?;
+    if similarity == Similarity::Cosine && vector.iter().all(|n| *n == 0.0) {
+        return Err(Error::QueryError(format!("{} has no direction to compare by cosine", value)));
+    }
+    Ok(vector)
+}
+
+/// A vector as the bytes it's stored as, each number's 32 bit float in little endian order
+pub fn vector_bytes(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|n| {
+            let bits = n.to_bits();
+            (0..4).map(move |i| (bits >> (8 * i)) as u8)
+        })
+        .collect()
+}
+
+/// The vector stored as `bytes`
+pub fn vector_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| f32::from_bits((0..4).fold(0, |bits, i| bits | u32::from(chunk[i]) << (8 * i))))
+        .collect()
+}
+
+/// The features of a rank features field, each a name and a positive weight
+fn rank_features(value: &Value) -> Result<BTreeMap<String, f32>> {
+    let malformed = || Error::QueryError(format!("{} is not an object of features and positive weights", value));
+    value
+        .as_object()
+        .ok_or_else(malformed)?
+        .iter()
+        .map(|(name, weight)| match weight.as_f64() {
+            Some(weight) if weight > 0.0 && weight.is_finite() && !name.is_empty() && !name.contains('\0') => {
+                Ok((name.clone(), weight as f32))
+            }
+            _ => Err(malformed()),
+        })
+        .collect()
+}
+
+/// Features as the bytes they're stored as, each name's UTF-8 followed by a 0 and the bytes of its weight like those of a
+/// vector
+pub fn features_bytes(features: &BTreeMap<String, f32>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (name, weight) in features {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend(vector_bytes(&[*weight]));
+    }
+    bytes
+}
+
+/// The features stored as `bytes`
+pub fn features_from_bytes(bytes: &[u8]) -> Vec<(String, f32)> {
+    let mut features = Vec::new();
+    let mut rest = bytes;
+    while let Some(end) = rest.iter().position(|b| *b == 0) {
+        if rest.len() < end + 5 {
+            break;
+        }
+        let name = String::from_utf8_lossy(&rest[..end]).into_owned();
+        features.push((name, vector_from_bytes(&rest[end + 1..end + 5])[0]));
+        rest = &rest[end + 5..];
+    }
+    features
+}
+
+/// Milliseconds since the epoch of `value`. Numbers are read with the first epoch format of `formats`, strings with
+/// the first format they match.
+pub fn parse_date(value: &Value, formats: &[String]) -> Result<i64> {
+    match value {
+        Value::Number(number) => {
+            let number = number
+                .as_i64()
+                .ok_or_else(|| Error::QueryError(format!("{} is not a whole number of milliseconds or seconds", number)))?;
+            match formats.iter().find(|f| *f == "epoch_millis" || *f == "epoch_second") {
+                Some(format) if format == "epoch_second" => Ok(number * 1000),
+                Some(_) => Ok(number),
+                None => Err(Error::QueryError(format!(
+                    "Date {} is a number but no epoch format is given",
+                    number
+                ))),
+            }
+        }
+        Value::String(text) => formats
+            .iter()
+            .filter_map(|format| parse_formatted(text, format))
+            .next()
+            .ok_or_else(|| Error::QueryError(format!("Date {} matches none of the formats {}", text, formats.join(", ")))),
+        other => Err(Error::QueryError(format!("{} is not a date", other))),
+    }
+}
+
+fn parse_formatted(text: &str, format: &str) -> Option<i64> {
+    match format {
+        "rfc3339" => DateTime::parse_from_rfc3339(text).ok().map(|date| date.timestamp_millis()),
+        "epoch_millis" => text.parse().ok(),
+        "epoch_second" => text.parse::<i64>().ok().map(|seconds| seconds * 1000),
+        // Patterns without an offset are in UTC, and those without a time are at midnight
+        pattern => DateTime::parse_from_str(text, pattern)
+            .map(|date| date.timestamp_millis())
+            .or_else(|_| NaiveDateTime::parse_from_str(text, pattern).map(|date| date.timestamp_millis()))
+            .or_else(|_| NaiveDate::parse_from_str(text, pattern).map(|date| date.and_hms(0, 0, 0).timestamp_millis()))
+            .ok(),
+    }
+}
+
+/// Milliseconds of a positive duration such as `30s`, `1h` or `7d`
+pub fn parse_duration(text: &str) -> Result<i64> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| text.len());
+    let (count, unit) = text.split_at(split);
+    let millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        _ => return Err(Error::QueryError(format!("Interval {} has no unit of ms, s, m, h, d or w", text))),
+    };
+    match count.parse::<i64>() {
+        Ok(count) if count > 0 => Ok(count * millis),
+        _ => Err(Error::QueryError(format!("Interval {} is not a positive number of {}", text, unit))),
+    }
+}
+
+/// `millis` since the epoch in RFC 3339, in UTC
+pub fn format_date(millis: i64) -> String {
+    Utc.timestamp_millis(millis).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_dates() {
+        let mut schema = json!([
+            { "name": "created", "type": "date", "options": { "stored": true } },
+            { "name": "day", "type": "date", "formats": ["%Y-%m-%d", "epoch_second"] },
+            { "name": "title", "type": "text", "options": { "stored": true } }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(
+            schema[0],
+            json!({ "name": "created", "type": "i64", "options": { "indexed": true, "fast": "single", "stored": true } })
+        );
+        assert_eq!(schema[2]["type"], "text");
+        assert_eq!(mappings.len(), 2);
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let created = &mappings["created"];
+        assert_eq!(
+            created.convert(&json!("2019-01-02T03:04:05.006Z")).unwrap(),
+            json!(1_546_398_245_006i64)
+        );
+        assert_eq!(
+            created.convert(&json!("2019-01-02T04:04:05.006+01:00")).unwrap(),
+            json!(1_546_398_245_006i64)
+        );
+        assert_eq!(created.convert(&json!(1_546_398_245_006i64)).unwrap(), json!(1_546_398_245_006i64));
+        assert_eq!(created.convert(&json!("1546398245006")).unwrap(), json!(1_546_398_245_006i64));
+        assert!(created.convert(&json!("2019-01-02")).is_err());
+        assert!(created.convert(&json!(true)).is_err());
+        let day = &mappings["day"];
+        assert_eq!(day.convert(&json!("2019-01-02")).unwrap(), json!(1_546_387_200_000i64));
+        assert_eq!(day.convert(&json!(1_546_387_200)).unwrap(), json!(1_546_387_200_000i64));
+        assert_eq!(format_date(1_546_398_245_006), "2019-01-02T03:04:05.006Z");
+
+        let doc = parse_document(&schema, &mappings, r#"{ "created": "2019-01-02T03:04:05.006Z", "title": "a" }"#).unwrap();
+        assert_eq!(
+            doc.get_first(schema.get_field("created").unwrap()).unwrap().i64_value(),
+            1_546_398_245_006
+        );
+        assert!(parse_document(&schema, &mappings, r#"{ "created": "yesterday" }"#).is_err());
+
+        let query: Query = serde_json::from_value(json!({ "bool": {
+            "must": [ { "term": { "day": "2019-01-02" } } ],
+            "filter": [ { "range": { "created": { "gte": "2019-01-01T00:00:00Z", "lt": "2019-02-01T00:00:00Z" } } } ]
+        } }))
+        .unwrap();
+        let converted = serde_json::to_value(convert_query(&mappings, query).unwrap()).unwrap();
+        assert_eq!(converted["bool"]["must"][0]["term"]["day"], "1546387200000");
+        assert_eq!(converted["bool"]["filter"][0]["range"]["created"]["gte"], 1_546_300_800_000i64);
+        assert_eq!(converted["bool"]["filter"][0]["range"]["created"]["lt"], 1_548_979_200_000i64);
+
+        let query: Query = serde_json::from_value(json!({ "function_score": { "functions": [
+            { "gauss": { "day": { "origin": "2019-01-02", "scale": "7d" } } },
+            { "exp": { "created": { "origin": "now", "scale": "1h" } } }
+        ] } }))
+        .unwrap();
+        let converted = serde_json::to_value(convert_query(&mappings, query).unwrap()).unwrap();
+        assert_eq!(
+            converted["function_score"]["functions"][0]["gauss"]["day"]["origin"],
+            1_546_387_200_000i64
+        );
+        assert_eq!(converted["function_score"]["functions"][0]["gauss"]["day"]["scale"], "7d");
+        assert_eq!(converted["function_score"]["functions"][1]["exp"]["created"]["origin"], "now");
+        assert_eq!(parse_duration("7d").unwrap(), 604_800_000);
+        assert!(parse_duration("7 days").is_err());
+        assert!(parse_duration("0s").is_err());
+
+        for bad in &[
+            json!([{ "name": "d", "type": "date", "formats": "yyyy" }]),
+            json!([{ "name": "d", "type": "date", "formats": [] }]),
+        ] {
+            assert!(apply_mappings(&mut bad.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut schema = json!([{ "name": "client", "type": "ip", "options": { "stored": true } }]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(schema[0]["type"], "text");
+        assert_eq!(schema[0]["options"]["indexing"]["tokenizer"], "raw");
+        assert_eq!(mappings["client"], FieldMapping::from(MappedType::Ip));
+
+        let client = &mappings["client"];
+        assert_eq!(
+            client.convert(&json!("10.1.2.3")).unwrap(),
+            json!("00000000000000000000ffff0a010203")
+        );
+        assert_eq!(
+            client.convert(&json!("2001:db8::1")).unwrap(),
+            json!("20010db8000000000000000000000001")
+        );
+        assert!(client.convert(&json!("10.1.2")).is_err());
+        assert!(client.convert(&json!(10)).is_err());
+        let shown = |term: &str| client.display(&FieldValue::Str(term.into()));
+        assert_eq!(shown("00000000000000000000ffff0a010203"), Some(FieldValue::Str("10.1.2.3".into())));
+        assert_eq!(
+            shown("20010db8000000000000000000000001"),
+            Some(FieldValue::Str("2001:db8::1".into()))
+        );
+
+        assert_eq!(
+            cidr_range("10.0.0.0/8").unwrap(),
+            (ip_bits("10.0.0.0").unwrap(), ip_bits("10.255.255.255").unwrap())
+        );
+        assert_eq!(cidr_range("10.1.2.3/32").unwrap().0, cidr_range("10.1.2.3/32").unwrap().1);
+        assert_eq!(cidr_range("::/0").unwrap(), (0, u128::max_value()));
+        assert!(cidr_range("10.0.0.0/33").is_err());
+
+        let query: Query = serde_json::from_value(json!({ "bool": { "should": [
+            { "term": { "client": "10.0.0.0/8" } },
+            { "term": { "client": "::1" } }
+        ] } }))
+        .unwrap();
+        let converted = serde_json::to_value(convert_query(&mappings, query).unwrap()).unwrap();
+        let range = &converted["bool"]["should"][0]["range"]["client"];
+        assert_eq!(range["gte"], "00000000000000000000ffff0a000000");
+        assert_eq!(range["lte"], "00000000000000000000ffff0affffff");
+        assert_eq!(converted["bool"]["should"][1]["term"]["client"], "00000000000000000000000000000001");
+    }
+
+    #[test]
+    fn test_geo_points() {
+        let mut schema = json!([{ "name": "location", "type": "geo_point", "options": { "stored": true } }]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(
+            schema[0],
+            json!({ "name": "location", "type": "u64", "options": { "indexed": true, "fast": "multi", "stored": true } })
+        );
+        assert!(mappings["location"].is_geo_point());
+
+        let location = &mappings["location"];
+        let amsterdam = location.convert(&json!({ "lat": 52.3702, "lon": 4.8952 })).unwrap();
+        assert_eq!(location.convert(&json!("52.3702, 4.8952")).unwrap(), amsterdam);
+        let bits = amsterdam.as_u64().unwrap();
+        assert_eq!(geohash(bits, 6), "u173zm");
+        let (lat, lon) = geo_decode(bits);
+        assert!((lat - 52.3702).abs() < 1e-6 && (lon - 4.8952).abs() < 1e-6);
+        assert_eq!(
+            location.display(&FieldValue::U64(bits)),
+            Some(FieldValue::Str("52.370200,4.895200".into()))
+        );
+        assert_eq!(geohash(geo_encode(-90.0, -180.0), 2), "00");
+        assert_eq!(geohash(geo_encode(90.0, 180.0), 2), "zz");
+        for bad in &[json!("91,0"), json!({ "lat": 10 }), json!([4.89, 52.37]), json!("north")] {
+            assert!(location.convert(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut schema = json!([
+            { "name": "payload", "type": "bytes", "options": { "stored": true, "fast": true } },
+            { "name": "embedding", "type": "bytes", "options": { "fast": true } },
+            { "name": "attachment", "type": "bytes", "options": { "stored": true } }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(
+            schema[0],
+            json!({ "name": "payload", "type": "text", "options": { "indexing": null, "stored": true } })
+        );
+        assert_eq!(schema[1], json!({ "name": "embedding", "type": "bytes" }));
+        assert_eq!(schema[3], json!({ "name": "payload.fast", "type": "bytes" }));
+        assert_eq!(mappings["attachment"].fast_field("attachment"), None);
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let text = r#"{ "payload": "aGVsbG8=", "embedding": "AAEC", "attachment": "d29ybGQ=" }"#;
+        let doc = parse_document(&schema, &mappings, text).unwrap();
+        let field = |name: &str| doc.get_first(schema.get_field(name).unwrap()).cloned();
+        assert_eq!(field("payload"), Some(FieldValue::Str("aGVsbG8=".into())));
+        assert_eq!(field("payload.fast"), Some(FieldValue::Bytes(b"hello".to_vec())));
+        assert_eq!(field("embedding"), Some(FieldValue::Bytes(vec![0, 1, 2])));
+        assert!(parse_document(&schema, &mappings, r#"{ "attachment": "not base64!" }"#).is_err());
+        assert!(apply_mappings(&mut json!([{ "name": "lost", "type": "bytes" }])).is_err());
+    }
+
+    #[test]
+    fn test_dense_vectors() {
+        let mut schema = json!([
+            { "name": "title", "type": "dense_vector", "dims": 3, "options": { "stored": true } },
+            { "name": "body", "type": "dense_vector", "dims": 2, "similarity": "l2_norm", "hnsw": { "m": 8 } }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(
+            mappings["body"],
+            FieldMapping::from(MappedType::DenseVector {
+                dims: 2,
+                similarity: Similarity::L2Norm,
+                stored: false,
+                hnsw: Some(HnswParams {
+                    m: 8,
+                    ef_construction: 100
+                }),
+            })
+        );
+        assert_eq!(schema[1], json!({ "name": "body", "type": "bytes" }));
+        assert_eq!(schema[2], json!({ "name": "title.fast", "type": "bytes" }));
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let doc = parse_document(&schema, &mappings, r#"{ "title": [1, 0.5, -2], "body": [0, 0] }"#).unwrap();
+        let field = |name: &str| doc.get_first(schema.get_field(name).unwrap()).cloned();
+        assert_eq!(field("body"), Some(FieldValue::Bytes(vector_bytes(&[0.0, 0.0]))));
+        let fast = match field("title.fast") {
+            Some(FieldValue::Bytes(bytes)) => vector_from_bytes(&bytes),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(format!("{:?}", fast), "[1.0, 0.5, -2.0]");
+        let stored = field("title").unwrap();
+        assert_eq!(mappings["title"].display(&stored), Some(FieldValue::Str("[1.0,0.5,-2.0]".into())));
+        for bad in &[r#"{ "title": [1, 2] }"#, r#"{ "title": [0, 0, 0] }"#, r#"{ "body": "[1, 2]" }"#] {
+            assert!(parse_document(&schema, &mappings, bad).is_err());
+        }
+
+        let query: Query = serde_json::from_value(json!({ "knn": {
+            "field": "body", "vector": [1, 1], "filter": { "range": { "rank": { "gte": 1 } } }
+        } }))
+        .unwrap();
+        let converted = serde_json::to_value(convert_query(&mappings, query).unwrap()).unwrap();
+        assert_eq!(converted["knn"]["similarity"], "l2_norm");
+        let query: Query = serde_json::from_value(json!({ "knn": { "field": "title", "vector": [1, 1] } })).unwrap();
+        assert!(convert_query(&mappings, query).is_err());
+        for bad in &[
+            json!([{ "name": "v", "type": "dense_vector" }]),
+            json!([{ "name": "v", "type": "dense_vector", "dims": 2, "hnsw": { "m": 1 } }]),
+        ] {
+            assert!(apply_mappings(&mut bad.clone()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_rank_features() {
+        let mut schema = json!([
+            { "name": "signals", "type": "rank_features", "options": { "stored": true } },
+            { "name": "title", "type": "text", "options": { "indexing": null, "stored": true } }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(mappings["signals"], FieldMapping::from(MappedType::RankFeatures { stored: true }));
+        assert_eq!(schema[2], json!({ "name": "signals.fast", "type": "bytes" }));
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let doc = parse_document(&schema, &mappings, r#"{ "signals": { "pagerank": 8, "rust": 0.5 } }"#).unwrap();
+        let field = |name: &str| doc.get_first(schema.get_field(name).unwrap()).cloned();
+        let fast = match field("signals.fast") {
+            Some(FieldValue::Bytes(bytes)) => features_from_bytes(&bytes),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(fast, vec![("pagerank".to_string(), 8.0), ("rust".to_string(), 0.5)]);
+        let stored = field("signals").unwrap();
+        assert_eq!(
+            mappings["signals"].display(&stored),
+            Some(FieldValue::Str(r#"{"pagerank":8.0,"rust":0.5}"#.into()))
+        );
+        for bad in &[
+            r#"{ "signals": { "pagerank": 0 } }"#,
+            r#"{ "signals": { "pagerank": "high" } }"#,
+            r#"{ "signals": [{ "pagerank": 1 }] }"#,
+        ] {
+            assert!(parse_document(&schema, &mappings, bad).is_err());
+        }
+
+        let query: Query = serde_json::from_value(json!({ "rank_feature": { "field": "title", "feature": "pagerank" } })).unwrap();
+        assert!(convert_query(&mappings, query).is_err());
+    }
+
+    #[test]
+    fn test_join() {
+        let mut schema = json!([
+            { "name": "title", "type": "text", "options": { "indexing": null, "stored": true } },
+            { "name": "qa", "type": "join", "relations": { "question": ["answer", "comment"], "answer": "comment" } }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        let relations = match &mappings["qa"].kind {
+            Some(MappedType::Join { relations }) => relations.clone(),
+            other => panic!("{:?} is not a join", other),
+        };
+        assert_eq!(relations["answer"], vec!["comment"]);
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let doc = |text: &str| parse_document(&schema, &mappings, text);
+        let field = |doc: &Document, name: &str| doc.get_first(schema.get_field(name).unwrap()).cloned();
+        let answer = doc(r#"{ "qa": { "name": "answer", "id": "a1", "parent": "q1" } }"#).unwrap();
+        assert_eq!(field(&answer, "qa"), Some(FieldValue::Str("answer".into())));
+        assert_eq!(field(&answer, JOIN_RELATION), Some(FieldValue::Str("answer".into())));
+        assert_eq!(field(&answer, JOIN_ID), Some(FieldValue::Str("a1".into())));
+        assert_eq!(field(&answer, JOIN_PARENT), Some(FieldValue::Str("q1".into())));
+        let question = doc(r#"{ "qa": { "name": "question", "id": 7 } }"#).unwrap();
+        assert_eq!(field(&question, JOIN_ID), Some(FieldValue::Str("7".into())));
+        assert_eq!(field(&question, JOIN_PARENT), None);
+
+        assert!(doc(r#"{ "qa": "question" }"#).is_err());
+        assert!(doc(r#"{ "qa": { "name": "comment" } }"#).is_err());
+        assert!(doc(r#"{ "qa": { "name": "question", "id": "q2", "parent": "q1" } }"#).is_err());
+        assert!(doc(r#"{ "qa": { "name": "vote", "parent": "q1" } }"#).is_err());
+        let mut two = json!([
+            { "name": "a", "type": "join", "relations": { "x": "y" } },
+            { "name": "b", "type": "join", "relations": { "x": "y" } }
+        ]);
+        assert!(apply_mappings(&mut two).is_err());
+        assert!(apply_mappings(&mut json!([{ "name": "qa", "type": "join" }])).is_err());
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let options = json!({ "indexing": { "record": "position", "tokenizer": "default" }, "stored": false });
+        let mut schema = json!([
+            { "name": "title", "type": "text", "options": { "indexing": null, "stored": true }, "copy_to": "all_text" },
+            { "name": "tags", "type": "text", "options": { "indexing": null, "stored": true }, "copy_to": ["all_text"] },
+            { "name": "year", "type": "u64", "options": { "indexed": true, "stored": true }, "copy_to": "all_text" },
+            { "name": "all_text", "type": "text", "options": options }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(mappings["title"].copy_to, vec!["all_text".to_string()]);
+        assert!(schema[0].get("copy_to").is_none());
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let text = r#"{ "title": "Dune", "tags": ["sci-fi", "classic"], "year": 1965, "all_text": "desert" }"#;
+        let doc = parse_document(&schema, &mappings, text).unwrap();
+        let all_text: Vec<FieldValue> = doc.get_all(schema.get_field("all_text").unwrap()).into_iter().cloned().collect();
+        let expected: Vec<FieldValue> = ["desert", "sci-fi", "classic", "Dune", "1965"]
+            .iter()
+            .map(|t| FieldValue::Str(t.to_string()))
+            .collect();
+        assert_eq!(all_text, expected);
+        assert_eq!(doc.get_first(schema.get_field("year").unwrap()), Some(&FieldValue::U64(1965)));
+
+        let mut missing = json!([{ "name": "title", "type": "text", "options": options, "copy_to": "everything" }]);
+        assert!(apply_mappings(&mut missing).is_err());
+        let mut date = json!([
+            { "name": "created", "type": "date", "copy_to": "all_text" },
+            { "name": "all_text", "type": "text", "options": options }
+        ]);
+        assert!(apply_mappings(&mut date).is_err());
+    }
+
+    #[test]
+    fn test_null_value() {
+        let mut schema = json!([
+            { "name": "status", "type": "text", "options": { "indexing": null, "stored": true }, "null_value": "unknown" },
+            { "name": "created", "type": "date", "formats": "%Y-%m-%d", "options": { "stored": true }, "null_value": "1970-01-02" }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(mappings["status"].null_value, Some(json!("unknown")));
+        assert!(schema[0].get("null_value").is_none());
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let doc = parse_document(&schema, &mappings, r#"{ "status": [null, "open"], "created": null }"#).unwrap();
+        let status: Vec<FieldValue> = doc.get_all(schema.get_field("status").unwrap()).into_iter().cloned().collect();
+        let expected = vec![FieldValue::Str("unknown".into()), FieldValue::Str("open".into())];
+        assert_eq!(status, expected);
+        assert_eq!(
+            doc.get_first(schema.get_field("created").unwrap()),
+            Some(&FieldValue::I64(86_400_000))
+        );
+
+        let mut invalid = json!([{ "name": "created", "type": "date", "null_value": "yesterday" }]);
+        assert!(apply_mappings(&mut invalid).is_err());
+    }
+
+    #[test]
+    fn test_ignore_malformed() {
+        let mut schema = json!([
+            { "name": "title", "type": "text", "options": { "indexing": null, "stored": true } },
+            { "name": "price", "type": "u64", "options": { "indexed": true, "stored": true }, "ignore_malformed": true },
+            { "name": "stock", "type": "i64", "options": { "indexed": true, "stored": true }, "ignore_malformed": false },
+            { "name": "created", "type": "date", "options": { "stored": true }, "ignore_malformed": true }
+        ]);
+        let mut mappings = apply_mappings(&mut schema).unwrap();
+        assert_eq!(mappings["price"].ignore_malformed, Some(true));
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+        let field =
+            |doc: &Document, name: &str| -> Vec<FieldValue> { doc.get_all(schema.get_field(name).unwrap()).into_iter().cloned().collect() };
+
+        let text = r#"{ "title": "Dune", "price": [12, "N/A"], "stock": 3, "created": "last week" }"#;
+        let doc = parse_document(&schema, &mappings, text).unwrap();
+        assert_eq!(field(&doc, "price"), vec![FieldValue::U64(12)]);
+        assert!(field(&doc, "created").is_empty());
+        assert!(parse_document(&schema, &mappings, r#"{ "title": 7, "stock": 3 }"#).is_err());
+        assert!(parse_document(&schema, &mappings, r#"{ "title": "Dune", "stock": "N/A" }"#).is_err());
+
+        // The index ignoring them covers the fields that don't say otherwise
+        ignore_malformed(&schema, &mut mappings);
+        assert_eq!(mappings["title"].ignore_malformed, Some(true));
+        assert_eq!(mappings["stock"].ignore_malformed, Some(false));
+        let doc = parse_document(&schema, &mappings, r#"{ "title": 7, "stock": 3 }"#).unwrap();
+        assert!(field(&doc, "title").is_empty());
+        assert_eq!(field(&doc, "stock"), vec![FieldValue::I64(3)]);
+
+        let mut invalid = json!([{ "name": "price", "type": "u64", "options": { "indexed": true }, "ignore_malformed": "yes" }]);
+        assert!(apply_mappings(&mut invalid).is_err());
+    }
+
+    #[test]
+    fn test_nested() {
+        let mut schema = json!([
+            { "name": "order", "type": "u64", "options": { "indexed": true, "stored": true } },
+            { "name": "items", "type": "nested", "options": { "stored": true }, "fields": [
+                { "name": "color", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": false } },
+                { "name": "added", "type": "date" }
+            ] }
+        ]);
+        let mappings = apply_mappings(&mut schema).unwrap();
+        let names: Vec<&str> = schema.as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "order",
+                "items",
+                "items.color",
+                "items.added",
+                NESTED_ID,
+                NESTED_PARENT,
+                NESTED_PATH
+            ]
+        );
+        assert_eq!(
+            mappings["items"],
+            FieldMapping::from(MappedType::Nested {
+                fields: vec!["color".into(), "added".into()]
+            })
+        );
+        assert!(mappings.contains_key("items.added"));
+        let schema: Schema = serde_json::from_value(schema).unwrap();
+
+        let text = r#"{ "order": 1, "items": [{ "color": "red", "added": 1000 }, { "color": "blue" }] }"#;
+        let docs = parse_documents(&schema, &mappings, text).unwrap();
+        assert_eq!(docs.len(), 3);
+        let field = |doc: &Document, name: &str| doc.get_first(schema.get_field(name).unwrap()).cloned();
+        let id = field(&docs[2], NESTED_ID).unwrap();
+        assert_eq!(field(&docs[0], NESTED_PARENT), Some(id.clone()));
+        assert_eq!(field(&docs[1], NESTED_PARENT), Some(id));
+        assert_eq!(field(&docs[0], "items.color"), Some(FieldValue::Str("red".into())));
+        assert_eq!(field(&docs[0], "items.added"), Some(FieldValue::I64(1000)));
+        assert_eq!(field(&docs[1], NESTED_PATH), Some(FieldValue::Str("items".into())));
+        assert_eq!(
+            field(&docs[2], "items"),
+            Some(FieldValue::Str(r#"{"added":1000,"color":"red"}"#.into()))
+        );
+        assert_eq!(field(&docs[2], "items.color"), None);
+        assert!(parse_documents(&schema, &mappings, r#"{ "items": ["red"] }"#).is_err());
+        assert!(apply_mappings(&mut json!([{ "name": "items", "type": "nested" }])).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::Request;
+
+use crate::lifecycle::{InFlight, Lifecycle};
+use crate::middleware::{Endpoint, RateLimiter};
+
+/// Why a request was turned away
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// The node has started draining
+    Draining,
+    /// The client is over its limit and should back off this long
+    RateLimited(Duration),
+}
+
+/// The checks `DrainMiddleware` and `RateLimitMiddleware` make, for the listeners that aren't served through tower-web
+#[derive(Clone)]
+pub struct Admission {
+    lifecycle: Arc<Lifecycle>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Admission {
+    pub fn new(lifecycle: &Arc<Lifecycle>, limiter: &Arc<RateLimiter>) -> Self {
+        Self {
+            lifecycle: Arc::clone(lifecycle),
+            limiter: Arc::clone(limiter),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.lifecycle.is_draining()
+    }
+
+    /// Let a request to `endpoint` in, returning the guard that counts it as in flight for as long as it's alive
+    pub fn admit<B>(&self, endpoint: Endpoint, request: &Request<B>) -> Result<InFlight, Rejection> {
+        if self.lifecycle.is_draining() {
+            return Err(Rejection::Draining);
+        }
+        self.limiter.check_endpoint(endpoint, request).map_err(Rejection::RateLimited)?;
+        Ok(Lifecycle::start_request(&self.lifecycle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn test_admit() {
+        let mut settings = crate::settings::Settings::default_rate_limit();
+        settings.enabled = true;
+        settings.search_per_second = 1.0;
+        settings.search_burst = 1.0;
+        let lifecycle = Arc::new(Lifecycle::new());
+        let admission = Admission::new(&lifecycle, &Arc::new(RateLimiter::new(settings)));
+        let request = Request::new(());
+
+        let in_flight = admission.admit(Endpoint::Search, &request).unwrap();
+        assert_eq!(lifecycle.in_flight(), 1);
+        match admission.admit(Endpoint::Search, &request) {
+            Err(Rejection::RateLimited(_)) => {}
+            _ => panic!("Expected the second search to be rate limited"),
+        }
+        assert!(admission.admit(Endpoint::Admin, &request).is_ok());
+        drop(in_flight);
+        assert_eq!(lifecycle.in_flight(), 0);
+
+        let mut rt = Runtime::new().unwrap();
+        let catalog = crate::index::tests::create_test_catalog("test_index");
+        rt.block_on(Lifecycle::drain(&lifecycle, catalog, Duration::from_millis(0)))
+            .unwrap();
+        assert_eq!(admission.admit(Endpoint::Admin, &request).err(), Some(Rejection::Draining));
+        rt.shutdown_now();
+    }
+}
@@ -0,0 +1,572 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io::{self, BufReader, Cursor, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Buf;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use futures::{try_ready, Async, Future, Poll};
+use http::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+use http::{Request, Response, StatusCode};
+use log::debug;
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+use tower_web_service::Service;
+
+use crate::middleware::{error_response, Classify, Endpoint, ResponseFuture};
+use crate::settings::BodyLimitSettings;
+
+/// Errors produced while streaming a request body through the body middleware
+#[derive(Debug)]
+pub enum BodyError<E> {
+    Inner(E),
+    TooLarge(usize),
+    Decode(io::Error),
+}
+
+impl<E: fmt::Display> fmt::Display for BodyError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::Inner(e) => e.fmt(f),
+            BodyError::TooLarge(limit) => write!(f, "Request body exceeds the limit of {} bytes", limit),
+            BodyError::Decode(e) => write!(f, "Unable to decompress request body: {}", e),
+        }
+    }
+}
+
+impl<E: StdError> StdError for BodyError<E> {}
+
+/// The content codings accepted on request bodies
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Parse a `Content-Encoding` header, `Ok(None)` means the body is not encoded at all
+    pub fn parse(value: &str) -> Result<Option<ContentEncoding>, ()> {
+        match value.trim().to_ascii_lowercase().as_ref() {
+            "" | "identity" => Ok(None),
+            "gzip" | "x-gzip" => Ok(Some(ContentEncoding::Gzip)),
+            "deflate" => Ok(Some(ContentEncoding::Deflate)),
+            "zstd" => Ok(Some(ContentEncoding::Zstd)),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Handles request bodies before they reach the handlers.
+///
+/// Compressed bodies (`Content-Encoding: gzip`, `deflate` or `zstd`) are accepted on the
+/// bulk and index endpoints and decompressed on the fly as they stream in.
+///
+/// The configured maximum body size is enforced for each kind of endpoint. Requests that
+/// announce an oversized body with a `Content-Length` are answered with a `413 Payload Too Large`
+/// before any of the body is read. Everything else is counted as it streams in, after
+/// decompression, and aborted as soon as it crosses the limit, so a handler never buffers
+/// more than the limit in memory. Whatever the handler answers then is replaced with a `413`.
+#[derive(Clone)]
+pub struct RequestBodyMiddleware {
+    limits: BodyLimitSettings,
+    classify: Classify,
+}
+
+impl RequestBodyMiddleware {
+    pub fn new(limits: BodyLimitSettings) -> Self {
+        Self {
+            limits,
+            classify: Endpoint::toshi,
+        }
+    }
+
+    /// Tell which limits apply to a request with `classify`, for APIs laid out differently from Toshi's own
+    pub fn classifying(mut self, classify: Classify) -> Self {
+        self.classify = classify;
+        self
+    }
+}
+
+fn limit_for(limits: &BodyLimitSettings, endpoint: Endpoint) -> usize {
+    match endpoint {
+        Endpoint::Bulk => limits.bulk,
+        Endpoint::Index => limits.index,
+        Endpoint::Search | Endpoint::Admin => limits.default,
+    }
+}
+
+fn content_length<B>(request: &Request<B>) -> Option<usize> {
+    request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+impl<S, B> Middleware<S> for RequestBodyMiddleware
+where
+    S: HttpService<RequestBody = RequestBody<B>>,
+    B: BufStream,
+{
+    type Request = Request<B>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Service = RequestBodyService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        RequestBodyService {
+            inner,
+            limits: self.limits.clone(),
+            classify: self.classify,
+        }
+    }
+}
+
+pub struct RequestBodyService<S> {
+    inner: S,
+    limits: BodyLimitSettings,
+    classify: Classify,
+}
+
+impl<S, B> Service for RequestBodyService<S>
+where
+    S: HttpService<RequestBody = RequestBody<B>>,
+    B: BufStream,
+{
+    type Request = Request<B>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Future = BodyResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let endpoint = (self.classify)(request.method(), request.uri().path());
+        let limit = limit_for(&self.limits, endpoint);
+        let path = request.uri().path().to_string();
+        BodyResponseFuture {
+            inner: self.respond(request, endpoint, limit, &path, &exceeded),
+            exceeded,
+            limit,
+            path,
+        }
+    }
+}
+
+impl<S, B> RequestBodyService<S>
+where
+    S: HttpService<RequestBody = RequestBody<B>>,
+    B: BufStream,
+{
+    fn respond(
+        &mut self,
+        mut request: Request<B>,
+        endpoint: Endpoint,
+        limit: usize,
+        path: &str,
+        exceeded: &Arc<AtomicBool>,
+    ) -> ResponseFuture<S::Future> {
+        if let Some(len) = content_length(&request) {
+            if len > limit {
+                debug!("Rejecting {} byte body for {}, limit is {}", len, path, limit);
+                let message = format!("Request body exceeds the limit of {} bytes", limit);
+                return ResponseFuture::Immediate(Some(error_response(StatusCode::PAYLOAD_TOO_LARGE, &message, path)));
+            }
+        }
+
+        let header = request.headers().get(CONTENT_ENCODING).map(|v| v.to_str().unwrap_or("unknown"));
+        let encoding = match header.map(ContentEncoding::parse) {
+            None | Some(Ok(None)) => None,
+            Some(Ok(Some(e))) if endpoint == Endpoint::Bulk || endpoint == Endpoint::Index => Some(e),
+            _ => {
+                let message = format!("Unsupported Content-Encoding for {}", path);
+                return ResponseFuture::Immediate(Some(error_response(StatusCode::UNSUPPORTED_MEDIA_TYPE, &message, path)));
+            }
+        };
+
+        if encoding.is_some() {
+            // Handlers see the decompressed body, so the original framing headers no longer apply
+            request.headers_mut().remove(CONTENT_ENCODING);
+            request.headers_mut().remove(CONTENT_LENGTH);
+        }
+
+        let request = request.map(|body| RequestBody::new(body, encoding, limit).flagging(Arc::clone(exceeded)));
+        ResponseFuture::Inner(self.inner.call_http(request))
+    }
+}
+
+/// Answers with a `413` when the body turned out to be over the limit while the handler was reading it, in place of
+/// the error the handler made of it
+pub struct BodyResponseFuture<F> {
+    inner: ResponseFuture<F>,
+    exceeded: Arc<AtomicBool>,
+    limit: usize,
+    path: String,
+}
+
+impl<F, B> Future for BodyResponseFuture<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<Either2<B, String>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let response = try_ready!(self.inner.poll());
+        if !self.exceeded.load(Ordering::SeqCst) {
+            return Ok(Async::Ready(response));
+        }
+        debug!(
+            "Body for {} went over the limit of {} bytes as it streamed in",
+            self.path, self.limit
+        );
+        let message = format!("Request body exceeds the limit of {} bytes", self.limit);
+        Ok(Async::Ready(
+            error_response(StatusCode::PAYLOAD_TOO_LARGE, &message, &self.path).map(Either2::B),
+        ))
+    }
+}
+
+/// The compressed bytes a `Decoder` has been given but not read yet. Reading past them fails with `WouldBlock` until
+/// more arrive, or ends once the body has
+#[derive(Default)]
+struct Input {
+    data: Vec<u8>,
+    pos: usize,
+    ended: bool,
+}
+
+impl Input {
+    fn push(&mut self, chunk: &[u8]) {
+        self.data.drain(..self.pos);
+        self.pos = 0;
+        self.data.extend_from_slice(chunk);
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.data[self.pos..];
+        if available.is_empty() && !self.ended {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// How much is decompressed at a time, at most
+const DECODE_CHUNK: usize = 64 * 1024;
+
+/// Decompresses as much as it's asked for, so a small body that expands enormously is never expanded any further
+/// than the limit
+enum Decoder {
+    Gzip(GzDecoder<Input>),
+    Deflate(ZlibDecoder<Input>),
+    Zstd(zstd::stream::read::Decoder<BufReader<Input>>),
+}
+
+impl Decoder {
+    fn new(encoding: ContentEncoding) -> io::Result<Self> {
+        Ok(match encoding {
+            ContentEncoding::Gzip => Decoder::Gzip(GzDecoder::new(Input::default())),
+            ContentEncoding::Deflate => Decoder::Deflate(ZlibDecoder::new(Input::default())),
+            ContentEncoding::Zstd => Decoder::Zstd(zstd::stream::read::Decoder::new(Input::default())?),
+        })
+    }
+
+    fn input(&mut self) -> &mut Input {
+        match self {
+            Decoder::Gzip(d) => d.get_mut(),
+            Decoder::Deflate(d) => d.get_mut(),
+            Decoder::Zstd(d) => d.get_mut().get_mut(),
+        }
+    }
+
+    /// Decompress up to `max` bytes, `WouldBlock` means more of the compressed body is needed first and an empty
+    /// result that the body has all been decompressed
+    fn decode(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        let mut out = vec![0; max.min(DECODE_CHUNK)];
+        let n = match self {
+            Decoder::Gzip(d) => d.read(&mut out)?,
+            Decoder::Deflate(d) => d.read(&mut out)?,
+            Decoder::Zstd(d) => d.read(&mut out)?,
+        };
+        out.truncate(n);
+        Ok(out)
+    }
+}
+
+/// A request body that is decompressed as it is read, and errors out once more
+/// than `limit` bytes have come out of it
+pub struct RequestBody<B> {
+    inner: B,
+    decoder: Option<Decoder>,
+    remaining: usize,
+    limit: usize,
+    done: bool,
+    /// Set once the body has gone over the limit
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<B> RequestBody<B> {
+    pub fn new(inner: B, encoding: Option<ContentEncoding>, limit: usize) -> Self {
+        Self {
+            inner,
+            // Creating a decoder only allocates, the zstd context is the only one that can fail at all
+            decoder: encoding.map(|e| Decoder::new(e).expect("Unable to allocate decompression context")),
+            remaining: limit,
+            limit,
+            done: false,
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set `exceeded` if the body goes over the limit
+    pub fn flagging(mut self, exceeded: Arc<AtomicBool>) -> Self {
+        self.exceeded = exceeded;
+        self
+    }
+
+    fn account<E>(&mut self, chunk: Vec<u8>) -> Result<Async<Option<Cursor<Vec<u8>>>>, BodyError<E>> {
+        if chunk.len() > self.remaining {
+            self.exceeded.store(true, Ordering::SeqCst);
+            return Err(BodyError::TooLarge(self.limit));
+        }
+        self.remaining -= chunk.len();
+        Ok(Async::Ready(Some(Cursor::new(chunk))))
+    }
+}
+
+impl<B> BufStream for RequestBody<B>
+where
+    B: BufStream,
+{
+    type Item = Cursor<Vec<u8>>;
+    type Error = BodyError<B::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            let decoder = match self.decoder {
+                Some(ref mut decoder) => decoder,
+                None => {
+                    return match self.inner.poll().map_err(BodyError::Inner)? {
+                        Async::NotReady => Ok(Async::NotReady),
+                        Async::Ready(Some(chunk)) => self.account(chunk.collect()),
+                        Async::Ready(None) => {
+                            self.done = true;
+                            Ok(Async::Ready(None))
+                        }
+                    };
+                }
+            };
+            // One byte past what's left is enough to tell the body is over the limit
+            match decoder.decode(self.remaining + 1) {
+                Ok(ref out) if out.is_empty() => self.done = true,
+                Ok(out) => return self.account(out),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => match self.inner.poll().map_err(BodyError::Inner)? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(Some(chunk)) => decoder.input().push(&chunk.collect::<Vec<u8>>()),
+                    Async::Ready(None) => decoder.input().ended = true,
+                },
+                Err(e) => return Err(BodyError::Decode(e)),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.decoder {
+            Some(_) => SizeHint::default(),
+            None => self.inner.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl BufStream for Chunks {
+        type Item = Cursor<Vec<u8>>;
+        type Error = ();
+
+        fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+            if self.0.is_empty() {
+                Ok(Async::Ready(None))
+            } else {
+                Ok(Async::Ready(Some(Cursor::new(self.0.remove(0)))))
+            }
+        }
+    }
+
+    fn read_all<B: BufStream>(body: &mut B) -> Result<Vec<u8>, B::Error> {
+        let mut out = Vec::new();
+        while let Async::Ready(Some(chunk)) = body.poll()? {
+            out.extend(chunk.collect::<Vec<u8>>());
+        }
+        Ok(out)
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_body_under_limit() {
+        let mut body = RequestBody::new(Chunks(vec![b"{\"a\": 1}\n".to_vec(), b"{\"a\": 2}\n".to_vec()]), None, 18);
+        assert_eq!(read_all(&mut body).unwrap().len(), 18);
+    }
+
+    #[test]
+    fn test_body_over_limit() {
+        let mut body = RequestBody::new(Chunks(vec![b"{\"a\": 1}\n".to_vec(), b"{\"a\": 2}\n".to_vec()]), None, 10);
+        match read_all(&mut body) {
+            Err(BodyError::TooLarge(10)) => {}
+            _ => panic!("Expected body to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_gzip_body() {
+        let text = b"{\"test_text\": \"asdf1234\"}\n{\"test_text\": \"asdf5678\"}\n";
+        let compressed = gzip(text);
+        let (first, second) = compressed.split_at(compressed.len() / 2);
+        let chunks = Chunks(vec![first.to_vec(), second.to_vec()]);
+
+        let mut body = RequestBody::new(chunks, Some(ContentEncoding::Gzip), 1024);
+        assert_eq!(read_all(&mut body).unwrap(), text.to_vec());
+    }
+
+    #[test]
+    fn test_limit_applies_after_decompression() {
+        let compressed = gzip(&vec![b'a'; 64 * 1024]);
+        assert!(compressed.len() < 1024);
+
+        let mut body = RequestBody::new(Chunks(vec![compressed]), Some(ContentEncoding::Gzip), 1024);
+        match read_all(&mut body) {
+            Err(BodyError::TooLarge(1024)) => {}
+            _ => panic!("Expected decompressed body to be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_zstd_body() {
+        let text = "{\"test_text\": \"asdf1234\"}\n".repeat(100);
+        let compressed = zstd::encode_all(text.as_bytes(), 3).unwrap();
+        let chunks = Chunks(compressed.chunks(7).map(<[u8]>::to_vec).collect());
+
+        let mut body = RequestBody::new(chunks, Some(ContentEncoding::Zstd), text.len());
+        assert_eq!(read_all(&mut body).unwrap(), text.into_bytes());
+    }
+
+    #[test]
+    fn test_decompresses_no_further_than_limit() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&vec![b'a'; 16 * 1024 * 1024]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut body = RequestBody::new(Chunks(vec![compressed]), Some(ContentEncoding::Deflate), 1024);
+        match read_all(&mut body) {
+            Err(BodyError::TooLarge(1024)) => {}
+            _ => panic!("Expected decompressed body to be rejected"),
+        }
+        match body.decoder {
+            Some(Decoder::Deflate(ref d)) => assert!(d.total_out() <= 1025),
+            _ => panic!("Expected a deflate decoder"),
+        }
+    }
+
+    /// Reads the whole body, answering `400` if it can't, as the handlers do
+    struct ReadBody;
+
+    impl Service for ReadBody {
+        type Request = Request<RequestBody<Chunks>>;
+        type Response = Response<String>;
+        type Error = ();
+        type Future = futures::future::FutureResult<Self::Response, ()>;
+
+        fn poll_ready(&mut self) -> Poll<(), ()> {
+            Ok(Async::Ready(()))
+        }
+
+        fn call(&mut self, mut request: Self::Request) -> Self::Future {
+            let status = match read_all(request.body_mut()) {
+                Ok(_) => StatusCode::OK,
+                Err(_) => StatusCode::BAD_REQUEST,
+            };
+            let mut response = Response::new(String::new());
+            *response.status_mut() = status;
+            futures::future::ok(response)
+        }
+    }
+
+    #[test]
+    fn test_chunked_body_over_limit() {
+        let mut limits = crate::settings::Settings::default_body_limits();
+        limits.bulk = 16;
+        let mut service = RequestBodyMiddleware::new(limits).wrap(ReadBody);
+        let upload = |chunks: Vec<&[u8]>| {
+            let chunks = Chunks(chunks.into_iter().map(<[u8]>::to_vec).collect());
+            let request = Request::builder()
+                .method("POST")
+                .uri("/test_index/_bulk")
+                .header("transfer-encoding", "chunked")
+                .body(chunks)
+                .unwrap();
+            service.call(request).wait().unwrap().status()
+        };
+
+        assert_eq!(upload(vec![b"{\"a\": 1}\n", b"{\"a\": 2}\n"]), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(upload(vec![b"{\"a\": 1}\n"]), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_elasticsearch_limits() {
+        let mut limits = crate::settings::Settings::default_body_limits();
+        limits.default = 8;
+        limits.bulk = 16;
+        let mut service = RequestBodyMiddleware::new(limits)
+            .classifying(Endpoint::elasticsearch)
+            .wrap(ReadBody);
+        let chunks = || Chunks(vec![b"{\"a\": 1}\n".to_vec()]);
+        let bulk = Request::post("/_bulk").body(chunks()).unwrap();
+        assert_eq!(service.call(bulk).wait().unwrap().status(), StatusCode::OK);
+        let search = Request::post("/test_index/_search").body(chunks()).unwrap();
+        assert_eq!(service.call(search).wait().unwrap().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_parse_content_encoding() {
+        assert_eq!(ContentEncoding::parse("gzip"), Ok(Some(ContentEncoding::Gzip)));
+        assert_eq!(ContentEncoding::parse("zstd"), Ok(Some(ContentEncoding::Zstd)));
+        assert_eq!(ContentEncoding::parse("identity"), Ok(None));
+        assert!(ContentEncoding::parse("compress").is_err());
+    }
+
+    #[test]
+    fn test_limits_per_endpoint() {
+        let limits = crate::settings::Settings::default_body_limits();
+        assert_eq!(limit_for(&limits, Endpoint::Bulk), limits.bulk);
+        assert_eq!(limit_for(&limits, Endpoint::Index), limits.index);
+        assert_eq!(limit_for(&limits, Endpoint::Search), limits.default);
+    }
+}
@@ -0,0 +1,303 @@
+use std::io::{Cursor, Write};
+use std::mem;
+
+use bytes::Buf;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use futures::{try_ready, Async, Future, Poll};
+use http::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY};
+use http::{Request, Response};
+use tower_web::middleware::Middleware;
+use tower_web::util::buf_stream::{BufStream, SizeHint};
+use tower_web::util::http::HttpService;
+use tower_web_service::Service;
+
+use crate::settings::CompressionSettings;
+
+/// The content codings Toshi knows how to produce, in order of preference when a client weights them equally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_ref() {
+            "br" => Some(Encoding::Brotli),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "deflate" => Some(Encoding::Deflate),
+            _ => None,
+        }
+    }
+
+    fn header_value(self) -> HeaderValue {
+        match self {
+            Encoding::Brotli => HeaderValue::from_static("br"),
+            Encoding::Gzip => HeaderValue::from_static("gzip"),
+            Encoding::Deflate => HeaderValue::from_static("deflate"),
+        }
+    }
+
+    /// Pick the best supported coding out of an `Accept-Encoding` header, honoring q-values
+    pub fn negotiate(accept: &str) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+        for entry in accept.split(',') {
+            let mut parts = entry.split(';').map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let quality = parts
+                .filter_map(|p| if p.starts_with("q=") { p[2..].parse::<f32>().ok() } else { None })
+                .next()
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            let encoding = match Encoding::from_name(name) {
+                Some(e) => e,
+                None if name == "*" => Encoding::Gzip,
+                None => continue,
+            };
+            best = match best {
+                Some((current, q)) if q > quality || (q == quality && (current as u8) <= (encoding as u8)) => Some((current, q)),
+                _ => Some((encoding, quality)),
+            };
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// Compresses responses for clients that ask for it with `Accept-Encoding`, leaving
+/// responses that are known to be smaller than the configured minimum untouched.
+#[derive(Clone)]
+pub struct CompressionMiddleware {
+    settings: CompressionSettings,
+}
+
+impl CompressionMiddleware {
+    pub fn new(settings: CompressionSettings) -> Self {
+        Self { settings }
+    }
+}
+
+impl<S> Middleware<S> for CompressionMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<CompressedBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Service = CompressionService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        CompressionService {
+            inner,
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+pub struct CompressionService<S> {
+    inner: S,
+    settings: CompressionSettings,
+}
+
+impl<S> Service for CompressionService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<CompressedBody<S::ResponseBody>>;
+    type Error = S::Error;
+    type Future = CompressionFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let encoding = if self.settings.enabled {
+            request
+                .headers()
+                .get(ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .and_then(Encoding::negotiate)
+        } else {
+            None
+        };
+
+        CompressionFuture {
+            inner: self.inner.call_http(request),
+            encoding,
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+pub struct CompressionFuture<F> {
+    inner: F,
+    encoding: Option<Encoding>,
+    settings: CompressionSettings,
+}
+
+impl<F, B> Future for CompressionFuture<F>
+where
+    F: Future<Item = Response<B>>,
+    B: BufStream,
+{
+    type Item = Response<CompressedBody<B>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let response = try_ready!(self.inner.poll());
+        let (mut parts, body) = response.into_parts();
+
+        let known_size = body.size_hint().upper().or_else(|| {
+            parts
+                .headers
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+        });
+        let too_small = known_size.map(|size| size < self.settings.min_size).unwrap_or(false);
+        let already_encoded = parts.headers.contains_key(CONTENT_ENCODING);
+
+        let encoder = match self.encoding {
+            Some(encoding) if !too_small && !already_encoded => {
+                parts.headers.insert(CONTENT_ENCODING, encoding.header_value());
+                parts.headers.remove(CONTENT_LENGTH);
+                Some(Encoder::new(encoding, self.settings.level))
+            }
+            _ => None,
+        };
+        if self.settings.enabled {
+            parts.headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        Ok(Async::Ready(Response::from_parts(parts, CompressedBody::new(body, encoder))))
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding, level: u32) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(Vec::new(), Compression::new(level.min(9)))),
+            Encoding::Deflate => Encoder::Deflate(DeflateEncoder::new(Vec::new(), Compression::new(level.min(9)))),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(Vec::new(), 4096, level.min(11), 22))),
+        }
+    }
+
+    /// Feed a chunk to the encoder, returning whatever compressed output is ready so far
+    fn encode(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let result = match self {
+            Encoder::Gzip(e) => e.write_all(chunk).map(|_| mem::replace(e.get_mut(), Vec::new())),
+            Encoder::Deflate(e) => e.write_all(chunk).map(|_| mem::replace(e.get_mut(), Vec::new())),
+            // Brotli holds on to its output until the stream is finished
+            Encoder::Brotli(e) => e.write_all(chunk).map(|_| Vec::new()),
+        };
+        result.expect("Writing to an in memory buffer cannot fail")
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let result = match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Deflate(e) => e.finish(),
+            Encoder::Brotli(e) => Ok((*e).into_inner()),
+        };
+        result.expect("Writing to an in memory buffer cannot fail")
+    }
+}
+
+/// A response body that is either passed through untouched or compressed as it streams
+pub struct CompressedBody<B> {
+    inner: B,
+    encoder: Option<Encoder>,
+    done: bool,
+}
+
+impl<B> CompressedBody<B> {
+    fn new(inner: B, encoder: Option<Encoder>) -> Self {
+        Self {
+            inner,
+            encoder,
+            done: false,
+        }
+    }
+}
+
+impl<B> BufStream for CompressedBody<B>
+where
+    B: BufStream,
+{
+    type Item = Cursor<Vec<u8>>;
+    type Error = B::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => {
+                    let chunk: Vec<u8> = chunk.collect();
+                    match self.encoder {
+                        Some(ref mut encoder) => {
+                            let out = encoder.encode(&chunk);
+                            if !out.is_empty() {
+                                return Ok(Async::Ready(Some(Cursor::new(out))));
+                            }
+                        }
+                        None => return Ok(Async::Ready(Some(Cursor::new(chunk)))),
+                    }
+                }
+                None => {
+                    self.done = true;
+                    return Ok(Async::Ready(self.encoder.take().map(|e| Cursor::new(e.finish()))));
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match self.encoder {
+            Some(_) => SizeHint::default(),
+            None => self.inner.size_hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("deflate;q=1.0, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(Encoding::negotiate("br;q=0, gzip;q=0.2"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate(""), None);
+    }
+
+    #[test]
+    fn test_gzip_roundtrip() {
+        let text = r#"{"hits": 1, "docs": [{"doc": {"test_text": ["Test Document 1"]}}]}"#;
+        let mut encoder = Encoder::new(Encoding::Gzip, 6);
+        let mut compressed = encoder.encode(text.as_bytes());
+        compressed.extend(encoder.finish());
+
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+    }
+}
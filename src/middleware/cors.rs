@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use http::header::{HeaderName, HeaderValue};
+use http::Method;
+use log::warn;
+use tower_web::middleware::cors::{AllowedOrigins, CorsBuilder, CorsMiddleware};
+
+use crate::settings::CorsSettings;
+
+/// Build the CORS middleware described by the settings, or none when CORS is disabled. Browsers then keep enforcing
+/// the same-origin policy, since no response carries CORS headers.
+pub fn cors_middleware(settings: &CorsSettings) -> Option<CorsMiddleware> {
+    if !settings.enabled {
+        return None;
+    }
+
+    let mut builder = CorsBuilder::new()
+        .allow_origins(allowed_origins(&settings.allowed_origins))
+        .allow_methods(parse_all(&settings.allowed_methods, |m| Method::from_bytes(m.as_bytes()).ok()))
+        .allow_headers(parse_all(&settings.allowed_headers, |h| HeaderName::from_bytes(h.as_bytes()).ok()))
+        .allow_credentials(settings.allow_credentials);
+
+    if settings.max_age > 0 {
+        builder = builder.max_age(Duration::from_secs(settings.max_age));
+    }
+    Some(builder.build())
+}
+
+/// Whether the CORS middleware would answer a request from `origin`, for listeners it can't be put in front of
+pub fn allows_origin(settings: &CorsSettings, origin: &HeaderValue) -> bool {
+    !settings.enabled
+        || settings
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.trim().as_bytes() == origin.as_bytes())
+}
+
+fn allowed_origins(origins: &[String]) -> AllowedOrigins {
+    if origins.iter().any(|o| o == "*") {
+        AllowedOrigins::Any { allow_null: false }
+    } else {
+        AllowedOrigins::Origins(parse_all(origins, |o| HeaderValue::from_str(o).ok()))
+    }
+}
+
+fn parse_all<T, C, F>(values: &[String], parse: F) -> C
+where
+    C: std::iter::FromIterator<T>,
+    F: Fn(&str) -> Option<T>,
+{
+    values
+        .iter()
+        .filter_map(|v| {
+            let parsed = parse(v.trim());
+            if parsed.is_none() {
+                warn!("Ignoring invalid CORS setting: '{}'", v);
+            }
+            parsed
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_methods() {
+        let methods = vec!["GET".to_string(), "POST".to_string(), "NOT A METHOD".to_string()];
+        let parsed: Vec<Method> = parse_all(&methods, |m| Method::from_bytes(m.as_bytes()).ok());
+        assert_eq!(parsed, vec![Method::GET, Method::POST]);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut settings = crate::settings::Settings::default_cors();
+        assert!(cors_middleware(&settings).is_none());
+        settings.enabled = true;
+        assert!(cors_middleware(&settings).is_some());
+    }
+
+    #[test]
+    fn test_allows_origin() {
+        let mut settings = crate::settings::Settings::default_cors();
+        let origin = HeaderValue::from_static("http://localhost:3000");
+        assert!(allows_origin(&settings, &origin));
+        settings.enabled = true;
+        settings.allowed_origins = vec!["http://example.com".into()];
+        assert!(!allows_origin(&settings, &origin));
+        settings.allowed_origins.push("http://localhost:3000".into());
+        assert!(allows_origin(&settings, &origin));
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        match allowed_origins(&["*".to_string()]) {
+            AllowedOrigins::Any { .. } => {}
+            _ => panic!("Expected any origin to be allowed"),
+        }
+        match allowed_origins(&["http://localhost:3000".to_string()]) {
+            AllowedOrigins::Origins(o) => assert!(o.contains(&HeaderValue::from_static("http://localhost:3000"))),
+            _ => panic!("Expected a list of origins"),
+        }
+    }
+}
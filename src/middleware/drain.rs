@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use futures::{try_ready, Async, Future, Poll};
+use http::header::{HeaderValue, CONNECTION};
+use http::{Request, Response, StatusCode};
+use tower_web::middleware::Middleware;
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+use tower_web_service::Service;
+
+use crate::lifecycle::{InFlight, Lifecycle};
+use crate::middleware::error_response;
+
+/// Requests that are still served while the node drains, so it can be observed and drained again
+fn is_control_path(path: &str) -> bool {
+    path.starts_with("/_drain") || path.starts_with("/_health")
+}
+
+/// Counts in flight requests, and turns new requests away with a `503 Service Unavailable`
+/// once the node has started draining.
+#[derive(Clone)]
+pub struct DrainMiddleware {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl DrainMiddleware {
+    pub fn new(lifecycle: Arc<Lifecycle>) -> Self {
+        Self { lifecycle }
+    }
+}
+
+impl<S> Middleware<S> for DrainMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Service = DrainService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        DrainService {
+            inner,
+            lifecycle: Arc::clone(&self.lifecycle),
+        }
+    }
+}
+
+pub struct DrainService<S> {
+    inner: S,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl<S> Service for DrainService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Future = DrainFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let path = request.uri().path().to_string();
+        if is_control_path(&path) {
+            return DrainFuture::Inner(self.inner.call_http(request), None);
+        }
+        if self.lifecycle.is_draining() {
+            let mut response = error_response(StatusCode::SERVICE_UNAVAILABLE, "Node is draining", &path);
+            response.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+            return DrainFuture::Rejected(Some(response));
+        }
+
+        let guard = Lifecycle::start_request(&self.lifecycle);
+        DrainFuture::Inner(self.inner.call_http(request), Some(guard))
+    }
+}
+
+pub enum DrainFuture<F> {
+    Inner(F, Option<InFlight>),
+    Rejected(Option<Response<String>>),
+}
+
+impl<F, B> Future for DrainFuture<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<Either2<B, String>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self {
+            DrainFuture::Inner(fut, guard) => {
+                let response = try_ready!(fut.poll());
+                guard.take();
+                Ok(Async::Ready(response.map(Either2::A)))
+            }
+            DrainFuture::Rejected(response) => {
+                let response = response.take().expect("DrainFuture polled after completion");
+                Ok(Async::Ready(response.map(Either2::B)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_paths() {
+        assert!(is_control_path("/_drain"));
+        assert!(is_control_path("/_health/ready"));
+        assert!(!is_control_path("/test_index"));
+        assert!(!is_control_path("/test_index/_bulk"));
+    }
+}
@@ -0,0 +1,144 @@
+//! Middleware wrapped around the HTTP router
+
+pub use self::admission::{Admission, Rejection};
+pub use self::body::{BodyError, RequestBodyMiddleware};
+pub use self::compression::CompressionMiddleware;
+pub use self::cors::cors_middleware;
+pub use self::drain::DrainMiddleware;
+pub use self::rate_limit::{RateLimitMiddleware, RateLimiter};
+
+pub mod admission;
+pub mod body;
+pub mod compression;
+pub mod cors;
+pub mod drain;
+pub mod rate_limit;
+
+use futures::{try_ready, Async, Future, Poll};
+use http::header::HeaderValue;
+use http::{Method, Request, Response, StatusCode};
+use tower_web::util::tuple::Either2;
+
+use crate::handlers::ErrorResponse;
+
+/// The broad classes of requests the router serves, used to pick which limits apply
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Endpoint {
+    Search,
+    Bulk,
+    Index,
+    Admin,
+}
+
+/// Which kind of endpoint a request with a method and path is for, each API classifies its own
+pub type Classify = fn(&Method, &str) -> Endpoint;
+
+impl Endpoint {
+    pub fn classify<B>(request: &Request<B>) -> Endpoint {
+        Endpoint::toshi(request.method(), request.uri().path())
+    }
+
+    /// The endpoints of Toshi's own API
+    pub fn toshi(method: &Method, path: &str) -> Endpoint {
+        let path = path.trim_matches('/');
+        if path.ends_with("/_bulk") {
+            return Endpoint::Bulk;
+        }
+        if path.is_empty() || path.starts_with('_') {
+            return Endpoint::Admin;
+        }
+        if path.ends_with("/_create") {
+            return Endpoint::Index;
+        }
+        match *method {
+            Method::GET | Method::POST if !path.contains('/') => Endpoint::Search,
+            Method::PUT | Method::DELETE if !path.contains('/') => Endpoint::Index,
+            _ => Endpoint::Admin,
+        }
+    }
+
+    /// The endpoints of the Elasticsearch compatible API
+    pub fn elasticsearch(_: &Method, path: &str) -> Endpoint {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["_bulk"] | [_, "_bulk"] => Endpoint::Bulk,
+            [_, "_doc", ..] => Endpoint::Index,
+            [_, "_search"] => Endpoint::Search,
+            _ => Endpoint::Admin,
+        }
+    }
+}
+
+/// Build a JSON error response in the same shape the router's catch handler produces,
+/// for middleware that has to answer a request without ever calling the handlers.
+pub fn error_response(status: StatusCode, message: &str, uri: &str) -> Response<String> {
+    let err_msg = ErrorResponse::new(message.into(), uri.into());
+    let body = serde_json::to_string(&err_msg).unwrap();
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("content-type", HeaderValue::from_static("application/json"));
+    response
+}
+
+/// Response future for middleware that either passes a request through or answers it immediately
+pub enum ResponseFuture<F> {
+    Inner(F),
+    Immediate(Option<Response<String>>),
+}
+
+impl<F, B> Future for ResponseFuture<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<Either2<B, String>>;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self {
+            ResponseFuture::Inner(fut) => {
+                let response = try_ready!(fut.poll());
+                Ok(Async::Ready(response.map(Either2::A)))
+            }
+            ResponseFuture::Immediate(response) => {
+                let response = response.take().expect("ResponseFuture polled after completion");
+                Ok(Async::Ready(response.map(Either2::B)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str) -> Request<()> {
+        Request::builder().method(method).uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(Endpoint::classify(&request(Method::POST, "/test_index")), Endpoint::Search);
+        assert_eq!(Endpoint::classify(&request(Method::GET, "/test_index")), Endpoint::Search);
+        assert_eq!(Endpoint::classify(&request(Method::POST, "/test_index/_bulk")), Endpoint::Bulk);
+        assert_eq!(Endpoint::classify(&request(Method::PUT, "/test_index")), Endpoint::Index);
+        assert_eq!(Endpoint::classify(&request(Method::PUT, "/test_index/_create")), Endpoint::Index);
+        assert_eq!(Endpoint::classify(&request(Method::DELETE, "/test_index")), Endpoint::Index);
+        assert_eq!(Endpoint::classify(&request(Method::GET, "/test_index/_summary")), Endpoint::Admin);
+        assert_eq!(Endpoint::classify(&request(Method::GET, "/")), Endpoint::Admin);
+    }
+
+    #[test]
+    fn test_classify_elasticsearch() {
+        assert_eq!(Endpoint::elasticsearch(&Method::POST, "/_bulk"), Endpoint::Bulk);
+        assert_eq!(Endpoint::elasticsearch(&Method::POST, "/test_index/_bulk"), Endpoint::Bulk);
+        assert_eq!(Endpoint::elasticsearch(&Method::PUT, "/test_index/_doc/1"), Endpoint::Index);
+        assert_eq!(Endpoint::elasticsearch(&Method::POST, "/test_index/_doc"), Endpoint::Index);
+        assert_eq!(Endpoint::elasticsearch(&Method::POST, "/test_index/_search"), Endpoint::Search);
+        assert_eq!(Endpoint::elasticsearch(&Method::GET, "/"), Endpoint::Admin);
+        // Toshi's own API would take these for a search and an admin request
+        assert_eq!(Endpoint::toshi(&Method::POST, "/_bulk"), Endpoint::Admin);
+        assert_eq!(Endpoint::toshi(&Method::PUT, "/test_index/_doc/1"), Endpoint::Admin);
+    }
+}
@@ -0,0 +1,386 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chashmap::CHashMap;
+use futures::{Future, Poll, Stream};
+use futures_watch::Watch;
+use http::header::HeaderValue;
+use http::{Request, Response, StatusCode};
+use log::{debug, error, info};
+use tokio::timer::Interval;
+use tower_web::middleware::Middleware;
+use tower_web::util::http::HttpService;
+use tower_web::util::tuple::Either2;
+use tower_web_service::Service;
+
+use crate::middleware::{error_response, Classify, Endpoint, ResponseFuture};
+use crate::settings::{RateLimitSettings, Settings};
+
+/// How often buckets that have refilled completely are dropped, a client coming back gets a full bucket anyway
+const SWEEP_INTERVAL_SECS: u64 = 10;
+
+/// A classic token bucket, refilled continuously at `rate` tokens per second up to `burst`
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(burst: f64, now: Instant) -> Self {
+        Self { tokens: burst, last: now }
+    }
+
+    /// Take a single token, or return how long the caller has to wait until one is available
+    pub fn try_take(&mut self, rate: f64, burst: f64, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+
+    fn is_full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        let idle = now.duration_since(self.last).as_secs() as f64;
+        self.tokens + idle * rate >= burst
+    }
+}
+
+/// Per client rate limiter, clients are identified by their API key when they send one of the configured keys
+/// and by their address otherwise.
+pub struct RateLimiter {
+    settings: RwLock<RateLimitSettings>,
+    buckets: CHashMap<(Endpoint, String), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings: RwLock::new(settings),
+            buckets: CHashMap::new(),
+        }
+    }
+
+    /// Keep the limiter in step with reloaded settings for as long as `watch` is alive
+    pub fn follow(limiter: &Arc<RateLimiter>, watch: Watch<Settings>) -> impl Future<Item = (), Error = ()> {
+        let limiter = Arc::clone(limiter);
+        let current = watch.clone();
+        watch
+            .for_each(move |_| {
+                limiter.update(current.borrow().rate_limit.clone());
+                Ok(())
+            })
+            .map_err(|e| error!("Stopped following rate limit settings: {:?}", e))
+    }
+
+    /// Drop the buckets that have refilled completely every `SWEEP_INTERVAL_SECS`, so clients that have gone
+    /// away aren't tracked forever
+    pub fn sweep(limiter: &Arc<RateLimiter>) -> impl Future<Item = (), Error = ()> {
+        let limiter = Arc::clone(limiter);
+        Interval::new_interval(Duration::from_secs(SWEEP_INTERVAL_SECS))
+            .for_each(move |_| {
+                limiter.evict_full(Instant::now());
+                Ok(())
+            })
+            .map_err(|e| error!("Stopped sweeping rate limit buckets: {:?}", e))
+    }
+
+    fn evict_full(&self, now: Instant) {
+        let settings = self.settings();
+        self.buckets.retain(|(e, _), bucket| {
+            let (rate, burst) = Self::limits(&settings, *e).unwrap_or((0.0, 0.0));
+            !bucket.is_full(rate, burst, now)
+        });
+    }
+
+    /// Swap in new limits. Buckets are sized by the old burst, so every client starts over.
+    pub fn update(&self, settings: RateLimitSettings) {
+        info!("Updating rate limits: {:?}", settings);
+        *self.settings.write().expect("Rate limit settings lock poisoned") = settings;
+        self.buckets.clear();
+    }
+
+    fn settings(&self) -> RateLimitSettings {
+        self.settings.read().expect("Rate limit settings lock poisoned").clone()
+    }
+
+    fn limits(settings: &RateLimitSettings, endpoint: Endpoint) -> Option<(f64, f64)> {
+        match endpoint {
+            Endpoint::Search => Some((settings.search_per_second, settings.search_burst)),
+            Endpoint::Bulk => Some((settings.bulk_per_second, settings.bulk_burst)),
+            _ => None,
+        }
+    }
+
+    /// The identity a request is accounted against
+    pub fn client_key<B>(&self, request: &Request<B>) -> String {
+        Self::key_for(&self.settings(), request)
+    }
+
+    fn key_for<B>(settings: &RateLimitSettings, request: &Request<B>) -> String {
+        let headers = request.headers();
+        // Keys aren't secret from the client, only the ones that have been handed out are taken for an identity or
+        // a client could get a fresh bucket with every request by making one up
+        if let Some(key) = headers.get(settings.key_header.as_str()).and_then(|v| v.to_str().ok()) {
+            if settings.api_keys.iter().any(|known| known == key) {
+                return format!("key:{}", key);
+            }
+        }
+        let peer = match request.extensions().get::<SocketAddr>() {
+            Some(addr) => addr.ip(),
+            None => return "anonymous".into(),
+        };
+        if !settings.trusted_proxies.contains(&peer) {
+            return format!("ip:{}", peer);
+        }
+        // Each proxy appends the address it got the request from, so the client is the last one a proxy we trust
+        // didn't add, anything before that could have been sent by the client itself
+        let forwarded: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|ip| ip.trim().parse().ok())
+            .collect();
+        let client = forwarded
+            .into_iter()
+            .rev()
+            .find(|ip| !settings.trusted_proxies.contains(ip))
+            .unwrap_or(peer);
+        format!("ip:{}", client)
+    }
+
+    /// Account for a request, returning how long the client should back off if it is over its limit
+    pub fn check<B>(&self, request: &Request<B>) -> Result<(), Duration> {
+        self.check_endpoint(Endpoint::classify(request), request)
+    }
+
+    /// Account for a request to `endpoint`, for APIs that tell their endpoints apart differently from Toshi's own
+    pub fn check_endpoint<B>(&self, endpoint: Endpoint, request: &Request<B>) -> Result<(), Duration> {
+        let settings = self.settings();
+        if !settings.enabled {
+            return Ok(());
+        }
+        let (rate, burst) = match Self::limits(&settings, endpoint) {
+            Some((rate, burst)) if rate > 0.0 => (rate, burst),
+            _ => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let key = (endpoint, Self::key_for(&settings, request));
+        let mut result = Ok(());
+        self.buckets.alter(key, |bucket| {
+            let mut bucket = bucket.unwrap_or_else(|| TokenBucket::new(burst, now));
+            result = bucket.try_take(rate, burst, now);
+            Some(bucket)
+        });
+        result
+    }
+}
+
+/// Rejects requests from clients that exceed their token bucket with a `429 Too Many Requests`. Clients without an
+/// API key are told apart by the address `router::serve` puts in the extensions of every request it serves.
+#[derive(Clone)]
+pub struct RateLimitMiddleware {
+    limiter: Arc<RateLimiter>,
+    classify: Classify,
+}
+
+impl RateLimitMiddleware {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            limiter,
+            classify: Endpoint::toshi,
+        }
+    }
+
+    /// Tell which limits apply to a request with `classify`, for APIs laid out differently from Toshi's own
+    pub fn classifying(mut self, classify: Classify) -> Self {
+        self.classify = classify;
+        self
+    }
+}
+
+impl<S> Middleware<S> for RateLimitMiddleware
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Service = RateLimitService<S>;
+
+    fn wrap(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: Arc::clone(&self.limiter),
+            classify: self.classify,
+        }
+    }
+}
+
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    classify: Classify,
+}
+
+impl<S> Service for RateLimitService<S>
+where
+    S: HttpService,
+{
+    type Request = Request<S::RequestBody>;
+    type Response = Response<Either2<S::ResponseBody, String>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, request: Self::Request) -> Self::Future {
+        let endpoint = (self.classify)(request.method(), request.uri().path());
+        match self.limiter.check_endpoint(endpoint, &request) {
+            Ok(()) => ResponseFuture::Inner(self.inner.call_http(request)),
+            Err(wait) => {
+                debug!("Rate limited {}, retry in {:?}", self.limiter.client_key(&request), wait);
+                let retry_after = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded", request.uri().path());
+                response.headers_mut().insert("retry-after", HeaderValue::from(retry_after));
+                ResponseFuture::Immediate(Some(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    fn request(method: Method, uri: &str) -> Request<()> {
+        Request::builder().method(method).uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, now);
+        assert!(bucket.try_take(1.0, 2.0, now).is_ok());
+        assert!(bucket.try_take(1.0, 2.0, now).is_ok());
+        let wait = bucket.try_take(1.0, 2.0, now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert!(bucket.try_take(1.0, 2.0, now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_limits_per_client() {
+        let mut settings = crate::settings::Settings::default_rate_limit();
+        settings.enabled = true;
+        settings.search_per_second = 1.0;
+        settings.search_burst = 1.0;
+        settings.api_keys = vec!["tenant-a".into(), "tenant-b".into()];
+        let limiter = RateLimiter::new(settings);
+
+        let first = Request::builder()
+            .method(Method::POST)
+            .uri("/test_index")
+            .header("x-api-key", "tenant-a")
+            .body(())
+            .unwrap();
+        let second = Request::builder()
+            .method(Method::POST)
+            .uri("/test_index")
+            .header("x-api-key", "tenant-b")
+            .body(())
+            .unwrap();
+
+        assert!(limiter.check(&first).is_ok());
+        assert!(limiter.check(&first).is_err());
+        assert!(limiter.check(&second).is_ok());
+        assert!(limiter.check(&request(Method::PUT, "/test_index")).is_ok());
+    }
+
+    #[test]
+    fn test_client_key() {
+        let mut settings = crate::settings::Settings::default_rate_limit();
+        settings.trusted_proxies = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        settings.api_keys = vec!["tenant-a".into()];
+        let limiter = RateLimiter::new(settings);
+        let with = |peer: &str, header: &str, value: Option<&str>| {
+            let mut request = request(Method::POST, "/test_index");
+            request.extensions_mut().insert(peer.parse::<SocketAddr>().unwrap());
+            if let Some(value) = value {
+                request.headers_mut().insert(header, HeaderValue::from_str(value).unwrap());
+            }
+            limiter.client_key(&request)
+        };
+        let from = |peer: &str, forwarded: Option<&str>| with(peer, "x-forwarded-for", forwarded);
+
+        assert_eq!(limiter.client_key(&request(Method::POST, "/test_index")), "anonymous");
+        assert_eq!(from("192.0.2.7:5000", None), "ip:192.0.2.7");
+        // Only proxies that are trusted get to say who the client is
+        assert_eq!(from("192.0.2.7:5000", Some("198.51.100.1")), "ip:192.0.2.7");
+        assert_eq!(from("10.0.0.1:5000", Some("198.51.100.1")), "ip:198.51.100.1");
+        assert_eq!(
+            from("10.0.0.1:5000", Some("203.0.113.9, 198.51.100.1, 10.0.0.2")),
+            "ip:198.51.100.1"
+        );
+        assert_eq!(from("10.0.0.1:5000", Some("10.0.0.2")), "ip:10.0.0.2");
+        assert_eq!(from("10.0.0.1:5000", Some("not an address")), "ip:10.0.0.1");
+        // Made up keys don't get a bucket of their own
+        assert_eq!(with("192.0.2.7:5000", "x-api-key", Some("tenant-a")), "key:tenant-a");
+        assert_eq!(with("192.0.2.7:5000", "x-api-key", Some("made-up")), "ip:192.0.2.7");
+    }
+
+    #[test]
+    fn test_evict_full() {
+        let mut settings = crate::settings::Settings::default_rate_limit();
+        settings.enabled = true;
+        settings.search_per_second = 1.0;
+        settings.search_burst = 2.0;
+        let limiter = RateLimiter::new(settings);
+        let from = |peer: &str| {
+            let mut request = request(Method::POST, "/test_index");
+            request.extensions_mut().insert(peer.parse::<SocketAddr>().unwrap());
+            request
+        };
+
+        assert!(limiter.check(&from("192.0.2.1:5000")).is_ok());
+        assert!(limiter.check(&from("192.0.2.2:5000")).is_ok());
+        assert!(limiter.check(&from("192.0.2.2:5000")).is_ok());
+        assert_eq!(limiter.buckets.len(), 2);
+
+        // The first client's bucket is full again a second later, the second's only after two
+        limiter.evict_full(Instant::now() + Duration::from_secs(1));
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(limiter.check(&from("192.0.2.2:5000")).is_err());
+        limiter.evict_full(Instant::now() + Duration::from_secs(3));
+        assert_eq!(limiter.buckets.len(), 0);
+    }
+
+    #[test]
+    fn test_update_limits() {
+        let mut settings = crate::settings::Settings::default_rate_limit();
+        settings.enabled = true;
+        settings.search_per_second = 1.0;
+        settings.search_burst = 1.0;
+        let limiter = RateLimiter::new(settings.clone());
+        let search = request(Method::POST, "/test_index");
+
+        assert!(limiter.check(&search).is_ok());
+        assert!(limiter.check(&search).is_err());
+
+        settings.enabled = false;
+        limiter.update(settings);
+        assert!(limiter.check(&search).is_ok());
+    }
+}
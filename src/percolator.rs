@@ -0,0 +1,177 @@
+//! Percolation turns searching around: queries are registered with an index ahead of time, and a document is then
+//! checked against all of them to find the ones it would match, which is what alerts and saved search notifications
+//! need. The registered queries are kept in `.percolator.json` in the index's directory, so they go with the index when
+//! it's dropped or moved. Indexes without a directory, such as ones opened by tests, keep them in memory.
+//!
+//! Each document is matched by indexing it on its own into an index in memory with the same schema and running every
+//! registered query against it, so queries match exactly as they would in a search.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_json::Value;
+use tantivy::collector::Count;
+use tantivy::schema::Schema;
+use tantivy::Index;
+
+use crate::analysis;
+use crate::index::IndexCatalog;
+use crate::query::Query;
+use crate::settings::Settings;
+use crate::{Error, Result};
+
+pub const PERCOLATOR_FILENAME: &str = ".percolator.json";
+/// Matching a document only ever indexes that one document, so its writer gets the smallest heap tantivy allows
+const PERCOLATE_HEAP: usize = 3_000_000;
+
+/// The queries registered with each index, by their ids
+pub type Queries = BTreeMap<String, Value>;
+
+#[derive(Clone)]
+pub struct Percolator {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    /// Queries of indexes without a directory to keep them in
+    in_memory: Arc<Mutex<HashMap<String, Queries>>>,
+    /// Held while registering or removing a query, so concurrent changes to the same file aren't lost
+    writing: Arc<Mutex<()>>,
+}
+
+impl Percolator {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        Percolator {
+            catalog,
+            in_memory: Arc::new(Mutex::new(HashMap::new())),
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// An empty index in memory with the schema and analyzers of `index`
+    fn empty(&self, schema: Schema) -> Result<Index> {
+        let index = Index::create_in_ram(schema);
+        analysis::register(index.tokenizers(), &index.schema(), &self.catalog.read()?.settings);
+        Ok(index)
+    }
+
+    /// The schema of `index`, and the directory its queries are kept in if it has one
+    fn index(&self, index: &str) -> Result<(Schema, Option<PathBuf>)> {
+        let catalog = self.catalog.read()?;
+        let schema = match catalog.shards(index)?.first() {
+            Some(shard) => shard.get_index().schema(),
+            None => return Err(Error::UnknownIndex(index.to_string())),
+        };
+        let path = catalog.index_path(index).ok().filter(|path| path.is_dir());
+        Ok((schema, path))
+    }
+
+    pub fn queries(&self, index: &str) -> Result<Queries> {
+        match self.index(index)? {
+            (_, Some(path)) => match fs::read(path.join(PERCOLATOR_FILENAME)) {
+                Ok(stored) => Ok(serde_json::from_slice(&stored)?),
+                Err(_) => Ok(Queries::new()),
+            },
+            (_, None) => Ok(self.in_memory.lock()?.get(index).cloned().unwrap_or_default()),
+        }
+    }
+
+    fn save(&self, index: &str, path: Option<PathBuf>, queries: Queries) -> Result<()> {
+        match path {
+            Some(path) => fs::write(path.join(PERCOLATOR_FILENAME), serde_json::to_vec_pretty(&queries)?)?,
+            None => {
+                self.in_memory.lock()?.insert(index.to_string(), queries);
+            }
+        }
+        Ok(())
+    }
+
+    /// Register `query` with `index` as `id`, replacing any query registered as `id` before
+    pub fn register(&self, index: &str, id: &str, query: Value) -> Result<()> {
+        let _writing = self.writing.lock()?;
+        let (schema, path) = self.index(index)?;
+        // A query that can't run against the index is refused now rather than failing every document later
+        serde_json::from_value::<Query>(query.clone())?.create(&self.empty(schema)?, None, None)?;
+        let mut queries = self.queries(index)?;
+        queries.insert(id.to_string(), query);
+        self.save(index, path, queries)
+    }
+
+    /// Remove the query registered as `id`, returning whether there was one
+    pub fn remove(&self, index: &str, id: &str) -> Result<bool> {
+        let _writing = self.writing.lock()?;
+        let (_, path) = self.index(index)?;
+        let mut queries = self.queries(index)?;
+        let removed = queries.remove(id).is_some();
+        if removed {
+            self.save(index, path, queries)?;
+        }
+        Ok(removed)
+    }
+
+    /// The ids of the registered queries `document` matches
+    pub fn percolate(&self, index: &str, document: &Value) -> Result<Vec<String>> {
+        let (schema, _) = self.index(index)?;
+        let queries = self.queries(index)?;
+        let settings = self.catalog.read()?.settings.clone();
+        matching(schema, &settings, document, queries)
+    }
+}
+
+/// The ids of the `queries` that `document` matches once indexed with `schema` and the analyzers of `settings`
+pub fn matching(schema: Schema, settings: &Settings, document: &Value, queries: Queries) -> Result<Vec<String>> {
+    let doc = schema.parse_document(&document.to_string())?;
+    let index = Index::create_in_ram(schema);
+    analysis::register(index.tokenizers(), &index.schema(), settings);
+    let mut writer = index.writer_with_num_threads(1, PERCOLATE_HEAP)?;
+    writer.add_document(doc);
+    writer.commit()?;
+    index.load_searchers()?;
+    let searcher = index.searcher();
+
+    let mut matches = Vec::new();
+    for (id, query) in queries {
+        let query = serde_json::from_value::<Query>(query)?.create(&index, None, None)?;
+        if searcher.search(&*query, &Count)? > 0 {
+            matches.push(id);
+        }
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    #[test]
+    fn test_percolate() {
+        let percolator = Percolator::new(create_test_catalog("test_index"));
+        percolator
+            .register("test_index", "babbaboo", json!({ "term": { "test_text": "babbaboo" } }))
+            .unwrap();
+        percolator
+            .register("test_index", "recent", json!({ "range": { "test_i64": { "gte": 2019 } } }))
+            .unwrap();
+        percolator
+            .register("test_index", "raw", json!({ "raw": "test_text:document AND test_u64:10" }))
+            .unwrap();
+        assert!(percolator
+            .register("test_index", "unknown", json!({ "term": { "missing": "field" } }))
+            .is_err());
+        assert!(percolator
+            .register("missing", "query", json!({ "raw": "test_text:document" }))
+            .is_err());
+        assert_eq!(percolator.queries("test_index").unwrap().len(), 3);
+
+        let document = json!({ "test_text": "Babbaboo", "test_i64": 2020, "test_u64": 10 });
+        assert_eq!(percolator.percolate("test_index", &document).unwrap(), vec!["babbaboo", "recent"]);
+        let document = json!({ "test_text": "Document", "test_i64": 2000, "test_u64": 10 });
+        assert_eq!(percolator.percolate("test_index", &document).unwrap(), vec!["raw"]);
+
+        assert!(percolator.remove("test_index", "recent").unwrap());
+        assert!(!percolator.remove("test_index", "recent").unwrap());
+        let document = json!({ "test_text": "Babbaboo", "test_i64": 2020 });
+        assert_eq!(percolator.percolate("test_index", &document).unwrap(), vec!["babbaboo"]);
+    }
+}
@@ -1,24 +1,347 @@
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::ops::Bound;
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::query::{Query, RangeQuery};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::{Executor, Result as TantivyResult, Searcher, SegmentReader};
+
+use crate::mapping::FieldMapping;
+use crate::query::aggregate::{merge_top_hits, SubAggregation, TopHitsResult};
+use crate::query::script::{Script, ScriptFilter};
+use crate::query::{FastValues, Query as ToshiQuery};
+use crate::{Error, Result};
 
-#[derive(Deserialize, Debug)]
+/// Count the documents a query matches in buckets of a numeric or date field with the bounds of each given
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RangeAggregation {
+    pub field: String,
+    pub ranges: Vec<Range>,
+    /// Bucket documents by what this script gives them instead of by the field's values, see `query::script`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// What's found for the documents of each bucket besides their count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<SubAggregation>,
+}
+
+/// The values from `from` up to but not including `to`, without a bound on the side either is left out
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Range {
-    from: Option<u64>,
-    to: Option<u64>,
+    /// The name of the bucket, `from-to` unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RangeBucket {
+    pub key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<Value>,
+    pub doc_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_hits: Option<TopHitsResult>,
+}
+
+/// Count the documents a query matches in named buckets, each of the documents another query matches
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FiltersAggregation {
+    pub filters: BTreeMap<String, ToshiQuery>,
+    /// What's found for the documents of each bucket besides their count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<SubAggregation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FilterBucket {
+    pub doc_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_hits: Option<TopHitsResult>,
+}
+
+/// The bounds of each range mapped to u64s that order the same way as the field's values
+type Bounds = Vec<(Option<u64>, Option<u64>)>;
+
+impl Range {
+    fn key(&self) -> String {
+        let bound = |value: &Option<Value>| match value {
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => "*".into(),
+        };
+        self.key
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", bound(&self.from), bound(&self.to)))
+    }
+}
+
+impl RangeAggregation {
+    /// A bucket for each range in the order they were given, empty ones included. Bounds are converted with the
+    /// field's `mapping` when it has one, so the bounds of a date field can be given in any of its formats.
+    pub fn collect(
+        &self,
+        searcher: &Searcher,
+        query: &Query,
+        executor: &Executor,
+        mapping: Option<&FieldMapping>,
+    ) -> Result<Vec<RangeBucket>> {
+        let schema = searcher.schema();
+        let (script, bounds) = self.bounds(&schema, mapping)?;
+        let collector = RangeCollector {
+            schema: schema.clone(),
+            field: self.field.clone(),
+            script,
+            bounds,
+        };
+        let counts = searcher.search_with_executor(query, &collector, executor)?;
+        Ok(self
+            .ranges
+            .iter()
+            .zip(counts)
+            .map(|(range, doc_count)| RangeBucket {
+                key: range.key(),
+                from: range.from.clone(),
+                to: range.to.clone(),
+                doc_count,
+                top_hits: None,
+            })
+            .collect())
+    }
+
+    /// The documents in the `nth` range
+    pub fn bucket_query(&self, schema: &Schema, nth: usize, mapping: Option<&FieldMapping>) -> Result<Box<Query>> {
+        let (script, bounds) = self.bounds(schema, mapping)?;
+        let (from, to) = bounds[nth];
+        let lower = from.map_or(Bound::Unbounded, Bound::Included);
+        let upper = to.map_or(Bound::Unbounded, Bound::Excluded);
+        let signed = |bound: Bound<u64>| match bound {
+            Bound::Included(v) => Bound::Included(tantivy::u64_to_i64(v)),
+            Bound::Excluded(v) => Bound::Excluded(tantivy::u64_to_i64(v)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        if let Some(script) = script {
+            return Ok(Box::new(ScriptFilter::within(script, signed(lower), signed(upper))));
+        }
+        let field = self.field(schema)?;
+        match schema.get_field_entry(field).field_type() {
+            FieldType::I64(_) => Ok(Box::new(RangeQuery::new_i64_bounds(field, signed(lower), signed(upper)))),
+            _ => Ok(Box::new(RangeQuery::new_u64_bounds(field, lower, upper))),
+        }
+    }
+
+    fn field(&self, schema: &Schema) -> Result<Field> {
+        schema
+            .get_field(&self.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", self.field)))
+    }
+
+    /// The script to bucket documents by if there's one, and the bounds of the ranges. A script's values are i64s,
+    /// and its bounds are taken as they're given rather than converted by the field's mapping.
+    fn bounds(&self, schema: &Schema, mapping: Option<&FieldMapping>) -> Result<(Option<Arc<Script>>, Bounds)> {
+        let (script, signed) = match self.script {
+            Some(ref script) => (Some(Arc::new(Script::compile(schema, script)?)), true),
+            None => match schema.get_field_entry(self.field(schema)?).field_type() {
+                FieldType::I64(options) if options.is_fast() => (None, true),
+                FieldType::U64(options) if options.is_fast() => (None, false),
+                _ => return Err(Error::QueryError(format!("Field {} is not a numeric fast field", self.field))),
+            },
+        };
+        let bound = |value: &Option<Value>| -> Result<Option<u64>> {
+            let value = match value {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let converted = match mapping {
+                Some(mapping) if script.is_none() => mapping.convert(value)?,
+                _ => value.clone(),
+            };
+            let bound = if signed {
+                converted.as_i64().map(tantivy::i64_to_u64)
+            } else {
+                converted.as_u64()
+            };
+            bound
+                .map(Some)
+                .ok_or_else(|| Error::QueryError(format!("{} is not a bound of field {}", value, self.field)))
+        };
+        let bounds = self
+            .ranges
+            .iter()
+            .map(|range| Ok((bound(&range.from)?, bound(&range.to)?)))
+            .collect::<Result<Bounds>>()?;
+        Ok((script, bounds))
+    }
+}
+
+/// Add the range buckets of several shards or nodes together, which each have every range in the same order
+pub fn merge_ranges<I: IntoIterator<Item = Vec<RangeBucket>>>(results: I) -> Vec<RangeBucket> {
+    let mut merged: Vec<RangeBucket> = Vec::new();
+    for buckets in results {
+        if merged.is_empty() {
+            merged = buckets;
+            continue;
+        }
+        for (existing, bucket) in merged.iter_mut().zip(buckets) {
+            existing.doc_count += bucket.doc_count;
+            existing.top_hits = merge_top_hits(existing.top_hits.take(), bucket.top_hits);
+        }
+    }
+    merged
 }
 
-#[derive(Serialize)]
-#[allow(dead_code)]
-pub struct RangeResult {
-    key: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    to: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    from: Option<String>,
-    num_docs: u64,
+/// Add the filter buckets of several shards or nodes together
+pub fn merge_filters<I: IntoIterator<Item = BTreeMap<String, FilterBucket>>>(results: I) -> BTreeMap<String, FilterBucket> {
+    let mut merged: BTreeMap<String, FilterBucket> = BTreeMap::new();
+    for (name, bucket) in results.into_iter().flat_map(|r| r.into_iter()) {
+        match merged.entry(name) {
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get_mut();
+                existing.doc_count += bucket.doc_count;
+                existing.top_hits = merge_top_hits(existing.top_hits.take(), bucket.top_hits);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(bucket);
+            }
+        }
+    }
+    merged
 }
 
-#[derive(Deserialize, Debug)]
-#[serde(untagged)]
-pub enum Buckets {
-    RangeAggregate { field: String, ranges: Vec<Range> },
+struct RangeCollector {
+    schema: Schema,
+    field: String,
+    script: Option<Arc<Script>>,
+    bounds: Bounds,
+}
+
+impl Collector for RangeCollector {
+    type Fruit = Vec<u64>;
+    type Child = RangeSegmentCollector;
+
+    fn for_segment(&self, _segment_local_id: u32, segment: &SegmentReader) -> TantivyResult<RangeSegmentCollector> {
+        let values = FastValues::named(segment, &self.schema, &self.field, self.script.as_ref())
+            .map_err(|e| tantivy::TantivyError::SchemaError(e.to_string()))?;
+        Ok(RangeSegmentCollector {
+            values,
+            found: Vec::new(),
+            bounds: self.bounds.clone(),
+            counts: vec![0; self.bounds.len()],
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_counts: Vec<Vec<u64>>) -> TantivyResult<Vec<u64>> {
+        let mut counts = vec![0; self.bounds.len()];
+        for segment in segment_counts {
+            for (count, found) in counts.iter_mut().zip(segment) {
+                *count += found;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+struct RangeSegmentCollector {
+    values: FastValues,
+    found: Vec<u64>,
+    bounds: Bounds,
+    counts: Vec<u64>,
+}
+
+impl SegmentCollector for RangeSegmentCollector {
+    type Fruit = Vec<u64>;
+
+    /// A document with several values counts once in each range any of them fall in
+    fn collect(&mut self, doc: u32, _score: f32) {
+        self.values.get(doc, &mut self.found);
+        for (count, (from, to)) in self.counts.iter_mut().zip(&self.bounds) {
+            let within = |v: &u64| from.map_or(true, |from| *v >= from) && to.map_or(true, |to| *v < to);
+            if self.found.iter().any(within) {
+                *count += 1;
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{SchemaBuilder, FAST, INDEXED};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_ranges() {
+        let mut builder = SchemaBuilder::new();
+        let latency = builder.add_i64_field("latency", INDEXED | FAST);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for value in &[-5i64, 20, 99, 100, 250, 800] {
+            writer.add_document(doc!(latency => *value));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+
+        let ranges: RangeAggregation = serde_json::from_str(
+            r#"{ "field": "latency", "ranges": [{ "to": 100 }, { "from": 100, "to": 500 }, { "key": "slow", "from": 500 }, { "from": 900 }] }"#,
+        )
+        .unwrap();
+        let buckets = ranges.collect(&searcher, &AllQuery, &Executor::single_thread(), None).unwrap();
+        let counts: Vec<(&str, u64)> = buckets.iter().map(|b| (b.key.as_str(), b.doc_count)).collect();
+        assert_eq!(counts, vec![("*-100", 3), ("100-500", 2), ("slow", 1), ("900-*", 0)]);
+
+        let bucket = ranges.bucket_query(&index.schema(), 1, None).unwrap();
+        assert_eq!(searcher.search(&bucket, &tantivy::collector::Count).unwrap(), 2);
+
+        let merged = merge_ranges(vec![buckets.clone(), buckets]);
+        assert_eq!(merged[0].doc_count, 6);
+        assert_eq!(merged[3].doc_count, 0);
+
+        let scripted: RangeAggregation =
+            serde_json::from_str(r#"{ "field": "tenths", "script": "latency / 10", "ranges": [{ "to": 10 }, { "from": 10 }] }"#).unwrap();
+        let buckets = scripted.collect(&searcher, &AllQuery, &Executor::single_thread(), None).unwrap();
+        assert_eq!(buckets.iter().map(|b| b.doc_count).collect::<Vec<_>>(), vec![3, 3]);
+        let bucket = scripted.bucket_query(&index.schema(), 1, None).unwrap();
+        assert_eq!(searcher.search(&bucket, &tantivy::collector::Count).unwrap(), 3);
+
+        let words: RangeAggregation = serde_json::from_str(r#"{ "field": "latency", "ranges": [{ "from": "fast" }] }"#).unwrap();
+        assert!(words.collect(&searcher, &AllQuery, &Executor::single_thread(), None).is_err());
+
+        let mut filters = BTreeMap::new();
+        filters.insert(
+            "errors".to_string(),
+            FilterBucket {
+                doc_count: 2,
+                top_hits: None,
+            },
+        );
+        let mut other = filters.clone();
+        other.insert(
+            "slow".to_string(),
+            FilterBucket {
+                doc_count: 1,
+                top_hits: None,
+            },
+        );
+        let merged = merge_filters(vec![filters, other]);
+        assert_eq!(merged["errors"].doc_count, 4);
+        assert_eq!(merged["slow"].doc_count, 1);
+    }
 }
@@ -0,0 +1,395 @@
+use std::collections::btree_map::{BTreeMap, Entry};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::query::Query;
+use tantivy::schema::{FieldType, IndexRecordOption, Schema};
+use tantivy::{DocId, DocSet, Searcher, SegmentReader};
+
+use crate::breaker::{MemoryBudget, BUCKET_BYTES};
+use crate::mapping::Mappings;
+use crate::query::aggregate::DateHistogram;
+use crate::query::FastValues;
+use crate::{Error, Result};
+
+/// Count the documents a query matches in buckets of every combination of the values of several sources, a page of
+/// `size` buckets at a time in the order of their keys, starting after the key `after` when it's given
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Composite {
+    /// Each source by the name its values have in bucket keys
+    pub sources: Vec<BTreeMap<String, CompositeSource>>,
+    #[serde(default = "Composite::default_size")]
+    pub size: usize,
+    /// The key of the last bucket of the previous page, which is the `after_key` it came with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<BTreeMap<String, Value>>,
+}
+
+/// Where the values of one part of a composite key come from
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeSource {
+    /// Each term of an indexed text field or value of a u64 or i64 fast field
+    Terms { field: String },
+    /// The start of the bucket of each date of a date field, in milliseconds since the epoch
+    DateHistogram(DateHistogram),
+}
+
+/// A page of composite buckets
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CompositeBuckets {
+    /// The key to give as `after` for the next page, left out when there are no more buckets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_key: Option<BTreeMap<String, Value>>,
+    pub buckets: Vec<CompositeBucket>,
+    /// The names of the sources in order and the size of a page, kept to merge the pages of several shards or nodes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CompositeBucket {
+    pub key: BTreeMap<String, Value>,
+    pub doc_count: u64,
+}
+
+/// A value of a source. Numbers that fit an i64 are always one, so a value compares the same whichever source or
+/// JSON it came from.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    I64(i64),
+    U64(u64),
+    Str(String),
+}
+
+impl Key {
+    fn from_u64(value: u64) -> Self {
+        if value <= i64::max_value() as u64 {
+            Key::I64(value as i64)
+        } else {
+            Key::U64(value)
+        }
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(text) => Some(Key::Str(text.clone())),
+            Value::Number(number) => number.as_i64().map(Key::I64).or_else(|| number.as_u64().map(Key::U64)),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            Key::I64(value) => Value::from(*value),
+            Key::U64(value) => Value::from(*value),
+            Key::Str(text) => Value::String(text.clone()),
+        }
+    }
+}
+
+impl Composite {
+    pub fn default_size() -> usize {
+        10
+    }
+
+    /// The first `size` buckets after `after` of the documents `query` matches. A document counts once in the bucket of
+    /// each combination of its values, and not at all when a source has none of them. Only the `size` smallest keys
+    /// are kept while counting, so a page costs as much as reading the values of every matching document. The values of
+    /// each segment's documents are taken from `budget` while they're counted, with the page of buckets.
+    pub fn collect(&self, searcher: &Searcher, query: &Query, mappings: &Mappings, budget: &MemoryBudget) -> Result<CompositeBuckets> {
+        let sources = self.named_sources()?;
+        let names: Vec<String> = sources.iter().map(|(name, _)| name.to_string()).collect();
+        let after = match self.after {
+            Some(ref after) => Some(keys_of(&names, after)?),
+            None => None,
+        };
+        let schema = searcher.schema();
+        let weight = query.weight(searcher, false)?;
+        budget.reserve(self.size.saturating_mul(BUCKET_BYTES))?;
+        let mut reserved = 0;
+        let mut counts: BTreeMap<Vec<Key>, u64> = BTreeMap::new();
+        for reader in searcher.segment_readers() {
+            let mut docs = Vec::new();
+            let mut scorer = weight.scorer(reader)?;
+            while scorer.advance() {
+                let doc = scorer.doc();
+                if !reader.delete_bitset().map(|d| d.is_deleted(doc)).unwrap_or(false) {
+                    docs.push(doc);
+                }
+            }
+            if docs.is_empty() {
+                continue;
+            }
+            // One segment's values are held at a time, so the budget only takes what the largest of them needs
+            let needed = docs.len().saturating_mul(BUCKET_BYTES).saturating_mul(sources.len());
+            if needed > reserved {
+                budget.reserve(needed - reserved)?;
+                reserved = needed;
+            }
+            let values = sources
+                .iter()
+                .map(|(_, source)| source.values(reader, &schema, &docs, mappings))
+                .collect::<Result<Vec<_>>>()?;
+            for nth in 0..docs.len() {
+                let mut keys: Vec<Vec<Key>> = vec![Vec::new()];
+                for source in &values {
+                    keys = keys
+                        .into_iter()
+                        .flat_map(|prefix| {
+                            source[nth].iter().map(move |value| {
+                                let mut key = prefix.clone();
+                                key.push(value.clone());
+                                key
+                            })
+                        })
+                        .collect();
+                }
+                for key in keys {
+                    if after.as_ref().map_or(false, |after| key <= *after) {
+                        continue;
+                    }
+                    *counts.entry(key).or_insert(0) += 1;
+                    // A key with `size` smaller ones can't be on this page, however many documents it's found in
+                    if counts.len() > self.size {
+                        let last = counts.keys().next_back().cloned();
+                        if let Some(last) = last {
+                            counts.remove(&last);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(page(&names, counts, self.size))
+    }
+
+    fn named_sources(&self) -> Result<Vec<(&str, &CompositeSource)>> {
+        if self.sources.is_empty() {
+            return Err(Error::QueryError("A composite aggregation has no sources".into()));
+        }
+        let mut named: Vec<(&str, &CompositeSource)> = Vec::with_capacity(self.sources.len());
+        for source in &self.sources {
+            let mut entries = source.iter();
+            match (entries.next(), entries.next()) {
+                (Some((name, source)), None) if named.iter().all(|(n, _)| *n != name) => named.push((name.as_str(), source)),
+                _ => {
+                    return Err(Error::QueryError(
+                        "Each source of a composite aggregation needs a single name of its own".into(),
+                    ))
+                }
+            }
+        }
+        Ok(named)
+    }
+}
+
+impl CompositeSource {
+    /// The values of each of `docs`, sorted and without repeats
+    fn values(&self, reader: &SegmentReader, schema: &Schema, docs: &[DocId], mappings: &Mappings) -> Result<Vec<Vec<Key>>> {
+        let name = match self {
+            CompositeSource::Terms { field } => field,
+            CompositeSource::DateHistogram(histogram) => &histogram.field,
+        };
+        let field = schema
+            .get_field(name)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", name)))?;
+        let mut values = vec![Vec::new(); docs.len()];
+        match (self, schema.get_field_entry(field).field_type()) {
+            (CompositeSource::Terms { .. }, FieldType::Str(options)) if options.get_indexing_options().is_some() => {
+                let mut nth = vec![None; reader.max_doc() as usize];
+                for (i, doc) in docs.iter().enumerate() {
+                    nth[*doc as usize] = Some(i);
+                }
+                let inverted_index = reader.inverted_index(field);
+                let mut terms = inverted_index.terms().stream();
+                while terms.advance() {
+                    let mut postings = inverted_index.read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic);
+                    while postings.advance() {
+                        if let Some(i) = nth[postings.doc() as usize] {
+                            values[i].push(Key::Str(String::from_utf8_lossy(terms.key()).into_owned()));
+                        }
+                    }
+                }
+            }
+            (CompositeSource::Terms { .. }, FieldType::U64(options)) | (CompositeSource::Terms { .. }, FieldType::I64(options))
+                if options.is_fast() =>
+            {
+                let fast = FastValues::new(reader, schema, field)?;
+                let mut found = Vec::new();
+                for (i, doc) in docs.iter().enumerate() {
+                    fast.get(*doc, &mut found);
+                    values[i] = found
+                        .iter()
+                        .map(|v| match fast.value(*v) {
+                            tantivy::schema::Value::I64(value) => Key::I64(value),
+                            _ => Key::from_u64(*v),
+                        })
+                        .collect();
+                }
+            }
+            (CompositeSource::Terms { .. }, _) => {
+                return Err(Error::QueryError(format!(
+                    "Field {} is neither an indexed text field nor a numeric fast field",
+                    name
+                )))
+            }
+            (CompositeSource::DateHistogram(histogram), _) => {
+                let mapping = match mappings.get(name) {
+                    Some(mapping) if mapping.is_date() => mapping,
+                    _ => return Err(Error::QueryError(format!("Field {} is not a date field", name))),
+                };
+                let missing = match histogram.missing {
+                    Some(ref date) => mapping.convert(date)?.as_i64(),
+                    None => None,
+                };
+                let interval = histogram.interval_millis()?;
+                let fast = FastValues::new(reader, schema, field)?;
+                let mut found = Vec::new();
+                for (i, doc) in docs.iter().enumerate() {
+                    fast.get(*doc, &mut found);
+                    let dates: Vec<i64> = found.iter().map(|v| tantivy::u64_to_i64(*v)).collect();
+                    let dates = if dates.is_empty() { missing.into_iter().collect() } else { dates };
+                    // Round towards negative infinity so dates before the epoch start their bucket too
+                    values[i] = dates
+                        .into_iter()
+                        .map(|date| {
+                            let key = date / interval * interval;
+                            Key::I64(if key > date { key - interval } else { key })
+                        })
+                        .collect();
+                }
+            }
+        }
+        for doc_values in &mut values {
+            doc_values.sort();
+            doc_values.dedup();
+        }
+        Ok(values)
+    }
+}
+
+/// The values `key` gives each source, in the order of `names`
+fn keys_of(names: &[String], key: &BTreeMap<String, Value>) -> Result<Vec<Key>> {
+    names
+        .iter()
+        .map(|name| {
+            key.get(name)
+                .and_then(Key::from_value)
+                .ok_or_else(|| Error::QueryError(format!("The after key has no string or number for source {}", name)))
+        })
+        .collect()
+}
+
+/// The first `size` of `counts` as a page
+fn page(names: &[String], counts: BTreeMap<Vec<Key>, u64>, size: usize) -> CompositeBuckets {
+    let buckets: Vec<CompositeBucket> = counts
+        .into_iter()
+        .take(size)
+        .map(|(key, doc_count)| CompositeBucket {
+            key: names.iter().cloned().zip(key.iter().map(Key::to_value)).collect(),
+            doc_count,
+        })
+        .collect();
+    CompositeBuckets {
+        after_key: buckets.last().map(|bucket| bucket.key.clone()),
+        buckets,
+        sources: names.to_vec(),
+        size,
+    }
+}
+
+/// Add the pages of several shards or nodes together. Each has its first `size` buckets after the same key, so the
+/// first `size` of them all have every document of their keys counted.
+pub fn merge_composite<I: IntoIterator<Item = CompositeBuckets>>(results: I) -> CompositeBuckets {
+    let (mut names, mut size) = (Vec::new(), 0);
+    let mut counts: BTreeMap<Vec<Key>, u64> = BTreeMap::new();
+    for result in results {
+        if names.is_empty() {
+            names = result.sources;
+        }
+        size = size.max(result.size);
+        for bucket in result.buckets {
+            let key = match keys_of(&names, &bucket.key) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            match counts.entry(key) {
+                Entry::Occupied(mut entry) => *entry.get_mut() += bucket.doc_count,
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket.doc_count);
+                }
+            }
+        }
+    }
+    page(&names, counts, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{SchemaBuilder, FAST, INDEXED, STRING};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_composite() {
+        let mut builder = SchemaBuilder::new();
+        let host = builder.add_text_field("host", STRING);
+        let status = builder.add_u64_field("status", INDEXED | FAST);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (h, s) in &[("a", 200), ("a", 200), ("a", 500), ("b", 200), ("c", 404)] {
+            writer.add_document(doc!(host => *h, status => *s as u64));
+        }
+        writer.add_document(doc!(status => 200u64));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+
+        let composite = |after: Option<Value>| -> Composite {
+            let mut composite = serde_json::json!({
+                "size": 2,
+                "sources": [{ "host": { "terms": { "field": "host" } } }, { "status": { "terms": { "field": "status" } } }]
+            });
+            if let Some(after) = after {
+                composite["after"] = after;
+            }
+            serde_json::from_value(composite).unwrap()
+        };
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = composite(after)
+                .collect(&searcher, &AllQuery, &Mappings::new(), &MemoryBudget::default())
+                .unwrap();
+            if page.buckets.is_empty() {
+                assert_eq!(page.after_key, None);
+                break;
+            }
+            after = page.after_key.clone().map(|key| serde_json::to_value(key).unwrap());
+            pages.push(page);
+        }
+        let keys: Vec<(String, u64)> = pages
+            .iter()
+            .flat_map(|page| page.buckets.iter())
+            .map(|b| (format!("{}/{}", b.key["host"].as_str().unwrap(), b.key["status"]), b.doc_count))
+            .collect();
+        let expected = vec![("a/200", 2), ("a/500", 1), ("b/200", 1), ("c/404", 1)];
+        assert_eq!(keys, expected.into_iter().map(|(k, c)| (k.to_string(), c)).collect::<Vec<_>>());
+
+        let first = composite(None)
+            .collect(&searcher, &AllQuery, &Mappings::new(), &MemoryBudget::default())
+            .unwrap();
+        let merged = merge_composite(vec![first.clone(), first.clone()]);
+        assert_eq!(merged.buckets.len(), 2);
+        assert_eq!(merged.buckets[0].doc_count, 4);
+        assert_eq!(merged.after_key, first.after_key);
+
+        let bad = composite(Some(serde_json::json!({ "host": "a" })));
+        assert!(bad
+            .collect(&searcher, &AllQuery, &Mappings::new(), &MemoryBudget::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::cmp::Ordering;
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::ops::Bound;
+
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::query::{Query, RangeQuery};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::{Executor, Result as TantivyResult, Searcher, SegmentReader};
+
+use crate::breaker::{MemoryBudget, BUCKET_BYTES};
+use crate::mapping;
+use crate::query::aggregate::{merge_top_hits, SubAggregation, TopHitsResult};
+use crate::query::FastValues;
+use crate::{Error, Result};
+
+/// Count the documents a query matches in the geohash cells of a geo point field, each `precision` characters long
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GeohashGrid {
+    pub field: String,
+    /// From 1, cells thousands of kilometers wide, to 12, cells a few centimeters wide
+    #[serde(default = "GeohashGrid::default_precision")]
+    pub precision: usize,
+    /// How many cells to return, those with the most documents first
+    #[serde(default = "GeohashGrid::default_size")]
+    pub size: usize,
+    /// What's found for the documents of each cell besides their count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<SubAggregation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct GeoBucket {
+    /// The geohash of the cell
+    pub key: String,
+    pub doc_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_hits: Option<TopHitsResult>,
+}
+
+impl GeohashGrid {
+    pub fn default_precision() -> usize {
+        5
+    }
+
+    pub fn default_size() -> usize {
+        10_000
+    }
+
+    /// The cells with the most of the documents `query` matches, leaving out the empty ones. Each cell is taken from
+    /// `budget` as it's found.
+    pub fn collect(&self, searcher: &Searcher, query: &Query, executor: &Executor, budget: &MemoryBudget) -> Result<Vec<GeoBucket>> {
+        let schema = searcher.schema();
+        let collector = GridCollector {
+            schema: schema.clone(),
+            field: self.field(&schema)?,
+            shift: self.shift()?,
+            budget: budget.clone(),
+        };
+        let counts = searcher.search_with_executor(query, &collector, executor)?;
+        budget.check()?;
+        let buckets = counts
+            .into_iter()
+            .map(|(cell, doc_count)| GeoBucket {
+                key: mapping::geohash(cell << collector.shift, self.precision),
+                doc_count,
+                top_hits: None,
+            })
+            .collect();
+        Ok(rank(buckets, self.size))
+    }
+
+    /// The documents with a point in the cell `key`
+    pub fn bucket_query(&self, schema: &Schema, key: &str) -> Result<Box<Query>> {
+        let field = self.field(schema)?;
+        let shift = self.shift()?;
+        let cell = key.bytes().try_fold(0u64, |cell, c| {
+            let value = b"0123456789bcdefghjkmnpqrstuvwxyz".iter().position(|b| *b == c)?;
+            Some(cell << 5 | value as u64)
+        });
+        match cell {
+            Some(cell) if key.len() == self.precision => {
+                let first = cell << shift;
+                let last = first | ((1 << shift) - 1);
+                Ok(Box::new(RangeQuery::new_u64_bounds(
+                    field,
+                    Bound::Included(first),
+                    Bound::Included(last),
+                )))
+            }
+            _ => Err(Error::QueryError(format!(
+                "{} is not a geohash of {} characters",
+                key, self.precision
+            ))),
+        }
+    }
+
+    fn field(&self, schema: &Schema) -> Result<Field> {
+        let field = schema
+            .get_field(&self.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", self.field)))?;
+        match schema.get_field_entry(field).field_type() {
+            FieldType::U64(options) if options.is_fast() => Ok(field),
+            _ => Err(Error::QueryError(format!("Field {} is not a geo point field", self.field))),
+        }
+    }
+
+    /// How many of the trailing bits of a stored point fall within a cell
+    fn shift(&self) -> Result<u32> {
+        match self.precision {
+            1..=12 => Ok(64 - 5 * self.precision as u32),
+            _ => Err(Error::QueryError(format!("Precision {} is not from 1 to 12", self.precision))),
+        }
+    }
+}
+
+/// Keep the `size` cells with the most documents, in the order of their keys when they have as many
+fn rank(mut buckets: Vec<GeoBucket>, size: usize) -> Vec<GeoBucket> {
+    buckets.sort_by(|a, b| match b.doc_count.cmp(&a.doc_count) {
+        Ordering::Equal => a.key.cmp(&b.key),
+        order => order,
+    });
+    buckets.truncate(size);
+    buckets
+}
+
+/// Add the cells of several shards or nodes together. Each returned at most as many cells as were asked for, so the
+/// merged cells are cut down to the most any of them returned.
+pub fn merge_geohash_grid<I: IntoIterator<Item = Vec<GeoBucket>>>(results: I) -> Vec<GeoBucket> {
+    let mut size = 0;
+    let mut merged: BTreeMap<String, GeoBucket> = BTreeMap::new();
+    for buckets in results {
+        size = size.max(buckets.len());
+        for bucket in buckets {
+            match merged.entry(bucket.key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    existing.doc_count += bucket.doc_count;
+                    existing.top_hits = merge_top_hits(existing.top_hits.take(), bucket.top_hits);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket);
+                }
+            }
+        }
+    }
+    rank(merged.into_iter().map(|(_, bucket)| bucket).collect(), size)
+}
+
+struct GridCollector {
+    schema: Schema,
+    field: Field,
+    shift: u32,
+    budget: MemoryBudget,
+}
+
+impl Collector for GridCollector {
+    type Fruit = BTreeMap<u64, u64>;
+    type Child = GridSegmentCollector;
+
+    fn for_segment(&self, _segment_local_id: u32, segment: &SegmentReader) -> TantivyResult<GridSegmentCollector> {
+        let values = FastValues::new(segment, &self.schema, self.field).map_err(|e| tantivy::TantivyError::SchemaError(e.to_string()))?;
+        Ok(GridSegmentCollector {
+            values,
+            found: Vec::new(),
+            shift: self.shift,
+            counts: BTreeMap::new(),
+            budget: self.budget.clone(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_counts: Vec<BTreeMap<u64, u64>>) -> TantivyResult<BTreeMap<u64, u64>> {
+        let mut counts = BTreeMap::new();
+        for (cell, count) in segment_counts.into_iter().flat_map(|c| c.into_iter()) {
+            *counts.entry(cell).or_insert(0) += count;
+        }
+        Ok(counts)
+    }
+}
+
+struct GridSegmentCollector {
+    values: FastValues,
+    found: Vec<u64>,
+    shift: u32,
+    counts: BTreeMap<u64, u64>,
+    budget: MemoryBudget,
+}
+
+impl SegmentCollector for GridSegmentCollector {
+    type Fruit = BTreeMap<u64, u64>;
+
+    /// A document with several points counts once in each cell any of them are in
+    fn collect(&mut self, doc: u32, _score: f32) {
+        self.values.get(doc, &mut self.found);
+        for point in self.found.iter_mut() {
+            *point >>= self.shift;
+        }
+        self.found.sort();
+        self.found.dedup();
+        for cell in &self.found {
+            match self.counts.entry(*cell) {
+                Entry::Occupied(mut count) => *count.get_mut() += 1,
+                // Past its budget the search stops adding cells, and fails once it's collected
+                Entry::Vacant(entry) => {
+                    if self.budget.reserve(BUCKET_BYTES).is_ok() {
+                        entry.insert(1);
+                    }
+                }
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitBreaker;
+    use crate::settings::CircuitBreakerSettings;
+    use std::sync::Arc;
+    use tantivy::collector::Count;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{SchemaBuilder, FAST, INDEXED};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_geohash_grid() {
+        let mut builder = SchemaBuilder::new();
+        let location = builder.add_u64_field("location", INDEXED | FAST);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        // Two points in Amsterdam and one in New York
+        for (lat, lon) in &[(52.3702, 4.8952), (52.3676, 4.9041), (40.7128, -74.0060)] {
+            writer.add_document(doc!(location => mapping::geo_encode(*lat, *lon)));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+
+        let grid = |precision: usize| GeohashGrid {
+            field: "location".into(),
+            precision,
+            size: 10,
+            aggs: None,
+        };
+        let buckets = grid(4)
+            .collect(&searcher, &AllQuery, &Executor::single_thread(), &MemoryBudget::default())
+            .unwrap();
+        let cells: Vec<(&str, u64)> = buckets.iter().map(|b| (b.key.as_str(), b.doc_count)).collect();
+        assert_eq!(cells, vec![("u173", 2), ("dr5r", 1)]);
+
+        let amsterdam = grid(4).bucket_query(&index.schema(), "u173").unwrap();
+        assert_eq!(searcher.search(&amsterdam, &Count).unwrap(), 2);
+        assert!(grid(4).bucket_query(&index.schema(), "u17").is_err());
+        assert!(grid(13)
+            .collect(&searcher, &AllQuery, &Executor::single_thread(), &MemoryBudget::default())
+            .is_err());
+
+        // A search with room for a single cell fails rather than finding both
+        let settings = CircuitBreakerSettings {
+            request_bytes: BUCKET_BYTES,
+            total_bytes: 0,
+        };
+        let budget = CircuitBreaker::budget(&Arc::new(CircuitBreaker::new(&settings)));
+        assert!(grid(4).collect(&searcher, &AllQuery, &Executor::single_thread(), &budget).is_err());
+
+        let merged = merge_geohash_grid(vec![buckets.clone(), vec![buckets[1].clone()]]);
+        let cells: Vec<(&str, u64)> = merged.iter().map(|b| (b.key.as_str(), b.doc_count)).collect();
+        assert_eq!(cells, vec![("dr5r", 2), ("u173", 2)]);
+    }
+}
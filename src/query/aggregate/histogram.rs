@@ -0,0 +1,265 @@
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::query::{Query, RangeQuery};
+use tantivy::schema::{FieldType, Schema};
+use tantivy::{Executor, Result as TantivyResult, Searcher, SegmentReader};
+
+use crate::breaker::{MemoryBudget, BUCKET_BYTES};
+use crate::mapping;
+use crate::query::aggregate::{merge_top_hits, Pipeline, SubAggregation, TopHitsResult};
+use crate::query::script::{Script, ScriptFilter};
+use crate::query::FastValues;
+use crate::{Error, Result};
+
+/// Count the documents a query matches in buckets of a date field, each `interval` wide, such as `30s`, `1h` or `7d`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DateHistogram {
+    pub field: String,
+    pub interval: String,
+    /// Bucket documents by the date in milliseconds this script gives them instead of by the field's, see `query::script`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// A date documents without any are counted at, instead of being left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing: Option<serde_json::Value>,
+    /// What's found for the documents of each bucket besides their count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<SubAggregation>,
+    /// Calculations over the buckets by name, such as a `cumulative_sum` of their counts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pipelines: BTreeMap<String, Pipeline>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DateBucket {
+    /// The start of the bucket in milliseconds since the epoch
+    pub key: i64,
+    pub key_as_string: String,
+    pub doc_count: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_hits: Option<TopHitsResult>,
+    /// The value each pipeline gave the bucket by name, leaving out those that gave it none
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pipelines: BTreeMap<String, f64>,
+}
+
+impl DateHistogram {
+    /// The width of each bucket in milliseconds
+    pub fn interval_millis(&self) -> Result<i64> {
+        mapping::parse_duration(&self.interval)
+    }
+
+    /// The buckets of the documents `query` matches in order, leaving out the empty ones. Documents without a date
+    /// count at `missing`, the date `missing` converts to in milliseconds, when there's one. Each bucket is taken from
+    /// `budget` as it's found.
+    pub fn collect(
+        &self,
+        searcher: &Searcher,
+        query: &Query,
+        executor: &Executor,
+        missing: Option<i64>,
+        budget: &MemoryBudget,
+    ) -> Result<Vec<DateBucket>> {
+        let collector = HistogramCollector::new(&searcher.schema(), self, self.interval_millis()?, missing, budget)?;
+        let counts = searcher.search_with_executor(query, &collector, executor)?;
+        budget.check()?;
+        Ok(counts
+            .into_iter()
+            .map(|(key, doc_count)| DateBucket {
+                key,
+                key_as_string: mapping::format_date(key),
+                doc_count,
+                top_hits: None,
+                pipelines: BTreeMap::new(),
+            })
+            .collect())
+    }
+
+    /// The documents with a date in the bucket starting at `key`
+    pub fn bucket_query(&self, schema: &Schema, key: i64) -> Result<Box<Query>> {
+        if let Some(ref script) = self.script {
+            let script = Arc::new(Script::compile(schema, script)?);
+            let end = key + self.interval_millis()?;
+            return Ok(Box::new(ScriptFilter::within(script, Bound::Included(key), Bound::Excluded(end))));
+        }
+        let field = schema
+            .get_field(&self.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", self.field)))?;
+        Ok(Box::new(RangeQuery::new_i64(field, key..key + self.interval_millis()?)))
+    }
+}
+
+/// Add the buckets of several shards or nodes together
+pub fn merge_buckets<I: IntoIterator<Item = DateBucket>>(buckets: I) -> Vec<DateBucket> {
+    let mut merged: BTreeMap<i64, DateBucket> = BTreeMap::new();
+    for bucket in buckets {
+        match merged.entry(bucket.key) {
+            Entry::Occupied(mut entry) => {
+                let existing = entry.get_mut();
+                existing.doc_count += bucket.doc_count;
+                existing.top_hits = merge_top_hits(existing.top_hits.take(), bucket.top_hits);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(bucket);
+            }
+        }
+    }
+    merged.into_iter().map(|(_, bucket)| bucket).collect()
+}
+
+struct HistogramCollector {
+    schema: Schema,
+    field: String,
+    script: Option<Arc<Script>>,
+    interval: i64,
+    missing: Option<i64>,
+    budget: MemoryBudget,
+}
+
+impl HistogramCollector {
+    fn new(schema: &Schema, histogram: &DateHistogram, interval: i64, missing: Option<i64>, budget: &MemoryBudget) -> Result<Self> {
+        let collector = |script| HistogramCollector {
+            schema: schema.clone(),
+            field: histogram.field.clone(),
+            script,
+            interval,
+            missing,
+            budget: budget.clone(),
+        };
+        if let Some(ref script) = histogram.script {
+            return Ok(collector(Some(Arc::new(Script::compile(schema, script)?))));
+        }
+        let field = schema
+            .get_field(&histogram.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", histogram.field)))?;
+        match schema.get_field_entry(field).field_type() {
+            FieldType::I64(options) if options.is_fast() => Ok(collector(None)),
+            _ => Err(Error::QueryError(format!(
+                "Field {} is not a date field",
+                schema.get_field_name(field)
+            ))),
+        }
+    }
+}
+
+impl Collector for HistogramCollector {
+    type Fruit = BTreeMap<i64, u64>;
+    type Child = HistogramSegmentCollector;
+
+    fn for_segment(&self, _segment_local_id: u32, segment: &SegmentReader) -> TantivyResult<HistogramSegmentCollector> {
+        let values = FastValues::named(segment, &self.schema, &self.field, self.script.as_ref())
+            .map_err(|e| tantivy::TantivyError::SchemaError(e.to_string()))?;
+        Ok(HistogramSegmentCollector {
+            values,
+            found: Vec::new(),
+            keys: Vec::new(),
+            interval: self.interval,
+            missing: self.missing,
+            counts: BTreeMap::new(),
+            budget: self.budget.clone(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_counts: Vec<BTreeMap<i64, u64>>) -> TantivyResult<BTreeMap<i64, u64>> {
+        let mut counts = BTreeMap::new();
+        for (key, count) in segment_counts.into_iter().flat_map(|c| c.into_iter()) {
+            *counts.entry(key).or_insert(0) += count;
+        }
+        Ok(counts)
+    }
+}
+
+struct HistogramSegmentCollector {
+    values: FastValues,
+    found: Vec<u64>,
+    keys: Vec<i64>,
+    interval: i64,
+    missing: Option<i64>,
+    counts: BTreeMap<i64, u64>,
+    budget: MemoryBudget,
+}
+
+impl SegmentCollector for HistogramSegmentCollector {
+    type Fruit = BTreeMap<i64, u64>;
+
+    /// A document with several dates counts once in each bucket any of them fall in
+    fn collect(&mut self, doc: u32, _score: f32) {
+        self.values.get(doc, &mut self.found);
+        self.keys.clear();
+        let values: Vec<i64> = self.found.iter().map(|v| tantivy::u64_to_i64(*v)).collect();
+        let values = if values.is_empty() {
+            self.missing.into_iter().collect()
+        } else {
+            values
+        };
+        for value in values {
+            // Round towards negative infinity so dates before the epoch start their bucket too
+            let mut key = value / self.interval * self.interval;
+            if key > value {
+                key -= self.interval;
+            }
+            self.keys.push(key);
+        }
+        self.keys.sort();
+        self.keys.dedup();
+        for key in &self.keys {
+            match self.counts.entry(*key) {
+                Entry::Occupied(mut count) => *count.get_mut() += 1,
+                // Past its budget the search stops adding buckets, and fails once it's collected
+                Entry::Vacant(entry) => {
+                    if self.budget.reserve(BUCKET_BYTES).is_ok() {
+                        entry.insert(1);
+                    }
+                }
+            }
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intervals() {
+        let interval = |interval: &str| {
+            DateHistogram {
+                field: "created".into(),
+                interval: interval.into(),
+                script: None,
+                missing: None,
+                aggs: None,
+                pipelines: BTreeMap::new(),
+            }
+            .interval_millis()
+        };
+        assert_eq!(interval("30s").unwrap(), 30_000);
+        assert_eq!(interval("1h").unwrap(), 3_600_000);
+        assert_eq!(interval("7d").unwrap(), interval("1w").unwrap());
+        assert!(interval("0d").is_err());
+        assert!(interval("1y").is_err());
+        assert!(interval("h").is_err());
+
+        let bucket = |key: i64, doc_count: u64| DateBucket {
+            key,
+            key_as_string: mapping::format_date(key),
+            doc_count,
+            top_hits: None,
+            pipelines: BTreeMap::new(),
+        };
+        let merged = merge_buckets(vec![bucket(2000, 1), bucket(0, 2), bucket(2000, 3)]);
+        assert_eq!(merged, vec![bucket(0, 2), bucket(2000, 4)]);
+    }
+}
@@ -1,5 +1,18 @@
 #![allow(dead_code)]
+pub use self::bucket::{merge_filters, merge_ranges, FilterBucket, FiltersAggregation, Range, RangeAggregation, RangeBucket};
+pub use self::composite::{merge_composite, Composite, CompositeBucket, CompositeBuckets, CompositeSource};
+pub use self::geo::{merge_geohash_grid, GeoBucket, GeohashGrid};
+pub use self::histogram::{merge_buckets, DateBucket, DateHistogram};
+pub use self::pipeline::{run_pipelines, Pipeline};
+pub use self::significant::{merge_significant_terms, SignificantBucket, SignificantBuckets, SignificantTerms};
 pub use self::sum::{SumCollector, SummaryDoc};
+pub use self::top_hits::{merge_top_hits, SubAggregation, TopHits, TopHitsResult};
 
 mod bucket;
+mod composite;
+mod geo;
+mod histogram;
+mod pipeline;
+mod significant;
 mod sum;
+mod top_hits;
@@ -0,0 +1,213 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::query::aggregate::DateBucket;
+use crate::query::{Sort, SortOrder};
+use crate::{Error, Result};
+
+/// The path of the document count of each bucket, which pipelines read unless given another
+pub const COUNT_PATH: &str = "_count";
+/// The path of the key of each bucket, which `bucket_sort` can sort by
+pub const KEY_PATH: &str = "_key";
+
+/// A calculation over the buckets of a date histogram, run once the buckets of every shard and node are added
+/// together. Each reads `buckets_path`, the document count of the buckets or the value another pipeline gave them.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Pipeline {
+    /// The sum of the values of the bucket and every one before it
+    CumulativeSum {
+        #[serde(default = "Pipeline::default_path")]
+        buckets_path: String,
+    },
+    /// How much the value changed since the bucket before, which the first bucket has none of
+    Derivative {
+        #[serde(default = "Pipeline::default_path")]
+        buckets_path: String,
+    },
+    /// The mean of the values of the `window` buckets before the bucket, which the first bucket has none of
+    MovingAvg {
+        #[serde(default = "Pipeline::default_path")]
+        buckets_path: String,
+        #[serde(default = "Pipeline::default_window")]
+        window: usize,
+    },
+    /// Sort the buckets by `_key`, `_count` or the value of another pipeline, and keep `size` of them from `from`
+    BucketSort {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sort: Option<Sort>,
+        #[serde(default)]
+        from: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<usize>,
+    },
+}
+
+impl Pipeline {
+    pub fn default_path() -> String {
+        COUNT_PATH.into()
+    }
+
+    pub fn default_window() -> usize {
+        5
+    }
+
+    fn buckets_path(&self) -> Option<&str> {
+        match self {
+            Pipeline::CumulativeSum { buckets_path } | Pipeline::Derivative { buckets_path } | Pipeline::MovingAvg { buckets_path, .. } => {
+                Some(buckets_path.as_str())
+            }
+            Pipeline::BucketSort { .. } => None,
+        }
+    }
+
+    /// The value of this pipeline for each of the bucket values `values`
+    fn values(&self, values: &[Option<f64>]) -> Vec<Option<f64>> {
+        match self {
+            Pipeline::CumulativeSum { .. } => values
+                .iter()
+                .scan(0.0, |sum, value| {
+                    *sum += value.unwrap_or(0.0);
+                    Some(Some(*sum))
+                })
+                .collect(),
+            Pipeline::Derivative { .. } => (0..values.len())
+                .map(|i| match (i.checked_sub(1).and_then(|before| values[before]), values[i]) {
+                    (Some(before), Some(value)) => Some(value - before),
+                    _ => None,
+                })
+                .collect(),
+            Pipeline::MovingAvg { window, .. } => (0..values.len())
+                .map(|i| {
+                    let before: Vec<f64> = values[i.saturating_sub(*window)..i].iter().filter_map(|v| *v).collect();
+                    if before.is_empty() {
+                        None
+                    } else {
+                        Some(before.iter().sum::<f64>() / before.len() as f64)
+                    }
+                })
+                .collect(),
+            Pipeline::BucketSort { .. } => vec![None; values.len()],
+        }
+    }
+}
+
+/// Run `pipelines` over `buckets`, each after the pipelines it reads the values of, and sort the buckets last
+pub fn run_pipelines(pipelines: &BTreeMap<String, Pipeline>, buckets: &mut Vec<DateBucket>) -> Result<()> {
+    let mut pending: Vec<(&String, &Pipeline)> = pipelines.iter().filter(|(_, p)| p.buckets_path().is_some()).collect();
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|(_, pipeline)| match pipeline.buckets_path() {
+            Some(COUNT_PATH) | None => true,
+            Some(path) => {
+                !pending.iter().any(|(name, _)| *name == path) && pipelines.get(path).map_or(false, |p| p.buckets_path().is_some())
+            }
+        });
+        let (name, pipeline) = match ready {
+            Some(ready) => pending.remove(ready),
+            None => {
+                let (name, pipeline) = pending[0];
+                return Err(Error::QueryError(format!(
+                    "Pipeline {} reads {}, which is neither _count nor a pipeline run before it",
+                    name,
+                    pipeline.buckets_path().unwrap_or_default()
+                )));
+            }
+        };
+        let path = pipeline.buckets_path().unwrap_or(COUNT_PATH);
+        let values: Vec<Option<f64>> = buckets.iter().map(|bucket| bucket_value(bucket, path)).collect();
+        for (bucket, value) in buckets.iter_mut().zip(pipeline.values(&values)) {
+            if let Some(value) = value {
+                bucket.pipelines.insert(name.clone(), value);
+            }
+        }
+    }
+
+    for (name, pipeline) in pipelines {
+        if let Pipeline::BucketSort { sort, from, size } = pipeline {
+            if let Some(sort) = sort {
+                if sort.field != KEY_PATH && sort.field != COUNT_PATH && !pipelines.contains_key(&sort.field) {
+                    return Err(Error::QueryError(format!(
+                        "Pipeline {} sorts by {}, which buckets don't have",
+                        name, sort.field
+                    )));
+                }
+                // Buckets without a value go last whichever way they're sorted
+                buckets.sort_by(|a, b| match (bucket_value(a, &sort.field), bucket_value(b, &sort.field)) {
+                    (Some(a), Some(b)) => {
+                        let order = a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+                        match sort.order {
+                            SortOrder::Asc => order,
+                            SortOrder::Desc => order.reverse(),
+                        }
+                    }
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => Ordering::Equal,
+                });
+            }
+            let kept: Vec<DateBucket> = buckets.drain(..).skip(*from).take(size.unwrap_or(usize::max_value())).collect();
+            *buckets = kept;
+        }
+    }
+    Ok(())
+}
+
+fn bucket_value(bucket: &DateBucket, path: &str) -> Option<f64> {
+    match path {
+        COUNT_PATH => Some(bucket.doc_count as f64),
+        KEY_PATH => Some(bucket.key as f64),
+        name => bucket.pipelines.get(name).cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping;
+
+    #[test]
+    fn test_pipelines() {
+        let bucket = |key: i64, doc_count: u64| DateBucket {
+            key,
+            key_as_string: mapping::format_date(key),
+            doc_count,
+            top_hits: None,
+            pipelines: BTreeMap::new(),
+        };
+        let mut buckets = vec![bucket(0, 2), bucket(1000, 4), bucket(2000, 3), bucket(3000, 7)];
+        let pipelines: BTreeMap<String, Pipeline> = serde_json::from_str(
+            r#"{
+                "total": { "cumulative_sum": {} },
+                "change": { "derivative": { "buckets_path": "total" } },
+                "smooth": { "moving_avg": { "window": 2 } },
+                "top": { "bucket_sort": { "sort": { "field": "_count", "order": "desc" }, "size": 2 } }
+            }"#,
+        )
+        .unwrap();
+        run_pipelines(&pipelines, &mut buckets).unwrap();
+
+        let keys: Vec<i64> = buckets.iter().map(|b| b.key).collect();
+        assert_eq!(keys, vec![3000, 1000]);
+        let values: Vec<String> = buckets
+            .iter()
+            .map(|b| format!("{} {} {}", b.pipelines["total"], b.pipelines["change"], b.pipelines["smooth"]))
+            .collect();
+        assert_eq!(values, vec!["16 7 3.5", "6 4 2"]);
+
+        let mut first = vec![bucket(0, 2)];
+        run_pipelines(&pipelines, &mut first).unwrap();
+        assert!(!first[0].pipelines.contains_key("change"));
+        assert!(!first[0].pipelines.contains_key("smooth"));
+
+        for bad in &[
+            r#"{ "a": { "derivative": { "buckets_path": "b" } }, "b": { "derivative": { "buckets_path": "a" } } }"#,
+            r#"{ "a": { "cumulative_sum": { "buckets_path": "missing" } } }"#,
+            r#"{ "a": { "bucket_sort": { "sort": { "field": "missing" } } } }"#,
+        ] {
+            let pipelines: BTreeMap<String, Pipeline> = serde_json::from_str(bad).unwrap();
+            assert!(run_pipelines(&pipelines, &mut vec![bucket(0, 1)]).is_err());
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::cmp::Ordering;
+use std::collections::btree_map::{BTreeMap, Entry};
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{Query, TermQuery};
+use tantivy::schema::{FieldType, IndexRecordOption, Schema};
+use tantivy::{DocSet, Searcher, Term};
+
+use crate::breaker::{MemoryBudget, BUCKET_BYTES};
+use crate::query::aggregate::{merge_top_hits, SubAggregation, TopHitsResult};
+use crate::{Error, Result};
+
+/// Find the terms of a text field that are unusually common among the documents a query matches, compared to how
+/// common they are in the whole index
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SignificantTerms {
+    pub field: String,
+    /// How many terms to return, the most significant first
+    #[serde(default = "SignificantTerms::default_size")]
+    pub size: usize,
+    /// How many matching documents a term has to be in to be returned at all
+    #[serde(default = "SignificantTerms::default_min_doc_count")]
+    pub min_doc_count: u64,
+    /// What's found for the matching documents with each term besides their count
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggs: Option<SubAggregation>,
+}
+
+/// The significant terms of the documents a query matched, out of `doc_count` matching documents and `bg_count`
+/// documents in the index
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SignificantBuckets {
+    pub doc_count: u64,
+    pub bg_count: u64,
+    pub buckets: Vec<SignificantBucket>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SignificantBucket {
+    pub key: String,
+    /// How many matching documents have the term
+    pub doc_count: u64,
+    /// How many documents in the index have the term
+    pub bg_count: u64,
+    pub score: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_hits: Option<TopHitsResult>,
+}
+
+impl SignificantTerms {
+    pub fn default_size() -> usize {
+        10
+    }
+
+    pub fn default_min_doc_count() -> u64 {
+        3
+    }
+
+    /// The most significant terms of the documents `query` matches. The terms of each segment are read from its
+    /// term dictionary along with the matching documents in their postings, so a field with many distinct terms
+    /// costs as much as reading all of its postings. Each term found is taken from `budget`.
+    pub fn collect(&self, searcher: &Searcher, query: &Query, budget: &MemoryBudget) -> Result<SignificantBuckets> {
+        let schema = searcher.schema();
+        let field = schema
+            .get_field(&self.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", self.field)))?;
+        match schema.get_field_entry(field).field_type() {
+            FieldType::Str(options) if options.get_indexing_options().is_some() => {}
+            _ => return Err(Error::QueryError(format!("Field {} is not an indexed text field", self.field))),
+        }
+
+        let weight = query.weight(searcher, false)?;
+        let mut doc_count = 0;
+        let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+        for reader in searcher.segment_readers() {
+            let mut matching = vec![false; reader.max_doc() as usize];
+            let mut segment_count = 0;
+            let mut scorer = weight.scorer(reader)?;
+            while scorer.advance() {
+                let doc = scorer.doc();
+                if !reader.delete_bitset().map(|d| d.is_deleted(doc)).unwrap_or(false) {
+                    matching[doc as usize] = true;
+                    segment_count += 1;
+                }
+            }
+            if segment_count == 0 {
+                continue;
+            }
+            doc_count += segment_count;
+            let inverted_index = reader.inverted_index(field);
+            let mut terms = inverted_index.terms().stream();
+            while terms.advance() {
+                let mut postings = inverted_index.read_postings_from_terminfo(terms.value(), IndexRecordOption::Basic);
+                let mut found = 0;
+                while postings.advance() {
+                    if matching[postings.doc() as usize] {
+                        found += 1;
+                    }
+                }
+                if found > 0 {
+                    let key = String::from_utf8_lossy(terms.key()).into_owned();
+                    match counts.entry(key) {
+                        Entry::Occupied(mut count) => *count.get_mut() += found,
+                        Entry::Vacant(entry) => {
+                            budget.reserve(BUCKET_BYTES + entry.key().len())?;
+                            entry.insert(found);
+                        }
+                    }
+                }
+            }
+        }
+
+        let bg_count = searcher.num_docs();
+        let buckets = counts
+            .into_iter()
+            .filter(|(_, count)| *count >= self.min_doc_count)
+            .map(|(key, count)| {
+                let bg = searcher.doc_freq(&Term::from_field_text(field, &key));
+                SignificantBucket {
+                    key,
+                    doc_count: count,
+                    bg_count: bg,
+                    score: 0.0,
+                    top_hits: None,
+                }
+            })
+            .collect();
+        Ok(rank(doc_count, bg_count, buckets, self.size))
+    }
+
+    /// The documents with the term `key`
+    pub fn bucket_query(&self, schema: &Schema, key: &str) -> Result<Box<Query>> {
+        let field = schema
+            .get_field(&self.field)
+            .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", self.field)))?;
+        let term = Term::from_field_text(field, key);
+        Ok(Box::new(TermQuery::new(term, IndexRecordOption::Basic)))
+    }
+}
+
+/// How much more common a term is among matching documents than among all of them, by the product of the absolute
+/// and relative differences in how many documents have it, or 0 when it's no more common
+fn score(bucket: &SignificantBucket, doc_count: u64, bg_count: u64) -> f64 {
+    if doc_count == 0 || bg_count == 0 || bucket.bg_count == 0 {
+        return 0.0;
+    }
+    let foreground = bucket.doc_count as f64 / doc_count as f64;
+    let background = bucket.bg_count as f64 / bg_count as f64;
+    if foreground <= background {
+        return 0.0;
+    }
+    (foreground - background) * (foreground / background)
+}
+
+/// Score `buckets` and keep the `size` most significant of them
+fn rank(doc_count: u64, bg_count: u64, buckets: Vec<SignificantBucket>, size: usize) -> SignificantBuckets {
+    let mut buckets: Vec<SignificantBucket> = buckets
+        .into_iter()
+        .map(|bucket| SignificantBucket {
+            score: score(&bucket, doc_count, bg_count),
+            ..bucket
+        })
+        .filter(|bucket| bucket.score > 0.0)
+        .collect();
+    buckets.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.key.cmp(&b.key))
+    });
+    buckets.truncate(size);
+    SignificantBuckets {
+        doc_count,
+        bg_count,
+        buckets,
+    }
+}
+
+/// Add the significant terms of several shards or nodes together and score them again. Each returned at most as many
+/// terms as were asked for, so the merged terms are cut down to the most any of them returned.
+pub fn merge_significant_terms<I: IntoIterator<Item = SignificantBuckets>>(results: I) -> SignificantBuckets {
+    let (mut doc_count, mut bg_count, mut size) = (0, 0, 0);
+    let mut merged: BTreeMap<String, SignificantBucket> = BTreeMap::new();
+    for result in results {
+        doc_count += result.doc_count;
+        bg_count += result.bg_count;
+        size = size.max(result.buckets.len());
+        for bucket in result.buckets {
+            match merged.entry(bucket.key.clone()) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    existing.doc_count += bucket.doc_count;
+                    existing.bg_count += bucket.bg_count;
+                    existing.top_hits = merge_top_hits(existing.top_hits.take(), bucket.top_hits);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket);
+                }
+            }
+        }
+    }
+    rank(doc_count, bg_count, merged.into_iter().map(|(_, bucket)| bucket).collect(), size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::TermQuery;
+    use tantivy::schema::{SchemaBuilder, STRING, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_significant_terms() {
+        let mut builder = SchemaBuilder::new();
+        let level = builder.add_text_field("level", STRING);
+        let message = builder.add_text_field("message", TEXT);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for _ in 0..3 {
+            writer.add_document(doc!(level => "error", message => "connection timeout from db"));
+        }
+        writer.add_document(doc!(level => "error", message => "request from client failed"));
+        for _ in 0..10 {
+            writer.add_document(doc!(level => "info", message => "request from client served"));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+
+        let errors = TermQuery::new(Term::from_field_text(level, "error"), IndexRecordOption::Basic);
+        let terms = SignificantTerms {
+            field: "message".into(),
+            size: 2,
+            min_doc_count: 3,
+            aggs: None,
+        };
+        let significant = terms.collect(&searcher, &errors, &MemoryBudget::default()).unwrap();
+        assert_eq!((significant.doc_count, significant.bg_count), (4, 14));
+        let keys: Vec<&str> = significant.buckets.iter().map(|b| b.key.as_str()).collect();
+        // Every document has "from", so it's no more common among errors
+        assert_eq!(keys, vec!["connection", "db"]);
+        assert_eq!(significant.buckets[0].doc_count, 3);
+        assert_eq!(significant.buckets[0].bg_count, 3);
+
+        let merged = merge_significant_terms(vec![significant.clone(), significant.clone()]);
+        assert_eq!((merged.doc_count, merged.bg_count), (8, 28));
+        assert_eq!(merged.buckets.len(), 2);
+        assert_eq!(merged.buckets[0].doc_count, 6);
+        assert!((merged.buckets[0].score - significant.buckets[0].score).abs() < 1e-9);
+
+        let missing = SignificantTerms {
+            field: "missing".into(),
+            ..terms
+        };
+        assert!(missing.collect(&searcher, &errors, &MemoryBudget::default()).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::query::{Sort, SortOrder};
+use crate::results::ScoredDoc;
+
+/// An aggregation given inside of a bucket aggregation, run on the documents of each bucket
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum SubAggregation {
+    TopHits { top_hits: TopHits },
+}
+
+/// The best `size` documents of each bucket, by score or by `sort`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TopHits {
+    #[serde(default = "TopHits::default_size")]
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort: Option<Sort>,
+    /// The stored fields each hit comes with, every one of them unless given
+    #[serde(default, rename = "_source", skip_serializing_if = "Option::is_none")]
+    pub source: Option<Vec<String>>,
+}
+
+/// The top hits of a bucket
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TopHitsResult {
+    /// The order the hits are in when they're sorted by a field rather than by score, kept to merge the hits of
+    /// several shards or nodes in the same order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<SortOrder>,
+    pub hits: Vec<ScoredDoc>,
+}
+
+impl TopHits {
+    pub fn default_size() -> usize {
+        3
+    }
+
+    /// The hits of a bucket, with only the fields of `_source` when it's given
+    pub fn result(&self, hits: Vec<ScoredDoc>) -> TopHitsResult {
+        let hits = match self.source {
+            Some(ref source) => hits
+                .into_iter()
+                .map(|mut hit| {
+                    hit.doc = hit.doc.into_iter().filter(|(name, _)| source.contains(name)).collect();
+                    hit
+                })
+                .collect(),
+            None => hits,
+        };
+        TopHitsResult {
+            order: self.sort.as_ref().map(|sort| sort.order),
+            hits,
+        }
+    }
+}
+
+/// The top hits of the same bucket of two shards or nodes together. Each returned at most as many hits as were asked
+/// for, so the merged hits are cut down to the most either of them returned.
+pub fn merge_top_hits(a: Option<TopHitsResult>, b: Option<TopHitsResult>) -> Option<TopHitsResult> {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        (a, b) => return a.or(b),
+    };
+    let size = a.hits.len().max(b.hits.len());
+    let order = a.order;
+    let mut hits = a.hits;
+    hits.extend(b.hits);
+    match order {
+        Some(SortOrder::Asc) => hits.sort_by(|a, b| a.sort_key.cmp(&b.sort_key)),
+        Some(SortOrder::Desc) => hits.sort_by(|a, b| b.sort_key.cmp(&a.sort_key)),
+        None => hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)),
+    }
+    hits.truncate(size);
+    Some(TopHitsResult { order, hits })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tantivy::schema::Value;
+
+    #[test]
+    fn test_top_hits() {
+        let hit = |key: u64, host: &str| {
+            let mut doc = BTreeMap::new();
+            doc.insert("host".to_string(), vec![Value::Str(host.into())]);
+            doc.insert("message".to_string(), vec![Value::Str("up".into())]);
+            ScoredDoc {
+                score: None,
+                doc,
+                sort_key: Some(key),
+                fields: BTreeMap::new(),
+            }
+        };
+        let top_hits: TopHits =
+            serde_json::from_str(r#"{ "size": 2, "sort": { "field": "timestamp", "order": "desc" }, "_source": ["host"] }"#).unwrap();
+        let shard = top_hits.result(vec![hit(9, "a"), hit(4, "a")]);
+        assert_eq!(shard.order, Some(SortOrder::Desc));
+        assert!(shard.hits.iter().all(|h| !h.doc.contains_key("message")));
+
+        let other = top_hits.result(vec![hit(7, "b")]);
+        let merged = merge_top_hits(Some(shard.clone()), Some(other)).unwrap();
+        let keys: Vec<Option<u64>> = merged.hits.iter().map(|h| h.sort_key).collect();
+        assert_eq!(keys, vec![Some(9), Some(7)]);
+        assert_eq!(merge_top_hits(None, Some(shard.clone())), Some(shard));
+    }
+}
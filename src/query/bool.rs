@@ -1,11 +1,15 @@
-use crate::query::{CreateQuery, TermQueries};
+use crate::query::{raw_query, CreateQuery, FilterCache, FilterQuery, TermQueries};
 use crate::Result;
 
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
 use tantivy::query::{BooleanQuery, Occur, Query};
 use tantivy::schema::Schema;
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::Index;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct BoolQuery {
     #[serde(default = "Vec::new")]
     must: Vec<TermQueries>,
@@ -21,23 +25,67 @@ pub struct BoolQuery {
 
 impl CreateQuery for BoolQuery {
     fn create_query(self, schema: &Schema) -> Result<Box<Query>> {
+        // Without an index there are only the default tokenizers to parse raw clauses with
+        self.build(schema, &TokenizerManager::default(), None)
+    }
+}
+
+impl BoolQuery {
+    /// Build the query over `index`, parsing raw clauses with its analyzers and with its filter clauses reusing the
+    /// documents they matched in earlier queries
+    pub fn create_cached_query(self, index: &Index, cache: Option<&Arc<FilterCache>>) -> Result<Box<Query>> {
+        self.build(&index.schema(), index.tokenizers(), cache)
+    }
+
+    fn build(self, schema: &Schema, tokenizers: &TokenizerManager, cache: Option<&Arc<FilterCache>>) -> Result<Box<Query>> {
         let mut all_queries: Vec<(Occur, Box<Query>)> = Vec::new();
-        all_queries.append(&mut parse_queries(schema, Occur::Must, &self.must)?);
-        all_queries.append(&mut parse_queries(schema, Occur::MustNot, &self.must_not)?);
-        all_queries.append(&mut parse_queries(schema, Occur::Should, &self.should)?);
+        all_queries.append(&mut parse_queries(schema, tokenizers, Occur::Must, &self.must)?);
+        all_queries.append(&mut parse_filters(schema, tokenizers, &self.filter, cache)?);
+        all_queries.append(&mut parse_queries(schema, tokenizers, Occur::MustNot, &self.must_not)?);
+        all_queries.append(&mut parse_queries(schema, tokenizers, Occur::Should, &self.should)?);
         Ok(Box::new(BooleanQuery::from(all_queries)))
     }
 }
 
-fn parse_queries(schema: &Schema, occur: Occur, queries: &[TermQueries]) -> Result<Vec<(Occur, Box<Query>)>> {
+/// Filters must match like `must` clauses but don't affect the score, the clause itself is the cache key
+fn parse_filters(
+    schema: &Schema,
+    tokenizers: &TokenizerManager,
+    filters: &[TermQueries],
+    cache: Option<&Arc<FilterCache>>,
+) -> Result<Vec<(Occur, Box<Query>)>> {
+    parse_queries(schema, tokenizers, Occur::Must, filters)?
+        .into_iter()
+        .zip(filters)
+        .map(|((occur, query), filter)| {
+            let key = serde_json::to_string(filter)?;
+            Ok((occur, Box::new(FilterQuery::new(query, key, cache.cloned())) as Box<Query>))
+        })
+        .collect()
+}
+
+pub fn parse_queries(
+    schema: &Schema,
+    tokenizers: &TokenizerManager,
+    occur: Occur,
+    queries: &[TermQueries],
+) -> Result<Vec<(Occur, Box<Query>)>> {
     queries
         .iter()
         .map(|q| match q {
+            TermQueries::Boolean { bool } => Ok((occur, (**bool).clone().build(schema, tokenizers, None)?)),
+            TermQueries::Nested { nested } => Ok((occur, nested.clone().build(schema, tokenizers)?)),
+            TermQueries::HasChild { has_child } => Ok((occur, has_child.clone().build(schema, tokenizers)?)),
+            TermQueries::HasParent { has_parent } => Ok((occur, has_parent.clone().build(schema, tokenizers)?)),
+            TermQueries::RankFeature { rank_feature } => Ok((occur, rank_feature.clone().build(schema)?)),
+            TermQueries::FunctionScore { function_score } => Ok((occur, (**function_score).clone().build(schema, tokenizers)?)),
+            TermQueries::Script { script } => Ok((occur, script.clone().build(schema)?)),
             TermQueries::Fuzzy(f) => Ok((occur, f.clone().create_query(&schema)?)),
             TermQueries::Exact(q) => Ok((occur, q.clone().create_query(&schema)?)),
             TermQueries::Range(r) => Ok((occur, r.clone().create_query(&schema)?)),
             TermQueries::Phrase(p) => Ok((occur, p.clone().create_query(&schema)?)),
             TermQueries::Regex(r) => Ok((occur, r.clone().create_query(&schema)?)),
+            TermQueries::Raw { raw, fields } => Ok((occur, raw_query(schema, tokenizers, raw, fields)?)),
         })
         .collect::<Result<Vec<(Occur, Box<Query>)>>>()
 }
@@ -75,7 +123,33 @@ mod tests {
             assert_eq!(bool.should.is_empty(), false);
             assert_eq!(bool.must_not.len(), 2);
             let query = bool.create_query(&_schema).unwrap().downcast::<BooleanQuery>().unwrap();
-            assert_eq!(query.clauses().len(), 6);
+            assert_eq!(query.clauses().len(), 7);
+        }
+    }
+
+    #[test]
+    fn test_nested_bool_query() {
+        let test_json = r#"
+        {"query": {
+            "bool": {
+                "must":   [ {"raw": "user:kimchy"} ],
+                "should": [ {"bool": {"must": [ {"term": {"user": "kimchy"}} ], "must_not": [ {"range": {"age": {"gte": 0, "lte": 5}}} ]}} ]
+              }
+            }
+        }"#;
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("user", STORED | TEXT);
+        builder.add_i64_field("age", FAST);
+        let schema = builder.build();
+
+        let result = serde_json::from_str::<Request>(test_json).unwrap();
+        if let Some(super::super::Query::Boolean { bool }) = result.query {
+            let query = bool.create_query(&schema).unwrap().downcast::<BooleanQuery>().unwrap();
+            assert_eq!(query.clauses().len(), 2);
+            let nested = query.clauses()[1].1.box_clone().downcast::<BooleanQuery>().unwrap();
+            assert_eq!(nested.clauses().len(), 2);
+        } else {
+            panic!("Expected a bool query");
         }
     }
 }
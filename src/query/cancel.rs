@@ -0,0 +1,151 @@
+//! Searches stop early once they're cancelled. The query a search runs is wrapped so its documents stop coming as
+//! soon as the search is asked to stop, which ends the collection of every collector fed by it, aggregations included,
+//! and the search then fails rather than answering with what it had found so far.
+
+use tantivy::query::{Query, Scorer, Weight};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, SkipResult};
+
+use crate::tasks::Cancellation;
+
+/// How many documents are collected between checks of whether the search has been cancelled
+const CHECK_EVERY: u32 = 4096;
+
+/// `query`, stopping once `cancellation` is set
+pub fn cancellable(query: Box<Query>, cancellation: &Cancellation) -> Box<Query> {
+    Box::new(CancellableQuery {
+        query,
+        cancellation: cancellation.clone(),
+    })
+}
+
+#[derive(Debug)]
+struct CancellableQuery {
+    query: Box<Query>,
+    cancellation: Cancellation,
+}
+
+impl Clone for CancellableQuery {
+    fn clone(&self) -> Self {
+        CancellableQuery {
+            query: self.query.box_clone(),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+impl Query for CancellableQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        Ok(Box::new(CancellableWeight {
+            weight: self.query.weight(searcher, scoring_enabled)?,
+            cancellation: self.cancellation.clone(),
+        }))
+    }
+}
+
+struct CancellableWeight {
+    weight: Box<Weight>,
+    cancellation: Cancellation,
+}
+
+impl Weight for CancellableWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        if self.cancellation.is_cancelled() {
+            return Err(tantivy::TantivyError::InvalidArgument("Task was cancelled".into()));
+        }
+        Ok(Box::new(CancellableScorer {
+            scorer: self.weight.scorer(reader)?,
+            cancellation: self.cancellation.clone(),
+            until_check: CHECK_EVERY,
+        }))
+    }
+}
+
+struct CancellableScorer {
+    scorer: Box<Scorer>,
+    cancellation: Cancellation,
+    until_check: u32,
+}
+
+impl CancellableScorer {
+    fn cancelled(&mut self) -> bool {
+        self.until_check -= 1;
+        if self.until_check > 0 {
+            return false;
+        }
+        self.until_check = CHECK_EVERY;
+        self.cancellation.is_cancelled()
+    }
+}
+
+impl DocSet for CancellableScorer {
+    fn advance(&mut self) -> bool {
+        !self.cancelled() && self.scorer.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        if self.cancelled() {
+            return SkipResult::End;
+        }
+        self.scorer.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.scorer.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorer.size_hint()
+    }
+}
+
+impl Scorer for CancellableScorer {
+    fn score(&mut self) -> Score {
+        self.scorer.score()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::Count;
+    use tantivy::doc;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{SchemaBuilder, INDEXED};
+    use tantivy::Index;
+
+    #[test]
+    fn test_cancellable() {
+        let mut builder = SchemaBuilder::new();
+        let number = builder.add_u64_field("number", INDEXED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 40_000_000).unwrap();
+        for n in 0..u64::from(CHECK_EVERY) * 3 {
+            writer.add_document(doc!(number => n));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+
+        let cancellation = Cancellation::default();
+        let query = cancellable(Box::new(AllQuery), &cancellation);
+        assert_eq!(searcher.search(&*query, &Count).unwrap(), CHECK_EVERY as usize * 3);
+
+        let weight = query.weight(&searcher, false).unwrap();
+        let mut scorer = weight.scorer(searcher.segment_reader(0)).unwrap();
+        let mut seen = 0;
+        while scorer.advance() {
+            seen += 1;
+            if seen == 10 {
+                cancellation.cancel();
+            }
+        }
+        assert_eq!(seen, CHECK_EVERY - 1);
+        assert!(query
+            .box_clone()
+            .weight(&searcher, false)
+            .unwrap()
+            .scorer(searcher.segment_reader(0))
+            .is_err());
+        assert!(searcher.search(&*query, &Count).is_err());
+    }
+}
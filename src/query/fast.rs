@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use tantivy::fastfield::{FastFieldReader, MultiValueIntFastFieldReader};
+use tantivy::schema::{Cardinality, Field, FieldType, Schema, Value};
+use tantivy::{DocAddress, DocId, Searcher, SegmentReader};
+
+use crate::query::script::{Script, ScriptValues};
+use crate::query::sort_field;
+use crate::{Error, Result};
+
+/// The values documents have in a u64 or i64 fast field of a segment, whether the field holds a single value for each
+/// document or any number of them, or those a script gives them as an i64 field that documents may have no value in
+pub enum FastValues {
+    U64(FastFieldReader<u64>),
+    I64(FastFieldReader<i64>),
+    MultiU64(MultiValueIntFastFieldReader<u64>),
+    MultiI64(MultiValueIntFastFieldReader<i64>),
+    Script(Box<ScriptValues>),
+}
+
+impl FastValues {
+    pub fn new(reader: &SegmentReader, schema: &Schema, field: Field) -> Result<Self> {
+        let values = match schema.get_field_entry(field).field_type() {
+            FieldType::I64(options) if options.get_fastfield_cardinality() == Some(Cardinality::MultiValues) => {
+                reader.multi_fast_field_reader::<i64>(field).map(FastValues::MultiI64)
+            }
+            FieldType::U64(options) if options.get_fastfield_cardinality() == Some(Cardinality::MultiValues) => {
+                reader.multi_fast_field_reader::<u64>(field).map(FastValues::MultiU64)
+            }
+            FieldType::I64(_) => reader.fast_field_reader::<i64>(field).map(FastValues::I64),
+            _ => reader.fast_field_reader::<u64>(field).map(FastValues::U64),
+        };
+        values.map_err(|e| Error::QueryError(format!("{:?}", e)))
+    }
+
+    /// The values of the fast field named `name` or, when given, those `script` works out
+    pub fn named(reader: &SegmentReader, schema: &Schema, name: &str, script: Option<&Arc<Script>>) -> Result<Self> {
+        match script {
+            Some(script) => Ok(FastValues::Script(Box::new(Script::values(script, reader, schema)?))),
+            None => FastValues::new(reader, schema, sort_field(schema, name)?),
+        }
+    }
+
+    /// Whether documents can have other than one value, so there's no single value of each to go by
+    pub fn is_multi(&self) -> bool {
+        match self {
+            FastValues::MultiU64(_) | FastValues::MultiI64(_) | FastValues::Script(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The value of `doc` in a single valued field, mapped to a u64 that orders the same way
+    pub fn single(&self, doc: DocId) -> Option<u64> {
+        match self {
+            FastValues::U64(reader) => Some(reader.get(doc)),
+            FastValues::I64(reader) => Some(tantivy::i64_to_u64(reader.get(doc))),
+            _ => None,
+        }
+    }
+
+    /// Every value of `doc`, each mapped to a u64 that orders the same way. Single valued fields give documents that
+    /// weren't given a value 0.
+    pub fn get(&self, doc: DocId, values: &mut Vec<u64>) {
+        values.clear();
+        match self {
+            FastValues::U64(_) | FastValues::I64(_) => values.extend(self.single(doc)),
+            FastValues::MultiU64(reader) => reader.get_vals(doc, values),
+            FastValues::MultiI64(reader) => {
+                let mut signed = Vec::new();
+                reader.get_vals(doc, &mut signed);
+                values.extend(signed.into_iter().map(tantivy::i64_to_u64));
+            }
+            FastValues::Script(script) => values.extend(script.get(doc).map(tantivy::i64_to_u64)),
+        }
+    }
+
+    /// A value `get` gave, back in the field's own type
+    pub fn value(&self, mapped: u64) -> Value {
+        match self {
+            FastValues::U64(_) | FastValues::MultiU64(_) => Value::U64(mapped),
+            FastValues::I64(_) | FastValues::MultiI64(_) | FastValues::Script(_) => Value::I64(tantivy::u64_to_i64(mapped)),
+        }
+    }
+}
+
+/// The u64 or i64 fast fields named `names`, to read the values of hits from
+pub fn doc_value_fields(schema: &Schema, names: &[String]) -> Result<Vec<Field>> {
+    names.iter().map(|name| sort_field(schema, name)).collect()
+}
+
+/// What `doc` has in each of `fields`, by field name
+pub fn doc_values(searcher: &Searcher, doc: DocAddress, fields: &[Field]) -> Result<BTreeMap<String, Vec<Value>>> {
+    let schema = searcher.schema();
+    let reader = searcher.segment_reader(doc.segment_ord());
+    let mut found = Vec::new();
+    let mut doc_values = BTreeMap::new();
+    for field in fields {
+        let values = FastValues::new(reader, schema, *field)?;
+        values.get(doc.doc(), &mut found);
+        let name = schema.get_field_name(*field).to_string();
+        doc_values.insert(name, found.iter().map(|v| values.value(*v)).collect());
+    }
+    Ok(doc_values)
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tantivy::fastfield::BytesFastFieldReader;
+use tantivy::query::{Query, Scorer, Weight};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader};
+
+use crate::mapping;
+use crate::{Error, Result};
+
+/// Scores documents by the weights their rank features field gives `feature`, or the sum of the weights it gives each
+/// of `features` times the weight the query gives it. Only documents with at least one of the features match. A
+/// weight is scored by `saturation` unless `log` or `linear` is given instead:
+///
+/// * `saturation` scores `weight / (weight + pivot)`, from 0 towards 1, with `pivot` the mean weight of the feature
+///   across the index unless given
+/// * `log` scores `ln(scaling_factor + weight)`
+/// * `linear` scores the weight as it is, which makes `features` the dot product of two sparse vectors
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RankFeatureQuery {
+    pub field: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feature: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<Saturation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<Log>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linear: Option<Linear>,
+    #[serde(default = "RankFeatureQuery::default_boost")]
+    pub boost: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Saturation {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pivot: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Log {
+    #[serde(default = "Log::default_scaling_factor")]
+    pub scaling_factor: f32,
+}
+
+impl Log {
+    pub fn default_scaling_factor() -> f32 {
+        1.0
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Linear {}
+
+/// How a feature's weight is scored, with the pivot of a saturation worked out for each search when not given
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FeatureFunction {
+    Saturation(Option<f32>),
+    Log(f32),
+    Linear,
+}
+
+impl FeatureFunction {
+    fn score(self, weight: f32) -> Score {
+        match self {
+            FeatureFunction::Saturation(pivot) => {
+                let pivot = pivot.unwrap_or(1.0);
+                weight / (weight + pivot)
+            }
+            FeatureFunction::Log(scaling_factor) => (scaling_factor + weight).ln(),
+            FeatureFunction::Linear => weight,
+        }
+    }
+}
+
+impl RankFeatureQuery {
+    pub fn default_boost() -> f32 {
+        1.0
+    }
+
+    pub fn build(self, schema: &Schema) -> Result<Box<Query>> {
+        let field = features_field(schema, &self.field)?;
+        let mut features: Vec<(String, f32)> = self.features.into_iter().collect();
+        if let Some(feature) = self.feature {
+            features.push((feature, 1.0));
+        }
+        if features.is_empty() {
+            return Err(Error::QueryError(format!(
+                "The rank_feature query of {} has no features",
+                self.field
+            )));
+        }
+        let function = match (self.saturation, self.log, self.linear) {
+            (saturation, None, None) => FeatureFunction::Saturation(saturation.unwrap_or_default().pivot),
+            (None, Some(log), None) => FeatureFunction::Log(log.scaling_factor),
+            (None, None, Some(_)) => FeatureFunction::Linear,
+            _ => {
+                return Err(Error::QueryError(format!(
+                    "The rank_feature query of {} gives more than one of saturation, log and linear",
+                    self.field
+                )))
+            }
+        };
+        match function {
+            FeatureFunction::Saturation(Some(pivot)) if pivot <= 0.0 => {
+                return Err(Error::QueryError(format!("Saturation pivot {} is not positive", pivot)))
+            }
+            FeatureFunction::Log(scaling_factor) if scaling_factor < 1.0 => {
+                return Err(Error::QueryError(format!("Log scaling factor {} is less than 1", scaling_factor)))
+            }
+            _ => {}
+        }
+        Ok(Box::new(FeatureQuery {
+            field,
+            features,
+            function,
+            boost: self.boost,
+        }))
+    }
+}
+
+/// The bytes fast field a rank features field named `name` keeps its features in
+pub fn features_field(schema: &Schema, name: &str) -> Result<Field> {
+    let field = schema
+        .get_field(&format!("{}.fast", name))
+        .or_else(|| schema.get_field(name))
+        .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", name)))?;
+    match schema.get_field_entry(field).field_type() {
+        FieldType::Bytes => Ok(field),
+        _ => Err(Error::QueryError(format!("Field {} is not a rank features field", name))),
+    }
+}
+
+/// Matches the documents of each segment with any of `features` in `field`, each given with the weight the query gives it
+#[derive(Debug, Clone)]
+struct FeatureQuery {
+    field: Field,
+    features: Vec<(String, f32)>,
+    function: FeatureFunction,
+    boost: f32,
+}
+
+impl Query for FeatureQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let mut functions = vec![self.function; self.features.len()];
+        if self.function == FeatureFunction::Saturation(None) {
+            // The pivot of each feature is the mean of its weights, which is scored 0.5
+            let mut sums = vec![(0.0f64, 0usize); self.features.len()];
+            for reader in searcher.segment_readers() {
+                let values = reader.bytes_fast_field_reader(self.field)?;
+                for doc in (0..reader.max_doc()).filter(|doc| !reader.is_deleted(*doc)) {
+                    for (name, weight) in mapping::features_from_bytes(values.get_val(doc)) {
+                        if let Some(i) = self.features.iter().position(|(feature, _)| *feature == name) {
+                            sums[i].0 += f64::from(weight);
+                            sums[i].1 += 1;
+                        }
+                    }
+                }
+            }
+            for (function, (sum, count)) in functions.iter_mut().zip(sums) {
+                if count > 0 && sum > 0.0 {
+                    *function = FeatureFunction::Saturation(Some((sum / count as f64) as f32));
+                }
+            }
+        }
+        Ok(Box::new(FeatureWeight {
+            field: self.field,
+            features: self
+                .features
+                .iter()
+                .zip(functions)
+                .map(|((name, weight), f)| (name.clone(), *weight, f))
+                .collect(),
+            boost: self.boost,
+        }))
+    }
+}
+
+struct FeatureWeight {
+    field: Field,
+    features: Vec<(String, f32, FeatureFunction)>,
+    boost: f32,
+}
+
+impl Weight for FeatureWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        Ok(Box::new(FeatureScorer {
+            values: reader.bytes_fast_field_reader(self.field)?,
+            features: self.features.clone(),
+            boost: self.boost,
+            max_doc: reader.max_doc(),
+            doc: None,
+            score: 0.0,
+        }))
+    }
+}
+
+/// Goes through every document of a segment for those with any of the features
+struct FeatureScorer {
+    values: BytesFastFieldReader,
+    features: Vec<(String, f32, FeatureFunction)>,
+    boost: f32,
+    max_doc: DocId,
+    doc: Option<DocId>,
+    score: Score,
+}
+
+impl DocSet for FeatureScorer {
+    fn advance(&mut self) -> bool {
+        let mut doc = self.doc.map_or(0, |doc| doc + 1);
+        while doc < self.max_doc {
+            let mut found = false;
+            let mut score = 0.0;
+            for (name, weight) in mapping::features_from_bytes(self.values.get_val(doc)) {
+                if let Some((_, given, function)) = self.features.iter().find(|(feature, _, _)| *feature == name) {
+                    found = true;
+                    score += given * function.score(weight);
+                }
+            }
+            if found {
+                self.doc = Some(doc);
+                self.score = score * self.boost;
+                return true;
+            }
+            doc += 1;
+        }
+        self.doc = Some(self.max_doc);
+        false
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc.unwrap_or(0)
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.max_doc
+    }
+}
+
+impl Scorer for FeatureScorer {
+    fn score(&mut self) -> Score {
+        self.score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, STORED, STRING};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_rank_feature_query() {
+        let mut builder = SchemaBuilder::new();
+        let name = builder.add_text_field("name", STRING | STORED);
+        let signals = builder.add_bytes_field("signals");
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (text, features) in &[
+            ("popular", vec![("pagerank", 9.0), ("rust", 0.5)]),
+            ("obscure", vec![("pagerank", 1.0), ("rust", 2.0)]),
+            ("average", vec![("pagerank", 5.0)]),
+        ] {
+            let features: BTreeMap<String, f32> = features.iter().map(|(f, w)| (f.to_string(), *w)).collect();
+            writer.add_document(doc!(name => *text, signals => mapping::features_bytes(&features)));
+        }
+        writer.add_document(doc!(name => "none"));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let ranked = |query: &str| -> Vec<(String, String)> {
+            let query: RankFeatureQuery = serde_json::from_str(query).unwrap();
+            let query = query.build(&schema).unwrap();
+            searcher
+                .search(&*query, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(score, doc)| {
+                    let doc = searcher.doc(doc).unwrap();
+                    (doc.get_first(name).unwrap().text().unwrap().to_string(), format!("{:.2}", score))
+                })
+                .collect()
+        };
+        let pair = |name: &str, score: &str| (name.to_string(), score.to_string());
+        // The pivot is the mean pagerank of 5
+        assert_eq!(
+            ranked(r#"{ "field": "signals", "feature": "pagerank" }"#),
+            vec![pair("popular", "0.64"), pair("average", "0.50"), pair("obscure", "0.17")]
+        );
+        assert_eq!(
+            ranked(r#"{ "field": "signals", "feature": "pagerank", "log": { "scaling_factor": 1 }, "boost": 2 }"#)[0],
+            pair("popular", "4.61")
+        );
+        assert_eq!(
+            ranked(r#"{ "field": "signals", "features": { "rust": 1.0, "pagerank": 0.1 }, "linear": {} }"#),
+            vec![pair("obscure", "2.10"), pair("popular", "1.40"), pair("average", "0.50")]
+        );
+
+        for bad in &[
+            r#"{ "field": "signals" }"#,
+            r#"{ "field": "name", "feature": "pagerank" }"#,
+            r#"{ "field": "signals", "feature": "pagerank", "log": {}, "linear": {} }"#,
+            r#"{ "field": "signals", "feature": "pagerank", "saturation": { "pivot": 0 } }"#,
+        ] {
+            let query: RankFeatureQuery = serde_json::from_str(bad).unwrap();
+            assert!(query.build(&schema).is_err());
+        }
+    }
+}
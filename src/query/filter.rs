@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use tantivy::query::{Query, Scorer, Weight};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentId, SegmentReader};
+
+/// The documents matching a filter clause in one segment, in ascending order
+type FilterDocs = Arc<Vec<DocId>>;
+
+/// Caches the documents each filter clause matches, per segment. Segments never change once written, apart from
+/// deletes which collectors skip anyway, so an entry stays valid until its segment is merged away.
+pub struct FilterCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<(SegmentId, String), (FilterDocs, u64)>,
+    tick: u64,
+}
+
+impl FilterCache {
+    /// A cache holding at most `capacity` segment and clause pairs, a capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        FilterCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop entries for segments that are no longer part of the index
+    pub fn retain_segments(&self, segments: &[SegmentId]) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.keys().any(|(segment, _)| !segments.contains(segment)) {
+            state.entries.retain(|(segment, _), _| segments.contains(segment));
+        }
+    }
+
+    fn get_or_insert<F>(&self, segment: SegmentId, key: &str, matching: F) -> tantivy::Result<FilterDocs>
+    where
+        F: FnOnce() -> tantivy::Result<Vec<DocId>>,
+    {
+        if self.capacity == 0 {
+            return matching().map(Arc::new);
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some((docs, last_used)) = state.entries.get_mut(&(segment, key.to_string())) {
+                *last_used = tick;
+                return Ok(Arc::clone(docs));
+            }
+        }
+
+        // Matching can take a while on a large segment, so it happens without holding the lock
+        let docs = Arc::new(matching()?);
+        let mut state = self.state.lock().unwrap();
+        if state.entries.len() >= self.capacity {
+            let oldest = state.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        let tick = state.tick;
+        state.entries.insert((segment, key.to_string()), (Arc::clone(&docs), tick));
+        Ok(docs)
+    }
+}
+
+impl fmt::Debug for FilterCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FilterCache {{ capacity: {}, len: {} }}", self.capacity, self.len())
+    }
+}
+
+/// Matches the same documents as `inner` without contributing to the score, reusing the
+/// matches cached for each segment under `key` when there is a cache to use.
+#[derive(Debug)]
+pub struct FilterQuery {
+    inner: Box<Query>,
+    key: String,
+    cache: Option<Arc<FilterCache>>,
+}
+
+impl FilterQuery {
+    pub fn new(inner: Box<Query>, key: String, cache: Option<Arc<FilterCache>>) -> Self {
+        FilterQuery { inner, key, cache }
+    }
+}
+
+impl Clone for FilterQuery {
+    fn clone(&self) -> Self {
+        FilterQuery {
+            inner: self.inner.box_clone(),
+            key: self.key.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl Query for FilterQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        Ok(Box::new(FilterWeight {
+            inner: self.inner.weight(searcher, false)?,
+            key: self.key.clone(),
+            cache: self.cache.clone(),
+        }))
+    }
+}
+
+struct FilterWeight {
+    inner: Box<Weight>,
+    key: String,
+    cache: Option<Arc<FilterCache>>,
+}
+
+impl FilterWeight {
+    fn matching(&self, reader: &SegmentReader) -> tantivy::Result<Vec<DocId>> {
+        let mut scorer = self.inner.scorer(reader)?;
+        let mut docs = Vec::new();
+        while scorer.advance() {
+            docs.push(scorer.doc());
+        }
+        Ok(docs)
+    }
+}
+
+impl Weight for FilterWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        let docs = match self.cache {
+            Some(ref cache) => cache.get_or_insert(reader.segment_id(), &self.key, || self.matching(reader))?,
+            None => Arc::new(self.matching(reader)?),
+        };
+        Ok(Box::new(FilterScorer { docs, cursor: None }))
+    }
+}
+
+struct FilterScorer {
+    docs: FilterDocs,
+    cursor: Option<usize>,
+}
+
+impl DocSet for FilterScorer {
+    fn advance(&mut self) -> bool {
+        let next = self.cursor.map(|c| c + 1).unwrap_or(0);
+        self.cursor = Some(next);
+        next < self.docs.len()
+    }
+
+    fn doc(&self) -> DocId {
+        self.docs[self.cursor.unwrap_or(0)]
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.docs.len() as u32
+    }
+}
+
+impl Scorer for FilterScorer {
+    fn score(&mut self) -> Score {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_index;
+    use tantivy::collector::Count;
+    use tantivy::query::TermQuery;
+    use tantivy::schema::IndexRecordOption;
+    use tantivy::Term;
+
+    #[test]
+    fn test_filter_cache() {
+        let index = create_test_index();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let field = index.schema().get_field("test_text").unwrap();
+        let term = TermQuery::new(Term::from_field_text(field, "document"), IndexRecordOption::Basic);
+
+        let cache = Arc::new(FilterCache::new(10));
+        let query = FilterQuery::new(Box::new(term), "test_text:document".into(), Some(Arc::clone(&cache)));
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 3);
+        assert_eq!(cache.len(), searcher.segment_readers().len());
+        assert_eq!(searcher.search(&query, &Count).unwrap(), 3);
+        assert_eq!(cache.len(), searcher.segment_readers().len());
+
+        cache.retain_segments(&[]);
+        assert!(cache.is_empty());
+    }
+}
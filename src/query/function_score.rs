@@ -0,0 +1,716 @@
+use std::collections::BTreeMap;
+use std::f32;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::query::{AllQuery, Occur, Query, Scorer, Weight};
+use tantivy::schema::{Field, Schema, Value as TantivyValue};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, SkipResult};
+
+use crate::mapping;
+use crate::query::{parse_queries, sort_field, FastValues, TermQueries};
+use crate::{Error, Result};
+
+/// Scores the documents `query` matches, or every document without one, by `functions` as well as by the query.
+/// Each function applies to the documents its `filter` matches, or to all of them without one, and its score is
+/// multiplied by its `weight`. The scores of the functions that apply to a document are combined by `score_mode`, and
+/// that by `boost_mode` with the query's score.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FunctionScoreQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    query: Option<Box<TermQueries>>,
+    #[serde(default)]
+    functions: Vec<ScoreFunction>,
+    #[serde(default)]
+    score_mode: FunctionScoreMode,
+    #[serde(default)]
+    boost_mode: BoostMode,
+    #[serde(default = "FunctionScoreQuery::default_boost")]
+    boost: f32,
+}
+
+/// One of the functions of a `function_score` query, a `random_score`, a `gauss`, `exp` or `linear` decay or a
+/// constant `weight` alone
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ScoreFunction {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<TermQueries>,
+    #[serde(default = "FunctionScoreQuery::default_boost")]
+    weight: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    random_score: Option<RandomScore>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gauss: Option<BTreeMap<String, Decay>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<BTreeMap<String, Decay>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    linear: Option<BTreeMap<String, Decay>>,
+}
+
+/// Scores documents by how far their value in a u64 or i64 fast field is from `origin`, 1 within `offset` of it and
+/// `decay` at `scale` further away. Numbers are given as they are, while a date field is given a date or `now` as its
+/// origin and durations such as `7d` or `12h` as its scale and offset.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Decay {
+    origin: Value,
+    scale: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset: Option<Value>,
+    #[serde(default = "Decay::default_decay")]
+    decay: f64,
+}
+
+impl Decay {
+    pub fn default_decay() -> f64 {
+        0.5
+    }
+}
+
+/// How a decay falls from 1 as a value gets further from the origin
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DecayShape {
+    /// Slowly near the origin and the scale, and quickly between them, like a normal distribution
+    Gauss,
+    /// Quickly at first and ever more slowly after
+    Exp,
+    /// In a straight line, down to 0 at twice the scale for a decay of 0.5
+    Linear,
+}
+
+impl DecayShape {
+    /// The score of a value `distance` past the offset, where the score at `scale` is `decay`
+    fn score(self, distance: f64, scale: f64, decay: f64) -> f64 {
+        match self {
+            DecayShape::Gauss => decay.powf(distance * distance / (scale * scale)),
+            DecayShape::Exp => decay.powf(distance / scale),
+            DecayShape::Linear => {
+                let zero = scale / (1.0 - decay);
+                ((zero - distance) / zero).max(0.0)
+            }
+        }
+    }
+}
+
+/// Scores each document between 0 and 1 by hashing `seed` together with the document's value in the u64 or i64 fast
+/// field `field`, so the same seed, such as the id of a user, shuffles the documents the same way each time. Without
+/// a field documents are hashed by their ids within their segments, which change as segments are merged.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct RandomScore {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<Seed>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum Seed {
+    Number(i64),
+    Text(String),
+}
+
+impl Seed {
+    /// FNV-1a of the seed as text, so `7` and `"7"` are the same seed, which unlike the standard library's hasher is
+    /// the same on every node and every release
+    fn hash(&self) -> u64 {
+        let bytes = match self {
+            Seed::Number(n) => n.to_string().into_bytes(),
+            Seed::Text(text) => text.clone().into_bytes(),
+        };
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// How the scores of the functions that apply to a document are combined
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionScoreMode {
+    Multiply,
+    Sum,
+    Avg,
+    First,
+    Max,
+    Min,
+}
+
+impl Default for FunctionScoreMode {
+    fn default() -> Self {
+        FunctionScoreMode::Multiply
+    }
+}
+
+/// How the combined score of the functions is combined with the query's
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BoostMode {
+    Multiply,
+    Replace,
+    Sum,
+    Avg,
+    Max,
+    Min,
+}
+
+impl Default for BoostMode {
+    fn default() -> Self {
+        BoostMode::Multiply
+    }
+}
+
+impl FunctionScoreQuery {
+    pub fn default_boost() -> f32 {
+        1.0
+    }
+
+    pub fn build(self, schema: &Schema, tokenizers: &TokenizerManager) -> Result<Box<Query>> {
+        let query = match self.query {
+            Some(query) => parse_queries(schema, tokenizers, Occur::Must, &[*query])?.remove(0).1,
+            None => Box::new(AllQuery),
+        };
+        let functions = self
+            .functions
+            .into_iter()
+            .map(|function| {
+                let filter = match function.filter {
+                    Some(filter) => Some(parse_queries(schema, tokenizers, Occur::Must, &[filter])?.remove(0).1),
+                    None => None,
+                };
+                let mut kinds = Vec::new();
+                if let Some(random) = function.random_score {
+                    kinds.push(Function::Random {
+                        seed: random.seed.map_or(0, |seed| seed.hash()),
+                        field: match random.field {
+                            Some(ref name) => Some(sort_field(schema, name)?),
+                            None => None,
+                        },
+                    });
+                }
+                for (shape, decays) in vec![
+                    (DecayShape::Gauss, function.gauss),
+                    (DecayShape::Exp, function.exp),
+                    (DecayShape::Linear, function.linear),
+                ] {
+                    if let Some(decays) = decays {
+                        kinds.push(decay_function(schema, shape, decays)?);
+                    }
+                }
+                let kind = match kinds.len() {
+                    0 => Function::Weight,
+                    1 => kinds.remove(0),
+                    _ => {
+                        return Err(Error::QueryError(
+                            "A function can only be one of random_score, gauss, exp and linear".into(),
+                        ))
+                    }
+                };
+                Ok(FilteredFunction {
+                    filter,
+                    weight: function.weight,
+                    kind,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if functions.is_empty() {
+            return Err(Error::QueryError("A function_score query needs at least one function".into()));
+        }
+        Ok(Box::new(FunctionQuery {
+            query,
+            functions,
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+            boost: self.boost,
+        }))
+    }
+}
+
+/// The decay of the one field of `decays`, with its origin, scale and offset as numbers
+fn decay_function(schema: &Schema, shape: DecayShape, decays: BTreeMap<String, Decay>) -> Result<Function> {
+    if decays.len() != 1 {
+        return Err(Error::QueryError("A decay function needs exactly one field".into()));
+    }
+    let (name, decay) = decays.into_iter().next().unwrap();
+    let field = sort_field(schema, &name)?;
+    let origin = match decay.origin {
+        Value::String(ref now) if now == "now" => Utc::now().timestamp_millis() as f64,
+        ref origin => origin
+            .as_f64()
+            .ok_or_else(|| Error::QueryError(format!("Origin {} of {} is not a number, a date or now", origin, name)))?,
+    };
+    let distance = |value: &Value| -> Result<f64> {
+        match value {
+            Value::String(duration) => Ok(mapping::parse_duration(duration)? as f64),
+            value => value
+                .as_f64()
+                .filter(|distance| *distance >= 0.0)
+                .ok_or_else(|| Error::QueryError(format!("{} of {} is not a positive number or a duration", value, name))),
+        }
+    };
+    let scale = distance(&decay.scale)?;
+    let offset = match decay.offset {
+        Some(ref offset) => distance(offset)?,
+        None => 0.0,
+    };
+    if scale <= 0.0 {
+        return Err(Error::QueryError(format!("Scale of {} is not positive", name)));
+    }
+    if decay.decay <= 0.0 || decay.decay >= 1.0 {
+        return Err(Error::QueryError(format!(
+            "Decay {} of {} is not between 0 and 1",
+            decay.decay, name
+        )));
+    }
+    Ok(Function::Decay {
+        field,
+        shape,
+        origin,
+        scale,
+        offset,
+        decay: decay.decay,
+    })
+}
+
+/// What a function scores a document by, before its weight
+#[derive(Debug, Clone, PartialEq)]
+enum Function {
+    Weight,
+    Random {
+        seed: u64,
+        field: Option<Field>,
+    },
+    Decay {
+        field: Field,
+        shape: DecayShape,
+        origin: f64,
+        scale: f64,
+        offset: f64,
+        decay: f64,
+    },
+}
+
+#[derive(Debug)]
+struct FilteredFunction {
+    filter: Option<Box<Query>>,
+    weight: f32,
+    kind: Function,
+}
+
+impl Clone for FilteredFunction {
+    fn clone(&self) -> Self {
+        FilteredFunction {
+            filter: self.filter.as_ref().map(|filter| filter.box_clone()),
+            weight: self.weight,
+            kind: self.kind.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FunctionQuery {
+    query: Box<Query>,
+    functions: Vec<FilteredFunction>,
+    score_mode: FunctionScoreMode,
+    boost_mode: BoostMode,
+    boost: f32,
+}
+
+impl Clone for FunctionQuery {
+    fn clone(&self) -> Self {
+        FunctionQuery {
+            query: self.query.box_clone(),
+            functions: self.functions.clone(),
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+            boost: self.boost,
+        }
+    }
+}
+
+impl Query for FunctionQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let filters = self
+            .functions
+            .iter()
+            .map(|function| match function.filter {
+                Some(ref filter) => filter.weight(searcher, false).map(Some),
+                None => Ok(None),
+            })
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        Ok(Box::new(FunctionWeight {
+            query: self.query.weight(searcher, scoring_enabled)?,
+            filters,
+            functions: self.functions.iter().map(|f| (f.weight, f.kind.clone())).collect(),
+            schema: searcher.schema().clone(),
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+            boost: self.boost,
+        }))
+    }
+}
+
+struct FunctionWeight {
+    query: Box<Weight>,
+    filters: Vec<Option<Box<Weight>>>,
+    functions: Vec<(f32, Function)>,
+    schema: Schema,
+    score_mode: FunctionScoreMode,
+    boost_mode: BoostMode,
+    boost: f32,
+}
+
+impl Weight for FunctionWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        let mut functions = Vec::with_capacity(self.functions.len());
+        for (filter, (weight, kind)) in self.filters.iter().zip(&self.functions) {
+            let filter = match filter {
+                Some(filter) => {
+                    let mut scorer = filter.scorer(reader)?;
+                    let current = if scorer.advance() { Some(scorer.doc()) } else { None };
+                    Some((scorer, current))
+                }
+                None => None,
+            };
+            let kind = match kind {
+                Function::Weight => SegmentFunction::Weight,
+                Function::Random { seed, field } => SegmentFunction::Random {
+                    seed: *seed,
+                    values: match field {
+                        Some(field) => Some(self.values(reader, *field)?),
+                        None => None,
+                    },
+                },
+                Function::Decay {
+                    field,
+                    shape,
+                    origin,
+                    scale,
+                    offset,
+                    decay,
+                } => SegmentFunction::Decay {
+                    values: self.values(reader, *field)?,
+                    shape: *shape,
+                    origin: *origin,
+                    scale: *scale,
+                    offset: *offset,
+                    decay: *decay,
+                },
+            };
+            functions.push((filter, *weight, kind));
+        }
+        Ok(Box::new(FunctionScorer {
+            query: self.query.scorer(reader)?,
+            functions,
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+            boost: self.boost,
+            values: Vec::new(),
+        }))
+    }
+}
+
+impl FunctionWeight {
+    fn values(&self, reader: &SegmentReader, field: Field) -> tantivy::Result<FastValues> {
+        FastValues::new(reader, &self.schema, field).map_err(|e| tantivy::TantivyError::SchemaError(e.to_string()))
+    }
+}
+
+/// A function as it scores the documents of one segment
+enum SegmentFunction {
+    Weight,
+    Random {
+        seed: u64,
+        values: Option<FastValues>,
+    },
+    Decay {
+        values: FastValues,
+        shape: DecayShape,
+        origin: f64,
+        scale: f64,
+        offset: f64,
+        decay: f64,
+    },
+}
+
+impl SegmentFunction {
+    fn score(&self, doc: DocId, values: &mut Vec<u64>) -> Score {
+        match self {
+            SegmentFunction::Weight => 1.0,
+            SegmentFunction::Random { seed, values: fast } => {
+                let value = match fast {
+                    Some(fast) => {
+                        fast.get(doc, values);
+                        values.first().cloned().unwrap_or(0)
+                    }
+                    None => u64::from(doc),
+                };
+                random(*seed, value)
+            }
+            SegmentFunction::Decay {
+                values: fast,
+                shape,
+                origin,
+                scale,
+                offset,
+                decay,
+            } => {
+                // Documents without a value aren't decayed, and those with several score by the closest
+                fast.get(doc, values);
+                if values.is_empty() {
+                    return 1.0;
+                }
+                values
+                    .iter()
+                    .map(|value| {
+                        let value = match fast.value(*value) {
+                            TantivyValue::I64(value) => value as f64,
+                            TantivyValue::U64(value) => value as f64,
+                            _ => 0.0,
+                        };
+                        let distance = ((value - origin).abs() - offset).max(0.0);
+                        shape.score(distance, *scale, *decay)
+                    })
+                    .fold(0.0, f64::max) as Score
+            }
+        }
+    }
+}
+
+/// A number between 0 and 1 that's the same for the same seed and value, by way of splitmix64
+fn random(seed: u64, value: u64) -> Score {
+    let mut x = seed ^ value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    // The top 24 bits are as many as an f32 holds exactly
+    (x >> 40) as Score / (1u64 << 24) as Score
+}
+
+struct FunctionScorer {
+    query: Box<Scorer>,
+    /// Each function with the scorer of its filter and the document it's on, none once it's past the last
+    functions: Vec<(Option<(Box<Scorer>, Option<DocId>)>, f32, SegmentFunction)>,
+    score_mode: FunctionScoreMode,
+    boost_mode: BoostMode,
+    boost: f32,
+    values: Vec<u64>,
+}
+
+impl DocSet for FunctionScorer {
+    fn advance(&mut self) -> bool {
+        self.query.advance()
+    }
+
+    fn doc(&self) -> DocId {
+        self.query.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.query.size_hint()
+    }
+}
+
+impl Scorer for FunctionScorer {
+    fn score(&mut self) -> Score {
+        let doc = self.query.doc();
+        let mut scores = Vec::with_capacity(self.functions.len());
+        for (filter, weight, function) in &mut self.functions {
+            if let Some((scorer, current)) = filter {
+                if current.map_or(false, |c| c < doc) {
+                    *current = match scorer.skip_next(doc) {
+                        SkipResult::End => None,
+                        _ => Some(scorer.doc()),
+                    };
+                }
+                if *current != Some(doc) {
+                    continue;
+                }
+            }
+            scores.push(*weight * function.score(doc, &mut self.values));
+            if self.score_mode == FunctionScoreMode::First {
+                break;
+            }
+        }
+        // A document none of the functions apply to keeps the query's score
+        let combined = if scores.is_empty() {
+            1.0
+        } else {
+            match self.score_mode {
+                FunctionScoreMode::Multiply => scores.iter().product(),
+                FunctionScoreMode::Sum => scores.iter().sum(),
+                FunctionScoreMode::Avg => scores.iter().sum::<Score>() / scores.len() as Score,
+                FunctionScoreMode::First => scores[0],
+                FunctionScoreMode::Max => scores.iter().cloned().fold(f32::MIN, Score::max),
+                FunctionScoreMode::Min => scores.iter().cloned().fold(f32::MAX, Score::min),
+            }
+        };
+        let score = self.query.score();
+        let boosted = match self.boost_mode {
+            BoostMode::Multiply => score * combined,
+            BoostMode::Replace => combined,
+            BoostMode::Sum => score + combined,
+            BoostMode::Avg => (score + combined) / 2.0,
+            BoostMode::Max => score.max(combined),
+            BoostMode::Min => score.min(combined),
+        };
+        boosted * self.boost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_random_score() {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let id = builder.add_u64_field("id", FAST | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for i in 0..20u64 {
+            let words = if i % 2 == 0 { "rust even" } else { "rust odd" };
+            writer.add_document(doc!(text => words, id => i));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let ranked = |query: &str| -> Vec<(u64, Score)> {
+            let query: FunctionScoreQuery = serde_json::from_str(query).unwrap();
+            let query = query.build(&schema, index.tokenizers()).unwrap();
+            searcher
+                .search(&*query, &TopDocs::with_limit(20))
+                .unwrap()
+                .into_iter()
+                .map(|(score, doc)| (searcher.doc(doc).unwrap().get_first(id).unwrap().u64_value(), score))
+                .collect()
+        };
+        let shuffle = |seed: &str| -> Vec<u64> {
+            ranked(&format!(
+                r#"{{ "functions": [{{ "random_score": {{ "seed": {}, "field": "id" }} }}], "boost_mode": "replace" }}"#,
+                seed
+            ))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+        };
+        // The same seed shuffles the same way, and another seed another way
+        let user = shuffle(r#""user-42""#);
+        assert_eq!(user.len(), 20);
+        assert_eq!(user, shuffle(r#""user-42""#));
+        assert_ne!(user, shuffle("7"));
+        assert_ne!(user, (0..20).collect::<Vec<u64>>());
+
+        // Scores are between 0 and 1 times the weight, only for the documents the filter matches
+        let weighted = ranked(
+            r#"{ "query": { "raw": "text:rust" },
+                 "functions": [{ "filter": { "raw": "text:even" }, "random_score": { "seed": 1, "field": "id" }, "weight": 10 },
+                               { "filter": { "raw": "text:odd" }, "weight": 0.5 }],
+                 "boost_mode": "replace" }"#,
+        );
+        for (id, score) in weighted {
+            if id % 2 == 0 {
+                assert!(score >= 0.0 && score < 10.0);
+            } else {
+                assert_eq!(score, 0.5);
+            }
+        }
+
+        let none: FunctionScoreQuery = serde_json::from_str(r#"{ "query": { "raw": "text:rust" } }"#).unwrap();
+        assert!(none.build(&schema, index.tokenizers()).is_err());
+        let missing: FunctionScoreQuery = serde_json::from_str(r#"{ "functions": [{ "random_score": { "field": "other" } }] }"#).unwrap();
+        assert!(missing.build(&schema, index.tokenizers()).is_err());
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut builder = SchemaBuilder::new();
+        let distance = builder.add_i64_field("distance", FAST | STORED);
+        let updated = builder.add_i64_field("updated", FAST);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        let day = 24 * 60 * 60 * 1000;
+        let now = Utc::now().timestamp_millis();
+        for km in &[0i64, 5, 10, 15, -10] {
+            writer.add_document(doc!(distance => *km, updated => now - km.abs() * day));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let scores = |function: &str| -> Vec<(i64, String)> {
+            let query: FunctionScoreQuery =
+                serde_json::from_str(&format!(r#"{{ "functions": [{}], "boost_mode": "replace" }}"#, function)).unwrap();
+            let query = query.build(&schema, index.tokenizers()).unwrap();
+            let mut scores: Vec<(i64, String)> = searcher
+                .search(&*query, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(score, doc)| {
+                    (
+                        searcher.doc(doc).unwrap().get_first(distance).unwrap().i64_value(),
+                        format!("{:.2}", score),
+                    )
+                })
+                .collect();
+            scores.sort();
+            scores
+        };
+        let pair = |km: i64, score: &str| (km, score.to_string());
+        assert_eq!(
+            scores(r#"{ "gauss": { "distance": { "origin": 0, "scale": 10 } } }"#),
+            vec![
+                pair(-10, "0.50"),
+                pair(0, "1.00"),
+                pair(5, "0.84"),
+                pair(10, "0.50"),
+                pair(15, "0.21")
+            ]
+        );
+        assert_eq!(
+            scores(r#"{ "exp": { "distance": { "origin": 0, "scale": 10, "offset": 5, "decay": 0.25 } } }"#),
+            vec![
+                pair(-10, "0.50"),
+                pair(0, "1.00"),
+                pair(5, "1.00"),
+                pair(10, "0.50"),
+                pair(15, "0.25")
+            ]
+        );
+        assert_eq!(
+            scores(r#"{ "linear": { "distance": { "origin": 0, "scale": 10 } } }"#),
+            vec![
+                pair(-10, "0.50"),
+                pair(0, "1.00"),
+                pair(5, "0.75"),
+                pair(10, "0.50"),
+                pair(15, "0.25")
+            ]
+        );
+        // Recency by days since now, a little after the documents were indexed
+        assert_eq!(
+            scores(r#"{ "linear": { "updated": { "origin": "now", "scale": "10d", "decay": 0.5 } } }"#)[3],
+            pair(10, "0.50")
+        );
+
+        for bad in &[
+            r#"{ "gauss": { "distance": { "origin": 0, "scale": 0 } } }"#,
+            r#"{ "gauss": { "distance": { "origin": 0, "scale": 10, "decay": 1.5 } } }"#,
+            r#"{ "gauss": { "distance": { "origin": "yesterday", "scale": 10 } } }"#,
+            r#"{ "gauss": { "distance": { "origin": 0, "scale": "10 days" } } }"#,
+            r#"{ "gauss": { "distance": { "origin": 0, "scale": 10 } }, "exp": { "distance": { "origin": 0, "scale": 10 } } }"#,
+            r#"{ "gauss": {} }"#,
+        ] {
+            let query: FunctionScoreQuery = serde_json::from_str(&format!(r#"{{ "functions": [{}] }}"#, bad)).unwrap();
+            assert!(query.build(&schema, index.tokenizers()).is_err());
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tantivy::schema::{Field, Schema};
+use tantivy::{DocId, Score, Searcher, SegmentId, SegmentReader};
+
+use crate::mapping::{self, MappedType, Mappings};
+use crate::query::knn::{vector_field, Similarity};
+
+/// The most layers a graph has above its bottom one, which would take far more vectors than a segment holds to fill
+const MAX_LEVEL: usize = 16;
+
+/// How the graph of a dense vector field's vectors is built in each segment
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct HnswParams {
+    /// How many neighbours each vector is linked to, twice as many in the bottom layer
+    #[serde(default = "HnswParams::default_m")]
+    pub m: usize,
+    /// How many candidate neighbours are looked at while linking a vector
+    #[serde(default = "HnswParams::default_ef_construction")]
+    pub ef_construction: usize,
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: HnswParams::default_m(),
+            ef_construction: HnswParams::default_ef_construction(),
+        }
+    }
+}
+
+impl HnswParams {
+    pub fn default_m() -> usize {
+        16
+    }
+
+    pub fn default_ef_construction() -> usize {
+        100
+    }
+}
+
+/// A hierarchical navigable small world graph of the vectors of one segment. Each vector is linked to those most like
+/// it in the bottom layer and in each of a few sparser layers above, so a search can start at the top and walk
+/// towards a vector's nearest in a number of steps that grows with the log of the number of vectors.
+pub struct Hnsw {
+    similarity: Similarity,
+    dims: usize,
+    /// The document of each node
+    docs: Vec<DocId>,
+    /// The vector of each node, one after the other
+    vectors: Vec<f32>,
+    /// The neighbours of each node in each layer it's in, from the bottom one up
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+}
+
+/// A node along with how alike its vector is to the one searched for
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(Score, u32);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal).then(self.1.cmp(&other.1))
+    }
+}
+
+impl Hnsw {
+    pub fn new(similarity: Similarity, dims: usize) -> Self {
+        Hnsw {
+            similarity,
+            dims,
+            docs: Vec::new(),
+            vectors: Vec::new(),
+            links: Vec::new(),
+            entry: None,
+        }
+    }
+
+    /// The graph of the vectors of `dims` numbers the documents of a segment have in the bytes fast field `field`
+    pub fn build(reader: &SegmentReader, field: Field, similarity: Similarity, dims: usize, params: HnswParams) -> tantivy::Result<Self> {
+        let vectors = reader.bytes_fast_field_reader(field)?;
+        let mut graph = Hnsw::new(similarity, dims);
+        for doc in 0..reader.max_doc() {
+            let bytes = vectors.get_val(doc);
+            // Deleted documents stay in the graph to be walked through, searches leave them out of what's found
+            if bytes.len() == dims * 4 {
+                graph.insert(doc, &mapping::vector_from_bytes(bytes), params);
+            }
+        }
+        Ok(graph)
+    }
+
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    pub fn similarity(&self) -> Similarity {
+        self.similarity
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    pub fn insert(&mut self, doc: DocId, vector: &[f32], params: HnswParams) {
+        let node = self.docs.len() as u32;
+        self.docs.push(doc);
+        self.vectors.extend_from_slice(vector);
+        let level = level(node, params.m);
+        self.links.push(vec![Vec::new(); level + 1]);
+        let mut entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(node);
+                return;
+            }
+        };
+
+        let top = self.links[entry as usize].len() - 1;
+        for layer in (level + 1..=top).rev() {
+            entry = self.search_layer(vector, &[entry], 1, layer, |_| true)[0].1;
+        }
+        let mut entries = vec![entry];
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(vector, &entries, params.ef_construction.max(params.m), layer, |_| true);
+            let most = if layer == 0 { params.m * 2 } else { params.m };
+            let neighbours: Vec<u32> = found.iter().take(params.m).map(|s| s.1).collect();
+            for neighbour in &neighbours {
+                let links = &mut self.links[*neighbour as usize][layer];
+                links.push(node);
+                if links.len() > most {
+                    self.prune(*neighbour, layer, most);
+                }
+            }
+            self.links[node as usize][layer] = neighbours;
+            entries = found.into_iter().map(|s| s.1).collect();
+        }
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+
+    /// The `k` documents `accept` lets through with the vectors most like `vector`, best first, from the `ef` most
+    /// alike vectors the search comes across
+    pub fn search<F: Fn(DocId) -> bool>(&self, vector: &[f32], k: usize, ef: usize, accept: F) -> Vec<(DocId, Score)> {
+        let mut entry = match self.entry {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        for layer in (1..self.links[entry as usize].len()).rev() {
+            entry = self.search_layer(vector, &[entry], 1, layer, |_| true)[0].1;
+        }
+        self.search_layer(vector, &[entry], ef.max(k), 0, |node| accept(self.docs[node as usize]))
+            .into_iter()
+            .take(k)
+            .map(|Scored(score, node)| (self.docs[node as usize], score))
+            .collect()
+    }
+
+    fn vector(&self, node: u32) -> &[f32] {
+        let start = node as usize * self.dims;
+        &self.vectors[start..start + self.dims]
+    }
+
+    /// The `ef` nodes of `layer` that `accept` lets through with the vectors most like `vector`, best first, walking
+    /// from `entries` to the neighbours of the best found so far until none of them are any better
+    fn search_layer<F: Fn(u32) -> bool>(&self, vector: &[f32], entries: &[u32], ef: usize, layer: usize, accept: F) -> Vec<Scored> {
+        let mut visited: HashSet<u32> = entries.iter().cloned().collect();
+        let mut candidates = BinaryHeap::new();
+        let mut found: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for entry in entries {
+            let scored = Scored(self.similarity.score(vector, self.vector(*entry)), *entry);
+            candidates.push(scored);
+            if accept(*entry) {
+                found.push(Reverse(scored));
+            }
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Scored(score, node)) = candidates.pop() {
+            let worst = |found: &BinaryHeap<Reverse<Scored>>| found.peek().map(|w| (w.0).0);
+            if found.len() >= ef && worst(&found).map_or(false, |worst| score < worst) {
+                break;
+            }
+            for neighbour in &self.links[node as usize][layer] {
+                if !visited.insert(*neighbour) {
+                    continue;
+                }
+                let scored = Scored(self.similarity.score(vector, self.vector(*neighbour)), *neighbour);
+                if found.len() < ef || worst(&found).map_or(true, |worst| scored.0 > worst) {
+                    candidates.push(scored);
+                    if accept(*neighbour) {
+                        found.push(Reverse(scored));
+                        if found.len() > ef {
+                            found.pop();
+                        }
+                    }
+                }
+            }
+        }
+        found.into_sorted_vec().into_iter().map(|Reverse(scored)| scored).collect()
+    }
+
+    /// Keep only the `most` neighbours of `node` in `layer` most like it
+    fn prune(&mut self, node: u32, layer: usize, most: usize) {
+        let vector = self.vector(node);
+        let mut scored: Vec<Scored> = self.links[node as usize][layer]
+            .iter()
+            .map(|n| Scored(self.similarity.score(vector, self.vector(*n)), *n))
+            .collect();
+        scored.sort_by(|a, b| b.cmp(a));
+        self.links[node as usize][layer] = scored.into_iter().take(most).map(|s| s.1).collect();
+    }
+}
+
+/// The top layer of a node, chosen at random with each layer holding about `m` times fewer nodes than the one below.
+/// The node numbers seed the choice, so a segment's graph comes out the same every time it's built.
+fn level(node: u32, m: usize) -> usize {
+    let mut bits = u64::from(node).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    bits = (bits ^ (bits >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    bits = (bits ^ (bits >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    bits ^= bits >> 31;
+    let uniform = ((bits >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    ((-uniform.ln() / (m.max(2) as f64).ln()) as usize).min(MAX_LEVEL)
+}
+
+/// A dense vector field built with a graph, by the fast field its vectors are in
+#[derive(Debug, Clone, Copy)]
+struct GraphField {
+    dims: usize,
+    similarity: Similarity,
+    params: HnswParams,
+}
+
+/// The graphs of the vectors of each segment, for the dense vector fields of an index that are built with one.
+/// Segments never change once written, so each graph is built once, after its segment is committed or the first time
+/// a merged segment is searched, and kept until the segment is merged away.
+#[derive(Default)]
+pub struct VectorGraphs {
+    fields: HashMap<Field, GraphField>,
+    graphs: Mutex<HashMap<(SegmentId, Field), Arc<Hnsw>>>,
+}
+
+impl VectorGraphs {
+    /// The graphs of the dense vector fields `mappings` has with `hnsw` parameters
+    pub fn new(schema: &Schema, mappings: &Mappings) -> Self {
+        let fields = mappings
+            .iter()
+            .filter_map(|(name, mapping)| match mapping.kind {
+                Some(MappedType::DenseVector {
+                    dims,
+                    similarity,
+                    hnsw: Some(params),
+                    ..
+                }) => {
+                    let field = vector_field(schema, name).ok()?;
+                    Some((field, GraphField { dims, similarity, params }))
+                }
+                _ => None,
+            })
+            .collect();
+        VectorGraphs {
+            fields,
+            graphs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How many segment and field pairs have a graph
+    pub fn len(&self) -> usize {
+        self.graphs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether any field is built with a graph at all
+    pub fn has_fields(&self) -> bool {
+        !self.fields.is_empty()
+    }
+
+    /// Drop the graphs of segments that are no longer part of the index
+    pub fn retain_segments(&self, segments: &[SegmentId]) {
+        self.graphs.lock().unwrap().retain(|(segment, _), _| segments.contains(segment));
+    }
+
+    /// Build the graphs the segments of `searcher` don't have yet
+    pub fn build(&self, searcher: &Searcher) -> tantivy::Result<()> {
+        for reader in searcher.segment_readers() {
+            for field in self.fields.keys() {
+                self.graph(reader, *field)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The graph of `field` in the segment of `reader`, built now if it wasn't yet, if the field is built with one
+    pub fn graph(&self, reader: &SegmentReader, field: Field) -> tantivy::Result<Option<Arc<Hnsw>>> {
+        let graph_field = match self.fields.get(&field) {
+            Some(graph_field) => *graph_field,
+            None => return Ok(None),
+        };
+        let key = (reader.segment_id(), field);
+        if let Some(graph) = self.graphs.lock().unwrap().get(&key) {
+            return Ok(Some(Arc::clone(graph)));
+        }
+        // Building takes a while for a large segment, so it happens without holding the lock
+        let graph = Arc::new(Hnsw::build(
+            reader,
+            field,
+            graph_field.similarity,
+            graph_field.dims,
+            graph_field.params,
+        )?);
+        self.graphs.lock().unwrap().insert(key, Arc::clone(&graph));
+        Ok(Some(graph))
+    }
+}
+
+impl fmt::Debug for VectorGraphs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VectorGraphs {{ fields: {}, len: {} }}", self.fields.len(), self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hnsw() {
+        // Points on a 30 by 30 grid, whose nearest neighbours are easy to tell
+        let params = HnswParams { m: 8, ef_construction: 50 };
+        let mut graph = Hnsw::new(Similarity::L2Norm, 2);
+        for doc in 0..900u32 {
+            graph.insert(doc, &[(doc % 30) as f32, (doc / 30) as f32], params);
+        }
+        assert_eq!(graph.len(), 900);
+
+        let nearest = graph.search(&[10.2, 20.1], 3, 50, |_| true);
+        let docs: Vec<DocId> = nearest.iter().map(|(doc, _)| *doc).collect();
+        assert_eq!(docs, vec![610, 611, 640]);
+
+        let even = graph.search(&[10.2, 20.1], 2, 50, |doc| doc % 2 == 0);
+        let docs: Vec<DocId> = even.iter().map(|(doc, _)| *doc).collect();
+        assert_eq!(docs, vec![610, 640]);
+
+        for corner in &[[0.0, 0.0], [29.0, 29.0], [0.0, 29.0]] {
+            let (doc, _) = graph.search(corner, 1, 20, |_| true)[0];
+            assert_eq!(((doc % 30) as f32, (doc / 30) as f32), (corner[0], corner[1]));
+        }
+        assert!(Hnsw::new(Similarity::Cosine, 2).search(&[1.0, 0.0], 1, 10, |_| true).is_empty());
+    }
+}
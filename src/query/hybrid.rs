@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tantivy::collector::TopDocs;
+use tantivy::query::{Occur, Query, Weight};
+use tantivy::schema::Schema;
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocAddress, DocId, Score, Searcher};
+
+use crate::query::knn::NearestWeight;
+use crate::query::{parse_queries, FilterCache, KnnQuery, TermQueries, VectorGraphs};
+use crate::Result;
+
+/// How the ranked lists of a hybrid query's lexical and vector searches are made into one
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Fusion {
+    /// Reciprocal rank fusion, scoring each document by the sum of `1 / (rank_constant + rank)` over the lists it's
+    /// ranked in, from 1 for the best. Only ranks count, so it needs no tuning to the scores of either search.
+    Rrf {
+        #[serde(default = "Fusion::default_rank_constant")]
+        rank_constant: f32,
+    },
+    /// A weighted sum of the scores of each search, with the lexical scores divided by the best of them so they're
+    /// between 0 and 1 like the vector scores already are
+    Linear {
+        #[serde(default = "Fusion::default_weight")]
+        lexical: f32,
+        #[serde(default = "Fusion::default_weight")]
+        vector: f32,
+    },
+}
+
+impl Default for Fusion {
+    fn default() -> Self {
+        Fusion::Rrf {
+            rank_constant: Fusion::default_rank_constant(),
+        }
+    }
+}
+
+impl Fusion {
+    pub fn default_rank_constant() -> f32 {
+        60.0
+    }
+
+    pub fn default_weight() -> f32 {
+        0.5
+    }
+
+    /// The fused score of each document of the `lexical` and `vector` lists, each best first
+    fn fuse(&self, lexical: &[(Score, DocAddress)], vector: &[(Score, DocAddress)]) -> BTreeMap<(u32, DocId), Score> {
+        let mut fused = BTreeMap::new();
+        let mut add = |doc: DocAddress, score: Score| {
+            *fused.entry((doc.segment_ord(), doc.doc())).or_insert(0.0) += score;
+        };
+        match *self {
+            Fusion::Rrf { rank_constant } => {
+                for list in &[lexical, vector] {
+                    for (rank, (_, doc)) in list.iter().enumerate() {
+                        add(*doc, 1.0 / (rank_constant + rank as f32 + 1.0));
+                    }
+                }
+            }
+            Fusion::Linear {
+                lexical: lexical_weight,
+                vector: vector_weight,
+            } => {
+                let best = lexical.first().map_or(0.0, |(score, _)| *score);
+                for (score, doc) in lexical {
+                    let scaled = if best > 0.0 { score / best } else { 0.0 };
+                    add(*doc, lexical_weight * scaled);
+                }
+                for (score, doc) in vector {
+                    add(*doc, vector_weight * score);
+                }
+            }
+        }
+        fused
+    }
+}
+
+/// Finds documents by both the words of `query` and the vectors of `knn`, ranking them in one list by fusing the
+/// ranked lists of the two searches. Each contributes its best `window` documents, so a document found by either can
+/// come out on top.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HybridQuery {
+    query: Box<TermQueries>,
+    knn: KnnQuery,
+    #[serde(default)]
+    fusion: Fusion,
+    #[serde(default = "HybridQuery::default_window")]
+    window: usize,
+}
+
+impl HybridQuery {
+    pub fn default_window() -> usize {
+        100
+    }
+
+    /// Build the query like its `knn` query would be, with its filter kept in `cache` and the graphs of the field's
+    /// vectors taken from `graphs` if given
+    pub fn build(
+        self,
+        schema: &Schema,
+        tokenizers: &TokenizerManager,
+        cache: Option<&Arc<FilterCache>>,
+        graphs: Option<&Arc<VectorGraphs>>,
+    ) -> Result<Box<Query>> {
+        let (_, lexical) = parse_queries(schema, tokenizers, Occur::Must, &[*self.query])?.remove(0);
+        let vector = self.knn.build(schema, tokenizers, cache, graphs)?;
+        Ok(Box::new(FusedQuery {
+            lexical,
+            vector,
+            fusion: self.fusion,
+            window: self.window,
+        }))
+    }
+}
+
+/// Matches the documents either of its queries ranks among its best `window`, scored by fusing their ranked lists.
+/// Both are searched across every segment once for the whole search when the weight is made.
+#[derive(Debug)]
+struct FusedQuery {
+    lexical: Box<Query>,
+    vector: Box<Query>,
+    fusion: Fusion,
+    window: usize,
+}
+
+impl Clone for FusedQuery {
+    fn clone(&self) -> Self {
+        FusedQuery {
+            lexical: self.lexical.box_clone(),
+            vector: self.vector.box_clone(),
+            fusion: self.fusion.clone(),
+            window: self.window,
+        }
+    }
+}
+
+impl Query for FusedQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let collector = TopDocs::with_limit(self.window.max(1));
+        let lexical = searcher.search(&*self.lexical, &collector)?;
+        let vector = searcher.search(&*self.vector, &collector)?;
+        let found = self
+            .fusion
+            .fuse(&lexical, &vector)
+            .into_iter()
+            .map(|((ord, doc), score)| (score, ord as usize, doc))
+            .collect();
+        Ok(Box::new(NearestWeight::new(searcher, found)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_hybrid_query() {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let embedding = builder.add_bytes_field("embedding");
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (words, vector) in &[
+            ("rust search engine", [1.0, 0.0]),
+            ("search engine in java", [0.0, 1.0]),
+            ("full text retrieval in rust", [0.9, 0.1]),
+            ("gardening tips", [-1.0, 0.0]),
+        ] {
+            writer.add_document(doc!(text => *words, embedding => mapping::vector_bytes(vector)));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let ranked = |fusion: &str| -> Vec<String> {
+            let query: HybridQuery = serde_json::from_str(&format!(
+                r#"{{ "query": {{ "raw": "text:search" }}, "knn": {{ "field": "embedding", "vector": [1.0, 0.0], "k": 2 }},
+                     "fusion": {} }}"#,
+                fusion
+            ))
+            .unwrap();
+            let query = query.build(&schema, index.tokenizers(), None, None).unwrap();
+            searcher
+                .search(&*query, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(_, doc)| searcher.doc(doc).unwrap().get_first(text).unwrap().text().unwrap().to_string())
+                .collect()
+        };
+        // Found by both searches, then by only the one or the other with the same rank in it
+        let fused = ranked(r#"{ "rrf": {} }"#);
+        assert_eq!(fused[0], "rust search engine");
+        let mut rest = fused[1..].to_vec();
+        rest.sort();
+        assert_eq!(rest, vec!["full text retrieval in rust", "search engine in java"]);
+        assert_eq!(
+            ranked(r#"{ "linear": { "lexical": 0.1, "vector": 0.9 } }"#),
+            vec!["rust search engine", "full text retrieval in rust", "search engine in java"]
+        );
+        assert_eq!(
+            ranked(r#"{ "linear": { "lexical": 1.0, "vector": 0.0 } }"#)[..2].to_vec(),
+            vec!["rust search engine", "search engine in java"]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::BTreeSet;
+use std::slice;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery, Weight};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Value};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocAddress, Searcher, Term};
+
+use crate::mapping::{JOIN_ID, JOIN_PARENT, JOIN_RELATION};
+use crate::query::{parse_queries, TermQueries};
+use crate::{Error, Result};
+
+/// Finds the documents with a child of the relation `type` that `query` matches
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HasChildQuery {
+    #[serde(rename = "type")]
+    relation: String,
+    query: Box<TermQueries>,
+}
+
+/// Finds the documents whose parent of the relation `parent_type` `query` matches
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HasParentQuery {
+    parent_type: String,
+    query: Box<TermQueries>,
+}
+
+impl HasChildQuery {
+    pub fn build(self, schema: &Schema, tokenizers: &TokenizerManager) -> Result<Box<Query>> {
+        let children = related(schema, tokenizers, &self.query, JOIN_RELATION, &self.relation)?;
+        Ok(Box::new(RelatedQuery::new(
+            children,
+            join_field(schema, JOIN_PARENT)?,
+            join_field(schema, JOIN_ID)?,
+        )))
+    }
+}
+
+impl HasParentQuery {
+    pub fn build(self, schema: &Schema, tokenizers: &TokenizerManager) -> Result<Box<Query>> {
+        let parents = related(schema, tokenizers, &self.query, JOIN_RELATION, &self.parent_type)?;
+        Ok(Box::new(RelatedQuery::new(
+            parents,
+            join_field(schema, JOIN_ID)?,
+            join_field(schema, JOIN_PARENT)?,
+        )))
+    }
+}
+
+fn join_field(schema: &Schema, name: &str) -> Result<Field> {
+    schema
+        .get_field(name)
+        .ok_or_else(|| Error::QueryError("The index has no join field".into()))
+}
+
+/// The documents `query` matches among those whose hidden field `field` holds `value`
+pub fn related(schema: &Schema, tokenizers: &TokenizerManager, query: &TermQueries, field: &str, value: &str) -> Result<Box<Query>> {
+    let term = match schema.get_field(field) {
+        Some(field) => Term::from_field_text(field, value),
+        None => return Err(Error::QueryError(format!("The index has no {} to relate documents by", field))),
+    };
+    let mut clauses = parse_queries(schema, tokenizers, Occur::Must, slice::from_ref(query))?;
+    clauses.push((Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<Query>));
+    Ok(Box::new(BooleanQuery::from(clauses)))
+}
+
+/// Matches the documents whose `to` field holds one of the values stored in `from` by the documents `matching` finds.
+/// Those are looked up whenever the query is run, so it's only as fast as loading every document they're found in.
+#[derive(Debug)]
+pub struct RelatedQuery {
+    matching: Box<Query>,
+    from: Field,
+    to: Field,
+}
+
+impl RelatedQuery {
+    pub fn new(matching: Box<Query>, from: Field, to: Field) -> Self {
+        RelatedQuery { matching, from, to }
+    }
+}
+
+impl Clone for RelatedQuery {
+    fn clone(&self) -> Self {
+        RelatedQuery {
+            matching: self.matching.box_clone(),
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+impl Query for RelatedQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let matching = self.matching.weight(searcher, false)?;
+        let mut keys = BTreeSet::new();
+        for (ord, reader) in searcher.segment_readers().iter().enumerate() {
+            let mut scorer = matching.scorer(reader)?;
+            while scorer.advance() {
+                let doc = scorer.doc();
+                if reader.delete_bitset().map(|d| d.is_deleted(doc)).unwrap_or(false) {
+                    continue;
+                }
+                if let Some(Value::Str(key)) = searcher.doc(DocAddress(ord as u32, doc))?.get_first(self.from) {
+                    keys.insert(key.clone());
+                }
+            }
+        }
+        let related: Vec<(Occur, Box<Query>)> = keys
+            .iter()
+            .map(|key| {
+                let term = Term::from_field_text(self.to, key);
+                (
+                    Occur::Should,
+                    Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<Query>,
+                )
+            })
+            .collect();
+        BooleanQuery::from(related).weight(searcher, scoring_enabled)
+    }
+}
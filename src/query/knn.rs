@@ -0,0 +1,376 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{Occur, Query, Scorer, Weight};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentId, SegmentReader};
+
+use crate::mapping;
+use crate::query::{parse_queries, FilterCache, FilterQuery, TermQueries, VectorGraphs};
+use crate::{Error, Result};
+
+/// How alike two vectors are, scored so that the most alike score highest
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Similarity {
+    /// The cosine of the angle between them, scored from 0 for opposite vectors to 1 for ones pointing the same way
+    Cosine,
+    /// Their dot product, which is their cosine for vectors of length 1, scored the same way
+    DotProduct,
+    /// The distance between them, scored from 1 for the same vector towards 0 the farther apart they are
+    L2Norm,
+}
+
+impl Default for Similarity {
+    fn default() -> Self {
+        Similarity::Cosine
+    }
+}
+
+impl Similarity {
+    pub fn score(self, a: &[f32], b: &[f32]) -> Score {
+        let dot = || a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+        match self {
+            Similarity::Cosine => {
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    (1.0 + dot() / norms) / 2.0
+                }
+            }
+            Similarity::DotProduct => (1.0 + dot()) / 2.0,
+            Similarity::L2Norm => 1.0 / (1.0 + a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum::<f32>()),
+        }
+    }
+}
+
+fn norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|v| v * v).sum::<f32>().sqrt()
+}
+
+/// Finds the `k` documents whose vectors in the dense vector field `field` are most like `vector`, out of those
+/// `filter` matches if given, scored by their similarity. Fields built with a graph find them by searching it, which
+/// may miss a few of the true nearest in return for not comparing every vector.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KnnQuery {
+    pub field: String,
+    pub vector: Vec<f32>,
+    #[serde(default = "KnnQuery::default_k")]
+    pub k: usize,
+    /// The field's own similarity unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<Similarity>,
+    /// How many candidates a search of the field's graph keeps, the more the likelier it finds the true nearest, 100 or
+    /// `k` whichever is more unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ef: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<Box<TermQueries>>,
+}
+
+impl KnnQuery {
+    pub fn default_k() -> usize {
+        10
+    }
+
+    pub fn default_ef() -> usize {
+        100
+    }
+
+    /// Build the query, with the documents its filter matched in earlier queries kept in `cache` and the graphs of
+    /// the field's vectors taken from `graphs` if given
+    pub fn build(
+        self,
+        schema: &Schema,
+        tokenizers: &TokenizerManager,
+        cache: Option<&Arc<FilterCache>>,
+        graphs: Option<&Arc<VectorGraphs>>,
+    ) -> Result<Box<Query>> {
+        let field = vector_field(schema, &self.field)?;
+        if self.vector.is_empty() {
+            return Err(Error::QueryError(format!("The knn query of {} has no vector", self.field)));
+        }
+        let filter = match self.filter {
+            Some(filter) => {
+                let key = serde_json::to_string(&filter)?;
+                let (_, query) = parse_queries(schema, tokenizers, Occur::Must, &[*filter])?.remove(0);
+                Some(Box::new(FilterQuery::new(query, key, cache.cloned())) as Box<Query>)
+            }
+            None => None,
+        };
+        Ok(Box::new(VectorQuery {
+            field,
+            vector: self.vector,
+            k: self.k,
+            ef: self.ef.unwrap_or_else(KnnQuery::default_ef).max(self.k),
+            similarity: self.similarity.unwrap_or_default(),
+            filter,
+            graphs: graphs.cloned(),
+        }))
+    }
+}
+
+/// The bytes fast field a dense vector field named `name` keeps its vectors in
+pub fn vector_field(schema: &Schema, name: &str) -> Result<Field> {
+    let fast_field = format!("{}.fast", name);
+    let field = schema
+        .get_field(&fast_field)
+        .or_else(|| schema.get_field(name))
+        .ok_or_else(|| Error::QueryError(format!("Field {} does not exist", name)))?;
+    match schema.get_field_entry(field).field_type() {
+        FieldType::Bytes => Ok(field),
+        _ => Err(Error::QueryError(format!("Field {} is not a dense vector field", name))),
+    }
+}
+
+/// Matches the `k` documents with the vectors most like `vector` across every segment. Those are found once for the
+/// whole search when the weight is made, by searching the graph of each segment whose field has one of the same
+/// similarity, and otherwise by comparing `vector` with each document's.
+#[derive(Debug)]
+struct VectorQuery {
+    field: Field,
+    vector: Vec<f32>,
+    k: usize,
+    ef: usize,
+    similarity: Similarity,
+    filter: Option<Box<Query>>,
+    graphs: Option<Arc<VectorGraphs>>,
+}
+
+impl Clone for VectorQuery {
+    fn clone(&self) -> Self {
+        VectorQuery {
+            field: self.field,
+            vector: self.vector.clone(),
+            k: self.k,
+            ef: self.ef,
+            similarity: self.similarity,
+            filter: self.filter.as_ref().map(|f| f.box_clone()),
+            graphs: self.graphs.clone(),
+        }
+    }
+}
+
+impl Query for VectorQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let filter = match self.filter {
+            Some(ref filter) => Some(filter.weight(searcher, false)?),
+            None => None,
+        };
+        let mut nearest: Vec<(Score, usize, DocId)> = Vec::new();
+        for (ord, reader) in searcher.segment_readers().iter().enumerate() {
+            // The documents the filter matches, in order
+            let matching = match filter {
+                Some(ref filter) => {
+                    let mut scorer = filter.scorer(reader)?;
+                    let mut docs = Vec::new();
+                    while scorer.advance() {
+                        docs.push(scorer.doc());
+                    }
+                    Some(docs)
+                }
+                None => None,
+            };
+            let graph = match self.graphs {
+                Some(ref graphs) => graphs.graph(reader, self.field)?,
+                None => None,
+            };
+            match graph {
+                // Comparing every vector the filter lets through is quicker than walking the graph past all the rest
+                Some(ref graph)
+                    if graph.similarity() == self.similarity
+                        && graph.dims() == self.vector.len()
+                        && matching.as_ref().map_or(true, |docs| docs.len() > self.ef) =>
+                {
+                    let accept =
+                        |doc: DocId| !reader.is_deleted(doc) && matching.as_ref().map_or(true, |docs| docs.binary_search(&doc).is_ok());
+                    let found = graph.search(&self.vector, self.k, self.ef, accept);
+                    nearest.extend(found.into_iter().map(|(doc, score)| (score, ord, doc)));
+                }
+                _ => self.compare(reader, ord, matching, &mut nearest)?,
+            }
+        }
+        keep_nearest(&mut nearest, self.k);
+        Ok(Box::new(NearestWeight::new(searcher, nearest)))
+    }
+}
+
+impl VectorQuery {
+    /// Compare `vector` with that of each document of a segment, or of those in `matching` if given
+    fn compare(
+        &self,
+        reader: &SegmentReader,
+        ord: usize,
+        matching: Option<Vec<DocId>>,
+        nearest: &mut Vec<(Score, usize, DocId)>,
+    ) -> tantivy::Result<()> {
+        let vectors = reader.bytes_fast_field_reader(self.field)?;
+        let docs: Box<Iterator<Item = DocId>> = match matching {
+            Some(docs) => Box::new(docs.into_iter()),
+            None => Box::new(0..reader.max_doc()),
+        };
+        for doc in docs {
+            let bytes = vectors.get_val(doc);
+            // Documents without a vector, or with one of another length than the field's, have nothing to compare
+            if bytes.len() != self.vector.len() * 4 || reader.is_deleted(doc) {
+                continue;
+            }
+            let score = self.similarity.score(&self.vector, &mapping::vector_from_bytes(bytes));
+            nearest.push((score, ord, doc));
+            // Keeping only the nearest so far bounds the memory to a few times k whatever the size of the index
+            if nearest.len() >= self.k.max(16) * 4 {
+                keep_nearest(nearest, self.k);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Keep the `k` highest scoring of `nearest`, highest first
+fn keep_nearest(nearest: &mut Vec<(Score, usize, DocId)>, k: usize) {
+    nearest.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    nearest.truncate(k);
+}
+
+/// The documents a query found across every segment ahead of scoring them, in each segment along with their scores
+/// in the order of the documents
+pub struct NearestWeight {
+    segments: HashMap<SegmentId, Vec<(DocId, Score)>>,
+}
+
+impl NearestWeight {
+    /// The weight matching `found`, each the score, segment ordinal and document of what was found
+    pub fn new(searcher: &Searcher, found: Vec<(Score, usize, DocId)>) -> Self {
+        let mut segments: HashMap<SegmentId, Vec<(DocId, Score)>> = HashMap::new();
+        for (score, ord, doc) in found {
+            let segment = searcher.segment_reader(ord as u32).segment_id();
+            segments.entry(segment).or_insert_with(Vec::new).push((doc, score));
+        }
+        for docs in segments.values_mut() {
+            docs.sort_by_key(|(doc, _)| *doc);
+        }
+        NearestWeight { segments }
+    }
+}
+
+impl Weight for NearestWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        let docs = self.segments.get(&reader.segment_id()).cloned().unwrap_or_default();
+        Ok(Box::new(NearestScorer { docs, cursor: None }))
+    }
+}
+
+struct NearestScorer {
+    docs: Vec<(DocId, Score)>,
+    cursor: Option<usize>,
+}
+
+impl DocSet for NearestScorer {
+    fn advance(&mut self) -> bool {
+        let next = self.cursor.map(|c| c + 1).unwrap_or(0);
+        self.cursor = Some(next);
+        next < self.docs.len()
+    }
+
+    fn doc(&self) -> DocId {
+        self.docs[self.cursor.unwrap_or(0)].0
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.docs.len() as u32
+    }
+}
+
+impl Scorer for NearestScorer {
+    fn score(&mut self) -> Score {
+        self.docs[self.cursor.unwrap_or(0)].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapping::{FieldMapping, MappedType, Mappings};
+    use crate::query::HnswParams;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, STORED, STRING};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_knn_query() {
+        let mut builder = SchemaBuilder::new();
+        let name = builder.add_text_field("name", STRING | STORED);
+        let embedding = builder.add_bytes_field("embedding");
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (text, vector) in &[
+            ("east", [1.0, 0.0]),
+            ("north", [0.0, 1.0]),
+            ("west", [-1.0, 0.0]),
+            ("northeast", [0.7, 0.7]),
+        ] {
+            writer.add_document(doc!(name => *text, embedding => mapping::vector_bytes(vector)));
+        }
+        writer.add_document(doc!(name => "nowhere"));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let mut mappings = Mappings::new();
+        let kind = MappedType::DenseVector {
+            dims: 2,
+            similarity: Similarity::Cosine,
+            stored: false,
+            hnsw: Some(HnswParams::default()),
+        };
+        mappings.insert("embedding".into(), FieldMapping::from(kind));
+        let graphs = Arc::new(VectorGraphs::new(&schema, &mappings));
+
+        // Searching the graph finds the same as comparing every vector, there being so few of them
+        let nearest = |query: &str| -> Vec<(String, String)> {
+            let found: Vec<Vec<(String, String)>> = [None, Some(&graphs)]
+                .iter()
+                .map(|graphs| {
+                    let query: KnnQuery = serde_json::from_str(query).unwrap();
+                    let query = query.build(&schema, &TokenizerManager::default(), None, *graphs).unwrap();
+                    searcher
+                        .search(&*query, &TopDocs::with_limit(10))
+                        .unwrap()
+                        .into_iter()
+                        .map(|(score, doc)| {
+                            let doc = searcher.doc(doc).unwrap();
+                            (doc.get_first(name).unwrap().text().unwrap().to_string(), format!("{:.2}", score))
+                        })
+                        .collect()
+                })
+                .collect();
+            assert_eq!(found[0], found[1]);
+            found[0].clone()
+        };
+        let pair = |name: &str, score: &str| (name.to_string(), score.to_string());
+        assert_eq!(
+            nearest(r#"{ "field": "embedding", "vector": [1.0, 0.1], "k": 2 }"#),
+            vec![pair("east", "1.00"), pair("northeast", "0.89")]
+        );
+        assert_eq!(
+            nearest(r#"{ "field": "embedding", "vector": [0.0, 2.0], "k": 1, "similarity": "l2_norm" }"#),
+            vec![pair("north", "0.50")]
+        );
+        assert_eq!(
+            nearest(r#"{ "field": "embedding", "vector": [1.0, 0.0], "k": 2, "filter": { "term": { "name": "west" } } }"#),
+            vec![pair("west", "0.00")]
+        );
+
+        assert_eq!(graphs.len(), 1);
+        graphs.retain_segments(&[]);
+        assert!(graphs.is_empty());
+
+        let query: KnnQuery = serde_json::from_str(r#"{ "field": "name", "vector": [1.0] }"#).unwrap();
+        assert!(query.build(&schema, &TokenizerManager::default(), None, None).is_err());
+    }
+}
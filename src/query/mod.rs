@@ -1,28 +1,70 @@
+use crate::analysis;
 use crate::settings::Settings;
+use crate::tasks::Cancellation;
 use crate::{Error, Result};
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use serde::{Deserialize, Serialize};
-use tantivy::query::Query as TantivyQuery;
+use tantivy::query::{AllQuery, Query as TantivyQuery};
 use tantivy::schema::Schema;
-use tantivy::Term;
+use tantivy::{Index, Term};
 use tower_web::Extract;
 
 pub use {
-    self::aggregate::{SumCollector, SummaryDoc},
-    self::bool::BoolQuery,
+    self::aggregate::{
+        merge_buckets, merge_composite, merge_filters, merge_geohash_grid, merge_ranges, merge_significant_terms, run_pipelines, Composite,
+        CompositeBucket, CompositeBuckets, CompositeSource, DateBucket, DateHistogram, FilterBucket, FiltersAggregation, GeoBucket,
+        GeohashGrid, Pipeline, RangeAggregation, RangeBucket, SignificantBucket, SignificantBuckets, SignificantTerms, SubAggregation,
+        SumCollector, SummaryDoc, TopHits, TopHitsResult,
+    },
+    self::bool::{parse_queries, BoolQuery},
+    self::cancel::cancellable,
+    self::fast::{doc_value_fields, doc_values, FastValues},
+    self::features::RankFeatureQuery,
+    self::filter::{FilterCache, FilterQuery},
+    self::function_score::FunctionScoreQuery,
     self::fuzzy::{FuzzyQuery, FuzzyTerm},
+    self::hnsw::{HnswParams, VectorGraphs},
+    self::hybrid::{Fusion, HybridQuery},
+    self::join::{HasChildQuery, HasParentQuery},
+    self::knn::{KnnQuery, Similarity},
+    self::nested::{without_nested, NestedQuery},
     self::phrase::PhraseQuery,
     self::range::{RangeQuery, Ranges},
+    self::raw::{raw_query, search_field, with_default_fields, BoostQuery},
     self::regex::RegexQuery,
+    self::rescore::{query_scores, RankerRescore, Rescore, ScoreMode, VectorRescore},
+    self::runtime::{apply_runtime_fields, doc_value_scripts, runtime_script, script_doc_values, RuntimeField, RuntimeFields},
+    self::script::{Script, ScriptQuery},
+    self::similarity::{Similarities, TextSimilarity},
+    self::sort::{sort_field, sorted_search, Missing, MissingPosition, Sort, SortOrder, SortedSegments},
     self::term::ExactTerm,
 };
 
 mod aggregate;
 mod bool;
+mod cancel;
+mod fast;
+mod features;
+mod filter;
+mod function_score;
 mod fuzzy;
+mod hnsw;
+mod hybrid;
+mod join;
+mod knn;
+mod nested;
 mod phrase;
 mod range;
+mod raw;
 mod regex;
+mod rescore;
+mod runtime;
+mod script;
+mod similarity;
+mod sort;
 mod term;
 
 pub trait CreateQuery {
@@ -32,57 +74,186 @@ pub trait CreateQuery {
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Query {
-    Boolean { bool: BoolQuery },
+    Boolean {
+        bool: BoolQuery,
+    },
+    Nested {
+        nested: NestedQuery,
+    },
+    HasChild {
+        has_child: HasChildQuery,
+    },
+    HasParent {
+        has_parent: HasParentQuery,
+    },
+    Knn {
+        knn: KnnQuery,
+    },
+    Hybrid {
+        hybrid: HybridQuery,
+    },
+    RankFeature {
+        rank_feature: RankFeatureQuery,
+    },
+    FunctionScore {
+        function_score: FunctionScoreQuery,
+    },
+    Script {
+        script: ScriptQuery,
+    },
     Fuzzy(FuzzyQuery),
     Exact(ExactTerm),
     Phrase(PhraseQuery),
     Regex(RegexQuery),
     Range(RangeQuery),
-    Raw { raw: String },
+    /// A query in the query parser's syntax, searching `fields` given as `name` or `name^boost` for terms without a
+    /// field, or the index's default fields when there are none
+    Raw {
+        raw: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<String>,
+    },
     All,
 }
 
+impl Query {
+    /// The tantivy query this stands for over `index`, with the filters of `bool` queries kept in `cache` and the
+    /// graphs of dense vector fields taken from `graphs` if given
+    pub fn create(self, index: &Index, cache: Option<&Arc<FilterCache>>, graphs: Option<&Arc<VectorGraphs>>) -> Result<Box<TantivyQuery>> {
+        let schema = index.schema();
+        match self {
+            Query::Regex(regex) => regex.create_query(&schema),
+            Query::Phrase(phrase) => phrase.create_query(&schema),
+            Query::Fuzzy(fuzzy) => fuzzy.create_query(&schema),
+            Query::Exact(term) => term.create_query(&schema),
+            Query::Boolean { bool } => bool.create_cached_query(index, cache),
+            Query::Nested { nested } => nested.build(&schema, index.tokenizers()),
+            Query::HasChild { has_child } => has_child.build(&schema, index.tokenizers()),
+            Query::HasParent { has_parent } => has_parent.build(&schema, index.tokenizers()),
+            Query::Knn { knn } => knn.build(&schema, index.tokenizers(), cache, graphs),
+            Query::Hybrid { hybrid } => hybrid.build(&schema, index.tokenizers(), cache, graphs),
+            Query::RankFeature { rank_feature } => rank_feature.build(&schema),
+            Query::FunctionScore { function_score } => function_score.build(&schema, index.tokenizers()),
+            Query::Script { script } => script.build(&schema),
+            Query::Range(range) => range.create_query(&schema),
+            Query::Raw { raw, fields } => raw_query(&schema, index.tokenizers(), &raw, &fields),
+            Query::All => Ok(Box::new(AllQuery)),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum Metrics {
     SumAgg { field: String },
+    DateHistogram { date_histogram: DateHistogram },
+    SignificantTerms { significant_terms: SignificantTerms },
+    Range { range: RangeAggregation },
+    Filters { filters: FiltersAggregation },
+    GeohashGrid { geohash_grid: GeohashGrid },
+    Composite { composite: Composite },
 }
 
 #[derive(Serialize, Extract, Deserialize, Debug)]
 pub struct Request {
     pub aggs: Option<Metrics>,
     pub query: Option<Query>,
+    /// Order results by a fast field instead of by score
+    #[serde(default)]
+    pub sort: Option<Sort>,
+    /// Fast fields whose values are returned with each hit, every one of them for fields holding several
+    #[serde(default)]
+    pub docvalue_fields: Vec<String>,
+    /// Score the best of the results again before they're returned
+    #[serde(default)]
+    pub rescore: Option<Rescore>,
+    /// Fields worked out from others by a script, which the rest of the search can use like those of the index
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub runtime_fields: RuntimeFields,
     #[serde(default = "Settings::default_result_limit")]
     pub limit: usize,
+    /// Set when the search is cancelled, to stop it early
+    #[serde(skip)]
+    pub cancellation: Cancellation,
 }
 
 impl Request {
     pub fn new(query: Option<Query>, aggs: Option<Metrics>, limit: usize) -> Self {
-        Request { query, aggs, limit }
+        Request {
+            query,
+            aggs,
+            sort: None,
+            docvalue_fields: Vec::new(),
+            rescore: None,
+            runtime_fields: RuntimeFields::new(),
+            limit,
+            cancellation: Cancellation::default(),
+        }
+    }
+
+    /// Take out the pipelines to run over the buckets of the search's date histogram, which are run once the buckets
+    /// of every shard and node are added together rather than by each of them
+    pub fn take_pipelines(&mut self) -> BTreeMap<String, Pipeline> {
+        match self.aggs {
+            Some(Metrics::DateHistogram { ref mut date_histogram }) => std::mem::replace(&mut date_histogram.pipelines, BTreeMap::new()),
+            _ => BTreeMap::new(),
+        }
     }
 
     pub fn all_docs() -> Self {
         Self {
             aggs: None,
             query: Some(Query::All),
+            sort: None,
+            docvalue_fields: Vec::new(),
+            rescore: None,
+            runtime_fields: RuntimeFields::new(),
             limit: Settings::default_result_limit(),
+            cancellation: Cancellation::default(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// The queries a `bool` query is made of, which can be further `bool` queries
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(untagged)]
 pub enum TermQueries {
+    Boolean {
+        bool: Box<BoolQuery>,
+    },
+    Nested {
+        nested: NestedQuery,
+    },
+    HasChild {
+        has_child: HasChildQuery,
+    },
+    HasParent {
+        has_parent: HasParentQuery,
+    },
+    RankFeature {
+        rank_feature: RankFeatureQuery,
+    },
+    FunctionScore {
+        function_score: Box<FunctionScoreQuery>,
+    },
+    Script {
+        script: ScriptQuery,
+    },
     Fuzzy(FuzzyQuery),
     Exact(ExactTerm),
     Phrase(PhraseQuery),
     Range(RangeQuery),
     Regex(RegexQuery),
+    Raw {
+        raw: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<String>,
+    },
 }
 
 fn make_field_value(schema: &Schema, k: &str, v: &str) -> Result<Term> {
     let field = schema
         .get_field(k)
         .ok_or_else(|| Error::QueryError(format!("Field: {} does not exist", k)))?;
-    Ok(Term::from_field_text(field, v))
+    Ok(Term::from_field_text(field, &analysis::normalize(schema, field, v)))
 }
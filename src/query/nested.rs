@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use tantivy::query::{BooleanQuery, Occur, Query, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::Term;
+
+use crate::mapping::{NESTED_ID, NESTED_PARENT, NESTED_PATH};
+use crate::query::join::{related, RelatedQuery};
+use crate::query::TermQueries;
+use crate::{Error, Result};
+
+/// Finds the documents with an object in the nested field `path` that `query` matches by itself, so that every clause
+/// of a `bool` query has to hold for the same object
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct NestedQuery {
+    path: String,
+    query: Box<TermQueries>,
+}
+
+impl NestedQuery {
+    pub fn build(self, schema: &Schema, tokenizers: &TokenizerManager) -> Result<Box<Query>> {
+        let field = |name: &str| {
+            schema
+                .get_field(name)
+                .ok_or_else(|| Error::QueryError(format!("Field {} is not a nested field", self.path)))
+        };
+        field(&self.path)?;
+        let objects = related(schema, tokenizers, &self.query, NESTED_PATH, &self.path)?;
+        Ok(Box::new(RelatedQuery::new(objects, field(NESTED_PARENT)?, field(NESTED_ID)?)))
+    }
+}
+
+/// `query` without the documents nested objects are indexed as, for an index with nested fields at `paths`
+pub fn without_nested(schema: &Schema, paths: &[String], query: Box<Query>) -> Box<Query> {
+    let field = match schema.get_field(NESTED_PATH) {
+        Some(field) if !paths.is_empty() => field,
+        _ => return query,
+    };
+    let objects: Vec<(Occur, Box<Query>)> = paths
+        .iter()
+        .map(|path| {
+            let term = Term::from_field_text(field, path);
+            (
+                Occur::Should,
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<Query>,
+            )
+        })
+        .collect();
+    Box::new(BooleanQuery::from(vec![
+        (Occur::Must, query),
+        (Occur::MustNot, Box::new(BooleanQuery::from(objects)) as Box<Query>),
+    ]))
+}
@@ -69,6 +69,14 @@ where
     Ok((upper, lower))
 }
 
+fn str_bound(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(s) => Bound::Included(s.as_str()),
+        Bound::Excluded(s) => Bound::Excluded(s.as_str()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
 pub fn create_range_query(schema: &Schema, field: &str, r: &Ranges) -> Result<Box<Query>> {
     match r {
         Ranges::ValueRange { gte, lte, lt, gt, .. } => {
@@ -85,6 +93,15 @@ pub fn create_range_query(schema: &Schema, field: &str, r: &Ranges) -> Result<Bo
                     let (upper, lower) = create_ranges::<u64>(&gte, &lte, &lt, &gt)?;
                     Ok(Box::new(TantivyRangeQuery::new_u64_bounds(field, lower, upper)))
                 }
+                // Terms compare as bytes, which suits terms written to sort, such as the addresses of ip fields
+                &FieldType::Str(_) => {
+                    let (upper, lower) = create_ranges::<String>(&gte, &lte, &lt, &gt)?;
+                    Ok(Box::new(TantivyRangeQuery::new_str_bounds(
+                        field,
+                        str_bound(&lower),
+                        str_bound(&upper),
+                    )))
+                }
                 ref ft => Err(Error::QueryError(format!("Invalid field type: {:?} for range query", ft))),
             }
         }
@@ -0,0 +1,228 @@
+use serde_json::Value;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, Scorer, Weight};
+use tantivy::schema::{Field, Schema};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader};
+
+use crate::analysis;
+use crate::query::Query as ToshiQuery;
+use crate::{Error, Result};
+
+/// A query in the query parser's syntax, whose terms without a field of their own are searched for in `fields`, each
+/// given as `name` or `name^boost`, or in every field when there are none
+pub fn raw_query(schema: &Schema, tokenizers: &TokenizerManager, raw: &str, fields: &[String]) -> Result<Box<Query>> {
+    let fields = if fields.is_empty() {
+        schema
+            .fields()
+            .iter()
+            .filter_map(|e| schema.get_field(e.name()))
+            .map(|f| (f, 1.0))
+            .collect()
+    } else {
+        fields
+            .iter()
+            .map(|spec| search_field(schema, spec))
+            .collect::<Result<Vec<(Field, f32)>>>()?
+    };
+    let all: Vec<Field> = fields.iter().map(|(field, _)| *field).collect();
+    let raw = analysis::expand_synonyms(raw, schema, tokenizers, &all);
+    let search_schema = analysis::search_schema(schema, tokenizers);
+
+    // Fields of the same boost are searched together, so without boosts this is a single parse like any other
+    let mut boosts: Vec<u32> = Vec::new();
+    for (_, boost) in &fields {
+        if !boosts.contains(&boost.to_bits()) {
+            boosts.push(boost.to_bits());
+        }
+    }
+    if boosts == [1f32.to_bits()] {
+        let query_parser = QueryParser::new(search_schema, all, tokenizers.clone());
+        return Ok(query_parser.parse_query(&raw)?);
+    }
+    let mut clauses: Vec<(Occur, Box<Query>)> = Vec::with_capacity(boosts.len());
+    for bits in boosts {
+        let boosted = fields
+            .iter()
+            .filter(|(_, boost)| boost.to_bits() == bits)
+            .map(|(field, _)| *field)
+            .collect();
+        let query_parser = QueryParser::new(search_schema.clone(), boosted, tokenizers.clone());
+        let query = query_parser.parse_query(&raw)?;
+        clauses.push((Occur::Should, Box::new(BoostQuery::new(query, f32::from_bits(bits)))));
+    }
+    Ok(Box::new(BooleanQuery::from(clauses)))
+}
+
+/// The field `spec` names and its boost, given as `name` or `name^boost`
+pub fn search_field(schema: &Schema, spec: &str) -> Result<(Field, f32)> {
+    let (name, boost) = match spec.rfind('^') {
+        Some(at) => {
+            let boost = spec[at + 1..]
+                .parse::<f32>()
+                .ok()
+                .filter(|b| *b > 0.0)
+                .ok_or_else(|| Error::QueryError(format!("The boost of {} is not a positive number", spec)))?;
+            (&spec[..at], boost)
+        }
+        None => (spec, 1.0),
+    };
+    schema
+        .get_field(name)
+        .map(|field| (field, boost))
+        .ok_or_else(|| Error::QueryError(format!("Field: {} does not exist", name)))
+}
+
+/// `query` with `fields` searched by each of its raw queries that doesn't list fields of its own
+pub fn with_default_fields(query: ToshiQuery, fields: &[String]) -> Result<ToshiQuery> {
+    if fields.is_empty() {
+        return Ok(query);
+    }
+    let mut value = serde_json::to_value(&query)?;
+    default_clauses(fields, &mut value);
+    Ok(serde_json::from_value(value)?)
+}
+
+fn default_clauses(fields: &[String], value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            if object.len() == 1 && object.get("raw").map_or(false, Value::is_string) {
+                let fields = fields.iter().map(|f| Value::String(f.clone())).collect();
+                object.insert("fields".into(), Value::Array(fields));
+                return;
+            }
+            for (key, value) in object.iter_mut() {
+                // These hold fields by name, one of which could be called raw
+                if ["term", "range", "fuzzy", "phrase", "regex"].contains(&key.as_str()) {
+                    continue;
+                }
+                default_clauses(fields, value);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                default_clauses(fields, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Matches the same documents as `inner` with their scores multiplied by `boost`
+#[derive(Debug)]
+pub struct BoostQuery {
+    inner: Box<Query>,
+    boost: f32,
+}
+
+impl BoostQuery {
+    pub fn new(inner: Box<Query>, boost: f32) -> Self {
+        BoostQuery { inner, boost }
+    }
+}
+
+impl Clone for BoostQuery {
+    fn clone(&self) -> Self {
+        BoostQuery {
+            inner: self.inner.box_clone(),
+            boost: self.boost,
+        }
+    }
+}
+
+impl Query for BoostQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        Ok(Box::new(BoostWeight {
+            inner: self.inner.weight(searcher, scoring_enabled)?,
+            boost: self.boost,
+        }))
+    }
+}
+
+struct BoostWeight {
+    inner: Box<Weight>,
+    boost: f32,
+}
+
+impl Weight for BoostWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        Ok(Box::new(BoostScorer {
+            inner: self.inner.scorer(reader)?,
+            boost: self.boost,
+        }))
+    }
+}
+
+struct BoostScorer {
+    inner: Box<Scorer>,
+    boost: f32,
+}
+
+impl DocSet for BoostScorer {
+    fn advance(&mut self) -> bool {
+        self.inner.advance()
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for BoostScorer {
+    fn score(&mut self) -> Score {
+        self.inner.score() * self.boost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_default_fields() {
+        let mut builder = SchemaBuilder::new();
+        let title = builder.add_text_field("title", TEXT | STORED);
+        let body = builder.add_text_field("body", TEXT);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        writer.add_document(doc!(title => "rust in action", body => "a book about systems"));
+        writer.add_document(doc!(title => "systems programming", body => "rust and c"));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let top = |fields: &[&str]| -> Vec<u32> {
+            let fields: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+            let query = raw_query(&schema, index.tokenizers(), "rust", &fields).unwrap();
+            searcher
+                .search(&query, &TopDocs::with_limit(2))
+                .unwrap()
+                .into_iter()
+                .map(|(_, doc)| doc.doc())
+                .collect()
+        };
+        assert_eq!(top(&["title"]), vec![0]);
+        assert_eq!(top(&["body"]), vec![1]);
+        assert_eq!(top(&["title^5", "body"]), vec![0, 1]);
+        assert_eq!(top(&["title", "body^5"]), vec![1, 0]);
+        assert!(search_field(&schema, "title^-1").is_err());
+        assert!(search_field(&schema, "subtitle").is_err());
+
+        let query: ToshiQuery = serde_json::from_str(r#"{ "bool": { "must": [{ "raw": "rust" }, { "term": { "raw": "x" } }] } }"#).unwrap();
+        let query = with_default_fields(query, &["title^2".to_string()]).unwrap();
+        let expected: ToshiQuery =
+            serde_json::from_str(r#"{ "bool": { "must": [{ "raw": "rust", "fields": ["title^2"] }, { "term": { "raw": "x" } }] } }"#)
+                .unwrap();
+        assert_eq!(query, expected);
+        let own: ToshiQuery = serde_json::from_str(r#"{ "raw": "rust", "fields": ["body"] }"#).unwrap();
+        let expected: ToshiQuery = serde_json::from_str(r#"{ "raw": "rust", "fields": ["body"] }"#).unwrap();
+        assert_eq!(with_default_fields(own, &["title".to_string()]).unwrap(), expected);
+    }
+}
@@ -0,0 +1,251 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::Query as TantivyQuery;
+use tantivy::{DocAddress, DocId, DocSet, Score, Searcher, SkipResult};
+
+use crate::mapping;
+use crate::query::knn::vector_field;
+use crate::query::{Query, Similarity};
+use crate::{Error, Result};
+
+/// A second pass over the best `window_size` documents of a search, scoring each again by `query`, by how alike its
+/// vector in a dense vector field is to `vector` or by a `ranker`, so that expensive scoring only touches the top of the
+/// results. The new score is combined with the one the search gave by `score_mode`, after multiplying them by `rescore_query_weight`
+/// and `query_weight`, and documents the second pass doesn't match keep the score the search gave them.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Rescore {
+    #[serde(default = "Rescore::default_window_size")]
+    pub window_size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<Query>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector: Option<VectorRescore>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranker: Option<RankerRescore>,
+    #[serde(default = "Rescore::default_weight")]
+    pub query_weight: f32,
+    #[serde(default = "Rescore::default_weight")]
+    pub rescore_query_weight: f32,
+    #[serde(default)]
+    pub score_mode: ScoreMode,
+}
+
+/// Scores documents by how alike their vector in the dense vector field `field` is to `vector`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct VectorRescore {
+    pub field: String,
+    pub vector: Vec<f32>,
+    /// The field's own similarity unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<Similarity>,
+}
+
+/// Scores documents by the ranker registered as `name`, given the score each of `features` gives them and the values
+/// of their fast `fields`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RankerRescore {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub features: BTreeMap<String, Query>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<String>,
+}
+
+/// How the score a search gave a document is combined with the one a rescore gave it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreMode {
+    Total,
+    Multiply,
+    Avg,
+    Max,
+    Min,
+}
+
+impl Default for ScoreMode {
+    fn default() -> Self {
+        ScoreMode::Total
+    }
+}
+
+impl ScoreMode {
+    fn combine(self, original: Score, rescored: Score) -> Score {
+        match self {
+            ScoreMode::Total => original + rescored,
+            ScoreMode::Multiply => original * rescored,
+            ScoreMode::Avg => (original + rescored) / 2.0,
+            ScoreMode::Max => original.max(rescored),
+            ScoreMode::Min => original.min(rescored),
+        }
+    }
+}
+
+impl Rescore {
+    pub fn default_window_size() -> usize {
+        10
+    }
+
+    pub fn default_weight() -> f32 {
+        1.0
+    }
+
+    /// Take out the query to score documents by again, which has to be built for the index like any other
+    pub fn take_query(&mut self) -> Result<Option<Query>> {
+        let given = [self.query.is_some(), self.vector.is_some(), self.ranker.is_some()];
+        if given.iter().filter(|given| **given).count() != 1 {
+            return Err(Error::QueryError("A rescore needs one of a query, a vector or a ranker".into()));
+        }
+        Ok(self.query.take())
+    }
+
+    /// The documents of `docs` that are rescored
+    pub fn window<'a>(&self, docs: &'a [(Score, DocAddress)]) -> &'a [(Score, DocAddress)] {
+        &docs[..self.window_size.min(docs.len())]
+    }
+
+    /// Score the first `window_size` of `docs` again, by `query` if given and otherwise by their vectors, and sort them
+    /// by their combined scores ahead of the rest. `docs` are the documents a search found, best first.
+    pub fn apply(&self, searcher: &Searcher, query: Option<&TantivyQuery>, docs: &mut Vec<(Score, DocAddress)>) -> Result<()> {
+        let rescored = match (query, &self.vector) {
+            (Some(query), _) => query_scores(searcher, query, self.window(docs))?,
+            (None, Some(vector)) => vector_scores(searcher, vector, self.window(docs))?,
+            (None, None) => return Err(Error::QueryError("A rescore needs either a query or a vector".into())),
+        };
+        self.combine(&rescored, docs);
+        Ok(())
+    }
+
+    /// Combine the scores of the first `window_size` of `docs` with those `rescored` gives them, by segment ordinal and
+    /// document, and sort them by their combined scores
+    pub fn combine(&self, rescored: &BTreeMap<(u32, DocId), Score>, docs: &mut Vec<(Score, DocAddress)>) {
+        let window = self.window_size.min(docs.len());
+        for (score, doc) in docs[..window].iter_mut() {
+            *score = match rescored.get(&(doc.segment_ord(), doc.doc())) {
+                Some(rescored) => self
+                    .score_mode
+                    .combine(self.query_weight * *score, self.rescore_query_weight * rescored),
+                None => self.query_weight * *score,
+            };
+        }
+        docs[..window].sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+    }
+}
+
+/// The score `query` gives each of `docs` it matches, by segment ordinal and document
+pub fn query_scores(searcher: &Searcher, query: &TantivyQuery, docs: &[(Score, DocAddress)]) -> Result<BTreeMap<(u32, DocId), Score>> {
+    let mut scores = BTreeMap::new();
+    let weight = query.weight(searcher, true)?;
+    for (ord, targets) in by_segment(docs) {
+        let mut scorer = weight.scorer(searcher.segment_reader(ord))?;
+        let mut current = if scorer.advance() { Some(scorer.doc()) } else { None };
+        for doc in targets {
+            if current.map_or(false, |c| c < doc) {
+                current = match scorer.skip_next(doc) {
+                    SkipResult::End => None,
+                    _ => Some(scorer.doc()),
+                };
+            }
+            if current == Some(doc) {
+                scores.insert((ord, doc), scorer.score());
+            }
+        }
+    }
+    Ok(scores)
+}
+
+/// How alike the vector of each of `docs` with one is to that of `rescore`
+fn vector_scores(searcher: &Searcher, rescore: &VectorRescore, docs: &[(Score, DocAddress)]) -> Result<BTreeMap<(u32, DocId), Score>> {
+    let mut scores = BTreeMap::new();
+    let field = vector_field(searcher.schema(), &rescore.field)?;
+    let similarity = rescore.similarity.unwrap_or_default();
+    for (ord, targets) in by_segment(docs) {
+        let vectors = searcher.segment_reader(ord).bytes_fast_field_reader(field)?;
+        for doc in targets {
+            // Documents without a vector of the same length have nothing to compare
+            let bytes = vectors.get_val(doc);
+            if !rescore.vector.is_empty() && bytes.len() == rescore.vector.len() * 4 {
+                scores.insert((ord, doc), similarity.score(&rescore.vector, &mapping::vector_from_bytes(bytes)));
+            }
+        }
+    }
+    Ok(scores)
+}
+
+/// The documents of `docs` in each segment, in order
+fn by_segment(docs: &[(Score, DocAddress)]) -> BTreeMap<u32, Vec<DocId>> {
+    let mut segments: BTreeMap<u32, Vec<DocId>> = BTreeMap::new();
+    for (_, doc) in docs {
+        segments.entry(doc.segment_ord()).or_insert_with(Vec::new).push(doc.doc());
+    }
+    for targets in segments.values_mut() {
+        targets.sort();
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::raw_query;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_rescore() {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let embedding = builder.add_bytes_field("embedding");
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (words, vector) in &[
+            ("rust", [0.0, 1.0]),
+            ("rust rust", [1.0, 0.0]),
+            ("rust rust rust", [-1.0, 0.0]),
+            ("rust programming", [0.7, 0.7]),
+        ] {
+            writer.add_document(doc!(text => *words, embedding => mapping::vector_bytes(vector)));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let search = |rescore: &str| -> Vec<String> {
+            let query = raw_query(&schema, index.tokenizers(), "text:rust", &[]).unwrap();
+            let mut docs = searcher.search(&*query, &TopDocs::with_limit(10)).unwrap();
+            let mut rescore: Rescore = serde_json::from_str(rescore).unwrap();
+            let query = rescore.take_query().unwrap();
+            let query = query.map(|q| q.create(&index, None, None).unwrap());
+            rescore.apply(&searcher, query.as_ref().map(|q| &**q), &mut docs).unwrap();
+            docs.into_iter()
+                .map(|(_, doc)| searcher.doc(doc).unwrap().get_first(text).unwrap().text().unwrap().to_string())
+                .collect()
+        };
+        let plain = search(r#"{ "window_size": 0, "vector": { "field": "embedding", "vector": [1.0, 0.0] } }"#);
+
+        // Only the best two are rescored, the vector deciding their order
+        let rescored = search(
+            r#"{ "window_size": 2, "vector": { "field": "embedding", "vector": [1.0, 0.0] },
+                 "query_weight": 0.0, "rescore_query_weight": 1.0 }"#,
+        );
+        let mut top = plain[..2].to_vec();
+        top.sort_by_key(|words| if words == "rust rust" { 0 } else { 1 });
+        assert_eq!(rescored[..2].to_vec(), top);
+        assert_eq!(rescored[2..].to_vec(), plain[2..].to_vec());
+
+        // Documents the rescore query doesn't match keep their scores, which are lower than those it adds to
+        let rescored = search(r#"{ "window_size": 4, "query": { "raw": "text:programming" }, "rescore_query_weight": 10.0 }"#);
+        assert_eq!(rescored[0], "rust programming");
+
+        let mut neither: Rescore = serde_json::from_str(r#"{ "window_size": 2 }"#).unwrap();
+        assert!(neither.take_query().is_err());
+        let mut both: Rescore = serde_json::from_str(r#"{ "query": { "raw": "text:rust" }, "ranker": { "name": "products" } }"#).unwrap();
+        assert!(both.take_query().is_err());
+        let mut ranked: Rescore = serde_json::from_str(r#"{ "ranker": { "name": "products", "fields": ["price"] } }"#).unwrap();
+        assert_eq!(ranked.take_query().unwrap(), None);
+        assert_eq!(ranked.ranker.unwrap().fields, vec!["price".to_string()]);
+    }
+}
@@ -0,0 +1,266 @@
+//! Runtime fields are named scripts that searches use as if they were fields of the index, computed when a search
+//! needs them instead of when documents are indexed. They're given with a search under `runtime_fields`, or for
+//! every search of an index under `[[runtime_fields]]` in the settings, and a search's own replace those of the same
+//! name. Range and term queries, sorts, range and date histogram aggregations and `docvalue_fields` can all name them.
+//!
+//! Runtime fields can read each other. One reading a field of its own name reads the indexed field, so a runtime
+//! field can stand in for an indexed one, such as `price` computed as `price / 100`. Each one read is worked into the
+//! script of the one reading it, so a runtime field can't read others to more than `MAX_EXPANDED_SIZE` parts of a
+//! script in all, however many times over they're read.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tantivy::schema::Schema;
+use tantivy::{DocAddress, Searcher};
+
+use crate::query::script::{Expression, Script};
+use crate::query::{Metrics, Query, Request};
+use crate::{Error, Result};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RuntimeField {
+    /// What the field's value is worked out from, see `query::script`
+    pub script: String,
+}
+
+/// How many numbers, fields, operators and functions the runtime fields read by one can be worked out to
+pub const MAX_EXPANDED_SIZE: usize = 1024;
+
+/// Runtime fields by name
+pub type RuntimeFields = BTreeMap<String, RuntimeField>;
+
+/// The script of the runtime field `name`, with those of the runtime fields it reads worked into it, if there's one
+pub fn runtime_script(fields: &RuntimeFields, name: &str) -> Result<Option<String>> {
+    if !fields.contains_key(name) {
+        return Ok(None);
+    }
+    Ok(Some(expand(fields, name, &mut Vec::new(), &mut 0)?.to_string()))
+}
+
+/// `expanded` counts the size of the scripts worked in so far, which reading the same fields over and over would
+/// otherwise grow without bound
+fn expand(fields: &RuntimeFields, name: &str, expanding: &mut Vec<String>, expanded: &mut usize) -> Result<Expression> {
+    let expression = Expression::parse(&fields[name].script)?;
+    *expanded += expression.size();
+    if *expanded > MAX_EXPANDED_SIZE {
+        return Err(Error::QueryError(format!(
+            "Runtime field {} reads other runtime fields that come to more than {} parts of a script",
+            expanding.first().map(String::as_str).unwrap_or(name),
+            MAX_EXPANDED_SIZE
+        )));
+    }
+    expanding.push(name.to_string());
+    let expression = expression.substitute(&mut |field| {
+        // A field already being expanded is read from the index
+        if fields.contains_key(field) && !expanding.iter().any(|e| e == field) {
+            expand(fields, field, expanding, expanded).map(Some)
+        } else {
+            Ok(None)
+        }
+    })?;
+    expanding.pop();
+    Ok(expression)
+}
+
+/// Make the parts of `request` that name runtime fields work out their values from the fields' scripts instead
+pub fn apply_runtime_fields(fields: &RuntimeFields, request: &mut Request) -> Result<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    if let Some(query) = request.query.take() {
+        request.query = Some(convert_query(fields, query)?);
+    }
+    if let Some(ref mut sort) = request.sort {
+        if sort.script.is_none() {
+            sort.script = runtime_script(fields, &sort.field)?;
+        }
+    }
+    match request.aggs {
+        Some(Metrics::Range { ref mut range }) if range.script.is_none() => range.script = runtime_script(fields, &range.field)?,
+        Some(Metrics::DateHistogram { ref mut date_histogram }) if date_histogram.script.is_none() => {
+            date_histogram.script = runtime_script(fields, &date_histogram.field)?
+        }
+        Some(Metrics::Filters { ref mut filters }) => {
+            let named = std::mem::replace(&mut filters.filters, BTreeMap::new());
+            for (name, filter) in named {
+                filters.filters.insert(name, convert_query(fields, filter)?);
+            }
+        }
+        _ => {}
+    }
+    if let Some(ref mut rescore) = request.rescore {
+        if let Some(query) = rescore.query.take() {
+            rescore.query = Some(convert_query(fields, query)?);
+        }
+    }
+    Ok(())
+}
+
+/// `query` with its range and term clauses on runtime fields made script clauses
+pub fn convert_query(fields: &RuntimeFields, query: Query) -> Result<Query> {
+    if fields.is_empty() {
+        return Ok(query);
+    }
+    // Clauses are found the same way however deeply they're nested in bool queries, by way of JSON
+    let mut value = serde_json::to_value(&query)?;
+    convert_clauses(fields, &mut value)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn convert_clauses(fields: &RuntimeFields, value: &mut Value) -> Result<()> {
+    match value {
+        Value::Object(object) => {
+            if let Some(script) = script_clause(fields, object)? {
+                *object = script;
+                return Ok(());
+            }
+            object.values_mut().try_for_each(|value| convert_clauses(fields, value))
+        }
+        Value::Array(values) => values.iter_mut().try_for_each(|value| convert_clauses(fields, value)),
+        _ => Ok(()),
+    }
+}
+
+/// The script clause matching what a range or term `clause` on a runtime field does
+fn script_clause(fields: &RuntimeFields, clause: &Map<String, Value>) -> Result<Option<Map<String, Value>>> {
+    let (kind, (name, condition)) = match clause.iter().next() {
+        Some((kind, Value::Object(conditions))) if clause.len() == 1 && conditions.len() == 1 && (kind == "range" || kind == "term") => {
+            (kind, conditions.iter().next().unwrap())
+        }
+        _ => return Ok(None),
+    };
+    let script = match runtime_script(fields, name)? {
+        Some(script) => script,
+        None => return Ok(None),
+    };
+    let number = |value: &Value| -> Result<i64> {
+        value
+            .as_i64()
+            .or_else(|| value.as_str().and_then(|s| s.trim().parse().ok()))
+            .ok_or_else(|| Error::QueryError(format!("{} is not a value of runtime field {}", value, name)))
+    };
+    let mut source = Vec::new();
+    if kind == "term" {
+        source.push(format!("{} == {}", script, number(condition)?));
+    } else {
+        for (bound, symbol) in &[("gt", ">"), ("gte", ">="), ("lt", "<"), ("lte", "<=")] {
+            if let Some(value) = condition.get(*bound).filter(|v| !v.is_null()) {
+                source.push(format!("{} {} {}", script, symbol, number(value)?));
+            }
+        }
+        if source.is_empty() {
+            return Err(Error::QueryError(format!("The range of runtime field {} has no bounds", name)));
+        }
+    }
+    let boost = condition.get("boost").and_then(Value::as_f64).unwrap_or(1.0);
+    let mut script = Map::new();
+    script.insert(
+        "script".into(),
+        serde_json::json!({ "source": source.join(" && "), "boost": boost }),
+    );
+    Ok(Some(script))
+}
+
+/// Take the runtime fields out of `names`, the fields a search asked for the values of, as scripts over `schema`
+pub fn doc_value_scripts(fields: &RuntimeFields, schema: &Schema, names: &mut Vec<String>) -> Result<Vec<(String, Arc<Script>)>> {
+    let mut scripts = Vec::new();
+    let mut stored = Vec::new();
+    for name in names.drain(..) {
+        match runtime_script(fields, &name)? {
+            Some(source) => scripts.push((name, Arc::new(Script::compile(schema, &source)?))),
+            None => stored.push(name),
+        }
+    }
+    *names = stored;
+    Ok(scripts)
+}
+
+/// The value each of `scripts` gives `doc`, by the name of its runtime field
+pub fn script_doc_values(
+    searcher: &Searcher,
+    doc: DocAddress,
+    scripts: &[(String, Arc<Script>)],
+) -> Result<BTreeMap<String, Vec<tantivy::schema::Value>>> {
+    let reader = searcher.segment_reader(doc.segment_ord());
+    scripts
+        .iter()
+        .map(|(name, script)| {
+            let value = Script::values(script, reader, searcher.schema())?.get(doc.doc());
+            Ok((name.clone(), value.map(tantivy::schema::Value::I64).into_iter().collect()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields() -> RuntimeFields {
+        serde_json::from_value(json!({
+            "total": { "script": "price * quantity" },
+            "discounted": { "script": "total - total / 10" },
+            "price": { "script": "price / 100" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_runtime_script() {
+        let fields = fields();
+        assert_eq!(runtime_script(&fields, "total").unwrap().unwrap(), "((price / 100) * quantity)");
+        assert_eq!(
+            runtime_script(&fields, "discounted").unwrap().unwrap(),
+            "(((price / 100) * quantity) - (((price / 100) * quantity) / 10))"
+        );
+        assert_eq!(runtime_script(&fields, "quantity").unwrap(), None);
+
+        let mut broken = fields.clone();
+        broken.insert("bad".into(), RuntimeField { script: "total +".into() });
+        assert!(runtime_script(&broken, "bad").is_err());
+
+        // Each field reads the next twice, which would double the script at every step
+        let mut doubling = RuntimeFields::new();
+        for step in 0..20 {
+            let script = format!("step{} + step{}", step + 1, step + 1);
+            doubling.insert(format!("step{}", step), RuntimeField { script });
+        }
+        assert!(runtime_script(&doubling, "step0").is_err());
+        assert!(runtime_script(&doubling, "step12").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut request: Request = serde_json::from_value(json!({
+            "query": { "bool": {
+                "must": [{ "range": { "total": { "gte": 100, "lt": "500" } } }],
+                "filter": [{ "term": { "quantity": "2" } }, { "term": { "total": "30" } }] } },
+            "sort": { "field": "total", "order": "asc" },
+            "aggs": { "range": { "field": "discounted", "ranges": [{ "to": 50 }] } }
+        }))
+        .unwrap();
+        apply_runtime_fields(&fields(), &mut request).unwrap();
+
+        let query = serde_json::to_value(request.query.as_ref().unwrap()).unwrap();
+        let total = "((price / 100) * quantity)";
+        assert_eq!(
+            query["bool"]["must"][0]["script"]["source"],
+            json!(format!("{} >= 100 && {} < 500", total, total))
+        );
+        assert_eq!(query["bool"]["filter"][0]["term"]["quantity"], "2");
+        assert_eq!(query["bool"]["filter"][1]["script"]["source"], json!(format!("{} == 30", total)));
+        assert_eq!(request.sort.unwrap().script.unwrap(), total);
+        match request.aggs {
+            Some(Metrics::Range { range }) => assert!(range.script.unwrap().starts_with("((")),
+            _ => panic!("Expected a range aggregation"),
+        }
+
+        let mut unbounded: Request = serde_json::from_value(json!({ "query": { "range": { "total": { "boost": 2.0 } } } })).unwrap();
+        assert!(apply_runtime_fields(&fields(), &mut unbounded).is_err());
+        let mut words: Request = serde_json::from_value(json!({ "query": { "term": { "total": "many" } } })).unwrap();
+        assert!(apply_runtime_fields(&fields(), &mut words).is_err());
+    }
+}
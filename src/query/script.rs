@@ -0,0 +1,754 @@
+//! Scripts are small integer expressions over the u64 and i64 fast fields of a document, such as
+//! `price * quantity - discount` or `max(updated, created) > 1546300800000`. They're worked out for each document
+//! when a search needs them, so they can filter, sort and bucket documents by values that were never indexed.
+//!
+//! A script has whole numbers, field names, the operators `+ - * / %`, comparisons `== != < <= > >=` that give 1
+//! or 0, `&& || !` that take anything but 0 as true, parentheses and the functions `abs`, `min` and `max`, nested no
+//! more than `MAX_DEPTH` deep. Numbers are 64 bit and wrap around rather than overflowing, and fields holding several values give their first. A document
+//! without a value in a field the script needs, or one the script would divide by 0, gets no value from it.
+
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{Query, Scorer, Weight};
+use tantivy::schema::{Field, Schema, Value};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader};
+
+use crate::query::{sort_field, FastValues};
+use crate::{Error, Result};
+
+/// How deep the parts of a script can be nested in each other, counting each operator, function and parentheses
+pub const MAX_DEPTH: usize = 64;
+
+/// Matches the documents `source` gives a value other than 0
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ScriptQuery {
+    pub source: String,
+    #[serde(default = "ScriptQuery::default_boost")]
+    pub boost: f32,
+}
+
+impl ScriptQuery {
+    pub fn default_boost() -> f32 {
+        1.0
+    }
+
+    pub fn build(self, schema: &Schema) -> Result<Box<Query>> {
+        let script = Arc::new(Script::compile(schema, &self.source)?);
+        Ok(Box::new(ScriptFilter::non_zero(script).with_boost(self.boost)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    fn apply(self, left: i64, right: i64) -> Option<i64> {
+        let truth = |b: bool| Some(b as i64);
+        match self {
+            BinaryOp::Add => Some(left.wrapping_add(right)),
+            BinaryOp::Sub => Some(left.wrapping_sub(right)),
+            BinaryOp::Mul => Some(left.wrapping_mul(right)),
+            BinaryOp::Div => left.checked_div(right),
+            BinaryOp::Rem => left.checked_rem(right),
+            BinaryOp::Eq => truth(left == right),
+            BinaryOp::Ne => truth(left != right),
+            BinaryOp::Lt => truth(left < right),
+            BinaryOp::Le => truth(left <= right),
+            BinaryOp::Gt => truth(left > right),
+            BinaryOp::Ge => truth(left >= right),
+            BinaryOp::And => truth(left != 0 && right != 0),
+            BinaryOp::Or => truth(left != 0 || right != 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Abs,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn named(name: &str) -> Option<Self> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Function::Abs => "abs",
+            Function::Min => "min",
+            Function::Max => "max",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    /// A field by name, and where its value is found once the script is compiled
+    Field(String, usize),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, values: &[Option<i64>]) -> Option<i64> {
+        match self {
+            Expr::Number(n) => Some(*n),
+            Expr::Field(_, slot) => values.get(*slot).and_then(|v| *v),
+            Expr::Negate(e) => e.eval(values).map(i64::wrapping_neg),
+            Expr::Not(e) => e.eval(values).map(|v| (v == 0) as i64),
+            Expr::Binary(op, left, right) => op.apply(left.eval(values)?, right.eval(values)?),
+            Expr::Call(Function::Abs, args) => args[0].eval(values).map(i64::wrapping_abs),
+            Expr::Call(Function::Min, args) => args.iter().map(|a| a.eval(values)).collect::<Option<Vec<_>>>()?.into_iter().min(),
+            Expr::Call(Function::Max, args) => args.iter().map(|a| a.eval(values)).collect::<Option<Vec<_>>>()?.into_iter().max(),
+        }
+    }
+
+    /// How many levels of operators and functions there are down to the deepest number or field
+    fn depth(&self) -> usize {
+        match self {
+            Expr::Number(_) | Expr::Field(..) => 1,
+            Expr::Negate(e) | Expr::Not(e) => e.depth() + 1,
+            Expr::Binary(_, left, right) => left.depth().max(right.depth()) + 1,
+            Expr::Call(_, args) => args.iter().map(Expr::depth).max().unwrap_or(0) + 1,
+        }
+    }
+
+    /// How many numbers, fields, operators and functions there are
+    fn size(&self) -> usize {
+        match self {
+            Expr::Number(_) | Expr::Field(..) => 1,
+            Expr::Negate(e) | Expr::Not(e) => e.size() + 1,
+            Expr::Binary(_, left, right) => left.size() + right.size() + 1,
+            Expr::Call(_, args) => args.iter().map(Expr::size).sum::<usize>() + 1,
+        }
+    }
+
+    /// Call `visit` with the name and slot of each field, in the order they appear
+    fn visit_fields<F>(&mut self, visit: &mut F) -> Result<()>
+    where
+        F: FnMut(&str, &mut usize) -> Result<()>,
+    {
+        match self {
+            Expr::Field(name, slot) => visit(name, slot),
+            Expr::Negate(e) | Expr::Not(e) => e.visit_fields(visit),
+            Expr::Binary(_, left, right) => {
+                left.visit_fields(visit)?;
+                right.visit_fields(visit)
+            }
+            Expr::Call(_, args) => args.iter_mut().try_for_each(|a| a.visit_fields(visit)),
+            Expr::Number(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Field(name, _) => write!(f, "{}", name),
+            Expr::Negate(e) => write!(f, "-({})", e),
+            Expr::Not(e) => write!(f, "!({})", e),
+            Expr::Binary(op, left, right) => write!(f, "({} {} {})", left, op.symbol(), right),
+            Expr::Call(function, args) => {
+                let args: Vec<String> = args.iter().map(Expr::to_string).collect();
+                write!(f, "{}({})", function.name(), args.join(", "))
+            }
+        }
+    }
+}
+
+/// A script as it's written, before its fields are found in an index
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    expr: Expr,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            depth: 0,
+            source,
+        };
+        let expr = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(Expression { expr }),
+            Some(token) => Err(parser.error(&format!("unexpected {}", token))),
+        }
+    }
+
+    /// How many numbers, fields, operators and functions there are
+    pub fn size(&self) -> usize {
+        self.expr.size()
+    }
+
+    /// Replace each field `expand` gives an expression for with that expression
+    pub fn substitute<F>(mut self, expand: &mut F) -> Result<Self>
+    where
+        F: FnMut(&str) -> Result<Option<Expression>>,
+    {
+        substitute(&mut self.expr, expand)?;
+        Ok(self)
+    }
+}
+
+fn substitute<F>(expr: &mut Expr, expand: &mut F) -> Result<()>
+where
+    F: FnMut(&str) -> Result<Option<Expression>>,
+{
+    match expr {
+        Expr::Field(name, _) => {
+            if let Some(expanded) = expand(name)? {
+                *expr = expanded.expr;
+            }
+            Ok(())
+        }
+        Expr::Negate(e) | Expr::Not(e) => substitute(e, expand),
+        Expr::Binary(_, left, right) => {
+            substitute(left, expand)?;
+            substitute(right, expand)
+        }
+        Expr::Call(_, args) => args.iter_mut().try_for_each(|a| substitute(a, expand)),
+        Expr::Number(_) => Ok(()),
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 19] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "%", "!", "(", ")", ",", "=", "&",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or_else(|| rest.len());
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| Error::QueryError(format!("Number {} in script {} is too large", &rest[..end], source)))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or_else(|| rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            // A lone `=` or `&` is most likely a mistake for `==` or `&&`, and is caught by the parser
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(**symbol))
+                .ok_or_else(|| Error::QueryError(format!("Script {} has an unexpected '{}'", source, c)))?;
+            tokens.push(Token::Symbol(*symbol));
+            rest = &rest[symbol.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How many parentheses, functions and unary operators the parser is inside of
+    depth: usize,
+    source: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, problem: &str) -> Error {
+        Error::QueryError(format!("Script {} can't be parsed: {}", self.source, problem))
+    }
+
+    fn too_deep(&self) -> Error {
+        self.error(&format!("it's nested more than {} deep", MAX_DEPTH))
+    }
+
+    /// Parse with `parse` one level further in, so scripts nested too deeply are caught before they run the stack out
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.too_deep());
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    /// `expr`, unless putting it together made it too deep, as long chains of operators do
+    fn node(&self, expr: Expr) -> Result<Expr> {
+        if expr.depth() > MAX_DEPTH {
+            return Err(self.too_deep());
+        }
+        Ok(expr)
+    }
+
+    fn eat(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Symbol(symbol)) if symbols.contains(symbol) => {
+                let symbol = *symbol;
+                self.pos += 1;
+                Some(symbol)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<()> {
+        match self.eat(&[symbol]) {
+            Some(_) => Ok(()),
+            None => Err(self.error(&format!("expected '{}'", symbol))),
+        }
+    }
+
+    fn binary(op: &str) -> BinaryOp {
+        match op {
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "<" => BinaryOp::Lt,
+            "<=" => BinaryOp::Le,
+            ">" => BinaryOp::Gt,
+            ">=" => BinaryOp::Ge,
+            "&&" => BinaryOp::And,
+            _ => BinaryOp::Or,
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while let Some(op) = self.eat(&["||"]) {
+            expr = self.node(Expr::Binary(Self::binary(op), Box::new(expr), Box::new(self.and()?)))?;
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.comparison()?;
+        while let Some(op) = self.eat(&["&&"]) {
+            expr = self.node(Expr::Binary(Self::binary(op), Box::new(expr), Box::new(self.comparison()?)))?;
+        }
+        Ok(expr)
+    }
+
+    /// Comparisons don't chain, `a < b < c` has to be written `a < b && b < c`
+    fn comparison(&mut self) -> Result<Expr> {
+        let expr = self.sum()?;
+        match self.eat(&["==", "!=", "<=", ">=", "<", ">"]) {
+            Some(op) => self.node(Expr::Binary(Self::binary(op), Box::new(expr), Box::new(self.sum()?))),
+            None => Ok(expr),
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        let mut expr = self.product()?;
+        while let Some(op) = self.eat(&["+", "-"]) {
+            expr = self.node(Expr::Binary(Self::binary(op), Box::new(expr), Box::new(self.product()?)))?;
+        }
+        Ok(expr)
+    }
+
+    fn product(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.eat(&["*", "/", "%"]) {
+            expr = self.node(Expr::Binary(Self::binary(op), Box::new(expr), Box::new(self.unary()?)))?;
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.eat(&["-", "!"]) {
+            Some("-") => self.node(Expr::Negate(Box::new(self.nested(Self::unary)?))),
+            Some(_) => self.node(Expr::Not(Box::new(self.nested(Self::unary)?))),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        if self.eat(&["("]).is_some() {
+            let expr = self.nested(Self::or)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => {
+                if self.eat(&["("]).is_none() {
+                    return Ok(Expr::Field(name, 0));
+                }
+                let function = Function::named(&name).ok_or_else(|| self.error(&format!("there is no function {}", name)))?;
+                let mut args = vec![self.nested(Self::or)?];
+                while self.eat(&[","]).is_some() {
+                    args.push(self.nested(Self::or)?);
+                }
+                self.expect(")")?;
+                if function == Function::Abs && args.len() != 1 {
+                    return Err(self.error("abs takes one value"));
+                }
+                self.node(Expr::Call(function, args))
+            }
+            Some(token) => Err(self.error(&format!("unexpected {}", token))),
+            None => Err(self.error("it ends too soon")),
+        }
+    }
+}
+
+/// A script with its fields found in an index, ready to be worked out for the documents of each segment
+#[derive(Debug)]
+pub struct Script {
+    expr: Expr,
+    fields: Vec<Field>,
+}
+
+impl Script {
+    /// `source` over the u64 and i64 fast fields of `schema`
+    pub fn compile(schema: &Schema, source: &str) -> Result<Self> {
+        let mut expr = Expression::parse(source)?.expr;
+        let mut fields: Vec<Field> = Vec::new();
+        expr.visit_fields(&mut |name, slot| {
+            let field = sort_field(schema, name)
+                .map_err(|_| Error::QueryError(format!("Script {} reads {}, which is not a u64 or i64 fast field", source, name)))?;
+            *slot = match fields.iter().position(|f| *f == field) {
+                Some(slot) => slot,
+                None => {
+                    fields.push(field);
+                    fields.len() - 1
+                }
+            };
+            Ok(())
+        })?;
+        Ok(Script { expr, fields })
+    }
+
+    /// What the script gives the documents of a segment
+    pub fn values(script: &Arc<Script>, reader: &SegmentReader, schema: &Schema) -> Result<ScriptValues> {
+        Ok(ScriptValues {
+            fields: script
+                .fields
+                .iter()
+                .map(|field| FastValues::new(reader, schema, *field))
+                .collect::<Result<_>>()?,
+            script: Arc::clone(script),
+        })
+    }
+}
+
+/// A script over the fast fields of a segment
+pub struct ScriptValues {
+    script: Arc<Script>,
+    fields: Vec<FastValues>,
+}
+
+impl ScriptValues {
+    /// The value the script gives `doc`, if it gives one
+    pub fn get(&self, doc: DocId) -> Option<i64> {
+        let mut found = Vec::new();
+        let values: Vec<Option<i64>> = self
+            .fields
+            .iter()
+            .map(|values| {
+                values.get(doc, &mut found);
+                found.first().map(|v| match values.value(*v) {
+                    Value::I64(v) => v,
+                    Value::U64(v) => v as i64,
+                    _ => 0,
+                })
+            })
+            .collect();
+        self.script.expr.eval(&values)
+    }
+}
+
+/// Matches the documents a script gives a value within bounds, or any value other than 0
+#[derive(Debug, Clone)]
+pub struct ScriptFilter {
+    script: Arc<Script>,
+    bounds: Option<(Bound<i64>, Bound<i64>)>,
+    boost: f32,
+}
+
+impl ScriptFilter {
+    pub fn non_zero(script: Arc<Script>) -> Self {
+        ScriptFilter {
+            script,
+            bounds: None,
+            boost: 1.0,
+        }
+    }
+
+    pub fn within(script: Arc<Script>, lower: Bound<i64>, upper: Bound<i64>) -> Self {
+        ScriptFilter {
+            script,
+            bounds: Some((lower, upper)),
+            boost: 1.0,
+        }
+    }
+
+    fn with_boost(mut self, boost: f32) -> Self {
+        self.boost = boost;
+        self
+    }
+}
+
+impl Query for ScriptFilter {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        Ok(Box::new(ScriptWeight {
+            filter: self.clone(),
+            schema: searcher.schema().clone(),
+        }))
+    }
+}
+
+struct ScriptWeight {
+    filter: ScriptFilter,
+    schema: Schema,
+}
+
+impl Weight for ScriptWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        let values =
+            Script::values(&self.filter.script, reader, &self.schema).map_err(|e| tantivy::TantivyError::SchemaError(e.to_string()))?;
+        Ok(Box::new(ScriptScorer {
+            values,
+            bounds: self.filter.bounds,
+            boost: self.filter.boost,
+            max_doc: reader.max_doc(),
+            doc: None,
+        }))
+    }
+}
+
+/// Goes through every document of a segment for those the script matches
+struct ScriptScorer {
+    values: ScriptValues,
+    bounds: Option<(Bound<i64>, Bound<i64>)>,
+    boost: f32,
+    max_doc: DocId,
+    doc: Option<DocId>,
+}
+
+impl ScriptScorer {
+    fn matches(&self, doc: DocId) -> bool {
+        let value = match self.values.get(doc) {
+            Some(value) => value,
+            None => return false,
+        };
+        match self.bounds {
+            None => value != 0,
+            Some((lower, upper)) => {
+                let above = match lower {
+                    Bound::Included(lower) => value >= lower,
+                    Bound::Excluded(lower) => value > lower,
+                    Bound::Unbounded => true,
+                };
+                let below = match upper {
+                    Bound::Included(upper) => value <= upper,
+                    Bound::Excluded(upper) => value < upper,
+                    Bound::Unbounded => true,
+                };
+                above && below
+            }
+        }
+    }
+}
+
+impl DocSet for ScriptScorer {
+    fn advance(&mut self) -> bool {
+        let mut doc = self.doc.map_or(0, |doc| doc + 1);
+        while doc < self.max_doc {
+            if self.matches(doc) {
+                self.doc = Some(doc);
+                return true;
+            }
+            doc += 1;
+        }
+        self.doc = Some(self.max_doc);
+        false
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc.unwrap_or(0)
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.max_doc
+    }
+}
+
+impl Scorer for ScriptScorer {
+    fn score(&mut self) -> Score {
+        self.boost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::Count;
+    use tantivy::schema::{SchemaBuilder, FAST};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_parse() {
+        let expression = Expression::parse("price * quantity - discount > 100 && !(free || abs(-3) == 3)").unwrap();
+        assert_eq!(
+            expression.to_string(),
+            "((((price * quantity) - discount) > 100) && !((free || (abs(-(3)) == 3))))"
+        );
+        assert_eq!(Expression::parse(&expression.to_string()).unwrap(), expression);
+
+        for bad in &[
+            "",
+            "1 +",
+            "(1",
+            "a = 1",
+            "a & b",
+            "a < b < c",
+            "sqrt(2)",
+            "abs(1, 2)",
+            "min()",
+            "99999999999999999999",
+            "1 $ 2",
+        ] {
+            assert!(Expression::parse(bad).is_err(), "{} parsed", bad);
+        }
+
+        let deep = MAX_DEPTH + 1;
+        for bad in &[
+            format!("{}1", "-".repeat(deep)),
+            format!("{}1{}", "(".repeat(deep), ")".repeat(deep)),
+            format!("{}1{}", "abs(".repeat(deep), ")".repeat(deep)),
+            format!("1{}", " + 1".repeat(deep)),
+            format!("{}1{}", "min(1, ".repeat(deep), ")".repeat(deep)),
+        ] {
+            assert!(Expression::parse(bad).is_err(), "{} parsed", bad);
+        }
+        assert!(Expression::parse(&format!("{}1", "-".repeat(MAX_DEPTH - 1))).is_ok());
+        assert!(Expression::parse(&format!("1{}", " + 1".repeat(MAX_DEPTH - 1))).is_ok());
+
+        let total = Expression::parse("price * quantity").unwrap();
+        let expanded = Expression::parse("total + 1")
+            .unwrap()
+            .substitute(&mut |name| Ok(Some(total.clone()).filter(|_| name == "total")))
+            .unwrap();
+        assert_eq!(expanded.to_string(), "((price * quantity) + 1)");
+    }
+
+    #[test]
+    fn test_script() {
+        let mut builder = SchemaBuilder::new();
+        let price = builder.add_u64_field("price", FAST);
+        let quantity = builder.add_i64_field("quantity", FAST);
+        builder.add_text_field("name", tantivy::schema::TEXT);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for (p, q) in &[(10u64, 3i64), (25, 0), (7, -2), (40, 5)] {
+            writer.add_document(doc!(price => *p, quantity => *q));
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let script = Arc::new(Script::compile(&schema, "price * quantity").unwrap());
+        let values = Script::values(&script, searcher.segment_reader(0), &schema).unwrap();
+        assert_eq!(
+            (0..4).map(|doc| values.get(doc)).collect::<Vec<_>>(),
+            vec![Some(30), Some(0), Some(-14), Some(200)]
+        );
+        let ratio = Arc::new(Script::compile(&schema, "price / quantity").unwrap());
+        assert_eq!(Script::values(&ratio, searcher.segment_reader(0), &schema).unwrap().get(1), None);
+
+        let count = |query: &Query| searcher.search(query, &Count).unwrap();
+        let query = ScriptQuery {
+            source: "price * quantity > 0 && price < 40".into(),
+            boost: 1.0,
+        };
+        assert_eq!(count(&*query.build(&schema).unwrap()), 1);
+        assert_eq!(
+            count(&ScriptFilter::within(Arc::clone(&script), Bound::Included(0), Bound::Excluded(200))),
+            2
+        );
+        assert_eq!(count(&ScriptFilter::within(script, Bound::Unbounded, Bound::Included(0))), 2);
+
+        assert!(Script::compile(&schema, "name + 1").is_err());
+        assert!(Script::compile(&schema, "missing + 1").is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+use tantivy::fieldnorm::FieldNormReader;
+use tantivy::postings::{Postings, SegmentPostings};
+use tantivy::query::{Query, Scorer, Weight};
+use tantivy::schema::{FieldType, IndexRecordOption};
+use tantivy::{DocId, DocSet, Score, Searcher, SegmentReader, SkipResult, Term};
+
+/// How much a document matching a term of a text field scores for it
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextSimilarity {
+    /// Okapi BM25, where `k1` sets how quickly repeats of a term stop adding to the score and `b` how much a long
+    /// field is penalized against the average length of the field. Short fields like product titles usually want a
+    /// lower `b` than the default.
+    Bm25 {
+        #[serde(default = "TextSimilarity::default_k1")]
+        k1: f32,
+        #[serde(default = "TextSimilarity::default_b")]
+        b: f32,
+    },
+    /// Lucene's classic TF-IDF, the square root of the term's frequency times its squared idf, divided by the square
+    /// root of the field's length
+    Classic,
+    /// 1 for every term the document has, however often and however rare the term is
+    Boolean,
+}
+
+impl Default for TextSimilarity {
+    fn default() -> Self {
+        TextSimilarity::Bm25 {
+            k1: TextSimilarity::default_k1(),
+            b: TextSimilarity::default_b(),
+        }
+    }
+}
+
+impl TextSimilarity {
+    pub fn default_k1() -> f32 {
+        1.2
+    }
+
+    pub fn default_b() -> f32 {
+        0.75
+    }
+
+    /// The score of a term found `term_freq` times in a field of `length` tokens, where the term is in `doc_freq`
+    /// of the `num_docs` documents and the field is `average_length` tokens long on average
+    fn score(self, term_freq: u32, length: u32, doc_freq: u64, num_docs: u64, average_length: f32) -> Score {
+        let term_freq = term_freq as f32;
+        match self {
+            TextSimilarity::Bm25 { k1, b } => {
+                let idf = (1.0 + (num_docs as f32 - doc_freq as f32 + 0.5) / (doc_freq as f32 + 0.5)).ln();
+                let relative = if average_length > 0.0 {
+                    length as f32 / average_length
+                } else {
+                    1.0
+                };
+                idf * term_freq * (k1 + 1.0) / (term_freq + k1 * (1.0 - b + b * relative))
+            }
+            TextSimilarity::Classic => {
+                let idf = 1.0 + (num_docs as f32 / (doc_freq as f32 + 1.0)).ln();
+                term_freq.sqrt() * idf * idf / (length.max(1) as f32).sqrt()
+            }
+            TextSimilarity::Boolean => 1.0,
+        }
+    }
+}
+
+/// The similarities the text fields of an index score with, by field name, with `default` for the fields that have
+/// none of their own
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Similarities {
+    pub default: Option<TextSimilarity>,
+    pub fields: HashMap<String, TextSimilarity>,
+}
+
+impl Similarities {
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.fields.is_empty()
+    }
+
+    fn get(&self, field: &str) -> TextSimilarity {
+        self.fields.get(field).cloned().or(self.default).unwrap_or_default()
+    }
+
+    /// `query` scored by these similarities, or `query` itself when there are none. The terms `query` searches are
+    /// scored again for each document it matches, replacing the score it would have given, so queries that don't
+    /// search terms, like `knn` queries, keep their own scores.
+    pub fn apply(&self, query: Box<Query>) -> Box<Query> {
+        if self.is_empty() {
+            return query;
+        }
+        Box::new(SimilarityQuery {
+            inner: query,
+            similarities: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct SimilarityQuery {
+    inner: Box<Query>,
+    similarities: Similarities,
+}
+
+impl Clone for SimilarityQuery {
+    fn clone(&self) -> Self {
+        SimilarityQuery {
+            inner: self.inner.box_clone(),
+            similarities: self.similarities.clone(),
+        }
+    }
+}
+
+impl Query for SimilarityQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> tantivy::Result<Box<Weight>> {
+        let schema = searcher.schema();
+        let mut terms = BTreeSet::new();
+        self.inner.query_terms(&mut terms);
+        // Only text fields keep how often each term is in a document
+        terms.retain(|term| match schema.get_field_entry(term.field()).field_type() {
+            FieldType::Str(options) => options.get_indexing_options().map_or(false, |i| i.index_option().has_freq()),
+            _ => false,
+        });
+        if !scoring_enabled || terms.is_empty() {
+            return self.inner.weight(searcher, scoring_enabled);
+        }
+        let num_docs = searcher.num_docs();
+        let max_docs: u64 = searcher.segment_readers().iter().map(|r| u64::from(r.max_doc())).sum();
+        let terms = terms
+            .into_iter()
+            .map(|term| {
+                let field = term.field();
+                let tokens: u64 = searcher
+                    .segment_readers()
+                    .iter()
+                    .map(|r| r.inverted_index(field).total_num_tokens())
+                    .sum();
+                TermStats {
+                    similarity: self.similarities.get(schema.get_field_name(field)),
+                    doc_freq: searcher.doc_freq(&term),
+                    average_length: if max_docs > 0 { tokens as f32 / max_docs as f32 } else { 0.0 },
+                    term,
+                }
+            })
+            .collect();
+        Ok(Box::new(SimilarityWeight {
+            inner: self.inner.weight(searcher, false)?,
+            terms,
+            num_docs,
+        }))
+    }
+
+    fn query_terms(&self, term_set: &mut BTreeSet<Term>) {
+        self.inner.query_terms(term_set)
+    }
+}
+
+struct TermStats {
+    term: Term,
+    similarity: TextSimilarity,
+    doc_freq: u64,
+    average_length: f32,
+}
+
+struct SimilarityWeight {
+    inner: Box<Weight>,
+    terms: Vec<TermStats>,
+    num_docs: u64,
+}
+
+impl Weight for SimilarityWeight {
+    fn scorer(&self, reader: &SegmentReader) -> tantivy::Result<Box<Scorer>> {
+        let mut terms = Vec::new();
+        for (i, stats) in self.terms.iter().enumerate() {
+            let field = stats.term.field();
+            if let Some(mut postings) = reader
+                .inverted_index(field)
+                .read_postings(&stats.term, IndexRecordOption::WithFreqs)
+            {
+                if postings.advance() {
+                    terms.push(TermPostings {
+                        stats: i,
+                        current: Some(postings.doc()),
+                        postings,
+                        fieldnorms: reader.get_fieldnorms_reader(field),
+                    });
+                }
+            }
+        }
+        Ok(Box::new(SimilarityScorer {
+            inner: self.inner.scorer(reader)?,
+            terms,
+            stats: self.terms.iter().map(|t| (t.similarity, t.doc_freq, t.average_length)).collect(),
+            num_docs: self.num_docs,
+        }))
+    }
+}
+
+struct TermPostings {
+    stats: usize,
+    postings: SegmentPostings,
+    /// The document the postings are on, none once they're past the last
+    current: Option<DocId>,
+    fieldnorms: FieldNormReader,
+}
+
+struct SimilarityScorer {
+    inner: Box<Scorer>,
+    terms: Vec<TermPostings>,
+    stats: Vec<(TextSimilarity, u64, f32)>,
+    num_docs: u64,
+}
+
+impl DocSet for SimilarityScorer {
+    fn advance(&mut self) -> bool {
+        self.inner.advance()
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for SimilarityScorer {
+    fn score(&mut self) -> Score {
+        let doc = self.inner.doc();
+        let mut score = 0.0;
+        for term in &mut self.terms {
+            match term.current {
+                Some(current) if current < doc => {
+                    term.current = match term.postings.skip_next(doc) {
+                        SkipResult::End => None,
+                        _ => Some(term.postings.doc()),
+                    };
+                }
+                _ => {}
+            }
+            if term.current == Some(doc) {
+                let (similarity, doc_freq, average_length) = self.stats[term.stats];
+                let length = term.fieldnorms.fieldnorm(doc);
+                score += similarity.score(term.postings.term_freq(), length, doc_freq, self.num_docs, average_length);
+            }
+        }
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::raw_query;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_similarities() {
+        let mut builder = SchemaBuilder::new();
+        let title = builder.add_text_field("title", TEXT | STORED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        writer.add_document(doc!(title => "red shoe"));
+        writer.add_document(doc!(title => "red red red running shoe with laces and a long description"));
+        writer.add_document(doc!(title => "blue shoe"));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let ranked = |similarities: &Similarities| -> Vec<(String, String)> {
+            let query = similarities.apply(raw_query(&schema, index.tokenizers(), "title:red", &[]).unwrap());
+            searcher
+                .search(&*query, &TopDocs::with_limit(10))
+                .unwrap()
+                .into_iter()
+                .map(|(score, doc)| {
+                    let text = searcher.doc(doc).unwrap().get_first(title).unwrap().text().unwrap().to_string();
+                    (format!("{:.3}", score), text)
+                })
+                .collect()
+        };
+
+        let bm25 = |b: f32| Similarities {
+            default: Some(TextSimilarity::Bm25 { k1: 1.2, b }),
+            ..Similarities::default()
+        };
+        // Without length normalization the repeats win, with it the short title does
+        assert_eq!(
+            ranked(&bm25(0.0))[0].1,
+            "red red red running shoe with laces and a long description"
+        );
+        assert_eq!(ranked(&bm25(1.0))[0].1, "red shoe");
+
+        let boolean: Similarities = Similarities {
+            fields: vec![("title".to_string(), TextSimilarity::Boolean)].into_iter().collect(),
+            ..Similarities::default()
+        };
+        let scores: Vec<String> = ranked(&boolean).into_iter().map(|(score, _)| score).collect();
+        assert_eq!(scores, vec!["1.000", "1.000"]);
+
+        let classic = Similarities {
+            default: Some(TextSimilarity::Classic),
+            ..Similarities::default()
+        };
+        // sqrt(1) * (1 + ln(3 / 3))^2 / sqrt(2)
+        assert_eq!(ranked(&classic)[0], ("0.707".to_string(), "red shoe".to_string()));
+
+        let parsed: TextSimilarity = serde_json::from_str(r#"{ "type": "bm25", "b": 0.3 }"#).unwrap();
+        assert_eq!(parsed, TextSimilarity::Bm25 { k1: 1.2, b: 0.3 });
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tantivy::query::{Query as TantivyQuery, Scorer};
+use tantivy::schema::{Field, FieldType, Schema};
+use tantivy::{DocAddress, DocId, DocSet, Searcher, SegmentId, SegmentReader, SkipResult};
+
+use crate::query::script::Script;
+use crate::query::FastValues;
+use crate::{Error, Result};
+
+/// Orders results by the value of a fast field instead of by score
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sort {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Where documents without a value go, last unless asked otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub missing: Option<Missing>,
+    /// Sort by what this script gives each document instead of by the field's values, see `query::script`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl Default for SortOrder {
+    fn default() -> Self {
+        SortOrder::Desc
+    }
+}
+
+/// What documents without a value in the sort field are sorted by, either `"_first"`, `"_last"` or a value of their own
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Missing {
+    Value(i64),
+    Position(MissingPosition),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MissingPosition {
+    #[serde(rename = "_first")]
+    First,
+    #[serde(rename = "_last")]
+    Last,
+}
+
+/// The field named `name`, if it's a u64 or i64 fast field that results can be sorted by
+pub fn sort_field(schema: &Schema, name: &str) -> Result<Field> {
+    let field = schema
+        .get_field(name)
+        .ok_or_else(|| Error::QueryError(format!("Field: {} does not exist", name)))?;
+    match schema.get_field_entry(field).field_type() {
+        FieldType::U64(opts) | FieldType::I64(opts) if opts.is_fast() => Ok(field),
+        _ => Err(Error::QueryError(format!("Field: {} is not a u64 or i64 fast field", name))),
+    }
+}
+
+/// The value documents without one are sorted by, mapped to a u64 like the values of `field`, or like an i64 for scripts
+fn missing_value(schema: &Schema, field: Option<Field>, sort: &Sort) -> Result<u64> {
+    match sort.missing.unwrap_or(Missing::Position(MissingPosition::Last)) {
+        Missing::Position(position) => match (position, sort.order) {
+            (MissingPosition::Last, SortOrder::Asc) | (MissingPosition::First, SortOrder::Desc) => Ok(u64::max_value()),
+            _ => Ok(0),
+        },
+        Missing::Value(value) => match field.map(|field| schema.get_field_entry(field).field_type()) {
+            Some(FieldType::I64(_)) | None => Ok(tantivy::i64_to_u64(value)),
+            _ if value >= 0 => Ok(value as u64),
+            _ => Err(Error::QueryError(format!("Field: {} can't hold {}", sort.field, value))),
+        },
+    }
+}
+
+/// The value `doc` is sorted by. Documents with several values sort by their lowest in ascending order and by their
+/// highest in descending order, and documents without any by `missing`.
+fn sort_value(values: &FastValues, doc: DocId, order: SortOrder, missing: u64, found: &mut Vec<u64>) -> u64 {
+    if let Some(value) = values.single(doc) {
+        return value;
+    }
+    values.get(doc, found);
+    let value = match order {
+        SortOrder::Asc => found.iter().min(),
+        SortOrder::Desc => found.iter().max(),
+    };
+    value.cloned().unwrap_or(missing)
+}
+
+/// Tracks which segments of an index have their documents in ascending order of the index's sort field.
+/// Tantivy writes documents in the order they're added, so segments are sorted as long as documents are
+/// ingested in order, as log events usually are. Merging sorted segments doesn't necessarily produce a
+/// sorted one, so every segment is checked once and the answer remembered until it's merged away.
+pub struct SortedSegments {
+    field: String,
+    sorted: Mutex<HashMap<SegmentId, bool>>,
+}
+
+impl SortedSegments {
+    pub fn new(field: String) -> Self {
+        SortedSegments {
+            field,
+            sorted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Forget segments that are no longer part of the index
+    pub fn retain_segments(&self, segments: &[SegmentId]) {
+        self.sorted.lock().unwrap().retain(|segment, _| segments.contains(segment));
+    }
+
+    fn is_sorted(&self, reader: &SegmentReader, values: &FastValues) -> bool {
+        if let Some(sorted) = self.sorted.lock().unwrap().get(&reader.segment_id()) {
+            return *sorted;
+        }
+        // Documents with several values have no single place in the order to check
+        let sorted = !values.is_multi() && (1..reader.max_doc()).all(|doc| values.single(doc - 1) <= values.single(doc));
+        self.sorted.lock().unwrap().insert(reader.segment_id(), sorted);
+        sorted
+    }
+}
+
+/// A candidate result. Keys are arranged so that smaller ones always come first, whatever the order.
+type Candidate = (u64, u32, DocId);
+
+/// Find the top `limit` documents matching `query` in the order `sort` asks for. Segments that `sorted` knows
+/// are in order of the requested field stop as soon as they've found `limit` documents, others are scanned fully.
+pub fn sorted_search(
+    searcher: &Searcher,
+    query: &TantivyQuery,
+    sort: &Sort,
+    limit: usize,
+    sorted: Option<&SortedSegments>,
+) -> Result<Vec<(u64, DocAddress)>> {
+    let schema = searcher.schema();
+    let (field, script) = match sort.script {
+        Some(ref script) => (None, Some(Arc::new(Script::compile(schema, script)?))),
+        None => (Some(sort_field(schema, &sort.field)?), None),
+    };
+    let missing = missing_value(schema, field, sort)?;
+    let weight = query.weight(searcher, false)?;
+    // What a script gives documents has no order the segments could have been written in
+    let sorted = sorted.filter(|s| s.field() == sort.field && script.is_none());
+    let key = |value: u64| match sort.order {
+        SortOrder::Asc => value,
+        SortOrder::Desc => !value,
+    };
+
+    let mut top: BinaryHeap<Candidate> = BinaryHeap::with_capacity(limit + 1);
+    let mut keep = |candidate: Candidate| {
+        top.push(candidate);
+        if top.len() > limit {
+            top.pop();
+        }
+    };
+    for (ord, reader) in searcher.segment_readers().iter().enumerate() {
+        let values = FastValues::named(reader, schema, &sort.field, script.as_ref())?;
+        let mut found = Vec::new();
+        let ord = ord as u32;
+        let is_live = |doc: DocId| reader.delete_bitset().map(|d| !d.is_deleted(doc)).unwrap_or(true);
+
+        if !sorted.map(|s| s.is_sorted(reader, &values)).unwrap_or(false) {
+            let mut scorer = weight.scorer(reader)?;
+            while scorer.advance() {
+                let doc = scorer.doc();
+                if is_live(doc) {
+                    keep((key(sort_value(&values, doc, sort.order, missing, &mut found)), ord, doc));
+                }
+            }
+            continue;
+        }
+
+        match sort.order {
+            SortOrder::Asc => {
+                let mut scorer = weight.scorer(reader)?;
+                let mut kept = 0;
+                while kept < limit && scorer.advance() {
+                    let doc = scorer.doc();
+                    if is_live(doc) {
+                        keep((key(sort_value(&values, doc, sort.order, missing, &mut found)), ord, doc));
+                        kept += 1;
+                    }
+                }
+            }
+            SortOrder::Desc => {
+                for doc in last_matching(&mut || weight.scorer(reader), reader.max_doc(), limit, &is_live)? {
+                    keep((key(sort_value(&values, doc, sort.order, missing, &mut found)), ord, doc));
+                }
+            }
+        }
+    }
+
+    Ok(top
+        .into_sorted_vec()
+        .into_iter()
+        .map(|(key, ord, doc)| {
+            let value = match sort.order {
+                SortOrder::Asc => key,
+                SortOrder::Desc => !key,
+            };
+            (value, DocAddress(ord, doc))
+        })
+        .collect())
+}
+
+/// The last `limit` live documents a scorer matches. Scorers only move forwards, so this looks at a window
+/// at the end of the segment and widens it until it holds enough matches or covers the whole segment.
+fn last_matching<F, L>(new_scorer: &mut F, max_doc: DocId, limit: usize, is_live: &L) -> Result<Vec<DocId>>
+where
+    F: FnMut() -> tantivy::Result<Box<Scorer>>,
+    L: Fn(DocId) -> bool,
+{
+    let mut window = (limit as DocId).max(1);
+    loop {
+        let start = max_doc.saturating_sub(window);
+        let mut scorer = new_scorer()?;
+        let mut docs = Vec::new();
+        if scorer.skip_next(start) != SkipResult::End {
+            loop {
+                let doc = scorer.doc();
+                if is_live(doc) {
+                    docs.push(doc);
+                }
+                if !scorer.advance() {
+                    break;
+                }
+            }
+        }
+        if docs.len() >= limit || start == 0 {
+            let skip = docs.len().saturating_sub(limit);
+            return Ok(docs.split_off(skip));
+        }
+        window = window.saturating_mul(4);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::{doc_value_fields, doc_values};
+    use tantivy::doc;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{Cardinality, IntOptions, SchemaBuilder, Value, FAST, INT_STORED, STORED, TEXT};
+    use tantivy::{Document, Index};
+
+    #[test]
+    fn test_sorted_search() {
+        let mut builder = SchemaBuilder::new();
+        let timestamp = builder.add_i64_field("timestamp", INT_STORED | FAST);
+        let message = builder.add_text_field("message", STORED | TEXT);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        // One segment ingested in order, and a second one that isn't
+        for ts in 1..=10i64 {
+            writer.add_document(doc! { timestamp => ts, message => "event" });
+        }
+        writer.commit().unwrap();
+        for ts in &[15i64, 11, 13] {
+            writer.add_document(doc! { timestamp => *ts, message => "event" });
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let sorted = SortedSegments::new("timestamp".into());
+
+        let latest = Sort {
+            field: "timestamp".into(),
+            order: SortOrder::Desc,
+            missing: None,
+            script: None,
+        };
+        let values = |results: Vec<(u64, DocAddress)>| -> Vec<i64> { results.into_iter().map(|(v, _)| tantivy::u64_to_i64(v)).collect() };
+        let without_early_exit = sorted_search(&searcher, &AllQuery, &latest, 4, None).unwrap();
+        let with_early_exit = sorted_search(&searcher, &AllQuery, &latest, 4, Some(&sorted)).unwrap();
+        assert_eq!(values(without_early_exit), vec![15, 13, 11, 10]);
+        assert_eq!(values(with_early_exit), vec![15, 13, 11, 10]);
+
+        let earliest = Sort {
+            field: "timestamp".into(),
+            order: SortOrder::Asc,
+            missing: None,
+            script: None,
+        };
+        let results = sorted_search(&searcher, &AllQuery, &earliest, 3, Some(&sorted)).unwrap();
+        assert_eq!(values(results), vec![1, 2, 3]);
+
+        let by_text = Sort {
+            field: "message".into(),
+            order: SortOrder::Asc,
+            missing: None,
+            script: None,
+        };
+        assert!(sorted_search(&searcher, &AllQuery, &by_text, 10, None).is_err());
+
+        // A script sorts by what it works out, never stopping early for the order of the field it's named after
+        let negated = Sort {
+            field: "timestamp".into(),
+            order: SortOrder::Asc,
+            missing: None,
+            script: Some("0 - timestamp".into()),
+        };
+        let results = sorted_search(&searcher, &AllQuery, &negated, 3, Some(&sorted)).unwrap();
+        assert_eq!(values(results), vec![-15, -13, -11]);
+    }
+
+    #[test]
+    fn test_multi_valued_sort() {
+        let mut builder = SchemaBuilder::new();
+        let options = IntOptions::default().set_stored().set_fast(Cardinality::MultiValues);
+        let scores = builder.add_i64_field("scores", options);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        for values in &[vec![5i64, -3], vec![4], vec![], vec![10, 1, 7]] {
+            let mut doc = Document::default();
+            for value in values {
+                doc.add_i64(scores, *value);
+            }
+            writer.add_document(doc);
+        }
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let sorted = SortedSegments::new("scores".into());
+
+        let sort = |order: SortOrder, missing: Option<Missing>| -> Vec<u32> {
+            let sort = Sort {
+                field: "scores".into(),
+                order,
+                missing,
+                script: None,
+            };
+            sorted_search(&searcher, &AllQuery, &sort, 4, Some(&sorted))
+                .unwrap()
+                .into_iter()
+                .map(|(_, doc)| doc.doc())
+                .collect()
+        };
+        assert_eq!(sort(SortOrder::Asc, None), vec![0, 3, 1, 2]);
+        assert_eq!(sort(SortOrder::Desc, None), vec![3, 0, 1, 2]);
+        let first = Some(Missing::Position(MissingPosition::First));
+        assert_eq!(sort(SortOrder::Asc, first), vec![2, 0, 3, 1]);
+        assert_eq!(sort(SortOrder::Desc, first), vec![2, 3, 0, 1]);
+        assert_eq!(sort(SortOrder::Asc, Some(Missing::Value(2))), vec![0, 3, 2, 1]);
+
+        let fields = doc_value_fields(searcher.schema(), &["scores".to_string()]).unwrap();
+        let values = doc_values(&searcher, DocAddress(0, 3), &fields).unwrap();
+        assert_eq!(values["scores"], vec![Value::I64(10), Value::I64(1), Value::I64(7)]);
+        assert!(doc_values(&searcher, DocAddress(0, 2), &fields).unwrap()["scores"].is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+//! Learning to rank: a rescore can hand the candidates at the top of a search to a ranker, which scores them by a
+//! model trained outside Toshi. Each candidate is given to the ranker with the score the search gave it, the scores
+//! of the rescore's feature queries and the values of its fast fields.
+//!
+//! Rankers configured under `[rankers.<name>]` are HTTP services, posted the candidates as JSON and answering with a
+//! score for each of them. Programs embedding Toshi can register rankers of their own with `Rankers::register`, such
+//! as one running an ONNX model in process.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::channel::bounded;
+use futures::sync::mpsc::{self, UnboundedSender};
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::query::Query as TantivyQuery;
+use tantivy::schema::Field;
+use tantivy::{DocAddress, DocId, Score, Searcher};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Timeout;
+
+use crate::query::{doc_values, query_scores};
+use crate::settings::RankerSettings;
+use crate::{Error, Result};
+
+/// A document of the top of a search, as it's given to a ranker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// The score the search gave the document
+    pub score: Score,
+    /// The score each feature query gave the document, 0 for those that don't match it
+    pub features: BTreeMap<String, Score>,
+    /// The values of the document's fast fields the rescore asked for
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, Vec<Value>>,
+}
+
+/// Scores the candidates of a search, which are ordered by the scores it gives them
+pub trait Ranker: Send + Sync {
+    /// A score for each of `candidates`, in the same order, for a search of `index`
+    fn rank(&self, index: &str, candidates: &[Candidate]) -> Result<Vec<Score>>;
+}
+
+/// The rankers searches can rescore with, by name
+#[derive(Default)]
+pub struct Rankers {
+    rankers: RwLock<HashMap<String, Arc<Ranker>>>,
+}
+
+impl Rankers {
+    /// The HTTP rankers of `settings`
+    pub fn new(settings: &HashMap<String, RankerSettings>) -> Result<Self> {
+        let rankers = Rankers::default();
+        for (name, ranker) in settings {
+            rankers.register(name, Arc::new(HttpRanker::new(ranker)?));
+        }
+        Ok(rankers)
+    }
+
+    /// Make `ranker` available to searches as `name`, in place of any ranker of the same name
+    pub fn register(&self, name: &str, ranker: Arc<Ranker>) {
+        self.rankers.write().unwrap().insert(name.to_string(), ranker);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Ranker>> {
+        self.rankers
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::QueryError(format!("There is no ranker named {}", name)))
+    }
+}
+
+type Ranking = Box<Future<Item = (), Error = ()> + Send>;
+
+lazy_static! {
+    /// Where the requests of every HTTP ranker are sent to be run. Searches rank from pool threads that may already be
+    /// running a future of their own, so the requests run on a thread of theirs, and wait for their answer.
+    static ref RANKINGS: Mutex<UnboundedSender<Ranking>> = Mutex::new(ranking_thread());
+}
+
+/// Start the thread ranking requests run on, which runs every one it's sent at once
+fn ranking_thread() -> UnboundedSender<Ranking> {
+    let (sender, rankings) = mpsc::unbounded::<Ranking>();
+    let started = thread::Builder::new().name("toshi-ranker".into()).spawn(move || {
+        let run = rankings.for_each(|ranking| {
+            tokio::spawn(ranking);
+            Ok(())
+        });
+        match Runtime::new() {
+            Ok(mut runtime) => {
+                let _ = runtime.block_on(run);
+            }
+            Err(e) => error!("Unable to start running rankers: {}", e),
+        }
+    });
+    if let Err(e) = started {
+        error!("Unable to start the ranker thread: {}", e);
+    }
+    sender
+}
+
+#[derive(Serialize)]
+struct RankRequest<'a> {
+    index: &'a str,
+    candidates: &'a [Candidate],
+}
+
+#[derive(Deserialize)]
+struct RankResponse {
+    scores: Vec<Score>,
+}
+
+/// A ranking service, posted `{ "index": ..., "candidates": [...] }` and answering with `{ "scores": [...] }`
+pub struct HttpRanker {
+    url: String,
+    timeout: Duration,
+    /// Kept for every ranking, so connections to the service are reused
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpRanker {
+    pub fn new(settings: &RankerSettings) -> Result<Self> {
+        let https = HttpsConnector::new(2).map_err(|e| Error::IOError(e.to_string()))?;
+        Ok(HttpRanker {
+            url: settings.url.clone(),
+            timeout: Duration::from_millis(settings.timeout),
+            client: Client::builder().build(https),
+        })
+    }
+}
+
+impl Ranker for HttpRanker {
+    fn rank(&self, index: &str, candidates: &[Candidate]) -> Result<Vec<Score>> {
+        let body = serde_json::to_string(&RankRequest { index, candidates })?;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .map_err(|e| Error::IOError(e.to_string()));
+        let client = self.client.clone();
+        let (url, timeout) = (self.url.clone(), self.timeout);
+        let slow = url.clone();
+        // Nothing is done until the ranking runs on the ranker thread, which is where the client's connections live
+        let ranking = future::result(request)
+            .and_then(move |request| client.request(request).map_err(|e| Error::IOError(e.to_string())))
+            .and_then(move |response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map_err(|e| Error::IOError(e.to_string()))
+                    .and_then(move |body| -> Result<Vec<Score>> {
+                        if !status.is_success() {
+                            return Err(Error::IOError(format!("Ranker {} answered {}", url, status)));
+                        }
+                        Ok(serde_json::from_slice::<RankResponse>(&body)?.scores)
+                    })
+            });
+        let (sender, receiver) = bounded(1);
+        let ranking = Timeout::new(ranking, timeout).then(move |ranked| {
+            let ranked = ranked.map_err(|e| {
+                e.into_inner()
+                    .unwrap_or_else(|| Error::IOError(format!("Ranker {} took longer than {:?}", slow, timeout)))
+            });
+            let _ = sender.send(ranked);
+            Ok(())
+        });
+        RANKINGS
+            .lock()?
+            .unbounded_send(Box::new(ranking))
+            .map_err(|_| Error::IOError("Rankers have stopped running".into()))?;
+        let scores = receiver
+            .recv()
+            .map_err(|_| Error::IOError(format!("Ranker {} was dropped before it answered", self.url)))??;
+        if scores.len() != candidates.len() {
+            return Err(Error::IOError(format!(
+                "Ranker {} gave {} scores for {} candidates",
+                self.url,
+                scores.len(),
+                candidates.len()
+            )));
+        }
+        Ok(scores)
+    }
+}
+
+/// The scores `ranker` gives `docs`, the top of a search of `index`, by segment ordinal and document. Each is given
+/// the score every one of `features` gives it and the values of its fast `fields`.
+pub fn rank(
+    ranker: &Ranker,
+    index: &str,
+    searcher: &Searcher,
+    features: &[(String, Box<TantivyQuery>)],
+    fields: &[Field],
+    docs: &[(Score, DocAddress)],
+) -> Result<BTreeMap<(u32, DocId), Score>> {
+    let scores = features
+        .iter()
+        .map(|(name, query)| Ok((name, query_scores(searcher, &**query, docs)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let candidates = docs
+        .iter()
+        .map(|(score, doc)| {
+            let key = (doc.segment_ord(), doc.doc());
+            Ok(Candidate {
+                score: *score,
+                features: scores
+                    .iter()
+                    .map(|(name, scores)| ((*name).clone(), scores.get(&key).cloned().unwrap_or(0.0)))
+                    .collect(),
+                fields: doc_values(searcher, *doc, fields)?,
+            })
+        })
+        .collect::<Result<Vec<Candidate>>>()?;
+    let ranked = ranker.rank(index, &candidates)?;
+    Ok(docs
+        .iter()
+        .zip(ranked)
+        .map(|((_, doc), score)| ((doc.segment_ord(), doc.doc()), score))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::raw_query;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{SchemaBuilder, FAST, STORED, TEXT};
+    use tantivy::{doc, Index};
+
+    /// Ranks candidates by the price they're given, and fails when there are none
+    struct PriceRanker;
+
+    impl Ranker for PriceRanker {
+        fn rank(&self, index: &str, candidates: &[Candidate]) -> Result<Vec<Score>> {
+            assert_eq!(index, "products");
+            if candidates.is_empty() {
+                return Err(Error::IOError("Nothing to rank".into()));
+            }
+            Ok(candidates
+                .iter()
+                .map(|c| c.fields["price"][0].as_u64().unwrap() as Score + c.features["cheap"])
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut builder = SchemaBuilder::new();
+        let text = builder.add_text_field("text", TEXT | STORED);
+        let price = builder.add_u64_field("price", FAST);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer_with_num_threads(1, 30_000_000).unwrap();
+        writer.add_document(doc!(text => "red shoe", price => 30u64));
+        writer.add_document(doc!(text => "red red shoe cheap", price => 10u64));
+        writer.add_document(doc!(text => "red hat", price => 20u64));
+        writer.commit().unwrap();
+        index.load_searchers().unwrap();
+        let searcher = index.searcher();
+        let schema = index.schema();
+
+        let rankers = Rankers::default();
+        rankers.register("price", Arc::new(PriceRanker));
+        assert!(rankers.get("other").is_err());
+
+        let query = raw_query(&schema, index.tokenizers(), "text:red", &[]).unwrap();
+        let docs = searcher.search(&*query, &TopDocs::with_limit(10)).unwrap();
+        let features = vec![(
+            "cheap".to_string(),
+            raw_query(&schema, index.tokenizers(), "text:cheap", &[]).unwrap(),
+        )];
+        let ranker = rankers.get("price").unwrap();
+        let scores = rank(&*ranker, "products", &searcher, &features, &[price], &docs).unwrap();
+        let mut ranked: Vec<(String, Score)> = docs
+            .iter()
+            .map(|(_, doc)| {
+                let words = searcher.doc(*doc).unwrap().get_first(text).unwrap().text().unwrap().to_string();
+                (words, scores[&(doc.segment_ord(), doc.doc())])
+            })
+            .collect();
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        assert_eq!(ranked[0], ("red shoe".to_string(), 30.0));
+        assert_eq!(ranked[2].0, "red red shoe cheap");
+        assert!(ranked[2].1 > 10.0);
+
+        assert!(rank(&*ranker, "products", &searcher, &features, &[price], &[]).is_err());
+    }
+
+    #[test]
+    fn test_http_ranker_unreachable() {
+        let settings = RankerSettings {
+            url: "http://127.0.0.1:1/rank".into(),
+            timeout: 2000,
+        };
+        let ranker = HttpRanker::new(&settings).unwrap();
+        let candidate = Candidate {
+            score: 1.0,
+            features: BTreeMap::new(),
+            fields: BTreeMap::new(),
+        };
+        assert!(ranker.rank("products", &[candidate]).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! Filling an index with documents pulled from somewhere else. A reindex runs as a task, reading its source a page
+//! at a time and indexing each page before the next is read, so a large source never has to fit in memory. The
+//! only source so far is an Elasticsearch or OpenSearch cluster, read through its scroll API, which makes moving an
+//! index over to Toshi a matter of creating the index with a matching schema and reindexing into it. A cancelled
+//! reindex stops before reading its next page.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use futures::{future, stream, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::{debug, info};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tower_web::Extract;
+
+use crate::handle::IndexHandle;
+use crate::handlers::index::AddDocument;
+use crate::index::IndexCatalog;
+use crate::tasks::Progress;
+use crate::{Error, Result};
+
+/// How long the source keeps a scroll open between pages
+const SCROLL_KEEP_ALIVE: &str = "5m";
+
+/// The body of `POST /:index/_reindex`
+#[derive(Extract, Deserialize, Clone, Debug)]
+pub struct ReindexRequest {
+    pub source: ReindexSource,
+    /// Which field of the target each field of the source goes into, by source field. Source fields can be nested
+    /// ones given as a dotted path. When empty, every source field of the same name as a target field is kept.
+    #[serde(default)]
+    pub mapping: HashMap<String, String>,
+}
+
+/// Where a reindex reads documents from, given by its `type`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReindexSource {
+    Elasticsearch(ElasticsearchSource),
+}
+
+/// An index of an Elasticsearch or OpenSearch cluster
+#[derive(Deserialize, Clone, Debug)]
+pub struct ElasticsearchSource {
+    /// The cluster's address, such as `http://elasticsearch:9200`
+    pub url: String,
+    pub index: String,
+    /// A query in the cluster's query DSL, only the documents it matches are reindexed
+    #[serde(default)]
+    pub query: Option<Value>,
+    /// How many documents are read at a time
+    #[serde(default = "ElasticsearchSource::default_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+impl ElasticsearchSource {
+    pub fn default_size() -> usize {
+        1000
+    }
+}
+
+#[derive(Deserialize)]
+struct ScrollResponse {
+    #[serde(rename = "_scroll_id")]
+    scroll_id: Option<String>,
+    hits: Hits,
+}
+
+#[derive(Deserialize)]
+struct Hits {
+    total: Total,
+    hits: Vec<Hit>,
+}
+
+/// Elasticsearch 7 and later give the total as an object, earlier versions as a number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Total {
+    Count(u64),
+    Object { value: u64 },
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    #[serde(rename = "_source", default)]
+    source: Map<String, Value>,
+}
+
+/// One page of documents read from a source
+struct Page {
+    scroll_id: Option<String>,
+    total: u64,
+    docs: Vec<Map<String, Value>>,
+}
+
+/// Reads every document of an Elasticsearch index a page at a time
+struct Scroller {
+    source: ElasticsearchSource,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Scroller {
+    fn new(source: ElasticsearchSource) -> Self {
+        let https = HttpsConnector::new(4).expect("Could not create TLS for Hyper");
+        Scroller {
+            source,
+            client: Client::builder().build(https),
+        }
+    }
+
+    fn call(&self, method: Method, path: &str, body: &Value) -> impl Future<Item = Vec<u8>, Error = Error> {
+        let mut request = Request::builder();
+        request
+            .method(method)
+            .uri(format!("{}{}", self.source.url.trim_end_matches('/'), path))
+            .header("content-type", "application/json");
+        if let Some(ref username) = self.source.username {
+            let credentials = format!("{}:{}", username, self.source.password.as_ref().map_or("", String::as_str));
+            request.header("authorization", format!("Basic {}", base64::encode(&credentials)).as_str());
+        }
+        let request = request
+            .body(Body::from(body.to_string()))
+            .map_err(|e| Error::IOError(e.to_string()));
+        let client = self.client.clone();
+        future::result(request)
+            .and_then(move |request| client.request(request).map_err(|e| Error::IOError(e.to_string())))
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .into_body()
+                    .concat2()
+                    .map_err(|e| Error::IOError(e.to_string()))
+                    .and_then(move |body| {
+                        if status.is_success() {
+                            Ok(body.to_vec())
+                        } else {
+                            Err(Error::IOError(format!(
+                                "Reindex source answered {}: {}",
+                                status,
+                                String::from_utf8_lossy(&body)
+                            )))
+                        }
+                    })
+            })
+    }
+
+    /// The first page of documents, or the one after the page that gave `scroll_id`
+    fn page(&self, scroll_id: Option<String>) -> impl Future<Item = Page, Error = Error> {
+        let response = match scroll_id {
+            None => {
+                let mut body = serde_json::json!({ "size": self.source.size, "sort": ["_doc"] });
+                if let Some(ref query) = self.source.query {
+                    body["query"] = query.clone();
+                }
+                let path = format!("/{}/_search?scroll={}", self.source.index, SCROLL_KEEP_ALIVE);
+                self.call(Method::POST, &path, &body)
+            }
+            Some(scroll_id) => {
+                let body = serde_json::json!({ "scroll": SCROLL_KEEP_ALIVE, "scroll_id": scroll_id });
+                self.call(Method::POST, "/_search/scroll", &body)
+            }
+        };
+        response.and_then(|body| {
+            let response: ScrollResponse = serde_json::from_slice(&body)?;
+            let total = match response.hits.total {
+                Total::Count(total) | Total::Object { value: total } => total,
+            };
+            Ok(Page {
+                scroll_id: response.scroll_id,
+                total,
+                docs: response.hits.hits.into_iter().map(|hit| hit.source).collect(),
+            })
+        })
+    }
+
+    /// Every page of the source, until one comes back empty
+    fn pages(scroller: Arc<Scroller>) -> impl Stream<Item = Page, Error = Error> {
+        stream::unfold(Some(None), move |next| {
+            let scroll_id = next?;
+            Some(scroller.page(scroll_id).map(|page| {
+                let next = match page.scroll_id {
+                    Some(ref id) if !page.docs.is_empty() => Some(Some(id.clone())),
+                    _ => None,
+                };
+                (page, next)
+            }))
+        })
+    }
+
+    /// Let the source free the scroll, which it would otherwise keep until it expires
+    fn clear(&self, scroll_id: String) -> impl Future<Item = (), Error = ()> {
+        let body = serde_json::json!({ "scroll_id": [scroll_id] });
+        self.call(Method::DELETE, "/_search/scroll", &body)
+            .map(|_| ())
+            .map_err(|e| debug!("Unable to clear the reindex scroll: {}", e))
+    }
+}
+
+/// Fill `index` with the documents of `request`'s source, reporting to `progress`. The target index must already
+/// exist, and is committed once every document has been indexed.
+pub fn reindex(
+    catalog: Arc<RwLock<IndexCatalog>>,
+    index: String,
+    request: ReindexRequest,
+    progress: Progress,
+) -> Box<Future<Item = (), Error = Error> + Send> {
+    let fields = match target_fields(&catalog, &index, &request.mapping) {
+        Ok(fields) => fields,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let ReindexRequest { source, mapping } = request;
+    let ReindexSource::Elasticsearch(source) = source;
+
+    let scroller = Arc::new(Scroller::new(source));
+    let clearing = Arc::clone(&scroller);
+    let (indexing, committing) = (Arc::clone(&catalog), catalog);
+    let target = index.clone();
+    let cancellation = progress.cancellation();
+    let reindexed = Scroller::pages(scroller)
+        .fold(None, move |_, page| -> Result<Option<String>> {
+            cancellation.check()?;
+            progress.expect_docs(page.total);
+            let (done, failed) = index_page(&indexing, &index, &mapping, &fields, page.docs)?;
+            progress.advance_docs(done, failed);
+            Ok(page.scroll_id)
+        })
+        .and_then(move |scroll_id| {
+            let cleared: Box<Future<Item = (), Error = ()> + Send> = match scroll_id {
+                Some(scroll_id) => Box::new(clearing.clear(scroll_id)),
+                None => Box::new(future::ok(())),
+            };
+            cleared.then(move |_| commit(&committing, &target))
+        });
+    Box::new(reindexed)
+}
+
+/// Check that `index` exists and has every field `request` maps documents into, before starting to reindex
+pub fn check(catalog: &RwLock<IndexCatalog>, index: &str, request: &ReindexRequest) -> Result<()> {
+    target_fields(catalog, index, &request.mapping).map(|_| ())
+}
+
+/// The fields of `index`, checking that every field `mapping` fills is one of them
+fn target_fields(catalog: &RwLock<IndexCatalog>, index: &str, mapping: &HashMap<String, String>) -> Result<HashSet<String>> {
+    let catalog = catalog.read()?;
+    let shards = catalog.shards(index)?;
+    let shard = shards.first().ok_or_else(|| Error::UnknownIndex(index.to_string()))?;
+    let fields: HashSet<String> = shard
+        .get_index()
+        .schema()
+        .fields()
+        .iter()
+        .map(|field| field.name().to_string())
+        .collect();
+    match mapping.values().find(|target| !fields.contains(*target)) {
+        Some(unknown) => Err(Error::UnknownIndexField(unknown.clone())),
+        None => Ok(fields),
+    }
+}
+
+/// Index a page of source documents, returning how many were indexed and how many were skipped for not fitting the
+/// target's schema
+fn index_page(
+    catalog: &RwLock<IndexCatalog>,
+    index: &str,
+    mapping: &HashMap<String, String>,
+    fields: &HashSet<String>,
+    docs: Vec<Map<String, Value>>,
+) -> Result<(u64, u64)> {
+    let catalog = catalog.read()?;
+    let (mut done, mut failed) = (0, 0);
+    for doc in docs {
+        let document = map_document(&doc, mapping, fields);
+        let add = AddDocument { options: None, document };
+        match catalog.route(index, &add.document)?.add_document(add) {
+            Ok(()) => done += 1,
+            Err(e) => {
+                debug!("Skipped a document reindexing {}: {}", index, e);
+                failed += 1;
+            }
+        }
+    }
+    Ok((done, failed))
+}
+
+fn commit(catalog: &RwLock<IndexCatalog>, index: &str) -> Result<()> {
+    for shard in catalog.read()?.shards(index)? {
+        shard.commit()?;
+    }
+    info!("Committed {} after reindexing", index);
+    Ok(())
+}
+
+/// The document `source` becomes in the target, following `mapping`, or keeping the fields the target has when
+/// there's no mapping
+pub fn map_document(source: &Map<String, Value>, mapping: &HashMap<String, String>, fields: &HashSet<String>) -> Value {
+    let mut document = Map::new();
+    if mapping.is_empty() {
+        for (name, value) in source {
+            if fields.contains(name) {
+                document.insert(name.clone(), value.clone());
+            }
+        }
+    } else {
+        for (from, to) in mapping {
+            if let Some(value) = lookup(source, from) {
+                document.insert(to.clone(), value.clone());
+            }
+        }
+    }
+    Value::Object(document)
+}
+
+/// The value at the dotted `path` in `source`, such as `user.name`
+fn lookup<'a>(source: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    if let Some(value) = source.get(path) {
+        return Some(value);
+    }
+    let mut parts = path.split('.');
+    let first = source.get(parts.next()?)?;
+    parts.try_fold(first, |value, part| value.get(part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_map_document() {
+        let source = json!({
+            "message": "disk full",
+            "host": { "name": "db-1" },
+            "level": "error",
+            "extra": 5
+        });
+        let source = source.as_object().unwrap();
+        let fields: HashSet<String> = vec!["message".to_string(), "hostname".to_string(), "level".to_string()]
+            .into_iter()
+            .collect();
+
+        let kept = map_document(source, &HashMap::new(), &fields);
+        assert_eq!(kept, json!({ "message": "disk full", "level": "error" }));
+
+        let mut mapping = HashMap::new();
+        mapping.insert("message".to_string(), "message".to_string());
+        mapping.insert("host.name".to_string(), "hostname".to_string());
+        mapping.insert("host.missing".to_string(), "level".to_string());
+        let mapped = map_document(source, &mapping, &fields);
+        assert_eq!(mapped, json!({ "message": "disk full", "hostname": "db-1" }));
+    }
+
+    #[test]
+    fn test_request() {
+        let request: ReindexRequest = serde_json::from_value(json!({
+            "source": { "type": "elasticsearch", "url": "http://es:9200", "index": "logs" },
+            "mapping": { "msg": "message" }
+        }))
+        .unwrap();
+        let ReindexSource::Elasticsearch(source) = request.source;
+        assert_eq!(source.size, ElasticsearchSource::default_size());
+        assert!(source.query.is_none());
+
+        let total: Total = serde_json::from_value(json!({ "value": 12, "relation": "eq" })).unwrap();
+        assert!(match total {
+            Total::Object { value } => value == 12,
+            Total::Count(_) => false,
+        });
+    }
+}
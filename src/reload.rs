@@ -0,0 +1,128 @@
+//! Reloading of settings while Toshi is running, triggered by SIGHUP or `POST /_settings/reload`.
+//!
+//! Subsystems that can pick up new values hold a `Watch<Settings>` and read it when they need
+//! a value, or listen on it for changes. Only the log level, auto commit duration and rate limits
+//! are taken from the reloaded file. Settings that are baked into the listener or the index
+//! writers, like the host, port, data path and body limits, still require a restart.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+use futures_watch::{Store, Watch};
+use log::{info, warn, LevelFilter};
+
+use crate::index::IndexCatalog;
+use crate::settings::{ConfigSource, Settings};
+use crate::{Error, Result};
+
+pub struct Reloader {
+    source: Option<ConfigSource>,
+    catalog: Arc<RwLock<IndexCatalog>>,
+    watch: Watch<Settings>,
+    store: Mutex<Store<Settings>>,
+}
+
+impl Reloader {
+    /// Build a reloader that re-reads the configuration file settings came from, publishing the initial settings right away
+    pub fn new(source: Option<ConfigSource>, catalog: Arc<RwLock<IndexCatalog>>, settings: Settings) -> Self {
+        let (watch, store) = Watch::new(settings);
+        Reloader {
+            source,
+            catalog,
+            watch,
+            store: Mutex::new(store),
+        }
+    }
+
+    /// A handle that always sees the most recently loaded settings
+    pub fn watch(&self) -> Watch<Settings> {
+        self.watch.clone()
+    }
+
+    /// Read the configuration file again and apply what can change at runtime, returning the settings now in effect.
+    /// A file that doesn't validate changes nothing.
+    pub fn reload(&self) -> Result<Settings> {
+        let source = match self.source {
+            Some(ref s) => s,
+            None => return Err(Error::IOError("Toshi was not started from a configuration file".into())),
+        };
+        let loaded = source
+            .load()
+            .map_err(|e| Error::IOError(format!("Invalid configuration file: {}", e)))?;
+        loaded
+            .validate()
+            .map_err(|errors| Error::IOError(format!("Invalid configuration file: {}", errors.join(", "))))?;
+
+        let mut settings = self.watch.borrow().clone();
+        settings.log_level = loaded.log_level;
+        settings.auto_commit_duration = loaded.auto_commit_duration;
+        settings.rate_limit = loaded.rate_limit;
+
+        if !set_log_level(&settings.log_level) {
+            warn!("log_level '{}' can only be applied on restart", settings.log_level);
+        }
+        self.catalog.write()?.settings = settings.clone();
+        self.store
+            .lock()?
+            .store(settings.clone())
+            .map_err(|_| Error::IOError("Unable to publish reloaded settings".into()))?;
+
+        info!("Reloaded settings from {}", source.path);
+        Ok(settings)
+    }
+}
+
+/// Change the global log level, returning false if `level` is a filter directive rather than a plain level
+pub fn set_log_level(level: &str) -> bool {
+    match level.parse::<LevelFilter>() {
+        Ok(filter) => {
+            log::set_max_level(filter);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use std::io::Write;
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("toshi-reload-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let catalog = create_test_catalog("test_index");
+        let reloader = Reloader::new(
+            Some(ConfigSource::new(path.to_str().unwrap(), None)),
+            Arc::clone(&catalog),
+            Settings::default(),
+        );
+        let watch = reloader.watch();
+        assert_eq!(watch.borrow().auto_commit_duration, 10);
+
+        let mut file = std::fs::File::create(&path).unwrap();
+        writeln!(file, "auto_commit_duration = 2\nport = 9200\n[rate_limit]\nenabled = true").unwrap();
+
+        reloader.reload().unwrap();
+        assert_eq!(watch.borrow().auto_commit_duration, 2);
+        assert!(watch.borrow().rate_limit.enabled);
+        assert_eq!(catalog.read().unwrap().settings.auto_commit_duration, 2);
+        // The port is bound once at startup, so it's left as it was
+        assert_eq!(watch.borrow().port, Settings::default().port);
+        assert_eq!(catalog.read().unwrap().settings.port, Settings::default().port);
+
+        // A file that doesn't validate changes nothing
+        std::fs::write(&path, "auto_commit_duration = 5\nindexing_threads = 0").unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(watch.borrow().auto_commit_duration, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_without_file() {
+        let reloader = Reloader::new(None, create_test_catalog("test_index"), Settings::default());
+        assert!(reloader.reload().is_err());
+    }
+}
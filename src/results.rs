@@ -1,4 +1,4 @@
-use crate::query::SummaryDoc;
+use crate::query::{CompositeBuckets, DateBucket, FilterBucket, GeoBucket, RangeBucket, SignificantBuckets, SummaryDoc};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use tantivy::schema::NamedFieldDocument;
@@ -10,6 +10,23 @@ pub struct SearchResults {
     pub hits: usize,
     pub docs: Vec<ScoredDoc>,
     pub aggregate: Option<Vec<SummaryDoc>>,
+    /// The buckets of a `date_histogram` aggregation, when one was asked for
+    pub buckets: Option<Vec<DateBucket>>,
+    /// The terms a `significant_terms` aggregation found, when one was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub significant_terms: Option<SignificantBuckets>,
+    /// The buckets of a `range` aggregation in the order of its ranges, when one was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranges: Option<Vec<RangeBucket>>,
+    /// The buckets of a `filters` aggregation by name, when one was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filters: Option<BTreeMap<String, FilterBucket>>,
+    /// The cells of a `geohash_grid` aggregation, when one was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geohash_grid: Option<Vec<GeoBucket>>,
+    /// A page of the buckets of a `composite` aggregation, when one was asked for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub composite: Option<CompositeBuckets>,
 }
 
 impl SearchResults {
@@ -18,6 +35,12 @@ impl SearchResults {
             hits: docs.len(),
             docs,
             aggregate: None,
+            buckets: None,
+            significant_terms: None,
+            ranges: None,
+            filters: None,
+            geohash_grid: None,
+            composite: None,
         }
     }
 
@@ -26,19 +49,87 @@ impl SearchResults {
             hits: docs.len(),
             docs,
             aggregate: Some(aggregate),
+            buckets: None,
+            significant_terms: None,
+            ranges: None,
+            filters: None,
+            geohash_grid: None,
+            composite: None,
         }
     }
+
+    pub fn with_buckets(mut self, buckets: Option<Vec<DateBucket>>) -> Self {
+        self.buckets = buckets;
+        self
+    }
+
+    pub fn with_significant_terms(mut self, significant_terms: Option<SignificantBuckets>) -> Self {
+        self.significant_terms = significant_terms;
+        self
+    }
+
+    pub fn with_ranges(mut self, ranges: Option<Vec<RangeBucket>>) -> Self {
+        self.ranges = ranges;
+        self
+    }
+
+    pub fn with_filters(mut self, filters: Option<BTreeMap<String, FilterBucket>>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    pub fn with_geohash_grid(mut self, geohash_grid: Option<Vec<GeoBucket>>) -> Self {
+        self.geohash_grid = geohash_grid;
+        self
+    }
+
+    pub fn with_composite(mut self, composite: Option<CompositeBuckets>) -> Self {
+        self.composite = composite;
+        self
+    }
+
+    /// These results with `docs` as their hits, keeping what they found for their aggregations
+    pub fn with_docs(mut self, docs: Vec<ScoredDoc>) -> Self {
+        self.hits = docs.len();
+        self.docs = docs;
+        self
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ScoredDoc {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub score: Option<f32>,
     pub doc: BTreeMap<String, Vec<Value>>,
+    /// The value results were sorted by, kept to merge the results of several shards or nodes in the same order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_key: Option<u64>,
+    /// The values of the fast fields the search asked for, by field
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, Vec<Value>>,
 }
 
 impl ScoredDoc {
     pub fn new(score: Option<f32>, doc: NamedFieldDocument) -> Self {
-        ScoredDoc { score, doc: doc.0 }
+        ScoredDoc {
+            score,
+            doc: doc.0,
+            sort_key: None,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn sorted(sort_key: u64, doc: NamedFieldDocument) -> Self {
+        ScoredDoc {
+            score: None,
+            doc: doc.0,
+            sort_key: Some(sort_key),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_fields(mut self, fields: BTreeMap<String, Vec<Value>>) -> Self {
+        self.fields = fields;
+        self
     }
 }
@@ -0,0 +1,182 @@
+//! Rollups summarize an index into another one on an interval, such as counting the errors of a log index by service
+//! every hour. Each run executes a SQL query, the same as `POST /_sql`, and adds every row of its results to the
+//! target index as a document holding the row's columns, then commits the target. The target index has to exist
+//! already, with a field for each column that should be kept; columns it has no field for are left out.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{future, Future, Stream};
+use log::{debug, error, info};
+use serde_json::{Map, Value};
+use tantivy::schema::{FieldType, Schema};
+use tokio::timer::Interval;
+
+use crate::handlers::index::AddDocument;
+use crate::handlers::SqlHandler;
+use crate::index::IndexCatalog;
+use crate::settings::Rollup;
+use crate::sql::Table;
+use crate::{Error, Result};
+
+/// Runs every rollup on its interval
+pub struct Rollups {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    sql: SqlHandler,
+    rollups: Vec<Rollup>,
+}
+
+impl Rollups {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, sql: SqlHandler, rollups: &[Rollup]) -> Self {
+        Rollups {
+            catalog,
+            sql,
+            rollups: rollups.to_vec(),
+        }
+    }
+
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        for rollup in self.rollups.iter().cloned() {
+            let (catalog, sql) = (Arc::clone(&self.catalog), self.sql.clone());
+            let name = rollup.name.clone();
+            let first = Instant::now() + Duration::from_secs(rollup.interval);
+            let runs = Interval::new(first, Duration::from_secs(rollup.interval))
+                .map_err(move |e| error!("Rollup {} timer failed: {}", name, e))
+                .for_each(move |_| {
+                    let (name, target) = (rollup.name.clone(), rollup.target.clone());
+                    roll_up(Arc::clone(&catalog), &sql, rollup.clone()).then(move |rolled| {
+                        match rolled {
+                            Ok(docs) => info!("Rollup {} added {} documents to {}", name, docs, target),
+                            Err(e) => error!("Rollup {} failed: {}", name, e),
+                        }
+                        Ok::<(), ()>(())
+                    })
+                });
+            tokio::spawn(runs);
+        }
+        future::ok(())
+    }
+}
+
+/// Run `rollup` once, returning how many documents it added to its target
+pub fn roll_up(catalog: Arc<RwLock<IndexCatalog>>, sql: &SqlHandler, rollup: Rollup) -> impl Future<Item = u64, Error = Error> {
+    sql.run(&rollup.query).and_then(move |table| {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let catalog = catalog.read()?;
+        let schema = match catalog.shards(&rollup.target)?.first() {
+            Some(shard) => shard.get_index().schema(),
+            None => return Err(Error::UnknownIndex(rollup.target.clone())),
+        };
+        let mut added = 0;
+        for document in documents(&rollup, &schema, &table, time) {
+            let add = AddDocument { options: None, document };
+            match catalog.route(&rollup.target, &add.document)?.add_document(add) {
+                Ok(()) => added += 1,
+                Err(e) => debug!("Skipped a row of rollup {}: {}", rollup.name, e),
+            }
+        }
+        for shard in catalog.shards(&rollup.target)? {
+            shard.commit()?;
+        }
+        Ok(added)
+    })
+}
+
+/// The documents the rows of `table` become in an index with `schema`, stamped with `time` when `rollup` has a
+/// timestamp field
+pub fn documents(rollup: &Rollup, schema: &Schema, table: &Table, time: u64) -> Vec<Value> {
+    table
+        .rows
+        .iter()
+        .map(|row| {
+            let mut document = Map::new();
+            for (column, value) in table.columns.iter().zip(row) {
+                if let Some(value) = convert(schema, column, value) {
+                    document.insert(column.clone(), value);
+                }
+            }
+            if let Some(ref field) = rollup.timestamp_field {
+                document.insert(field.clone(), time.into());
+            }
+            Value::Object(document)
+        })
+        .collect()
+}
+
+/// `value` as the type of `field` takes it. Aggregates are computed as floats, which integer fields can't hold, so
+/// they're rounded.
+fn convert(schema: &Schema, field: &str, value: &Value) -> Option<Value> {
+    let entry = schema.get_field_entry(schema.get_field(field)?);
+    match (entry.field_type(), value) {
+        (_, Value::Null) => None,
+        (FieldType::U64(_), Value::Number(n)) => n
+            .as_u64()
+            .or_else(|| n.as_f64().map(|f| f.max(0.0).round() as u64))
+            .map(Value::from),
+        (FieldType::I64(_), Value::Number(n)) => n.as_i64().or_else(|| n.as_f64().map(|f| f.round() as i64)).map(Value::from),
+        (FieldType::Str(_), Value::String(_)) => Some(value.clone()),
+        (FieldType::Str(_), other) => Some(other.to_string().into()),
+        _ => Some(value.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::SearchHandler;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    fn rollup(query: &str) -> Rollup {
+        Rollup {
+            name: "documents".into(),
+            query: query.into(),
+            target: "test_index".into(),
+            interval: 3600,
+            timestamp_field: Some("test_u64".into()),
+        }
+    }
+
+    #[test]
+    fn test_documents() {
+        let catalog = create_test_catalog("test_index");
+        let schema = catalog.read().unwrap().shards("test_index").unwrap()[0].get_index().schema();
+        let table = Table {
+            columns: vec!["test_text".into(), "test_i64".into(), "missing".into()],
+            rows: vec![vec![json!("errors"), json!(12.6), json!(1)], vec![json!(7), Value::Null, json!(2)]],
+        };
+        let docs = documents(&rollup(""), &schema, &table, 1_550_000_000);
+        assert_eq!(
+            docs[0],
+            json!({ "test_text": "errors", "test_i64": 13, "test_u64": 1_550_000_000u64 })
+        );
+        assert_eq!(docs[1], json!({ "test_text": "7", "test_u64": 1_550_000_000u64 }));
+    }
+
+    #[test]
+    fn test_roll_up() {
+        let catalog = create_test_catalog("test_index");
+        let sql = SqlHandler::new(Arc::clone(&catalog), SearchHandler::new(Arc::clone(&catalog)));
+        let query = "SELECT SUM(test_i64) AS test_i64 FROM test_index WHERE MATCH(test_text, 'document')";
+        assert_eq!(roll_up(Arc::clone(&catalog), &sql, rollup(query)).wait().unwrap(), 1);
+
+        let table = sql
+            .run("SELECT test_text, test_i64 FROM test_index WHERE test_i64 = 2015")
+            .wait()
+            .unwrap();
+        assert_eq!(table.rows, vec![vec![Value::Null, json!(2015)]]);
+        assert!(roll_up(
+            catalog,
+            &sql,
+            Rollup {
+                target: "missing".into(),
+                ..rollup(query)
+            }
+        )
+        .wait()
+        .is_err());
+    }
+}
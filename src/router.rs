@@ -1,57 +1,311 @@
+use std::error::Error as StdError;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use flate2::Compression;
 use http::response::Builder;
-use http::Request;
-use log::info;
-use tokio::net::TcpListener;
+use http::{Request, Response};
+use hyper::service::{make_service_fn, Service};
+use hyper::{Body, Server};
+use log::{error, info};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
-use tower_web::middleware::deflate::DeflateMiddleware;
 use tower_web::middleware::log::LogMiddleware;
+use tower_web::util::http::{HttpService, NewHttpService};
+use tower_web::util::BufStream;
 use tower_web::Error as TowerError;
 use tower_web::ServiceBuilder;
 
+use crate::alert::Alerter;
+use crate::cluster::remote_cluster::RemoteClusters;
+use crate::cluster::replication::Replicator;
+use crate::cluster::routing::Routing;
+use crate::executor::Executors;
+use crate::grpc::GrpcServer;
 use crate::handlers::*;
+use crate::ilm::LifecycleScheduler;
 use crate::index::IndexCatalog;
-use crate::settings::VERSION;
+use crate::lifecycle::Lifecycle;
+use crate::middleware::{
+    cors_middleware, Admission, CompressionMiddleware, DrainMiddleware, Endpoint, RateLimitMiddleware, RateLimiter, RequestBodyMiddleware,
+};
+use crate::reload::Reloader;
+use crate::rollup::Rollups;
+use crate::settings::{Settings, VERSION};
+use crate::snapshot;
+use crate::tasks::Tasks;
+use crate::websocket::StreamingServer;
 
-pub fn router_with_catalog(addr: &SocketAddr, catalog: &Arc<RwLock<IndexCatalog>>) -> Box<Future<Item = (), Error = ()> + Send> {
-    let search_handler = SearchHandler::new(Arc::clone(catalog));
-    let index_handler = IndexHandler::new(Arc::clone(catalog));
-    let bulk_handler = BulkHandler::new(Arc::clone(catalog));
+pub fn router_with_catalog(
+    addr: &SocketAddr,
+    catalog: &Arc<RwLock<IndexCatalog>>,
+    lifecycle: &Arc<Lifecycle>,
+    reloader: &Arc<Reloader>,
+    executors: &Executors,
+) -> io::Result<Box<Future<Item = (), Error = ()> + Send>> {
+    let settings = catalog.read().unwrap().settings.clone();
+    let listener = bind(addr)?;
+    // Every listener shares the same limits, a client can't get around them by switching APIs
+    let rate_limiter = Arc::new(RateLimiter::new(settings.rate_limit.clone()));
+    tokio::spawn(RateLimiter::follow(&rate_limiter, reloader.watch()));
+    tokio::spawn(RateLimiter::sweep(&rate_limiter));
+    let admission = Admission::new(lifecycle, &rate_limiter);
+    let tasks = Tasks::new(format!("{}:{}", settings.host, settings.port));
+    let search_handler = SearchHandler::with_executor(Arc::clone(catalog), executors.search.clone())
+        .with_remote_clusters(RemoteClusters::new(&settings.remote_clusters))
+        .with_routing(Routing::new(&settings.replicas))
+        .with_tasks(tasks.clone());
+    // Replicas that are shipped segments don't take writes
+    let replicator = if settings.replication.ships_segments() {
+        Replicator::default()
+    } else {
+        Replicator::new(&settings.replicas)
+    };
+    let index_handler = IndexHandler::new(Arc::clone(catalog)).with_replicator(replicator.clone());
+    let bulk_handler = BulkHandler::with_executor(Arc::clone(catalog), executors.indexing.clone()).with_replicator(replicator);
     let summary_handler = SummaryHandler::new(Arc::clone(catalog));
+    let changes_handler = ChangesHandler::new(Arc::clone(catalog));
+    let percolator_handler = PercolatorHandler::new(Arc::clone(catalog));
+    let analyze_handler = AnalyzeHandler::new(Arc::clone(catalog));
+    let snapshot_handler = SnapshotHandler::new(Arc::clone(catalog), snapshot::repository(&settings)).with_tasks(tasks.clone());
+    let reindex_handler = ReindexHandler::new(Arc::clone(catalog), tasks.clone());
+    let sql_handler = SqlHandler::new(Arc::clone(catalog), search_handler.clone());
+    let template_handler = TemplateHandler::new(Arc::clone(catalog), search_handler.clone());
+    let async_search_handler = AsyncSearchHandler::new(search_handler.clone());
+    let graphql_handler = GraphqlHandler::new(Arc::clone(catalog), search_handler.clone(), settings.graphql.enabled);
+    let task_handler = TaskHandler::new(tasks.clone());
     let root_handler = RootHandler::new(VERSION);
-    let listener = TcpListener::bind(addr).unwrap().incoming();
+    let health_handler = HealthHandler::new(Arc::clone(lifecycle));
+    let reload_handler = ReloadHandler::new(Arc::clone(reloader));
+    let drain_handler = DrainHandler::new(
+        Arc::clone(catalog),
+        Arc::clone(lifecycle),
+        Duration::from_secs(settings.drain_timeout),
+    );
+    if settings.elasticsearch.enabled {
+        let es_addr = SocketAddr::new(addr.ip(), settings.elasticsearch.port);
+        let handler = ElasticsearchHandler::new(
+            Arc::clone(catalog),
+            search_handler.clone(),
+            index_handler.clone(),
+            settings.elasticsearch.id_field.clone(),
+        );
+        tokio::spawn(elasticsearch_router(&es_addr, handler, &settings, lifecycle, &rate_limiter)?);
+    }
+    if settings.grpc.enabled {
+        let grpc = GrpcServer::new(
+            Arc::clone(catalog),
+            search_handler.clone(),
+            index_handler.clone(),
+            bulk_handler.clone(),
+        );
+        let grpc_listener = bind(&SocketAddr::new(addr.ip(), settings.grpc.port))?;
+        tokio::spawn(grpc.serve(grpc_listener, admission.clone()));
+    }
+    if settings.websocket.enabled {
+        let streaming = StreamingServer::new(Arc::clone(catalog), search_handler.clone(), settings.websocket.clone());
+        let websocket_listener = bind(&SocketAddr::new(addr.ip(), settings.websocket.port))?;
+        tokio::spawn(streaming.serve(websocket_listener, admission, settings.cors.clone()));
+    }
+    if !settings.alerts.is_empty() {
+        tokio::spawn(Alerter::new(search_handler.clone(), &settings.alerts).run());
+    }
+    if !settings.rollups.is_empty() {
+        tokio::spawn(Rollups::new(Arc::clone(catalog), sql_handler.clone(), &settings.rollups).run());
+    }
+    // Lifecycle policies run on every node, over the indexes it holds
+    if !settings.lifecycle_policies.is_empty() {
+        let scheduler = LifecycleScheduler::new(Arc::clone(catalog), tasks, &settings)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Unable to start lifecycle policies: {}", e)))?;
+        tokio::spawn(scheduler.run());
+    }
 
+    // Admin resources and `GET /_list` go first so their paths aren't taken for index names by the search resource
     let router = ServiceBuilder::new()
-        .resource(search_handler)
+        .resource(drain_handler)
+        .resource(health_handler)
+        .resource(reload_handler)
+        .resource(task_handler)
+        .resource(sql_handler)
+        .resource(template_handler)
+        .resource(async_search_handler)
+        .resource(graphql_handler)
+        .resource(percolator_handler)
+        .resource(analyze_handler)
         .resource(index_handler)
+        .resource(search_handler)
         .resource(bulk_handler)
         .resource(summary_handler)
+        .resource(changes_handler)
+        .resource(snapshot_handler)
+        .resource(reindex_handler)
         .resource(root_handler)
         .middleware(LogMiddleware::new("toshi"))
-        .middleware(DeflateMiddleware::new(Compression::fast()))
-        .catch(|request: &Request<()>, error: TowerError| {
-            info!("{:?}", error);
-            let err_msg = ErrorResponse::new(error.to_string(), request.uri().path().into());
-            let json = serde_json::to_string(&err_msg).unwrap();
-
-            let (status, body) = match error.kind() {
-                e if e.is_not_found() => (404, json),
-                e if e.is_bad_request() => (400, json),
-                e if e.is_internal() => (500, json),
-                _ => (404, json),
-            };
-            let response = Builder::new()
-                .header("content-type", "application/json")
-                .status(status)
-                .body(body)
-                .unwrap();
-
-            Ok(response)
-        })
-        .serve(listener);
-
-    Box::new(router)
+        .middleware(DrainMiddleware::new(Arc::clone(lifecycle)))
+        .middleware(RequestBodyMiddleware::new(settings.body_limits))
+        .middleware(RateLimitMiddleware::new(rate_limiter));
+    let catch = |request: &Request<()>, error: TowerError| {
+        info!("{:?}", error);
+        let err_msg = ErrorResponse::new(error.to_string(), request.uri().path().into());
+        let json = serde_json::to_string(&err_msg).unwrap();
+
+        let (status, body) = match error.kind() {
+            e if e.is_not_found() => (404, json),
+            e if e.is_bad_request() => (400, json),
+            e if e.is_internal() => (500, json),
+            _ => (404, json),
+        };
+        let response = Builder::new()
+            .header("content-type", "application/json")
+            .status(status)
+            .body(body)
+            .unwrap();
+
+        Ok(response)
+    };
+
+    // Without CORS there's no middleware for it at all, so requests that carry an `Origin` are served like any other
+    let compression = CompressionMiddleware::new(settings.compression);
+    let served: Box<Future<Item = (), Error = ()> + Send> = match cors_middleware(&settings.cors) {
+        Some(cors) => Box::new(serve(
+            listener,
+            router.middleware(cors).middleware(compression).catch(catch).build_new_service(),
+        )),
+        None => Box::new(serve(listener, router.middleware(compression).catch(catch).build_new_service())),
+    };
+    Ok(served)
+}
+
+/// Serve the Elasticsearch compatible API on `addr`, answering errors the way Elasticsearch does. Requests go
+/// through the same middleware as the main router's, sharing its rate limits.
+pub fn elasticsearch_router(
+    addr: &SocketAddr,
+    handler: ElasticsearchHandler,
+    settings: &Settings,
+    lifecycle: &Arc<Lifecycle>,
+    rate_limiter: &Arc<RateLimiter>,
+) -> io::Result<Box<Future<Item = (), Error = ()> + Send>> {
+    let listener = bind(addr)?;
+    info!("Serving the Elasticsearch compatible API on {}", addr);
+    let router = ServiceBuilder::new()
+        .resource(handler)
+        .middleware(LogMiddleware::new("toshi::elasticsearch"))
+        .middleware(DrainMiddleware::new(Arc::clone(lifecycle)))
+        .middleware(RequestBodyMiddleware::new(settings.body_limits.clone()).classifying(Endpoint::elasticsearch))
+        .middleware(RateLimitMiddleware::new(Arc::clone(rate_limiter)).classifying(Endpoint::elasticsearch));
+    let catch = |_: &Request<()>, error: TowerError| {
+        let status = match error.kind() {
+            e if e.is_not_found() => 404,
+            e if e.is_bad_request() => 400,
+            _ => 500,
+        };
+        let body = serde_json::json!({
+            "error": { "type": "toshi_exception", "reason": error.to_string() },
+            "status": status
+        });
+        let response = Builder::new()
+            .header("content-type", "application/json")
+            .status(status)
+            .body(body.to_string())
+            .unwrap();
+
+        Ok(response)
+    };
+
+    let compression = CompressionMiddleware::new(settings.compression.clone());
+    let served: Box<Future<Item = (), Error = ()> + Send> = match cors_middleware(&settings.cors) {
+        Some(cors) => Box::new(serve(
+            listener,
+            router.middleware(cors).middleware(compression).catch(catch).build_new_service(),
+        )),
+        None => Box::new(serve(listener, router.middleware(compression).catch(catch).build_new_service())),
+    };
+    Ok(served)
+}
+
+/// Bind a listener to `addr`, saying which address it was when that fails
+fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).map_err(|e| io::Error::new(e.kind(), format!("Unable to listen on {}: {}", addr, e)))
+}
+
+/// Serve `new_service` on `listener`, putting the address of the client on the other end of each connection in the
+/// extensions of every request that comes over it
+pub fn serve<T>(listener: TcpListener, new_service: T) -> impl Future<Item = (), Error = ()> + Send
+where
+    T: NewHttpService<RequestBody = Body> + Send + Sync + 'static,
+    T::Future: Send + 'static,
+    T::Service: Send + 'static,
+    <T::Service as HttpService>::Future: Send + 'static,
+    T::ResponseBody: Send + 'static,
+    <T::ResponseBody as BufStream>::Item: Send,
+    <T::ResponseBody as BufStream>::Error: Into<Box<StdError + Send + Sync>>,
+    T::Error: Into<Box<StdError + Send + Sync>>,
+{
+    let make_service = make_service_fn(move |connection: &TcpStream| {
+        let peer = connection.peer_addr().ok();
+        new_service
+            .new_http_service()
+            .map(move |inner| Connection { inner, peer })
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to build the router"))
+    });
+    Server::builder(listener.incoming())
+        .serve(make_service)
+        .map_err(|e| error!("Failed to serve HTTP: {}", e))
+}
+
+/// The router serving a single connection
+struct Connection<S> {
+    inner: S,
+    /// Where the connection comes from
+    peer: Option<SocketAddr>,
+}
+
+impl<S> Service for Connection<S>
+where
+    S: HttpService<RequestBody = Body>,
+    S::Future: Send + 'static,
+    S::ResponseBody: Send + 'static,
+    <S::ResponseBody as BufStream>::Item: Send,
+    <S::ResponseBody as BufStream>::Error: Into<Box<StdError + Send + Sync>>,
+    S::Error: Into<Box<StdError + Send + Sync>>,
+{
+    type ReqBody = Body;
+    type ResBody = Payload<S::ResponseBody>;
+    type Error = S::Error;
+    type Future = future::Map<S::Future, fn(Response<S::ResponseBody>) -> Response<Payload<S::ResponseBody>>>;
+
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_http_ready()
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        if let Some(peer) = self.peer {
+            request.extensions_mut().insert(peer);
+        }
+        self.inner.call_http(request).map(Payload::response as fn(_) -> _)
+    }
+}
+
+/// A response body as hyper sends it
+struct Payload<B>(B);
+
+impl<B> Payload<B> {
+    fn response(response: Response<B>) -> Response<Payload<B>> {
+        response.map(Payload)
+    }
+}
+
+impl<B> hyper::body::Payload for Payload<B>
+where
+    B: BufStream + Send + 'static,
+    B::Item: Send,
+    B::Error: Into<Box<StdError + Send + Sync>>,
+{
+    type Data = B::Item;
+    type Error = B::Error;
+
+    fn poll_data(&mut self) -> Poll<Option<Self::Data>, Self::Error> {
+        self.0.poll()
+    }
 }
@@ -1,13 +1,24 @@
 use clap::ArgMatches;
-use config::{Config, ConfigError, File, FileFormat, Source};
+use config::{Config, ConfigError, File, FileFormat, Source, Value};
 use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use tantivy::merge_policy::*;
 
+use crate::analysis::{self, Analyzer};
+use crate::query::{runtime_script, Request, RuntimeField, RuntimeFields, Similarities, TextSimilarity};
+use crate::snapshot::schedule::Schedule;
+use crate::sql::Statement;
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Prefix of the environment variables that override settings, e.g. `TOSHI_PORT`
+pub const ENV_PREFIX: &str = "TOSHI";
+
 pub const HEADER: &str = r#"
   ______         __   _   ____                 __
  /_  __/__  ___ / /  (_) / __/__ ___ _________/ /
@@ -48,30 +59,678 @@ impl ConfigMergePolicy {
     }
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateLimitSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "RateLimitSettings::default_key_header")]
+    pub key_header: String,
+    /// The keys handed out to clients, a client sending any other key is told apart by its address
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    #[serde(default = "RateLimitSettings::default_search_rate")]
+    pub search_per_second: f64,
+    #[serde(default = "RateLimitSettings::default_search_burst")]
+    pub search_burst: f64,
+    #[serde(default = "RateLimitSettings::default_bulk_rate")]
+    pub bulk_per_second: f64,
+    #[serde(default = "RateLimitSettings::default_bulk_burst")]
+    pub bulk_burst: f64,
+    /// Proxies whose `X-Forwarded-For` is taken for the address of the client
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimitSettings {
+    pub fn default_key_header() -> String {
+        "x-api-key".to_string()
+    }
+
+    pub fn default_search_rate() -> f64 {
+        100.0
+    }
+
+    pub fn default_search_burst() -> f64 {
+        200.0
+    }
+
+    pub fn default_bulk_rate() -> f64 {
+        5.0
+    }
+
+    pub fn default_bulk_burst() -> f64 {
+        10.0
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BodyLimitSettings {
+    #[serde(default = "BodyLimitSettings::default_max")]
+    pub default: usize,
+    #[serde(default = "BodyLimitSettings::default_index_max")]
+    pub index: usize,
+    #[serde(default = "BodyLimitSettings::default_bulk_max")]
+    pub bulk: usize,
+}
+
+impl BodyLimitSettings {
+    pub fn default_max() -> usize {
+        1_048_576
+    }
+
+    pub fn default_index_max() -> usize {
+        10_485_760
+    }
+
+    pub fn default_bulk_max() -> usize {
+        268_435_456
+    }
+}
+
+/// How much memory searches can take for their hits and aggregation buckets, where 0 is no limit
+#[derive(Deserialize, Clone, Debug)]
+pub struct CircuitBreakerSettings {
+    /// What a single search of an index can take
+    #[serde(default = "CircuitBreakerSettings::default_request_bytes")]
+    pub request_bytes: usize,
+    /// What every search running at once can take between them
+    #[serde(default = "CircuitBreakerSettings::default_total_bytes")]
+    pub total_bytes: usize,
+}
+
+impl CircuitBreakerSettings {
+    pub fn default_request_bytes() -> usize {
+        268_435_456
+    }
+
+    pub fn default_total_bytes() -> usize {
+        1_073_741_824
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CompressionSettings {
+    #[serde(default = "CompressionSettings::default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "CompressionSettings::default_min_size")]
+    pub min_size: usize,
+    #[serde(default = "CompressionSettings::default_level")]
+    pub level: u32,
+}
+
+impl CompressionSettings {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn default_min_size() -> usize {
+        1024
+    }
+
+    pub fn default_level() -> u32 {
+        6
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CorsSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "CorsSettings::default_allowed_origins")]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "CorsSettings::default_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "CorsSettings::default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    #[serde(default = "CorsSettings::default_max_age")]
+    pub max_age: u64,
+}
+
+impl CorsSettings {
+    pub fn default_allowed_origins() -> Vec<String> {
+        vec!["*".to_string()]
+    }
+
+    pub fn default_allowed_methods() -> Vec<String> {
+        vec!["GET", "POST", "PUT", "DELETE"].into_iter().map(String::from).collect()
+    }
+
+    pub fn default_allowed_headers() -> Vec<String> {
+        vec!["content-type".to_string()]
+    }
+
+    pub fn default_max_age() -> u64 {
+        3600
+    }
+}
+
+/// A second listener answering a subset of the Elasticsearch API, for clients and shippers that only speak it
+#[derive(Deserialize, Clone, Debug)]
+pub struct ElasticsearchSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "ElasticsearchSettings::default_port")]
+    pub port: u16,
+    /// The field documents' `_id`s are kept in, which needs to be a `STRING` text field to look documents up by
+    #[serde(default = "ElasticsearchSettings::default_id_field")]
+    pub id_field: String,
+}
+
+impl ElasticsearchSettings {
+    pub fn default_port() -> u16 {
+        9200
+    }
+
+    pub fn default_id_field() -> String {
+        "doc_id".to_string()
+    }
+}
+
+/// The public gRPC API defined in `proto/toshi.proto`, served on a port of its own
+#[derive(Deserialize, Clone, Debug)]
+pub struct GrpcSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "GrpcSettings::default_port")]
+    pub port: u16,
+}
+
+impl GrpcSettings {
+    pub fn default_port() -> u16 {
+        8083
+    }
+}
+
+/// Searches streamed over WebSockets, on a port of their own
+#[derive(Deserialize, Clone, Debug)]
+pub struct WebsocketSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "WebsocketSettings::default_port")]
+    pub port: u16,
+    /// How many documents each message holds
+    #[serde(default = "WebsocketSettings::default_batch_size")]
+    pub batch_size: usize,
+    /// How often, in milliseconds, followed searches check their indexes for new commits
+    #[serde(default = "WebsocketSettings::default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+impl WebsocketSettings {
+    pub fn default_port() -> u16 {
+        8084
+    }
+
+    pub fn default_batch_size() -> usize {
+        100
+    }
+
+    pub fn default_poll_interval() -> u64 {
+        1000
+    }
+}
+
+/// A GraphQL endpoint at `/graphql`, with a schema generated from the indexes' schemas
+#[derive(Deserialize, Clone, Debug)]
+pub struct GraphqlSettings {
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+/// How nodes find each other when `metadata_store` is `kubernetes`
+#[derive(Deserialize, Clone, Debug)]
+pub struct KubernetesSettings {
+    /// A headless service to resolve for pod addresses, instead of listing pods through the API server
+    #[serde(default)]
+    pub service: Option<String>,
+    /// The namespace to list pods in, by default the one this pod runs in
+    #[serde(default)]
+    pub namespace: Option<String>,
+    #[serde(default = "KubernetesSettings::default_label_selector")]
+    pub label_selector: String,
+    #[serde(default = "KubernetesSettings::default_port")]
+    pub port: u16,
+}
+
+impl KubernetesSettings {
+    pub fn default_label_selector() -> String {
+        "app=toshi".to_string()
+    }
+
+    pub fn default_port() -> u16 {
+        8080
+    }
+}
+
+/// Failure detection between nodes, gossiped over UDP on each node's port
+#[derive(Deserialize, Clone, Debug)]
+pub struct GossipSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How often each node probes another
+    #[serde(default = "GossipSettings::default_probe_interval_ms")]
+    pub probe_interval_ms: u64,
+    /// How long to wait for a probe to be answered before asking other nodes to try
+    #[serde(default = "GossipSettings::default_probe_timeout_ms")]
+    pub probe_timeout_ms: u64,
+    /// How long a node that missed its probes has to refute the suspicion before it's declared dead
+    #[serde(default = "GossipSettings::default_suspect_timeout_ms")]
+    pub suspect_timeout_ms: u64,
+    /// How many other nodes are asked to probe one that didn't answer
+    #[serde(default = "GossipSettings::default_indirect_checks")]
+    pub indirect_checks: usize,
+}
+
+impl GossipSettings {
+    pub fn default_probe_interval_ms() -> u64 {
+        1000
+    }
+
+    pub fn default_probe_timeout_ms() -> u64 {
+        500
+    }
+
+    pub fn default_suspect_timeout_ms() -> u64 {
+        3000
+    }
+
+    pub fn default_indirect_checks() -> usize {
+        3
+    }
+}
+
+/// Moving indexes from the busiest data node to the least busy one, done by the master
+#[derive(Deserialize, Clone, Debug)]
+pub struct RebalanceSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How often the load on the data nodes is compared
+    #[serde(default = "RebalanceSettings::default_interval_secs")]
+    pub interval_secs: u64,
+    /// How much less than the busiest node the least busy can hold, as a fraction of the busiest's load
+    #[serde(default = "RebalanceSettings::default_threshold")]
+    pub threshold: f64,
+    /// What a node's load is measured by, either `bytes` on disk or `docs`
+    #[serde(default = "RebalanceSettings::default_measure")]
+    pub measure: String,
+}
+
+impl RebalanceSettings {
+    pub fn default_interval_secs() -> u64 {
+        60
+    }
+
+    pub fn default_threshold() -> f64 {
+        0.2
+    }
+
+    pub fn default_measure() -> String {
+        "bytes".to_string()
+    }
+}
+
+/// How a master keeps its `replicas` up to date
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReplicationSettings {
+    /// `document` to forward every write to the replicas, or `segment` to ship them committed segments instead
+    #[serde(default = "ReplicationSettings::default_mode")]
+    pub mode: String,
+    /// How often new commits are shipped in segment mode
+    #[serde(default = "ReplicationSettings::default_ship_interval_secs")]
+    pub ship_interval_secs: u64,
+    /// Where the master serves its segments to replicas in segment mode, which must be reachable by them
+    #[serde(default = "ReplicationSettings::default_ship_addr")]
+    pub ship_addr: String,
+}
+
+impl ReplicationSettings {
+    pub fn default_mode() -> String {
+        "document".to_string()
+    }
+
+    pub fn default_ship_interval_secs() -> u64 {
+        5
+    }
+
+    pub fn default_ship_addr() -> String {
+        "127.0.0.1:8081".to_string()
+    }
+
+    pub fn ships_segments(&self) -> bool {
+        self.mode == "segment"
+    }
+}
+
+/// Snapshots taken on a schedule, see `Settings::snapshot_policies`
+#[derive(Deserialize, Clone, Debug)]
+pub struct SnapshotPolicy {
+    /// What the policy's snapshots are named after, each being `<name>-<unix time>`
+    pub name: String,
+    /// When to take snapshots, as a cron expression in UTC
+    pub schedule: String,
+    /// The indexes to snapshot, every index when empty
+    #[serde(default)]
+    pub indexes: Vec<String>,
+    /// How many of the policy's snapshots of each index to keep, the newest ones
+    #[serde(default)]
+    pub keep: Option<usize>,
+    /// How many days to keep the policy's snapshots for
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+}
+
+impl SnapshotPolicy {
+    /// What the names of the policy's snapshots start with
+    pub fn snapshot_prefix(&self) -> String {
+        format!("{}-", self.name)
+    }
+}
+
+/// Indexes moved on through the phases of their life as they age or grow, see `Settings::lifecycle_policies`
+#[derive(Deserialize, Clone, Debug)]
+pub struct LifecyclePolicy {
+    pub name: String,
+    /// The indexes the policy manages, by name or by a pattern such as `logs-*`, which indexes created later match too
+    #[serde(default)]
+    pub indexes: Vec<String>,
+    /// How many days after it's created an index stops taking writes
+    #[serde(default)]
+    pub read_only_after_days: Option<u64>,
+    /// How many bytes an index can grow to before it stops taking writes, if it does before `read_only_after_days`
+    #[serde(default)]
+    pub read_only_after_bytes: Option<u64>,
+    /// How many days after it's created an index has its segments merged into one, being made read only first
+    #[serde(default)]
+    pub force_merge_after_days: Option<u64>,
+    #[serde(default)]
+    pub delete_after_days: Option<u64>,
+}
+
+/// A search run on an interval that posts to a webhook when its results meet a condition, see `Settings::alerts`
+#[derive(Deserialize, Clone, Debug)]
+pub struct Alert {
+    pub name: String,
+    /// The index to search, or several separated by commas
+    pub index: String,
+    /// The search request, as JSON in the same form as the body of `POST /:index`
+    pub query: String,
+    /// How often to run the search, in seconds
+    #[serde(default = "Alert::default_interval")]
+    pub interval: u64,
+    /// Fire when the search has more hits than this
+    #[serde(default)]
+    pub hits_above: Option<usize>,
+    /// Fire when the search has fewer hits than this, such as when documents stop arriving
+    #[serde(default)]
+    pub hits_below: Option<usize>,
+    /// `sum`, `min`, `max` or `avg` of `field` over the matching documents, compared with `above` and `below`
+    #[serde(default)]
+    pub aggregation: Option<String>,
+    #[serde(default)]
+    pub field: Option<String>,
+    #[serde(default)]
+    pub above: Option<f64>,
+    #[serde(default)]
+    pub below: Option<f64>,
+    /// The URL the alert is posted to
+    pub webhook: String,
+    /// `json` to post the alert as it is, or `slack` to post it as a Slack message
+    #[serde(default = "Alert::default_format")]
+    pub format: String,
+    /// How many of the matching documents are sent with the alert
+    #[serde(default = "Alert::default_max_docs")]
+    pub max_docs: usize,
+}
+
+impl Alert {
+    pub fn default_interval() -> u64 {
+        60
+    }
+
+    pub fn default_format() -> String {
+        "json".to_string()
+    }
+
+    pub fn default_max_docs() -> usize {
+        10
+    }
+}
+
+/// A SQL query run on an interval whose rows are indexed into another index, see `Settings::rollups`
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rollup {
+    pub name: String,
+    /// A `SELECT` in the form `POST /_sql` takes, usually aggregating with `GROUP BY`
+    pub query: String,
+    /// The index each row of the query's results is added to as a document
+    pub target: String,
+    /// How often to run the query, in seconds
+    #[serde(default = "Rollup::default_interval")]
+    pub interval: u64,
+    /// A u64 field of the target set to the time of the run, in seconds since the epoch
+    #[serde(default)]
+    pub timestamp_field: Option<String>,
+}
+
+impl Rollup {
+    pub fn default_interval() -> u64 {
+        3600
+    }
+}
+
+/// An S3 compatible object store snapshots are kept in, such as S3 itself or MinIO
+#[derive(Deserialize, Clone, Debug)]
+pub struct S3Settings {
+    /// The store's address, such as `https://s3.us-east-1.amazonaws.com` or `http://minio:9000`. Snapshots aren't kept
+    /// in an object store when it's empty.
+    #[serde(default)]
+    pub endpoint: String,
+    #[serde(default)]
+    pub bucket: String,
+    #[serde(default = "S3Settings::default_region")]
+    pub region: String,
+    /// Taken from `AWS_ACCESS_KEY_ID` when not given
+    #[serde(default)]
+    pub access_key: String,
+    /// Taken from `AWS_SECRET_ACCESS_KEY` when not given
+    #[serde(default)]
+    pub secret_key: String,
+    /// What every key Toshi writes starts with, so a bucket can be shared with other data
+    #[serde(default)]
+    pub prefix: String,
+}
+
+impl S3Settings {
+    pub fn default_region() -> String {
+        "us-east-1".to_string()
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.endpoint.is_empty()
+    }
+}
+
+/// The embedded Raft group that can keep cluster metadata instead of Consul, needs the `raft` feature
+#[derive(Deserialize, Clone, Debug)]
+pub struct RaftSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "RaftSettings::default_node_id")]
+    pub node_id: u64,
+    #[serde(default = "RaftSettings::default_addr")]
+    pub addr: String,
+    /// The other members of the group, each given as `id@host:port`
+    #[serde(default)]
+    pub peers: Vec<String>,
+    #[serde(default = "RaftSettings::default_tick_ms")]
+    pub tick_ms: u64,
+    #[serde(default = "RaftSettings::default_election_ticks")]
+    pub election_ticks: u32,
+}
+
+impl RaftSettings {
+    pub fn default_node_id() -> u64 {
+        1
+    }
+
+    pub fn default_addr() -> String {
+        "0.0.0.0:8090".to_string()
+    }
+
+    pub fn default_tick_ms() -> u64 {
+        100
+    }
+
+    pub fn default_election_ticks() -> u32 {
+        10
+    }
+
+    /// The address of each peer, keyed by its node id
+    pub fn peer_addrs(&self) -> Result<HashMap<u64, SocketAddr>, String> {
+        self.peers
+            .iter()
+            .map(|peer| {
+                let mut parts = peer.splitn(2, '@');
+                let id = parts.next().and_then(|id| id.parse::<u64>().ok());
+                let addr = parts.next().and_then(|addr| addr.parse::<SocketAddr>().ok());
+                match (id, addr) {
+                    (Some(id), Some(addr)) => Ok((id, addr)),
+                    _ => Err(format!("raft peer '{}' must be in the form id@host:port", peer)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// A query run against an index whenever its searchers are reloaded, see `Settings::warmup_queries`
+#[derive(Deserialize, Clone, Debug)]
+pub struct WarmupQuery {
+    pub index: String,
+    /// The search request, as JSON in the same form as the body of `POST /:index`
+    pub query: String,
+}
+
+/// A service rescores can rank the top of a search with, see `Settings::rankers`
+#[derive(Deserialize, Clone, Debug)]
+pub struct RankerSettings {
+    /// The URL the candidates are posted to
+    pub url: String,
+    /// How many milliseconds a search waits for the ranking, keeping its own scores when it takes longer
+    #[serde(default = "RankerSettings::default_timeout")]
+    pub timeout: u64,
+}
+
+impl RankerSettings {
+    pub fn default_timeout() -> u64 {
+        500
+    }
+}
+
+/// How an index's text fields score the documents they match, see `Settings::similarities`
+#[derive(Deserialize, Clone, Debug)]
+pub struct SimilaritySettings {
+    pub index: String,
+    /// The field this is for, or every text field of the index without one of its own when not given
+    #[serde(default)]
+    pub field: Option<String>,
+    #[serde(flatten)]
+    pub similarity: TextSimilarity,
+}
+
+/// A field of an index worked out by a script when searches use it, see `Settings::runtime_fields`
+#[derive(Deserialize, Clone, Debug)]
+pub struct RuntimeFieldSettings {
+    pub index: String,
+    pub name: String,
+    /// What the field's value is worked out from, such as `price * quantity`
+    pub script: String,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Settings {
     #[serde(default = "Settings::default_host")]
     pub host: String,
     #[serde(default = "Settings::default_port")]
     pub port: u16,
-    #[serde(default = "Settings::default_path")]
-    pub path: String,
+    #[serde(default = "Settings::default_path", deserialize_with = "Settings::deserialize_path")]
+    pub path: Vec<String>,
     #[serde(default = "Settings::default_place_addr")]
     pub place_addr: String,
     #[serde(default = "Settings::default_level")]
     pub log_level: String,
     #[serde(default = "Settings::default_writer_memory")]
     pub writer_memory: usize,
+    #[serde(default = "Settings::default_writer_memory_budget")]
+    pub writer_memory_budget: usize,
+    #[serde(default = "Settings::default_writer_idle_timeout")]
+    pub writer_idle_timeout: u64,
     #[serde(default = "Settings::default_json_parsing_threads")]
     pub json_parsing_threads: usize,
+    #[serde(default = "Settings::default_search_threads")]
+    pub search_threads: usize,
+    #[serde(default = "Settings::default_indexing_threads")]
+    pub indexing_threads: usize,
+    #[serde(default = "Settings::default_segment_search_threads")]
+    pub segment_search_threads: usize,
     #[serde(default = "Settings::default_auto_commit_duration")]
     pub auto_commit_duration: u64,
     #[serde(default = "Settings::default_bulk_buffer_size")]
     pub bulk_buffer_size: usize,
+    #[serde(default = "Settings::default_filter_cache_size")]
+    pub filter_cache_size: usize,
+    #[serde(default = "Settings::default_warmup_queries")]
+    pub warmup_queries: Vec<WarmupQuery>,
+    #[serde(default = "Settings::default_similarities")]
+    pub similarities: Vec<SimilaritySettings>,
+    /// Fields every search of an index can use as if they were indexed, on top of those a search gives itself
+    #[serde(default = "Settings::default_runtime_fields")]
+    pub runtime_fields: Vec<RuntimeFieldSettings>,
+    /// The ranking services rescores can use, by the name they refer to them with
+    #[serde(default = "Settings::default_rankers")]
+    pub rankers: HashMap<String, RankerSettings>,
+    #[serde(default = "Settings::default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// The directory index snapshots are kept in, snapshots can't be taken when it's empty
+    #[serde(default = "Settings::default_snapshot_repository")]
+    pub snapshot_repository: String,
+    /// An object store to keep snapshots in instead of a directory
+    #[serde(default = "Settings::default_snapshot_s3")]
+    pub snapshot_s3: S3Settings,
+    /// Snapshots to take unattended
+    #[serde(default = "Settings::default_snapshot_policies")]
+    pub snapshot_policies: Vec<SnapshotPolicy>,
+    /// Indexes made read only, merged and deleted as they age or grow
+    #[serde(default = "Settings::default_lifecycle_policies")]
+    pub lifecycle_policies: Vec<LifecyclePolicy>,
+    /// How often the lifecycle policies are evaluated, in seconds
+    #[serde(default = "Settings::default_lifecycle_interval")]
+    pub lifecycle_interval: u64,
+    /// How many hours of writes the write-ahead log of each index keeps, for point-in-time restores to replay and the
+    /// change feed to serve. 0 keeps no log at all.
+    #[serde(default = "Settings::default_wal_retention_hours")]
+    pub wal_retention_hours: u64,
+    #[serde(default = "Settings::default_alerts")]
+    pub alerts: Vec<Alert>,
+    #[serde(default = "Settings::default_rollups")]
+    pub rollups: Vec<Rollup>,
+    /// Analyzers text fields can pick besides the built in ones, by name
+    #[serde(default = "Settings::default_analyzers")]
+    pub analyzers: HashMap<String, Analyzer>,
+    /// The directory stop word files are read from
+    #[serde(default = "Settings::default_analysis_path")]
+    pub analysis_path: String,
     #[serde(default = "Settings::default_merge_policy")]
     pub merge_policy: ConfigMergePolicy,
+    #[serde(default = "Settings::default_metadata_store")]
+    pub metadata_store: String,
     #[serde(default = "Settings::default_consul_addr")]
     pub consul_addr: String,
+    #[serde(default = "Settings::default_etcd_addr")]
+    pub etcd_addr: String,
+    #[serde(default = "Settings::default_kubernetes")]
+    pub kubernetes: KubernetesSettings,
     #[serde(default = "Settings::default_cluster_name")]
     pub cluster_name: String,
     #[serde(default = "Settings::default_enable_clustering")]
@@ -80,6 +739,39 @@ pub struct Settings {
     pub master: bool,
     #[serde(default = "Settings::default_nodes")]
     pub nodes: Vec<String>,
+    #[serde(default = "Settings::default_seed_nodes")]
+    pub seed_nodes: Vec<String>,
+    #[serde(default = "Settings::default_gossip")]
+    pub gossip: GossipSettings,
+    #[serde(default = "Settings::default_rebalance")]
+    pub rebalance: RebalanceSettings,
+    /// The coordinators of other clusters, by the name searches refer to them with as `name:index`
+    #[serde(default = "Settings::default_remote_clusters")]
+    pub remote_clusters: HashMap<String, Vec<String>>,
+    #[serde(default = "Settings::default_replicas")]
+    pub replicas: Vec<String>,
+    #[serde(default = "Settings::default_replication")]
+    pub replication: ReplicationSettings,
+    #[serde(default = "Settings::default_raft")]
+    pub raft: RaftSettings,
+    #[serde(default = "Settings::default_rate_limit")]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default = "Settings::default_body_limits")]
+    pub body_limits: BodyLimitSettings,
+    #[serde(default = "Settings::default_circuit_breaker")]
+    pub circuit_breaker: CircuitBreakerSettings,
+    #[serde(default = "Settings::default_compression")]
+    pub compression: CompressionSettings,
+    #[serde(default = "Settings::default_cors")]
+    pub cors: CorsSettings,
+    #[serde(default = "Settings::default_elasticsearch")]
+    pub elasticsearch: ElasticsearchSettings,
+    #[serde(default = "Settings::default_graphql")]
+    pub graphql: GraphqlSettings,
+    #[serde(default = "Settings::default_grpc")]
+    pub grpc: GrpcSettings,
+    #[serde(default = "Settings::default_websocket")]
+    pub websocket: WebsocketSettings,
 }
 
 impl Default for Settings {
@@ -91,15 +783,112 @@ impl Default for Settings {
             place_addr: Settings::default_place_addr(),
             log_level: Settings::default_level(),
             writer_memory: Settings::default_writer_memory(),
+            writer_memory_budget: Settings::default_writer_memory_budget(),
+            writer_idle_timeout: Settings::default_writer_idle_timeout(),
             json_parsing_threads: Settings::default_json_parsing_threads(),
+            search_threads: Settings::default_search_threads(),
+            indexing_threads: Settings::default_indexing_threads(),
+            segment_search_threads: Settings::default_segment_search_threads(),
             auto_commit_duration: Settings::default_auto_commit_duration(),
             bulk_buffer_size: Settings::default_bulk_buffer_size(),
+            filter_cache_size: Settings::default_filter_cache_size(),
+            warmup_queries: Settings::default_warmup_queries(),
+            similarities: Settings::default_similarities(),
+            runtime_fields: Settings::default_runtime_fields(),
+            rankers: Settings::default_rankers(),
+            drain_timeout: Settings::default_drain_timeout(),
+            snapshot_repository: Settings::default_snapshot_repository(),
+            snapshot_s3: Settings::default_snapshot_s3(),
+            snapshot_policies: Settings::default_snapshot_policies(),
+            lifecycle_policies: Settings::default_lifecycle_policies(),
+            lifecycle_interval: Settings::default_lifecycle_interval(),
+            wal_retention_hours: Settings::default_wal_retention_hours(),
+            alerts: Settings::default_alerts(),
+            rollups: Settings::default_rollups(),
+            analyzers: Settings::default_analyzers(),
+            analysis_path: Settings::default_analysis_path(),
             merge_policy: Settings::default_merge_policy(),
+            metadata_store: Settings::default_metadata_store(),
             consul_addr: Settings::default_consul_addr(),
+            etcd_addr: Settings::default_etcd_addr(),
+            kubernetes: Settings::default_kubernetes(),
             cluster_name: Settings::default_cluster_name(),
             enable_clustering: Settings::default_enable_clustering(),
             master: Settings::default_master(),
             nodes: Settings::default_nodes(),
+            seed_nodes: Settings::default_seed_nodes(),
+            gossip: Settings::default_gossip(),
+            rebalance: Settings::default_rebalance(),
+            remote_clusters: Settings::default_remote_clusters(),
+            replicas: Settings::default_replicas(),
+            replication: Settings::default_replication(),
+            raft: Settings::default_raft(),
+            rate_limit: Settings::default_rate_limit(),
+            body_limits: Settings::default_body_limits(),
+            circuit_breaker: Settings::default_circuit_breaker(),
+            compression: Settings::default_compression(),
+            cors: Settings::default_cors(),
+            elasticsearch: Settings::default_elasticsearch(),
+            graphql: Settings::default_graphql(),
+            grpc: Settings::default_grpc(),
+            websocket: Settings::default_websocket(),
+        }
+    }
+}
+
+/// The `TOSHI_*` variables among a set of environment variables, as settings
+#[derive(Clone, Debug)]
+pub struct EnvVars {
+    vars: Vec<(String, String)>,
+}
+
+impl EnvVars {
+    pub fn new<I: IntoIterator<Item = (String, String)>>(vars: I) -> Self {
+        EnvVars {
+            vars: vars.into_iter().collect(),
+        }
+    }
+}
+
+impl Source for EnvVars {
+    fn clone_into_box(&self) -> Box<Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, Value>, ConfigError> {
+        let prefix = format!("{}_", ENV_PREFIX);
+        let origin = "the environment".to_string();
+        Ok(self
+            .vars
+            .iter()
+            .filter(|(name, _)| name.len() > prefix.len() && name.starts_with(&prefix))
+            .map(|(name, value)| {
+                let key = name[prefix.len()..].to_lowercase().replace("__", ".");
+                (key, Value::new(Some(&origin), value.clone()))
+            })
+            .collect())
+    }
+}
+
+/// Where settings were loaded from, so they can be loaded again on reload
+#[derive(Clone, Debug)]
+pub struct ConfigSource {
+    pub path: String,
+    pub format: Option<FileFormat>,
+}
+
+impl ConfigSource {
+    pub fn new(path: &str, format: Option<FileFormat>) -> Self {
+        ConfigSource {
+            path: path.to_string(),
+            format,
+        }
+    }
+
+    pub fn load(&self) -> Result<Settings, ConfigError> {
+        match self.format {
+            Some(format) => Settings::with_format(&self.path, format),
+            None => Settings::new(&self.path),
         }
     }
 }
@@ -113,29 +902,74 @@ impl FromStr for Settings {
 }
 
 impl Settings {
+    /// Load settings from a configuration file, with `TOSHI_*` environment variables taking precedence.
+    /// The format is picked from the file's extension, so TOML, YAML and JSON files all work.
     pub fn new(path: &str) -> Result<Self, ConfigError> {
-        Self::from_config(File::with_name(path))
+        Self::from_file(File::with_name(path))
     }
 
+    /// Load settings from a configuration file in the given format, regardless of its extension
+    pub fn with_format(path: &str, format: FileFormat) -> Result<Self, ConfigError> {
+        Self::from_file(File::new(path, format))
+    }
+
+    /// The configuration formats accepted by `--config-format`
+    pub fn parse_format(format: &str) -> Option<FileFormat> {
+        match format.to_ascii_lowercase().as_ref() {
+            "toml" => Some(FileFormat::Toml),
+            "yaml" | "yml" => Some(FileFormat::Yaml),
+            "json" => Some(FileFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn from_file<T: Source + Send + Sync + 'static>(file: T) -> Result<Self, ConfigError> {
+        Self::from_file_with_env(file, Settings::environment())
+    }
+
+    /// Load settings from `file`, with the variables of `env` taking precedence
+    pub fn from_file_with_env<T, E>(file: T, env: E) -> Result<Self, ConfigError>
+    where
+        T: Source + Send + Sync + 'static,
+        E: Source + Send + Sync + 'static,
+    {
+        let mut cfg = Config::new();
+        cfg.merge(file)?;
+        cfg.merge(env)?;
+        cfg.try_into()
+    }
+
+    /// Build settings from command line flags, with `TOSHI_*` environment variables taking precedence
     pub fn from_args(args: &ArgMatches) -> Self {
-        Self {
-            host: args.value_of("host").unwrap().to_string(),
-            port: args.value_of("port").unwrap().parse().expect("Invalid port given."),
-            path: args.value_of("path").unwrap().to_string(),
-            log_level: args.value_of("level").unwrap().to_string(),
-            consul_addr: args.value_of("consul-addr").unwrap().to_string(),
-            enable_clustering: args.is_present("enable-clustering"),
-            cluster_name: args.value_of("cluster-name").unwrap().to_string(),
-            ..Default::default()
+        let flags = [
+            ("host", "host"),
+            ("port", "port"),
+            ("path", "path"),
+            ("level", "log_level"),
+            ("consul-addr", "consul_addr"),
+            ("cluster-name", "cluster_name"),
+        ];
+        let mut cfg = Config::new();
+        for (flag, key) in flags.iter() {
+            if let Some(value) = args.value_of(flag) {
+                cfg.set(key, value).expect("Invalid command line flag given.");
+            }
         }
+        cfg.set("enable_clustering", args.is_present("enable-clustering"))
+            .expect("Invalid command line flag given.");
+        cfg.merge(Settings::environment()).expect("Invalid TOSHI_* environment variable.");
+        cfg.try_into().expect("Invalid settings given.")
+    }
+
+    /// Environment variables are named after the setting, nested settings are separated by a double underscore,
+    /// e.g. `TOSHI_CONSUL_ADDR` or `TOSHI_RATE_LIMIT__SEARCH_BURST`.
+    pub fn environment() -> EnvVars {
+        EnvVars::new(std::env::vars())
     }
 
     pub fn from_config<T: Source + Send + Sync + 'static>(c: T) -> Result<Self, ConfigError> {
         let mut cfg = Config::new();
-        match cfg.merge(c) {
-            Ok(_) => {}
-            Err(e) => panic!("Problem with config file: {}", e),
-        };
+        cfg.merge(c)?;
         cfg.try_into()
     }
 
@@ -151,8 +985,37 @@ impl Settings {
         "0.0.0.0".to_string()
     }
 
-    pub fn default_path() -> String {
-        "data/".to_string()
+    pub fn default_path() -> Vec<String> {
+        vec!["data/".to_string()]
+    }
+
+    /// `path` may be a single directory or a list of them, so existing configurations keep working
+    fn deserialize_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(path) => Ok(vec![path]),
+            OneOrMany::Many(paths) => Ok(paths),
+        }
+    }
+
+    /// Every directory indexes may be placed in. The first one also holds node metadata such as the node id.
+    pub fn data_paths(&self) -> Vec<PathBuf> {
+        self.path.iter().map(PathBuf::from).collect()
+    }
+
+    /// Where snapshots are kept, if anywhere
+    pub fn snapshot_path(&self) -> Option<PathBuf> {
+        if self.snapshot_repository.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(&self.snapshot_repository))
+        }
     }
 
     pub fn default_port() -> u16 {
@@ -171,18 +1034,141 @@ impl Settings {
         200_000_000
     }
 
-    pub fn default_json_parsing_threads() -> usize {
-        4
+    pub fn default_writer_memory_budget() -> usize {
+        0
     }
 
-    pub fn default_bulk_buffer_size() -> usize {
-        10000
+    pub fn default_writer_idle_timeout() -> u64 {
+        300
     }
 
-    pub fn default_auto_commit_duration() -> u64 {
+    pub fn default_json_parsing_threads() -> usize {
+        4
+    }
+
+    pub fn default_search_threads() -> usize {
+        num_cpus::get()
+    }
+
+    pub fn default_indexing_threads() -> usize {
+        (num_cpus::get() / 2).max(1)
+    }
+
+    pub fn default_segment_search_threads() -> usize {
+        num_cpus::get()
+    }
+
+    pub fn default_bulk_buffer_size() -> usize {
+        10000
+    }
+
+    pub fn default_filter_cache_size() -> usize {
+        1000
+    }
+
+    pub fn default_warmup_queries() -> Vec<WarmupQuery> {
+        Vec::new()
+    }
+
+    /// The warm-up queries configured for `index`
+    pub fn warmup_queries_for(&self, index: &str) -> Vec<&str> {
+        self.warmup_queries
+            .iter()
+            .filter(|w| w.index == index)
+            .map(|w| w.query.as_str())
+            .collect()
+    }
+
+    pub fn default_similarities() -> Vec<SimilaritySettings> {
+        Vec::new()
+    }
+
+    /// The similarities configured for the text fields of `index`
+    pub fn similarities_for(&self, index: &str) -> Similarities {
+        let mut similarities = Similarities::default();
+        for configured in self.similarities.iter().filter(|s| s.index == index) {
+            match configured.field {
+                Some(ref field) => {
+                    similarities.fields.insert(field.clone(), configured.similarity);
+                }
+                None => similarities.default = Some(configured.similarity),
+            }
+        }
+        similarities
+    }
+
+    pub fn default_runtime_fields() -> Vec<RuntimeFieldSettings> {
+        Vec::new()
+    }
+
+    /// The runtime fields configured for `index`
+    pub fn runtime_fields_for(&self, index: &str) -> RuntimeFields {
+        self.runtime_fields
+            .iter()
+            .filter(|r| r.index == index)
+            .map(|r| (r.name.clone(), RuntimeField { script: r.script.clone() }))
+            .collect()
+    }
+
+    pub fn default_rankers() -> HashMap<String, RankerSettings> {
+        HashMap::new()
+    }
+
+    pub fn default_auto_commit_duration() -> u64 {
         10
     }
 
+    pub fn default_drain_timeout() -> u64 {
+        30
+    }
+
+    pub fn default_snapshot_repository() -> String {
+        String::new()
+    }
+
+    pub fn default_alerts() -> Vec<Alert> {
+        Vec::new()
+    }
+
+    pub fn default_rollups() -> Vec<Rollup> {
+        Vec::new()
+    }
+
+    pub fn default_analyzers() -> HashMap<String, Analyzer> {
+        HashMap::new()
+    }
+
+    pub fn default_analysis_path() -> String {
+        "config/analysis".to_string()
+    }
+
+    pub fn default_snapshot_policies() -> Vec<SnapshotPolicy> {
+        Vec::new()
+    }
+
+    pub fn default_lifecycle_policies() -> Vec<LifecyclePolicy> {
+        Vec::new()
+    }
+
+    pub fn default_lifecycle_interval() -> u64 {
+        600
+    }
+
+    pub fn default_wal_retention_hours() -> u64 {
+        0
+    }
+
+    pub fn default_snapshot_s3() -> S3Settings {
+        S3Settings {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: S3Settings::default_region(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: String::new(),
+        }
+    }
+
     pub fn default_merge_policy() -> ConfigMergePolicy {
         ConfigMergePolicy {
             kind: "log".to_string(),
@@ -196,6 +1182,23 @@ impl Settings {
         "127.0.0.1:8500".to_string()
     }
 
+    pub fn default_metadata_store() -> String {
+        "consul".to_string()
+    }
+
+    pub fn default_etcd_addr() -> String {
+        "127.0.0.1:2379".to_string()
+    }
+
+    pub fn default_kubernetes() -> KubernetesSettings {
+        KubernetesSettings {
+            service: None,
+            namespace: None,
+            label_selector: KubernetesSettings::default_label_selector(),
+            port: KubernetesSettings::default_port(),
+        }
+    }
+
     pub fn default_cluster_name() -> String {
         "kitsune".to_string()
     }
@@ -212,6 +1215,131 @@ impl Settings {
         Vec::new()
     }
 
+    pub fn default_seed_nodes() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn default_gossip() -> GossipSettings {
+        GossipSettings {
+            enabled: false,
+            probe_interval_ms: GossipSettings::default_probe_interval_ms(),
+            probe_timeout_ms: GossipSettings::default_probe_timeout_ms(),
+            suspect_timeout_ms: GossipSettings::default_suspect_timeout_ms(),
+            indirect_checks: GossipSettings::default_indirect_checks(),
+        }
+    }
+
+    pub fn default_rebalance() -> RebalanceSettings {
+        RebalanceSettings {
+            enabled: false,
+            interval_secs: RebalanceSettings::default_interval_secs(),
+            threshold: RebalanceSettings::default_threshold(),
+            measure: RebalanceSettings::default_measure(),
+        }
+    }
+
+    pub fn default_remote_clusters() -> HashMap<String, Vec<String>> {
+        HashMap::new()
+    }
+
+    pub fn default_replicas() -> Vec<String> {
+        Vec::new()
+    }
+
+    pub fn default_replication() -> ReplicationSettings {
+        ReplicationSettings {
+            mode: ReplicationSettings::default_mode(),
+            ship_interval_secs: ReplicationSettings::default_ship_interval_secs(),
+            ship_addr: ReplicationSettings::default_ship_addr(),
+        }
+    }
+
+    pub fn default_raft() -> RaftSettings {
+        RaftSettings {
+            enabled: false,
+            node_id: RaftSettings::default_node_id(),
+            addr: RaftSettings::default_addr(),
+            peers: Vec::new(),
+            tick_ms: RaftSettings::default_tick_ms(),
+            election_ticks: RaftSettings::default_election_ticks(),
+        }
+    }
+
+    pub fn default_rate_limit() -> RateLimitSettings {
+        RateLimitSettings {
+            enabled: false,
+            key_header: RateLimitSettings::default_key_header(),
+            api_keys: Vec::new(),
+            search_per_second: RateLimitSettings::default_search_rate(),
+            search_burst: RateLimitSettings::default_search_burst(),
+            bulk_per_second: RateLimitSettings::default_bulk_rate(),
+            bulk_burst: RateLimitSettings::default_bulk_burst(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    pub fn default_body_limits() -> BodyLimitSettings {
+        BodyLimitSettings {
+            default: BodyLimitSettings::default_max(),
+            index: BodyLimitSettings::default_index_max(),
+            bulk: BodyLimitSettings::default_bulk_max(),
+        }
+    }
+
+    pub fn default_circuit_breaker() -> CircuitBreakerSettings {
+        CircuitBreakerSettings {
+            request_bytes: CircuitBreakerSettings::default_request_bytes(),
+            total_bytes: CircuitBreakerSettings::default_total_bytes(),
+        }
+    }
+
+    pub fn default_compression() -> CompressionSettings {
+        CompressionSettings {
+            enabled: CompressionSettings::default_enabled(),
+            min_size: CompressionSettings::default_min_size(),
+            level: CompressionSettings::default_level(),
+        }
+    }
+
+    pub fn default_cors() -> CorsSettings {
+        CorsSettings {
+            enabled: false,
+            allowed_origins: CorsSettings::default_allowed_origins(),
+            allowed_methods: CorsSettings::default_allowed_methods(),
+            allowed_headers: CorsSettings::default_allowed_headers(),
+            allow_credentials: false,
+            max_age: CorsSettings::default_max_age(),
+        }
+    }
+
+    pub fn default_elasticsearch() -> ElasticsearchSettings {
+        ElasticsearchSettings {
+            enabled: false,
+            port: ElasticsearchSettings::default_port(),
+            id_field: ElasticsearchSettings::default_id_field(),
+        }
+    }
+
+    pub fn default_graphql() -> GraphqlSettings {
+        GraphqlSettings { enabled: false }
+    }
+
+    pub fn default_grpc() -> GrpcSettings {
+        GrpcSettings {
+            enabled: false,
+            port: GrpcSettings::default_port(),
+        }
+    }
+
+    pub fn default_websocket() -> WebsocketSettings {
+        WebsocketSettings {
+            enabled: false,
+            port: WebsocketSettings::default_port(),
+            batch_size: WebsocketSettings::default_batch_size(),
+            poll_interval: WebsocketSettings::default_poll_interval(),
+        }
+    }
+
     pub fn get_channel<T>(&self) -> (Sender<T>, Receiver<T>) {
         if self.bulk_buffer_size == 0 {
             unbounded::<T>()
@@ -220,6 +1348,348 @@ impl Settings {
         }
     }
 
+    /// Check settings that would otherwise only fail once Toshi is partway through starting up,
+    /// returning a description of every problem found.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        if format!("{}:{}", self.host, self.port).parse::<SocketAddr>().is_err() {
+            errors.push(format!("host '{}' is not a valid IP address to bind to", self.host));
+        }
+        if self.enable_clustering {
+            if self.place_addr.parse::<SocketAddr>().is_err() {
+                errors.push(format!("place_addr '{}' is not a valid socket address", self.place_addr));
+            }
+            let store_addr = match self.metadata_store.as_ref() {
+                "consul" => Some(("consul_addr", &self.consul_addr)),
+                "etcd" => Some(("etcd_addr", &self.etcd_addr)),
+                "kubernetes" => None,
+                "seeds" => {
+                    if self.seed_nodes.is_empty() {
+                        errors.push("seed_nodes must list at least one node for the 'seeds' metadata_store".into());
+                    }
+                    None
+                }
+                store => {
+                    errors.push(format!(
+                        "metadata_store '{}' must be one of 'consul', 'etcd', 'kubernetes' or 'seeds'",
+                        store
+                    ));
+                    None
+                }
+            };
+            if let Some((name, addr)) = store_addr {
+                let port = addr.rsplit(':').next().and_then(|p| p.parse::<u16>().ok());
+                if !addr.contains(':') || port.is_none() {
+                    errors.push(format!("{} '{}' must be in the form host:port", name, addr));
+                }
+            }
+        }
+        for node in self.nodes.iter().filter(|n| n.parse::<SocketAddr>().is_err()) {
+            errors.push(format!("node '{}' is not a valid socket address", node));
+        }
+        for seed in self.seed_nodes.iter().filter(|s| s.parse::<SocketAddr>().is_err()) {
+            errors.push(format!("seed node '{}' is not a valid socket address", seed));
+        }
+        for replica in self.replicas.iter().filter(|r| r.parse::<SocketAddr>().is_err()) {
+            errors.push(format!("replica '{}' is not a valid socket address", replica));
+        }
+        for (name, coordinators) in &self.remote_clusters {
+            if name.is_empty() || name.contains(':') || name.contains(',') {
+                errors.push(format!("remote cluster name '{}' can't be empty or contain ':' or ','", name));
+            }
+            if coordinators.is_empty() {
+                errors.push(format!("remote cluster '{}' must list at least one coordinator", name));
+            }
+            for coordinator in coordinators.iter().filter(|c| c.parse::<SocketAddr>().is_err()) {
+                errors.push(format!(
+                    "remote cluster '{}' coordinator '{}' is not a valid socket address",
+                    name, coordinator
+                ));
+            }
+        }
+        if self.gossip.enabled && self.gossip.probe_timeout_ms >= self.gossip.probe_interval_ms {
+            errors.push("gossip probe_timeout_ms must be shorter than probe_interval_ms".into());
+        }
+        if self.rebalance.enabled {
+            if !self.master || self.nodes.len() < 2 {
+                errors.push("rebalance needs a master node with at least 2 data nodes".into());
+            }
+            if self.rebalance.threshold <= 0.0 || self.rebalance.threshold >= 1.0 {
+                errors.push(format!(
+                    "rebalance threshold must be between 0 and 1, not {}",
+                    self.rebalance.threshold
+                ));
+            }
+            if self.rebalance.measure != "bytes" && self.rebalance.measure != "docs" {
+                errors.push(format!(
+                    "rebalance measure must be 'bytes' or 'docs', not '{}'",
+                    self.rebalance.measure
+                ));
+            }
+        }
+        if self.replication.mode != "document" && !self.replication.ships_segments() {
+            errors.push(format!(
+                "replication mode must be 'document' or 'segment', not '{}'",
+                self.replication.mode
+            ));
+        }
+        if self.replication.ships_segments() && self.replication.ship_addr.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "replication ship_addr '{}' is not a valid socket address",
+                self.replication.ship_addr
+            ));
+        }
+        if self.snapshot_s3.enabled() {
+            if !self.snapshot_repository.is_empty() {
+                errors.push("snapshot_repository and snapshot_s3 can't both be set, snapshots are kept in one place".into());
+            }
+            match self.snapshot_s3.endpoint.parse::<hyper::Uri>() {
+                Ok(ref uri) if uri.scheme_part().is_some() && uri.host().is_some() => {}
+                _ => errors.push(format!("snapshot_s3 endpoint '{}' is not a valid URL", self.snapshot_s3.endpoint)),
+            }
+            if self.snapshot_s3.bucket.is_empty() {
+                errors.push("snapshot_s3 needs a bucket".into());
+            }
+        }
+        if !self.snapshot_policies.is_empty() && self.snapshot_path().is_none() && !self.snapshot_s3.enabled() {
+            errors.push("snapshot_policies need a snapshot_repository or snapshot_s3 to keep snapshots in".into());
+        }
+        let mut policy_names = HashSet::new();
+        for policy in &self.snapshot_policies {
+            if policy.name.is_empty() || policy.name.starts_with('.') || policy.name.contains('/') || policy.name.contains('\\') {
+                errors.push(format!("snapshot policy name '{}' is not valid", policy.name));
+            }
+            if !policy_names.insert(&policy.name) {
+                errors.push(format!("snapshot policy {} is given more than once", policy.name));
+            }
+            if let Err(e) = policy.schedule.parse::<Schedule>() {
+                errors.push(format!("snapshot policy {} schedule: {}", policy.name, e));
+            }
+            if policy.keep == Some(0) {
+                errors.push(format!("snapshot policy {} must keep at least one snapshot", policy.name));
+            }
+        }
+        let mut lifecycle_names = HashSet::new();
+        for policy in &self.lifecycle_policies {
+            if !lifecycle_names.insert(&policy.name) {
+                errors.push(format!("lifecycle policy {} is given more than once", policy.name));
+            }
+            if policy.indexes.is_empty() {
+                errors.push(format!("lifecycle policy {} needs indexes to manage", policy.name));
+            }
+            let days = [policy.read_only_after_days, policy.force_merge_after_days, policy.delete_after_days];
+            if days.iter().all(Option::is_none) && policy.read_only_after_bytes.is_none() {
+                errors.push(format!("lifecycle policy {} has nothing to do", policy.name));
+            }
+            let given: Vec<u64> = days.iter().filter_map(|days| *days).collect();
+            if given.windows(2).any(|pair| pair[0] > pair[1]) {
+                errors.push(format!(
+                    "lifecycle policy {} must make indexes read only, merge and delete them in that order",
+                    policy.name
+                ));
+            }
+        }
+        if !self.lifecycle_policies.is_empty() && self.lifecycle_interval == 0 {
+            errors.push("lifecycle_interval must be at least 1 second".into());
+        }
+        let mut alert_names = HashSet::new();
+        for alert in &self.alerts {
+            if !alert_names.insert(&alert.name) {
+                errors.push(format!("alert {} is given more than once", alert.name));
+            }
+            if let Err(e) = serde_json::from_str::<Request>(&alert.query) {
+                errors.push(format!("alert {} query is not a valid search request: {}", alert.name, e));
+            }
+            if alert.interval == 0 {
+                errors.push(format!("alert {} interval must be at least 1 second", alert.name));
+            }
+            let compares_aggregation = alert.above.is_some() || alert.below.is_some();
+            if alert.hits_above.is_none() && alert.hits_below.is_none() && !compares_aggregation {
+                errors.push(format!("alert {} needs a condition to fire on", alert.name));
+            }
+            if compares_aggregation || alert.aggregation.is_some() {
+                match alert.aggregation.as_ref().map(String::as_str) {
+                    Some("sum") | Some("min") | Some("max") | Some("avg") => {}
+                    _ => errors.push(format!("alert {} aggregation must be sum, min, max or avg", alert.name)),
+                }
+                if alert.field.is_none() {
+                    errors.push(format!("alert {} needs a field to aggregate", alert.name));
+                }
+            }
+            match alert.webhook.parse::<hyper::Uri>() {
+                Ok(ref uri) if uri.scheme_part().is_some() && uri.host().is_some() => {}
+                _ => errors.push(format!("alert {} webhook '{}' is not a valid URL", alert.name, alert.webhook)),
+            }
+            if alert.format != "json" && alert.format != "slack" {
+                errors.push(format!("alert {} format must be json or slack", alert.name));
+            }
+        }
+        for name in self.analyzers.keys() {
+            // tantivy's own tokenizers can't be replaced, indexes created before would be searched differently
+            if name == "default" || name == "raw" || name == "en_stem" {
+                errors.push(format!("analyzer {} would replace a tantivy tokenizer", name));
+            } else if let Err(e) = analysis::resolve(name, self) {
+                errors.push(format!("analyzer {} can't be used: {}", name, e));
+            }
+        }
+        let mut rollup_names = HashSet::new();
+        for rollup in &self.rollups {
+            if !rollup_names.insert(&rollup.name) {
+                errors.push(format!("rollup {} is given more than once", rollup.name));
+            }
+            if let Err(e) = Statement::parse(&rollup.query) {
+                errors.push(format!("rollup {} query is not valid SQL: {}", rollup.name, e));
+            }
+            if rollup.interval == 0 {
+                errors.push(format!("rollup {} interval must be at least 1 second", rollup.name));
+            }
+        }
+        if self.elasticsearch.enabled {
+            if self.elasticsearch.port == self.port {
+                errors.push("elasticsearch port must differ from the port Toshi's own API listens on".into());
+            }
+            if self.elasticsearch.id_field.is_empty() {
+                errors.push("elasticsearch id_field can't be empty".into());
+            }
+        }
+        if self.grpc.enabled {
+            if self.grpc.port == self.port {
+                errors.push("grpc port must differ from the port Toshi's own API listens on".into());
+            }
+            if self.elasticsearch.enabled && self.grpc.port == self.elasticsearch.port {
+                errors.push("grpc port must differ from the elasticsearch port".into());
+            }
+        }
+        if self.websocket.enabled {
+            let taken = [
+                Some(self.port),
+                if self.elasticsearch.enabled {
+                    Some(self.elasticsearch.port)
+                } else {
+                    None
+                },
+                if self.grpc.enabled { Some(self.grpc.port) } else { None },
+            ];
+            if taken.contains(&Some(self.websocket.port)) {
+                errors.push("websocket port must differ from the ports of the other APIs".into());
+            }
+            if self.websocket.batch_size == 0 {
+                errors.push("websocket batch_size must be at least 1".into());
+            }
+            if self.websocket.poll_interval == 0 {
+                errors.push("websocket poll_interval must be at least 1 millisecond".into());
+            }
+        }
+        if !self.master && !self.replicas.is_empty() {
+            errors.push("replicas can only be set on a master node, data nodes don't take writes of their own".into());
+        }
+        if self.raft.enabled {
+            if !cfg!(feature = "raft") {
+                errors.push("raft is enabled, but Toshi was built without the raft feature".into());
+            }
+            if self.raft.addr.parse::<SocketAddr>().is_err() {
+                errors.push(format!("raft addr '{}' is not a valid socket address", self.raft.addr));
+            }
+            match self.raft.peer_addrs() {
+                Ok(ref peers) if peers.contains_key(&self.raft.node_id) => {
+                    errors.push(format!("raft node_id {} is also listed as a peer", self.raft.node_id))
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            }
+            if self.raft.tick_ms == 0 || self.raft.election_ticks < 2 {
+                errors.push("raft tick_ms must be at least 1 and election_ticks at least 2".into());
+            }
+        }
+
+        if self.path.is_empty() {
+            errors.push("path must contain at least one directory".into());
+        }
+        for dir in &self.path {
+            let path = Path::new(dir);
+            if path.exists() && !path.is_dir() {
+                errors.push(format!("path '{}' is not a directory", dir));
+            } else if path.metadata().map(|m| m.permissions().readonly()).unwrap_or(false) {
+                errors.push(format!("path '{}' is read only", dir));
+            }
+        }
+
+        match self.merge_policy.kind.to_ascii_lowercase().as_ref() {
+            "log" | "nomerge" => {}
+            kind => errors.push(format!("merge_policy kind '{}' must be either 'log' or 'nomerge'", kind)),
+        }
+        if self.writer_memory < 3_000_000 {
+            errors.push("writer_memory must be at least 3000000 bytes".into());
+        }
+        if self.writer_memory_budget > 0 && self.writer_memory_budget < 3_000_000 {
+            errors.push("writer_memory_budget must be 0 for no budget, or at least 3000000 bytes".into());
+        }
+        if self.json_parsing_threads == 0 {
+            errors.push("json_parsing_threads must be at least 1".into());
+        }
+        if self.search_threads == 0 {
+            errors.push("search_threads must be at least 1".into());
+        }
+        if self.indexing_threads == 0 {
+            errors.push("indexing_threads must be at least 1".into());
+        }
+        if self.segment_search_threads == 0 {
+            errors.push("segment_search_threads must be at least 1".into());
+        }
+        let rates = [
+            self.rate_limit.search_per_second,
+            self.rate_limit.search_burst,
+            self.rate_limit.bulk_per_second,
+            self.rate_limit.bulk_burst,
+        ];
+        if rates.iter().any(|r| *r < 0.0) {
+            errors.push("rate_limit rates and bursts must not be negative".into());
+        }
+        if self.body_limits.default == 0 || self.body_limits.index == 0 || self.body_limits.bulk == 0 {
+            errors.push("body_limits must all be greater than 0".into());
+        }
+        let breaker = &self.circuit_breaker;
+        if breaker.total_bytes > 0 && breaker.request_bytes > breaker.total_bytes {
+            errors.push("circuit_breaker request_bytes must not be more than total_bytes".into());
+        }
+        for warmup in &self.warmup_queries {
+            if let Err(e) = serde_json::from_str::<Request>(&warmup.query) {
+                errors.push(format!(
+                    "warm-up query for index '{}' is not a valid search request: {}",
+                    warmup.index, e
+                ));
+            }
+        }
+        for (name, ranker) in &self.rankers {
+            match ranker.url.parse::<hyper::Uri>() {
+                Ok(ref uri) if uri.scheme_part().is_some() && uri.host().is_some() => {}
+                _ => errors.push(format!("ranker {} url '{}' is not a valid URL", name, ranker.url)),
+            }
+        }
+        for configured in &self.runtime_fields {
+            if let Err(e) = runtime_script(&self.runtime_fields_for(&configured.index), &configured.name) {
+                errors.push(format!("runtime field {} of index '{}': {}", configured.name, configured.index, e));
+            }
+        }
+        for configured in &self.similarities {
+            if let TextSimilarity::Bm25 { k1, b } = configured.similarity {
+                if k1 < 0.0 || b < 0.0 || b > 1.0 {
+                    errors.push(format!(
+                        "bm25 similarity for index '{}' needs k1 of at least 0 and b between 0 and 1",
+                        configured.index
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     pub fn get_merge_policy(&self) -> Box<MergePolicy> {
         match self.merge_policy.get_kind() {
             MergePolicyType::Log => {
@@ -243,22 +1713,68 @@ impl Settings {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::analysis::{Language, Split};
 
     #[test]
     fn valid_default_config() {
         let default = Settings::from_str("").unwrap();
         assert_eq!(default.host, "0.0.0.0");
         assert_eq!(default.port, 8080);
-        assert_eq!(default.path, "data/");
+        assert_eq!(default.path, vec!["data/".to_string()]);
         assert_eq!(default.writer_memory, 200_000_000);
+        assert_eq!(default.writer_memory_budget, 0);
+        assert_eq!(default.writer_idle_timeout, 300);
         assert_eq!(default.log_level, "info");
         assert_eq!(default.json_parsing_threads, 4);
+        assert_eq!(default.search_threads, num_cpus::get());
+        assert!(default.indexing_threads >= 1);
+        assert_eq!(default.segment_search_threads, num_cpus::get());
         assert_eq!(default.bulk_buffer_size, 10000);
+        assert_eq!(default.filter_cache_size, 1000);
+        assert!(default.warmup_queries.is_empty());
+        assert!(default.replicas.is_empty());
+        assert!(!default.replication.ships_segments());
+        assert!(!default.raft.enabled);
+        assert_eq!(default.raft.tick_ms, 100);
         assert_eq!(default.merge_policy.kind, "log");
         assert_eq!(default.merge_policy.level_log_size, None);
         assert_eq!(default.merge_policy.min_layer_size, None);
         assert_eq!(default.merge_policy.min_merge_size, None);
         assert_eq!(default.consul_addr, "127.0.0.1:8500");
+        assert_eq!(default.metadata_store, "consul");
+        assert_eq!(default.etcd_addr, "127.0.0.1:2379");
+        assert_eq!(default.kubernetes.label_selector, "app=toshi");
+        assert!(default.seed_nodes.is_empty());
+        assert!(!default.gossip.enabled);
+        assert!(default.remote_clusters.is_empty());
+        assert_eq!(default.gossip.suspect_timeout_ms, 3000);
+        assert!(!default.rebalance.enabled);
+        assert_eq!(default.rebalance.measure, "bytes");
+        assert_eq!(default.body_limits.default, 1_048_576);
+        assert_eq!(default.body_limits.bulk, 268_435_456);
+        assert_eq!(default.circuit_breaker.request_bytes, 268_435_456);
+        assert_eq!(default.circuit_breaker.total_bytes, 1_073_741_824);
+        assert_eq!(default.drain_timeout, 30);
+        assert_eq!(default.snapshot_path(), None);
+        assert!(!default.snapshot_s3.enabled());
+        assert!(default.snapshot_policies.is_empty());
+        assert!(default.lifecycle_policies.is_empty());
+        assert_eq!(default.lifecycle_interval, 600);
+        assert_eq!(default.wal_retention_hours, 0);
+        assert!(default.alerts.is_empty());
+        assert!(default.rollups.is_empty());
+        assert!(default.analyzers.is_empty());
+        assert_eq!(default.analysis_path, "config/analysis");
+        assert_eq!(default.snapshot_s3.region, "us-east-1");
+        assert!(!default.elasticsearch.enabled);
+        assert_eq!(default.elasticsearch.port, 9200);
+        assert!(!default.graphql.enabled);
+        assert!(!default.grpc.enabled);
+        assert_eq!(default.grpc.port, 8083);
+        assert!(!default.websocket.enabled);
+        assert_eq!(default.websocket.port, 8084);
+        assert_eq!(default.websocket.batch_size, 100);
+        assert_eq!(default.websocket.poll_interval, 1000);
     }
 
     #[test]
@@ -292,6 +1808,385 @@ mod tests {
         assert_eq!(config.merge_policy.min_merge_size, None);
     }
 
+    #[test]
+    fn valid_circuit_breaker() {
+        let cfg = r#"
+            [circuit_breaker]
+            request_bytes = 1000"#;
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.circuit_breaker.request_bytes, 1000);
+        assert_eq!(config.circuit_breaker.total_bytes, 1_073_741_824);
+        assert!(config.validate().is_ok());
+
+        let cfg = r#"
+            [circuit_breaker]
+            request_bytes = 2000
+            total_bytes = 1000"#;
+        assert!(Settings::from_str(cfg).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn valid_rate_limit() {
+        let cfg = r#"
+            [rate_limit]
+            enabled = true
+            search_per_second = 50.0
+            bulk_burst = 2.0
+            trusted_proxies = ["10.0.0.1", "::1"]
+            api_keys = ["tenant-a"]"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+
+        assert!(config.rate_limit.enabled);
+        assert_eq!(config.rate_limit.key_header, "x-api-key");
+        assert_eq!(config.rate_limit.search_per_second, 50.0);
+        assert_eq!(config.rate_limit.search_burst, 200.0);
+        assert_eq!(config.rate_limit.bulk_burst, 2.0);
+        assert_eq!(config.rate_limit.trusted_proxies.len(), 2);
+        assert_eq!(config.rate_limit.api_keys, vec!["tenant-a"]);
+    }
+
+    #[test]
+    fn valid_warmup_queries() {
+        let cfg = r#"
+            [[warmup_queries]]
+            index = "logs"
+            query = '{ "query": { "term": { "level": "error" } }, "limit": 10 }'
+
+            [[warmup_queries]]
+            index = "users"
+            query = '{ "query": { "raw": "status:active" } }'"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+
+        assert_eq!(config.warmup_queries_for("logs").len(), 1);
+        assert!(config.warmup_queries_for("other").is_empty());
+        assert!(config.validate().is_ok());
+
+        let invalid = Settings::from_str("[[warmup_queries]]\nindex = \"logs\"\nquery = '{ \"query\": 5 }'").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn valid_similarities() {
+        let cfg = r#"
+            [[similarities]]
+            index = "products"
+            type = "bm25"
+            b = 0.3
+
+            [[similarities]]
+            index = "products"
+            field = "tags"
+            type = "boolean"
+
+            [[similarities]]
+            index = "articles"
+            type = "classic""#;
+
+        let config = Settings::from_str(cfg).unwrap();
+        let products = config.similarities_for("products");
+        assert_eq!(products.default, Some(TextSimilarity::Bm25 { k1: 1.2, b: 0.3 }));
+        assert_eq!(products.fields["tags"], TextSimilarity::Boolean);
+        assert_eq!(config.similarities_for("articles").default, Some(TextSimilarity::Classic));
+        assert!(config.similarities_for("other").is_empty());
+        assert!(config.validate().is_ok());
+
+        let invalid = Settings::from_str("[[similarities]]\nindex = \"products\"\ntype = \"bm25\"\nb = 2.0").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn valid_runtime_fields() {
+        let cfg = r#"
+            [[runtime_fields]]
+            index = "orders"
+            name = "total"
+            script = "price * quantity"
+
+            [[runtime_fields]]
+            index = "orders"
+            name = "discounted"
+            script = "total - total / 10""#;
+
+        let config = Settings::from_str(cfg).unwrap();
+        let orders = config.runtime_fields_for("orders");
+        assert_eq!(orders["total"].script, "price * quantity");
+        assert_eq!(orders.len(), 2);
+        assert!(config.runtime_fields_for("other").is_empty());
+        assert!(config.validate().is_ok());
+
+        let invalid = Settings::from_str("[[runtime_fields]]\nindex = \"orders\"\nname = \"total\"\nscript = \"price *\"").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn valid_rankers() {
+        let cfg = r#"
+            [rankers.products]
+            url = "http://localhost:9000/rank"
+            timeout = 200"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.rankers["products"].url, "http://localhost:9000/rank");
+        assert_eq!(config.rankers["products"].timeout, 200);
+        assert!(config.validate().is_ok());
+
+        let invalid = Settings::from_str("[rankers.products]\nurl = \"nowhere\"").unwrap();
+        assert_eq!(invalid.rankers["products"].timeout, 500);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn valid_raft_peers() {
+        let cfg = r#"
+            [raft]
+            enabled = true
+            node_id = 1
+            peers = ["2@10.0.0.2:8090", "3@10.0.0.3:8090"]"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+        let peers = config.raft.peer_addrs().unwrap();
+        assert_eq!(peers[&2], "10.0.0.2:8090".parse::<SocketAddr>().unwrap());
+        assert_eq!(peers.len(), 2);
+
+        let invalid = Settings::from_str("[raft]\nenabled = true\npeers = [\"1@10.0.0.1:8090\", \"10.0.0.2\"]").unwrap();
+        let errors = invalid.validate().unwrap_err();
+        assert!(errors.iter().any(|e| e.contains("10.0.0.2")));
+    }
+
+    #[test]
+    fn valid_remote_clusters() {
+        let cfg = r#"
+            [remote_clusters]
+            archive = ["10.1.0.1:8080", "10.1.0.2:8080"]"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+        assert_eq!(config.remote_clusters["archive"].len(), 2);
+
+        let invalid = Settings::from_str("[remote_clusters]\narchive = []\neu = [\"nowhere\"]").unwrap();
+        let errors = invalid.validate().unwrap_err();
+        assert!(errors.iter().any(|e| e.contains("at least one coordinator")));
+        assert!(errors.iter().any(|e| e.contains("nowhere")));
+    }
+
+    #[test]
+    fn valid_cors() {
+        let cfg = r#"
+            [cors]
+            enabled = true
+            allowed_origins = ["https://search.example.com"]"#;
+
+        let config = Settings::from_str(cfg).unwrap();
+
+        assert!(config.cors.enabled);
+        assert_eq!(config.cors.allowed_origins, vec!["https://search.example.com"]);
+        assert_eq!(config.cors.allowed_methods, vec!["GET", "POST", "PUT", "DELETE"]);
+        assert_eq!(config.cors.max_age, 3600);
+    }
+
+    #[test]
+    fn valid_data_paths() {
+        let single = Settings::from_str(r#"path = "/mnt/disk1/""#).unwrap();
+        assert_eq!(single.path, vec!["/mnt/disk1/"]);
+
+        let many = Settings::from_str(r#"path = ["/mnt/disk1/", "/mnt/disk2/"]"#).unwrap();
+        assert_eq!(many.path, vec!["/mnt/disk1/", "/mnt/disk2/"]);
+        assert_eq!(many.data_paths(), vec![PathBuf::from("/mnt/disk1/"), PathBuf::from("/mnt/disk2/")]);
+
+        let none = Settings::from_str("path = []").unwrap();
+        assert!(none.validate().is_err());
+    }
+
+    #[test]
+    fn env_overrides_config_file() {
+        let cfg = r#"
+            port = 8081
+            consul_addr = "10.0.0.1:8500"
+            [rate_limit]
+            search_burst = 50.0"#;
+        let env = EnvVars::new(vec![
+            ("TOSHI_PORT".to_string(), "9200".to_string()),
+            ("TOSHI_RATE_LIMIT__SEARCH_BURST".to_string(), "5.0".to_string()),
+            ("PORT".to_string(), "1234".to_string()),
+        ]);
+
+        let config = Settings::from_file_with_env(File::from_str(cfg, FileFormat::Toml), env).unwrap();
+        assert_eq!(config.port, 9200);
+        assert_eq!(config.consul_addr, "10.0.0.1:8500");
+        assert_eq!(config.rate_limit.search_burst, 5.0);
+    }
+
+    #[test]
+    fn valid_yaml_config() {
+        let cfg = r#"
+host: "127.0.0.1"
+port: 9200
+merge_policy:
+  kind: "nomerge"
+rate_limit:
+  enabled: true
+  search_burst: 50.0
+"#;
+        let config = Settings::from_config(File::from_str(cfg, FileFormat::Yaml)).unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.port, 9200);
+        assert!(config.merge_policy.get_kind() == MergePolicyType::NoMerge);
+        assert!(config.rate_limit.enabled);
+        assert_eq!(config.rate_limit.search_burst, 50.0);
+    }
+
+    #[test]
+    fn valid_json_config() {
+        let cfg = r#"{ "port": 9200, "cors": { "enabled": true, "allowed_origins": ["https://search.example.com"] } }"#;
+        let config = Settings::from_config(File::from_str(cfg, FileFormat::Json)).unwrap();
+        assert_eq!(config.port, 9200);
+        assert!(config.cors.enabled);
+        assert_eq!(config.cors.allowed_origins, vec!["https://search.example.com"]);
+    }
+
+    #[test]
+    fn config_formats() {
+        assert_eq!(Settings::parse_format("YAML"), Some(FileFormat::Yaml));
+        assert_eq!(Settings::parse_format("yml"), Some(FileFormat::Yaml));
+        assert_eq!(Settings::parse_format("json"), Some(FileFormat::Json));
+        assert_eq!(Settings::parse_format("ini"), None);
+    }
+
+    #[test]
+    fn validate_config() {
+        assert!(Settings::default().validate().is_ok());
+
+        let cfg = r#"
+            host = "not an address"
+            writer_memory = 100
+            nodes = ["127.0.0.1:8081", "nowhere"]
+            [merge_policy]
+            kind = "asdf1234""#;
+        let errors = Settings::from_str(cfg).unwrap().validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].contains("not an address"));
+        assert!(errors[1].contains("nowhere"));
+    }
+
+    #[test]
+    fn snapshot_policies() {
+        let cfg = r#"
+            snapshot_repository = "/var/backups/toshi"
+            [[snapshot_policies]]
+            name = "nightly"
+            schedule = "30 2 * * *"
+            keep = 7
+            [[snapshot_policies]]
+            name = "nightly"
+            schedule = "every day"
+            keep = 0"#;
+        let settings = Settings::from_str(cfg).unwrap();
+        assert_eq!(settings.snapshot_policies[0].snapshot_prefix(), "nightly-");
+        assert!(settings.snapshot_policies[0].indexes.is_empty());
+        let errors = settings.validate().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("more than once"));
+        assert!(errors[1].contains("every day"));
+    }
+
+    #[test]
+    fn lifecycle_policies() {
+        let cfg = r#"
+            lifecycle_interval = 60
+            [[lifecycle_policies]]
+            name = "logs"
+            indexes = ["logs-*"]
+            read_only_after_days = 1
+            read_only_after_bytes = 1073741824
+            force_merge_after_days = 2
+            delete_after_days = 30
+            [[lifecycle_policies]]
+            name = "metrics"
+            [[lifecycle_policies]]
+            name = "metrics"
+            indexes = ["metrics"]
+            read_only_after_days = 7
+            delete_after_days = 3"#;
+        let settings = Settings::from_str(cfg).unwrap();
+        assert_eq!(settings.lifecycle_interval, 60);
+        assert_eq!(settings.lifecycle_policies[0].read_only_after_bytes, Some(1_073_741_824));
+        assert_eq!(settings.lifecycle_policies[1].force_merge_after_days, None);
+        let errors = settings.validate().unwrap_err();
+        assert_eq!(errors.len(), 4);
+        assert!(errors[0].contains("needs indexes"));
+        assert!(errors[1].contains("nothing to do"));
+        assert!(errors[2].contains("more than once"));
+        assert!(errors[3].contains("in that order"));
+    }
+
+    #[test]
+    fn alerts() {
+        let cfg = r#"
+            [[alerts]]
+            name = "errors"
+            index = "logs"
+            query = '{ "query": { "term": { "level": "error" } }, "limit": 1000 }'
+            hits_above = 10
+            webhook = "https://hooks.slack.com/services/T0/B0/X"
+            format = "slack"
+            [[alerts]]
+            name = "latency"
+            index = "logs"
+            query = '{ "query": "all" }'
+            aggregation = "median"
+            above = 500.0
+            webhook = "nowhere"
+            format = "xml""#;
+        let settings = Settings::from_str(cfg).unwrap();
+        assert_eq!(settings.alerts[0].interval, 60);
+        assert_eq!(settings.alerts[0].max_docs, 10);
+        let errors = settings.validate().unwrap_err();
+        assert_eq!(errors.len(), 5);
+        assert!(errors[0].contains("latency query"));
+        assert!(errors[1].contains("sum, min, max or avg"));
+        assert!(errors[2].contains("field"));
+        assert!(errors[3].contains("nowhere"));
+        assert!(errors[4].contains("json or slack"));
+    }
+
+    #[test]
+    fn rollups() {
+        let cfg = r#"
+            [[rollups]]
+            name = "hourly_errors"
+            query = "SELECT level, COUNT(*) AS errors FROM logs WHERE level = 'error' GROUP BY level"
+            target = "error_counts"
+            timestamp_field = "hour"
+            [[rollups]]
+            name = "hourly_errors"
+            query = "SELECT FROM"
+            target = "error_counts"
+            interval = 0"#;
+        let settings = Settings::from_str(cfg).unwrap();
+        assert_eq!(settings.rollups[0].interval, 3600);
+        let errors = settings.validate().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].contains("more than once"));
+        assert!(errors[1].contains("not valid SQL"));
+        assert!(errors[2].contains("interval"));
+    }
+
+    #[test]
+    fn analyzers() {
+        let cfg = r#"
+            [analyzers.tags]
+            tokenizer = "whitespace"
+            lowercase = true
+            [analyzers.default]
+            stemmer = "german""#;
+        let settings = Settings::from_str(cfg).unwrap();
+        assert_eq!(settings.analyzers["tags"], Analyzer::new(Split::Whitespace).with_lowercase());
+        assert_eq!(settings.analyzers["default"].stemmer, Some(Language::German));
+        let errors = settings.validate().unwrap_err();
+        assert_eq!(errors, vec!["analyzer default would replace a tantivy tokenizer".to_string()]);
+    }
+
     #[test]
     #[should_panic]
     fn bad_config_file() {
@@ -0,0 +1,180 @@
+//! Splitting an index into shards. A sharded index is a directory holding a description of how it's split,
+//! and one ordinary index per shard named after its number. Documents are routed to a shard by a hash of
+//! their routing field, and searches run against every shard with the results merged afterwards.
+
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::schema::Schema;
+use tantivy::Index;
+
+use crate::query::{
+    merge_buckets, merge_composite, merge_filters, merge_geohash_grid, merge_ranges, merge_significant_terms, Sort, SortOrder,
+};
+use crate::results::{ScoredDoc, SearchResults};
+use crate::storage::StorageSettings;
+use crate::{Error, Result};
+
+pub const SHARDS_FILENAME: &str = ".shards.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Sharding {
+    pub shards: usize,
+    /// The field whose value picks a document's shard. Without one, the whole document is hashed.
+    #[serde(default)]
+    pub routing_field: Option<String>,
+}
+
+impl Sharding {
+    pub fn new(shards: usize, routing_field: Option<String>) -> Result<Self> {
+        if shards == 0 {
+            return Err(Error::IOError("An index needs at least one shard".into()));
+        }
+        Ok(Sharding { shards, routing_field })
+    }
+
+    /// How the index at `path` is sharded, or `None` if it's an ordinary index
+    pub fn load(path: &Path) -> Result<Option<Sharding>> {
+        match fs::read_to_string(path.join(SHARDS_FILENAME)) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create a sharded index at `path`, with every shard stored as `storage` describes
+    pub fn create(&self, path: &Path, schema: &Schema, storage: &StorageSettings) -> Result<Vec<Index>> {
+        fs::create_dir_all(path)?;
+        fs::write(path.join(SHARDS_FILENAME), serde_json::to_string_pretty(self)?)?;
+        (0..self.shards)
+            .map(|shard| storage.create(&path.join(shard.to_string()), schema.clone()))
+            .collect()
+    }
+
+    /// The name a shard is kept under in the catalog. Index names come from a single path segment,
+    /// so they can't clash with an ordinary index.
+    pub fn shard_name(index: &str, shard: usize) -> String {
+        format!("{}/{}", index, shard)
+    }
+
+    pub fn shard_names(&self, index: &str) -> Vec<String> {
+        (0..self.shards).map(|shard| Sharding::shard_name(index, shard)).collect()
+    }
+
+    /// The shard a document belongs in
+    pub fn route(&self, doc: &Value) -> Result<usize> {
+        let key = match self.routing_field {
+            Some(ref field) => match doc.get(field) {
+                Some(Value::String(s)) => s.clone(),
+                Some(value) => value.to_string(),
+                None => return Err(Error::QueryError(format!("Document is missing routing field: {}", field))),
+            },
+            None => doc.to_string(),
+        };
+        Ok((fnv1a(key.as_bytes()) % self.shards as u64) as usize)
+    }
+}
+
+/// A hash that stays the same across Rust releases, since documents have to keep routing to the shard they were written to
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Merge the results of searching each shard into the top `limit`, ordered by `sort` when results were sorted
+/// and by score otherwise, adding up the buckets of their aggregations and the counts of their significant terms
+pub fn merge_results(results: Vec<SearchResults>, sort: Option<&Sort>, limit: usize) -> SearchResults {
+    let bucketed = results.iter().any(|r| r.buckets.is_some());
+    let significant = results.iter().any(|r| r.significant_terms.is_some());
+    let ranged = results.iter().any(|r| r.ranges.is_some());
+    let filtered = results.iter().any(|r| r.filters.is_some());
+    let gridded = results.iter().any(|r| r.geohash_grid.is_some());
+    let composed = results.iter().any(|r| r.composite.is_some());
+    let mut buckets = Vec::new();
+    let mut significant_terms = Vec::new();
+    let mut ranges = Vec::new();
+    let mut filters = Vec::new();
+    let mut cells = Vec::new();
+    let mut pages = Vec::new();
+    let mut docs: Vec<ScoredDoc> = Vec::new();
+    for result in results {
+        buckets.extend(result.buckets.unwrap_or_default());
+        significant_terms.extend(result.significant_terms);
+        ranges.extend(result.ranges);
+        filters.extend(result.filters);
+        cells.extend(result.geohash_grid);
+        pages.extend(result.composite);
+        docs.extend(result.docs);
+    }
+    match sort {
+        Some(sort) => docs.sort_by(|a, b| match sort.order {
+            SortOrder::Asc => a.sort_key.cmp(&b.sort_key),
+            SortOrder::Desc => b.sort_key.cmp(&a.sort_key),
+        }),
+        None => docs.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)),
+    }
+    docs.truncate(limit);
+    SearchResults::new(docs)
+        .with_buckets(if bucketed { Some(merge_buckets(buckets)) } else { None })
+        .with_significant_terms(if significant {
+            Some(merge_significant_terms(significant_terms))
+        } else {
+            None
+        })
+        .with_ranges(if ranged { Some(merge_ranges(ranges)) } else { None })
+        .with_filters(if filtered { Some(merge_filters(filters)) } else { None })
+        .with_geohash_grid(if gridded { Some(merge_geohash_grid(cells)) } else { None })
+        .with_composite(if composed { Some(merge_composite(pages)) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing() {
+        let sharding = Sharding::new(4, Some("user".into())).unwrap();
+        let doc = serde_json::json!({ "user": "alice", "message": "hello" });
+        let same_user = serde_json::json!({ "user": "alice", "message": "again" });
+        assert!(sharding.route(&doc).unwrap() < 4);
+        assert_eq!(sharding.route(&doc).unwrap(), sharding.route(&same_user).unwrap());
+        assert!(sharding.route(&serde_json::json!({ "message": "anonymous" })).is_err());
+
+        let spread: std::collections::HashSet<usize> = (0..100)
+            .map(|i| sharding.route(&serde_json::json!({ "user": i })).unwrap())
+            .collect();
+        assert_eq!(spread.len(), 4);
+        assert!(Sharding::new(0, None).is_err());
+        assert_eq!(sharding.shard_names("logs"), vec!["logs/0", "logs/1", "logs/2", "logs/3"]);
+    }
+
+    #[test]
+    fn test_merge_node_results() {
+        use tantivy::schema::NamedFieldDocument;
+
+        // Results from other nodes arrive as JSON, and have to keep what they were sorted by
+        let node = |keys: &[u64]| {
+            let docs = keys
+                .iter()
+                .map(|k| ScoredDoc::sorted(*k, NamedFieldDocument(Default::default())))
+                .collect();
+            let json = serde_json::to_string(&SearchResults::new(docs)).unwrap();
+            serde_json::from_str::<SearchResults>(&json).unwrap()
+        };
+        let sort = Sort {
+            field: "created".into(),
+            order: SortOrder::Desc,
+            missing: None,
+            script: None,
+        };
+        let merged = merge_results(vec![node(&[9, 4, 1]), node(&[7, 5])], Some(&sort), 3);
+        let keys: Vec<Option<u64>> = merged.docs.iter().map(|d| d.sort_key).collect();
+        assert_eq!(keys, vec![Some(9), Some(7), Some(5)]);
+        assert_eq!(merged.hits, 3);
+    }
+}
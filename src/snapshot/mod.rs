@@ -0,0 +1,739 @@
+//! Point-in-time backups of indexes. A snapshot is a directory in the snapshot repository holding the files of an
+//! index's last commit, along with a manifest describing them. Committed segment files never change, so they're
+//! hard linked into the snapshot rather than copied, which takes next to no time or space and never pauses writes.
+//! The metadata is written out from the commit the files were linked for, so the snapshot is consistent even when
+//! another commit lands while it's taken.
+//!
+//! Snapshots are incremental. A segment file an earlier snapshot of the index already holds is linked from that
+//! snapshot instead, so when the repository is on another file system than the index, only segments written since
+//! the last snapshot are copied.
+//!
+//! When an index's writes are logged, each snapshot records where in the log its commit stands, so an index can be
+//! restored to a point in time after the snapshot by replaying the writes logged since on top of it.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use futures::sync::oneshot;
+use futures::{future, Future, IntoFuture};
+use serde::{Deserialize, Serialize};
+use tantivy::Index;
+use tower_web::Response;
+
+use crate::cluster::rebalance::index_files;
+use crate::executor::blocking;
+use crate::index::{IndexCatalog, MOVING_PREFIX};
+use crate::settings::Settings;
+use crate::shard::SHARDS_FILENAME;
+use crate::storage::{DirectoryType, StorageSettings, STORAGE_FILENAME};
+use crate::tasks::Progress;
+use crate::wal::{self, Replay, WalPosition};
+use crate::{Error, Result};
+
+pub mod s3;
+pub mod schedule;
+
+pub use self::s3::S3Repository;
+pub use self::schedule::SnapshotScheduler;
+
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+/// How many times the files of a commit are linked before giving up, when merges keep removing them underneath
+const LINK_ATTEMPTS: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SnapshotFile {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Response, Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub name: String,
+    pub index: String,
+    /// When the snapshot was taken, in seconds since the Unix epoch
+    pub created: u64,
+    pub docs: u64,
+    pub files: Vec<SnapshotFile>,
+    /// How many of the files were shared with earlier snapshots rather than taken from the index
+    #[serde(default)]
+    pub reused: usize,
+    /// Where the commit of each shard stands in the index's write-ahead log, when its writes were logged
+    #[serde(default)]
+    pub wal: Option<WalPosition>,
+}
+
+/// Every snapshot of an index, oldest first
+#[derive(Response, Serialize, Debug)]
+pub struct Snapshots {
+    pub snapshots: Vec<Manifest>,
+}
+
+pub type SnapshotFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// Somewhere snapshots are kept
+pub trait SnapshotRepository: Send + Sync {
+    /// What the repository is addressed as in `/_snapshot/:repo` paths, the kind of place it keeps snapshots in
+    fn name(&self) -> &str;
+
+    /// Snapshot the last commit of `index` as `name`, or as the time it's taken if no name is given
+    fn snapshot(&self, catalog: Arc<RwLock<IndexCatalog>>, index: String, name: Option<String>) -> SnapshotFuture<Manifest>;
+
+    /// Every snapshot of `index`, oldest first
+    fn list(&self, index: String) -> SnapshotFuture<Vec<Manifest>>;
+
+    /// Delete snapshot `name` of `index`, leaving the files other snapshots share with it
+    fn delete(&self, index: String, name: String) -> SnapshotFuture<()>;
+
+    /// Bring snapshot `name` of `index` back as the index `into`, or as `index` itself if not given. No index of
+    /// that name may exist. The index is only served once every file has been verified against the manifest, and
+    /// `replay` has brought it forward from the snapshot if given.
+    fn restore(
+        &self,
+        catalog: Arc<RwLock<IndexCatalog>>,
+        index: String,
+        name: String,
+        into: Option<String>,
+        replay: Option<Replay>,
+        progress: Progress,
+    ) -> SnapshotFuture<Manifest>;
+}
+
+/// The repository configured in `settings`, if any
+pub fn repository(settings: &Settings) -> Option<Arc<SnapshotRepository>> {
+    if settings.snapshot_s3.enabled() {
+        Some(Arc::new(S3Repository::new(settings.snapshot_s3.clone())) as Arc<SnapshotRepository>)
+    } else {
+        settings
+            .snapshot_path()
+            .map(|path| Arc::new(FsRepository::new(path)) as Arc<SnapshotRepository>)
+    }
+}
+
+/// A directory snapshots are kept in, one directory per index holding one per snapshot
+#[derive(Clone, Debug)]
+pub struct FsRepository {
+    path: PathBuf,
+}
+
+impl FsRepository {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FsRepository { path: path.into() }
+    }
+
+    /// The directory the snapshots of `index` are kept in
+    fn index_dir(&self, index: &str) -> Result<PathBuf> {
+        check_name("index", index)?;
+        Ok(self.path.join(index))
+    }
+
+    fn take(&self, shards: &Shards, index: &str, name: Option<String>) -> Result<Manifest> {
+        let created = now();
+        let name = snapshot_name(name, created)?;
+        let dir = self.index_dir(index)?;
+        let dest = dir.join(&name);
+        if dest.exists() {
+            return Err(Error::IOError(format!("Snapshot {} of {} already exists", name, index)));
+        }
+
+        // Segment files are named after a unique id, so one of the same name and size holds the same data
+        let mut previous = HashMap::new();
+        for manifest in self.manifests(index)? {
+            for file in manifest.files {
+                let path = dir.join(&manifest.name).join(&file.name);
+                previous.insert((file.name, file.size), path);
+            }
+        }
+
+        // The snapshot is put together under a hidden name, so a half finished one is never listed
+        let partial = dir.join(format!(".{}", name));
+        if partial.exists() {
+            fs::remove_dir_all(&partial)?;
+        }
+        fs::create_dir_all(&partial)?;
+        let taken = stage(shards, &partial, &previous).and_then(|(docs, reused, wal)| {
+            let manifest = manifest_of(&partial, name, index, created, docs, reused, wal)?;
+            fs::write(partial.join(MANIFEST_FILENAME), serde_json::to_vec_pretty(&manifest)?)?;
+            fs::rename(&partial, &dest)?;
+            Ok(manifest)
+        });
+        if taken.is_err() {
+            let _ = fs::remove_dir_all(&partial);
+        }
+        taken
+    }
+
+    fn manifests(&self, index: &str) -> Result<Vec<Manifest>> {
+        let dir = self.index_dir(index)?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let manifest = entry?.path().join(MANIFEST_FILENAME);
+            if manifest.exists() {
+                snapshots.push(serde_json::from_slice::<Manifest>(&fs::read(manifest)?)?);
+            }
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    fn bring_back(
+        &self,
+        catalog: &RwLock<IndexCatalog>,
+        index: &str,
+        name: &str,
+        into: Option<String>,
+        replay: Option<&Replay>,
+        progress: &Progress,
+    ) -> Result<Manifest> {
+        let source = self.index_dir(index)?.join(snapshot_name(Some(name.to_string()), 0)?);
+        let manifest: Manifest = match fs::read(source.join(MANIFEST_FILENAME)) {
+            Ok(manifest) => serde_json::from_slice(&manifest)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::IOError(format!("Snapshot {} of {} does not exist", name, index)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (target, staging) = restore_staging(&catalog.read()?, index, into)?;
+        progress.expect(manifest.files.len() as u64, manifest.files.iter().map(|file| file.size).sum());
+        let cancellation = progress.cancellation();
+        let linked = manifest.files.iter().try_for_each(|file| -> Result<()> {
+            cancellation.check()?;
+            let to = staging.join(&file.name);
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            link_or_copy(&source.join(&file.name), &to)?;
+            progress.advance(file.size);
+            Ok(())
+        });
+        if let Err(e) = linked {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        adopt(catalog, &target, &staging, &manifest, replay)?;
+        Ok(manifest)
+    }
+}
+
+impl SnapshotRepository for FsRepository {
+    fn name(&self) -> &str {
+        "fs"
+    }
+
+    fn snapshot(&self, catalog: Arc<RwLock<IndexCatalog>>, index: String, name: Option<String>) -> SnapshotFuture<Manifest> {
+        let shards = catalog.read().map_err(Error::from).and_then(|cat| Shards::of(&cat, &index));
+        let repository = self.clone();
+        Box::new(future::result(shards).and_then(move |shards| blocking(move || repository.take(&shards, &index, name))))
+    }
+
+    fn list(&self, index: String) -> SnapshotFuture<Vec<Manifest>> {
+        Box::new(future::result(self.manifests(&index)))
+    }
+
+    fn delete(&self, index: String, name: String) -> SnapshotFuture<()> {
+        let deleted = snapshot_name(Some(name), 0).and_then(|name| {
+            let dir = self.index_dir(&index)?.join(&name);
+            if !dir.join(MANIFEST_FILENAME).exists() {
+                return Err(Error::IOError(format!("Snapshot {} of {} does not exist", name, index)));
+            }
+            // The manifest goes first, so a snapshot that's only partly deleted is no longer listed. Every snapshot
+            // holds its own links to the files it shares, so removing them leaves the others whole.
+            fs::remove_file(dir.join(MANIFEST_FILENAME))?;
+            fs::remove_dir_all(&dir)?;
+            Ok(())
+        });
+        Box::new(future::result(deleted))
+    }
+
+    fn restore(
+        &self,
+        catalog: Arc<RwLock<IndexCatalog>>,
+        index: String,
+        name: String,
+        into: Option<String>,
+        replay: Option<Replay>,
+        progress: Progress,
+    ) -> SnapshotFuture<Manifest> {
+        let repository = self.clone();
+        Box::new(blocking(move || {
+            repository.bring_back(&catalog, &index, &name, into, replay.as_ref(), &progress)
+        }))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// Runs work one piece after another, each starting once everything queued before it has finished or been given up
+/// on. Clones take their turns with each other.
+#[derive(Clone, Default)]
+pub struct Serial {
+    last: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+}
+
+impl Serial {
+    /// Run the future `f` makes once the work queued before it is done
+    pub fn run<F, R>(&self, f: F) -> impl Future<Item = R::Item, Error = R::Error>
+    where
+        F: FnOnce() -> R,
+        R: IntoFuture,
+    {
+        let (done, finished) = oneshot::channel::<()>();
+        let previous = self.last.lock().unwrap_or_else(|e| e.into_inner()).replace(finished);
+        let turn = match previous {
+            // The work before is done when its end of the channel is dropped
+            Some(previous) => future::Either::A(previous.then(|_| Ok(()))),
+            None => future::Either::B(future::ok(())),
+        };
+        turn.and_then(move |_| {
+            f().into_future().then(move |result| {
+                drop(done);
+                result
+            })
+        })
+    }
+}
+
+/// Make sure `name` can't escape the directory it's kept in, `kind` being what it names
+fn check_name(kind: &str, name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\\') {
+        return Err(Error::IOError(format!("Invalid {} name: {}", kind, name)));
+    }
+    Ok(())
+}
+
+/// `name`, or a name made from the time the snapshot is `created` if not given, as long as it can't escape the
+/// directory it's kept in
+fn snapshot_name(name: Option<String>, created: u64) -> Result<String> {
+    let name = name.unwrap_or_else(|| format!("snapshot-{}", created));
+    check_name("snapshot", &name)?;
+    Ok(name)
+}
+
+/// Where the shards of an index are and how to read their commits, taken from the catalog so a snapshot can be
+/// taken without holding on to it
+struct Shards {
+    root: PathBuf,
+    sharded: bool,
+    /// The identity of the index's write-ahead log, if its writes are logged
+    wal: Option<String>,
+    /// The directory of each shard within `root`, and the shard
+    shards: Vec<(PathBuf, Index)>,
+}
+
+impl Shards {
+    /// The shards of `index`, as long as it's kept on disk where it can be snapshotted
+    fn of(catalog: &IndexCatalog, index: &str) -> Result<Self> {
+        let root = catalog.index_path(index)?;
+        if StorageSettings::load(&root)?.0.directory == DirectoryType::Ram {
+            return Err(Error::IOError(format!(
+                "Index {} is kept in memory and can't be snapshotted",
+                index
+            )));
+        }
+        let (sharded, shards) = match catalog.sharding(index) {
+            Some(sharding) => {
+                let shards = catalog.shards(index)?;
+                let shards = (0..sharding.shards)
+                    .map(|shard| PathBuf::from(shard.to_string()))
+                    .zip(shards.into_iter().map(|shard| shard.get_index().clone()))
+                    .collect();
+                (true, shards)
+            }
+            None => (false, vec![(PathBuf::new(), catalog.get_index(index)?.get_index().clone())]),
+        };
+        let wal = catalog.wal(index).ok().map(|wal| wal.id().to_string());
+        Ok(Shards {
+            root,
+            sharded,
+            wal,
+            shards,
+        })
+    }
+}
+
+/// Link every shard's last commit into `dest`, returning how many documents they hold, how many files were found in
+/// `previous` snapshots, and where the commits stand in the index's log
+fn stage(shards: &Shards, dest: &Path, previous: &HashMap<(String, u64), PathBuf>) -> Result<(u64, usize, Option<WalPosition>)> {
+    let root = &shards.root;
+    if shards.sharded {
+        copy_if_exists(&root.join(SHARDS_FILENAME), &dest.join(SHARDS_FILENAME))?;
+    }
+
+    let (mut docs, mut reused, mut seqs) = (0, 0, Vec::new());
+    for (dir, shard) in &shards.shards {
+        fs::create_dir_all(dest.join(dir))?;
+        copy_if_exists(&root.join(dir).join(STORAGE_FILENAME), &dest.join(dir).join(STORAGE_FILENAME))?;
+        let (shard_docs, shard_reused, seq) = link_commit(shard, &root.join(dir), dest, dir, previous)?;
+        docs += shard_docs;
+        reused += shard_reused;
+        seqs.push(seq);
+    }
+    let wal = shards.wal.clone().map(|id| WalPosition { id, seqs });
+    Ok((docs, reused, wal))
+}
+
+/// Link the files of the last commit of the shard in `from` into `dir` within the snapshot at `dest`, preferring
+/// the copies in `previous` snapshots, by name within the snapshot and size. Returns how many documents the commit
+/// holds, how many files were found in `previous`, and the last logged write it holds.
+fn link_commit(
+    index: &Index,
+    from: &Path,
+    dest: &Path,
+    dir: &Path,
+    previous: &HashMap<(String, u64), PathBuf>,
+) -> Result<(u64, usize, u64)> {
+    let mut attempt = 0;
+    loop {
+        let metas = index.load_metas()?;
+        let mut reused = 0;
+        let linked = metas
+            .segments
+            .iter()
+            .flat_map(|segment| segment.list_files())
+            .map(|file| {
+                let source = from.join(&file);
+                let size = fs::metadata(&source)?.len();
+                let name = dir.join(&file).to_string_lossy().into_owned();
+                match previous.get(&(name, size)) {
+                    Some(earlier) if fs::hard_link(earlier, dest.join(dir).join(&file)).is_ok() => {
+                        reused += 1;
+                        Ok(())
+                    }
+                    _ => link_or_copy(&source, &dest.join(dir).join(&file)),
+                }
+            })
+            .collect::<io::Result<Vec<()>>>();
+        match linked {
+            Ok(_) => {
+                fs::write(dest.join(dir).join("meta.json"), serde_json::to_vec_pretty(&metas)?)?;
+                let docs = metas.segments.iter().map(|segment| u64::from(segment.num_docs())).sum();
+                return Ok((docs, reused, wal::committed_seq(&metas)));
+            }
+            // A merge finished and removed segments of the commit after it was read, so the newer one is taken
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && attempt + 1 < LINK_ATTEMPTS => attempt += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The manifest of the snapshot staged in `dir`
+fn manifest_of(
+    dir: &Path,
+    name: String,
+    index: &str,
+    created: u64,
+    docs: u64,
+    reused: usize,
+    wal: Option<WalPosition>,
+) -> Result<Manifest> {
+    let files = index_files(dir)?
+        .into_iter()
+        .filter(|(name, _)| name != MANIFEST_FILENAME)
+        .map(|(name, size)| SnapshotFile { name, size })
+        .collect();
+    Ok(Manifest {
+        name,
+        index: index.to_string(),
+        created,
+        docs,
+        files,
+        reused,
+        wal,
+    })
+}
+
+/// The name a snapshot of `index` is restored as, and an empty directory to put its files together in
+fn restore_staging(catalog: &IndexCatalog, index: &str, into: Option<String>) -> Result<(String, PathBuf)> {
+    let target = into.unwrap_or_else(|| index.to_string());
+    check_name("index", &target)?;
+    if catalog.exists(&target) {
+        return Err(Error::IOError(format!("Index {} already exists", target)));
+    }
+    // Staged in the first data path, like an index moved from another node, so adopting it is a rename
+    let staging = catalog.base_path().join(format!("{}{}", MOVING_PREFIX, target));
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+    Ok((target, staging))
+}
+
+/// Make sure every file of `manifest` was restored into `staging` whole
+fn verify(staging: &Path, manifest: &Manifest) -> Result<()> {
+    for file in &manifest.files {
+        let size = match fs::metadata(staging.join(&file.name)) {
+            Ok(metadata) => metadata.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::IOError(format!("Restored file {} is missing", file.name)));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if size != file.size {
+            return Err(Error::IOError(format!(
+                "Restored file {} is {} bytes, but {} in the snapshot",
+                file.name, size, file.size
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Start serving the index restored into `staging` as `target`, once its files are verified against `manifest` and
+/// `replay` has brought it forward
+fn adopt(catalog: &RwLock<IndexCatalog>, target: &str, staging: &Path, manifest: &Manifest, replay: Option<&Replay>) -> Result<()> {
+    let adopted = verify(staging, manifest)
+        .and_then(|_| match replay {
+            Some(replay) => {
+                let settings = catalog.read()?.settings.clone();
+                replay.apply(staging, manifest, &settings).map(|_| ())
+            }
+            None => Ok(()),
+        })
+        .and_then(|_| catalog.write()?.adopt_index(target, staging));
+    if adopted.is_err() {
+        let _ = fs::remove_dir_all(staging);
+    }
+    adopted
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if to.exists() {
+        return Ok(());
+    }
+    // Hard links can't cross file systems, for a repository on another disk the file is copied
+    fs::hard_link(from, to).or_else(|e| if from.exists() { fs::copy(from, to).map(|_| ()) } else { Err(e) })
+}
+
+fn copy_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::copy(from, to) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::IndexHandle;
+    use crate::handlers::index::{AddDocument, DeleteDoc, IndexOptions};
+    use tantivy::collector::Count;
+    use tantivy::query::TermQuery;
+    use tantivy::schema::{IndexRecordOption, SchemaBuilder, STORED, TEXT};
+    use tantivy::Term;
+
+    #[test]
+    fn test_snapshot() {
+        let path = std::env::temp_dir().join("toshi-snapshot-test");
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("data")).unwrap();
+        let mut catalog = IndexCatalog::with_path(path.join("data")).unwrap();
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("text", STORED | TEXT);
+        catalog
+            .create_index("logs", builder.build(), None, StorageSettings::default())
+            .unwrap();
+        for text in &["first", "second"] {
+            let doc: AddDocument = serde_json::from_value(serde_json::json!({
+                "options": { "commit": true },
+                "document": { "text": text }
+            }))
+            .unwrap();
+            catalog.get_index("logs").unwrap().add_document(doc).unwrap();
+        }
+
+        let catalog = Arc::new(RwLock::new(catalog));
+        let repository = FsRepository::new(path.join("snapshots"));
+        let snapshot = |name: &str| repository.snapshot(Arc::clone(&catalog), "logs".into(), Some(name.into())).wait();
+        let manifest = snapshot("nightly").unwrap();
+        assert_eq!(manifest.docs, 2);
+        assert!(manifest.files.iter().any(|file| file.name == "meta.json"));
+        assert!(snapshot("nightly").is_err());
+        assert!(snapshot("../escape").is_err());
+        for index in &["..", "../data", ".hidden", ""] {
+            assert!(repository.snapshot(Arc::clone(&catalog), index.to_string(), None).wait().is_err());
+            assert!(repository.list(index.to_string()).wait().is_err());
+            assert!(repository.delete(index.to_string(), "nightly".into()).wait().is_err());
+            let restored = repository.restore(
+                Arc::clone(&catalog),
+                index.to_string(),
+                "nightly".into(),
+                Some("escaped".into()),
+                None,
+                Progress::default(),
+            );
+            assert!(restored.wait().is_err());
+        }
+
+        let taken = Index::open_in_dir(path.join("snapshots").join("logs").join("nightly")).unwrap();
+        taken.load_searchers().unwrap();
+        assert_eq!(taken.searcher().num_docs(), 2);
+
+        // Only the segment added since is taken from the index for the next snapshot
+        let doc: AddDocument = serde_json::from_value(serde_json::json!({
+            "options": { "commit": true },
+            "document": { "text": "third" }
+        }))
+        .unwrap();
+        catalog.read().unwrap().get_index("logs").unwrap().add_document(doc).unwrap();
+        let incremental = snapshot("later").unwrap();
+        assert_eq!(incremental.docs, 3);
+        assert!(incremental.reused > 0);
+        assert!(incremental.reused < manifest.files.len());
+
+        let listed = repository.list("logs".into()).wait().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().any(|snapshot| snapshot.name == "nightly"));
+
+        let progress = Progress::default();
+        let restore = |into: Option<String>| {
+            repository
+                .restore(Arc::clone(&catalog), "logs".into(), "nightly".into(), into, None, progress.clone())
+                .wait()
+        };
+        assert!(restore(None).is_err());
+        restore(Some("restored".into())).unwrap();
+        let status = progress.status();
+        assert_eq!(status.done_files, manifest.files.len() as u64);
+        assert_eq!(status.done_bytes, status.total_bytes);
+        let restored = catalog.read().unwrap().get_index("restored").unwrap().get_index().clone();
+        restored.load_searchers().unwrap();
+        assert_eq!(restored.searcher().num_docs(), 2);
+
+        // A cancelled restore stops before its first file and leaves nothing behind
+        let cancelled = Progress::default();
+        cancelled.cancellation().cancel();
+        let into = Some("cancelled".to_string());
+        assert!(repository
+            .restore(Arc::clone(&catalog), "logs".into(), "nightly".into(), into, None, cancelled.clone())
+            .wait()
+            .is_err());
+        assert_eq!(cancelled.status().done_files, 0);
+        assert!(catalog.read().unwrap().get_index("cancelled").is_err());
+
+        repository.delete("logs".into(), "nightly".into()).wait().unwrap();
+        assert!(repository.delete("logs".into(), "nightly".into()).wait().is_err());
+        let listed = repository.list("logs".into()).wait().unwrap();
+        assert_eq!(listed.len(), 1);
+        let later = Index::open_in_dir(path.join("snapshots").join("logs").join("later")).unwrap();
+        later.load_searchers().unwrap();
+        assert_eq!(later.searcher().num_docs(), 3);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_point_in_time() {
+        let path = std::env::temp_dir().join(format!("toshi-pitr-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(path.join("data")).unwrap();
+        let mut settings = Settings::default();
+        settings.wal_retention_hours = 24;
+        let mut catalog = IndexCatalog::new(vec![path.join("data")], settings).unwrap();
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("text", STORED | TEXT);
+        catalog
+            .create_index("logs", builder.build(), None, StorageSettings::default())
+            .unwrap();
+        let catalog = Arc::new(RwLock::new(catalog));
+        let add = |text: &str| {
+            let doc: AddDocument = serde_json::from_value(serde_json::json!({
+                "options": { "commit": true },
+                "document": { "text": text }
+            }))
+            .unwrap();
+            catalog.read().unwrap().get_index("logs").unwrap().add_document(doc).unwrap();
+        };
+        add("first");
+        add("second");
+        let repository = FsRepository::new(path.join("snapshots"));
+        let manifest = repository
+            .snapshot(Arc::clone(&catalog), "logs".into(), Some("base".into()))
+            .wait()
+            .unwrap();
+        assert_eq!(manifest.wal.as_ref().unwrap().seqs, vec![2]);
+
+        // A bad delete between two writes after the snapshot
+        add("third");
+        let mut terms = HashMap::new();
+        terms.insert("text".to_string(), "first".to_string());
+        let delete = DeleteDoc {
+            options: Some(IndexOptions { commit: true }),
+            terms,
+        };
+        catalog.read().unwrap().delete_documents("logs", &delete).unwrap();
+        add("fourth");
+
+        let wal = Arc::clone(catalog.read().unwrap().wal("logs").unwrap());
+        assert_eq!(wal.last_seq(), 5);
+        let recover = |into: &str, until_seq: Option<u64>| {
+            let replay = Replay::new(Arc::clone(&wal), None, until_seq);
+            let manifests = repository.list("logs".into()).wait().unwrap();
+            assert_eq!(replay.nearest(&manifests).unwrap().name, "base");
+            repository
+                .restore(
+                    Arc::clone(&catalog),
+                    "logs".into(),
+                    "base".into(),
+                    Some(into.into()),
+                    Some(replay),
+                    Progress::default(),
+                )
+                .wait()
+                .unwrap();
+            let index = catalog.read().unwrap().get_index(into).unwrap().get_index().clone();
+            index.load_searchers().unwrap();
+            let searcher = index.searcher();
+            let field = index.schema().get_field("text").unwrap();
+            let count = |text: &str| {
+                let query = TermQuery::new(Term::from_field_text(field, text), IndexRecordOption::Basic);
+                searcher.search(&query, &Count).unwrap()
+            };
+            (searcher.num_docs(), count("first"), count("fourth"))
+        };
+        assert_eq!(recover("before-delete", Some(3)), (3, 1, 0));
+        assert_eq!(recover("latest", None), (3, 0, 1));
+
+        // A snapshot taken after the point can't be started from
+        let replay = Replay::new(Arc::clone(&wal), None, Some(1));
+        assert!(replay.nearest(&repository.list("logs".into()).wait().unwrap()).is_none());
+        drop(catalog);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_serial() {
+        let serial = Serial::default();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let (release, released) = oneshot::channel::<()>();
+        let first = {
+            let order = Arc::clone(&order);
+            serial.run(move || released.map(move |_| order.lock().unwrap().push(1)))
+        };
+        let second = {
+            let order = Arc::clone(&order);
+            serial.run(move || {
+                order.lock().unwrap().push(2);
+                Ok::<(), oneshot::Canceled>(())
+            })
+        };
+
+        // The second is ready to go right away, but waits for the first
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        runtime.spawn(second.map_err(|_| ()));
+        runtime.spawn(first.map_err(|_| ()));
+        runtime.block_on(future::lazy(|| release.send(()))).unwrap();
+        runtime.run().unwrap();
+        assert_eq!(*order.lock().unwrap(), vec![1, 2]);
+    }
+}
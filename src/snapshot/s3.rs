@@ -0,0 +1,541 @@
+//! Snapshots kept in an S3 compatible object store, such as S3 itself or MinIO, so they don't need a file system
+//! every node can reach. A snapshot is first put together as hard links next to the index, exactly as it would be in
+//! a directory repository, then streamed to the bucket file by file. Restores stream the files back into a hidden
+//! directory before the index is taken in.
+//!
+//! Within the bucket, an index's segment files are kept once under `<prefix><index>/segments/`, shared by every
+//! snapshot that holds them, so only the segments written since the last snapshot are uploaded. The metadata of each
+//! snapshot is kept under `<prefix><index>/snapshots/<name>/`, and the manifests of all of them in
+//! `<prefix><index>/snapshots.json`. Requests are signed with AWS Signature Version 4. A single file can't be larger
+//! than the 5GB an object store takes in one upload.
+//!
+//! Snapshots and deletes in the same bucket and prefix take turns, since both rewrite `snapshots.json` and a delete
+//! could otherwise remove a segment a snapshot being taken counts on having been uploaded already.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use chrono::Utc;
+use futures::{future, stream, Future, Stream};
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+
+use super::{
+    adopt, check_name, manifest_of, now, restore_staging, snapshot_name, stage, Manifest, Serial, Shards, SnapshotFile, SnapshotFuture,
+    SnapshotRepository,
+};
+use crate::executor::blocking;
+use crate::index::IndexCatalog;
+use crate::settings::S3Settings;
+use crate::tasks::Progress;
+use crate::wal::Replay;
+use crate::{Error, Result};
+
+/// The manifests of every snapshot of an index, kept next to its segments
+const INDEX_FILENAME: &str = "snapshots.json";
+
+/// What the directory a snapshot is put together in before it's uploaded starts with
+pub const STAGING_PREFIX: &str = ".snapshot-";
+
+/// How much of a file is read at a time while it's uploaded
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Uploads are streamed, so their bodies aren't hashed into the signature
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+lazy_static! {
+    /// The turns of the repositories in each bucket and prefix, shared by every repository made for them
+    static ref TURNS: Mutex<HashMap<String, Serial>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone)]
+pub struct S3Repository {
+    settings: S3Settings,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// What snapshots and deletes take turns on
+    turns: Serial,
+}
+
+impl S3Repository {
+    pub fn new(mut settings: S3Settings) -> Self {
+        if settings.access_key.is_empty() {
+            settings.access_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default();
+        }
+        if settings.secret_key.is_empty() {
+            settings.secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default();
+        }
+        let https = HttpsConnector::new(4).expect("Could not create TLS for Hyper");
+        let location = format!(
+            "{}/{}/{}",
+            settings.endpoint.trim_end_matches('/'),
+            settings.bucket,
+            settings.prefix
+        );
+        let turns = TURNS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(location)
+            .or_insert_with(Serial::default)
+            .clone();
+        S3Repository {
+            settings,
+            client: Client::builder().build(https),
+            turns,
+        }
+    }
+
+    /// Where `file` of snapshot `name` of `index` is kept. Segment files never change, so each is kept once for
+    /// every snapshot, while metadata is kept per snapshot.
+    fn key(&self, index: &str, name: &str, file: &str) -> String {
+        if file.ends_with(".json") {
+            format!("{}{}/snapshots/{}/{}", self.settings.prefix, index, name, file)
+        } else {
+            format!("{}{}/segments/{}", self.settings.prefix, index, file)
+        }
+    }
+
+    fn index_key(&self, index: &str) -> String {
+        format!("{}{}/{}", self.settings.prefix, index, INDEX_FILENAME)
+    }
+
+    /// A signed request for the object at `key`
+    fn request(&self, method: Method, key: &str, body: Body, length: Option<u64>) -> Result<Request<Body>> {
+        let endpoint = self.settings.endpoint.trim_end_matches('/');
+        let uri: Uri = format!("{}/{}/{}", endpoint, self.settings.bucket, uri_encode(key))
+            .parse()
+            .map_err(|e| Error::IOError(format!("Invalid object URL: {}", e)))?;
+        let host = match uri.authority_part() {
+            Some(authority) => authority.as_str().to_string(),
+            None => return Err(Error::IOError(format!("No host in {}", uri))),
+        };
+        let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let headers = [
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        let canonical = canonical_request(method.as_str(), uri.path(), "", &headers, UNSIGNED_PAYLOAD);
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.settings.access_key,
+            scope(&amz_date, &self.settings.region),
+            signed_headers(&headers),
+            signature(&self.settings.secret_key, &amz_date, &self.settings.region, &canonical)
+        );
+
+        let mut request = Request::builder();
+        request
+            .method(method)
+            .uri(uri)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header("x-amz-date", amz_date.as_str())
+            .header("authorization", authorization.as_str());
+        if let Some(length) = length {
+            request.header("content-length", length.to_string().as_str());
+        }
+        request.body(body).map_err(|e| Error::IOError(e.to_string()))
+    }
+
+    /// Send `request`, turning any answer but a success into an error. Objects that don't exist are `None`.
+    fn send(&self, request: Result<Request<Body>>) -> impl Future<Item = Option<Body>, Error = Error> {
+        let client = self.client.clone();
+        future::result(request).and_then(move |request| {
+            client.request(request).map_err(|e| Error::IOError(e.to_string())).and_then(
+                |response| -> Box<Future<Item = Option<Body>, Error = Error> + Send> {
+                    let status = response.status();
+                    if status.is_success() {
+                        Box::new(future::ok(Some(response.into_body())))
+                    } else if status == StatusCode::NOT_FOUND {
+                        Box::new(future::ok(None))
+                    } else {
+                        let failed = response
+                            .into_body()
+                            .concat2()
+                            .map_err(|e| Error::IOError(e.to_string()))
+                            .and_then(move |body| {
+                                Err(Error::IOError(format!(
+                                    "Object store answered {}: {}",
+                                    status,
+                                    String::from_utf8_lossy(&body)
+                                )))
+                            });
+                        Box::new(failed)
+                    }
+                },
+            )
+        })
+    }
+
+    fn manifests(&self, index: &str) -> impl Future<Item = Vec<Manifest>, Error = Error> {
+        let request = self.request(Method::GET, &self.index_key(index), Body::empty(), None);
+        self.send(request)
+            .and_then(|body| -> Box<Future<Item = Vec<Manifest>, Error = Error> + Send> {
+                match body {
+                    Some(body) => Box::new(
+                        body.concat2()
+                            .map_err(|e| Error::IOError(e.to_string()))
+                            .and_then(|body| Ok(serde_json::from_slice(&body)?)),
+                    ),
+                    None => Box::new(future::ok(Vec::new())),
+                }
+            })
+    }
+
+    /// Stream the file at `path` to `key`
+    fn upload(&self, key: &str, path: &Path) -> impl Future<Item = (), Error = Error> {
+        let request = fs::File::open(path)
+            .and_then(|file| Ok((file.metadata()?.len(), file)))
+            .map_err(Error::from)
+            .and_then(|(length, file)| {
+                let body = Body::wrap_stream(stream::iter_result(Chunks(file)));
+                self.request(Method::PUT, key, body, Some(length))
+            });
+        let key = key.to_string();
+        self.send(request).and_then(move |body| match body {
+            Some(_) => Ok(()),
+            None => Err(Error::IOError(format!("Unable to upload {}, does the bucket exist?", key))),
+        })
+    }
+
+    /// Stream the object at `key` into the file at `path`
+    fn download(&self, key: &str, path: PathBuf) -> impl Future<Item = (), Error = Error> {
+        let request = self.request(Method::GET, key, Body::empty(), None);
+        let key = key.to_string();
+        self.send(request)
+            .and_then(move |body| {
+                let body = body.ok_or_else(|| Error::IOError(format!("Snapshot file {} is missing", key)))?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                Ok((body, fs::File::create(&path)?))
+            })
+            .and_then(|(body, mut file)| {
+                body.map_err(|e| Error::IOError(e.to_string()))
+                    .for_each(move |chunk| file.write_all(&chunk).map_err(Error::from))
+            })
+    }
+
+    /// Put `shards` of `index` together in `staging` as snapshot `name`, linked rather than copied, so the upload isn't
+    /// affected by merges removing the files of the commit
+    fn prepare(shards: &Shards, index: &str, name: String, created: u64, staging: &Path) -> Result<Manifest> {
+        if staging.exists() {
+            fs::remove_dir_all(staging)?;
+        }
+        fs::create_dir_all(staging)?;
+        let (docs, _, wal) = stage(shards, staging, &HashMap::new())?;
+        manifest_of(staging, name, index, created, docs, 0, wal)
+    }
+
+    fn take(&self, catalog: Arc<RwLock<IndexCatalog>>, index: String, name: String, created: u64) -> SnapshotFuture<Manifest> {
+        let staged = catalog.read().map_err(Error::from).and_then(|cat| {
+            let staging = cat.base_path().join(format!("{}{}-{}", STAGING_PREFIX, index, name));
+            Ok((Shards::of(&cat, &index)?, staging))
+        });
+        let (shards, staging) = match staged {
+            Ok(staged) => staged,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let (repository, cleanup) = (self.clone(), staging.clone());
+
+        let taken = self
+            .manifests(&index)
+            .and_then(move |earlier| {
+                if earlier.iter().any(|manifest| manifest.name == name) {
+                    return Err(Error::IOError(format!("Snapshot {} of {} already exists", name, index)));
+                }
+                Ok((earlier, name, index))
+            })
+            .and_then(move |(earlier, name, index)| {
+                blocking(move || S3Repository::prepare(&shards, &index, name, created, &staging))
+                    .map(move |manifest| (earlier, manifest, staging))
+            })
+            .and_then(move |(earlier, mut manifest, staging)| {
+                // Segment files of the same name and size as one already uploaded hold the same data
+                let uploaded: HashSet<&SnapshotFile> = earlier.iter().flat_map(|manifest| &manifest.files).collect();
+                let upload: Vec<(String, PathBuf)> = manifest
+                    .files
+                    .iter()
+                    .filter(|file| file.name.ends_with(".json") || !uploaded.contains(file))
+                    .map(|file| {
+                        (
+                            repository.key(&manifest.index, &manifest.name, &file.name),
+                            staging.join(&file.name),
+                        )
+                    })
+                    .collect();
+                manifest.reused = manifest.files.len() - upload.len();
+                let uploads = repository.clone();
+                stream::iter_ok(upload)
+                    .for_each(move |(key, path)| uploads.upload(&key, &path))
+                    .map(move |_| (repository, manifest))
+            })
+            // The snapshot is only listed once all of its files are in place
+            .and_then(|(repository, manifest)| {
+                let index_key = repository.index_key(&manifest.index);
+                repository.manifests(&manifest.index).and_then(move |mut manifests| {
+                    manifests.push(manifest.clone());
+                    manifests.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.name.cmp(&b.name)));
+                    let body = serde_json::to_vec_pretty(&manifests)?;
+                    let length = body.len() as u64;
+                    Ok((
+                        repository.request(Method::PUT, &index_key, Body::from(body), Some(length)),
+                        repository,
+                        manifest,
+                    ))
+                })
+            })
+            .and_then(|(request, repository, manifest)| repository.send(request).map(|_| manifest))
+            .then(move |taken| {
+                blocking(move || {
+                    let _ = fs::remove_dir_all(&cleanup);
+                    Ok(())
+                })
+                .then(move |_| taken)
+            });
+        Box::new(taken)
+    }
+
+    fn remove(&self, index: String, name: String) -> SnapshotFuture<()> {
+        let repository = self.clone();
+        let deleted = self
+            .manifests(&index)
+            .and_then(move |mut manifests| {
+                let at = manifests
+                    .iter()
+                    .position(|manifest| manifest.name == name)
+                    .ok_or_else(|| Error::IOError(format!("Snapshot {} of {} does not exist", name, index)))?;
+                let deleted = manifests.remove(at);
+                // Segment files other snapshots still hold are kept
+                let kept: HashSet<&SnapshotFile> = manifests.iter().flat_map(|manifest| &manifest.files).collect();
+                let keys: Vec<String> = deleted
+                    .files
+                    .iter()
+                    .filter(|file| file.name.ends_with(".json") || !kept.contains(file))
+                    .map(|file| repository.key(&index, &name, &file.name))
+                    .collect();
+                let body = serde_json::to_vec_pretty(&manifests)?;
+                let length = body.len() as u64;
+                let request = repository.request(Method::PUT, &repository.index_key(&index), Body::from(body), Some(length));
+                Ok((repository, request, keys))
+            })
+            // The snapshot stops being listed before its files go, so it's never listed with files missing
+            .and_then(|(repository, request, keys)| repository.send(request).map(move |_| (repository, keys)))
+            .and_then(|(repository, keys)| {
+                stream::iter_ok(keys).for_each(move |key| {
+                    let request = repository.request(Method::DELETE, &key, Body::empty(), None);
+                    repository.send(request).map(|_| ())
+                })
+            });
+        Box::new(deleted)
+    }
+}
+
+impl SnapshotRepository for S3Repository {
+    fn name(&self) -> &str {
+        "s3"
+    }
+
+    fn snapshot(&self, catalog: Arc<RwLock<IndexCatalog>>, index: String, name: Option<String>) -> SnapshotFuture<Manifest> {
+        let created = now();
+        let name = match check_name("index", &index).and_then(|_| snapshot_name(name, created)) {
+            Ok(name) => name,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let repository = self.clone();
+        Box::new(self.turns.run(move || repository.take(catalog, index, name, created)))
+    }
+
+    fn list(&self, index: String) -> SnapshotFuture<Vec<Manifest>> {
+        Box::new(self.manifests(&index))
+    }
+
+    fn delete(&self, index: String, name: String) -> SnapshotFuture<()> {
+        let repository = self.clone();
+        Box::new(self.turns.run(move || repository.remove(index, name)))
+    }
+
+    fn restore(
+        &self,
+        catalog: Arc<RwLock<IndexCatalog>>,
+        index: String,
+        name: String,
+        into: Option<String>,
+        replay: Option<Replay>,
+        progress: Progress,
+    ) -> SnapshotFuture<Manifest> {
+        let repository = self.clone();
+        let restored = self
+            .manifests(&index)
+            .and_then(move |manifests| {
+                let manifest = manifests
+                    .into_iter()
+                    .find(|manifest| manifest.name == name)
+                    .ok_or_else(|| Error::IOError(format!("Snapshot {} of {} does not exist", name, index)))?;
+                let (target, staging) = restore_staging(&*catalog.read()?, &index, into)?;
+                Ok((catalog, manifest, target, staging))
+            })
+            .and_then(move |(catalog, manifest, target, staging)| {
+                let files: Vec<(String, PathBuf, u64)> = manifest
+                    .files
+                    .iter()
+                    .map(|file| {
+                        (
+                            repository.key(&manifest.index, &manifest.name, &file.name),
+                            staging.join(&file.name),
+                            file.size,
+                        )
+                    })
+                    .collect();
+                progress.expect(files.len() as u64, files.iter().map(|(_, _, size)| size).sum());
+                let cleanup = staging.clone();
+                let cancellation = progress.cancellation();
+                stream::iter_ok(files)
+                    .for_each(move |(key, path, size)| {
+                        let (progress, repository) = (progress.clone(), repository.clone());
+                        future::result(cancellation.check())
+                            .and_then(move |_| repository.download(&key, path))
+                            .map(move |_| progress.advance(size))
+                    })
+                    .and_then(move |_| {
+                        // Replaying the log writes to the index, so it's done on a blocking thread
+                        blocking(move || {
+                            adopt(&catalog, &target, &staging, &manifest, replay.as_ref())?;
+                            Ok(manifest)
+                        })
+                    })
+                    .map_err(move |e| {
+                        let _ = fs::remove_dir_all(&cleanup);
+                        e
+                    })
+            });
+        Box::new(restored)
+    }
+}
+
+/// A file read a chunk at a time, for streaming it into a request
+struct Chunks(fs::File);
+
+impl Iterator for Chunks {
+    type Item = io::Result<hyper::Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        match self.0.read(&mut buf) {
+            Ok(0) => None,
+            Ok(read) => {
+                buf.truncate(read);
+                Some(Ok(buf.into()))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Percent encode `key` for a URL path, leaving its slashes
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], message: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.input(message.as_bytes());
+    mac.result().code().to_vec()
+}
+
+/// The names of `headers`, which are lowercase and sorted, as they're listed in a signature
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+    headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";")
+}
+
+/// The request as it's hashed into a signature, with `headers` lowercase and sorted by name
+fn canonical_request(method: &str, path: &str, query: &str, headers: &[(&str, &str)], payload_hash: &str) -> String {
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        path,
+        query,
+        canonical_headers,
+        signed_headers(headers),
+        payload_hash
+    )
+}
+
+/// The day and region a signature made at `amz_date` holds for
+fn scope(amz_date: &str, region: &str) -> String {
+    format!("{}/{}/s3/aws4_request", &amz_date[..8], region)
+}
+
+/// The signature of `canonical`, a request made at `amz_date`
+fn signature(secret_key: &str, amz_date: &str, region: &str, canonical: &str) -> String {
+    let to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope(amz_date, region),
+        hex(&Sha256::digest(canonical.as_bytes()))
+    );
+    let key = [&amz_date[..8], region, "s3", "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", secret_key).into_bytes(), |key, part| hmac(&key, part));
+    hex(&hmac(&key, &to_sign))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Settings;
+
+    #[test]
+    fn test_signature() {
+        // The example GET Object request from the Signature Version 4 documentation
+        let empty = hex(&Sha256::digest(b""));
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com"),
+            ("range", "bytes=0-9"),
+            ("x-amz-content-sha256", empty.as_str()),
+            ("x-amz-date", "20130524T000000Z"),
+        ];
+        let canonical = canonical_request("GET", "/test.txt", "", &headers, &empty);
+        assert_eq!(signed_headers(&headers), "host;range;x-amz-content-sha256;x-amz-date");
+        assert_eq!(
+            signature(
+                "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                "20130524T000000Z",
+                "us-east-1",
+                &canonical
+            ),
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+        assert_eq!(scope("20130524T000000Z", "us-east-1"), "20130524/us-east-1/s3/aws4_request");
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut settings = Settings::default_snapshot_s3();
+        settings.prefix = "toshi/".into();
+        let repository = S3Repository::new(settings);
+        assert_eq!(
+            repository.key("logs", "nightly", "0/meta.json"),
+            "toshi/logs/snapshots/nightly/0/meta.json"
+        );
+        assert_eq!(repository.key("logs", "nightly", "0/abc.idx"), "toshi/logs/segments/0/abc.idx");
+        assert_eq!(repository.index_key("logs"), "toshi/logs/snapshots.json");
+        assert_eq!(uri_encode("logs/a b+c.json"), "logs/a%20b%2Bc.json");
+    }
+}
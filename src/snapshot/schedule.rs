@@ -0,0 +1,284 @@
+//! Taking snapshots unattended. Each policy in `snapshot_policies` snapshots its indexes on a cron schedule, naming
+//! the snapshots after itself, and afterwards deletes the ones it took earlier that its retention rules no longer
+//! keep. Snapshots taken by hand or by other policies are never deleted. Policies due at the same time run one after
+//! the other, as does a policy due while the last one is still running.
+
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures::{future, stream, Future, Stream};
+use log::{error, info, warn};
+use tokio::timer::Interval;
+
+use super::{now, Manifest, Serial, SnapshotRepository};
+use crate::index::IndexCatalog;
+use crate::settings::SnapshotPolicy;
+use crate::Error;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a policy runs, parsed from a cron expression of five fields: minute, hour, day of the month, month and day
+/// of the week, with Sunday as 0 or 7. Each field is `*`, a value, a range `a-b`, any of those with a step as in
+/// `*/15`, or a comma separated list of them. `@hourly`, `@daily`, `@weekly` and `@monthly` are also understood.
+/// Times are in UTC.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month and of the week were both restricted, in which case either one matching will do
+    either_day: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("'{}' doesn't have the five fields of a cron expression", expression));
+        }
+        let mut weekdays = field(fields[4], 0, 7)?;
+        // Sunday can be written as 7 as well as 0
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Schedule {
+            minutes: field(fields[0], 0, 59)?,
+            hours: field(fields[1], 0, 23)?,
+            days: field(fields[2], 1, 31)?,
+            months: field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: fields[2] != "*" && fields[4] != "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule runs in the minute `time` falls in
+    pub fn matches(&self, time: &DateTime<Utc>) -> bool {
+        let bit = |set: u64, value: u32| set & (1 << value) != 0;
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = if self.either_day { day || weekday } else { day && weekday };
+        bit(self.minutes, time.minute()) && bit(self.hours, time.hour()) && bit(self.months, time.month()) && day_matches
+    }
+}
+
+/// The values one field of a cron expression allows, as a set of bits
+fn field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("'{}' isn't a valid cron field for values from {} to {}", field, min, max);
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(at) => (&part[..at], part[at + 1..].parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.find('-') {
+                Some(at) => (
+                    range[..at].parse().map_err(|_| invalid())?,
+                    range[at + 1..].parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    // A single value with a step runs from it to the end of the field
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if step == 0 || from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        for value in (from..=to).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// The names of the snapshots among `manifests` that `policy` took and no longer keeps at the time `now`, in
+/// seconds since the Unix epoch
+pub fn expired(policy: &SnapshotPolicy, manifests: &[Manifest], now: u64) -> Vec<String> {
+    let prefix = policy.snapshot_prefix();
+    let mut taken: Vec<&Manifest> = manifests.iter().filter(|manifest| manifest.name.starts_with(&prefix)).collect();
+    taken.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.name.cmp(&a.name)));
+    taken
+        .into_iter()
+        .enumerate()
+        .filter(|(newer, manifest)| {
+            let too_many = policy.keep.map_or(false, |keep| *newer >= keep);
+            let too_old = policy
+                .max_age_days
+                .map_or(false, |days| manifest.created + days * SECONDS_PER_DAY < now);
+            too_many || too_old
+        })
+        .map(|(_, manifest)| manifest.name.clone())
+        .collect()
+}
+
+/// Runs every snapshot policy on its schedule
+pub struct SnapshotScheduler {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    repository: Arc<SnapshotRepository>,
+    policies: Vec<(SnapshotPolicy, Schedule)>,
+    /// What the policies take turns on, so they never work on the repository at once
+    turns: Serial,
+}
+
+impl SnapshotScheduler {
+    /// Policies whose schedules don't parse are left out, settings validation reports them
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, repository: Arc<SnapshotRepository>, policies: &[SnapshotPolicy]) -> Self {
+        let policies = policies
+            .iter()
+            .filter_map(|policy| policy.schedule.parse().ok().map(|schedule| (policy.clone(), schedule)))
+            .collect();
+        SnapshotScheduler {
+            catalog,
+            repository,
+            policies,
+            turns: Serial::default(),
+        }
+    }
+
+    /// Check the schedules at the start of every minute
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        let into_minute = Duration::from_secs(60 - now() % 60);
+        Interval::new(Instant::now() + into_minute, Duration::from_secs(60))
+            .map_err(|e| error!("Snapshot schedule timer failed: {}", e))
+            .for_each(move |_| {
+                let time = Utc::now();
+                for (policy, schedule) in &self.policies {
+                    if schedule.matches(&time) {
+                        let applied = self.apply(policy.clone());
+                        tokio::spawn(self.turns.run(move || applied));
+                    }
+                }
+                Ok(())
+            })
+    }
+
+    /// Snapshot every index of `policy`, then delete the snapshots it no longer keeps
+    fn apply(&self, policy: SnapshotPolicy) -> impl Future<Item = (), Error = ()> {
+        let indexes = if policy.indexes.is_empty() {
+            match self.catalog.read() {
+                Ok(cat) => cat.index_names(),
+                Err(e) => {
+                    error!("Unable to read the index catalog: {}", e);
+                    Vec::new()
+                }
+            }
+        } else {
+            policy.indexes.clone()
+        };
+        let (catalog, repository) = (Arc::clone(&self.catalog), Arc::clone(&self.repository));
+        stream::iter_ok(indexes).for_each(move |index| {
+            let name = format!("{}{}", policy.snapshot_prefix(), now());
+            let (policy, retain, repository) = (policy.clone(), Arc::clone(&repository), Arc::clone(&repository));
+            repository
+                .snapshot(Arc::clone(&catalog), index.clone(), Some(name))
+                .and_then(move |manifest| {
+                    info!("Policy {} took snapshot {} of {}", policy.name, manifest.name, index);
+                    retain.list(index.clone()).map(move |manifests| (retain, policy, index, manifests))
+                })
+                .and_then(|(repository, policy, index, manifests)| {
+                    let deletes = expired(&policy, &manifests, now()).into_iter().map(move |name| {
+                        let index = index.clone();
+                        repository
+                            .delete(index.clone(), name.clone())
+                            .then(move |deleted| -> Result<(), Error> {
+                                match deleted {
+                                    Ok(()) => info!("Deleted expired snapshot {} of {}", name, index),
+                                    Err(e) => warn!("Failed to delete expired snapshot {} of {}: {}", name, index, e),
+                                }
+                                Ok(())
+                            })
+                    });
+                    future::join_all(deletes.collect::<Vec<_>>()).map(|_| ())
+                })
+                // One index failing doesn't keep the others from being snapshotted
+                .or_else(|e| {
+                    warn!("Scheduled snapshot failed: {}", e);
+                    Ok(())
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_schedule() {
+        let every_quarter: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // 2019-03-04 was a Monday
+        assert!(every_quarter.matches(&Utc.ymd(2019, 3, 4).and_hms(9, 45, 0)));
+        assert!(!every_quarter.matches(&Utc.ymd(2019, 3, 4).and_hms(9, 50, 0)));
+        assert!(!every_quarter.matches(&Utc.ymd(2019, 3, 4).and_hms(18, 0, 0)));
+        assert!(!every_quarter.matches(&Utc.ymd(2019, 3, 3).and_hms(9, 45, 0)));
+
+        let daily: Schedule = "@daily".parse().unwrap();
+        assert!(daily.matches(&Utc.ymd(2019, 3, 3).and_hms(0, 0, 30)));
+        assert!(!daily.matches(&Utc.ymd(2019, 3, 3).and_hms(0, 1, 0)));
+
+        // With both days restricted, either one matching is enough
+        let either: Schedule = "0 3 1 * 7".parse().unwrap();
+        assert!(either.matches(&Utc.ymd(2019, 3, 3).and_hms(3, 0, 0)));
+        assert!(either.matches(&Utc.ymd(2019, 3, 1).and_hms(3, 0, 0)));
+        assert!(!either.matches(&Utc.ymd(2019, 3, 2).and_hms(3, 0, 0)));
+
+        assert!("* * *".parse::<Schedule>().is_err());
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("5-1 * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn test_expired() {
+        let manifest = |name: &str, created: u64| Manifest {
+            name: name.into(),
+            index: "logs".into(),
+            created,
+            docs: 0,
+            files: Vec::new(),
+            reused: 0,
+            wal: None,
+        };
+        let day = SECONDS_PER_DAY;
+        let manifests = vec![
+            manifest("nightly-1", day),
+            manifest("nightly-2", 2 * day),
+            manifest("by-hand", 2 * day),
+            manifest("nightly-3", 3 * day),
+            manifest("nightly-4", 4 * day),
+        ];
+        let mut policy = SnapshotPolicy {
+            name: "nightly".into(),
+            schedule: "@daily".into(),
+            indexes: Vec::new(),
+            keep: Some(2),
+            max_age_days: None,
+        };
+        assert_eq!(expired(&policy, &manifests, 5 * day), vec!["nightly-2", "nightly-1"]);
+
+        policy.keep = None;
+        policy.max_age_days = Some(2);
+        assert_eq!(expired(&policy, &manifests, 5 * day), vec!["nightly-2", "nightly-1"]);
+        policy.max_age_days = Some(10);
+        assert!(expired(&policy, &manifests, 5 * day).is_empty());
+    }
+}
@@ -0,0 +1,1130 @@
+//! A read only subset of SQL over an index. A statement is parsed, its `WHERE` clause compiled into a Toshi query,
+//! and the documents it matches turned into a table. Statements that group or aggregate are computed from every
+//! matching document, so they're refused when more than `MAX_AGGREGATED_DOCS` documents match.
+//!
+//! ```sql
+//! SELECT level, COUNT(*) AS events, AVG(duration) FROM logs
+//! WHERE MATCH(message, 'timeout') AND duration > 100
+//! GROUP BY level ORDER BY events DESC LIMIT 5
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tantivy::schema::{FieldType, Schema};
+use tower_web::Response;
+
+use crate::analysis;
+use crate::query::{Query, Request, Sort, SortOrder};
+use crate::results::{ScoredDoc, SearchResults};
+use crate::settings::Settings;
+use crate::{Error, Result};
+
+/// The most documents a statement that groups or aggregates can be computed from
+pub const MAX_AGGREGATED_DOCS: usize = 10_000;
+
+/// How deeply a `WHERE` clause's conditions can be nested in parentheses, `NOT`s and chains of `AND` and `OR`
+pub const MAX_DEPTH: usize = 64;
+
+const KEYWORDS: &[&str] = &[
+    "select", "from", "where", "group", "order", "by", "limit", "and", "or", "not", "between", "in", "like", "as", "asc", "desc",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    /// A double quoted identifier, which can't be taken for a keyword
+    Quoted(String),
+    Str(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) | Token::Number(word) => write!(f, "{}", word),
+            Token::Quoted(ident) => write!(f, "\"{}\"", ident),
+            Token::Str(text) => write!(f, "'{}'", text),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: &[&str] = &["<=", ">=", "<>", "!=", "=", "<", ">", "(", ")", ",", "*", ";"];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = Vec::new();
+    let mut at = 0;
+    while at < chars.len() {
+        let c = chars[at];
+        let rest: String = chars[at..chars.len().min(at + 2)].iter().collect();
+        if c.is_whitespace() {
+            at += 1;
+        } else if c == '\'' || c == '"' {
+            // A quote is escaped by doubling it
+            let mut text = String::new();
+            at += 1;
+            loop {
+                match chars.get(at) {
+                    None => return Err(Error::QueryError("Unterminated quote in SQL".into())),
+                    Some(&q) if q == c && chars.get(at + 1) == Some(&c) => {
+                        text.push(c);
+                        at += 2;
+                    }
+                    Some(&q) if q == c => {
+                        at += 1;
+                        break;
+                    }
+                    Some(&other) => {
+                        text.push(other);
+                        at += 1;
+                    }
+                }
+            }
+            tokens.push(if c == '\'' { Token::Str(text) } else { Token::Quoted(text) });
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(at + 1).map_or(false, char::is_ascii_digit)) {
+            let start = at;
+            at += 1;
+            while at < chars.len() && (chars[at].is_ascii_digit() || chars[at] == '.') {
+                at += 1;
+            }
+            tokens.push(Token::Number(chars[start..at].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' || c == '@' {
+            let start = at;
+            while at < chars.len() && (chars[at].is_alphanumeric() || "_.@".contains(chars[at])) {
+                at += 1;
+            }
+            tokens.push(Token::Word(chars[start..at].iter().collect()));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            at += symbol.len();
+            tokens.push(Token::Symbol(*symbol));
+        } else {
+            return Err(Error::QueryError(format!("Unexpected '{}' in SQL", c)));
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl Function {
+    pub fn parse(name: &str) -> Option<Function> {
+        match name.to_lowercase().as_str() {
+            "count" => Some(Function::Count),
+            "sum" => Some(Function::Sum),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "avg" => Some(Function::Avg),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Column {
+    Field {
+        name: String,
+        alias: Option<String>,
+    },
+    /// An aggregate over a field, or over every document for `COUNT(*)`
+    Aggregate {
+        function: Function,
+        field: Option<String>,
+        alias: Option<String>,
+    },
+}
+
+impl Column {
+    /// The column's name in the table
+    pub fn name(&self) -> String {
+        match self {
+            Column::Field { alias: Some(alias), .. } | Column::Aggregate { alias: Some(alias), .. } => alias.clone(),
+            Column::Field { name, .. } => name.clone(),
+            Column::Aggregate { function, field, .. } => {
+                format!("{:?}({})", function, field.as_ref().map_or("*", String::as_str)).to_lowercase()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        field: String,
+        op: Comparison,
+        value: Value,
+    },
+    Between {
+        field: String,
+        low: Value,
+        high: Value,
+    },
+    In {
+        field: String,
+        values: Vec<Value>,
+    },
+    Like {
+        field: String,
+        pattern: String,
+    },
+    /// Full text search of a field, `MATCH(field, 'text')`
+    Match {
+        field: String,
+        text: String,
+    },
+}
+
+impl Expr {
+    fn depth(&self) -> usize {
+        match self {
+            Expr::And(left, right) | Expr::Or(left, right) => left.depth().max(right.depth()) + 1,
+            Expr::Not(expr) => expr.depth() + 1,
+            _ => 1,
+        }
+    }
+}
+
+/// A parsed `SELECT` statement
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    /// The columns selected, every stored field for `*` when empty
+    pub columns: Vec<Column>,
+    pub index: String,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<String>,
+    pub order_by: Vec<(String, SortOrder)>,
+    pub limit: Option<usize>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+    /// How many parentheses and `NOT`s the parser is inside of
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn unexpected<T>(&self) -> Result<T> {
+        match self.peek() {
+            Some(token) => Err(Error::QueryError(format!("Unexpected {} in SQL", token))),
+            None => Err(Error::QueryError("Unexpected end of SQL".into())),
+        }
+    }
+
+    fn too_deep<T>(&self) -> Result<T> {
+        Err(Error::QueryError(format!(
+            "SQL conditions can't be nested more than {} deep",
+            MAX_DEPTH
+        )))
+    }
+
+    /// Parses with `parse` one level further in, so conditions nested too deeply are refused before they run the
+    /// stack out
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        if self.depth >= MAX_DEPTH {
+            return self.too_deep();
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    /// `expr`, unless putting it together made it too deep, as long chains of `AND` and `OR` do
+    fn node(&self, expr: Expr) -> Result<Expr> {
+        if expr.depth() > MAX_DEPTH {
+            return self.too_deep();
+        }
+        Ok(expr)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if self.keyword(keyword) {
+            Ok(())
+        } else {
+            self.unexpected()
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(s)) if *s == symbol => {
+                self.at += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        if self.symbol(symbol) {
+            Ok(())
+        } else {
+            self.unexpected()
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(Token::Word(word)) if !KEYWORDS.contains(&word.to_lowercase().as_str()) => {}
+            Some(Token::Quoted(_)) => {}
+            _ => return self.unexpected(),
+        }
+        match self.next() {
+            Some(Token::Word(ident)) | Some(Token::Quoted(ident)) => Ok(ident),
+            _ => self.unexpected(),
+        }
+    }
+
+    fn literal(&mut self) -> Result<Value> {
+        let value = match self.peek() {
+            Some(Token::Str(text)) => Value::String(text.clone()),
+            Some(Token::Number(number)) => {
+                serde_json::from_str(number).map_err(|_| Error::QueryError(format!("{} isn't a number", number)))?
+            }
+            _ => return self.unexpected(),
+        };
+        self.at += 1;
+        Ok(value)
+    }
+
+    fn alias(&mut self) -> Result<Option<String>> {
+        if self.keyword("as") {
+            return self.identifier().map(Some);
+        }
+        match self.peek() {
+            Some(Token::Word(word)) if !KEYWORDS.contains(&word.to_lowercase().as_str()) => self.identifier().map(Some),
+            Some(Token::Quoted(_)) => self.identifier().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn column(&mut self) -> Result<Column> {
+        let function = match (self.peek(), self.tokens.get(self.at + 1)) {
+            (Some(Token::Word(word)), Some(Token::Symbol("("))) => match Function::parse(word) {
+                Some(function) => Some(function),
+                None => return Err(Error::QueryError(format!("Unknown function {}", word))),
+            },
+            _ => None,
+        };
+        match function {
+            Some(function) => {
+                self.at += 2;
+                let field = if function == Function::Count && self.symbol("*") {
+                    None
+                } else {
+                    Some(self.identifier()?)
+                };
+                self.expect_symbol(")")?;
+                let alias = self.alias()?;
+                Ok(Column::Aggregate { function, field, alias })
+            }
+            None => {
+                let name = self.identifier()?;
+                let alias = self.alias()?;
+                Ok(Column::Field { name, alias })
+            }
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement> {
+        self.expect_keyword("select")?;
+        let mut columns = Vec::new();
+        if !self.symbol("*") {
+            loop {
+                columns.push(self.column()?);
+                if !self.symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.expect_keyword("from")?;
+        // Index names can hold characters identifiers can't, so they can also be given as strings
+        let index = match self.peek() {
+            Some(Token::Str(index)) => {
+                let index = index.clone();
+                self.at += 1;
+                index
+            }
+            _ => self.identifier()?,
+        };
+        let filter = if self.keyword("where") { Some(self.or()?) } else { None };
+        let mut group_by = Vec::new();
+        if self.keyword("group") {
+            self.expect_keyword("by")?;
+            loop {
+                group_by.push(self.identifier()?);
+                if !self.symbol(",") {
+                    break;
+                }
+            }
+        }
+        let mut order_by = Vec::new();
+        if self.keyword("order") {
+            self.expect_keyword("by")?;
+            loop {
+                let field = match self.peek() {
+                    // Aggregates are ordered by under the name of their column
+                    Some(Token::Word(_)) if self.tokens.get(self.at + 1) == Some(&Token::Symbol("(")) => self.column()?.name(),
+                    _ => self.identifier()?,
+                };
+                let order = if self.keyword("desc") {
+                    SortOrder::Desc
+                } else {
+                    self.keyword("asc");
+                    SortOrder::Asc
+                };
+                order_by.push((field, order));
+                if !self.symbol(",") {
+                    break;
+                }
+            }
+        }
+        let limit = if self.keyword("limit") {
+            match self.literal()?.as_u64() {
+                Some(limit) => Some(limit as usize),
+                None => return Err(Error::QueryError("LIMIT must be a whole number".into())),
+            }
+        } else {
+            None
+        };
+        self.symbol(";");
+        if self.peek().is_some() {
+            return self.unexpected();
+        }
+        Ok(Statement {
+            columns,
+            index,
+            filter,
+            group_by,
+            order_by,
+            limit,
+        })
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = self.node(Expr::Or(Box::new(expr), Box::new(self.and()?)))?;
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.keyword("and") {
+            expr = self.node(Expr::And(Box::new(expr), Box::new(self.not()?)))?;
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.keyword("not") {
+            return self.node(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        if self.symbol("(") {
+            let expr = self.nested(Self::or)?;
+            self.expect_symbol(")")?;
+            return Ok(expr);
+        }
+        if let (Some(Token::Word(word)), Some(Token::Symbol("("))) = (self.peek(), self.tokens.get(self.at + 1)) {
+            if word.eq_ignore_ascii_case("match") {
+                self.at += 2;
+                let field = self.identifier()?;
+                self.expect_symbol(",")?;
+                let text = match self.literal()? {
+                    Value::String(text) => text,
+                    _ => return Err(Error::QueryError("MATCH searches for a string".into())),
+                };
+                self.expect_symbol(")")?;
+                return Ok(Expr::Match { field, text });
+            }
+        }
+        self.predicate()
+    }
+
+    fn predicate(&mut self) -> Result<Expr> {
+        let field = self.identifier()?;
+        let negated = self.keyword("not");
+        let expr = if self.keyword("between") {
+            let low = self.literal()?;
+            self.expect_keyword("and")?;
+            let high = self.literal()?;
+            Expr::Between { field, low, high }
+        } else if self.keyword("in") {
+            self.expect_symbol("(")?;
+            let mut values = vec![self.literal()?];
+            while self.symbol(",") {
+                values.push(self.literal()?);
+            }
+            self.expect_symbol(")")?;
+            Expr::In { field, values }
+        } else if self.keyword("like") {
+            match self.literal()? {
+                Value::String(pattern) => Expr::Like { field, pattern },
+                _ => return Err(Error::QueryError("LIKE matches a string".into())),
+            }
+        } else if negated {
+            return self.unexpected();
+        } else {
+            let op = match self.next() {
+                Some(Token::Symbol("=")) => Comparison::Eq,
+                Some(Token::Symbol("<")) => Comparison::Lt,
+                Some(Token::Symbol("<=")) => Comparison::Le,
+                Some(Token::Symbol(">")) => Comparison::Gt,
+                Some(Token::Symbol(">=")) => Comparison::Ge,
+                Some(Token::Symbol("!=")) | Some(Token::Symbol("<>")) => {
+                    let value = self.literal()?;
+                    return Ok(Expr::Not(Box::new(Expr::Compare {
+                        field,
+                        op: Comparison::Eq,
+                        value,
+                    })));
+                }
+                _ => {
+                    self.at -= 1;
+                    return self.unexpected();
+                }
+            };
+            let value = self.literal()?;
+            Expr::Compare { field, op, value }
+        };
+        Ok(if negated { Expr::Not(Box::new(expr)) } else { expr })
+    }
+}
+
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::And(left, right) => {
+            let mut all = conjuncts(left);
+            all.extend(conjuncts(right));
+            all
+        }
+        other => vec![other],
+    }
+}
+
+fn disjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Or(left, right) => {
+            let mut all = disjuncts(left);
+            all.extend(disjuncts(right));
+            all
+        }
+        other => vec![other],
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Compiles conditions into the JSON of Toshi queries, using the schema to tell text fields from numeric ones
+struct Compiler<'a> {
+    schema: &'a Schema,
+}
+
+impl<'a> Compiler<'a> {
+    fn field_type(&self, field: &str) -> Result<&'a FieldType> {
+        let field = self
+            .schema
+            .get_field(field)
+            .ok_or_else(|| Error::UnknownIndexField(field.to_string()))?;
+        Ok(self.schema.get_field_entry(field).field_type())
+    }
+
+    fn clause(&self, expr: &Expr) -> Result<Value> {
+        match expr {
+            Expr::And(..) | Expr::Not(_) => {
+                let (mut must, mut must_not) = (Vec::new(), Vec::new());
+                for conjunct in conjuncts(expr) {
+                    match conjunct {
+                        Expr::Not(negated) => must_not.push(self.clause(negated)?),
+                        other => must.push(self.clause(other)?),
+                    }
+                }
+                // Excluding documents only narrows down the ones something else matches
+                if must.is_empty() {
+                    return Err(Error::QueryError(
+                        "NOT and != need to be joined with AND to a condition that isn't negated".into(),
+                    ));
+                }
+                Ok(json!({ "bool": { "must": must, "must_not": must_not } }))
+            }
+            Expr::Or(..) => {
+                let should = disjuncts(expr).into_iter().map(|e| self.clause(e)).collect::<Result<Vec<_>>>()?;
+                Ok(json!({ "bool": { "should": should } }))
+            }
+            Expr::Compare { field, op, value } => {
+                let (low, high) = match op {
+                    Comparison::Eq => return self.equals(field, value),
+                    Comparison::Lt => (None, Some(("lt", value))),
+                    Comparison::Le => (None, Some(("lte", value))),
+                    Comparison::Gt => (Some(("gt", value)), None),
+                    Comparison::Ge => (Some(("gte", value)), None),
+                };
+                self.range(field, low, high)
+            }
+            Expr::Between { field, low, high } => self.range(field, Some(("gte", low)), Some(("lte", high))),
+            Expr::In { field, values } => {
+                let should = values.iter().map(|value| self.equals(field, value)).collect::<Result<Vec<_>>>()?;
+                Ok(json!({ "bool": { "should": should } }))
+            }
+            Expr::Like { field, pattern } => {
+                self.text_field(field, "LIKE")?;
+                let regex: String = pattern
+                    .split('%')
+                    .map(|part| part.split('_').map(escape_regex).collect::<Vec<_>>().join("."))
+                    .collect::<Vec<_>>()
+                    .join(".*");
+                Ok(json!({ "regexp": { field.as_str(): regex } }))
+            }
+            Expr::Match { field, text } => {
+                self.text_field(field, "MATCH")?;
+                let terms: Vec<String> = text
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .map(|word| format!("{}:{}", field, word))
+                    .collect();
+                if terms.is_empty() {
+                    return Err(Error::QueryError(format!("MATCH on {} has no words to search for", field)));
+                }
+                Ok(json!({ "raw": terms.join(" ") }))
+            }
+        }
+    }
+
+    fn text_field(&self, field: &str, what: &str) -> Result<()> {
+        match self.field_type(field)? {
+            FieldType::Str(_) => Ok(()),
+            _ => Err(Error::QueryError(format!(
+                "{} only works on text fields, which {} isn't",
+                what, field
+            ))),
+        }
+    }
+
+    fn equals(&self, field: &str, value: &Value) -> Result<Value> {
+        match (self.field_type(field)?, value) {
+            (FieldType::Str(_), Value::String(text)) => Ok(json!({ "term": { field: text } })),
+            (FieldType::Str(_), other) => Ok(json!({ "term": { field: other.to_string() } })),
+            // Numeric fields are matched by a range holding only the value
+            _ => self.range(field, Some(("gte", value)), Some(("lte", value))),
+        }
+    }
+
+    /// A range over a numeric field, filling in whichever bound isn't given with the extreme of the field's type
+    fn range(&self, field: &str, low: Option<(&str, &Value)>, high: Option<(&str, &Value)>) -> Result<Value> {
+        let (min, max): (Value, Value) = match self.field_type(field)? {
+            FieldType::I64(_) => (i64::min_value().into(), i64::max_value().into()),
+            FieldType::U64(_) => (u64::min_value().into(), u64::max_value().into()),
+            _ => {
+                return Err(Error::QueryError(format!(
+                    "Only numeric fields can be compared, which {} isn't",
+                    field
+                )));
+            }
+        };
+        let mut bounds = Map::new();
+        let (low_op, low) = low.unwrap_or(("gte", &min));
+        let (high_op, high) = high.unwrap_or(("lte", &max));
+        if !low.is_number() || !high.is_number() {
+            return Err(Error::QueryError(format!("{} can only be compared with numbers", field)));
+        }
+        bounds.insert(low_op.to_string(), low.clone());
+        bounds.insert(high_op.to_string(), high.clone());
+        Ok(json!({ "range": { field: bounds } }))
+    }
+}
+
+/// The result of a statement, a row of values for each document or group
+#[derive(Response, Serialize, Debug, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+/// A running aggregate over the values of a group
+#[derive(Default)]
+pub struct Accumulator {
+    count: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Accumulator {
+    pub fn add(&mut self, value: Option<f64>) {
+        if let Some(value) = value {
+            self.count += 1;
+            self.sum += value;
+            self.min = Some(self.min.map_or(value, |min| min.min(value)));
+            self.max = Some(self.max.map_or(value, |max| max.max(value)));
+        }
+    }
+
+    pub fn finish(&self, function: Function) -> Value {
+        match function {
+            Function::Count => self.count.into(),
+            Function::Sum => self.sum.into(),
+            Function::Min => self.min.map_or(Value::Null, Value::from),
+            Function::Max => self.max.map_or(Value::Null, Value::from),
+            Function::Avg if self.count == 0 => Value::Null,
+            Function::Avg => (self.sum / self.count as f64).into(),
+        }
+    }
+}
+
+/// The value of `field` in a result, unwrapped from its array when it holds a single one
+fn field_value(doc: &ScoredDoc, field: &str) -> Value {
+    match doc.doc.get(field).map(serde_json::to_value) {
+        Some(Ok(Value::Array(mut values))) => {
+            if values.len() == 1 {
+                values.pop().unwrap_or_default()
+            } else {
+                Value::Array(values)
+            }
+        }
+        _ => Value::Null,
+    }
+}
+
+/// Every value of `field` in a result, or a single null when it has none
+fn field_values(doc: &ScoredDoc, field: &str) -> Vec<Value> {
+    match doc.doc.get(field).map(serde_json::to_value) {
+        Some(Ok(Value::Array(ref values))) if values.is_empty() => vec![Value::Null],
+        Some(Ok(Value::Array(values))) => values,
+        _ => vec![Value::Null],
+    }
+}
+
+/// `value` of `field` as a keyword field with normalizers indexes it, so its groups match what filtering by it finds
+fn normalized(schema: &Schema, field: &str, value: Value) -> Value {
+    match (schema.get_field(field), value) {
+        (Some(field), Value::String(text)) => Value::String(analysis::normalize(schema, field, &text)),
+        (_, value) => value,
+    }
+}
+
+/// Orders values the way `ORDER BY` does, with nulls first, then numbers, then everything else by its text
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .unwrap_or_default()
+            .partial_cmp(&b.as_f64().unwrap_or_default())
+            .unwrap_or(Ordering::Equal),
+        (Value::Number(_), _) => Ordering::Less,
+        (_, Value::Number(_)) => Ordering::Greater,
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+impl Statement {
+    pub fn parse(sql: &str) -> Result<Self> {
+        let tokens = tokenize(sql)?;
+        Parser { tokens, at: 0, depth: 0 }.statement()
+    }
+
+    /// Whether the statement groups or aggregates documents rather than listing them
+    pub fn is_aggregate(&self) -> bool {
+        !self.group_by.is_empty()
+            || self
+                .columns
+                .iter()
+                .any(|column| if let Column::Aggregate { .. } = column { true } else { false })
+    }
+
+    /// The search that finds the documents the statement's table is made from
+    pub fn request(&self, schema: &Schema) -> Result<Request> {
+        let query = match self.filter {
+            Some(ref filter) => serde_json::from_value(Compiler { schema }.clause(filter)?)?,
+            None => Query::All,
+        };
+        if self.is_aggregate() {
+            // One more than can be aggregated, to tell when there are too many
+            return Ok(Request::new(Some(query), None, MAX_AGGREGATED_DOCS + 1));
+        }
+        let mut request = Request::new(Some(query), None, self.limit.unwrap_or_else(Settings::default_result_limit));
+        request.sort = match self.order_by.as_slice() {
+            [] => None,
+            [(field, order)] => Some(Sort {
+                field: field.clone(),
+                order: *order,
+                missing: None,
+                script: None,
+            }),
+            _ => {
+                return Err(Error::QueryError(
+                    "Without GROUP BY, results can only be ordered by one field".into(),
+                ))
+            }
+        };
+        Ok(request)
+    }
+
+    /// The table made of `results`, the documents matched by the statement's `request`
+    pub fn table(&self, schema: &Schema, results: SearchResults) -> Result<Table> {
+        if self.is_aggregate() {
+            return self.aggregate(schema, results);
+        }
+        let fields: Vec<(String, String)> = if self.columns.is_empty() {
+            schema
+                .fields()
+                .iter()
+                .filter(|entry| entry.is_stored())
+                .map(|entry| (entry.name().to_string(), entry.name().to_string()))
+                .collect()
+        } else {
+            self.columns
+                .iter()
+                .map(|column| match column {
+                    Column::Field { name, .. } => (name.clone(), column.name()),
+                    Column::Aggregate { .. } => unreachable!("aggregates are handled by aggregate"),
+                })
+                .collect()
+        };
+        let rows = results
+            .docs
+            .iter()
+            .map(|doc| fields.iter().map(|(field, _)| field_value(doc, field)).collect())
+            .collect();
+        Ok(Table {
+            columns: fields.into_iter().map(|(_, name)| name).collect(),
+            rows,
+        })
+    }
+
+    fn aggregate(&self, schema: &Schema, results: SearchResults) -> Result<Table> {
+        if results.docs.len() > MAX_AGGREGATED_DOCS {
+            return Err(Error::QueryError(format!(
+                "More than {} documents match, which is more than can be grouped or aggregated",
+                MAX_AGGREGATED_DOCS
+            )));
+        }
+        if self.columns.is_empty() {
+            return Err(Error::QueryError("SELECT * can't be grouped".into()));
+        }
+        for column in &self.columns {
+            if let Column::Field { name, .. } = column {
+                if !self.group_by.contains(name) {
+                    return Err(Error::QueryError(format!("{} is selected but isn't in GROUP BY", name)));
+                }
+            }
+        }
+
+        let mut groups: BTreeMap<String, (Vec<Value>, Vec<Accumulator>)> = BTreeMap::new();
+        // Aggregating without grouping gives a single row even when nothing matches
+        if self.group_by.is_empty() {
+            groups.insert(
+                Value::Array(Vec::new()).to_string(),
+                (Vec::new(), self.columns.iter().map(|_| Accumulator::default()).collect()),
+            );
+        }
+        for doc in &results.docs {
+            // A document with several values in a grouped field is in the group of each of them
+            let mut keys: Vec<Vec<Value>> = vec![Vec::new()];
+            for field in &self.group_by {
+                let values: Vec<Value> = field_values(doc, field)
+                    .into_iter()
+                    .map(|value| normalized(schema, field, value))
+                    .collect();
+                keys = keys
+                    .into_iter()
+                    .flat_map(|key| {
+                        values.iter().map(move |value| {
+                            let mut key = key.clone();
+                            key.push(value.clone());
+                            key
+                        })
+                    })
+                    .collect();
+            }
+            let mut keys: Vec<(String, Vec<Value>)> = keys.into_iter().map(|key| (Value::Array(key.clone()).to_string(), key)).collect();
+            keys.sort_by(|a, b| a.0.cmp(&b.0));
+            keys.dedup_by(|a, b| a.0 == b.0);
+
+            for (name, key) in keys {
+                let columns = &self.columns;
+                let (_, accumulators) = groups
+                    .entry(name)
+                    .or_insert_with(|| (key, columns.iter().map(|_| Accumulator::default()).collect()));
+                for (column, accumulator) in self.columns.iter().zip(accumulators.iter_mut()) {
+                    if let Column::Aggregate { field, .. } = column {
+                        match field {
+                            // COUNT(*) counts every document
+                            None => accumulator.add(Some(0.0)),
+                            // while aggregating a field takes in each of its values
+                            Some(field) => {
+                                for value in field_values(doc, field) {
+                                    accumulator.add(value.as_f64());
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let columns: Vec<String> = self.columns.iter().map(Column::name).collect();
+        let mut rows: Vec<Vec<Value>> = groups
+            .into_iter()
+            .map(|(_, (key, accumulators))| {
+                self.columns
+                    .iter()
+                    .zip(accumulators)
+                    .map(|(column, accumulator)| match column {
+                        Column::Field { name, .. } => {
+                            let at = self.group_by.iter().position(|field| field == name).unwrap_or_default();
+                            key.get(at).cloned().unwrap_or_default()
+                        }
+                        Column::Aggregate { function, .. } => accumulator.finish(*function),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut order = Vec::new();
+        for (name, direction) in &self.order_by {
+            match columns.iter().position(|column| column == name) {
+                Some(at) => order.push((at, *direction)),
+                None => {
+                    return Err(Error::QueryError(format!(
+                        "{} can't be ordered by, it isn't a selected column",
+                        name
+                    )))
+                }
+            }
+        }
+        rows.sort_by(|a, b| {
+            order.iter().fold(Ordering::Equal, |ordering, &(at, direction)| {
+                ordering.then_with(|| match direction {
+                    SortOrder::Asc => compare(&a[at], &b[at]),
+                    SortOrder::Desc => compare(&b[at], &a[at]),
+                })
+            })
+        });
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        Ok(Table { columns, rows })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::schema::{Document, SchemaBuilder, INT_INDEXED, INT_STORED, STORED, STRING, TEXT};
+
+    fn schema() -> Schema {
+        let mut builder = SchemaBuilder::new();
+        builder.add_text_field("level", STRING | STORED);
+        builder.add_text_field("message", TEXT | STORED);
+        builder.add_u64_field("duration", INT_INDEXED | INT_STORED);
+        builder.build()
+    }
+
+    #[test]
+    fn test_parse() {
+        let statement = Statement::parse(
+            "select level, count(*) as events, AVG(duration) from logs \
+             where match(message, 'disk full') and not level = 'debug' and duration between 5 and 10 \
+             group by level order by events desc limit 3;",
+        )
+        .unwrap();
+        assert_eq!(statement.index, "logs");
+        assert_eq!(statement.columns.len(), 3);
+        assert_eq!(statement.columns[1].name(), "events");
+        assert_eq!(statement.columns[2].name(), "avg(duration)");
+        assert_eq!(statement.group_by, vec!["level"]);
+        assert_eq!(statement.order_by, vec![("events".to_string(), SortOrder::Desc)]);
+        assert_eq!(statement.limit, Some(3));
+        assert!(statement.is_aggregate());
+
+        let statement = Statement::parse("SELECT * FROM 'logs-2019' WHERE level != 'info' OR duration IN (1, 2)").unwrap();
+        assert!(statement.columns.is_empty());
+        assert_eq!(statement.index, "logs-2019");
+        assert!(match statement.filter {
+            Some(Expr::Or(ref left, _)) => match **left {
+                Expr::Not(_) => true,
+                _ => false,
+            },
+            _ => false,
+        });
+
+        assert!(Statement::parse("SELECT FROM logs").is_err());
+        assert!(Statement::parse("SELECT level FROM logs WHERE").is_err());
+        assert!(Statement::parse("SELECT level FROM logs LIMIT 'ten'").is_err());
+        assert!(Statement::parse("DELETE FROM logs").is_err());
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let nested = |open: &str, close: &str, depth: usize| {
+            Statement::parse(&format!(
+                "SELECT * FROM logs WHERE {}level = 'error'{}",
+                open.repeat(depth),
+                close.repeat(depth)
+            ))
+        };
+        assert!(nested("(", ")", MAX_DEPTH).is_ok());
+        assert!(nested("NOT ", "", MAX_DEPTH - 1).is_ok());
+        assert!(nested("(", ")", 100_000).is_err());
+        assert!(nested("NOT ", "", 100_000).is_err());
+        assert!(nested("NOT (", ")", 100_000).is_err());
+        assert!(nested("", " AND level = 'error'", MAX_DEPTH - 1).is_ok());
+        assert!(nested("", " OR level = 'error'", 100_000).is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let schema = schema();
+        let request = Statement::parse("SELECT * FROM logs WHERE duration > 5 AND level <> 'debug' ORDER BY duration DESC LIMIT 7")
+            .unwrap()
+            .request(&schema)
+            .unwrap();
+        let expected: Query = serde_json::from_value(json!({ "bool": {
+            "must": [{ "range": { "duration": { "gt": 5, "lte": u64::max_value() } } }],
+            "must_not": [{ "term": { "level": "debug" } }]
+        }}))
+        .unwrap();
+        assert_eq!(request.query, Some(expected));
+        assert_eq!(request.limit, 7);
+        assert_eq!(request.sort.unwrap().order, SortOrder::Desc);
+
+        let request = Statement::parse("SELECT level FROM logs WHERE level LIKE 'err%' OR MATCH(message, 'disk')")
+            .unwrap()
+            .request(&schema)
+            .unwrap();
+        let expected: Query = serde_json::from_value(json!({ "bool": {
+            "should": [{ "regexp": { "level": "err.*" } }, { "raw": "message:disk" }]
+        }}))
+        .unwrap();
+        assert_eq!(request.query, Some(expected));
+
+        let invalid = |sql: &str| Statement::parse(sql).unwrap().request(&schema).is_err();
+        assert!(invalid("SELECT * FROM logs WHERE NOT level = 'debug'"));
+        assert!(invalid("SELECT * FROM logs WHERE message > 5"));
+        assert!(invalid("SELECT * FROM logs WHERE missing = 5"));
+        assert!(invalid("SELECT * FROM logs ORDER BY duration, level"));
+    }
+
+    #[test]
+    fn test_table() {
+        let schema = schema();
+        let doc = |level: &str, duration: u64| {
+            let mut doc = Document::default();
+            doc.add_text(schema.get_field("level").unwrap(), level);
+            doc.add_u64(schema.get_field("duration").unwrap(), duration);
+            ScoredDoc::new(Some(1.0), schema.to_named_doc(&doc))
+        };
+        let results = || {
+            SearchResults::new(vec![
+                doc("error", 10),
+                doc("info", 1),
+                doc("error", 30),
+                doc("info", 3),
+                doc("warn", 5),
+            ])
+        };
+
+        let statement =
+            Statement::parse("SELECT level, COUNT(*) AS n, MAX(duration) FROM logs GROUP BY level ORDER BY n DESC, level LIMIT 2").unwrap();
+        let table = statement.table(&schema, results()).unwrap();
+        assert_eq!(table.columns, vec!["level", "n", "max(duration)"]);
+        assert_eq!(
+            table.rows,
+            vec![
+                vec![json!("error"), json!(2), json!(30.0)],
+                vec![json!("info"), json!(2), json!(3.0)]
+            ]
+        );
+
+        let statement = Statement::parse("SELECT AVG(duration) FROM logs").unwrap();
+        assert_eq!(statement.table(&schema, results()).unwrap().rows, vec![vec![json!(9.8)]]);
+        let table = statement.table(&schema, SearchResults::new(Vec::new())).unwrap();
+        assert_eq!(table.rows, vec![vec![Value::Null]]);
+
+        let statement = Statement::parse("SELECT duration AS d, level FROM logs").unwrap();
+        let table = statement.table(&schema, results()).unwrap();
+        assert_eq!(table.columns, vec!["d", "level"]);
+        assert_eq!(table.rows[0], vec![json!(10), json!("error")]);
+
+        let mut tagged = Document::default();
+        tagged.add_text(schema.get_field("level").unwrap(), "error");
+        tagged.add_text(schema.get_field("level").unwrap(), "warn");
+        tagged.add_u64(schema.get_field("duration").unwrap(), 2);
+        tagged.add_u64(schema.get_field("duration").unwrap(), 4);
+        let mut results = results();
+        results.docs.push(ScoredDoc::new(Some(1.0), schema.to_named_doc(&tagged)));
+        let statement = Statement::parse("SELECT level, COUNT(*), SUM(duration) FROM logs GROUP BY level").unwrap();
+        assert_eq!(
+            statement.table(&schema, results).unwrap().rows,
+            vec![
+                vec![json!("error"), json!(3), json!(46.0)],
+                vec![json!("info"), json!(2), json!(4.0)],
+                vec![json!("warn"), json!(2), json!(11.0)]
+            ]
+        );
+
+        assert!(Statement::parse("SELECT level, COUNT(*) FROM logs")
+            .unwrap()
+            .table(&schema, results())
+            .is_err());
+    }
+}
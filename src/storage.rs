@@ -0,0 +1,211 @@
+//! How each index is stored. Most indexes are memory mapped from disk, but small, latency critical indexes
+//! can be kept entirely in memory, and large ones can have their files preloaded when they're opened.
+//!
+//! The choice is made when an index is created and recorded in a file inside the index's directory, next to
+//! the files Tantivy manages, so it's honored every time the index is opened again.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use tantivy::directory::MmapDirectory;
+use tantivy::schema::Schema;
+use tantivy::Index;
+
+use crate::mapping::Mappings;
+use crate::query::{search_field, sort_field};
+use crate::shard::SHARDS_FILENAME;
+use crate::{Error, Result};
+
+pub const STORAGE_FILENAME: &str = ".storage.json";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DirectoryType {
+    /// Memory map the index's files, letting the OS page cache decide what stays in memory
+    Mmap,
+    /// Keep the whole index in memory. Its documents don't survive a restart, only its schema does.
+    Ram,
+}
+
+impl Default for DirectoryType {
+    fn default() -> Self {
+        DirectoryType::Mmap
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct StorageSettings {
+    #[serde(default)]
+    pub directory: DirectoryType,
+    /// Read every file of a memory mapped index when it's opened, so the first searches aren't served from disk
+    #[serde(default)]
+    pub preload: bool,
+    /// A u64 or i64 fast field documents are expected to be added in order of, such as a log event's timestamp.
+    /// Searches sorted by it can stop early in segments that turn out to be in order.
+    #[serde(default)]
+    pub sort_by: Option<String>,
+    /// Fields of types Tantivy has none of, such as dates, and how their values are converted to what they're stored as
+    #[serde(default)]
+    pub mappings: Mappings,
+    /// The fields raw queries search for terms without a field of their own, as `name` or `name^boost`. Every field
+    /// is searched when there are none.
+    #[serde(default)]
+    pub default_fields: Vec<String>,
+}
+
+/// What's recorded about an index that isn't stored the default way
+#[derive(Serialize, Deserialize)]
+struct StoredSettings {
+    #[serde(flatten)]
+    storage: StorageSettings,
+    /// In memory indexes have no meta.json to read their schema back from
+    schema: Option<Schema>,
+}
+
+impl StorageSettings {
+    /// The settings recorded for the index at `path`, or the defaults if none were
+    pub fn load(path: &Path) -> Result<(StorageSettings, Option<Schema>)> {
+        match fs::read_to_string(path.join(STORAGE_FILENAME)) {
+            Ok(contents) => {
+                let stored: StoredSettings = serde_json::from_str(&contents)?;
+                Ok((stored.storage, stored.schema))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok((StorageSettings::default(), None)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, path: &Path, schema: &Schema) -> Result<()> {
+        if *self == StorageSettings::default() {
+            return Ok(());
+        }
+        let stored = StoredSettings {
+            storage: self.clone(),
+            schema: if self.directory == DirectoryType::Ram {
+                Some(schema.clone())
+            } else {
+                None
+            },
+        };
+        fs::write(path.join(STORAGE_FILENAME), serde_json::to_string_pretty(&stored)?)?;
+        Ok(())
+    }
+
+    /// Create an index at `path` stored according to these settings, or open it if it already exists
+    pub fn create(&self, path: &Path, schema: Schema) -> Result<Index> {
+        if let Some(ref field) = self.sort_by {
+            sort_field(&schema, field)?;
+        }
+        for field in &self.default_fields {
+            search_field(&schema, field)?;
+        }
+        if !path.exists() {
+            fs::create_dir(path)?;
+        }
+        self.save(path, &schema)?;
+        self.open_with_schema(path, schema)
+    }
+
+    fn open_with_schema(&self, path: &Path, schema: Schema) -> Result<Index> {
+        match self.directory {
+            DirectoryType::Ram => Ok(Index::create_in_ram(schema)),
+            DirectoryType::Mmap => {
+                let dir = MmapDirectory::open(path).map_err(|e| Error::IOError(e.to_string()))?;
+                let index = Index::open_or_create(dir, schema)?;
+                if self.preload {
+                    preload(path)?;
+                }
+                Ok(index)
+            }
+        }
+    }
+}
+
+/// Whether `path` holds an index, either on disk, recorded as kept in memory, or split into shards
+pub fn is_index(path: &Path) -> bool {
+    path.join("meta.json").exists() || path.join(STORAGE_FILENAME).exists() || path.join(SHARDS_FILENAME).exists()
+}
+
+/// Open the index at `path` the way it was stored when it was created, along with how that is
+pub fn open(path: &Path) -> Result<(Index, StorageSettings)> {
+    let (storage, schema) = StorageSettings::load(path)?;
+    let index = match (storage.directory, schema) {
+        (DirectoryType::Ram, Some(schema)) => storage.open_with_schema(path, schema)?,
+        _ => {
+            let index = Index::open_in_dir(path)?;
+            if storage.preload {
+                preload(path)?;
+            }
+            index
+        }
+    };
+    Ok((index, storage))
+}
+
+/// Read every file in `path` once, pulling it into the page cache
+fn preload(path: &Path) -> Result<u64> {
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?.path();
+        if entry.is_file() {
+            total += io::copy(&mut File::open(&entry)?, &mut io::sink())?;
+        }
+    }
+    info!("Preloaded {} bytes from {}", total, path.display());
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_index;
+
+    #[test]
+    fn test_storage_settings() {
+        let path = std::env::temp_dir().join("toshi-storage-test");
+        let _ = fs::remove_dir_all(&path);
+        let schema = create_test_index().schema();
+
+        let ram = StorageSettings {
+            directory: DirectoryType::Ram,
+            preload: false,
+            sort_by: None,
+            mappings: Mappings::new(),
+            default_fields: vec!["test_text^2".into()],
+        };
+        ram.create(&path, schema.clone()).unwrap();
+        assert!(!path.join("meta.json").exists());
+        assert!(is_index(&path));
+        let (loaded, stored_schema) = StorageSettings::load(&path).unwrap();
+        assert_eq!(loaded, ram);
+        assert_eq!(stored_schema.unwrap().fields().len(), schema.fields().len());
+        assert_eq!(open(&path).unwrap().0.schema().fields().len(), schema.fields().len());
+        fs::remove_dir_all(&path).unwrap();
+
+        let preloaded = StorageSettings {
+            directory: DirectoryType::Mmap,
+            preload: true,
+            sort_by: None,
+            mappings: Mappings::new(),
+            default_fields: Vec::new(),
+        };
+        let unsortable = StorageSettings {
+            sort_by: Some("test_text".into()),
+            ..preloaded.clone()
+        };
+        assert!(unsortable.create(&path, schema.clone()).is_err());
+        let unsearchable = StorageSettings {
+            default_fields: vec!["missing".into()],
+            ..preloaded.clone()
+        };
+        assert!(unsearchable.create(&path, schema.clone()).is_err());
+        preloaded.create(&path, schema).unwrap();
+        assert!(path.join("meta.json").exists());
+        assert!(open(&path).is_ok());
+        assert!(preload(&path).unwrap() > 0);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,329 @@
+//! Operations that take a while, such as reindexing, taking and restoring snapshots and searching. Each one is given
+//! an id when it starts, and its progress can be followed through `GET /_tasks/:id` until it finishes, including for
+//! those that carry on after the request that started them has been answered. Finished tasks are kept for a while so
+//! their outcome can still be looked up. Tasks that check whether they've been asked to stop, such as searches and
+//! reindexes, can be cancelled through `POST /_tasks/:id/_cancel`.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tower_web::Response;
+
+use crate::{Error, Result};
+
+/// How many finished tasks are kept before the oldest are forgotten
+const KEEP_FINISHED: usize = 100;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Response, Serialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub id: u64,
+    pub action: String,
+    pub description: String,
+    /// When the task started, in seconds since the Unix epoch
+    pub started: u64,
+    /// The node running the task, as the `host:port` it listens on
+    pub node: String,
+    pub state: TaskState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether the task stops when it's cancelled
+    pub cancellable: bool,
+    pub total_files: u64,
+    pub done_files: u64,
+    pub total_bytes: u64,
+    pub done_bytes: u64,
+    /// Documents to get through, for tasks that handle documents rather than files
+    #[serde(skip_serializing_if = "is_zero")]
+    pub total_docs: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub done_docs: u64,
+    /// Documents that couldn't be handled and were skipped
+    #[serde(skip_serializing_if = "is_zero")]
+    pub failed_docs: u64,
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+#[derive(Response, Serialize, Debug)]
+pub struct TaskList {
+    pub tasks: Vec<TaskStatus>,
+}
+
+/// Set once a task has been asked to stop, for the task to check as it goes. One that isn't attached to a task, from
+/// `Cancellation::default()`, is never set.
+#[derive(Clone, Default, Debug)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// An error if the task has been asked to stop
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::IOError("Task was cancelled".into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reports the progress of a task. One that isn't attached to a task, from `Progress::default()`, reports nowhere.
+#[derive(Clone)]
+pub struct Progress(Arc<Mutex<TaskStatus>>, Cancellation);
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress(
+            Arc::new(Mutex::new(TaskStatus {
+                id: 0,
+                action: String::new(),
+                description: String::new(),
+                started: 0,
+                node: String::new(),
+                state: TaskState::Running,
+                error: None,
+                cancellable: false,
+                total_files: 0,
+                done_files: 0,
+                total_bytes: 0,
+                done_bytes: 0,
+                total_docs: 0,
+                done_docs: 0,
+                failed_docs: 0,
+            })),
+            Cancellation::default(),
+        )
+    }
+}
+
+impl Progress {
+    fn update<F: FnOnce(&mut TaskStatus)>(&self, f: F) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// There are `files` more files holding `bytes` to get through
+    pub fn expect(&self, files: u64, bytes: u64) {
+        self.update(|status| {
+            status.total_files += files;
+            status.total_bytes += bytes;
+        })
+    }
+
+    /// Another file of `bytes` has been got through
+    pub fn advance(&self, bytes: u64) {
+        self.update(|status| {
+            status.done_files += 1;
+            status.done_bytes += bytes;
+        })
+    }
+
+    /// There are `docs` documents in all to get through
+    pub fn expect_docs(&self, docs: u64) {
+        self.update(|status| status.total_docs = docs)
+    }
+
+    /// `done` more documents have been got through, and `failed` skipped
+    pub fn advance_docs(&self, done: u64, failed: u64) {
+        self.update(|status| {
+            status.done_docs += done;
+            status.failed_docs += failed;
+        })
+    }
+
+    /// What the task checks to find out whether it's been cancelled, which makes it cancellable
+    pub fn cancellation(&self) -> Cancellation {
+        self.update(|status| status.cancellable = true);
+        self.1.clone()
+    }
+
+    pub fn finish<T>(&self, result: &Result<T>) {
+        let cancelled = self.1.is_cancelled();
+        self.update(|status| match result {
+            Ok(_) => status.state = TaskState::Completed,
+            Err(e) => {
+                status.state = if cancelled { TaskState::Cancelled } else { TaskState::Failed };
+                status.error = Some(e.to_string());
+            }
+        })
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Tasks {
+    node: String,
+    next: Arc<AtomicUsize>,
+    tasks: Arc<RwLock<BTreeMap<u64, Progress>>>,
+}
+
+impl Tasks {
+    /// Tasks run by `node`, which they're reported as running on
+    pub fn new(node: String) -> Self {
+        Tasks { node, ..Tasks::default() }
+    }
+
+    /// Start keeping track of a new task
+    pub fn start(&self, action: &str, description: String) -> Progress {
+        self.start_with(action, description, Cancellation::default())
+    }
+
+    /// Start keeping track of a new task that stops on `cancellation`, which whoever started it can also set
+    pub fn start_with(&self, action: &str, description: String, cancellation: Cancellation) -> Progress {
+        let progress = Progress(Progress::default().0, cancellation);
+        let id = self.next.fetch_add(1, Ordering::SeqCst) as u64 + 1;
+        progress.update(|status| {
+            status.id = id;
+            status.action = action.to_string();
+            status.description = description;
+            status.node = self.node.clone();
+            status.started = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or_default();
+        });
+
+        let mut tasks = self.tasks.write().unwrap_or_else(|e| e.into_inner());
+        let finished: Vec<u64> = tasks
+            .iter()
+            .filter(|(_, task)| task.status().state != TaskState::Running)
+            .map(|(id, _)| *id)
+            .collect();
+        if finished.len() >= KEEP_FINISHED {
+            for id in &finished[..=finished.len() - KEEP_FINISHED] {
+                tasks.remove(id);
+            }
+        }
+        tasks.insert(id, progress.clone());
+        progress
+    }
+
+    /// Every task that's running or finished recently, oldest first
+    pub fn list(&self) -> Vec<TaskStatus> {
+        let tasks = self.tasks.read().unwrap_or_else(|e| e.into_inner());
+        tasks.values().map(Progress::status).collect()
+    }
+
+    /// The tasks doing any of `actions` that are running or finished recently, oldest first
+    pub fn list_actions(&self, actions: &[&str]) -> Vec<TaskStatus> {
+        let tasks = self.tasks.read().unwrap_or_else(|e| e.into_inner());
+        tasks
+            .values()
+            .map(Progress::status)
+            .filter(|status| actions.contains(&status.action.as_str()))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<TaskStatus> {
+        let tasks = self.tasks.read().unwrap_or_else(|e| e.into_inner());
+        tasks.get(&id).map(Progress::status)
+    }
+
+    /// Ask the running task `id` to stop, returning its status
+    pub fn cancel(&self, id: u64) -> Result<TaskStatus> {
+        let tasks = self.tasks.read().unwrap_or_else(|e| e.into_inner());
+        let task = tasks
+            .get(&id)
+            .ok_or_else(|| Error::IOError(format!("No task {} is running or finished recently", id)))?;
+        let status = task.status();
+        if !status.cancellable {
+            return Err(Error::IOError(format!("Task {} can't be cancelled", id)));
+        }
+        if status.state != TaskState::Running {
+            return Err(Error::IOError(format!("Task {} has already finished", id)));
+        }
+        task.1.cancel();
+        Ok(status)
+    }
+
+    /// Stop keeping track of the task `id`, for tasks that aren't worth looking up once they've finished
+    pub fn remove(&self, id: u64) {
+        let mut tasks = self.tasks.write().unwrap_or_else(|e| e.into_inner());
+        tasks.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn test_tasks() {
+        let tasks = Tasks::default();
+        let restore = tasks.start("restore", "logs from nightly".into());
+        restore.expect(2, 300);
+        restore.advance(100);
+        let status = tasks.get(1).unwrap();
+        assert_eq!(status.state, TaskState::Running);
+        assert_eq!((status.done_files, status.total_files, status.done_bytes), (1, 2, 100));
+
+        restore.finish::<()>(&Err(Error::IOError("gone".into())));
+        let status = tasks.get(1).unwrap();
+        assert_eq!(status.state, TaskState::Failed);
+        assert!(status.error.unwrap().contains("gone"));
+
+        // Only the most recent finished tasks are kept
+        for _ in 0..KEEP_FINISHED + 5 {
+            tasks.start("restore", String::new()).finish(&Ok(()));
+        }
+        assert!(tasks.get(1).is_none());
+        assert_eq!(tasks.list().len(), KEEP_FINISHED);
+    }
+
+    #[test]
+    fn test_list_actions() {
+        let tasks = Tasks::new("10.0.0.1:8080".into());
+        tasks.start("restore", String::new());
+        tasks.start("reindex", String::new());
+        tasks.start("search", String::new());
+        let listed = tasks.list_actions(&["reindex", "restore"]);
+        assert_eq!(listed.iter().map(|status| status.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(listed.iter().all(|status| status.node == "10.0.0.1:8080"));
+        assert!(tasks.list_actions(&["snapshot"]).is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let tasks = Tasks::default();
+        let restore = tasks.start("restore", String::new());
+        let id = restore.status().id;
+        assert!(tasks.cancel(id).is_err());
+        restore.finish(&Ok(()));
+
+        let search = tasks.start("search", "test_index".into());
+        let id = search.status().id;
+        let cancellation = search.cancellation();
+        assert!(cancellation.check().is_ok());
+        assert_eq!(tasks.cancel(id).unwrap().state, TaskState::Running);
+        assert!(cancellation.check().is_err());
+        search.finish(&cancellation.check());
+        assert_eq!(tasks.get(id).unwrap().state, TaskState::Cancelled);
+        assert!(tasks.cancel(id).is_err());
+
+        tasks.remove(id);
+        assert!(tasks.get(id).is_none());
+    }
+}
@@ -0,0 +1,245 @@
+//! Search templates are searches stored ahead of time with placeholders in them, so applications keep their query
+//! logic on the server and only send the parameters that change between searches. A placeholder is a mustache-style
+//! `{{name}}` in any string of the template, keys included, where `name` can be a dotted path into an object
+//! parameter. A string that's nothing but a placeholder is replaced by the parameter as it is, keeping numbers,
+//! arrays and objects as they are, while a placeholder among other text is replaced by the parameter's text.
+//! Since a parameter can stand for a whole object or name a key, it can change the shape of the search around it as
+//! well as its values, so parameters that come from end users should be checked by the application before use.
+//!
+//! Templates are kept in `.search_templates.json` in the first data path, so every index can be searched with them.
+//! Catalogs without a data directory, such as ones opened by tests, keep them in memory.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+
+use serde_json::{Map, Value};
+
+use crate::index::IndexCatalog;
+use crate::query::Request;
+use crate::{Error, Result};
+
+pub const TEMPLATES_FILENAME: &str = ".search_templates.json";
+
+/// The stored templates, by their ids
+pub type Templates = BTreeMap<String, Value>;
+
+#[derive(Clone)]
+pub struct TemplateStore {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    /// Templates of catalogs without a directory to keep them in
+    in_memory: Arc<Mutex<Templates>>,
+    /// Held while storing or removing a template, so concurrent changes to the file aren't lost
+    writing: Arc<Mutex<()>>,
+}
+
+impl TemplateStore {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>) -> Self {
+        TemplateStore {
+            catalog,
+            in_memory: Arc::new(Mutex::new(Templates::new())),
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The directory templates are kept in, if the catalog has one
+    fn path(&self) -> Result<Option<PathBuf>> {
+        Ok(Some(self.catalog.read()?.base_path().clone()).filter(|path| path.is_dir()))
+    }
+
+    pub fn templates(&self) -> Result<Templates> {
+        match self.path()? {
+            Some(path) => match fs::read(path.join(TEMPLATES_FILENAME)) {
+                Ok(stored) => Ok(serde_json::from_slice(&stored)?),
+                Err(_) => Ok(Templates::new()),
+            },
+            None => Ok(self.in_memory.lock()?.clone()),
+        }
+    }
+
+    fn save(&self, templates: Templates) -> Result<()> {
+        match self.path()? {
+            Some(path) => {
+                let tmp = path.join(format!("{}.tmp", TEMPLATES_FILENAME));
+                fs::write(&tmp, serde_json::to_vec_pretty(&templates)?)?;
+                fs::rename(tmp, path.join(TEMPLATES_FILENAME))?;
+            }
+            None => *self.in_memory.lock()? = templates,
+        }
+        Ok(())
+    }
+
+    /// The template stored as `id`
+    pub fn get(&self, id: &str) -> Result<Value> {
+        self.templates()?
+            .remove(id)
+            .ok_or_else(|| Error::QueryError(format!("No search template is stored as {}", id)))
+    }
+
+    /// Store `source` as `id`, replacing any template stored as `id` before
+    pub fn store(&self, id: &str, source: Value) -> Result<()> {
+        if !source.is_object() {
+            return Err(Error::QueryError(format!("Search template {} is not an object", id)));
+        }
+        // A placeholder that's never closed would fail every search, so it's refused now
+        placeholders(&source, &mut Vec::new())?;
+        let _writing = self.writing.lock()?;
+        let mut templates = self.templates()?;
+        templates.insert(id.to_string(), source);
+        self.save(templates)
+    }
+
+    /// Remove the template stored as `id`, returning whether there was one
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        let _writing = self.writing.lock()?;
+        let mut templates = self.templates()?;
+        let removed = templates.remove(id).is_some();
+        if removed {
+            self.save(templates)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// The search `source` stands for with its placeholders replaced by `params`
+pub fn render(source: &Value, params: &Map<String, Value>) -> Result<Request> {
+    Ok(serde_json::from_value(render_value(source, params)?)?)
+}
+
+/// `value` with its placeholders replaced by `params`
+pub fn render_value(value: &Value, params: &Map<String, Value>) -> Result<Value> {
+    match value {
+        Value::String(text) => match whole_placeholder(text) {
+            Some(name) => param(params, name).map(Value::clone),
+            None => render_text(text, params).map(Value::String),
+        },
+        Value::Array(values) => Ok(Value::Array(
+            values.iter().map(|value| render_value(value, params)).collect::<Result<_>>()?,
+        )),
+        Value::Object(object) => Ok(Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| Ok((render_text(key, params)?, render_value(value, params)?)))
+                .collect::<Result<_>>()?,
+        )),
+        other => Ok(other.clone()),
+    }
+}
+
+/// The name of the placeholder `text` is nothing but, if it is one
+fn whole_placeholder(text: &str) -> Option<&str> {
+    let text = text.trim();
+    if text.starts_with("{{") && text.ends_with("}}") && text.len() > 4 && !text[2..text.len() - 2].contains("}}") {
+        Some(text[2..text.len() - 2].trim())
+    } else {
+        None
+    }
+}
+
+/// `text` with each placeholder replaced by the text of its parameter, strings as they are and anything else as JSON
+fn render_text(text: &str, params: &Map<String, Value>) -> Result<String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| Error::QueryError(format!("Placeholder in {} is never closed", text)))?;
+        match param(params, rest[start + 2..start + end].trim())? {
+            Value::String(value) => rendered.push_str(value),
+            value => rendered.push_str(&value.to_string()),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// The parameter a placeholder names, following dots into objects
+fn param<'a>(params: &'a Map<String, Value>, name: &str) -> Result<&'a Value> {
+    let mut parts = name.split('.');
+    let mut value = parts.next().and_then(|first| params.get(first));
+    for part in parts {
+        value = value.and_then(|value| value.get(part));
+    }
+    value.ok_or_else(|| Error::QueryError(format!("Template parameter {} is not given", name)))
+}
+
+/// The names of the placeholders in `value`, in order
+pub fn placeholders(value: &Value, names: &mut Vec<String>) -> Result<()> {
+    match value {
+        Value::String(text) => placeholder_names(text, names),
+        Value::Array(values) => values.iter().try_for_each(|value| placeholders(value, names)),
+        Value::Object(object) => object.iter().try_for_each(|(key, value)| {
+            placeholder_names(key, names)?;
+            placeholders(value, names)
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn placeholder_names(text: &str, names: &mut Vec<String>) -> Result<()> {
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| Error::QueryError(format!("Placeholder in {} is never closed", text)))?;
+        names.push(rest[start + 2..start + end].trim().to_string());
+        rest = &rest[start + end + 2..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let source = json!({
+            "query": { "bool": {
+                "must": [{ "term": { "{{field}}": "{{user.name}}" } }],
+                "filter": [{ "range": { "test_i64": { "gte": "{{from}}" } } }] } },
+            "limit": "{{size}}"
+        });
+        let params = json!({ "field": "test_text", "user": { "name": "document" }, "from": 2012, "size": 5 });
+        let rendered = render_value(&source, params.as_object().unwrap()).unwrap();
+        assert_eq!(rendered["query"]["bool"]["must"][0]["term"]["test_text"], "document");
+        assert_eq!(rendered["query"]["bool"]["filter"][0]["range"]["test_i64"]["gte"], 2012);
+        assert_eq!(rendered["limit"], 5);
+        assert_eq!(render(&source, params.as_object().unwrap()).unwrap().limit, 5);
+
+        // Within other text a parameter is its text, and can't break out of the string it's in
+        let raw = json!({ "query": { "raw": "test_text:{{word}} AND test_i64:{{year}}" } });
+        let params = json!({ "word": "\"} }, \"limit\": 1000", "year": 2014 });
+        let rendered = render_value(&raw, params.as_object().unwrap()).unwrap();
+        assert_eq!(rendered["query"]["raw"], "test_text:\"} }, \"limit\": 1000 AND test_i64:2014");
+        assert!(rendered.get("limit").is_none());
+
+        assert!(render_value(&source, &Map::new()).is_err());
+        let mut names = Vec::new();
+        placeholders(&source, &mut names).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["field", "from", "size", "user.name"]);
+        assert!(placeholders(&json!({ "query": { "raw": "{{open" } }), &mut names).is_err());
+    }
+
+    #[test]
+    fn test_store() {
+        let store = TemplateStore::new(create_test_catalog("test_index"));
+        store
+            .store("by_text", json!({ "query": { "term": { "test_text": "{{text}}" } } }))
+            .unwrap();
+        assert_eq!(store.templates().unwrap().keys().collect::<Vec<_>>(), vec!["by_text"]);
+        assert_eq!(store.get("by_text").unwrap()["query"]["term"]["test_text"], "{{text}}");
+        assert!(store.store("bad", json!("{{text}}")).is_err());
+        assert!(store.store("bad", json!({ "query": { "raw": "{{text" } })).is_err());
+
+        assert!(store.remove("by_text").unwrap());
+        assert!(!store.remove("by_text").unwrap());
+        assert!(store.get("by_text").is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! Write-ahead logs of the writes made to indexes. Every document added and every delete is appended to its index's
+//! log, numbered in order with a sequence number, before the index's writer takes it. Each commit records in its
+//! payload the last sequence number the log held while the commit was made under the writer's lock, so a commit
+//! holds every write up to that number. A snapshot of the commit knows where in the log it stands, and the index can
+//! be restored to any point after it by replaying the writes logged since. The log is also served as the index's
+//! change feed, for other systems to follow its writes by sequence number.
+//!
+//! A log is kept in the index's directory as segment files of newline separated JSON entries, each segment named after
+//! the first sequence number it was started for. Once the newest segment grows past `SEGMENT_BYTES` a new one is
+//! started, and older segments last written to more than `wal_retention_hours` ago are deleted.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tantivy::{Index, IndexMeta, IndexWriter};
+
+use crate::handle::LocalIndex;
+use crate::handlers::index::{AddDocument, DeleteDoc};
+use crate::settings::Settings;
+use crate::shard::Sharding;
+use crate::snapshot::Manifest;
+use crate::storage;
+use crate::{Error, Result};
+
+/// The directory within an index's directory its log is kept in
+pub const WAL_DIRNAME: &str = "wal";
+
+/// Where the identity of a log is kept, so a log started over for an index of the same name is never mistaken for it
+const ID_FILENAME: &str = "id";
+
+/// How big a segment of a log grows before the next one is started
+const SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// A write to an index
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    /// A document was added, as it was given
+    Add { document: Value },
+    /// The documents holding any of `terms` were deleted
+    Delete { terms: HashMap<String, String> },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub seq: u64,
+    /// When the write was made, in seconds since the Unix epoch
+    pub time: u64,
+    #[serde(flatten)]
+    pub op: Operation,
+}
+
+/// Where in the log of an index a snapshot of it stands
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WalPosition {
+    /// The identity of the log
+    pub id: String,
+    /// The last sequence number the commit of each shard holds, in the order of the shards
+    pub seqs: Vec<u64>,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    id: String,
+    retention: Duration,
+    log: Mutex<Log>,
+}
+
+/// The segment being appended to
+struct Log {
+    file: File,
+    size: u64,
+    last_seq: u64,
+}
+
+impl Wal {
+    /// Open the log kept in `dir`, starting it if there is none. Writes are numbered on from the last one logged, or
+    /// from `committed` if the index's commits hold later ones than the log does.
+    pub fn open(dir: &Path, retention: Duration, committed: u64) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let id = match fs::read_to_string(dir.join(ID_FILENAME)) {
+            Ok(id) => id.trim().to_string(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let id = uuid::Uuid::new_v4().to_string();
+                fs::write(dir.join(ID_FILENAME), &id)?;
+                id
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let (file, size, last_seq) = match segments(dir)?.pop() {
+            Some((first, path)) => {
+                let (size, last) = complete_entries(&path)?;
+                let file = OpenOptions::new().append(true).open(&path)?;
+                // An entry only partly written when the node stopped is dropped, it never reached the index
+                file.set_len(size)?;
+                (file, size, last.unwrap_or_else(|| first.saturating_sub(1)).max(committed))
+            }
+            None => (create_segment(dir, committed + 1)?, 0, committed),
+        };
+        let wal = Wal {
+            dir: dir.to_path_buf(),
+            id,
+            retention,
+            log: Mutex::new(Log { file, size, last_seq }),
+        };
+        wal.expire()?;
+        Ok(wal)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The sequence number of the last write logged
+    pub fn last_seq(&self) -> u64 {
+        self.log.lock().unwrap_or_else(|e| e.into_inner()).last_seq
+    }
+
+    /// Log `op`, returning its sequence number. The write must be handed to the index's writer while its lock is still
+    /// held, so no commit is made between the two.
+    pub fn append(&self, op: Operation) -> Result<u64> {
+        let mut log = self.log.lock()?;
+        let seq = log.last_seq + 1;
+        let mut line = serde_json::to_vec(&Entry { seq, time: now(), op })?;
+        line.push(b'\n');
+        if log.size > 0 && log.size + line.len() as u64 > SEGMENT_BYTES {
+            log.file = create_segment(&self.dir, seq)?;
+            log.size = 0;
+            if let Err(e) = self.expire() {
+                warn!("Failed to delete expired segments of {}: {}", self.dir.display(), e);
+            }
+        }
+        if let Err(e) = log.file.write_all(&line) {
+            // What was written of the entry is cut off, so the next one starts on a line of its own
+            let _ = log.file.set_len(log.size);
+            return Err(e.into());
+        }
+        log.size += line.len() as u64;
+        log.last_seq = seq;
+        Ok(seq)
+    }
+
+    /// Call `f` with every entry logged after `since`, in order, for as long as it returns true. Fails when the log no
+    /// longer goes back that far.
+    pub fn scan<F: FnMut(Entry) -> Result<bool>>(&self, since: u64, mut f: F) -> Result<()> {
+        let segments = segments(&self.dir)?;
+        if let Some(&(oldest, _)) = segments.first() {
+            if oldest > since + 1 {
+                return Err(Error::IOError(format!(
+                    "The write-ahead log only goes back to sequence number {}",
+                    oldest
+                )));
+            }
+        }
+        // The segment the entry after `since` is in, and every one after it
+        let start = segments.iter().rposition(|&(first, _)| first <= since + 1).unwrap_or(0);
+        for (_, path) in &segments[start..] {
+            for line in BufReader::new(File::open(path)?).lines() {
+                // The entry at the end may be being written
+                let entry: Entry = match serde_json::from_str(&line?) {
+                    Ok(entry) => entry,
+                    Err(_) => return Ok(()),
+                };
+                if entry.seq > since && !f(entry)? {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The sequence number the oldest entry the log still holds comes after
+    pub fn oldest(&self) -> Result<u64> {
+        Ok(segments(&self.dir)?.first().map_or(0, |&(first, _)| first.saturating_sub(1)))
+    }
+
+    /// Up to `limit` of the entries logged after `since`, in order
+    pub fn since(&self, since: u64, limit: usize) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        if limit > 0 {
+            self.scan(since, |entry| {
+                entries.push(entry);
+                Ok(entries.len() < limit)
+            })?;
+        }
+        Ok(entries)
+    }
+
+    /// Delete the segments last written to longer ago than the retention, never the one being appended to
+    fn expire(&self) -> Result<()> {
+        let mut segments = segments(&self.dir)?;
+        segments.pop();
+        for (_, path) in segments {
+            let age = fs::metadata(&path)?.modified()?.elapsed().unwrap_or_default();
+            if age > self.retention {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commit `writer`, recording the last write `wal` logged as the last one the commit holds. The caller holds the
+/// writer's lock, so every write logged for the index up to then has been handed to the writer.
+pub fn commit(writer: &mut IndexWriter, wal: Option<&Wal>) -> Result<u64> {
+    let mut prepared = writer.prepare_commit()?;
+    if let Some(wal) = wal {
+        prepared.set_payload(&wal.last_seq().to_string());
+    }
+    Ok(prepared.commit()?)
+}
+
+/// The last logged write `metas` holds, none for a commit made without a log
+pub fn committed_seq(metas: &IndexMeta) -> u64 {
+    metas.payload.as_ref().and_then(|payload| payload.parse().ok()).unwrap_or(0)
+}
+
+/// The last logged write held by the commit of any of `shards`, where their log is to number writes on from
+pub fn last_committed(shards: &[&Index]) -> Result<u64> {
+    let mut committed = 0;
+    for index in shards {
+        committed = committed.max(committed_seq(&index.load_metas()?));
+    }
+    Ok(committed)
+}
+
+/// Bringing an index restored from a snapshot forward to a point in time, by replaying the writes its log holds from
+/// where the snapshot stands up to that point
+pub struct Replay {
+    wal: Arc<Wal>,
+    /// The last time to replay writes up to, in seconds since the Unix epoch
+    until: Option<u64>,
+    /// The last sequence number to replay
+    until_seq: Option<u64>,
+}
+
+impl Replay {
+    /// Replay the writes `wal` holds up to `until` and `until_seq`, or every one of them if neither is given
+    pub fn new(wal: Arc<Wal>, until: Option<u64>, until_seq: Option<u64>) -> Self {
+        Replay { wal, until, until_seq }
+    }
+
+    /// Whether the snapshot `manifest` describes was taken from the log replayed, before the point replayed to
+    pub fn starts_from(&self, manifest: &Manifest) -> bool {
+        match manifest.wal {
+            Some(ref position) if position.id == self.wal.id() => {
+                self.until.map_or(true, |until| manifest.created <= until)
+                    && self.until_seq.map_or(true, |until| position.seqs.iter().all(|seq| *seq <= until))
+            }
+            _ => false,
+        }
+    }
+
+    /// The latest of `manifests` the replay can start from
+    pub fn nearest<'a>(&self, manifests: &'a [Manifest]) -> Option<&'a Manifest> {
+        manifests
+            .iter()
+            .filter(|manifest| self.starts_from(manifest))
+            .max_by_key(|manifest| manifest.created)
+    }
+
+    /// Replay the writes logged after the snapshot `manifest` describes into the copy of it in `staging`, opened with
+    /// `settings`, returning how many were replayed
+    pub fn apply(&self, staging: &Path, manifest: &Manifest, settings: &Settings) -> Result<u64> {
+        let seqs = match manifest.wal {
+            Some(ref position) => &position.seqs,
+            None => {
+                return Err(Error::IOError(format!(
+                    "Snapshot {} doesn't know where in the log it stands",
+                    manifest.name
+                )))
+            }
+        };
+        let sharding = Sharding::load(staging)?;
+        let dirs = match sharding {
+            Some(ref sharding) => (0..sharding.shards).map(|shard| staging.join(shard.to_string())).collect(),
+            None => vec![staging.to_path_buf()],
+        };
+        if dirs.len() != seqs.len() {
+            return Err(Error::IOError(format!(
+                "Snapshot {} has {} shards, but where {} of them stand in the log",
+                manifest.name,
+                dirs.len(),
+                seqs.len()
+            )));
+        }
+        let shards = dirs
+            .iter()
+            .map(|dir| {
+                let (index, storage) = storage::open(dir)?;
+                Ok(LocalIndex::new(index, settings.clone(), &manifest.index)?.with_mappings(storage.mappings))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut replayed = 0;
+        let start = seqs.iter().cloned().min().unwrap_or(0);
+        self.wal.scan(start, |Entry { seq, time, op }| {
+            if self.until.map_or(false, |until| time > until) || self.until_seq.map_or(false, |until| seq > until) {
+                return Ok(false);
+            }
+            // A shard's commit may already hold the writes logged up to where it stands
+            match op {
+                Operation::Add { document } => {
+                    let shard = match sharding {
+                        Some(ref sharding) => sharding.route(&document)?,
+                        None => 0,
+                    };
+                    if seq > seqs[shard] {
+                        shards[shard].add_document(AddDocument { options: None, document })?;
+                    }
+                }
+                Operation::Delete { terms } => {
+                    for (shard, index) in shards.iter().enumerate().filter(|(shard, _)| seq > seqs[*shard]) {
+                        index.delete_term(DeleteDoc {
+                            options: None,
+                            terms: terms.clone(),
+                        })?;
+                    }
+                }
+            }
+            replayed += 1;
+            Ok(true)
+        })?;
+        for shard in shards {
+            shard.close_and_wait()?;
+        }
+        Ok(replayed)
+    }
+}
+
+/// The segments of the log in `dir`, by the sequence number each was started for, oldest first
+fn segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let first = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| name.ends_with(".log"))
+            .and_then(|name| name.trim_end_matches(".log").parse::<u64>().ok());
+        if let Some(first) = first {
+            segments.push((first, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn create_segment(dir: &Path, first: u64) -> Result<File> {
+    let path = dir.join(format!("{:020}.log", first));
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// How many bytes of the segment at `path` are whole entries, and the sequence number of the last of them
+fn complete_entries(path: &Path) -> Result<(u64, Option<u64>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (mut size, mut last) = (0, None);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            return Ok((size, last));
+        }
+        match serde_json::from_str::<Entry>(&line) {
+            Ok(entry) => last = Some(entry.seq),
+            Err(_) => return Ok((size, last)),
+        }
+        size += read as u64;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_scan() {
+        let dir = std::env::temp_dir().join(format!("toshi-wal-{}", uuid::Uuid::new_v4()));
+        let retention = Duration::from_secs(3600);
+        let wal = Wal::open(&dir, retention, 0).unwrap();
+        assert_eq!(wal.last_seq(), 0);
+        let add = |n: u64| Operation::Add {
+            document: serde_json::json!({ "n": n }),
+        };
+        assert_eq!(wal.append(add(1)).unwrap(), 1);
+        assert_eq!(wal.append(add(2)).unwrap(), 2);
+        let mut terms = HashMap::new();
+        terms.insert("n".to_string(), "1".to_string());
+        assert_eq!(wal.append(Operation::Delete { terms: terms.clone() }).unwrap(), 3);
+
+        let entries = wal.since(1, 10).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(entries[1].op, Operation::Delete { terms });
+        assert_eq!(wal.since(0, 1).unwrap().len(), 1);
+        let id = wal.id().to_string();
+        drop(wal);
+
+        // A torn entry at the end is dropped when the log is opened again, and numbering goes on after what's committed
+        let segment = segments(&dir).unwrap().pop().unwrap().1;
+        OpenOptions::new()
+            .append(true)
+            .open(&segment)
+            .unwrap()
+            .write_all(b"{\"seq\": 4, ")
+            .unwrap();
+        let wal = Wal::open(&dir, retention, 0).unwrap();
+        assert_eq!(wal.id(), id);
+        assert_eq!(wal.last_seq(), 3);
+        assert_eq!(wal.append(add(4)).unwrap(), 4);
+        assert_eq!(wal.since(3, 10).unwrap().len(), 1);
+        drop(wal);
+        let wal = Wal::open(&dir, retention, 10).unwrap();
+        assert_eq!(wal.append(add(11)).unwrap(), 11);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
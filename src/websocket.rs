@@ -0,0 +1,539 @@
+//! Searches streamed over WebSockets. A client connects to `ws://host:port/:index`, sends the search as its first
+//! text message, in the form `POST /:index` takes, and gets the results back in messages of `batch_size` documents
+//! each as `{ "docs": [..] }`, followed by `{ "hits": n }`. The results of each index searched are sent as soon as it
+//! has them. Connecting to `/:index?follow=true` keeps the socket open afterwards: whenever the index commits, the
+//! search is run again and the documents sorted past the last one sent are sent as `{ "docs": [..], "live": true }`,
+//! which is enough to tail an index of logs by their timestamps.
+//!
+//! Only unfragmented messages are understood. Pings are answered between messages.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use bytes::{BufMut, BytesMut};
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Sink, Stream};
+use hyper::header::{HeaderValue, CONNECTION, ORIGIN, RETRY_AFTER, UPGRADE};
+use hyper::service::{make_service_fn, service_fn_ok};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request as HttpRequest, Response, Server, StatusCode};
+use log::{error, info};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tokio::codec::{Decoder, Encoder};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Interval;
+
+use crate::cluster::routing::Preference;
+use crate::handlers::search::Searches;
+use crate::handlers::SearchHandler;
+use crate::index::IndexCatalog;
+use crate::middleware::cors::allows_origin;
+use crate::middleware::{Admission, Endpoint, Rejection};
+use crate::query::{Request, SortOrder};
+use crate::results::{ScoredDoc, SearchResults};
+use crate::settings::{CorsSettings, WebsocketSettings};
+use crate::Error;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Messages from clients are only ever searches, so anything larger is refused
+const MAX_PAYLOAD: u64 = 1024 * 1024;
+
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn text(text: String) -> Self {
+        Frame {
+            opcode: TEXT,
+            payload: text.into_bytes(),
+        }
+    }
+
+    pub fn close() -> Self {
+        Frame {
+            opcode: CLOSE,
+            payload: Vec::new(),
+        }
+    }
+
+    /// The answer to a ping, which carries its payload back
+    pub fn pong(payload: Vec<u8>) -> Self {
+        Frame { opcode: PONG, payload }
+    }
+}
+
+/// Reads the masked frames clients send and writes unmasked ones back
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let (first, second) = (buf[0], buf[1]);
+        if first & 0x80 == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Fragmented messages aren't supported"));
+        }
+        let (length, mut header) = match second & 0x7F {
+            126 if buf.len() >= 4 => (u64::from(buf[2]) << 8 | u64::from(buf[3]), 4),
+            127 if buf.len() >= 10 => (buf[2..10].iter().fold(0, |length, b| length << 8 | u64::from(*b)), 10),
+            126 | 127 => return Ok(None),
+            length => (u64::from(length), 2),
+        };
+        if length > MAX_PAYLOAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Message is too large"));
+        }
+        let masked = second & 0x80 != 0;
+        if masked {
+            header += 4;
+        }
+        if buf.len() < header + length as usize {
+            return Ok(None);
+        }
+        let frame = buf.split_to(header + length as usize);
+        let mut payload = frame[header..].to_vec();
+        if masked {
+            let mask = &frame[header - 4..header];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok(Some(Frame {
+            opcode: first & 0x0F,
+            payload,
+        }))
+    }
+}
+
+impl Encoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, buf: &mut BytesMut) -> io::Result<()> {
+        let length = frame.payload.len();
+        buf.reserve(length + 10);
+        buf.put_u8(0x80 | frame.opcode);
+        if length < 126 {
+            buf.put_u8(length as u8);
+        } else if length <= 0xFFFF {
+            buf.put_u8(126);
+            buf.put_u16_be(length as u16);
+        } else {
+            buf.put_u8(127);
+            buf.put_u64_be(length as u64);
+        }
+        buf.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answering a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&Sha1::digest(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+/// `docs` in messages of `batch_size`, each one written out only once the socket has taken the one before
+fn batches(docs: Vec<ScoredDoc>, batch_size: usize, live: bool) -> impl Stream<Item = Frame, Error = Error> {
+    stream::unfold(docs.into_iter(), move |mut docs| {
+        let batch: Vec<ScoredDoc> = docs.by_ref().take(batch_size).collect();
+        if batch.is_empty() {
+            return None;
+        }
+        let message = if live {
+            json!({ "docs": batch, "live": true })
+        } else {
+            json!({ "docs": batch })
+        };
+        Some(future::ok((Frame::text(message.to_string()), docs)))
+    })
+}
+
+/// How far a followed search has got: the largest sort key sent and how many documents with that key were. Documents
+/// sorted before it have been sent or are older than anything that was, so it's all there is to remember however
+/// long the search is followed.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Cursor {
+    key: Option<u64>,
+    at_key: usize,
+}
+
+impl Cursor {
+    /// Move the cursor past `docs`
+    fn advance(&mut self, docs: &[ScoredDoc]) {
+        for doc in docs {
+            if doc.sort_key > self.key {
+                self.key = doc.sort_key;
+                self.at_key = 1;
+            } else if doc.sort_key == self.key {
+                self.at_key += 1;
+            }
+        }
+    }
+
+    /// The documents of `docs` past the cursor in ascending order, moving the cursor past them
+    fn take(&mut self, mut docs: Vec<ScoredDoc>) -> Vec<ScoredDoc> {
+        docs.sort_by_key(|doc| doc.sort_key);
+        let before = docs.iter().take_while(|doc| doc.sort_key < self.key).count();
+        let at_key = docs[before..].iter().take_while(|doc| doc.sort_key == self.key).count();
+        let new = docs.split_off(before + at_key.min(self.at_key));
+        self.advance(&new);
+        new
+    }
+}
+
+type Frames = Box<Stream<Item = Frame, Error = Error> + Send>;
+
+#[derive(Clone)]
+pub struct StreamingServer {
+    catalog: Arc<RwLock<IndexCatalog>>,
+    search: SearchHandler,
+    settings: WebsocketSettings,
+    /// What searches are let in by, once the server is serving
+    admission: Option<Admission>,
+    cors: Option<CorsSettings>,
+}
+
+impl StreamingServer {
+    pub fn new(catalog: Arc<RwLock<IndexCatalog>>, search: SearchHandler, settings: WebsocketSettings) -> Self {
+        StreamingServer {
+            catalog,
+            search,
+            settings,
+            admission: None,
+            cors: None,
+        }
+    }
+
+    /// Serve searches on `listener`, letting them in the way the HTTP API's middleware lets requests in. Browsers
+    /// don't apply CORS to WebSockets, so the origins it allows are checked on the handshake instead.
+    pub fn serve(self, listener: TcpListener, admission: Admission, cors: CorsSettings) -> impl Future<Item = (), Error = ()> {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Streaming searches over WebSockets on {}", addr);
+        }
+        let server = StreamingServer {
+            admission: Some(admission),
+            cors: Some(cors),
+            ..self
+        };
+        let make_service = make_service_fn(move |connection: &TcpStream| {
+            let server = server.clone();
+            let peer = connection.peer_addr().ok();
+            Ok::<_, io::Error>(service_fn_ok(move |mut request: HttpRequest<Body>| {
+                if let Some(peer) = peer {
+                    request.extensions_mut().insert(peer);
+                }
+                server.handshake(request)
+            }))
+        });
+        Server::builder(listener.incoming())
+            .serve(make_service)
+            .map_err(|e| error!("WebSocket listener error: {}", e))
+    }
+
+    fn handshake(&self, request: HttpRequest<Body>) -> Response<Body> {
+        if let (Some(cors), Some(origin)) = (&self.cors, request.headers().get(ORIGIN)) {
+            if !allows_origin(cors, origin) {
+                return StreamingServer::refuse(StatusCode::FORBIDDEN, "Origin not allowed");
+            }
+        }
+        let in_flight = match self.admission.as_ref().map(|a| a.admit(Endpoint::Search, &request)) {
+            Some(Ok(in_flight)) => Some(in_flight),
+            Some(Err(Rejection::Draining)) => return StreamingServer::refuse(StatusCode::SERVICE_UNAVAILABLE, "Node is draining"),
+            Some(Err(Rejection::RateLimited(wait))) => {
+                let mut response = StreamingServer::refuse(StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded");
+                let retry_after = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return response;
+            }
+            None => None,
+        };
+        let is_upgrade = request
+            .headers()
+            .get(UPGRADE)
+            .and_then(|upgrade| upgrade.to_str().ok())
+            .map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+        let key = match request.headers().get("Sec-WebSocket-Key").and_then(|key| key.to_str().ok()) {
+            Some(key) if is_upgrade => accept_key(key),
+            _ => return StreamingServer::refuse(StatusCode::BAD_REQUEST, "Expected a WebSocket upgrade"),
+        };
+        let index = request.uri().path().trim_start_matches('/').to_string();
+        if index.is_empty() {
+            return StreamingServer::refuse(StatusCode::NOT_FOUND, "Connect to /:index to search an index");
+        }
+        let follow = request.uri().query().map_or(false, |query| {
+            query.split('&').any(|option| option == "follow" || option == "follow=true")
+        });
+
+        let server = self.clone();
+        let session = request
+            .into_body()
+            .on_upgrade()
+            .map_err(|e| error!("WebSocket upgrade failed: {}", e))
+            .and_then(move |socket| server.session(socket, index, follow))
+            .then(move |ended| {
+                drop(in_flight);
+                ended
+            });
+        tokio::spawn(session);
+
+        Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(UPGRADE, "websocket")
+            .header(CONNECTION, "Upgrade")
+            .header("Sec-WebSocket-Accept", key)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn refuse(status: StatusCode, message: &str) -> Response<Body> {
+        Response::builder().status(status).body(Body::from(message.to_string())).unwrap()
+    }
+
+    fn session(self, socket: Upgraded, index: String, follow: bool) -> impl Future<Item = (), Error = ()> {
+        let (sink, frames) = FrameCodec.framed(socket).split();
+        frames
+            .into_future()
+            .map_err(|(e, _)| Error::from(e))
+            .and_then(move |(first, frames)| {
+                let search = match first {
+                    Some(ref frame) if frame.opcode == TEXT => frame.payload.clone(),
+                    _ => return Err(Error::QueryError("The first message must be a search".into())),
+                };
+                // Whatever the client sends afterwards only matters as a sign that it's gone, or as a ping to answer
+                let closed = Arc::new(AtomicBool::new(false));
+                let watching = Arc::clone(&closed);
+                let (pongs, pinged) = mpsc::unbounded();
+                let watch = frames
+                    .for_each(move |frame| match frame.opcode {
+                        CLOSE => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "closed")),
+                        PING => {
+                            let _ = pongs.unbounded_send(Frame::pong(frame.payload));
+                            Ok(())
+                        }
+                        _ => Ok(()),
+                    })
+                    .then(move |_| {
+                        watching.store(true, Ordering::SeqCst);
+                        Ok(())
+                    });
+                tokio::spawn(watch);
+                Ok((self.messages(index, search, follow, closed), pinged, sink))
+            })
+            .and_then(|(messages, pinged, sink)| {
+                // A failed search is reported to the client before the socket closes
+                let messages = messages
+                    .then(|message| match message {
+                        Ok(frame) => Ok::<_, Error>(frame),
+                        Err(e) => Ok(Frame::text(json!({ "error": e.to_string() }).to_string())),
+                    })
+                    .map(Some)
+                    .chain(stream::once(Ok(None)));
+                // Pongs are sent between messages for as long as there are messages, which end with `None`
+                let pongs = pinged.map(Some).map_err(|_| Error::IOError("Stopped answering pings".into()));
+                let frames = messages
+                    .select(pongs)
+                    .take_while(|frame| Ok(frame.is_some()))
+                    .filter_map(|frame| frame);
+                sink.sink_map_err(Error::from).send_all(frames).map(|_| ())
+            })
+            .map_err(|e| info!("WebSocket search ended: {}", e))
+    }
+
+    /// The messages answering a search: its results, then either a close or the results of every commit after it
+    fn messages(&self, index: String, search: Vec<u8>, follow: bool, closed: Arc<AtomicBool>) -> Frames {
+        let request = match StreamingServer::request(&search, follow) {
+            Ok(request) => request,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+        let (batch_size, limit) = (self.settings.batch_size, request.limit);
+        let opstamp = self.opstamp(&index);
+        let searches = match self.search.searches(request, &index, &Preference::parse(None)) {
+            Ok(Searches::Single(search)) => vec![search],
+            Ok(Searches::Many(searches)) => searches,
+            Err(e) => return Box::new(stream::once(Err(e))),
+        };
+
+        // Each index's results are sent as soon as it has them, up to the search's limit between them
+        let cursor = Arc::new(Mutex::new(Cursor::default()));
+        let sent = Arc::clone(&cursor);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&hits);
+        let mut remaining = limit;
+        let initial = stream::futures_unordered(searches)
+            .map(move |results| {
+                let mut docs = results.docs;
+                docs.truncate(remaining);
+                remaining -= docs.len();
+                counted.fetch_add(docs.len(), Ordering::SeqCst);
+                sent.lock().unwrap().advance(&docs);
+                batches(docs, batch_size, false)
+            })
+            .flatten()
+            .chain(future::lazy(move || Ok(Frame::text(json!({ "hits": hits.load(Ordering::SeqCst) }).to_string()))).into_stream());
+        if !follow {
+            return Box::new(initial.chain(stream::once(Ok(Frame::close()))));
+        }
+
+        let server = self.clone();
+        let searched = index.clone();
+        let mut last_opstamp = opstamp;
+        let live = Interval::new_interval(Duration::from_millis(self.settings.poll_interval))
+            .map_err(|e| Error::IOError(e.to_string()))
+            .take_while({
+                let server = self.clone();
+                move |_| Ok(!closed.load(Ordering::SeqCst) && !server.is_draining())
+            })
+            .filter(move |_| {
+                let opstamp = server.opstamp(&index);
+                let committed = opstamp != last_opstamp;
+                last_opstamp = opstamp;
+                committed
+            })
+            .and_then({
+                let server = self.clone();
+                move |_| server.newest(&searched, &search)
+            })
+            .map(move |results| batches(cursor.lock().unwrap().take(results.docs), batch_size, true))
+            .flatten();
+        Box::new(initial.chain(live))
+    }
+
+    /// The search a client sent. A followed search has to be sorted, which is what it's followed by.
+    fn request(search: &[u8], follow: bool) -> Result<Request, Error> {
+        let request: Request = serde_json::from_slice(search)?;
+        if follow && request.sort.is_none() {
+            return Err(Error::QueryError(
+                "A followed search has to be sorted by a field that grows, such as a timestamp".into(),
+            ));
+        }
+        Ok(request)
+    }
+
+    /// Run a followed search again for the documents with the largest sort keys, which new ones are among
+    fn newest(&self, index: &str, search: &[u8]) -> Box<Future<Item = SearchResults, Error = Error> + Send> {
+        let mut request = match StreamingServer::request(search, true) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        if let Some(ref mut sort) = request.sort {
+            sort.order = SortOrder::Desc;
+        }
+        self.search.search_refs(request, index.to_string(), Preference::parse(None))
+    }
+
+    /// Followed searches end once the node starts draining, so they don't hold the drain up
+    fn is_draining(&self) -> bool {
+        self.admission.as_ref().map_or(false, Admission::is_draining)
+    }
+
+    /// Changes whenever one of the searched indexes commits
+    fn opstamp(&self, index: &str) -> u64 {
+        let catalog = match self.catalog.read() {
+            Ok(catalog) => catalog,
+            Err(_) => return 0,
+        };
+        index
+            .split(',')
+            .filter_map(|name| catalog.shards(name.trim()).ok())
+            .flatten()
+            .filter_map(|shard| shard.get_index().load_metas().ok())
+            .fold(0u64, |stamp, metas| stamp.wrapping_add(metas.opstamp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::create_test_catalog;
+    use std::collections::BTreeMap;
+    use tantivy::schema::NamedFieldDocument;
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        // A masked "Hello" from the example in RFC 6455
+        let mut buf = BytesMut::from(vec![0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+        let frame = FrameCodec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame, Frame::text("Hello".into()));
+        assert!(buf.is_empty());
+        let mut partial = BytesMut::from(vec![0x81, 0x85, 0x37]);
+        assert_eq!(FrameCodec.decode(&mut partial).unwrap(), None);
+
+        let mut out = BytesMut::new();
+        FrameCodec.encode(Frame::text("x".repeat(200)), &mut out).unwrap();
+        assert_eq!(&out[..4], &[0x81, 126, 0, 200]);
+        assert_eq!(FrameCodec.decode(&mut out).unwrap().unwrap().payload.len(), 200);
+    }
+
+    #[test]
+    fn test_messages() {
+        let catalog = create_test_catalog("test_index");
+        let mut settings = crate::settings::Settings::default_websocket();
+        settings.batch_size = 2;
+        let server = StreamingServer::new(Arc::clone(&catalog), SearchHandler::new(catalog), settings);
+        let search = br#"{ "query": { "raw": "test_text:document" } }"#.to_vec();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let frames: Vec<Frame> = server
+            .messages("test_index".into(), search, false, closed)
+            .collect()
+            .wait()
+            .unwrap();
+        // Three documents in batches of two, then the hits and a close
+        assert_eq!(frames.len(), 4);
+        let first: serde_json::Value = serde_json::from_slice(&frames[0].payload).unwrap();
+        assert_eq!(first["docs"].as_array().unwrap().len(), 2);
+        assert_eq!(frames[2].payload, br#"{"hits":3}"#.to_vec());
+        assert_eq!(frames[3], Frame::close());
+
+        // Followed searches need a sort key to follow
+        let closed = Arc::new(AtomicBool::new(true));
+        let unsorted = br#"{ "query": { "raw": "test_text:document" } }"#.to_vec();
+        assert!(server
+            .messages("test_index".into(), unsorted, true, closed)
+            .collect()
+            .wait()
+            .is_err());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let doc = |key: u64| ScoredDoc::sorted(key, NamedFieldDocument(BTreeMap::new()));
+        let keys = |docs: Vec<ScoredDoc>| docs.into_iter().map(|doc| doc.sort_key.unwrap()).collect::<Vec<_>>();
+        let mut cursor = Cursor::default();
+        cursor.advance(&[doc(3), doc(1), doc(3)]);
+
+        // Only what's sorted past the documents already sent is new, including a third document with the same key
+        assert_eq!(
+            keys(cursor.take(vec![doc(5), doc(3), doc(3), doc(4), doc(3), doc(2)])),
+            vec![3, 4, 5]
+        );
+        assert_eq!(keys(cursor.take(vec![doc(5), doc(4), doc(3)])), Vec::<u64>::new());
+        assert_eq!(keys(cursor.take(vec![doc(5), doc(5), doc(4)])), vec![5]);
+        assert_eq!(cursor, Cursor { key: Some(5), at_key: 2 });
+    }
+
+    #[test]
+    fn test_batches() {
+        let docs = vec![ScoredDoc::sorted(1, NamedFieldDocument(BTreeMap::new())); 3];
+        let frames: Vec<Frame> = batches(docs, 2, true).collect().wait().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].payload, br#"{"docs":[{"doc":{},"sort_key":1}],"live":true}"#.to_vec());
+        assert_eq!(Frame::pong(b"ping".to_vec()).opcode, PONG);
+    }
+}
@@ -0,0 +1,14 @@
+#[cfg(feature = "grpc")]
+extern crate tower_grpc_build;
+
+#[cfg(feature = "grpc")]
+fn main() {
+    tower_grpc_build::Config::new()
+        .enable_server(false)
+        .enable_client(true)
+        .build(&["../proto/toshi.proto"], &["../proto/"])
+        .unwrap_or_else(|e| panic!("Compilation failed :( {}", e));
+}
+
+#[cfg(not(feature = "grpc"))]
+fn main() {}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+
+use futures::{future, Future, Stream};
+use hyper::client::HttpConnector;
+use hyper::{Body, Method, Request};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Bulk, CreateIndex, Delete, Document, Error, Search};
+
+pub type ClientFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// How many copies of an index must accept a write before it's acknowledged
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    One,
+    Quorum,
+    All,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchResults {
+    pub hits: usize,
+    pub docs: Vec<ScoredDoc>,
+    /// The sum asked for with `Search::sum`
+    pub aggregate: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScoredDoc {
+    /// How well the document matched, which sorted searches leave out
+    #[serde(default)]
+    pub score: Option<f32>,
+    /// The document's stored fields, each with all of its values
+    pub doc: BTreeMap<String, Vec<Value>>,
+}
+
+#[derive(Deserialize)]
+struct DocsAffected {
+    docs_affected: u32,
+}
+
+/// A client for the HTTP API of a Toshi node
+#[derive(Clone)]
+pub struct Client {
+    http: hyper::Client<HttpConnector>,
+    base: String,
+    consistency: Option<Consistency>,
+}
+
+impl Client {
+    /// A client for the node at `base`, such as `http://localhost:8080`
+    pub fn new(base: &str) -> Self {
+        Client {
+            http: hyper::Client::new(),
+            base: base.trim_end_matches('/').to_string(),
+            consistency: None,
+        }
+    }
+
+    /// How many copies of an index writes must reach, `One` unless given
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = Some(consistency);
+        self
+    }
+
+    /// Search an index, or several separated by commas
+    pub fn search(&self, index: &str, search: &Search) -> ClientFuture<SearchResults> {
+        match serde_json::to_vec(search) {
+            Ok(body) => self.json(Method::POST, format!("/{}", index), body),
+            Err(e) => Box::new(future::err(e.into())),
+        }
+    }
+
+    /// The first documents of an index, up to the server's `result_limit`
+    pub fn all_docs(&self, index: &str) -> ClientFuture<SearchResults> {
+        self.json(Method::GET, format!("/{}", index), Vec::new())
+    }
+
+    /// Index a document, committing the index afterwards when `commit` is set
+    pub fn add(&self, index: &str, document: &Document, commit: bool) -> ClientFuture<()> {
+        let body = serde_json::json!({ "options": { "commit": commit }, "document": document });
+        let path = self.write_path(&format!("/{}", index));
+        self.empty(Method::PUT, path, body.to_string().into_bytes())
+    }
+
+    /// Index many documents at once. They're committed in the background along with the index's other writes.
+    pub fn bulk(&self, index: &str, bulk: &Bulk) -> ClientFuture<()> {
+        match bulk.to_body() {
+            Ok(body) => self.empty(Method::POST, self.write_path(&format!("/{}/_bulk", index)), body),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /// Delete documents, returning how many were deleted
+    pub fn delete(&self, index: &str, delete: &Delete) -> ClientFuture<u32> {
+        let path = self.write_path(&format!("/{}", index));
+        let deleted = self.json::<DocsAffected>(Method::DELETE, path, delete.to_json().to_string().into_bytes());
+        Box::new(deleted.map(|affected| affected.docs_affected))
+    }
+
+    /// The names of the node's indexes
+    pub fn list_indexes(&self) -> ClientFuture<Vec<String>> {
+        self.json(Method::GET, "/_list".into(), Vec::new())
+    }
+
+    pub fn create_index(&self, index: &str, create: &CreateIndex) -> ClientFuture<()> {
+        let query = create.query_string();
+        let path = if query.is_empty() {
+            format!("/{}/_create", index)
+        } else {
+            format!("/{}/_create?{}", index, query)
+        };
+        match serde_json::to_vec(create.schema()) {
+            Ok(body) => self.empty(Method::PUT, path, body),
+            Err(e) => Box::new(future::err(e.into())),
+        }
+    }
+
+    pub fn drop_index(&self, index: &str) -> ClientFuture<()> {
+        self.empty(Method::DELETE, format!("/{}/_drop", index), Vec::new())
+    }
+
+    /// The index's metadata: its schema, segments and how many documents each holds
+    pub fn summary(&self, index: &str) -> ClientFuture<Value> {
+        self.json(Method::GET, format!("/{}/_summary", index), Vec::new())
+    }
+
+    fn write_path(&self, path: &str) -> String {
+        match self.consistency {
+            Some(consistency) => {
+                let consistency = serde_json::to_value(consistency).unwrap_or(Value::Null);
+                format!("{}?consistency={}", path, consistency.as_str().unwrap_or("one"))
+            }
+            None => path.to_string(),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str, body: Vec<u8>) -> Result<Request<Body>, Error> {
+        Request::builder()
+            .method(method)
+            .uri(format!("{}{}", self.base, path))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .map_err(Error::from)
+    }
+
+    /// Send a request, failing with the response's body when it isn't successful
+    fn send(&self, method: Method, path: String, body: Vec<u8>) -> ClientFuture<Vec<u8>> {
+        let request = match self.request(method, &path, body) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let response = self.http.request(request).map_err(Error::from).and_then(|response| {
+            let status = response.status();
+            response.into_body().concat2().map_err(Error::from).and_then(move |body| {
+                if status.is_success() {
+                    Ok(body.to_vec())
+                } else {
+                    Err(Error::ResponseError {
+                        status: status.as_u16(),
+                        message: String::from_utf8_lossy(&body).into_owned(),
+                    })
+                }
+            })
+        });
+        Box::new(response)
+    }
+
+    fn json<T: DeserializeOwned + Send + 'static>(&self, method: Method, path: String, body: Vec<u8>) -> ClientFuture<T> {
+        Box::new(
+            self.send(method, path, body)
+                .and_then(|body| serde_json::from_slice(&body).map_err(Error::from)),
+        )
+    }
+
+    fn empty(&self, method: Method, path: String, body: Vec<u8>) -> ClientFuture<()> {
+        Box::new(self.send(method, path, body).map(|_| ()))
+    }
+}
+
+/// Percent encode a query string value
+pub fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let client = Client::new("http://localhost:8080/");
+        let request = client.request(Method::PUT, &client.write_path("/books"), Vec::new()).unwrap();
+        assert_eq!(request.uri(), "http://localhost:8080/books");
+        let client = client.with_consistency(Consistency::Quorum);
+        assert_eq!(client.write_path("/books/_bulk"), "/books/_bulk?consistency=quorum");
+        assert_eq!(encode("/data/my books"), "/data/my%20books");
+
+        let results: SearchResults = serde_json::from_str(
+            r#"{ "hits": 1, "docs": [ { "score": 1.5, "doc": { "title": ["The Old Man and the Sea"] } } ], "aggregate": null }"#,
+        )
+        .unwrap();
+        assert_eq!(results.hits, 1);
+        assert_eq!(results.docs[0].doc["title"], vec![Value::from("The Old Man and the Sea")]);
+    }
+}
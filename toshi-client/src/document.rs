@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::Result;
+
+/// A document to index, made of named fields each holding one or several values
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Document(Map<String, Value>);
+
+impl Document {
+    pub fn new() -> Self {
+        Document::default()
+    }
+
+    /// A document from anything serializing to a JSON object, such as a struct deriving `Serialize`
+    pub fn from_serialize<T: Serialize>(value: &T) -> Result<Self> {
+        match serde_json::to_value(value)? {
+            Value::Object(fields) => Ok(Document(fields)),
+            other => Err(crate::Error::JsonError(format!("A document must be an object, not {}", other))),
+        }
+    }
+
+    /// Set `name` to `value`, replacing any values it held
+    pub fn field<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        self.0.insert(name.to_string(), value.into());
+        self
+    }
+
+    /// Add `value` to the values `name` holds
+    pub fn add_value<V: Into<Value>>(mut self, name: &str, value: V) -> Self {
+        let value = value.into();
+        let values = match self.0.remove(name) {
+            Some(Value::Array(mut values)) => {
+                values.push(value);
+                values
+            }
+            Some(existing) => vec![existing, value],
+            None => vec![value],
+        };
+        self.0.insert(name.to_string(), Value::Array(values));
+        self
+    }
+
+    pub fn fields(&self) -> &Map<String, Value> {
+        &self.0
+    }
+}
+
+/// Documents to index together through `POST /:index/_bulk`
+#[derive(Debug, Clone, Default)]
+pub struct Bulk {
+    documents: Vec<Document>,
+}
+
+impl Bulk {
+    pub fn new() -> Self {
+        Bulk::default()
+    }
+
+    pub fn add(mut self, document: Document) -> Self {
+        self.documents.push(document);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    pub fn documents(&self) -> &[Document] {
+        &self.documents
+    }
+
+    /// The documents one per line, as the bulk endpoint takes them
+    pub fn to_body(&self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        for document in &self.documents {
+            serde_json::to_writer(&mut body, document)?;
+            body.push(b'\n');
+        }
+        Ok(body)
+    }
+}
+
+/// Deletes the documents holding any of the terms given, `DELETE /:index`
+#[derive(Debug, Clone, Default)]
+pub struct Delete {
+    terms: HashMap<String, String>,
+    commit: bool,
+}
+
+impl Delete {
+    pub fn new() -> Self {
+        Delete::default()
+    }
+
+    pub fn term(mut self, field: &str, value: &str) -> Self {
+        self.terms.insert(field.to_string(), value.to_string());
+        self
+    }
+
+    /// Commit the index once the documents are deleted, so searches stop finding them straight away
+    pub fn commit(mut self, commit: bool) -> Self {
+        self.commit = commit;
+        self
+    }
+
+    pub fn terms(&self) -> &HashMap<String, String> {
+        &self.terms
+    }
+
+    pub fn is_commit(&self) -> bool {
+        self.commit
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "options": { "commit": self.commit }, "terms": self.terms })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Book {
+        title: &'static str,
+        year: u64,
+    }
+
+    #[test]
+    fn test_documents() {
+        let document = Document::new()
+            .field("title", "The Old Man and the Sea")
+            .add_value("tag", "fiction")
+            .add_value("tag", "classic")
+            .field("year", 1952);
+        assert_eq!(
+            serde_json::to_value(&document).unwrap(),
+            json!({ "title": "The Old Man and the Sea", "tag": ["fiction", "classic"], "year": 1952 })
+        );
+        let book = Book {
+            title: "The Old Man and the Sea",
+            year: 1952,
+        };
+        assert_eq!(
+            Document::from_serialize(&book).unwrap(),
+            Document::new().field("title", "The Old Man and the Sea").field("year", 1952)
+        );
+        assert!(Document::from_serialize(&1952).is_err());
+
+        let bulk = Bulk::new()
+            .add(Document::new().field("year", 1952))
+            .add(Document::new().field("year", 1953));
+        assert_eq!(bulk.to_body().unwrap(), b"{\"year\":1952}\n{\"year\":1953}\n".to_vec());
+
+        let delete = Delete::new().term("title", "sea").commit(true);
+        assert_eq!(
+            delete.to_json(),
+            json!({ "options": { "commit": true }, "terms": { "title": "sea" } })
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! A client for Toshi's gRPC API, defined in `proto/toshi.proto`. Documents are sent as typed fields: each JSON
+//! value of a `Document` fills the members of a `FieldValue` it could stand for, and the server uses the one the
+//! field's type in the index's schema calls for.
+
+use std::io;
+use std::net::SocketAddr;
+
+use futures::Future;
+use serde_json::Value;
+use tokio::net::tcp::{ConnectFuture, TcpStream};
+use tokio_executor::DefaultExecutor;
+use tower_buffer::Buffer;
+use tower_grpc::{BoxBody, Request};
+use tower_h2::client::{Connect, Connection};
+use tower_http::add_origin::Builder;
+use tower_http::AddOrigin;
+use tower_util::MakeService;
+
+use self::proto::client;
+use crate::{Bulk, ClientFuture, Consistency, CreateIndex, Delete, Document, Error, Search};
+
+pub mod proto {
+    use prost_derive::{Enumeration, Message};
+
+    #[cfg(target_family = "unix")]
+    include!(concat!(env!("OUT_DIR"), "/toshi.v1.rs"));
+    #[cfg(target_family = "windows")]
+    include!(concat!(env!("OUT_DIR"), "\\toshi.v1.rs"));
+}
+
+pub type Buf = Buffer<AddOrigin<Connection<TcpStream, DefaultExecutor, BoxBody>>, http::Request<BoxBody>>;
+
+struct GrpcConn(SocketAddr);
+
+impl tokio_connect::Connect for GrpcConn {
+    type Connected = TcpStream;
+    type Error = io::Error;
+    type Future = ConnectFuture;
+
+    fn connect(&self) -> Self::Future {
+        TcpStream::connect(&self.0)
+    }
+}
+
+fn grpc_error<E: std::fmt::Debug>(error: E) -> Error {
+    Error::GrpcError(format!("{:?}", error))
+}
+
+fn field_value(value: &Value) -> proto::FieldValue {
+    let mut field_value = proto::FieldValue::default();
+    match value {
+        Value::String(text) => field_value.text = text.clone(),
+        Value::Number(number) => {
+            if let Some(u) = number.as_u64() {
+                field_value.u64 = u;
+            }
+            if let Some(i) = number.as_i64() {
+                field_value.i64 = i;
+            }
+        }
+        other => field_value.text = other.to_string(),
+    }
+    field_value
+}
+
+/// A document as the fields the gRPC API takes
+pub fn document(document: &Document) -> proto::Document {
+    let fields = document
+        .fields()
+        .iter()
+        .map(|(name, value)| proto::Field {
+            name: name.clone(),
+            values: match value {
+                Value::Array(values) => values.iter().map(field_value).collect(),
+                value => vec![field_value(value)],
+            },
+        })
+        .collect();
+    proto::Document { fields, score: 0.0 }
+}
+
+/// A client for the gRPC API of a Toshi node
+#[derive(Clone)]
+pub struct GrpcClient {
+    client: client::Toshi<Buf>,
+    consistency: proto::Consistency,
+}
+
+impl GrpcClient {
+    /// Connect to the gRPC API at `addr`, the port of the `[grpc]` settings
+    pub fn connect(addr: SocketAddr) -> impl Future<Item = GrpcClient, Error = Error> + Send {
+        let uri: http::Uri = format!("http://{}", addr).parse().unwrap();
+        let mut connect = Connect::new(GrpcConn(addr), Default::default(), DefaultExecutor::current());
+        connect.make_service(()).map_err(grpc_error).and_then(move |conn| {
+            let connection = Builder::new().uri(uri).build(conn).map_err(grpc_error)?;
+            let buffered = Buffer::new(connection, 0).map_err(grpc_error)?;
+            Ok(GrpcClient {
+                client: client::Toshi::new(buffered),
+                consistency: proto::Consistency::One,
+            })
+        })
+    }
+
+    /// How many copies of an index writes must reach, `One` unless given
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = match consistency {
+            Consistency::One => proto::Consistency::One,
+            Consistency::Quorum => proto::Consistency::Quorum,
+            Consistency::All => proto::Consistency::All,
+        };
+        self
+    }
+
+    pub fn search(&self, index: &str, search: &Search) -> ClientFuture<proto::SearchReply> {
+        let request = proto::SearchRequest {
+            index: index.to_string(),
+            search: search.to_json().to_string().into_bytes(),
+        };
+        Box::new(
+            self.client
+                .clone()
+                .search(Request::new(request))
+                .map(|r| r.into_inner())
+                .map_err(grpc_error),
+        )
+    }
+
+    pub fn add(&self, index: &str, document: &Document, commit: bool) -> ClientFuture<()> {
+        let request = proto::IndexRequest {
+            index: index.to_string(),
+            document: Some(self::document(document)),
+            commit,
+            consistency: self.consistency as i32,
+        };
+        Box::new(self.client.clone().index(Request::new(request)).map(|_| ()).map_err(grpc_error))
+    }
+
+    pub fn bulk(&self, index: &str, bulk: &Bulk) -> ClientFuture<()> {
+        let request = proto::BulkRequest {
+            index: index.to_string(),
+            documents: bulk.documents().iter().map(document).collect(),
+            consistency: self.consistency as i32,
+        };
+        Box::new(self.client.clone().bulk(Request::new(request)).map(|_| ()).map_err(grpc_error))
+    }
+
+    /// Delete documents, returning how many were deleted
+    pub fn delete(&self, index: &str, delete: &Delete) -> ClientFuture<u32> {
+        let request = proto::DeleteRequest {
+            index: index.to_string(),
+            terms: delete
+                .terms()
+                .iter()
+                .map(|(field, value)| proto::Term {
+                    field: field.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            commit: delete.is_commit(),
+            consistency: self.consistency as i32,
+        };
+        let deleted = self.client.clone().delete(Request::new(request));
+        Box::new(deleted.map(|r| r.into_inner().docs_affected).map_err(grpc_error))
+    }
+
+    pub fn list_indexes(&self) -> ClientFuture<Vec<String>> {
+        let listed = self.client.clone().list_indexes(Request::new(proto::ListIndexesRequest {}));
+        Box::new(listed.map(|r| r.into_inner().indexes).map_err(grpc_error))
+    }
+
+    /// Create an index. Only the schema, shards and routing field are sent, the gRPC API has no other options.
+    pub fn create_index(&self, index: &str, create: &CreateIndex) -> ClientFuture<()> {
+        let options = serde_json::to_value(create).unwrap_or(Value::Null);
+        let request = proto::CreateIndexRequest {
+            index: index.to_string(),
+            schema: serde_json::to_vec(create.schema()).unwrap_or_default(),
+            shards: options["shards"].as_u64().unwrap_or(0) as u32,
+            routing_field: options["routing_field"].as_str().unwrap_or("").to_string(),
+        };
+        Box::new(
+            self.client
+                .clone()
+                .create_index(Request::new(request))
+                .map(|_| ())
+                .map_err(grpc_error),
+        )
+    }
+
+    pub fn drop_index(&self, index: &str) -> ClientFuture<()> {
+        let request = proto::DropIndexRequest { index: index.to_string() };
+        Box::new(
+            self.client
+                .clone()
+                .drop_index(Request::new(request))
+                .map(|_| ())
+                .map_err(grpc_error),
+        )
+    }
+
+    pub fn summary(&self, index: &str) -> ClientFuture<proto::SummaryReply> {
+        let request = proto::SummaryRequest { index: index.to_string() };
+        Box::new(
+            self.client
+                .clone()
+                .summary(Request::new(request))
+                .map(|r| r.into_inner())
+                .map_err(grpc_error),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_document() {
+        let converted = document(&Document::new().field("title", "Sea").field("year", 1952).field("offset", -3));
+        let value = |name: &str| converted.fields.iter().find(|f| f.name == name).unwrap().values[0].clone();
+        assert_eq!(value("title").text, "Sea");
+        assert_eq!((value("year").u64, value("year").i64), (1952, 1952));
+        assert_eq!((value("offset").u64, value("offset").i64), (0, -3));
+    }
+}
@@ -0,0 +1,221 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Options for a numeric field
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NumericOptions {
+    /// Searchable with term and range queries
+    pub indexed: bool,
+    /// Returned with search results
+    pub stored: bool,
+    /// Kept in a column, which sorting and aggregations need
+    pub fast: bool,
+}
+
+/// An index's schema in the JSON form `PUT /:index/_create` takes
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Schema(Vec<Value>);
+
+impl Schema {
+    pub fn new() -> Self {
+        Schema::default()
+    }
+
+    /// A text field split into words, which phrase queries can search
+    pub fn text(self, name: &str, stored: bool) -> Self {
+        let indexing = json!({ "record": "position", "tokenizer": "default" });
+        self.field(name, "text", json!({ "indexing": indexing, "stored": stored }))
+    }
+
+    /// A text field split into words by `analyzer`, such as `whitespace`, `english` or one named in the server's settings
+    pub fn analyzed(mut self, name: &str, analyzer: &str, stored: bool) -> Self {
+        self.0
+            .push(json!({ "name": name, "type": "text", "analyzer": analyzer, "options": { "stored": stored } }));
+        self
+    }
+
+    /// A text field split into words as `text` fields are, stemmed in the language `stemmer` names or not stemmed
+    /// at all with `none`
+    pub fn stemmed(mut self, name: &str, stemmer: &str, stored: bool) -> Self {
+        self.0
+            .push(json!({ "name": name, "type": "text", "stemmer": stemmer, "options": { "stored": stored } }));
+        self
+    }
+
+    /// A text field kept whole as a single term, for identifiers and keywords
+    pub fn string(self, name: &str, stored: bool) -> Self {
+        let indexing = json!({ "record": "basic", "tokenizer": "raw" });
+        self.field(name, "text", json!({ "indexing": indexing, "stored": stored }))
+    }
+
+    /// A text field kept whole as a single term like `string`, normalized by each of `normalizers`, `lowercase` and
+    /// `trim`, so exact matches of it don't depend on case or surrounding whitespace
+    pub fn keyword(mut self, name: &str, normalizers: &[&str], stored: bool) -> Self {
+        let keyword = json!({ "normalizer": normalizers });
+        self.0
+            .push(json!({ "name": name, "type": "text", "keyword": keyword, "options": { "stored": stored } }));
+        self
+    }
+
+    pub fn u64(self, name: &str, options: NumericOptions) -> Self {
+        self.field(name, "u64", Self::numeric(options))
+    }
+
+    pub fn i64(self, name: &str, options: NumericOptions) -> Self {
+        self.field(name, "i64", Self::numeric(options))
+    }
+
+    /// A hierarchical facet such as `/category/fiction`
+    pub fn facet(mut self, name: &str) -> Self {
+        self.0.push(json!({ "name": name, "type": "hierarchical_facet" }));
+        self
+    }
+
+    /// A stored field of raw bytes, given base64 encoded in documents
+    pub fn bytes(mut self, name: &str) -> Self {
+        self.0.push(json!({ "name": name, "type": "bytes" }));
+        self
+    }
+
+    fn numeric(options: NumericOptions) -> Value {
+        let mut numeric = json!({ "indexed": options.indexed, "stored": options.stored });
+        if options.fast {
+            numeric["fast"] = "single".into();
+        }
+        numeric
+    }
+
+    fn field(mut self, name: &str, field_type: &str, options: Value) -> Self {
+        self.0.push(json!({ "name": name, "type": field_type, "options": options }));
+        self
+    }
+}
+
+/// Where an index keeps its files
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Directory {
+    /// Memory mapped from disk
+    Mmap,
+    /// Only in memory, so documents don't survive a restart
+    Ram,
+}
+
+/// Creates an index, `PUT /:index/_create`
+#[derive(Serialize, Debug, Clone)]
+pub struct CreateIndex {
+    #[serde(skip)]
+    schema: Schema,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<Directory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preload: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shards: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routing_field: Option<String>,
+}
+
+impl CreateIndex {
+    pub fn new(schema: Schema) -> Self {
+        CreateIndex {
+            schema,
+            data_path: None,
+            directory: None,
+            preload: None,
+            sort_by: None,
+            shards: None,
+            routing_field: None,
+        }
+    }
+
+    /// A directory to create the index in instead of one of the server's data paths
+    pub fn with_data_path(mut self, data_path: &str) -> Self {
+        self.data_path = Some(data_path.to_string());
+        self
+    }
+
+    pub fn with_directory(mut self, directory: Directory) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Read the index's files into memory whenever it's opened
+    pub fn with_preload(mut self, preload: bool) -> Self {
+        self.preload = Some(preload);
+        self
+    }
+
+    /// A fast field documents are added in order of, letting searches sorted by it stop early
+    pub fn with_sort_by(mut self, field: &str) -> Self {
+        self.sort_by = Some(field.to_string());
+        self
+    }
+
+    /// Split the index into `shards`, picking each document's shard by the value of `routing_field`
+    pub fn with_shards(mut self, shards: usize, routing_field: &str) -> Self {
+        self.shards = Some(shards);
+        self.routing_field = Some(routing_field.to_string());
+        self
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The options as the query string of the request
+    pub fn query_string(&self) -> String {
+        let options = serde_json::to_value(self).unwrap_or(Value::Null);
+        let mut pairs: Vec<String> = Vec::new();
+        if let Value::Object(options) = options {
+            for (name, value) in options {
+                let value = match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                pairs.push(format!("{}={}", name, crate::client::encode(&value)));
+            }
+        }
+        pairs.join("&")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_index() {
+        let numeric = NumericOptions {
+            indexed: true,
+            stored: true,
+            fast: true,
+        };
+        let schema = Schema::new()
+            .text("title", true)
+            .string("isbn", false)
+            .analyzed("summary", "english", false)
+            .stemmed("author", "none", true)
+            .u64("year", numeric)
+            .facet("category");
+        assert_eq!(
+            serde_json::to_value(&schema).unwrap(),
+            json!([
+                { "name": "title", "type": "text", "options": { "indexing": { "record": "position", "tokenizer": "default" }, "stored": true } },
+                { "name": "isbn", "type": "text", "options": { "indexing": { "record": "basic", "tokenizer": "raw" }, "stored": false } },
+                { "name": "summary", "type": "text", "analyzer": "english", "options": { "stored": false } },
+                { "name": "author", "type": "text", "stemmer": "none", "options": { "stored": true } },
+                { "name": "year", "type": "u64", "options": { "indexed": true, "stored": true, "fast": "single" } },
+                { "name": "category", "type": "hierarchical_facet" }
+            ])
+        );
+
+        let create = CreateIndex::new(schema).with_directory(Directory::Ram).with_shards(2, "isbn");
+        assert_eq!(create.query_string(), "directory=ram&routing_field=isbn&shards=2");
+        assert_eq!(CreateIndex::new(Schema::new()).query_string(), "");
+    }
+}
@@ -0,0 +1,59 @@
+//! A client for Toshi's HTTP API, and with the `grpc` feature its gRPC API, with builders for the queries,
+//! documents, bulk writes and admin requests it takes so they don't have to be written out as JSON.
+//!
+//! ```no_run
+//! use futures::Future;
+//! use toshi_client::{Client, Document, Query, Search};
+//!
+//! let client = Client::new("http://localhost:8080");
+//! let document = Document::new().field("title", "The Old Man and the Sea").field("year", 1952);
+//! let search = Search::new(Query::term("title", "sea")).limit(10);
+//! let results = client.add("books", &document, true).and_then(move |_| client.search("books", &search));
+//! tokio::run(results.map(|results| println!("{} hits", results.hits)).map_err(|e| eprintln!("{}", e)));
+//! ```
+
+use failure::Fail;
+
+pub use self::client::{Client, ClientFuture, Consistency, ScoredDoc, SearchResults};
+pub use self::document::{Bulk, Delete, Document};
+pub use self::index::{CreateIndex, Directory, NumericOptions, Schema};
+pub use self::query::{Bool, Query, Range, Search, SortOrder};
+
+mod client;
+mod document;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+mod index;
+mod query;
+
+#[derive(Debug, Fail, Clone)]
+pub enum Error {
+    #[fail(display = "HTTP Error: {}", _0)]
+    HttpError(String),
+    #[fail(display = "Toshi responded with status {}: {}", status, message)]
+    ResponseError { status: u16, message: String },
+    #[fail(display = "JSON Error: {}", _0)]
+    JsonError(String),
+    #[fail(display = "gRPC Error: {}", _0)]
+    GrpcError(String),
+}
+
+impl From<hyper::Error> for Error {
+    fn from(err: hyper::Error) -> Self {
+        Error::HttpError(err.to_string())
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(err: http::Error) -> Self {
+        Error::HttpError(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::JsonError(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,237 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// A query in the JSON form Toshi takes, made with one of the constructors below
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Query(Value);
+
+impl Query {
+    /// Documents where `field` holds exactly `value`
+    pub fn term(field: &str, value: &str) -> Self {
+        Query(json!({ "term": { field: value } }))
+    }
+
+    /// Documents where `field` holds a term within `distance` edits of `value`, counting a transposition as one
+    /// edit when `transposition` is set
+    pub fn fuzzy(field: &str, value: &str, distance: u8, transposition: bool) -> Self {
+        Query(json!({ "fuzzy": { field: { "value": value, "distance": distance, "transposition": transposition } } }))
+    }
+
+    /// Documents where `field` holds `terms` one after another
+    pub fn phrase<S: ToString>(field: &str, terms: &[S]) -> Self {
+        let terms: Vec<String> = terms.iter().map(ToString::to_string).collect();
+        Query(json!({ "phrase": { field: { "terms": terms } } }))
+    }
+
+    /// Documents where `field` holds a term matching the regular expression `pattern`
+    pub fn regex(field: &str, pattern: &str) -> Self {
+        Query(json!({ "regexp": { field: pattern } }))
+    }
+
+    /// A query in tantivy's query language, such as `title:sea AND year:1952`
+    pub fn raw(query: &str) -> Self {
+        Query(json!({ "raw": query }))
+    }
+
+    pub fn as_json(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl From<Range> for Query {
+    fn from(range: Range) -> Self {
+        Query(json!({ "range": { range.field: range.bounds } }))
+    }
+}
+
+impl From<Bool> for Query {
+    fn from(bool: Bool) -> Self {
+        Query(json!({ "bool": bool }))
+    }
+}
+
+/// Documents where a numeric field falls between the bounds given
+#[derive(Debug, Clone)]
+pub struct Range {
+    field: String,
+    bounds: serde_json::Map<String, Value>,
+}
+
+impl Range {
+    pub fn new(field: &str) -> Self {
+        Range {
+            field: field.to_string(),
+            bounds: serde_json::Map::new(),
+        }
+    }
+
+    pub fn gt<V: Into<Value>>(self, value: V) -> Self {
+        self.bound("gt", value.into())
+    }
+
+    pub fn gte<V: Into<Value>>(self, value: V) -> Self {
+        self.bound("gte", value.into())
+    }
+
+    pub fn lt<V: Into<Value>>(self, value: V) -> Self {
+        self.bound("lt", value.into())
+    }
+
+    pub fn lte<V: Into<Value>>(self, value: V) -> Self {
+        self.bound("lte", value.into())
+    }
+
+    fn bound(mut self, bound: &str, value: Value) -> Self {
+        self.bounds.insert(bound.to_string(), value);
+        self
+    }
+}
+
+/// Combines queries: documents must match every `must` and `filter` query and none of the `must_not` ones, and
+/// matching `should` queries adds to their score
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Bool {
+    must: Vec<Query>,
+    filter: Vec<Query>,
+    must_not: Vec<Query>,
+    should: Vec<Query>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    minimum_should_match: Option<u64>,
+}
+
+impl Bool {
+    pub fn new() -> Self {
+        Bool::default()
+    }
+
+    pub fn must<Q: Into<Query>>(mut self, query: Q) -> Self {
+        self.must.push(query.into());
+        self
+    }
+
+    /// Like `must`, but without adding to the score, which lets Toshi cache which documents match
+    pub fn filter<Q: Into<Query>>(mut self, query: Q) -> Self {
+        self.filter.push(query.into());
+        self
+    }
+
+    pub fn must_not<Q: Into<Query>>(mut self, query: Q) -> Self {
+        self.must_not.push(query.into());
+        self
+    }
+
+    pub fn should<Q: Into<Query>>(mut self, query: Q) -> Self {
+        self.should.push(query.into());
+        self
+    }
+
+    pub fn minimum_should_match(mut self, count: u64) -> Self {
+        self.minimum_should_match = Some(count);
+        self
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Sort {
+    field: String,
+    order: SortOrder,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Aggregation {
+    field: String,
+}
+
+/// The body of a search, `POST /:index`
+#[derive(Serialize, Debug, Clone)]
+pub struct Search {
+    query: Query,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort: Option<Sort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggs: Option<Aggregation>,
+}
+
+impl Search {
+    pub fn new<Q: Into<Query>>(query: Q) -> Self {
+        Search {
+            query: query.into(),
+            limit: None,
+            sort: None,
+            aggs: None,
+        }
+    }
+
+    /// How many documents to return, the server's `result_limit` setting unless given
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Order results by a fast field instead of by score
+    pub fn sort(mut self, field: &str, order: SortOrder) -> Self {
+        self.sort = Some(Sort {
+            field: field.to_string(),
+            order,
+        });
+        self
+    }
+
+    /// Sum a numeric field over the matching documents
+    pub fn sum(mut self, field: &str) -> Self {
+        self.aggs = Some(Aggregation { field: field.to_string() });
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let query = Bool::new()
+            .must(Query::term("user", "kimchy"))
+            .filter(Range::new("age").gte(10).lt(20))
+            .must_not(Query::regex("user", "ki.*"))
+            .should(Query::fuzzy("user", "kimchi", 1, true))
+            .should(Query::phrase("message", &["hello", "world"]))
+            .minimum_should_match(1);
+        let search = Search::new(query).limit(5).sort("age", SortOrder::Desc).sum("age");
+        assert_eq!(
+            search.to_json(),
+            json!({
+                "query": { "bool": {
+                    "must": [ { "term": { "user": "kimchy" } } ],
+                    "filter": [ { "range": { "age": { "gte": 10, "lt": 20 } } } ],
+                    "must_not": [ { "regexp": { "user": "ki.*" } } ],
+                    "should": [
+                        { "fuzzy": { "user": { "value": "kimchi", "distance": 1, "transposition": true } } },
+                        { "phrase": { "message": { "terms": ["hello", "world"] } } }
+                    ],
+                    "minimum_should_match": 1
+                } },
+                "limit": 5,
+                "sort": { "field": "age", "order": "desc" },
+                "aggs": { "field": "age" }
+            })
+        );
+        assert_eq!(
+            Search::new(Query::raw("user:kimchy")).to_json(),
+            json!({ "query": { "raw": "user:kimchy" } })
+        );
+    }
+}